@@ -0,0 +1,60 @@
+#![no_main]
+
+use compiler::Token;
+use compiler::intern::Symbol;
+use libfuzzer_sys::fuzz_target;
+
+// Going through the tokenizer would spend most inputs on lexical errors, so each byte picks a
+// token directly. Identifiers are interned, so they can't be constants and are picked from
+// NAMES by the bytes past the end of TOKENS.
+const TOKENS: [Token<'static>; 36] = [
+    Token::OpenParen,
+    Token::CloseParen,
+    Token::OpenBrace,
+    Token::CloseBrace,
+    Token::Semicolon,
+    Token::Comma,
+    Token::Operator("+"),
+    Token::Operator("-"),
+    Token::Operator("*"),
+    Token::Operator("/"),
+    Token::Operator("="),
+    Token::Operator("=="),
+    Token::Operator("<"),
+    Token::Operator("&&"),
+    Token::Operator("!"),
+    Token::Operator("&"),
+    Token::Operator("+="),
+    Token::Operator(":"),
+    Token::Keyword("void"),
+    Token::Keyword("int"),
+    Token::Keyword("char"),
+    Token::Keyword("unsigned"),
+    Token::Keyword("return"),
+    Token::Keyword("if"),
+    Token::Keyword("else"),
+    Token::Keyword("while"),
+    Token::Keyword("for"),
+    Token::Keyword("break"),
+    Token::Keyword("switch"),
+    Token::Keyword("case"),
+    Token::Keyword("default"),
+    Token::Keyword("struct"),
+    Token::Keyword("enum"),
+    Token::Keyword("typedef"),
+    Token::IntegerLiteral(1),
+    Token::StringLiteral("s"),
+];
+
+const NAMES: [&str; 3] = ["main", "x", "T"];
+
+fuzz_target!(|data: &[u8]| {
+    let tokens: Vec<Token> = data
+        .iter()
+        .map(|&b| match b as usize % (TOKENS.len() + NAMES.len()) {
+            i if i < TOKENS.len() => TOKENS[i].clone(),
+            i => Token::Identifier(Symbol::intern(NAMES[i - TOKENS.len()])),
+        })
+        .collect();
+    let _ = compiler::parse(&tokens);
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Any source text must tokenize, and whatever it tokenizes to must parse, or fail with an
+// error; never panic
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data)
+        && let Ok(tokens) = compiler::tokenize(source)
+    {
+        let _ = compiler::parse(&tokens);
+    }
+});
@@ -0,0 +1,133 @@
+use std::fmt;
+
+/*
+ * Assembly as the register-machine backends build it, before it's written out as text.
+ *
+ * An instruction is a mnemonic and its operands, in the order the target's assembly syntax
+ * writes them: source first for x86-64's AT&T syntax, destination first for RISC-V. Labels,
+ * directives, and comments sit alongside them. Keeping them structured until the end lets
+ * a pass look at an instruction's operands without parsing them back out of a string, and
+ * each target supplies its own way of writing an operand.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand<R> {
+    Register(R),
+    // The register's low byte, on targets that name it separately
+    ByteRegister(R),
+    Immediate(i64),
+    Memory { base: R, offset: i64 },
+    // base + index * scale
+    Indexed { base: R, index: R, scale: u8 },
+    // A label's address relative to the instruction, with any relocation suffix like
+    // @GOTPCREL
+    PcRelative(String),
+    // A label or symbol by name, as a jump or call target, with any suffix like @PLT
+    Symbol(String),
+    // A jump to the address held in the register
+    Indirect(R),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction<R> {
+    Op {
+        mnemonic: &'static str,
+        operands: Vec<Operand<R>>,
+    },
+    Label(String),
+    // A directive, or any other line that's written out as it is
+    Directive(String),
+    Comment(String),
+}
+
+pub fn op<R, const N: usize>(mnemonic: &'static str, operands: [Operand<R>; N]) -> Instruction<R> {
+    Instruction::Op {
+        mnemonic,
+        operands: operands.into(),
+    }
+}
+
+pub fn reg<R>(reg: R) -> Operand<R> {
+    Operand::Register(reg)
+}
+
+pub fn imm<R>(value: impl Into<i64>) -> Operand<R> {
+    Operand::Immediate(value.into())
+}
+
+pub fn mem<R>(base: R, offset: impl Into<i64>) -> Operand<R> {
+    Operand::Memory {
+        base,
+        offset: offset.into(),
+    }
+}
+
+pub fn sym<R>(name: impl fmt::Display) -> Operand<R> {
+    Operand::Symbol(name.to_string())
+}
+
+pub fn directive<R>(text: impl Into<String>) -> Instruction<R> {
+    Instruction::Directive(text.into())
+}
+
+pub fn label<R>(name: impl fmt::Display) -> Instruction<R> {
+    Instruction::Label(name.to_string())
+}
+
+// Writes an instruction as a line of GNU assembler input, with each operand written by
+// `operand`. Every target's assembler takes # as a comment.
+pub fn render<R>(instruction: &Instruction<R>, operand: impl Fn(&Operand<R>) -> String) -> String {
+    match instruction {
+        Instruction::Op { mnemonic, operands } if operands.is_empty() => mnemonic.to_string(),
+        Instruction::Op { mnemonic, operands } => {
+            let operands: Vec<String> = operands.iter().map(operand).collect();
+            format!("{} {}", mnemonic, operands.join(", "))
+        }
+        Instruction::Label(name) => format!("{}:", name),
+        Instruction::Directive(text) => text.clone(),
+        Instruction::Comment(text) => format!("# {}", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(operand: &Operand<&str>) -> String {
+        match operand {
+            Operand::Register(reg) => reg.to_string(),
+            Operand::Immediate(value) => value.to_string(),
+            Operand::Memory { base, offset } => format!("{}({})", offset, base),
+            Operand::Symbol(name) => name.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let lines: Vec<String> = [
+            label(".L0"),
+            op("ret", []),
+            op("ld", [reg("a0"), mem("sp", 8)]),
+            op("addi", [reg("sp"), reg("sp"), imm(-16)]),
+            op("j", [sym(".L0")]),
+            directive(".text"),
+            Instruction::Comment("v1 = 1".to_owned()),
+        ]
+        .iter()
+        .map(|instruction| render(instruction, plain))
+        .collect();
+        assert_eq!(
+            lines,
+            [
+                ".L0:",
+                "ret",
+                "ld a0, 8(sp)",
+                "addi sp, sp, -16",
+                "j .L0",
+                ".text",
+                "# v1 = 1"
+            ]
+        );
+    }
+}
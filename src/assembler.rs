@@ -0,0 +1,851 @@
+use crate::cfg::literal_bytes;
+use crate::elf::{
+    Object, R_X86_64_64, R_X86_64_GOTPCREL, R_X86_64_PC32, R_X86_64_PLT32, Relocation, Section,
+    SectionKind, Symbol,
+};
+use crate::error::CompileError;
+use std::collections::{HashMap, HashSet};
+
+/*
+    Turns the x86-64 backend's assembly into an Object, for --emit=obj, so an object file
+    can be written without an assembler installed. It only knows the instructions and
+    directives codegen emits, and rejects anything else.
+
+    Every instruction has a single encoding. Jumps always take a 32-bit displacement, so
+    an instruction's size never depends on where its target ends up, and everything can be
+    encoded in one pass. A jump to a label in the same section is patched once the label
+    is defined. Anything else that names a symbol becomes a relocation: calls against the
+    callee's PLT entry, %rip-relative addresses against the symbol, and `.quad` data
+    against its absolute address. A jump table's `.long label-table` entries are relative
+    to the table, which has to be defined first, in the section the entry is in.
+*/
+
+// A reference to a symbol, waiting to be patched or turned into a relocation
+struct Fixup {
+    section: usize,
+    relocation: Relocation,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Register(u8),     // a 64-bit register, numbered as in the instruction encoding
+    ByteRegister(u8), // its low byte
+    Immediate(i64),
+    Memory { base: u8, displacement: i32 },
+    Indexed { base: u8, index: u8, scale: u8 }, // (%base,%index,scale), with no displacement
+    Indirect(u8),                               // *%reg, a jump's target in a register
+    RipRelative(String),
+    Symbol(String),
+}
+
+fn register_number(name: &str) -> Option<u8> {
+    const NAMES: [&str; 16] = [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ];
+    NAMES.iter().position(|n| *n == name).map(|n| n as u8)
+}
+
+fn byte_register_number(name: &str) -> Option<u8> {
+    const NAMES: [&str; 8] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil"];
+    if let Some(n) = NAMES.iter().position(|n| *n == name) {
+        return Some(n as u8);
+    }
+    let n: u8 = name.strip_prefix('r')?.strip_suffix('b')?.parse().ok()?;
+    (8..16).contains(&n).then_some(n)
+}
+
+// The number in a condition code's instruction, as in `0f 80+cc` for jcc
+fn condition_code(name: &str) -> Option<u8> {
+    let code = match name {
+        "o" => 0,
+        "no" => 1,
+        "b" => 2,
+        "ae" => 3,
+        "e" | "z" => 4,
+        "ne" | "nz" => 5,
+        "be" => 6,
+        "a" => 7,
+        "s" => 8,
+        "ns" => 9,
+        "l" => 12,
+        "ge" => 13,
+        "le" => 14,
+        "g" => 15,
+        _ => return None,
+    };
+    Some(code)
+}
+
+fn error(line: &str, message: &str) -> CompileError {
+    CompileError::CodegenError(format!("{} in `{}`", message, line))
+}
+
+fn parse_operand(text: &str, line: &str) -> Result<Operand, CompileError> {
+    let text = text.trim();
+    if let Some(name) = text.strip_prefix('%') {
+        return match (register_number(name), byte_register_number(name)) {
+            (Some(reg), _) => Ok(Operand::Register(reg)),
+            (_, Some(reg)) => Ok(Operand::ByteRegister(reg)),
+            _ => Err(error(line, &format!("Unknown register %{}", name))),
+        };
+    }
+    if let Some(name) = text.strip_prefix("*%") {
+        return register_number(name)
+            .map(Operand::Indirect)
+            .ok_or_else(|| error(line, &format!("Unknown register %{}", name)));
+    }
+    if let Some(value) = text.strip_prefix('$') {
+        return parse_number(value)
+            .map(Operand::Immediate)
+            .ok_or_else(|| error(line, "Expected a number after `$`"));
+    }
+    if let Some((displacement, base)) = text.split_once('(') {
+        let base = base
+            .strip_suffix(')')
+            .and_then(|b| b.strip_prefix('%'))
+            .ok_or_else(|| error(line, "Expected a base register"))?;
+        if base == "rip" {
+            return Ok(Operand::RipRelative(displacement.to_owned()));
+        }
+        if let Some((base, rest)) = base.split_once(',') {
+            let (index, scale) = rest.split_once(',').unwrap_or((rest, "1"));
+            let index = index.trim().strip_prefix('%').and_then(register_number);
+            // rsp can't be an index
+            let (Some(base), Some(index @ (0..=3 | 5..=15))) = (register_number(base), index)
+            else {
+                return Err(error(line, "Expected base and index registers"));
+            };
+            let scale = match scale.trim() {
+                "1" => 0,
+                "2" => 1,
+                "4" => 2,
+                "8" => 3,
+                _ => return Err(error(line, "Expected a scale of 1, 2, 4 or 8")),
+            };
+            if !displacement.is_empty() {
+                return Err(error(line, "Unsupported displacement with an index"));
+            }
+            return Ok(Operand::Indexed { base, index, scale });
+        }
+        let base = register_number(base).ok_or_else(|| error(line, "Unknown base register"))?;
+        let displacement = match displacement {
+            "" => 0,
+            d => parse_number(d)
+                .and_then(|d| i32::try_from(d).ok())
+                .ok_or_else(|| error(line, "Expected a 32-bit displacement"))?,
+        };
+        return Ok(Operand::Memory { base, displacement });
+    }
+    // A call into a shared library names the callee's PLT entry, which a PLT32
+    // relocation against the callee already refers to
+    let symbol = text.strip_suffix("@PLT").unwrap_or(text);
+    Ok(Operand::Symbol(symbol.to_owned()))
+}
+
+// Splits at the commas between operands, which only appear inside one between parentheses
+fn parse_operands(text: &str, line: &str) -> Result<Vec<Operand>, CompileError> {
+    if text.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let mut operands = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(parse_operand(&text[start..i], line)?);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(parse_operand(&text[start..], line)?);
+    Ok(operands)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    // Values too big for an i64 are written unsigned, and stand for the same 64 bits
+    text.parse::<i64>()
+        .ok()
+        .or_else(|| text.parse::<u64>().ok().map(|v| v as i64))
+}
+
+struct Assembler {
+    object: Object,
+    current: usize,
+    labels: HashMap<String, (usize, u64)>,
+    globals: HashSet<String>,
+    sizes: HashMap<String, u64>, // from .size
+    fixups: Vec<Fixup>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        let mut assembler = Assembler {
+            object: Object::default(),
+            current: 0,
+            labels: HashMap::new(),
+            globals: HashSet::new(),
+            sizes: HashMap::new(),
+            fixups: vec![],
+        };
+        assembler.switch_to(".text");
+        assembler.object.sections[0].align = 16;
+        // Marks the stack as not executable, which linkers otherwise warn about
+        assembler.switch_to(".note.GNU-stack");
+        assembler.switch_to(".text");
+        assembler
+    }
+
+    fn switch_to(&mut self, name: &str) {
+        if let Some(i) = self.object.sections.iter().position(|s| s.name == name) {
+            self.current = i;
+            return;
+        }
+        let kind = match name {
+            ".text" => SectionKind::Text,
+            ".data" => SectionKind::Data,
+            ".bss" => SectionKind::Bss,
+            n if n.starts_with(".note") => SectionKind::Note,
+            _ => SectionKind::ReadOnly,
+        };
+        self.object.sections.push(Section::new(name, kind));
+        self.current = self.object.sections.len() - 1;
+    }
+
+    fn offset(&self) -> u64 {
+        self.object.sections[self.current].data.len() as u64
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.object.sections[self.current].data.extend(bytes);
+    }
+
+    // Reserves four bytes for a reference to symbol, to be filled in later
+    fn reference(&mut self, symbol: &str, kind: u32, addend: i64) {
+        let offset = self.offset();
+        let size = if kind == R_X86_64_64 { 8 } else { 4 };
+        self.fixups.push(Fixup {
+            section: self.current,
+            relocation: Relocation {
+                offset,
+                symbol: symbol.to_owned(),
+                kind,
+                addend,
+            },
+        });
+        self.emit(&vec![0; size]);
+    }
+
+    // The REX prefix for a 64-bit operation with `reg` in ModRM.reg and `rm` in ModRM.rm
+    fn rex_w(&mut self, reg: u8, rm: u8) {
+        self.emit(&[0x48 | (reg >> 3) << 2 | rm >> 3]);
+    }
+
+    // The same for a memory operand, whose index register, if any, has its own bit
+    fn rex_w_memory(&mut self, reg: u8, operand: &Operand) {
+        match operand {
+            Operand::Indexed { base, index, .. } => {
+                self.emit(&[0x48 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3]);
+            }
+            _ => self.rex_w(reg, Self::base(operand)),
+        }
+    }
+
+    fn modrm_register(&mut self, reg: u8, rm: u8) {
+        self.emit(&[0xc0 | (reg & 7) << 3 | rm & 7]);
+    }
+
+    // ModRM, and SIB and displacement if needed, for a memory operand. rbp and r13 can't
+    // be a base without a displacement, and rsp and r12 need a SIB byte.
+    fn modrm_memory(&mut self, reg: u8, operand: &Operand) {
+        match operand {
+            Operand::Memory { base, displacement } => {
+                let mode = match displacement {
+                    0 if base & 7 != 5 => 0,
+                    -128..=127 => 1,
+                    _ => 2,
+                };
+                self.emit(&[mode << 6 | (reg & 7) << 3 | base & 7]);
+                if base & 7 == 4 {
+                    self.emit(&[0x24]);
+                }
+                match mode {
+                    1 => self.emit(&[*displacement as i8 as u8]),
+                    2 => self.emit(&displacement.to_le_bytes()),
+                    _ => {}
+                }
+            }
+            // rbp and r13 as a base need a displacement here too
+            Operand::Indexed { base, index, scale } => {
+                let mode = if base & 7 == 5 { 1 } else { 0 };
+                self.emit(&[mode << 6 | (reg & 7) << 3 | 4]);
+                self.emit(&[scale << 6 | (index & 7) << 3 | base & 7]);
+                if mode == 1 {
+                    self.emit(&[0]);
+                }
+            }
+            Operand::RipRelative(symbol) => {
+                self.emit(&[(reg & 7) << 3 | 5]);
+                // The displacement is from the end of the instruction, which ends with it.
+                // Only the linker knows where a symbol's GOT entry is.
+                match symbol.strip_suffix("@GOTPCREL") {
+                    Some(symbol) => self.reference(symbol, R_X86_64_GOTPCREL, -4),
+                    None => self.reference(symbol, R_X86_64_PC32, -4),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn base(operand: &Operand) -> u8 {
+        match operand {
+            Operand::Memory { base, .. } => *base,
+            _ => 0,
+        }
+    }
+
+    fn instruction(&mut self, line: &str) -> Result<(), CompileError> {
+        let (mnemonic, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let operands = parse_operands(rest, line)?;
+        use Operand::*;
+        match (mnemonic, operands.as_slice()) {
+            ("ret", []) => self.emit(&[0xc3]),
+            ("cqo", []) => self.emit(&[0x48, 0x99]),
+            ("syscall", []) => self.emit(&[0x0f, 0x05]),
+            ("push" | "pop", [Register(reg)]) => {
+                if *reg >= 8 {
+                    self.emit(&[0x41]);
+                }
+                let opcode = if mnemonic == "push" { 0x50 } else { 0x58 };
+                self.emit(&[opcode + (reg & 7)]);
+            }
+            ("mov", [Register(src), Register(dest)]) => {
+                self.rex_w(*src, *dest);
+                self.emit(&[0x89]);
+                self.modrm_register(*src, *dest);
+            }
+            // As with the GNU assembler, a mov whose immediate needs more than 32 bits is
+            // encoded as a movabs
+            ("mov" | "movabs", [Immediate(value), Register(dest)]) => {
+                match (mnemonic, i32::try_from(*value)) {
+                    ("mov", Ok(value)) => {
+                        self.rex_w(0, *dest);
+                        self.emit(&[0xc7]);
+                        self.modrm_register(0, *dest);
+                        self.emit(&value.to_le_bytes());
+                    }
+                    _ => {
+                        self.rex_w(0, *dest);
+                        self.emit(&[0xb8 + (dest & 7)]);
+                        self.emit(&value.to_le_bytes());
+                    }
+                }
+            }
+            ("mov" | "lea", [memory @ (Memory { .. } | RipRelative(_)), Register(dest)]) => {
+                self.rex_w(*dest, Self::base(memory));
+                self.emit(&[if mnemonic == "mov" { 0x8b } else { 0x8d }]);
+                self.modrm_memory(*dest, memory);
+            }
+            ("mov", [Register(src), memory @ (Memory { .. } | RipRelative(_))]) => {
+                self.rex_w(*src, Self::base(memory));
+                self.emit(&[0x89]);
+                self.modrm_memory(*src, memory);
+            }
+            ("add" | "sub" | "cmp" | "and" | "or" | "xor", [Register(src), Register(dest)]) => {
+                self.rex_w(*src, *dest);
+                self.emit(&[match mnemonic {
+                    "add" => 0x01,
+                    "sub" => 0x29,
+                    "and" => 0x21,
+                    "or" => 0x09,
+                    "xor" => 0x31,
+                    _ => 0x39,
+                }]);
+                self.modrm_register(*src, *dest);
+            }
+            ("add" | "sub" | "cmp", [Immediate(value), Register(dest)]) => {
+                let extension = match mnemonic {
+                    "add" => 0,
+                    "sub" => 5,
+                    _ => 7,
+                };
+                self.rex_w(0, *dest);
+                match i8::try_from(*value) {
+                    Ok(value) => {
+                        self.emit(&[0x83]);
+                        self.modrm_register(extension, *dest);
+                        self.emit(&[value as u8]);
+                    }
+                    Err(_) => {
+                        let value = i32::try_from(*value)
+                            .map_err(|_| error(line, "Expected a 32-bit immediate"))?;
+                        self.emit(&[0x81]);
+                        self.modrm_register(extension, *dest);
+                        self.emit(&value.to_le_bytes());
+                    }
+                }
+            }
+            ("imul", [Register(src), Register(dest)]) => {
+                self.rex_w(*dest, *src);
+                self.emit(&[0x0f, 0xaf]);
+                self.modrm_register(*dest, *src);
+            }
+            ("neg" | "not" | "div" | "idiv", [Register(reg)]) => {
+                let extension = match mnemonic {
+                    "neg" => 3,
+                    "not" => 2,
+                    "div" => 6,
+                    _ => 7,
+                };
+                self.rex_w(0, *reg);
+                self.emit(&[0xf7]);
+                self.modrm_register(extension, *reg);
+            }
+            // Shifts by %cl, the only register a shift count can be in, or by a constant
+            ("sal" | "shr" | "sar", [count @ (ByteRegister(1) | Immediate(_)), Register(reg)]) => {
+                let extension = match mnemonic {
+                    "sal" => 4,
+                    "shr" => 5,
+                    _ => 7,
+                };
+                self.rex_w(0, *reg);
+                match count {
+                    Immediate(count) => {
+                        let count = u8::try_from(*count)
+                            .map_err(|_| error(line, "Expected a shift count under 256"))?;
+                        self.emit(&[0xc1]);
+                        self.modrm_register(extension, *reg);
+                        self.emit(&[count]);
+                    }
+                    _ => {
+                        self.emit(&[0xd3]);
+                        self.modrm_register(extension, *reg);
+                    }
+                }
+            }
+            ("movzbq", [ByteRegister(src), Register(dest)]) => {
+                self.rex_w(*dest, *src);
+                self.emit(&[0x0f, 0xb6]);
+                self.modrm_register(*dest, *src);
+            }
+            ("movsbq", [ByteRegister(src), Register(dest)]) => {
+                self.rex_w(*dest, *src);
+                self.emit(&[0x0f, 0xbe]);
+                self.modrm_register(*dest, *src);
+            }
+            ("movsbq", [memory @ Memory { .. }, Register(dest)]) => {
+                self.rex_w(*dest, Self::base(memory));
+                self.emit(&[0x0f, 0xbe]);
+                self.modrm_memory(*dest, memory);
+            }
+            ("movb", [ByteRegister(src), memory @ Memory { .. }]) => {
+                // Without a REX prefix, 4-7 would be ah, ch, dh, and bh
+                let base = Self::base(memory);
+                if *src >= 4 || base >= 8 {
+                    self.emit(&[0x40 | (src >> 3) << 2 | base >> 3]);
+                }
+                self.emit(&[0x88]);
+                self.modrm_memory(*src, memory);
+            }
+            ("movslq", [memory @ Indexed { .. }, Register(dest)]) => {
+                self.rex_w_memory(*dest, memory);
+                self.emit(&[0x63]);
+                self.modrm_memory(*dest, memory);
+            }
+            ("jmp", [Symbol(label)]) => {
+                self.emit(&[0xe9]);
+                self.reference(label, R_X86_64_PC32, -4);
+            }
+            ("jmp", [Indirect(reg)]) => {
+                if *reg >= 8 {
+                    self.emit(&[0x41]);
+                }
+                self.emit(&[0xff]);
+                self.modrm_register(4, *reg);
+            }
+            ("call", [Symbol(function)]) => {
+                self.emit(&[0xe8]);
+                self.reference(function, R_X86_64_PLT32, -4);
+            }
+            (m, [Symbol(label)]) if m.starts_with('j') => {
+                let Some(code) = condition_code(&m[1..]) else {
+                    return Err(error(line, "Unknown instruction"));
+                };
+                self.emit(&[0x0f, 0x80 + code]);
+                self.reference(label, R_X86_64_PC32, -4);
+            }
+            (m, [ByteRegister(reg)]) if m.starts_with("set") => {
+                let Some(code) = condition_code(&m[3..]) else {
+                    return Err(error(line, "Unknown instruction"));
+                };
+                // Without a REX prefix, 4-7 would be ah, ch, dh, and bh
+                if *reg >= 4 {
+                    self.emit(&[0x40 | reg >> 3]);
+                }
+                self.emit(&[0x0f, 0x90 + code]);
+                self.modrm_register(0, *reg);
+            }
+            _ => return Err(error(line, "Unsupported instruction")),
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, line: &str) -> Result<(), CompileError> {
+        let (name, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        match name {
+            ".text" | ".data" | ".bss" => self.switch_to(name),
+            ".section" => self.switch_to(argument),
+            ".global" | ".globl" => {
+                self.globals.insert(argument.to_owned());
+            }
+            // A symbol's type follows from its section, so there's nothing to record
+            ".type" => {
+                if !argument.ends_with(", @function") && !argument.ends_with(", @object") {
+                    return Err(error(line, "Expected @function or @object"));
+                }
+            }
+            // Only `.size name, .-name`, which is how far here is past the label
+            ".size" => {
+                let name = argument
+                    .split_once(", .-")
+                    .filter(|(name, start)| name == start)
+                    .map(|(name, _)| name);
+                match name.and_then(|name| Some((name, self.labels.get(name)?))) {
+                    Some((name, (section, offset))) if *section == self.current => {
+                        let size = self.offset() - offset;
+                        self.sizes.insert(name.to_owned(), size);
+                    }
+                    _ => return Err(error(line, "Expected the size of a label in this section")),
+                }
+            }
+            ".align" => {
+                let align: u64 = argument
+                    .parse()
+                    .map_err(|_| error(line, "Expected an alignment"))?;
+                let section = &mut self.object.sections[self.current];
+                section.align = section.align.max(align);
+                while !(section.data.len() as u64).is_multiple_of(align) {
+                    section.data.push(0);
+                }
+            }
+            ".zero" => {
+                let size: usize = argument
+                    .parse()
+                    .map_err(|_| error(line, "Expected a size"))?;
+                self.emit(&vec![0; size]);
+            }
+            ".long" => match (parse_number(argument), argument.split_once('-')) {
+                (Some(value), _) => {
+                    let value = i32::try_from(value)
+                        .or_else(|_| u32::try_from(value).map(|v| v as i32))
+                        .map_err(|_| error(line, "Expected a 32-bit value"))?;
+                    self.emit(&value.to_le_bytes());
+                }
+                // The difference is the symbol's address relative to here, plus how far
+                // here is past the table
+                (None, Some((symbol, table))) => {
+                    let offset = match self.labels.get(table) {
+                        Some((section, offset)) if *section == self.current => *offset,
+                        _ => return Err(error(line, "Expected a label earlier in the section")),
+                    };
+                    let addend = (self.offset() - offset) as i64;
+                    self.reference(symbol, R_X86_64_PC32, addend);
+                }
+                _ => return Err(error(line, "Expected a number or a difference of labels")),
+            },
+            ".quad" => match parse_number(argument) {
+                Some(value) => self.emit(&value.to_le_bytes()),
+                None => self.reference(argument, R_X86_64_64, 0),
+            },
+            ".string" => {
+                let text = argument
+                    .strip_prefix('"')
+                    .and_then(|a| a.strip_suffix('"'))
+                    .ok_or_else(|| error(line, "Expected a quoted string"))?;
+                let mut bytes = literal_bytes(text);
+                bytes.push(0);
+                self.emit(&bytes);
+            }
+            _ => return Err(error(line, "Unsupported directive")),
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), CompileError> {
+        let line = line.trim();
+        // Comments from --asm-comments are always on a line of their own
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            let location = (self.current, self.offset());
+            if self.labels.insert(label.to_owned(), location).is_some() {
+                return Err(error(line, "Duplicate label"));
+            }
+            return Ok(());
+        }
+        if line.starts_with('.') {
+            return self.directive(line);
+        }
+        self.instruction(line)
+    }
+
+    // Patches the references that can be resolved now, and turns the rest into relocations
+    fn finish(mut self) -> Object {
+        for Fixup {
+            section,
+            relocation,
+        } in self.fixups
+        {
+            let local = !self.globals.contains(&relocation.symbol);
+            match self.labels.get(&relocation.symbol) {
+                Some((target, value))
+                    if *target == section && local && relocation.kind == R_X86_64_PC32 =>
+                {
+                    let displacement = *value as i64 + relocation.addend - relocation.offset as i64;
+                    let start = relocation.offset as usize;
+                    self.object.sections[section].data[start..start + 4]
+                        .copy_from_slice(&(displacement as i32).to_le_bytes());
+                }
+                _ => self.object.sections[section].relocations.push(relocation),
+            }
+        }
+        let mut labels: Vec<(String, (usize, u64))> = self.labels.into_iter().collect();
+        labels.sort();
+        for (name, (section, value)) in labels {
+            let global = self.globals.contains(&name);
+            let size = self.sizes.get(&name).copied().unwrap_or(0);
+            self.object.symbols.push(Symbol {
+                name,
+                section,
+                value,
+                size,
+                global,
+            });
+        }
+        self.object
+    }
+}
+
+pub fn assemble(asm: &[String]) -> Result<Object, CompileError> {
+    let mut assembler = Assembler::new();
+    for line in asm {
+        assembler.line(line)?;
+    }
+    Ok(assembler.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_lines(lines: &[&str]) -> Result<Object, CompileError> {
+        let asm: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        assemble(&asm)
+    }
+
+    #[test]
+    fn test_encodings() -> Result<(), String> {
+        // Checked against GNU as
+        let cases: [(&str, &[u8]); 40] = [
+            ("push %rbp", &[0x55]),
+            ("syscall", &[0x0f, 0x05]),
+            ("pop %r12", &[0x41, 0x5c]),
+            ("mov %rsp, %rbp", &[0x48, 0x89, 0xe5]),
+            ("mov %r10, %rax", &[0x4c, 0x89, 0xd0]),
+            ("mov $-1, %r11", &[0x49, 0xc7, 0xc3, 0xff, 0xff, 0xff, 0xff]),
+            (
+                "mov $4294967296, %rax",
+                &[0x48, 0xb8, 0, 0, 0, 0, 1, 0, 0, 0],
+            ),
+            (
+                "mov $-15, %rax",
+                &[0x48, 0xc7, 0xc0, 0xf1, 0xff, 0xff, 0xff],
+            ),
+            (
+                "movabs $-9223372036854775808, %r12",
+                &[0x49, 0xbc, 0, 0, 0, 0, 0, 0, 0, 0x80],
+            ),
+            ("movabs $1, %rcx", &[0x48, 0xb9, 1, 0, 0, 0, 0, 0, 0, 0]),
+            ("mov -8(%rbp), %rbx", &[0x48, 0x8b, 0x5d, 0xf8]),
+            (
+                "mov %r15, -200(%rbp)",
+                &[0x4c, 0x89, 0xbd, 0x38, 0xff, 0xff, 0xff],
+            ),
+            ("mov (%r13), %rcx", &[0x49, 0x8b, 0x4d, 0x00]),
+            ("mov %rax, (%r12)", &[0x49, 0x89, 0x04, 0x24]),
+            ("lea -16(%rbp), %r10", &[0x4c, 0x8d, 0x55, 0xf0]),
+            ("sub $64, %rsp", &[0x48, 0x83, 0xec, 0x40]),
+            ("imul %r11, %r10", &[0x4d, 0x0f, 0xaf, 0xd3]),
+            ("idiv %r11", &[0x49, 0xf7, 0xfb]),
+            ("setle %r11b", &[0x41, 0x0f, 0x9e, 0xc3]),
+            ("div %r11", &[0x49, 0xf7, 0xf3]),
+            ("xor %rdx, %rdx", &[0x48, 0x31, 0xd2]),
+            ("setae %r11b", &[0x41, 0x0f, 0x93, 0xc3]),
+            ("shr %cl, %r10", &[0x49, 0xd3, 0xea]),
+            ("movzbq %r11b, %rdx", &[0x49, 0x0f, 0xb6, 0xd3]),
+            ("and %r11, %r10", &[0x4d, 0x21, 0xda]),
+            ("xor %r8, %rcx", &[0x4c, 0x31, 0xc1]),
+            ("sal %cl, %r10", &[0x49, 0xd3, 0xe2]),
+            ("sar %cl, %rax", &[0x48, 0xd3, 0xf8]),
+            ("sal $32, %r10", &[0x49, 0xc1, 0xe2, 0x20]),
+            ("sar $32, %rax", &[0x48, 0xc1, 0xf8, 0x20]),
+            ("shr $32, %r10", &[0x49, 0xc1, 0xea, 0x20]),
+            ("movsbq %sil, %r9", &[0x4c, 0x0f, 0xbe, 0xce]),
+            ("movsbq -9(%rbp), %r12", &[0x4c, 0x0f, 0xbe, 0x65, 0xf7]),
+            ("movb %cl, (%rax)", &[0x88, 0x08]),
+            ("movb %sil, (%rax)", &[0x40, 0x88, 0x30]),
+            ("movb %al, (%r12)", &[0x41, 0x88, 0x04, 0x24]),
+            ("movslq (%r11,%r10,4), %r10", &[0x4f, 0x63, 0x14, 0x93]),
+            (
+                "movslq (%r13,%rax,4), %rcx",
+                &[0x49, 0x63, 0x4c, 0x85, 0x00],
+            ),
+            ("jmp *%r10", &[0x41, 0xff, 0xe2]),
+            ("jmp *%rax", &[0xff, 0xe0]),
+        ];
+        for (line, expected) in cases {
+            let object = assemble_lines(&[line])?;
+            assert_eq!(object.sections[0].data, expected, "{}", line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels_and_relocations() -> Result<(), String> {
+        let object = assemble_lines(&[
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "jmp .Lmain_1",
+            ".Lmain_1:",
+            "lea .Lstr0(%rip), %rax",
+            "call putchar@PLT",
+            "je .Lmain_1",
+            ".size main, .-main",
+            ".section .rodata",
+            ".Lstr0:",
+            ".string \"a\\n\"",
+            ".section .data",
+            ".align 8",
+            ".globl p",
+            "p:",
+            ".quad .Lstr0",
+        ])?;
+
+        // The jump's displacement is patched in, since its target is in the same section
+        let text = &object.sections[0];
+        assert_eq!(text.data[..5], [0xe9, 0, 0, 0, 0]);
+        assert_eq!(text.data[17..23], [0x0f, 0x84, 0xee, 0xff, 0xff, 0xff]);
+        let relocation = |offset, symbol: &str, kind, addend| Relocation {
+            offset,
+            symbol: symbol.to_owned(),
+            kind,
+            addend,
+        };
+        assert_eq!(
+            text.relocations,
+            vec![
+                relocation(8, ".Lstr0", R_X86_64_PC32, -4),
+                relocation(13, "putchar", R_X86_64_PLT32, -4),
+            ]
+        );
+        let data = &object.sections[3];
+        assert_eq!(data.name, ".data");
+        assert_eq!(
+            data.relocations,
+            vec![relocation(0, ".Lstr0", R_X86_64_64, 0)]
+        );
+        assert_eq!(object.sections[2].data, b"a\n\0");
+
+        let symbols: Vec<(&str, bool, u64)> = object
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.global, s.size))
+            .collect();
+        assert_eq!(
+            symbols,
+            vec![
+                (".Lmain_1", false, 0),
+                (".Lstr0", false, 0),
+                ("main", true, 23),
+                ("p", true, 0)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_jump_table() -> Result<(), String> {
+        let object = assemble_lines(&[
+            "f:",
+            ".Lf_1:",
+            "ret",
+            ".Lf_2:",
+            "ret",
+            ".section .rodata",
+            ".align 4",
+            ".Lf_0_table:",
+            ".long .Lf_2-.Lf_0_table",
+            ".long .Lf_1-.Lf_0_table",
+            ".text",
+            ".Lf_3_table:",
+            ".long .Lf_2-.Lf_3_table",
+        ])?;
+        // Entries for labels in another section are left to the linker, and otherwise
+        // patched in
+        let rodata = &object.sections[2];
+        assert_eq!(rodata.data, [0; 8]);
+        let relocation = |offset, symbol: &str, addend| Relocation {
+            offset,
+            symbol: symbol.to_owned(),
+            kind: R_X86_64_PC32,
+            addend,
+        };
+        assert_eq!(
+            rodata.relocations,
+            vec![relocation(0, ".Lf_2", 0), relocation(4, ".Lf_1", 4)]
+        );
+        assert_eq!(
+            object.sections[0].data,
+            [0xc3, 0xc3, 0xff, 0xff, 0xff, 0xff]
+        );
+
+        let before = assemble_lines(&[".long .Lf_1-.Lf_0_table", ".Lf_0_table:"]);
+        assert!(before.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_got_relocation() -> Result<(), String> {
+        let object = assemble_lines(&["mov x@GOTPCREL(%rip), %rbx"])?;
+        assert_eq!(object.sections[0].data, [0x48, 0x8b, 0x1d, 0, 0, 0, 0]);
+        assert_eq!(
+            object.sections[0].relocations,
+            vec![Relocation {
+                offset: 3,
+                symbol: "x".to_owned(),
+                kind: R_X86_64_GOTPCREL,
+                addend: -4,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(
+            assemble_lines(&["xchg %rax, %rbx"]).map(|_| ()),
+            Err(CompileError::CodegenError(
+                "Unsupported instruction in `xchg %rax, %rbx`".to_owned()
+            ))
+        );
+        assert!(assemble_lines(&["mov %eax, %rbx"]).is_err());
+        assert!(assemble_lines(&["f:", ".size f, 8"]).is_err());
+        assert!(assemble_lines(&[".size f, .-f"]).is_err());
+    }
+}
@@ -1,7 +1,12 @@
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
 use crate::tokenizer::Token;
+use std::collections::HashMap;
+use std::fmt;
 
 #[allow(dead_code)]
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum BinOp {
     Add,
     Sub,
@@ -9,11 +14,33 @@ pub enum BinOp {
     Div,
     Assign,
     Equals,
+    NotEquals,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    LogicalAnd,
+    LogicalOr,
+    ShiftLeft,
+    ShiftRight,
+    BitAnd,
+    BitOr,
+    BitXor,
+    // Compound assignments, rewritten to `Assign` by the desugar pass
+    AddAssign,
+    SubAssign,
+    MulAssign,
+    DivAssign,
+    ShiftLeftAssign,
+    ShiftRightAssign,
+    BitAndAssign,
+    BitOrAssign,
+    BitXorAssign,
 }
 
 #[allow(dead_code)]
 impl BinOp {
-    pub fn from_token(token: &Token) -> Result<BinOp, String> {
+    pub fn from_token(token: &Token) -> Result<BinOp, CompileError> {
         match token {
             Token::Operator("+") => Ok(BinOp::Add),
             Token::Operator("-") => Ok(BinOp::Sub),
@@ -21,7 +48,31 @@ impl BinOp {
             Token::Operator("/") => Ok(BinOp::Div),
             Token::Operator("=") => Ok(BinOp::Assign),
             Token::Operator("==") => Ok(BinOp::Equals),
-            _ => Err(format!("Cannot construct BinOp from {:?}", token)),
+            Token::Operator("!=") => Ok(BinOp::NotEquals),
+            Token::Operator("<") => Ok(BinOp::Less),
+            Token::Operator("<=") => Ok(BinOp::LessEqual),
+            Token::Operator(">") => Ok(BinOp::Greater),
+            Token::Operator(">=") => Ok(BinOp::GreaterEqual),
+            Token::Operator("&&") => Ok(BinOp::LogicalAnd),
+            Token::Operator("||") => Ok(BinOp::LogicalOr),
+            Token::Operator("<<") => Ok(BinOp::ShiftLeft),
+            Token::Operator(">>") => Ok(BinOp::ShiftRight),
+            Token::Operator("&") => Ok(BinOp::BitAnd),
+            Token::Operator("|") => Ok(BinOp::BitOr),
+            Token::Operator("^") => Ok(BinOp::BitXor),
+            Token::Operator("+=") => Ok(BinOp::AddAssign),
+            Token::Operator("-=") => Ok(BinOp::SubAssign),
+            Token::Operator("*=") => Ok(BinOp::MulAssign),
+            Token::Operator("/=") => Ok(BinOp::DivAssign),
+            Token::Operator("<<=") => Ok(BinOp::ShiftLeftAssign),
+            Token::Operator(">>=") => Ok(BinOp::ShiftRightAssign),
+            Token::Operator("&=") => Ok(BinOp::BitAndAssign),
+            Token::Operator("|=") => Ok(BinOp::BitOrAssign),
+            Token::Operator("^=") => Ok(BinOp::BitXorAssign),
+            _ => Err(CompileError::ParseError {
+                message: format!("Cannot construct BinOp from {:?}", token),
+                span: None,
+            }),
         }
     }
 
@@ -32,7 +83,137 @@ impl BinOp {
             BinOp::Mul => 40,
             BinOp::Div => 40,
             BinOp::Assign => 10,
-            BinOp::Equals => 20,
+            BinOp::LogicalOr => 12,
+            BinOp::LogicalAnd => 14,
+            // The bitwise operators bind more loosely than ==, so `x & 1 == 0` is x & (1 == 0)
+            BinOp::BitOr => 16,
+            BinOp::BitXor => 17,
+            BinOp::BitAnd => 18,
+            BinOp::Equals | BinOp::NotEquals => 20,
+            BinOp::Less | BinOp::LessEqual | BinOp::Greater | BinOp::GreaterEqual => 25,
+            BinOp::ShiftLeft | BinOp::ShiftRight => 27,
+            BinOp::AddAssign
+            | BinOp::SubAssign
+            | BinOp::MulAssign
+            | BinOp::DivAssign
+            | BinOp::ShiftLeftAssign
+            | BinOp::ShiftRightAssign
+            | BinOp::BitAndAssign
+            | BinOp::BitOrAssign
+            | BinOp::BitXorAssign => 10,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Assign => "=",
+            BinOp::Equals => "==",
+            BinOp::NotEquals => "!=",
+            BinOp::Less => "<",
+            BinOp::LessEqual => "<=",
+            BinOp::Greater => ">",
+            BinOp::GreaterEqual => ">=",
+            BinOp::LogicalAnd => "&&",
+            BinOp::LogicalOr => "||",
+            BinOp::ShiftLeft => "<<",
+            BinOp::ShiftRight => ">>",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::AddAssign => "+=",
+            BinOp::SubAssign => "-=",
+            BinOp::MulAssign => "*=",
+            BinOp::DivAssign => "/=",
+            BinOp::ShiftLeftAssign => "<<=",
+            BinOp::ShiftRightAssign => ">>=",
+            BinOp::BitAndAssign => "&=",
+            BinOp::BitOrAssign => "|=",
+            BinOp::BitXorAssign => "^=",
+        }
+    }
+
+    // Operators that compute an int from two ints: + - * / and the bitwise ones
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            BinOp::Add
+                | BinOp::Sub
+                | BinOp::Mul
+                | BinOp::Div
+                | BinOp::ShiftLeft
+                | BinOp::ShiftRight
+                | BinOp::BitAnd
+                | BinOp::BitOr
+                | BinOp::BitXor
+        )
+    }
+
+    // Operators that compare their operands, yielding 1 or 0
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinOp::Equals
+                | BinOp::NotEquals
+                | BinOp::Less
+                | BinOp::LessEqual
+                | BinOp::Greater
+                | BinOp::GreaterEqual
+        )
+    }
+
+    pub fn is_assignment(&self) -> bool {
+        *self == BinOp::Assign || self.compound_operator().is_some()
+    }
+
+    // For a compound assignment like `+=`, the arithmetic operator it applies.
+    pub fn compound_operator(&self) -> Option<BinOp> {
+        match self {
+            BinOp::AddAssign => Some(BinOp::Add),
+            BinOp::SubAssign => Some(BinOp::Sub),
+            BinOp::MulAssign => Some(BinOp::Mul),
+            BinOp::DivAssign => Some(BinOp::Div),
+            BinOp::ShiftLeftAssign => Some(BinOp::ShiftLeft),
+            BinOp::ShiftRightAssign => Some(BinOp::ShiftRight),
+            BinOp::BitAndAssign => Some(BinOp::BitAnd),
+            BinOp::BitOrAssign => Some(BinOp::BitOr),
+            BinOp::BitXorAssign => Some(BinOp::BitXor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,    // logical `!`
+    BitNot, // `~`
+    AddrOf, // `&`
+    Deref,  // `*`
+}
+
+impl UnaryOp {
+    pub fn from_token(token: &Token) -> Option<UnaryOp> {
+        match token {
+            Token::Operator("-") => Some(UnaryOp::Neg),
+            Token::Operator("!") => Some(UnaryOp::Not),
+            Token::Operator("~") => Some(UnaryOp::BitNot),
+            Token::Operator("&") => Some(UnaryOp::AddrOf),
+            Token::Operator("*") => Some(UnaryOp::Deref),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+            UnaryOp::AddrOf => "&",
+            UnaryOp::Deref => "*",
         }
     }
 }
@@ -41,10 +222,114 @@ pub struct ScopeIdCounter {
     pub counter: u32,
 }
 
+/*
+ * Every expression, statement, and declaration gets a NodeId that is unique within the
+ * program. Like scope ids, they're handed out bottom-up: a node's id is always larger than
+ * the ids of the nodes inside it. Later passes record facts about nodes (types, spans, ...)
+ * in a NodeTable instead of adding fields to the AST.
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NodeId(pub u32);
+
+pub type NodeTable<T> = HashMap<NodeId, T>;
+
+pub struct NodeIdCounter {
+    pub counter: u32,
+}
+
+impl NodeIdCounter {
+    // Not an Iterator, since it never runs out
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> NodeId {
+        self.counter += 1;
+        NodeId(self.counter)
+    }
+
+    // A counter that continues after every id already used in `declarations`. Passes after
+    // desugaring can't rely on ids being bottom-up, so this walks the whole tree.
+    pub fn after(declarations: &[Declaration]) -> Self {
+        fn max_expr(expr: &Expr) -> u32 {
+            let children = match &expr.kind {
+                ExprKind::BinaryOperation { left, right, .. } => {
+                    max_expr(left).max(max_expr(right))
+                }
+                ExprKind::Call { args, .. } => args.iter().map(max_expr).max().unwrap_or(0),
+                ExprKind::UnaryOperation { expr, .. } | ExprKind::Cast { expr, .. } => {
+                    max_expr(expr)
+                }
+                _ => 0,
+            };
+            expr.id.0.max(children)
+        }
+        fn max_scope(scope: &Scope) -> u32 {
+            scope
+                .statements
+                .iter()
+                .map(max_statement)
+                .max()
+                .unwrap_or(0)
+        }
+        fn max_statement(stmt: &Statement) -> u32 {
+            let children = match &stmt.kind {
+                StatementKind::Return(expr) => expr.as_ref().map_or(0, max_expr),
+                StatementKind::Expression(expr) => max_expr(expr),
+                StatementKind::VarDeclare { value, .. } => value.as_ref().map_or(0, max_expr),
+                StatementKind::If {
+                    condition,
+                    true_block,
+                    false_block,
+                } => max_expr(condition)
+                    .max(max_scope(true_block))
+                    .max(false_block.as_ref().map_or(0, max_scope)),
+                StatementKind::While { condition, body } => {
+                    max_expr(condition).max(max_scope(body))
+                }
+                StatementKind::Switch { value, body } => max_expr(value).max(max_scope(body)),
+                StatementKind::Case(value) => max_expr(value),
+                StatementKind::Block(block) => max_scope(block),
+                StatementKind::Break | StatementKind::Continue | StatementKind::Default => 0,
+                StatementKind::For {
+                    init,
+                    condition,
+                    step,
+                    body,
+                } => init
+                    .as_deref()
+                    .map_or(0, max_statement)
+                    .max(condition.as_ref().map_or(0, max_expr))
+                    .max(step.as_ref().map_or(0, max_expr))
+                    .max(max_scope(body)),
+            };
+            stmt.id.0.max(children)
+        }
+
+        let counter = declarations
+            .iter()
+            .map(|dec| match dec {
+                Declaration::Function { id, scope, .. } => id.0.max(max_scope(scope)),
+                Declaration::Global { id, value, .. } => {
+                    id.0.max(value.as_ref().map_or(0, max_expr))
+                }
+                Declaration::Enum { id, variants, .. } => variants
+                    .iter()
+                    .filter_map(|(_, value)| value.as_ref())
+                    .map(max_expr)
+                    .fold(id.0, u32::max),
+                dec => dec.id().0,
+            })
+            .max()
+            .unwrap_or(0);
+        NodeIdCounter { counter }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Scope {
     pub id: u32,
     pub statements: Vec<Statement>,
+    // The source the scope covers, from its opening brace to its closing one, if it came
+    // from the source. A function's root scope starts at its parameter list.
+    pub span: Option<Span>,
 }
 
 #[allow(dead_code)]
@@ -54,37 +339,212 @@ impl Scope {
         Scope {
             id: id_counter.counter,
             statements,
+            span: None,
         }
     }
+
+    // The innermost scope under this one whose span contains `offset`. Scopes the desugar
+    // pass made up have no span, and are looked through to the ones inside them.
+    pub fn innermost_at(&self, offset: usize) -> Option<u32> {
+        if let Some(span) = self.span
+            && !(span.start..span.end).contains(&offset)
+        {
+            return None;
+        }
+        let inner = self
+            .statements
+            .iter()
+            .flat_map(|s| s.child_scopes())
+            .find_map(|child| child.innermost_at(offset));
+        inner.or(self.span.map(|_| self.id))
+    }
 }
 
-#[derive(PartialEq, Debug)]
-pub enum Expr {
-    IntLiteral(u64),
+// The id of the innermost scope containing the byte `offset`, or None outside every
+// function body
+pub fn scope_at(declarations: &[Declaration], offset: usize) -> Option<u32> {
+    declarations.iter().find_map(|dec| match dec {
+        Declaration::Function { scope, .. } => scope.innermost_at(offset),
+        _ => None,
+    })
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Expr {
+    pub id: NodeId,
+    pub kind: ExprKind,
+    pub span: Option<Span>, // the source the expression was parsed from, if it was
+}
+
+impl Expr {
+    pub fn new(id: NodeId, kind: ExprKind) -> Self {
+        Expr {
+            id,
+            kind,
+            span: None,
+        }
+    }
+
+    // Whether the expression designates a storage location that can be assigned to
+    pub fn is_lvalue(&self) -> bool {
+        matches!(
+            self.kind,
+            ExprKind::Variable(_)
+                | ExprKind::UnaryOperation {
+                    op: UnaryOp::Deref,
+                    ..
+                }
+        )
+    }
+
+    // Whether evaluating the expression does anything besides produce a value, which is
+    // the case if there's an assignment or a call anywhere in it
+    pub fn has_side_effects(&self) -> bool {
+        match &self.kind {
+            ExprKind::BinaryOperation { op, left, right } => {
+                op.is_assignment() || left.has_side_effects() || right.has_side_effects()
+            }
+            ExprKind::UnaryOperation { expr, .. } | ExprKind::Cast { expr, .. } => {
+                expr.has_side_effects()
+            }
+            ExprKind::Call { .. } => true,
+            ExprKind::IntLiteral(_) | ExprKind::StringLiteral(_) | ExprKind::Variable(_) => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ExprKind {
+    IntLiteral(i64),
     StringLiteral(String),
     // TODO: CharLiteral,
-    Variable(String),
+    Variable(Symbol),
     BinaryOperation {
         op: BinOp,
         left: Box<Expr>,
         right: Box<Expr>,
     },
+    UnaryOperation {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Call {
+        name: Symbol,
+        args: Vec<Expr>,
+    },
+    // Implicit conversion, inserted by the type checker
+    Cast {
+        target: Type,
+        expr: Box<Expr>,
+    },
 }
 
 #[derive(PartialEq, Debug)]
-pub enum Statement {
-    Return(Expr),
+pub struct Statement {
+    pub id: NodeId,
+    pub kind: StatementKind,
+    pub span: Option<Span>, // where the statement starts, if it came from the source
+}
+
+impl Statement {
+    pub fn new(id: NodeId, kind: StatementKind) -> Self {
+        Statement {
+            id,
+            kind,
+            span: None,
+        }
+    }
+
+    // The expressions directly in this statement, not counting nested scopes
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match &self.kind {
+            StatementKind::Return(expr) | StatementKind::VarDeclare { value: expr, .. } => {
+                expr.iter().collect()
+            }
+            StatementKind::Expression(expr)
+            | StatementKind::If {
+                condition: expr, ..
+            }
+            | StatementKind::While {
+                condition: expr, ..
+            }
+            | StatementKind::Switch { value: expr, .. }
+            | StatementKind::Case(expr) => vec![expr],
+            StatementKind::For {
+                condition, step, ..
+            } => condition.iter().chain(step).collect(),
+            StatementKind::Block(_)
+            | StatementKind::Break
+            | StatementKind::Continue
+            | StatementKind::Default => vec![],
+        }
+    }
+
+    // The scopes nested directly inside this statement, in source order
+    pub fn child_scopes(&self) -> Vec<&Scope> {
+        match &self.kind {
+            StatementKind::If {
+                true_block,
+                false_block,
+                ..
+            } => std::iter::once(true_block).chain(false_block).collect(),
+            StatementKind::While { body, .. }
+            | StatementKind::Switch { body, .. }
+            | StatementKind::Block(body)
+            | StatementKind::For { body, .. } => vec![body],
+            StatementKind::Return(_)
+            | StatementKind::Expression(_)
+            | StatementKind::VarDeclare { .. }
+            | StatementKind::Break
+            | StatementKind::Continue
+            | StatementKind::Case(_)
+            | StatementKind::Default => vec![],
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum StatementKind {
+    Return(Option<Expr>),
     Expression(Expr),
     VarDeclare {
-        name: String,
+        name: Symbol,
         var_type: Type,
         value: Option<Expr>,
+        span: Span, // location of the variable's name
     },
     If {
         condition: Expr,
         true_block: Scope,
         false_block: Option<Scope>,
     },
+    While {
+        condition: Expr,
+        body: Scope,
+    },
+    // Jumps to the label in the body whose case matches the value, or to the default label,
+    // or past the body if there's neither. Control falls through from one label's
+    // statements to the next.
+    Switch {
+        value: Expr,
+        body: Scope,
+    },
+    // Labels, only allowed directly in a switch's body. A case's value must be a constant.
+    Case(Expr),
+    Default,
+    // A bare `{ ... }` nested inside another scope
+    Block(Scope),
+    // Break leaves the innermost enclosing loop or switch, and continue goes on to the next
+    // iteration of the innermost enclosing loop
+    Break,
+    Continue,
+    // Rewritten to a Block containing a While by the desugar pass
+    For {
+        init: Option<Box<Statement>>,
+        condition: Option<Expr>,
+        step: Option<Expr>,
+        body: Scope,
+    },
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -92,22 +552,126 @@ pub enum Type {
     Void,
     Int,
     Char,
-    UserDefined(String),
-    // TODO: float, ptr, etc.
+    Unsigned,            // unsigned int
+    UserDefined(Symbol), // a typedef name
+    Struct(Symbol),
+    Enum(Symbol),
+    Pointer(Box<Type>),
+    // TODO: float, arrays, etc.
+}
+
+impl Type {
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            Type::Int | Type::Char | Type::Unsigned | Type::Enum(_)
+        )
+    }
+
+    // Whether values of the type divide and compare as unsigned numbers
+    pub fn is_unsigned(&self) -> bool {
+        matches!(self, Type::Unsigned | Type::Pointer(_))
+    }
+
+    // Types that can be tested for truthiness in a condition
+    pub fn is_scalar(&self) -> bool {
+        self.is_integer() || matches!(self, Type::Pointer(_))
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Void => write!(f, "void"),
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Unsigned => write!(f, "unsigned"),
+            Type::UserDefined(name) => write!(f, "{}", name),
+            Type::Struct(name) => write!(f, "struct {}", name),
+            Type::Enum(name) => write!(f, "enum {}", name),
+            Type::Pointer(inner) => write!(f, "{}*", inner),
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
 pub enum Declaration {
     Function {
-        name: String,
+        id: NodeId,
+        span: Span, // location of the function's name
+        name: Symbol,
         args: Vec<VarInfo>,
         return_type: Type,
         scope: Scope,
+        // Declared `inline`: a hint to inline calls to it, whatever its size
+        inline: bool,
+    },
+    // A declaration without a body, for a function that another file or a library defines.
+    // A variadic one takes any number of arguments after `args`, as in `int printf(char
+    // *format, ...);`.
+    Prototype {
+        id: NodeId,
+        span: Span, // location of the function's name
+        name: Symbol,
+        args: Vec<VarInfo>,
+        return_type: Type,
+        variadic: bool,
+    },
+    Struct {
+        id: NodeId,
+        span: Span, // location of the struct's tag
+        name: Symbol,
+        members: Vec<VarInfo>,
+    },
+    Enum {
+        id: NodeId,
+        span: Span,
+        name: Symbol,
+        variants: Vec<(Symbol, Option<Expr>)>, // enumerators, with their values if given
+    },
+    Typedef {
+        id: NodeId,
+        span: Span, // location of the new type name
+        name: Symbol,
+        target: Type,
+    },
+    // A variable at file scope. Its initializer, if any, has to be a constant.
+    Global {
+        id: NodeId,
+        span: Span, // location of the variable's name
+        name: Symbol,
+        var_type: Type,
+        value: Option<Expr>,
     },
 }
 
-#[derive(Debug, PartialEq)]
+impl Declaration {
+    pub fn id(&self) -> NodeId {
+        match self {
+            Declaration::Function { id, .. }
+            | Declaration::Prototype { id, .. }
+            | Declaration::Struct { id, .. }
+            | Declaration::Enum { id, .. }
+            | Declaration::Typedef { id, .. }
+            | Declaration::Global { id, .. } => *id,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Declaration::Function { span, .. }
+            | Declaration::Prototype { span, .. }
+            | Declaration::Struct { span, .. }
+            | Declaration::Enum { span, .. }
+            | Declaration::Typedef { span, .. }
+            | Declaration::Global { span, .. } => *span,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct VarInfo {
-    pub name: String,
+    pub name: Symbol,
     pub var_type: Type,
+    pub span: Span, // location of the name in its declaration
 }
@@ -0,0 +1,295 @@
+use crate::ast::*;
+
+/*
+ * Deterministic S-expression dump of the AST, used by `--dump-ast` and the golden
+ * files under test/. Each function is printed as
+ *   (fn <name> <return type> (<args>)
+ *     <statement>
+ *     ...)
+ * with one statement per line, e.g. `int main() { return 0; }` becomes
+ *   (fn main int ()
+ *     (ret (int 0)))
+ * Scope ids are deliberately left out so the dump doesn't change when the parser
+ * allocates ids differently.
+ */
+
+const INDENT: &str = "  ";
+
+pub fn dump(declarations: &[Declaration]) -> String {
+    let mut out = String::new();
+    for dec in declarations {
+        dump_declaration(dec, &mut out);
+        out.push('\n');
+    }
+    out
+}
+
+fn dump_declaration(dec: &Declaration, out: &mut String) {
+    let (name, args, return_type, scope, inline) = match dec {
+        Declaration::Function {
+            name,
+            args,
+            return_type,
+            scope,
+            inline,
+            ..
+        } => (name, args, return_type, scope, *inline),
+        Declaration::Prototype {
+            name,
+            args,
+            return_type,
+            variadic,
+            ..
+        } => {
+            let mut args = dump_args(args);
+            if *variadic {
+                args.push_str(" ...");
+            }
+            out.push_str(&format!("(declare {} {} ({}))", name, return_type, args));
+            return;
+        }
+        Declaration::Struct { name, members, .. } => {
+            let members: Vec<String> = members
+                .iter()
+                .map(|m| format!("({} {})", m.var_type, m.name))
+                .collect();
+            out.push_str(&format!("(struct {} ({}))", name, members.join(" ")));
+            return;
+        }
+        Declaration::Enum { name, variants, .. } => {
+            let variants: Vec<String> = variants
+                .iter()
+                .map(|(variant, value)| match value {
+                    Some(value) => format!("(= {} {})", variant, dump_expr(value)),
+                    None => variant.to_string(),
+                })
+                .collect();
+            out.push_str(&format!("(enum {} ({}))", name, variants.join(" ")));
+            return;
+        }
+        Declaration::Typedef { name, target, .. } => {
+            out.push_str(&format!("(typedef {} {})", target, name));
+            return;
+        }
+        Declaration::Global {
+            name,
+            var_type,
+            value,
+            ..
+        } => {
+            match value {
+                Some(value) => out.push_str(&format!(
+                    "(global {} {} {})",
+                    var_type,
+                    name,
+                    dump_expr(value)
+                )),
+                None => out.push_str(&format!("(global {} {})", var_type, name)),
+            }
+            return;
+        }
+    };
+
+    if inline {
+        out.push_str("(inline ");
+    }
+    out.push_str(&format!(
+        "(fn {} {} ({})",
+        name,
+        return_type,
+        dump_args(args)
+    ));
+    dump_statements(&scope.statements, 1, out);
+    out.push(')');
+    if inline {
+        out.push(')');
+    }
+}
+
+fn dump_args(args: &[VarInfo]) -> String {
+    args.iter()
+        .map(|a| format!("({} {})", a.var_type, a.name))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn dump_statements(statements: &[Statement], depth: usize, out: &mut String) {
+    for s in statements {
+        out.push('\n');
+        out.push_str(&INDENT.repeat(depth));
+        dump_statement(s, depth, out);
+    }
+}
+
+fn dump_statement(stmt: &Statement, depth: usize, out: &mut String) {
+    match &stmt.kind {
+        StatementKind::Return(Some(expr)) => out.push_str(&format!("(ret {})", dump_expr(expr))),
+        StatementKind::Return(None) => out.push_str("(ret)"),
+        StatementKind::Expression(expr) => out.push_str(&format!("(expr {})", dump_expr(expr))),
+        StatementKind::VarDeclare {
+            name,
+            var_type,
+            value: Some(value),
+            ..
+        } => out.push_str(&format!(
+            "(decl {} {} {})",
+            var_type,
+            name,
+            dump_expr(value)
+        )),
+        StatementKind::VarDeclare {
+            name,
+            var_type,
+            value: None,
+            ..
+        } => out.push_str(&format!("(decl {} {})", var_type, name)),
+        StatementKind::If {
+            condition,
+            true_block,
+            false_block,
+        } => {
+            out.push_str(&format!("(if {}", dump_expr(condition)));
+            dump_block("then", true_block, depth + 1, out);
+            if let Some(false_scope) = false_block {
+                dump_block("else", false_scope, depth + 1, out);
+            }
+            out.push(')');
+        }
+        StatementKind::While { condition, body } => {
+            out.push_str(&format!("(while {}", dump_expr(condition)));
+            dump_statements(&body.statements, depth + 1, out);
+            out.push(')');
+        }
+        StatementKind::Switch { value, body } => {
+            out.push_str(&format!("(switch {}", dump_expr(value)));
+            dump_statements(&body.statements, depth + 1, out);
+            out.push(')');
+        }
+        StatementKind::Case(value) => out.push_str(&format!("(case {})", dump_expr(value))),
+        StatementKind::Default => out.push_str("(default)"),
+        StatementKind::Block(scope) => {
+            out.push_str("(block");
+            dump_statements(&scope.statements, depth + 1, out);
+            out.push(')');
+        }
+        StatementKind::Break => out.push_str("(break)"),
+        StatementKind::Continue => out.push_str("(continue)"),
+        StatementKind::For {
+            init,
+            condition,
+            step,
+            body,
+        } => {
+            let init = match init {
+                Some(init) => {
+                    let mut s = String::new();
+                    dump_statement(init, depth, &mut s);
+                    s
+                }
+                None => "()".to_owned(),
+            };
+            let condition = condition.as_ref().map_or("()".to_owned(), dump_expr);
+            let step = step.as_ref().map_or("()".to_owned(), dump_expr);
+            out.push_str(&format!("(for {} {} {}", init, condition, step));
+            dump_statements(&body.statements, depth + 1, out);
+            out.push(')');
+        }
+    }
+}
+
+fn dump_block(label: &str, scope: &Scope, depth: usize, out: &mut String) {
+    out.push('\n');
+    out.push_str(&INDENT.repeat(depth));
+    out.push_str(&format!("({}", label));
+    dump_statements(&scope.statements, depth + 1, out);
+    out.push(')');
+}
+
+pub fn dump_expr(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::IntLiteral(i) => format!("(int {})", i),
+        ExprKind::StringLiteral(s) => format!("(str {:?})", s),
+        ExprKind::Variable(name) => format!("(var {})", name),
+        ExprKind::BinaryOperation { op, left, right } => {
+            format!("({} {} {})", op.as_str(), dump_expr(left), dump_expr(right))
+        }
+        ExprKind::UnaryOperation { op, expr } => format!("({} {})", op.as_str(), dump_expr(expr)),
+        ExprKind::Call { name, args } => {
+            let args: Vec<String> = args.iter().map(dump_expr).collect();
+            match args.is_empty() {
+                true => format!("(call {})", name),
+                false => format!("(call {} {})", name, args.join(" ")),
+            }
+        }
+        ExprKind::Cast { target, expr } => format!("(cast {} {})", target, dump_expr(expr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+    use std::fs::{read_dir, read_to_string};
+    use std::path::Path;
+
+    fn dump_source(source: &str) -> Result<String, String> {
+        let tokens = tokenize(source)?;
+        Ok(dump(&parse(&tokens)?))
+    }
+
+    #[test]
+    fn test_dump_return() -> Result<(), String> {
+        assert_eq!(
+            dump_source("int main() { return 0; }")?,
+            "(fn main int ()\n  (ret (int 0)))\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_if_else() -> Result<(), String> {
+        let expected = "\
+(fn main int ()
+  (decl int x)
+  (decl MyType y (str \"text\"))
+  (if (== (var x) (int 1))
+    (then
+      (expr (= (var x) (+ (int 1) (* (int 2) (int 3))))))
+    (else
+      (ret (var x)))))
+";
+        assert_eq!(
+            dump_source(
+                "int main() { int x; MyType y = \"text\"; \
+                 if (x == 1) { x = 1 + 2 * 3; } else { return x; } }"
+            )?,
+            expected
+        );
+        Ok(())
+    }
+
+    // Every test/<name>.c that has a test/<name>.ast next to it must dump to exactly
+    // the contents of that golden file.
+    #[test]
+    fn test_golden_ast_dumps() -> Result<(), String> {
+        let mut checked = 0;
+        for entry in read_dir("test").map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().is_none_or(|ext| ext != "c") {
+                continue;
+            }
+            let golden = path.with_extension("ast");
+            if !Path::exists(&golden) {
+                continue;
+            }
+
+            let source = read_to_string(&path).map_err(|e| e.to_string())?;
+            let expected = read_to_string(&golden).map_err(|e| e.to_string())?;
+            assert_eq!(dump_source(&source)?, expected, "{}", golden.display());
+            checked += 1;
+        }
+        assert!(checked > 0);
+        Ok(())
+    }
+}
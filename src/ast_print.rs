@@ -0,0 +1,329 @@
+use crate::ast::*;
+
+/*
+ * Prints the AST back out as C that parses to the same tree. Bodies always get braces,
+ * and an expression only gets parentheses where the parser would otherwise group it
+ * differently: every binary operator associates to the left, so a right operand of the
+ * same precedence needs them, and a unary operator only takes a primary expression.
+ *
+ * Implicit casts are left out, since they're what the type checker inserts and the source
+ * never spells them.
+ */
+
+const INDENT: &str = "    ";
+
+pub fn print(declarations: &[Declaration]) -> String {
+    let mut out = String::new();
+    for dec in declarations {
+        print_declaration(dec, &mut out);
+    }
+    out
+}
+
+fn print_declaration(dec: &Declaration, out: &mut String) {
+    match dec {
+        Declaration::Function {
+            name,
+            args,
+            return_type,
+            scope,
+            inline,
+            ..
+        } => {
+            if *inline {
+                out.push_str("inline ");
+            }
+            out.push_str(&format!("{} {}({}) ", return_type, name, print_args(args)));
+            print_scope(scope, 0, out);
+            out.push('\n');
+        }
+        Declaration::Prototype {
+            name,
+            args,
+            return_type,
+            variadic,
+            ..
+        } => {
+            let mut args = print_args(args);
+            if *variadic {
+                args.push_str(", ...");
+            }
+            out.push_str(&format!("{} {}({});\n", return_type, name, args));
+        }
+        Declaration::Struct { name, members, .. } => {
+            out.push_str(&format!("struct {} {{\n", name));
+            for member in members {
+                out.push_str(&format!("{}{} {};\n", INDENT, member.var_type, member.name));
+            }
+            out.push_str("};\n");
+        }
+        Declaration::Enum { name, variants, .. } => {
+            out.push_str(&format!("enum {} {{\n", name));
+            for (variant, value) in variants {
+                match value {
+                    Some(value) => {
+                        out.push_str(&format!("{}{} = {},\n", INDENT, variant, print_expr(value)))
+                    }
+                    None => out.push_str(&format!("{}{},\n", INDENT, variant)),
+                }
+            }
+            out.push_str("};\n");
+        }
+        Declaration::Typedef { name, target, .. } => {
+            out.push_str(&format!("typedef {} {};\n", target, name));
+        }
+        Declaration::Global {
+            name,
+            var_type,
+            value,
+            ..
+        } => out.push_str(&format!("{};\n", print_declare(name, var_type, value))),
+    }
+}
+
+fn print_args(args: &[VarInfo]) -> String {
+    args.iter()
+        .map(|a| format!("{} {}", a.var_type, a.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_declare(name: &str, var_type: &Type, value: &Option<Expr>) -> String {
+    match value {
+        Some(value) => format!("{} {} = {}", var_type, name, print_expr(value)),
+        None => format!("{} {}", var_type, name),
+    }
+}
+
+// The braces and statements of a scope, with the closing brace at `depth`
+fn print_scope(scope: &Scope, depth: usize, out: &mut String) {
+    out.push_str("{\n");
+    for stmt in &scope.statements {
+        print_statement(stmt, depth + 1, out);
+    }
+    out.push_str(&INDENT.repeat(depth));
+    out.push('}');
+}
+
+fn print_statement(stmt: &Statement, depth: usize, out: &mut String) {
+    // Labels sit back at the level of the switch they're in
+    let indent = match stmt.kind {
+        StatementKind::Case(_) | StatementKind::Default => depth.saturating_sub(1),
+        _ => depth,
+    };
+    out.push_str(&INDENT.repeat(indent));
+    match &stmt.kind {
+        StatementKind::If {
+            condition,
+            true_block,
+            false_block,
+        } => {
+            out.push_str(&format!("if ({}) ", print_condition(condition)));
+            print_scope(true_block, depth, out);
+            if let Some(false_block) = false_block {
+                out.push_str(" else ");
+                print_scope(false_block, depth, out);
+            }
+        }
+        StatementKind::While { condition, body } => {
+            out.push_str(&format!("while ({}) ", print_condition(condition)));
+            print_scope(body, depth, out);
+        }
+        StatementKind::Switch { value, body } => {
+            out.push_str(&format!("switch ({}) ", print_expr(value)));
+            print_scope(body, depth, out);
+        }
+        StatementKind::Block(scope) => print_scope(scope, depth, out),
+        StatementKind::For {
+            init,
+            condition,
+            step,
+            body,
+        } => {
+            let init = init
+                .as_deref()
+                .map_or(String::new(), print_simple_statement);
+            let condition = condition.as_ref().map_or(String::new(), print_expr);
+            let step = step.as_ref().map_or(String::new(), print_expr);
+            out.push_str(&format!("for ({}; {}; {}) ", init, condition, step));
+            print_scope(body, depth, out);
+        }
+        StatementKind::Case(value) => out.push_str(&format!("case {}:", print_expr(value))),
+        StatementKind::Default => out.push_str("default:"),
+        _ => out.push_str(&format!("{};", print_simple_statement(stmt))),
+    }
+    out.push('\n');
+}
+
+// A statement without a body, as it's written before its semicolon
+fn print_simple_statement(stmt: &Statement) -> String {
+    match &stmt.kind {
+        StatementKind::Return(Some(expr)) => format!("return {}", print_expr(expr)),
+        StatementKind::Return(None) => "return".to_owned(),
+        StatementKind::Expression(expr) => print_expr(expr),
+        StatementKind::VarDeclare {
+            name,
+            var_type,
+            value,
+            ..
+        } => print_declare(name, var_type, value),
+        StatementKind::Break => "break".to_owned(),
+        StatementKind::Continue => "continue".to_owned(),
+        _ => unreachable!("{:?} has a body", stmt.kind),
+    }
+}
+
+// An assignment as a condition gets a second pair of parentheses, which is how to say it's
+// meant to be one
+fn print_condition(condition: &Expr) -> String {
+    match &condition.kind {
+        ExprKind::BinaryOperation { op, .. } if op.is_assignment() => {
+            format!("({})", print_expr(condition))
+        }
+        _ => print_expr(condition),
+    }
+}
+
+pub fn print_expr(expr: &Expr) -> String {
+    match &expr.kind {
+        // A literal too big for an i64 was parsed by wrapping it around, so it's printed
+        // back the same way
+        ExprKind::IntLiteral(i) => (*i as u64).to_string(),
+        ExprKind::StringLiteral(s) => format!("\"{}\"", s),
+        ExprKind::Variable(name) => name.to_string(),
+        ExprKind::BinaryOperation { op, left, right } => {
+            let left = match precedence(left) < op.precedence() {
+                true => format!("({})", print_expr(left)),
+                false => print_expr(left),
+            };
+            let right = match precedence(right) <= op.precedence() {
+                true => format!("({})", print_expr(right)),
+                false => print_expr(right),
+            };
+            format!("{} {} {}", left, op.as_str(), right)
+        }
+        ExprKind::UnaryOperation { op, expr } => match expr.kind {
+            ExprKind::IntLiteral(_)
+            | ExprKind::StringLiteral(_)
+            | ExprKind::Variable(_)
+            | ExprKind::Call { .. } => format!("{}{}", op.as_str(), print_expr(expr)),
+            _ => format!("{}({})", op.as_str(), print_expr(expr)),
+        },
+        ExprKind::Call { name, args } => {
+            let args: Vec<String> = args.iter().map(print_expr).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+        ExprKind::Cast { expr, .. } => print_expr(expr),
+    }
+}
+
+// How tightly an expression holds together when it's an operand. Anything but a binary
+// operation is as tight as it gets.
+fn precedence(expr: &Expr) -> u32 {
+    match &expr.kind {
+        ExprKind::BinaryOperation { op, .. } => op.precedence(),
+        ExprKind::Cast { expr, .. } => precedence(expr),
+        _ => u32::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_dump::dump;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+    use std::fs::{read_dir, read_to_string};
+
+    // Printing the source's AST and parsing that again has to give the same tree. The
+    // dumps leave out ids and spans, which are bound to differ.
+    fn assert_round_trip(source: &str) -> Result<(), String> {
+        let ast = parse(&tokenize(source)?)?;
+        let printed = print(&ast);
+        let reparsed = parse(&tokenize(&printed)?).map_err(|e| format!("{}\n{}", e, printed))?;
+        assert_eq!(dump(&reparsed), dump(&ast), "printed as\n{}", printed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_print() -> Result<(), String> {
+        let source =
+            "int main() { int x = 1; if (x) return -x; else { x += 2; } return x * (x + 1); }";
+        let expected = "\
+int main() {
+    int x = 1;
+    if (x) {
+        return -x;
+    } else {
+        x += 2;
+    }
+    return x * (x + 1);
+}
+";
+        assert_eq!(print(&parse(&tokenize(source)?)?), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_expressions() -> Result<(), String> {
+        for expr in [
+            "a - (b - c)",
+            "(a - b) - c",
+            "a = b = c",
+            "a = (b = c)",
+            "-(a + b) * ~c",
+            "!!a",
+            "*&a",
+            "-(-a)",
+            "a & 1 == 0",
+            "(a & 1) == 0",
+            "a << 2 | b >> 1 ^ c",
+            "f(a, g(b + 1), \"s\") && a || b",
+            "18446744073709551615",
+        ] {
+            assert_round_trip(&format!("int main() {{ {}; }}", expr))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_declarations() -> Result<(), String> {
+        assert_round_trip(
+            "
+typedef int number;
+struct point { int x; char *name; };
+enum color { RED, GREEN = 4, BLUE, };
+unsigned int count = 3;
+char *greeting = \"hi\";
+int printf(char *format, ...);
+int f(void);
+inline int g(struct point *p, enum color c) { return c; }
+int main() {
+    number n;
+    for (int i = 0; i < 3; i += 1) { n = i; if ((n = 2)) break; }
+    for (;;) { continue; }
+    while (n) n = n - 1;
+    switch (n) { case 1: { n = 2; } case 2 + 1: break; default: return 0; }
+    { int scoped; }
+    return;
+}",
+        )
+    }
+
+    // Each test/*.c, so the printer keeps up with whatever the grammar grows to cover
+    #[test]
+    fn test_round_trip_test_files() -> Result<(), String> {
+        let mut checked = 0;
+        for entry in read_dir("test").map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().is_none_or(|ext| ext != "c") {
+                continue;
+            }
+            let source = read_to_string(&path).map_err(|e| e.to_string())?;
+            assert_round_trip(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+            checked += 1;
+        }
+        assert!(checked > 0);
+        Ok(())
+    }
+}
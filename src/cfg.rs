@@ -1,7 +1,18 @@
-use crate::ast;
+use crate::CompileOptions;
+use crate::ast::{self, NodeTable};
+use crate::constant::{self, Enumerators};
+use crate::diagnostic::DiagnosticSink;
+use crate::error::CompileError;
+use crate::ice;
+use crate::intern::Symbol;
+use crate::liveness::Liveness;
+use crate::span::Span;
 use crate::symbol_table::VarName;
-use std::collections::HashMap;
-use std::ops::Deref;
+
+use crate::type_table::TypeTable;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
 
 // Defines the Control Flow GRaph types
 /*
@@ -10,7 +21,7 @@ use std::ops::Deref;
   v1 = 5
   v2 = 10
   v3 = v1 + v2
-  HashMap<id, ControlBlock>
+  BTreeMap<id, ControlBlock>
   Statements:
     - if var then goto A else goto B
     - goto
@@ -18,216 +29,2085 @@ use std::ops::Deref;
     - binary operations
     - return var
 */
-pub type CfgVarName = String;
 pub type ControlBlockId = u64;
 
+// A virtual register, printed as v1, v2, and so on. A function's vars are numbered from 1,
+// so tables of them can be indexed by number rather than hashed.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct VReg(pub u32);
+
+impl VReg {
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+// The vars separated by commas, as in a call's arguments
+pub fn join_vars<'a>(vars: impl IntoIterator<Item = &'a VReg>) -> String {
+    let vars: Vec<String> = vars.into_iter().map(VReg::to_string).collect();
+    vars.join(", ")
+}
+
+// A table from vars to values, held in a vector indexed by the var's number
+#[derive(Clone, Debug, PartialEq)]
+pub struct VRegMap<T> {
+    values: Vec<Option<T>>,
+}
+
+impl<T> Default for VRegMap<T> {
+    fn default() -> Self {
+        VRegMap { values: vec![] }
+    }
+}
+
+impl<T> VRegMap<T> {
+    pub fn new() -> Self {
+        VRegMap::default()
+    }
+
+    pub fn insert(&mut self, var: VReg, value: T) -> Option<T> {
+        if var.index() >= self.values.len() {
+            self.values.resize_with(var.index() + 1, || None);
+        }
+        self.values[var.index()].replace(value)
+    }
+
+    pub fn get(&self, var: &VReg) -> Option<&T> {
+        self.values.get(var.index())?.as_ref()
+    }
+
+    pub fn contains_key(&self, var: &VReg) -> bool {
+        self.get(var).is_some()
+    }
+
+    // In order of the vars' numbers
+    pub fn iter(&self) -> impl Iterator<Item = (VReg, &T)> {
+        self.values
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| Some((VReg(i as u32), value.as_ref()?)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.values.iter().flatten()
+    }
+
+    pub fn into_values(self) -> impl Iterator<Item = T> {
+        self.values.into_iter().flatten()
+    }
+}
+
+impl<T> std::ops::Index<&VReg> for VRegMap<T> {
+    type Output = T;
+
+    fn index(&self, var: &VReg) -> &T {
+        self.get(var)
+            .unwrap_or_else(|| panic!("{} has no entry in the table", var))
+    }
+}
+
+impl<T> Extend<(VReg, T)> for VRegMap<T> {
+    fn extend<I: IntoIterator<Item = (VReg, T)>>(&mut self, iter: I) {
+        for (var, value) in iter {
+            self.insert(var, value);
+        }
+    }
+}
+
+impl<T> FromIterator<(VReg, T)> for VRegMap<T> {
+    fn from_iter<I: IntoIterator<Item = (VReg, T)>>(iter: I) -> Self {
+        let mut map = VRegMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum BinOp {
     Add,
     Sub,
     Mul,
     Div,
+    Shl,
+    // Arithmetic: the sign bit fills in from the left, as for a negative int
+    Shr,
+    And,
+    Or,
+    Xor,
+    // Comparisons set dest to 1 if they hold and 0 otherwise
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // The same on the operands as unsigned numbers, which is how unsigned values and
+    // pointers compare. UShr is logical: zeros fill in from the left.
+    UDiv,
+    UShr,
+    ULt,
+    ULe,
+    UGt,
+    UGe,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not, // 1 if the operand is zero, 0 otherwise
+    BitNot,
+    Sext8,  // the operand's low byte, sign-extended: a conversion to char
+    Sext32, // the operand's low 32 bits, sign-extended: a conversion to int
+    Zext32, // the operand's low 32 bits, zero-extended: a conversion to unsigned
+}
+
+/*
+ * How many bytes of memory a Load or Store touches. A var always holds a full 64-bit value,
+ * so a char is sign-extended as it's loaded and only its low byte is stored.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Width {
+    I8,
+    I64,
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Statement {
-    // TODO: add in conditional support later
-    // If {
-    //     var: CfgVarName,
-    //     goto_true: ControlBlockId,
-    //     goto_false: ControlBlockId,
-    // },
+    // Branches on whether var is nonzero
+    If {
+        var: VReg,
+        goto_true: ControlBlockId,
+        goto_false: ControlBlockId,
+    },
     Goto(ControlBlockId),
+    // Goes to the block at index var in targets, or to default when var is past the end,
+    // taken as an unsigned number so that a negative one is too. A backend can jump through
+    // a table of the targets.
+    Switch {
+        var: VReg,
+        targets: Vec<ControlBlockId>,
+        default: ControlBlockId,
+    },
+    // Constants are held signed, though like every value they're just 64 bits
     Assign {
-        var: CfgVarName,
-        value: u64,
+        var: VReg,
+        value: i64,
+    },
+    Copy {
+        dest: VReg,
+        src: VReg,
     },
     Operation {
-        dest: CfgVarName,
+        dest: VReg,
         op: BinOp,
-        lhs: CfgVarName,
-        rhs: CfgVarName,
+        lhs: VReg,
+        rhs: VReg,
+    },
+    UnaryOperation {
+        dest: VReg,
+        op: UnaryOp,
+        operand: VReg,
+    },
+    // Calls func with the args in order, storing its return value in dest. A tail call is
+    // followed by a return of dest, so a backend can jump to func instead, leaving func to
+    // return straight to the caller.
+    Call {
+        dest: VReg,
+        func: Symbol,
+        args: Vec<VReg>,
+        tail: bool,
+    },
+    // Returns from the function, with the value of var if there is one
+    Return(Option<VReg>),
+    // dest holds the address of a new stack slot of `size` bytes, which lives until the
+    // function returns
+    Alloca {
+        dest: VReg,
+        size: u64,
+    },
+    // dest takes the value stored at the address in addr
+    Load {
+        dest: VReg,
+        addr: VReg,
+        width: Width,
     },
-    Return(CfgVarName),
+    // Stores src at the address in addr
+    Store {
+        addr: VReg,
+        src: VReg,
+        width: Width,
+    },
+    // dest takes the address of the program's data at label
+    LoadAddress {
+        dest: VReg,
+        label: Symbol,
+    },
+    // Only present in SSA form, at the start of a block: dest takes the var listed for
+    // whichever predecessor control arrived from
+    Phi {
+        dest: VReg,
+        sources: Vec<(ControlBlockId, VReg)>,
+    },
+    // The statements after this one come from this line of the source, for debug
+    // information. Only there when the program was lowered with line numbers.
+    Line(u32),
+}
+
+impl Statement {
+    // Whether control never falls through to the statement after this one
+    fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Statement::If { .. }
+                | Statement::Goto(_)
+                | Statement::Switch { .. }
+                | Statement::Return(_)
+        )
+    }
+
+    // The blocks this statement can transfer control to
+    pub fn successors(&self) -> Vec<ControlBlockId> {
+        match self {
+            Statement::If {
+                goto_true,
+                goto_false,
+                ..
+            } => vec![*goto_true, *goto_false],
+            Statement::Goto(block) => vec![*block],
+            Statement::Switch {
+                targets, default, ..
+            } => targets.iter().chain([default]).copied().collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn defined_var(&self) -> Option<&VReg> {
+        match self {
+            Statement::Assign { var, .. } => Some(var),
+            Statement::Copy { dest, .. }
+            | Statement::Operation { dest, .. }
+            | Statement::UnaryOperation { dest, .. }
+            | Statement::Call { dest, .. }
+            | Statement::Alloca { dest, .. }
+            | Statement::Load { dest, .. }
+            | Statement::LoadAddress { dest, .. }
+            | Statement::Phi { dest, .. } => Some(dest),
+            Statement::If { .. }
+            | Statement::Goto(_)
+            | Statement::Switch { .. }
+            | Statement::Return(_)
+            | Statement::Store { .. }
+            | Statement::Line(_) => None,
+        }
+    }
+
+    pub fn defined_var_mut(&mut self) -> Option<&mut VReg> {
+        match self {
+            Statement::Assign { var, .. } => Some(var),
+            Statement::Copy { dest, .. }
+            | Statement::Operation { dest, .. }
+            | Statement::UnaryOperation { dest, .. }
+            | Statement::Call { dest, .. }
+            | Statement::Alloca { dest, .. }
+            | Statement::Load { dest, .. }
+            | Statement::LoadAddress { dest, .. }
+            | Statement::Phi { dest, .. } => Some(dest),
+            Statement::If { .. }
+            | Statement::Goto(_)
+            | Statement::Switch { .. }
+            | Statement::Return(_)
+            | Statement::Store { .. }
+            | Statement::Line(_) => None,
+        }
+    }
+
+    pub fn used_vars(&self) -> Vec<&VReg> {
+        match self {
+            Statement::If { var, .. } | Statement::Switch { var, .. } => vec![var],
+            Statement::Return(var) => var.iter().collect(),
+            Statement::Copy { src, .. }
+            | Statement::UnaryOperation { operand: src, .. }
+            | Statement::Load { addr: src, .. } => vec![src],
+            Statement::Operation { lhs, rhs, .. } => vec![lhs, rhs],
+            Statement::Store { addr, src, .. } => vec![addr, src],
+            Statement::Call { args, .. } => args.iter().collect(),
+            Statement::Phi { sources, .. } => sources.iter().map(|(_, var)| var).collect(),
+            Statement::Assign { .. }
+            | Statement::Alloca { .. }
+            | Statement::LoadAddress { .. }
+            | Statement::Goto(_)
+            | Statement::Line(_) => vec![],
+        }
+    }
+
+    // Phi sources are left out, since each one is used at the end of its predecessor
+    // rather than here
+    pub fn used_vars_mut(&mut self) -> Vec<&mut VReg> {
+        match self {
+            Statement::If { var, .. } | Statement::Switch { var, .. } => vec![var],
+            Statement::Return(var) => var.iter_mut().collect(),
+            Statement::Copy { src, .. }
+            | Statement::UnaryOperation { operand: src, .. }
+            | Statement::Load { addr: src, .. } => vec![src],
+            Statement::Operation { lhs, rhs, .. } => vec![lhs, rhs],
+            Statement::Store { addr, src, .. } => vec![addr, src],
+            Statement::Call { args, .. } => args.iter_mut().collect(),
+            Statement::Phi { .. }
+            | Statement::Assign { .. }
+            | Statement::Alloca { .. }
+            | Statement::LoadAddress { .. }
+            | Statement::Goto(_)
+            | Statement::Line(_) => vec![],
+        }
+    }
+}
+
+impl BinOp {
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinOp::Eq
+                | BinOp::Ne
+                | BinOp::Lt
+                | BinOp::Le
+                | BinOp::Gt
+                | BinOp::Ge
+                | BinOp::ULt
+                | BinOp::ULe
+                | BinOp::UGt
+                | BinOp::UGe
+        )
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Xor => "^",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::UDiv => "/u",
+            BinOp::UShr => ">>u",
+            BinOp::ULt => "<u",
+            BinOp::ULe => "<=u",
+            BinOp::UGt => ">u",
+            BinOp::UGe => ">=u",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+            UnaryOp::Sext8 => "sext8 ",
+            UnaryOp::Sext32 => "sext32 ",
+            UnaryOp::Zext32 => "zext32 ",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Width {
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::I8 => 1,
+            Width::I64 => 8,
+        }
+    }
+}
+
+// A byte-sized load or store is load8 or store8 in the text form
+impl fmt::Display for Width {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Width::I8 => write!(f, "8"),
+            Width::I64 => Ok(()),
+        }
+    }
+}
+
+// The name a block goes by when printed
+pub fn block_name(block: ControlBlockId) -> String {
+    format!("bb{}", block)
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::If {
+                var,
+                goto_true,
+                goto_false,
+            } => write!(
+                f,
+                "if {} goto {} else {}",
+                var,
+                block_name(*goto_true),
+                block_name(*goto_false)
+            ),
+            Statement::Goto(block) => write!(f, "goto {}", block_name(*block)),
+            Statement::Switch {
+                var,
+                targets,
+                default,
+            } => {
+                let targets: Vec<String> = targets.iter().map(|t| block_name(*t)).collect();
+                write!(
+                    f,
+                    "switch {} [{}] else {}",
+                    var,
+                    targets.join(", "),
+                    block_name(*default)
+                )
+            }
+            Statement::Assign { var, value } => write!(f, "{} = {}", var, value),
+            Statement::Copy { dest, src } => write!(f, "{} = {}", dest, src),
+            Statement::Operation { dest, op, lhs, rhs } => {
+                write!(f, "{} = {} {} {}", dest, lhs, op, rhs)
+            }
+            Statement::UnaryOperation { dest, op, operand } => {
+                write!(f, "{} = {}{}", dest, op, operand)
+            }
+            Statement::Call {
+                dest,
+                func,
+                args,
+                tail,
+            } => {
+                let call = if *tail { "tail call" } else { "call" };
+                write!(f, "{} = {} {}({})", dest, call, func, join_vars(args))
+            }
+            Statement::Return(Some(var)) => write!(f, "ret {}", var),
+            Statement::Return(None) => write!(f, "ret"),
+            Statement::Alloca { dest, size } => write!(f, "{} = alloca {}", dest, size),
+            Statement::Load { dest, addr, width } => {
+                write!(f, "{} = load{} {}", dest, width, addr)
+            }
+            Statement::Store { addr, src, width } => {
+                write!(f, "store{} {}, {}", width, src, addr)
+            }
+            Statement::LoadAddress { dest, label } => write!(f, "{} = addr {}", dest, label),
+            Statement::Phi { dest, sources } => {
+                let sources: Vec<String> = sources
+                    .iter()
+                    .map(|(block, var)| format!("{}: {}", block_name(*block), var))
+                    .collect();
+                write!(f, "{} = phi [{}]", dest, sources.join(", "))
+            }
+            Statement::Line(line) => write!(f, "line {}", line),
+        }
+    }
 }
 
 /*
  * Eventually, want to be able to map variable name in a scope to a cfg var name
+ *
+ * Statements are lowered into the current block. Control flow finishes the current block
+ * with a branch and moves on to a new one, so block 0 is always the entry block.
+ *
+ * A variable whose address is taken can't just be a var, since a pointer has to point
+ * somewhere. It gets a stack slot instead, and every read and write of it goes through a
+ * Load or Store. Which variables those are is worked out by name, so a variable that shares
+ * its name with one whose address is taken gets a slot too.
+ *
+ * Variables are known by the scope that declares them as well as their name, as in the
+ * symbol table, so a variable that shadows another gets a var of its own. A name is looked
+ * up from the innermost enclosing scope outwards, among the declarations lowered so far.
+ *
+ * Every write of a value that isn't a constant to a variable's var is kept as a VarStore, so
+ * once the function is lowered, the ones that are never read can be warned about.
  */
-struct CFGBuildContext {
-    var_counter: u64,
-    var_map: HashMap<VarName, CfgVarName>, // maps Symbol Table var names to CFG var names (e.g. "x" -> "v1")
+struct CFGBuildContext<'a> {
+    var_counter: u32,
+    var_map: HashMap<(u32, VarName), VReg>, // maps (scope_id, var_name) to CFG var names (e.g. "x" -> "v1")
+    slots: HashMap<(u32, VarName), VReg>, // maps variables kept in memory to the var holding their slot's address
+    address_taken: HashSet<VarName>,
+    scopes: Vec<u32>, // the scopes enclosing what's being lowered, innermost last
+    blocks: BTreeMap<ControlBlockId, ControlBlock>,
+    current_block: ControlBlockId,
+    loops: Vec<LoopTargets>, // enclosing loops and switches, innermost last
+    labels: HashMap<ast::NodeId, ControlBlockId>, // the block each case and default label starts
+    strings: &'a mut StringPool, // shared by every function in the program
+    globals: &'a Globals,
+    enumerators: &'a Enumerators,
+    types: &'a NodeTable<ast::Type>, // each expression's type, from the type checker
+    lines: &'a NodeTable<Span>,      // where each statement starts, if known
+    line: u32,                       // the line being lowered, or 0 if unknown
+    names: VRegMap<VarName>,         // the variable each variable's var holds
+    span: Option<Span>,              // where the statement being lowered is, if known
+    stores: Vec<VarStore>,
+}
+
+// A write to a variable's var, which is statement `index` of `block`
+struct VarStore {
+    block: ControlBlockId,
+    index: usize,
+    span: Option<Span>,
+}
+
+// Where break and continue jump to inside a loop. A switch can be broken out of, but a
+// continue in it goes on to the enclosing loop.
+struct LoopTargets {
+    continue_to: Option<ControlBlockId>,
+    break_to: ControlBlockId,
 }
 
 #[allow(dead_code)]
-impl CFGBuildContext {
-    // TODO: for now, only support storing variables in a few registers
-    const MAX_VAR_COUNT: usize = 11;
+impl<'a> CFGBuildContext<'a> {
+    // Every value is held in a full register, so that's how big a slot is too
+    const SLOT_SIZE: u64 = 8;
 
-    fn new() -> Self {
+    fn new(
+        address_taken: HashSet<VarName>,
+        strings: &'a mut StringPool,
+        globals: &'a Globals,
+        enumerators: &'a Enumerators,
+        types: &'a NodeTable<ast::Type>,
+        lines: &'a NodeTable<Span>,
+    ) -> Self {
         CFGBuildContext {
             var_counter: 0,
             var_map: HashMap::new(),
+            slots: HashMap::new(),
+            address_taken,
+            scopes: vec![],
+            blocks: BTreeMap::from([(0, vec![])]),
+            current_block: 0,
+            loops: vec![],
+            labels: HashMap::new(),
+            strings,
+            globals,
+            enumerators,
+            types,
+            lines,
+            line: 0,
+            names: VRegMap::new(),
+            span: None,
+            stores: vec![],
         }
     }
 
-    fn inc(&mut self) -> CfgVarName {
-        assert!(self.var_counter < CFGBuildContext::MAX_VAR_COUNT as u64);
+    // Marks the statements that follow as coming from the current line, unless they
+    // already are
+    fn mark_line(&mut self) {
+        let block = &self.blocks[&self.current_block];
+        let marked = block.iter().rev().find_map(|s| match s {
+            Statement::Line(line) => Some(*line),
+            _ => None,
+        });
+        if self.line != 0 && marked != Some(self.line) {
+            self.emit(vec![Statement::Line(self.line)]);
+        }
+    }
+
+    // How much memory reading or writing the lvalue expr touches. Without the checker's
+    // types every value is taken to be a full 64 bits.
+    fn width(&self, expr: &ast::Expr) -> Width {
+        match self.types.get(&expr.id) {
+            Some(ast::Type::Char) => Width::I8,
+            _ => Width::I64,
+        }
+    }
+
+    fn string_label(&mut self, value: &str) -> Symbol {
+        string_label(self.strings, self.globals, value)
+    }
+
+    // The scope and name of the local variable the name refers to, if it's a local
+    fn resolve(&self, var: &VarName) -> Option<(u32, VarName)> {
+        self.scopes
+            .iter()
+            .rev()
+            .map(|scope| (*scope, *var))
+            .find(|key| self.var_map.contains_key(key) || self.slots.contains_key(key))
+    }
+
+    // Whether the name refers to a global, which it does until a local of the same name
+    // is declared
+    fn is_global(&self, var: &VarName) -> bool {
+        self.globals.contains_key(var) && self.resolve(var).is_none()
+    }
+
+    // Puts the global's address in a new var
+    fn global_address(&mut self, var: &VarName) -> (Statement, VReg) {
+        let addr = self.inc();
+        let statement = Statement::LoadAddress {
+            dest: addr,
+            label: *var,
+        };
+        (statement, addr)
+    }
+
+    fn inc(&mut self) -> VReg {
         self.var_counter += 1;
-        format!("v{:}", self.var_counter)
+        VReg(self.var_counter)
+    }
+
+    // An unsigned value is kept zero-extended from 32 bits, so the result of an operation
+    // that can carry past them is cut back down, which is how unsigned arithmetic wraps
+    fn wrap_unsigned(
+        &mut self,
+        expr: &ast::Expr,
+        statements: &mut Vec<Statement>,
+        operand: VReg,
+    ) -> VReg {
+        if self.types.get(&expr.id) != Some(&ast::Type::Unsigned) {
+            return operand;
+        }
+        let dest = self.inc();
+        statements.push(Statement::UnaryOperation {
+            dest,
+            op: UnaryOp::Zext32,
+            operand,
+        });
+        dest
+    }
+
+    // The scope variables are being declared in
+    fn scope(&self) -> u32 {
+        *self
+            .scopes
+            .last()
+            .expect("variables are declared inside a scope")
     }
 
-    fn register_var(&mut self, var: CfgVarName) {
+    fn register_var(&mut self, var: VarName) {
         let a = self.inc();
-        self.var_map.insert(var, a);
+        self.var_map.insert((self.scope(), var), a);
+        self.names.insert(a, var);
+    }
+
+    fn lookup(&self, var: &VarName) -> Option<&VReg> {
+        self.var_map.get(&self.resolve(var)?)
+    }
+
+    // The var holding the address of the variable's slot, if it's kept in memory
+    fn slot(&self, var: &VarName) -> Option<VReg> {
+        self.slots.get(&self.resolve(var)?).copied()
+    }
+
+    // How much of a variable's slot its declaration `id` stores into, going by the checker's
+    // type for the declaration as width does for expressions
+    fn declared_width(&self, id: ast::NodeId) -> Width {
+        match self.types.get(&id) {
+            Some(ast::Type::Char) => Width::I8,
+            _ => Width::I64,
+        }
+    }
+
+    // Gives the variable a stack slot holding the value in src, which is `width` wide
+    fn store_in_slot(&mut self, var: VarName, src: VReg, width: Width) -> Vec<Statement> {
+        let slot = self.inc();
+        self.slots.insert((self.scope(), var), slot);
+        vec![
+            Statement::Alloca {
+                dest: slot,
+                size: CFGBuildContext::SLOT_SIZE,
+            },
+            Statement::Store {
+                addr: slot,
+                src,
+                width,
+            },
+        ]
+    }
+
+    fn new_block(&mut self) -> ControlBlockId {
+        let id = self.blocks.len() as ControlBlockId;
+        self.blocks.insert(id, vec![]);
+        id
+    }
+
+    fn switch_to(&mut self, block: ControlBlockId) {
+        self.current_block = block;
+    }
+
+    fn emit(&mut self, statements: Vec<Statement>) {
+        let block = self
+            .blocks
+            .get_mut(&self.current_block)
+            .expect("Current block was never created");
+        for statement in statements {
+            // Constants are left out, since `int x = 0;` is usually there just to be safe
+            let var = statement.defined_var();
+            if var.is_some_and(|var| self.names.contains_key(var))
+                && !matches!(statement, Statement::Assign { .. })
+            {
+                self.stores.push(VarStore {
+                    block: self.current_block,
+                    index: block.len(),
+                    span: self.span,
+                });
+            }
+            block.push(statement);
+        }
     }
 
-    fn lookup(&self, var: &VarName) -> Option<&CfgVarName> {
-        self.var_map.get(var)
+    // Whether the current block already ends in a branch or return
+    fn is_terminated(&self) -> bool {
+        self.blocks[&self.current_block]
+            .last()
+            .is_some_and(Statement::is_terminator)
     }
 }
 
+// The variables a function takes the address of anywhere in its body
+fn address_taken_vars(scope: &ast::Scope) -> HashSet<VarName> {
+    fn visit_expr(expr: &ast::Expr, vars: &mut HashSet<VarName>) {
+        match &expr.kind {
+            ast::ExprKind::UnaryOperation {
+                op: ast::UnaryOp::AddrOf,
+                expr,
+            } => match &expr.kind {
+                ast::ExprKind::Variable(name) => {
+                    vars.insert(*name);
+                }
+                _ => visit_expr(expr, vars),
+            },
+            ast::ExprKind::BinaryOperation { left, right, .. } => {
+                visit_expr(left, vars);
+                visit_expr(right, vars);
+            }
+            ast::ExprKind::UnaryOperation { expr, .. } | ast::ExprKind::Cast { expr, .. } => {
+                visit_expr(expr, vars)
+            }
+            ast::ExprKind::Call { args, .. } => args.iter().for_each(|a| visit_expr(a, vars)),
+            _ => {}
+        }
+    }
+    fn visit_scope(scope: &ast::Scope, vars: &mut HashSet<VarName>) {
+        for stmt in &scope.statements {
+            match &stmt.kind {
+                ast::StatementKind::Return(Some(expr)) | ast::StatementKind::Expression(expr) => {
+                    visit_expr(expr, vars)
+                }
+                ast::StatementKind::VarDeclare {
+                    value: Some(value), ..
+                } => visit_expr(value, vars),
+                ast::StatementKind::If {
+                    condition,
+                    true_block,
+                    false_block,
+                } => {
+                    visit_expr(condition, vars);
+                    visit_scope(true_block, vars);
+                    if let Some(false_block) = false_block {
+                        visit_scope(false_block, vars);
+                    }
+                }
+                ast::StatementKind::While { condition, body }
+                | ast::StatementKind::Switch {
+                    value: condition,
+                    body,
+                } => {
+                    visit_expr(condition, vars);
+                    visit_scope(body, vars);
+                }
+                ast::StatementKind::Block(block) => visit_scope(block, vars),
+                _ => {}
+            }
+        }
+    }
+    let mut vars = HashSet::new();
+    visit_scope(scope, &mut vars);
+    vars
+}
+
 pub type ControlBlock = Vec<Statement>;
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
-pub struct ControlFlowGraph(HashMap<ControlBlockId, ControlBlock>);
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlFlowGraph(pub BTreeMap<ControlBlockId, ControlBlock>);
 
 impl Deref for ControlFlowGraph {
-    type Target = HashMap<ControlBlockId, ControlBlock>;
+    type Target = BTreeMap<ControlBlockId, ControlBlock>;
 
-    fn deref(&self) -> &HashMap<ControlBlockId, ControlBlock> {
+    fn deref(&self) -> &BTreeMap<ControlBlockId, ControlBlock> {
         &self.0
     }
 }
 
-#[allow(dead_code)]
+impl DerefMut for ControlFlowGraph {
+    fn deref_mut(&mut self) -> &mut BTreeMap<ControlBlockId, ControlBlock> {
+        &mut self.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub params: Vec<VReg>,
+    pub cfg: ControlFlowGraph,
+    // Declared `inline`, printed as `inline fn`
+    pub inline: bool,
+}
+
+/*
+ * The contents of the program's string literals, by label. They're kept as written in the
+ * source, escapes and all, which is also how the assembler wants them.
+ */
+pub type StringPool = BTreeMap<Symbol, String>;
+
+// The label of a string literal's data, adding it to the pool unless it's already there.
+// Globals' addresses are loaded by name too, so labels skip over their names.
+fn string_label(strings: &mut StringPool, globals: &Globals, value: &str) -> Symbol {
+    if let Some((label, _)) = strings.iter().find(|(_, s)| *s == value) {
+        return *label;
+    }
+    let label = (strings.len()..)
+        .map(|n| Symbol::intern(&format!("str{}", n)))
+        .find(|label| !strings.contains_key(label) && !globals.contains_key(label))
+        .expect("there are only finitely many labels in use");
+    strings.insert(label, value.to_owned());
+    label
+}
+
+// The value a global starts out with
+#[derive(Clone, Debug, PartialEq)]
+pub enum Initializer {
+    Zero,
+    Int(i64),
+    // The address of a string from the pool, by label
+    String(Symbol),
+}
+
+impl fmt::Display for Initializer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Initializer::Zero => write!(f, "0"),
+            Initializer::Int(value) => write!(f, "{}", value),
+            Initializer::String(label) => write!(f, "addr {}", label),
+        }
+    }
+}
+
+/*
+ * The program's global variables by name. Each one takes 8 bytes, like a local's slot, and
+ * like a slot it's only read and written through its address, which a LoadAddress with the
+ * global's name as its label gives.
+ */
+pub type Globals = BTreeMap<Symbol, Initializer>;
+
+// A global's initializer, which the checker has made sure is a string or a constant
+// expression
+fn initializer(
+    value: &ast::Expr,
+    strings: &mut StringPool,
+    globals: &Globals,
+    enumerators: &Enumerators,
+) -> Result<Initializer, CompileError> {
+    match &value.kind {
+        ast::ExprKind::StringLiteral(value) => {
+            Ok(Initializer::String(string_label(strings, globals, value)))
+        }
+        _ => match constant::evaluate(value, enumerators) {
+            Ok(value) => Ok(Initializer::Int(value)),
+            Err(e) => Err(CompileError::LoweringError(format!(
+                "Initializer is not a constant: {}",
+                e.message()
+            ))),
+        },
+    }
+}
+
+// The bytes a literal from the pool stands for, with its escapes resolved
+pub fn literal_bytes(value: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('a') => 0x07,
+            Some('b') => 0x08,
+            Some('f') => 0x0c,
+            Some('v') => 0x0b,
+            Some('x') => {
+                let mut digits = String::new();
+                while let Some(d) = chars.next_if(|d| d.is_ascii_hexdigit() && digits.len() < 2) {
+                    digits.push(d);
+                }
+                u8::from_str_radix(&digits, 16).unwrap_or(b'x')
+            }
+            Some(other) => other as u8,
+            None => b'\\',
+        };
+        bytes.push(byte);
+    }
+    bytes
+}
+
+// Every function in the translation unit, each lowered into its own graph, along with the
+// constant data and global variables they refer to
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub functions: BTreeMap<Symbol, Function>,
+    pub strings: StringPool,
+    pub globals: Globals,
+}
+
+impl Program {
+    pub fn from(declarations: &[ast::Declaration]) -> Result<Self, CompileError> {
+        Program::with_lines(declarations, &NodeTable::new())
+    }
+
+    // Lowers the program with a Line before the statements of each line that has a span
+    // in `lines`
+    pub fn with_lines(
+        declarations: &[ast::Declaration],
+        lines: &NodeTable<Span>,
+    ) -> Result<Self, CompileError> {
+        let mut diagnostics = DiagnosticSink::default();
+        let options = CompileOptions::default().debug_info(true);
+        let type_table = TypeTable::from_declarations(declarations, options.target)?;
+        Program::lower(
+            declarations,
+            &NodeTable::new(),
+            type_table.enumerators(),
+            lines,
+            &options,
+            &mut diagnostics,
+        )
+    }
+
+    // Lowers a program that has been through check_types, whose `types` say which loads and
+    // stores are of a char, along with the Lines from `lines`. `enumerators` are the values of
+    // the program's enumerators, from its TypeTable. Values assigned to a variable and never
+    // read are reported to `diagnostics`. The Lines are left out unless `options` ask for
+    // line information.
+    pub fn lower(
+        declarations: &[ast::Declaration],
+        types: &NodeTable<ast::Type>,
+        enumerators: &Enumerators,
+        lines: &NodeTable<Span>,
+        options: &CompileOptions,
+        diagnostics: &mut DiagnosticSink,
+    ) -> Result<Self, CompileError> {
+        let no_lines = NodeTable::new();
+        let lines = match options.line_info() {
+            true => lines,
+            false => &no_lines,
+        };
+        let mut strings = StringPool::new();
+
+        // Every global is named before any string gets a label
+        let mut globals = Globals::new();
+        for declaration in declarations {
+            if let ast::Declaration::Global {
+                id, name, var_type, ..
+            } = declaration
+            {
+                let var_type = types.get(id).unwrap_or(var_type);
+                // Every global gets a word, of which a char only ever reads or writes the
+                // low byte
+                if !matches!(
+                    var_type,
+                    ast::Type::Int
+                        | ast::Type::Char
+                        | ast::Type::Unsigned
+                        | ast::Type::Enum(_)
+                        | ast::Type::Pointer(_)
+                ) {
+                    return Err(CompileError::LoweringError(format!(
+                        "Global {} has unsupported type {}",
+                        name, var_type
+                    )));
+                }
+                globals.insert(*name, Initializer::Zero);
+            }
+        }
+        for declaration in declarations {
+            if let ast::Declaration::Global {
+                id,
+                name,
+                var_type,
+                value: Some(value),
+                ..
+            } = declaration
+            {
+                let value = match initializer(value, &mut strings, &globals, enumerators)? {
+                    // Held zero-extended, like any unsigned value
+                    Initializer::Int(value)
+                        if types.get(id).unwrap_or(var_type) == &ast::Type::Unsigned =>
+                    {
+                        Initializer::Int(value as u32 as i64)
+                    }
+                    value => value,
+                };
+                globals.insert(*name, value);
+            }
+        }
+
+        let mut functions = BTreeMap::new();
+        for declaration in declarations {
+            let ast::Declaration::Function { name, span, .. } = declaration else {
+                continue;
+            };
+            ice::enter_function(name, Some(*span));
+            let function = ControlFlowGraph::from_function(
+                declaration,
+                &mut strings,
+                &globals,
+                enumerators,
+                types,
+                lines,
+                diagnostics,
+            )?;
+            functions.insert(*name, function);
+        }
+        Ok(Program {
+            functions,
+            strings,
+            globals,
+        })
+    }
+}
+
+// Prints each block's label followed by its statements, one per line, in block order
+impl fmt::Display for ControlFlowGraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (id, statements) in self.iter() {
+            writeln!(f, "{}:", block_name(*id))?;
+            for s in statements {
+                writeln!(f, "  {}", s)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (label, value) in &self.strings {
+            writeln!(f, "{} = \"{}\"", label, value)?;
+        }
+        for (name, value) in &self.globals {
+            match value {
+                Initializer::Zero => writeln!(f, "global {}", name)?,
+                value => writeln!(f, "global {} = {}", name, value)?,
+            }
+        }
+        for (name, function) in &self.functions {
+            if function.inline {
+                write!(f, "inline ")?;
+            }
+            writeln!(f, "fn {}({}) {{", name, join_vars(&function.params))?;
+            write!(f, "{}", function.cfg)?;
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
+
 impl ControlFlowGraph {
-    fn new() {}
+    // A switch with fewer cases is always lowered to comparisons
+    const MIN_JUMP_TABLE_CASES: usize = 4;
+
+    pub fn successors(&self, block: ControlBlockId) -> Vec<ControlBlockId> {
+        self[&block]
+            .last()
+            .map_or(vec![], |terminator| terminator.successors())
+    }
+
+    // Maps every block to the blocks that can jump to it, each list in ascending order
+    pub fn predecessors(&self) -> BTreeMap<ControlBlockId, Vec<ControlBlockId>> {
+        let mut predecessors: BTreeMap<ControlBlockId, Vec<ControlBlockId>> =
+            self.keys().map(|id| (*id, vec![])).collect();
+        for id in self.keys() {
+            for successor in self.successors(*id) {
+                let list = predecessors.entry(successor).or_default();
+                if !list.contains(id) {
+                    list.push(*id);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /*
+     * A Graphviz DOT graph of the CFG: one box per block listing its statements, and an
+     * edge to each block it can branch to. If edges are labelled with the branch they take.
+     */
+    pub fn to_dot(&self, name: &str) -> String {
+        let ids: Vec<&ControlBlockId> = self.keys().collect();
+        let mut out = format!("digraph \"{}\" {{\n", name);
+        out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+        for id in &ids {
+            // \l ends a left-justified line
+            let mut label = format!("{}:\\l", block_name(**id));
+            for s in &self[id] {
+                label.push_str(&format!("  {}\\l", s));
+            }
+            out.push_str(&format!("  {} [label=\"{}\"];\n", block_name(**id), label));
+        }
+        for id in &ids {
+            match self[id].last() {
+                Some(Statement::If {
+                    goto_true,
+                    goto_false,
+                    ..
+                }) => {
+                    for (target, branch) in [(goto_true, "true"), (goto_false, "false")] {
+                        out.push_str(&format!(
+                            "  {} -> {} [label=\"{}\"];\n",
+                            block_name(**id),
+                            block_name(*target),
+                            branch
+                        ));
+                    }
+                }
+                // Each edge of a switch is labeled with the index that takes it
+                Some(Statement::Switch {
+                    targets, default, ..
+                }) => {
+                    let indices = targets.iter().enumerate().map(|(i, t)| (t, i.to_string()));
+                    for (target, index) in indices.chain([(default, "default".to_owned())]) {
+                        out.push_str(&format!(
+                            "  {} -> {} [label=\"{}\"];\n",
+                            block_name(**id),
+                            block_name(*target),
+                            index
+                        ));
+                    }
+                }
+                _ => {
+                    for target in self.successors(**id) {
+                        out.push_str(&format!(
+                            "  {} -> {};\n",
+                            block_name(**id),
+                            block_name(target)
+                        ));
+                    }
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // The highest number among the graph's vars, so new vars can be numbered after it
+    pub fn max_var_number(&self) -> u32 {
+        self.values()
+            .flatten()
+            .flat_map(|s| s.used_vars().into_iter().chain(s.defined_var()))
+            .map(|var| var.0)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Lowers one function's body, with its parameters taking the first vars in order
+    fn from_function(
+        declaration: &ast::Declaration,
+        strings: &mut StringPool,
+        globals: &Globals,
+        enumerators: &Enumerators,
+        types: &NodeTable<ast::Type>,
+        lines: &NodeTable<Span>,
+        diagnostics: &mut DiagnosticSink,
+    ) -> Result<Function, CompileError> {
+        let ast::Declaration::Function {
+            name,
+            args: params,
+            scope,
+            span,
+            inline,
+            ..
+        } = declaration
+        else {
+            return Err(CompileError::LoweringError(format!(
+                "Expected a Function, but got {:?}",
+                declaration
+            )));
+        };
+        let address_taken = address_taken_vars(scope);
+        let mut context =
+            CFGBuildContext::new(address_taken, strings, globals, enumerators, types, lines);
+        // The prologue belongs to the line the function is declared on
+        if !lines.is_empty() {
+            context.line = span.line;
+        }
+        context.mark_line();
+        // The parameters are declared in the body's scope, as in the symbol table
+        context.scopes.push(scope.id);
+        for param in params {
+            context.register_var(param.name);
+        }
+        // A parameter whose address is taken is copied into a slot on entry
+        for param in params {
+            if context.address_taken.contains(&param.name) {
+                let var = *context
+                    .lookup(&param.name)
+                    .expect("parameters are registered before the body");
+                let statements = context.store_in_slot(param.name, var, Width::I64);
+                context.emit(statements);
+            }
+        }
+        let params = params
+            .iter()
+            .map(|p| {
+                let var = context.lookup(&p.name);
+                *var.expect("parameters are registered before the body")
+            })
+            .collect();
+        ControlFlowGraph::lower_statements(&scope.statements, &mut context)?;
+        // Running off the end returns, with 0 from main and nothing from anything else
+        if !context.is_terminated() {
+            let statements = match *name == "main" {
+                true => {
+                    let zero = context.inc();
+                    vec![
+                        Statement::Assign {
+                            var: zero,
+                            value: 0,
+                        },
+                        Statement::Return(Some(zero)),
+                    ]
+                }
+                false => vec![Statement::Return(None)],
+            };
+            context.emit(statements);
+        }
+        let cfg = ControlFlowGraph(context.blocks);
+        if diagnostics.is_enabled("dead-store") {
+            let dead = Liveness::analyze(&cfg).dead_definitions(&cfg);
+            for store in &context.stores {
+                if !dead.contains(&(store.block, store.index)) {
+                    continue;
+                }
+                let var = cfg[&store.block][store.index]
+                    .defined_var()
+                    .expect("a store defines its variable's var");
+                // A variable that's declared and never referred to again is left to
+                // -Wunused-variable
+                let mentions = cfg
+                    .values()
+                    .flatten()
+                    .filter(|s| s.defined_var() == Some(var) || s.used_vars().contains(&var));
+                if mentions.count() == 1 {
+                    continue;
+                }
+                diagnostics.warn(
+                    "dead-store",
+                    format!("Value assigned to {} is never read", context.names[var]),
+                    store.span,
+                );
+            }
+        }
+        Ok(Function {
+            params,
+            cfg,
+            inline: *inline,
+        })
+    }
+
+    fn lower_scope(scope: &ast::Scope, context: &mut CFGBuildContext) -> Result<(), CompileError> {
+        context.scopes.push(scope.id);
+        let result = ControlFlowGraph::lower_statements(&scope.statements, context);
+        context.scopes.pop();
+        result
+    }
+
+    // Lowers the statements of the innermost scope in context.scopes
+    fn lower_statements(
+        statements: &[ast::Statement],
+        context: &mut CFGBuildContext,
+    ) -> Result<(), CompileError> {
+        // Statements the desugar pass made up have no span, and take the line of the
+        // statement they're part of
+        let enclosing_line = context.line;
+        let enclosing_span = context.span;
+        for (i, stmt) in statements.iter().enumerate() {
+            let label = context.labels.get(&stmt.id).copied();
+            // Nothing after a return, break, or continue can run until the next case label.
+            // A declaration in between still declares its variable for the code after the
+            // label, so it goes in a block of its own that nothing jumps to.
+            if context.is_terminated() && label.is_none() {
+                let is_label = |s: &ast::Statement| context.labels.contains_key(&s.id);
+                match &stmt.kind {
+                    ast::StatementKind::VarDeclare { .. }
+                        if statements[i + 1..].iter().any(is_label) =>
+                    {
+                        let block = context.new_block();
+                        context.switch_to(block);
+                    }
+                    _ => continue,
+                }
+            }
+            if let Some(block) = label {
+                if !context.is_terminated() {
+                    context.emit(vec![Statement::Goto(block)]);
+                }
+                context.switch_to(block);
+            }
+            context.line = context
+                .lines
+                .get(&stmt.id)
+                .map_or(enclosing_line, |span| span.line);
+            // A declaration's stores are put down to the name it declares
+            context.span = match &stmt.kind {
+                ast::StatementKind::VarDeclare { span, .. } => Some(*span),
+                _ => stmt.span.or(enclosing_span),
+            };
+            // A block is no code of its own
+            if !matches!(stmt.kind, ast::StatementKind::Block(_)) {
+                context.mark_line();
+            }
+            match &stmt.kind {
+                ast::StatementKind::If {
+                    condition,
+                    true_block,
+                    false_block,
+                } => ControlFlowGraph::lower_if(condition, true_block, false_block, context)?,
+                ast::StatementKind::While { condition, body } => {
+                    ControlFlowGraph::lower_while(condition, body, context)?
+                }
+                ast::StatementKind::Switch { value, body } => {
+                    ControlFlowGraph::lower_switch(value, body, context)?
+                }
+                ast::StatementKind::Block(block) => ControlFlowGraph::lower_scope(block, context)?,
+                ast::StatementKind::Case(_) | ast::StatementKind::Default => {}
+                ast::StatementKind::Break | ast::StatementKind::Continue => {
+                    let target = match stmt.kind {
+                        ast::StatementKind::Break => context.loops.last().map(|t| t.break_to),
+                        _ => context.loops.iter().rev().find_map(|t| t.continue_to),
+                    };
+                    let Some(target) = target else {
+                        return Err(CompileError::LoweringError(format!(
+                            "{:?} outside of a loop",
+                            stmt.kind
+                        )));
+                    };
+                    context.emit(vec![Statement::Goto(target)]);
+                }
+                _ => {
+                    let statements = ControlFlowGraph::process(stmt, context)?;
+                    context.emit(statements);
+                }
+            }
+        }
+        context.line = enclosing_line;
+        context.span = enclosing_span;
+        Ok(())
+    }
+
+    /*
+     * The current block ends by branching on the condition. Each branch gets its own block,
+     * and both continue in a new join block unless they return first. Without an else, a
+     * false condition goes straight to the join block.
+     */
+    fn lower_if(
+        condition: &ast::Expr,
+        true_block: &ast::Scope,
+        false_block: &Option<ast::Scope>,
+        context: &mut CFGBuildContext,
+    ) -> Result<(), CompileError> {
+        let (statements, var) = ControlFlowGraph::lower_expr(condition, context)?;
+        context.emit(statements);
+
+        let true_id = context.new_block();
+        let false_id = false_block.as_ref().map(|_| context.new_block());
+        let join_id = context.new_block();
+        context.emit(vec![Statement::If {
+            var,
+            goto_true: true_id,
+            goto_false: false_id.unwrap_or(join_id),
+        }]);
+
+        let branches =
+            std::iter::once((true_id, true_block)).chain(false_id.zip(false_block.as_ref()));
+        for (id, scope) in branches {
+            context.switch_to(id);
+            ControlFlowGraph::lower_scope(scope, context)?;
+            if !context.is_terminated() {
+                context.emit(vec![Statement::Goto(join_id)]);
+            }
+        }
+        context.switch_to(join_id);
+        Ok(())
+    }
+
+    /*
+     * A loop gets a header block that evaluates the condition and branches into the body or
+     * out to the exit block. The end of the body jumps back to the header, as does continue,
+     * while break jumps to the exit block.
+     */
+    fn lower_while(
+        condition: &ast::Expr,
+        body: &ast::Scope,
+        context: &mut CFGBuildContext,
+    ) -> Result<(), CompileError> {
+        let header_id = context.new_block();
+        let body_id = context.new_block();
+        let exit_id = context.new_block();
+        context.emit(vec![Statement::Goto(header_id)]);
+
+        context.switch_to(header_id);
+        context.mark_line();
+        let (statements, var) = ControlFlowGraph::lower_expr(condition, context)?;
+        context.emit(statements);
+        context.emit(vec![Statement::If {
+            var,
+            goto_true: body_id,
+            goto_false: exit_id,
+        }]);
+
+        context.switch_to(body_id);
+        context.loops.push(LoopTargets {
+            continue_to: Some(header_id),
+            break_to: exit_id,
+        });
+        ControlFlowGraph::lower_scope(body, context)?;
+        context.loops.pop();
+        if !context.is_terminated() {
+            context.emit(vec![Statement::Goto(header_id)]);
+        }
+        context.switch_to(exit_id);
+        Ok(())
+    }
 
-    pub fn from(declarations: &Vec<ast::Declaration>) -> Self {
-        // For now, we're only considering programs with a single declaration: a main function
-        assert_eq!(declarations.len(), 1);
+    /*
+     * Each case and default label starts a block of its own, and the body falls through
+     * from one to the next. A break jumps to the exit block, which is also where a value
+     * with no label goes when there's no default.
+     *
+     * When the case values are dense enough, the value picks its label's block out of a
+     * jump table, whose holes go to the default. Otherwise it's compared against each case
+     * value in turn.
+     */
+    fn lower_switch(
+        value: &ast::Expr,
+        body: &ast::Scope,
+        context: &mut CFGBuildContext,
+    ) -> Result<(), CompileError> {
+        let (statements, var) = ControlFlowGraph::lower_expr(value, context)?;
+        context.emit(statements);
 
-        let ast::Declaration::Function {
-            name,
-            args,
-            return_type,
-            scope,
-        } = &declarations[0];
-        assert_eq!(name, "main");
-        assert_eq!(args.len(), 0);
-        assert_eq!(*return_type, ast::Type::Int);
+        let mut cases = vec![];
+        let mut default = None;
+        for stmt in &body.statements {
+            match &stmt.kind {
+                ast::StatementKind::Case(value) => {
+                    let value = constant::evaluate(value, context.enumerators).map_err(|e| {
+                        CompileError::LoweringError(format!(
+                            "Case value is not a constant: {}",
+                            e.message()
+                        ))
+                    })?;
+                    let block = context.new_block();
+                    context.labels.insert(stmt.id, block);
+                    cases.push((value, block));
+                }
+                ast::StatementKind::Default => {
+                    let block = context.new_block();
+                    context.labels.insert(stmt.id, block);
+                    default = Some(block);
+                }
+                _ => {}
+            }
+        }
+        let exit_id = context.new_block();
+        let default = default.unwrap_or(exit_id);
 
-        let mut context = CFGBuildContext::new();
-        let mut block: ControlBlock = vec![];
-        for stmt in &scope.statements {
-            block.append(&mut ControlFlowGraph::process(stmt, &mut context).expect(""));
+        cases.sort();
+        let min = cases.first().map_or(0, |(value, _)| *value);
+        let max = cases.last().map_or(0, |(value, _)| *value);
+        let range = max as i128 - min as i128 + 1;
+        // The line is only tracked with debug info, but the span is always there
+        let line = context.span.map_or(context.line, |span| span.line);
+        if cases.len() >= ControlFlowGraph::MIN_JUMP_TABLE_CASES && range <= 2 * cases.len() as i128
+        {
+            log::info!("switch on line {}: jump table of {} entries", line, range);
+            let index = match min {
+                0 => var,
+                _ => {
+                    let (base, index) = (context.inc(), context.inc());
+                    context.emit(vec![
+                        Statement::Assign {
+                            var: base,
+                            value: min,
+                        },
+                        Statement::Operation {
+                            dest: index,
+                            op: BinOp::Sub,
+                            lhs: var,
+                            rhs: base,
+                        },
+                    ]);
+                    index
+                }
+            };
+            let mut targets = vec![default; range as usize];
+            for (value, block) in &cases {
+                targets[(*value as i128 - min as i128) as usize] = *block;
+            }
+            context.emit(vec![Statement::Switch {
+                var: index,
+                targets,
+                default,
+            }]);
+        } else {
+            let plural = if cases.len() == 1 { "" } else { "s" };
+            log::info!(
+                "switch on line {}: {} comparison{}",
+                line,
+                cases.len(),
+                plural
+            );
+            // Each comparison after the first is in a block of its own
+            let tests: Vec<ControlBlockId> =
+                cases.iter().skip(1).map(|_| context.new_block()).collect();
+            for (i, (value, block)) in cases.iter().enumerate() {
+                if i > 0 {
+                    context.switch_to(tests[i - 1]);
+                }
+                let (constant, equal) = (context.inc(), context.inc());
+                let next = tests.get(i).copied().unwrap_or(default);
+                context.emit(vec![
+                    Statement::Assign {
+                        var: constant,
+                        value: *value,
+                    },
+                    Statement::Operation {
+                        dest: equal,
+                        op: BinOp::Eq,
+                        lhs: var,
+                        rhs: constant,
+                    },
+                    Statement::If {
+                        var: equal,
+                        goto_true: *block,
+                        goto_false: next,
+                    },
+                ]);
+            }
+            if cases.is_empty() {
+                context.emit(vec![Statement::Goto(default)]);
+            }
         }
 
-        // Right now this is just a single block since there are no conditionals
-        ControlFlowGraph(HashMap::from([(0, block)]))
+        context.loops.push(LoopTargets {
+            continue_to: None,
+            break_to: exit_id,
+        });
+        ControlFlowGraph::lower_scope(body, context)?;
+        context.loops.pop();
+        if !context.is_terminated() {
+            context.emit(vec![Statement::Goto(exit_id)]);
+        }
+        context.switch_to(exit_id);
+        Ok(())
     }
 
     fn process(
         stmt: &ast::Statement,
         context: &mut CFGBuildContext,
-    ) -> Result<Vec<Statement>, String> {
-        match stmt {
-            ast::Statement::VarDeclare { .. } => {
-                ControlFlowGraph::process_var_declare(&stmt, context)
+    ) -> Result<Vec<Statement>, CompileError> {
+        match &stmt.kind {
+            ast::StatementKind::VarDeclare { .. } => {
+                ControlFlowGraph::process_var_declare(stmt, context)
             }
-            ast::Statement::Return(..) => ControlFlowGraph::process_return(&stmt, context),
-            _ => Err("Not Implemented".to_owned()),
+            ast::StatementKind::Return(..) => ControlFlowGraph::process_return(stmt, context),
+            // Only the side effects matter, so the result is dropped, and an expression without
+            // any isn't lowered at all
+            ast::StatementKind::Expression(expr) if !expr.has_side_effects() => Ok(vec![]),
+            ast::StatementKind::Expression(expr) => {
+                Ok(ControlFlowGraph::lower_expr(expr, context)?.0)
+            }
+            _ => Err(CompileError::LoweringError("Not Implemented".to_owned())),
         }
     }
 
     fn process_var_declare(
         stmt: &ast::Statement,
         context: &mut CFGBuildContext,
-    ) -> Result<Vec<Statement>, String> {
-        if let ast::Statement::VarDeclare {
+    ) -> Result<Vec<Statement>, CompileError> {
+        if let ast::StatementKind::VarDeclare {
             name,
             var_type,
             value,
-        } = stmt
+            ..
+        } = &stmt.kind
         {
-            assert_eq!(var_type, &ast::Type::Int);
+            // The checker's type has any typedef resolved, but an unchecked program only has
+            // the type as written
+            let var_type = context.types.get(&stmt.id).unwrap_or(var_type);
+            if !matches!(
+                var_type,
+                ast::Type::Int
+                    | ast::Type::Char
+                    | ast::Type::Unsigned
+                    | ast::Type::Enum(_)
+                    | ast::Type::Pointer(_)
+            ) {
+                return Err(CompileError::LoweringError(format!(
+                    "Variable {} has unsupported type {}",
+                    name, var_type
+                )));
+            }
 
-            context.register_var(name.clone());
-            let cfg_var_name = context.lookup(name).expect("");
+            if context.address_taken.contains(name) {
+                let (mut statements, src) = match value {
+                    Some(value) => ControlFlowGraph::lower_expr(value, context)?,
+                    None => {
+                        let zero = context.inc();
+                        let assign = Statement::Assign {
+                            var: zero,
+                            value: 0,
+                        };
+                        (vec![assign], zero)
+                    }
+                };
+                let width = context.declared_width(stmt.id);
+                statements.extend(context.store_in_slot(*name, src, width));
+                return Ok(statements);
+            }
 
-            let unwrapped = value.as_ref().unwrap_or(&ast::Expr::IntLiteral(0));
-            // TODO: process inner expression. For now, assume it's an int literal
-            if let ast::Expr::IntLiteral(v) = unwrapped {
+            // An uninitialized variable starts out as 0
+            let Some(value) = value else {
+                context.register_var(*name);
+                let cfg_var_name = context
+                    .lookup(name)
+                    .expect("the variable was just registered");
                 return Ok(vec![Statement::Assign {
-                    var: cfg_var_name.clone(),
-                    value: *v,
+                    var: *cfg_var_name,
+                    value: 0,
                 }]);
+            };
+            // The variable simply names the temporary holding its initial value, unless that
+            // belongs to another variable, which may be reassigned later
+            let (mut statements, src) = ControlFlowGraph::lower_expr(value, context)?;
+            if context.var_map.values().any(|v| *v == src) {
+                context.register_var(*name);
+                let dest = *context
+                    .lookup(name)
+                    .expect("the variable was just registered");
+                statements.push(Statement::Copy { dest, src });
+            } else {
+                context.var_map.insert((context.scope(), *name), src);
+                context.names.insert(src, *name);
             }
-            return Err(format!("Expected an IntLiteral, but got {:?}", value));
+            return Ok(statements);
         }
 
-        Err(format!("Expected a VarDeclare, but got {:?}", stmt))
+        Err(CompileError::LoweringError(format!(
+            "Expected a VarDeclare, but got {:?}",
+            stmt
+        )))
     }
 
     fn process_return(
         stmt: &ast::Statement,
         context: &mut CFGBuildContext,
-    ) -> Result<Vec<Statement>, String> {
-        if let ast::Statement::Return(expr) = stmt {
-            match expr {
-                ast::Expr::IntLiteral(i) => {
-                    let cfg_var_name = context.inc();
-                    return Ok(vec![
-                        Statement::Assign {
-                            var: cfg_var_name.clone(),
-                            value: *i,
-                        },
-                        Statement::Return(cfg_var_name.clone()),
-                    ]);
+    ) -> Result<Vec<Statement>, CompileError> {
+        match &stmt.kind {
+            ast::StatementKind::Return(Some(expr)) => {
+                let (mut statements, cfg_var_name) = ControlFlowGraph::lower_expr(expr, context)?;
+                // Returning a call's result makes it a tail call, unless the callee might be
+                // handed the address of a local, which has to outlive the caller's frame
+                if let Some(Statement::Call { dest, tail, .. }) = statements.last_mut()
+                    && *dest == cfg_var_name
+                    && context.address_taken.is_empty()
+                {
+                    *tail = true;
+                }
+                statements.push(Statement::Return(Some(cfg_var_name)));
+                Ok(statements)
+            }
+            ast::StatementKind::Return(None) => Ok(vec![Statement::Return(None)]),
+            _ => Err(CompileError::LoweringError(format!(
+                "Expected a Return, but got {:?}",
+                stmt
+            ))),
+        }
+    }
+
+    /*
+     * Flattens an expression into three-address statements, returning them along with the
+     * var that holds the result. Every literal and operation gets a fresh temporary, while a
+     * variable read just uses the variable's own var.
+     *
+     * Short-circuit operators split the current block, so the returned statements belong at
+     * the end of whichever block is current once this returns.
+     */
+    fn lower_expr(
+        expr: &ast::Expr,
+        context: &mut CFGBuildContext,
+    ) -> Result<(Vec<Statement>, VReg), CompileError> {
+        match &expr.kind {
+            ast::ExprKind::IntLiteral(i) => {
+                let cfg_var_name = context.inc();
+                Ok((
+                    vec![Statement::Assign {
+                        var: cfg_var_name,
+                        value: *i,
+                    }],
+                    cfg_var_name,
+                ))
+            }
+            ast::ExprKind::StringLiteral(value) => {
+                let label = context.string_label(value);
+                let dest = context.inc();
+                Ok((vec![Statement::LoadAddress { dest, label }], dest))
+            }
+            ast::ExprKind::Variable(var_name) if let Some(addr) = context.slot(var_name) => {
+                let dest = context.inc();
+                let width = context.width(expr);
+                Ok((vec![Statement::Load { dest, addr, width }], dest))
+            }
+            ast::ExprKind::Variable(var_name) if context.is_global(var_name) => {
+                let (load_address, addr) = context.global_address(var_name);
+                let dest = context.inc();
+                let width = context.width(expr);
+                Ok((
+                    vec![load_address, Statement::Load { dest, addr, width }],
+                    dest,
+                ))
+            }
+            ast::ExprKind::Variable(var_name) => match context.lookup(var_name) {
+                Some(cfg_var_name) => Ok((vec![], *cfg_var_name)),
+                None if let Some(value) = context.enumerators.get(var_name) => {
+                    let var = context.inc();
+                    Ok((vec![Statement::Assign { var, value: *value }], var))
                 }
-                ast::Expr::Variable(var_name) => {
-                    let cfg_var_name = context.lookup(var_name).expect("");
-                    return Ok(vec![Statement::Return(cfg_var_name.clone())]);
+                None => Err(CompileError::LoweringError(format!(
+                    "Undefined variable {}",
+                    var_name
+                ))),
+            },
+            // Stores into the variable's own var, which is also the assignment's value
+            ast::ExprKind::BinaryOperation {
+                op: ast::BinOp::Assign,
+                left,
+                right,
+            } => {
+                if let Some(addr_expr) = ControlFlowGraph::memory_target(left, context) {
+                    // The right side may branch, so the address has to be in place first
+                    let (addr_statements, addr) = match addr_expr {
+                        Ok(addr) => (vec![], addr),
+                        Err(pointer) => ControlFlowGraph::lower_expr(pointer, context)?,
+                    };
+                    context.emit(addr_statements);
+                    let (mut statements, src) = ControlFlowGraph::lower_expr(right, context)?;
+                    let width = context.width(left);
+                    statements.push(Statement::Store { addr, src, width });
+                    return Ok((statements, src));
                 }
-                _ => return Err(format!("")),
-            };
+                let ast::ExprKind::Variable(var_name) = &left.kind else {
+                    return Err(CompileError::LoweringError(format!(
+                        "Unsupported assignment target {:?}",
+                        left
+                    )));
+                };
+                let (mut statements, src) = ControlFlowGraph::lower_expr(right, context)?;
+                let dest = context.lookup(var_name).copied().ok_or_else(|| {
+                    CompileError::LoweringError(format!("Undefined variable {}", var_name))
+                })?;
+                statements.push(Statement::Copy { dest, src });
+                Ok((statements, dest))
+            }
+            ast::ExprKind::BinaryOperation {
+                op: op @ (ast::BinOp::LogicalAnd | ast::BinOp::LogicalOr),
+                left,
+                right,
+            } => ControlFlowGraph::lower_logical(op, left, right, context),
+            // The checker has converted both operands to the type the operation is done in,
+            // so the left one's type says whether it's done unsigned
+            ast::ExprKind::BinaryOperation { op, left, right } => {
+                let unsigned = context.types.get(&left.id).is_some_and(|t| t.is_unsigned());
+                let op = match (op, unsigned) {
+                    (ast::BinOp::Add, _) => BinOp::Add,
+                    (ast::BinOp::Sub, _) => BinOp::Sub,
+                    (ast::BinOp::Mul, _) => BinOp::Mul,
+                    (ast::BinOp::Div, false) => BinOp::Div,
+                    (ast::BinOp::Div, true) => BinOp::UDiv,
+                    (ast::BinOp::ShiftLeft, _) => BinOp::Shl,
+                    (ast::BinOp::ShiftRight, false) => BinOp::Shr,
+                    (ast::BinOp::ShiftRight, true) => BinOp::UShr,
+                    (ast::BinOp::BitAnd, _) => BinOp::And,
+                    (ast::BinOp::BitOr, _) => BinOp::Or,
+                    (ast::BinOp::BitXor, _) => BinOp::Xor,
+                    (ast::BinOp::Equals, _) => BinOp::Eq,
+                    (ast::BinOp::NotEquals, _) => BinOp::Ne,
+                    (ast::BinOp::Less, false) => BinOp::Lt,
+                    (ast::BinOp::Less, true) => BinOp::ULt,
+                    (ast::BinOp::LessEqual, false) => BinOp::Le,
+                    (ast::BinOp::LessEqual, true) => BinOp::ULe,
+                    (ast::BinOp::Greater, false) => BinOp::Gt,
+                    (ast::BinOp::Greater, true) => BinOp::UGt,
+                    (ast::BinOp::GreaterEqual, false) => BinOp::Ge,
+                    (ast::BinOp::GreaterEqual, true) => BinOp::UGe,
+                    (op, _) => {
+                        return Err(CompileError::LoweringError(format!(
+                            "Unsupported operator {}",
+                            op.as_str()
+                        )));
+                    }
+                };
+                // The right operand may branch, so the left one's statements have to be in
+                // place first
+                let (left_statements, lhs) = ControlFlowGraph::lower_expr(left, context)?;
+                context.emit(left_statements);
+                let (mut statements, rhs) = ControlFlowGraph::lower_expr(right, context)?;
+                let dest = context.inc();
+                let wraps = matches!(op, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Shl);
+                statements.push(Statement::Operation { dest, op, lhs, rhs });
+                let dest = match wraps {
+                    true => context.wrap_unsigned(expr, &mut statements, dest),
+                    false => dest,
+                };
+                Ok((statements, dest))
+            }
+            // A variable's address is its slot, and `&*p` is just p
+            ast::ExprKind::UnaryOperation {
+                op: ast::UnaryOp::AddrOf,
+                expr,
+            } => match ControlFlowGraph::memory_target(expr, context) {
+                Some(Ok(addr)) => Ok((vec![], addr)),
+                Some(Err(pointer)) => ControlFlowGraph::lower_expr(pointer, context),
+                None => Err(CompileError::LoweringError(format!(
+                    "Cannot take the address of {:?}",
+                    expr
+                ))),
+            },
+            ast::ExprKind::UnaryOperation {
+                op: ast::UnaryOp::Deref,
+                expr: pointer,
+            } => {
+                let (mut statements, addr) = ControlFlowGraph::lower_expr(pointer, context)?;
+                let dest = context.inc();
+                let width = context.width(expr);
+                statements.push(Statement::Load { dest, addr, width });
+                Ok((statements, dest))
+            }
+            ast::ExprKind::UnaryOperation { op, expr: operand } => {
+                let op = match op {
+                    ast::UnaryOp::Neg => UnaryOp::Neg,
+                    ast::UnaryOp::Not => UnaryOp::Not,
+                    ast::UnaryOp::BitNot => UnaryOp::BitNot,
+                    ast::UnaryOp::AddrOf | ast::UnaryOp::Deref => unreachable!(),
+                };
+                let (mut statements, operand) = ControlFlowGraph::lower_expr(operand, context)?;
+                let dest = context.inc();
+                let wraps = op != UnaryOp::Not;
+                statements.push(Statement::UnaryOperation { dest, op, operand });
+                let dest = match wraps {
+                    true => context.wrap_unsigned(expr, &mut statements, dest),
+                    false => dest,
+                };
+                Ok((statements, dest))
+            }
+            // Each argument may branch, so the ones before it have to be in place first
+            ast::ExprKind::Call { name, args } => {
+                let mut arg_vars = vec![];
+                for arg in args {
+                    let (statements, var) = ControlFlowGraph::lower_expr(arg, context)?;
+                    context.emit(statements);
+                    arg_vars.push(var);
+                }
+                let dest = context.inc();
+                Ok((
+                    vec![Statement::Call {
+                        dest,
+                        func: *name,
+                        args: arg_vars,
+                        tail: false,
+                    }],
+                    dest,
+                ))
+            }
+            // Every integer is held in a full register, a char or int sign-extended and an
+            // unsigned zero-extended from 32 bits. So converting between char, int and enums
+            // (which are ints underneath) only changes bits going to char, which keeps the low
+            // byte, and converting to or from unsigned redoes the top 32 bits.
+            ast::ExprKind::Cast {
+                target:
+                    target @ (ast::Type::Int
+                    | ast::Type::Unsigned
+                    | ast::Type::Enum(_)
+                    | ast::Type::Char),
+                expr,
+            } => {
+                let from_unsigned = context.types.get(&expr.id) == Some(&ast::Type::Unsigned);
+                let (mut statements, operand) = ControlFlowGraph::lower_expr(expr, context)?;
+                let op = match target {
+                    ast::Type::Char => UnaryOp::Sext8,
+                    ast::Type::Unsigned if !from_unsigned => UnaryOp::Zext32,
+                    ast::Type::Int | ast::Type::Enum(_) if from_unsigned => UnaryOp::Sext32,
+                    _ => return Ok((statements, operand)),
+                };
+                let dest = context.inc();
+                statements.push(Statement::UnaryOperation { dest, op, operand });
+                Ok((statements, dest))
+            }
+            _ => Err(CompileError::LoweringError(format!(
+                "Unsupported expression {:?}",
+                expr
+            ))),
+        }
+    }
+
+    /*
+     * Where an lvalue lives if it's in memory: either a variable's slot, whose address is
+     * already in a var, a global, whose address is loaded into a var in the current block,
+     * or the pointer expression a `*` dereferences. None means the lvalue is a plain var.
+     */
+    fn memory_target<'a>(
+        lvalue: &'a ast::Expr,
+        context: &mut CFGBuildContext,
+    ) -> Option<Result<VReg, &'a ast::Expr>> {
+        match &lvalue.kind {
+            ast::ExprKind::Variable(var_name) if context.is_global(var_name) => {
+                let (load_address, addr) = context.global_address(var_name);
+                context.emit(vec![load_address]);
+                Some(Ok(addr))
+            }
+            ast::ExprKind::Variable(var_name) => context.slot(var_name).map(Ok),
+            ast::ExprKind::UnaryOperation {
+                op: ast::UnaryOp::Deref,
+                expr,
+            } => Some(Err(expr)),
+            _ => None,
+        }
+    }
+
+    /*
+     * `&&` and `||` only evaluate their right operand when the left one doesn't already
+     * decide the result. The result var starts out as that deciding value (0 for `&&`, 1 for
+     * `||`), and the right operand's block overwrites it with whether the right operand is
+     * nonzero. Both paths meet in a join block, where the rest of the expression continues.
+     */
+    fn lower_logical(
+        op: &ast::BinOp,
+        left: &ast::Expr,
+        right: &ast::Expr,
+        context: &mut CFGBuildContext,
+    ) -> Result<(Vec<Statement>, VReg), CompileError> {
+        let (statements, lhs) = ControlFlowGraph::lower_expr(left, context)?;
+        context.emit(statements);
+
+        let right_id = context.new_block();
+        let join_id = context.new_block();
+        let dest = context.inc();
+        let (short_circuit, goto_true, goto_false) = match op {
+            ast::BinOp::LogicalAnd => (0, right_id, join_id),
+            _ => (1, join_id, right_id),
         };
+        context.emit(vec![
+            Statement::Assign {
+                var: dest,
+                value: short_circuit,
+            },
+            Statement::If {
+                var: lhs,
+                goto_true,
+                goto_false,
+            },
+        ]);
 
-        Err(format!(""))
+        context.switch_to(right_id);
+        let (mut statements, rhs) = ControlFlowGraph::lower_expr(right, context)?;
+        let zero = context.inc();
+        statements.extend([
+            Statement::Assign {
+                var: zero,
+                value: 0,
+            },
+            Statement::Operation {
+                dest,
+                op: BinOp::Ne,
+                lhs: rhs,
+                rhs: zero,
+            },
+            Statement::Goto(join_id),
+        ]);
+        context.emit(statements);
+
+        context.switch_to(join_id);
+        Ok((vec![], dest))
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser::parse;
+    use crate::desugar::desugar;
+    use crate::diagnostic::DiagnosticSink;
+    use crate::parser::{parse, parse_with_statement_spans};
+    use crate::span::Span;
     use crate::symantic_check::check_syntax;
-    use crate::tokenizer::tokenize;
+    use crate::tokenizer::{tokenize, tokenize_with_spans};
     use std::fs::read_to_string;
 
     #[test]
     fn test_cfg_var_declare() -> Result<(), String> {
-        let vd = ast::Statement::VarDeclare {
-            name: "x".to_owned(),
-            var_type: ast::Type::Int,
-            value: Some(ast::Expr::IntLiteral(123)),
-        };
+        let vd = ast::Statement::new(
+            ast::NodeId(2),
+            ast::StatementKind::VarDeclare {
+                name: "x".into(),
+                var_type: ast::Type::Int,
+                value: Some(ast::Expr::new(
+                    ast::NodeId(1),
+                    ast::ExprKind::IntLiteral(123),
+                )),
+                span: Span::default(),
+            },
+        );
 
-        let mut context = CFGBuildContext::new();
+        let mut strings = StringPool::new();
+        let globals = Globals::new();
+        let enumerators = Enumerators::new();
+        let types = NodeTable::new();
+        let lines = NodeTable::new();
+        let mut context = CFGBuildContext::new(
+            HashSet::new(),
+            &mut strings,
+            &globals,
+            &enumerators,
+            &types,
+            &lines,
+        );
+        context.scopes.push(1);
         assert_eq!(
             ControlFlowGraph::process(&vd, &mut context)?,
             vec![Statement::Assign {
-                var: "v1".to_owned(),
+                var: VReg(1),
                 value: 123,
             }]
         );
         assert_eq!(
             ControlFlowGraph::process(&vd, &mut context)?,
             vec![Statement::Assign {
-                var: "v2".to_owned(),
+                var: VReg(2),
                 value: 123,
             }]
         );
@@ -237,16 +2117,34 @@ mod tests {
 
     #[test]
     fn test_return_int_literal() -> Result<(), String> {
-        let ret = ast::Statement::Return(ast::Expr::IntLiteral(123));
-        let mut context = CFGBuildContext::new();
+        let ret = ast::Statement::new(
+            ast::NodeId(2),
+            ast::StatementKind::Return(Some(ast::Expr::new(
+                ast::NodeId(1),
+                ast::ExprKind::IntLiteral(123),
+            ))),
+        );
+        let mut strings = StringPool::new();
+        let globals = Globals::new();
+        let enumerators = Enumerators::new();
+        let types = NodeTable::new();
+        let lines = NodeTable::new();
+        let mut context = CFGBuildContext::new(
+            HashSet::new(),
+            &mut strings,
+            &globals,
+            &enumerators,
+            &types,
+            &lines,
+        );
         assert_eq!(
             ControlFlowGraph::process(&ret, &mut context)?,
             vec![
                 Statement::Assign {
-                    var: "v1".to_owned(),
+                    var: VReg(1),
                     value: 123,
                 },
-                Statement::Return("v1".to_owned()),
+                Statement::Return(Some(VReg(1))),
             ]
         );
 
@@ -255,16 +2153,854 @@ mod tests {
 
     #[test]
     fn test_return_var() -> Result<(), String> {
-        let ret = ast::Statement::Return(ast::Expr::Variable("x".to_owned()));
+        let ret = ast::Statement::new(
+            ast::NodeId(2),
+            ast::StatementKind::Return(Some(ast::Expr::new(
+                ast::NodeId(1),
+                ast::ExprKind::Variable("x".into()),
+            ))),
+        );
 
-        let mut context = CFGBuildContext::new();
-        context.register_var("x".to_owned());
+        let mut strings = StringPool::new();
+        let globals = Globals::new();
+        let enumerators = Enumerators::new();
+        let types = NodeTable::new();
+        let lines = NodeTable::new();
+        let mut context = CFGBuildContext::new(
+            HashSet::new(),
+            &mut strings,
+            &globals,
+            &enumerators,
+            &types,
+            &lines,
+        );
+        context.scopes.push(1);
+        context.register_var("x".into());
 
         assert_eq!(
             ControlFlowGraph::process(&ret, &mut context)?,
-            vec![Statement::Return("v1".to_owned()),]
+            vec![Statement::Return(Some(VReg(1))),]
+        );
+
+        Ok(())
+    }
+
+    fn lower_program(source: &str) -> Result<Program, String> {
+        let ast = parse(&tokenize(source)?)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        Ok(Program::from(&ast)?)
+    }
+
+    // The graph of main in a program
+    fn lower_source(source: &str) -> Result<ControlFlowGraph, String> {
+        let mut program = lower_program(source)?;
+        let main = program
+            .functions
+            .remove(&Symbol::intern("main"))
+            .ok_or("Missing main")?;
+        Ok(main.cfg)
+    }
+
+    #[test]
+    fn test_cfg_if() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 1; if (x) { return 2; } return 3; }")?;
+        let expected = ControlFlowGraph(BTreeMap::from([
+            (
+                0,
+                vec![
+                    Statement::Assign {
+                        var: VReg(1),
+                        value: 1,
+                    },
+                    Statement::If {
+                        var: VReg(1),
+                        goto_true: 1,
+                        goto_false: 2,
+                    },
+                ],
+            ),
+            (
+                1,
+                vec![
+                    Statement::Assign {
+                        var: VReg(2),
+                        value: 2,
+                    },
+                    Statement::Return(Some(VReg(2))),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    Statement::Assign {
+                        var: VReg(3),
+                        value: 3,
+                    },
+                    Statement::Return(Some(VReg(3))),
+                ],
+            ),
+        ]));
+        assert_eq!(cfg, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_if_else() -> Result<(), String> {
+        let cfg = lower_source(
+            "int main() { int x = 0; if (x) { int y = 1; } else { int z = 2; } return x; }",
+        )?;
+        assert_eq!(
+            cfg[&0].last(),
+            Some(&Statement::If {
+                var: VReg(1),
+                goto_true: 1,
+                goto_false: 2,
+            })
+        );
+        // Both branches fall through to the join block, which holds the rest of main
+        assert_eq!(cfg[&1].last(), Some(&Statement::Goto(3)));
+        assert_eq!(cfg[&2].last(), Some(&Statement::Goto(3)));
+        assert_eq!(cfg[&3], vec![Statement::Return(Some(VReg(1)))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_while() -> Result<(), String> {
+        let cfg = lower_source(
+            "int main() { int x = 1; \
+             while (x) { if (x) { break; } continue; return 5; } return x; }",
+        )?;
+        let expected = ControlFlowGraph(BTreeMap::from([
+            (
+                0,
+                vec![
+                    Statement::Assign {
+                        var: VReg(1),
+                        value: 1,
+                    },
+                    Statement::Goto(1),
+                ],
+            ),
+            (
+                1,
+                vec![Statement::If {
+                    var: VReg(1),
+                    goto_true: 2,
+                    goto_false: 3,
+                }],
+            ),
+            (
+                2,
+                vec![Statement::If {
+                    var: VReg(1),
+                    goto_true: 4,
+                    goto_false: 5,
+                }],
+            ),
+            // The return after continue is never lowered
+            (3, vec![Statement::Return(Some(VReg(1)))]),
+            (4, vec![Statement::Goto(3)]),
+            (5, vec![Statement::Goto(1)]),
+        ]));
+        assert_eq!(cfg, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_expressions() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 2; int y = x * (x + 3); return y - x / 4; }")?;
+        let op = |dest: u32, op: BinOp, lhs: u32, rhs: u32| Statement::Operation {
+            dest: VReg(dest),
+            op,
+            lhs: VReg(lhs),
+            rhs: VReg(rhs),
+        };
+        let assign = |var: u32, value: i64| Statement::Assign {
+            var: VReg(var),
+            value,
+        };
+        let expected = vec![
+            assign(1, 2),
+            assign(2, 3),
+            op(3, BinOp::Add, 1, 2),
+            op(4, BinOp::Mul, 1, 3),
+            assign(5, 4),
+            op(6, BinOp::Div, 1, 5),
+            op(7, BinOp::Sub, 4, 6),
+            Statement::Return(Some(VReg(7))),
+        ];
+        assert_eq!(cfg[&0], expected);
+
+        let cfg = lower_source("int main() { int x = 1; if (x + 1) { return x; } return 0; }")?;
+        assert_eq!(
+            cfg[&0].last(),
+            Some(&Statement::If {
+                var: VReg(3),
+                goto_true: 1,
+                goto_false: 2,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_assignment() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 1; int y = x; x = x + 2; y = x; return y; }")?;
+        let copy = |dest: u32, src: u32| Statement::Copy {
+            dest: VReg(dest),
+            src: VReg(src),
+        };
+        let assign = |var: u32, value: i64| Statement::Assign {
+            var: VReg(var),
+            value,
+        };
+        let expected = vec![
+            assign(1, 1),
+            // y gets its own var, since x's is about to change
+            copy(2, 1),
+            assign(3, 2),
+            Statement::Operation {
+                dest: VReg(4),
+                op: BinOp::Add,
+                lhs: VReg(1),
+                rhs: VReg(3),
+            },
+            copy(1, 4),
+            copy(2, 1),
+            Statement::Return(Some(VReg(2))),
+        ];
+        assert_eq!(cfg[&0], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_comparisons() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 2; return x <= 3; }")?;
+        let expected = vec![
+            Statement::Assign {
+                var: VReg(1),
+                value: 2,
+            },
+            Statement::Assign {
+                var: VReg(2),
+                value: 3,
+            },
+            Statement::Operation {
+                dest: VReg(3),
+                op: BinOp::Le,
+                lhs: VReg(1),
+                rhs: VReg(2),
+            },
+            Statement::Return(Some(VReg(3))),
+        ];
+        assert_eq!(cfg[&0], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_unary_operations() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 2; return -x + !~x; }")?;
+        let unary = |dest: u32, op: UnaryOp, operand: u32| Statement::UnaryOperation {
+            dest: VReg(dest),
+            op,
+            operand: VReg(operand),
+        };
+        let expected = vec![
+            Statement::Assign {
+                var: VReg(1),
+                value: 2,
+            },
+            unary(2, UnaryOp::Neg, 1),
+            unary(3, UnaryOp::BitNot, 1),
+            unary(4, UnaryOp::Not, 3),
+            Statement::Operation {
+                dest: VReg(5),
+                op: BinOp::Add,
+                lhs: VReg(2),
+                rhs: VReg(4),
+            },
+            Statement::Return(Some(VReg(5))),
+        ];
+        assert_eq!(cfg[&0], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_short_circuit() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 2; return x && x / 2 || x; }")?;
+        let assign = |var: u32, value: i64| Statement::Assign {
+            var: VReg(var),
+            value,
+        };
+        let op = |dest: u32, op: BinOp, lhs: u32, rhs: u32| Statement::Operation {
+            dest: VReg(dest),
+            op,
+            lhs: VReg(lhs),
+            rhs: VReg(rhs),
+        };
+        let if_ = |var: u32, goto_true, goto_false| Statement::If {
+            var: VReg(var),
+            goto_true,
+            goto_false,
+        };
+        let expected = ControlFlowGraph(BTreeMap::from([
+            // x && ...: the division only runs when x is nonzero
+            (0, vec![assign(1, 2), assign(2, 0), if_(1, 1, 2)]),
+            (
+                1,
+                vec![
+                    assign(3, 2),
+                    op(4, BinOp::Div, 1, 3),
+                    assign(5, 0),
+                    op(2, BinOp::Ne, 4, 5),
+                    Statement::Goto(2),
+                ],
+            ),
+            // ... || x: x is only tested when the && came out false
+            (2, vec![assign(6, 1), if_(2, 4, 3)]),
+            (
+                3,
+                vec![assign(7, 0), op(6, BinOp::Ne, 1, 7), Statement::Goto(4)],
+            ),
+            (4, vec![Statement::Return(Some(VReg(6)))]),
+        ]));
+        assert_eq!(cfg, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_functions() -> Result<(), String> {
+        let program = lower_program(
+            "int add(int a, int b) { return a + b; } \
+             int main() { int x = 1; return add(x, x && 2); }",
+        )?;
+        let add = &program.functions[&Symbol::intern("add")];
+        assert_eq!(add.params, vec![VReg(1), VReg(2)]);
+        assert_eq!(
+            add.cfg[&0],
+            vec![
+                Statement::Operation {
+                    dest: VReg(3),
+                    op: BinOp::Add,
+                    lhs: VReg(1),
+                    rhs: VReg(2),
+                },
+                Statement::Return(Some(VReg(3))),
+            ]
+        );
+
+        // The call comes after the second argument's short circuit, in its join block, and
+        // it's a tail call since main returns its result
+        let main = &program.functions[&Symbol::intern("main")];
+        assert!(main.params.is_empty());
+        assert_eq!(
+            main.cfg[&2],
+            vec![
+                Statement::Call {
+                    dest: VReg(5),
+                    func: "add".into(),
+                    args: vec![VReg(1), VReg(2)],
+                    tail: true,
+                },
+                Statement::Return(Some(VReg(5))),
+            ]
+        );
+
+        // Functions and blocks print in name and id order, whatever order they were added in
+        let text = program.to_string();
+        let headers: Vec<&str> = text
+            .lines()
+            .filter(|l| l.starts_with("fn ") || l.starts_with("bb"))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                "fn add(v1, v2) {",
+                "bb0:",
+                "fn main() {",
+                "bb0:",
+                "bb1:",
+                "bb2:"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_nested_calls() -> Result<(), String> {
+        // Inner calls are lowered first, each into its own temporary, left to right
+        let main = lower_source(
+            "int g(int x) { return x * 2; } int f(int x) { return x + 1; } \
+             int main() { int a = 3; return a * f(g(1) + 2) - g(a); }",
+        )?;
+        let expected = "\
+bb0:
+  v1 = 3
+  v2 = 1
+  v3 = call g(v2)
+  v4 = 2
+  v5 = v3 + v4
+  v6 = call f(v5)
+  v7 = v1 * v6
+  v8 = call g(v1)
+  v9 = v7 - v8
+  ret v9
+";
+        assert_eq!(main.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_expression_statements() -> Result<(), String> {
+        // Calls made for their effects stay in order, while expressions with none leave
+        // nothing behind, not even for their operands
+        let main = lower_source(
+            "int g(int x) { return x * 2; } \
+             int main() { int a = 3; g(a); a + g(1); a * 2; -a; g(a) + 1; return a; }",
+        )?;
+        let expected = "\
+bb0:
+  v1 = 3
+  v2 = call g(v1)
+  v3 = 1
+  v4 = call g(v3)
+  v5 = v1 + v4
+  v6 = call g(v1)
+  v7 = 1
+  v8 = v6 + v7
+  ret v1
+";
+        assert_eq!(main.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_tail_calls() -> Result<(), String> {
+        // Only a call whose result is returned as it is can be a tail call, and not when a
+        // slot in the frame might still be pointed to
+        let program = lower_program(
+            "int g(int x) { return x; } \
+             int tail(int x) { return g(x); } \
+             int added(int x) { return g(x) + 1; } \
+             int slot(int x) { int *p = &x; return g(*p); }",
+        )?;
+        let is_tail = |name: &str| {
+            let cfg = &program.functions[&Symbol::intern(name)].cfg;
+            cfg.values()
+                .flatten()
+                .any(|s| matches!(s, Statement::Call { tail: true, .. }))
+        };
+        assert!(is_tail("tail"));
+        assert!(!is_tail("added"));
+        assert!(!is_tail("slot"));
+        assert!(program.to_string().contains("v2 = tail call g(v1)"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_shadowing() -> Result<(), String> {
+        // Each inner x is a var of its own, and the outer one, v1, is back once they're gone
+        let main = lower_source(
+            "int main() { int x = 1; int y = 0; if (x) { int x = 10; y = x; } \
+             { int x = 100; y = y + x; } return x + y; }",
+        )?;
+        let expected = "\
+bb0:
+  v1 = 1
+  v2 = 0
+  if v1 goto bb1 else bb2
+bb1:
+  v3 = 10
+  v2 = v3
+  goto bb2
+bb2:
+  v4 = 100
+  v5 = v2 + v4
+  v2 = v5
+  v6 = v1 + v2
+  ret v6
+";
+        assert_eq!(main.to_string(), expected);
+
+        // A name used before an inner declaration of it still means the outer variable
+        let main =
+            lower_source("int main() { int x = 1; { int y = x; int x = 2; return x + y; } }")?;
+        let expected = "bb0:\n  v1 = 1\n  v2 = v1\n  v3 = 2\n  v4 = v3 + v2\n  ret v4\n";
+        assert_eq!(main.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_address_taken() -> Result<(), String> {
+        // x lives in a slot because its address is taken, while y stays a plain var
+        let program = lower_program(
+            "int f(int a) { int *p = &a; return *p; } \
+             int main() { int x; int y = 2; int *p = &x; *p = y; return x; }",
+        )?;
+        let expected = "\
+fn f(v1) {
+bb0:
+  v2 = alloca 8
+  store v1, v2
+  v3 = load v2
+  ret v3
+}
+fn main() {
+bb0:
+  v1 = 0
+  v2 = alloca 8
+  store v1, v2
+  v3 = 2
+  store v3, v2
+  v4 = load v2
+  ret v4
+}
+";
+        assert_eq!(program.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_switch() -> Result<(), String> {
+        // Four cases in a range of four make a jump table, where 4 falls through to 6. Two
+        // far apart are compared against in order.
+        let program = lower_program(
+            "int dense(int x) { switch (x) { case 3: return 1; case 4: case 6: x = 2; break; \
+             default: return 0; case 5: x = 5; } return x; } \
+             int sparse(int x) { switch (x) { case 10: return 1; case -10: return 2; } \
+             return x; }",
+        )?;
+        let expected = "\
+fn dense(v1) {
+bb0:
+  v2 = 3
+  v3 = v1 - v2
+  switch v3 [bb1, bb2, bb5, bb3] else bb4
+bb1:
+  v4 = 1
+  ret v4
+bb2:
+  goto bb3
+bb3:
+  v5 = 2
+  v1 = v5
+  goto bb6
+bb4:
+  v6 = 0
+  ret v6
+bb5:
+  v7 = 5
+  v1 = v7
+  goto bb6
+bb6:
+  ret v1
+}
+fn sparse(v1) {
+bb0:
+  v2 = -10
+  v3 = v1 == v2
+  if v3 goto bb2 else bb4
+bb1:
+  v6 = 1
+  ret v6
+bb2:
+  v7 = 2
+  ret v7
+bb3:
+  ret v1
+bb4:
+  v4 = 10
+  v5 = v1 == v4
+  if v5 goto bb1 else bb3
+}
+";
+        assert_eq!(program.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_string_literals() -> Result<(), String> {
+        // Both functions share one pool, and a repeated literal reuses its label
+        let program = lower_program(
+            "char *f() { return \"b\"; } \
+             int main() { char *s = \"a\"; char *t = \"b\"; return s == \"a\"; }",
+        )?;
+        assert_eq!(
+            program.strings,
+            StringPool::from([
+                ("str0".into(), "b".to_owned()),
+                ("str1".into(), "a".to_owned()),
+            ])
+        );
+        let main = &program.functions[&Symbol::intern("main")];
+        let labels: Vec<&str> = main.cfg[&0]
+            .iter()
+            .filter_map(|s| match s {
+                Statement::LoadAddress { label, .. } => Some(label.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(labels, vec!["str1", "str0", "str1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_globals() -> Result<(), String> {
+        // A global is read and written through its address. The string label skips str0,
+        // which is the name of a global.
+        let program = lower_program(
+            "int str0 = -2; char *s = \"a\"; int n; \
+             int main() { n = str0; return n; }",
+        )?;
+        assert_eq!(
+            program.globals,
+            Globals::from([
+                ("n".into(), Initializer::Zero),
+                ("s".into(), Initializer::String("str1".into())),
+                ("str0".into(), Initializer::Int(-2)),
+            ])
+        );
+        let expected = "\
+str1 = \"a\"
+global n
+global s = addr str1
+global str0 = -2
+fn main() {
+bb0:
+  v1 = addr n
+  v2 = addr str0
+  v3 = load v2
+  store v3, v1
+  v4 = addr n
+  v5 = load v4
+  ret v5
+}
+";
+        assert_eq!(program.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_chars() -> Result<(), String> {
+        // A char is sign-extended from its low byte wherever an int becomes one, and c's
+        // slot is only ever read and written a byte at a time
+        let source = "int main() { char c = 300; char *p = &c; *p = c + 1; return c; }";
+        let options = crate::CompileOptions {
+            passes: vec![],
+            ..Default::default()
+        };
+        let compiled = crate::compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+        let expected = "\
+bb0:
+  v1 = 300
+  v2 = sext8 v1
+  v3 = alloca 8
+  store8 v2, v3
+  v4 = load8 v3
+  v5 = 1
+  v6 = v4 + v5
+  v7 = sext8 v6
+  store8 v7, v3
+  v8 = load8 v3
+  ret v8
+";
+        let main = &compiled.program.functions[&Symbol::intern("main")];
+        assert_eq!(main.cfg.to_string(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_void() -> Result<(), String> {
+        // A function that runs off its end returns too, with 0 if it's main
+        let source = "int g; void set(int x) { if (x) return; g = x; } int main() { set(1); }";
+        let options = crate::CompileOptions {
+            passes: vec![],
+            ..Default::default()
+        };
+        let compiled = crate::compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+        let set = "\
+bb0:
+  if v1 goto bb1 else bb2
+bb1:
+  ret
+bb2:
+  v2 = addr g
+  store v1, v2
+  ret
+";
+        let functions = &compiled.program.functions;
+        assert_eq!(functions[&Symbol::intern("set")].cfg.to_string(), set);
+        let main = "bb0:\n  v1 = 1\n  v2 = call set(v1)\n  v3 = 0\n  ret v3\n";
+        assert_eq!(functions[&Symbol::intern("main")].cfg.to_string(), main);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_dead_stores() -> Result<(), String> {
+        // The loop's stores are read by the next time around, a constant initializer isn't
+        // reported, and r is left to -Wunused-variable
+        let source = "
+int f(int a) { return a * 2; }
+int main() {
+    int total = 0;
+    for (int i = 0; i < 3; i = i + 1) { total = total + i; }
+    int x = f(1);
+    x = f(2);
+    int r = f(3);
+    x = total;
+    return 0;
+}";
+        let options = crate::CompileOptions {
+            warnings: vec!["-Wdead-store".to_owned()],
+            ..Default::default()
+        };
+        let compiled = crate::compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+        let warnings: Vec<String> = compiled.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "6:9: warning: Value assigned to x is never read [-Wdead-store]",
+                "7:5: warning: Value assigned to x is never read [-Wdead-store]",
+                "9:5: warning: Value assigned to x is never read [-Wdead-store]",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_declared_types() -> Result<(), String> {
+        let lower = |source: &str| -> Result<String, String> {
+            let options = crate::CompileOptions {
+                passes: vec![],
+                ..Default::default()
+            };
+            let compiled =
+                crate::compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+            Ok(compiled.program.functions[&Symbol::intern("main")]
+                .cfg
+                .to_string())
+        };
+        // A typedef for char is a char, so its slot is stored into a byte at a time, and an
+        // enum is an int
+        let main = lower(
+            "typedef char byte; enum E { A, B }; \
+             int main() { byte b = 65; char *p = &b; enum E e = 1; return *p + e; }",
+        )?;
+        let expected = "\
+bb0:
+  v1 = 65
+  v2 = sext8 v1
+  v3 = alloca 8
+  store8 v2, v3
+  v4 = 1
+  v5 = load8 v3
+  v6 = v5 + v4
+  ret v6
+";
+        assert_eq!(main, expected);
+
+        assert_eq!(
+            lower("struct S { int a; }; int main() { struct S s; return 0; }"),
+            Err("error: Variable s has unsupported type struct S".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_unsigned() -> Result<(), String> {
+        let options = crate::CompileOptions {
+            passes: vec![],
+            ..Default::default()
+        };
+        // Division, shifts right, and comparisons go by the type of their operands, and
+        // pointers compare unsigned
+        let source = "int main() { unsigned u = 7; int i = -2; int *p = &i; \
+                      return (u / 2 < i) + (i / 2 < u) + (u >> 1 > 1) + (i >> 1 > -2) + (p <= p); }";
+        let compiled = crate::compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+        let main = &compiled.program.functions[&Symbol::intern("main")];
+        let ops: Vec<BinOp> = main
+            .cfg
+            .values()
+            .flatten()
+            .filter_map(|s| match s {
+                Statement::Operation { op, .. } if *op != BinOp::Add => Some(op.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                BinOp::UDiv,
+                BinOp::ULt,
+                BinOp::Div,
+                BinOp::ULt,
+                BinOp::UShr,
+                BinOp::UGt,
+                BinOp::Shr,
+                BinOp::Gt,
+                BinOp::ULe,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cfg_lines() -> Result<(), String> {
+        // The for loop's condition and step were made up by the desugar pass, and take the
+        // loop's line
+        let source = "int main() {\n  int x = 0;\n  for (int i = 0; i < 3; i = i + 1)\n    \
+                      x = x + i;\n  return x;\n}";
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let options = CompileOptions::default().debug_info(true);
+        let (ast, lines) = parse_with_statement_spans(&tokens, &spans, &options, &mut diagnostics)?;
+        let program = Program::with_lines(&desugar(ast), &lines)?;
+        let expected = "\
+bb0:
+  line 1
+  line 2
+  v1 = 0
+  line 3
+  v2 = 0
+  goto bb1
+bb1:
+  line 3
+  v3 = 3
+  v4 = v2 < v3
+  if v4 goto bb2 else bb3
+bb2:
+  line 4
+  v5 = v1 + v2
+  v1 = v5
+  line 3
+  v6 = 1
+  v7 = v2 + v6
+  v2 = v7
+  goto bb1
+bb3:
+  line 5
+  ret v1
+";
+        assert_eq!(
+            program.functions[&Symbol::intern("main")].cfg.to_string(),
+            expected
         );
+        Ok(())
+    }
 
+    #[test]
+    fn test_cfg_to_dot() -> Result<(), String> {
+        let cfg = lower_source("int main() { int x = 1; if (x) { return -x; } return x + 2; }")?;
+        let expected = r#"digraph "main" {
+  node [shape=box, fontname="monospace"];
+  bb0 [label="bb0:\l  v1 = 1\l  if v1 goto bb1 else bb2\l"];
+  bb1 [label="bb1:\l  v2 = -v1\l  ret v2\l"];
+  bb2 [label="bb2:\l  v3 = 2\l  v4 = v1 + v3\l  ret v4\l"];
+  bb0 -> bb1 [label="true"];
+  bb0 -> bb2 [label="false"];
+}
+"#;
+        assert_eq!(cfg.to_dot("main"), expected);
         Ok(())
     }
 
@@ -273,21 +3009,22 @@ mod tests {
         let s = read_to_string("test/return.c").unwrap();
         let tokens = tokenize(&s)?;
         let ast = parse(&tokens)?;
-        check_syntax(&ast)?;
-        let cfg = ControlFlowGraph::from(&ast);
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let program = Program::from(&ast)?;
+        let cfg = &program.functions[&Symbol::intern("main")].cfg;
 
         println!("CFG: {:?}", cfg);
 
         let control_block = vec![
             Statement::Assign {
-                var: "v1".to_owned(),
+                var: VReg(1),
                 value: 123,
             },
-            Statement::Return("v1".to_owned()),
+            Statement::Return(Some(VReg(1))),
         ];
-        let expected = ControlFlowGraph(HashMap::from([(0, control_block)]));
+        let expected = ControlFlowGraph(BTreeMap::from([(0, control_block)]));
 
-        assert_eq!(cfg, expected);
+        assert_eq!(*cfg, expected);
 
         Ok(())
     }
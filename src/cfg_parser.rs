@@ -0,0 +1,788 @@
+use crate::cfg::*;
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
+use std::collections::BTreeMap;
+
+/*
+ * Parses the text form of the CFG IR that ControlFlowGraph and Program print, so passes
+ * can be tested on small IR snippets. Each block starts with a `bbN:` label, and its
+ * statements are separated by newlines or semicolons:
+ *
+ *   fn main() {
+ *   bb0: v1 = 5; v2 = v1 * v1; if v2 goto bb1 else bb2
+ *   bb1: ret v2
+ *   bb2:
+ *     v3 = phi [bb0: v1]
+ *     ret v3
+ *   }
+ *
+ * Loads and stores of a single byte are `load8` and `store8`. A jump table is
+ * `switch v1 [bb1, bb2] else bb3`.
+ *
+ * `//` starts a comment that runs to the end of the line. A graph on its own is just the
+ * blocks, without the `fn` line and closing brace. A program's string literals come before
+ * its functions, one per line, as `str0 = "text"`, and so do its globals, as `global x`,
+ * `global x = 5`, or `global s = addr str0`.
+ */
+
+// A statement's text, along with where it starts for error messages
+struct Piece<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+fn error(span: Span, message: String) -> CompileError {
+    CompileError::ParseError {
+        message,
+        span: Some(span),
+    }
+}
+
+// Splits a line at each `;` up to any comment, along with where each part starts. Neither
+// counts inside a string.
+fn line_parts(line: &str) -> Vec<(usize, &str)> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut in_string = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => {
+                parts.push((start, &line[start..i]));
+                start = i + 1;
+            }
+            '/' if !in_string && chars.peek().is_some_and(|(_, next)| *next == '/') => {
+                parts.push((start, &line[start..i]));
+                return parts;
+            }
+            _ => {}
+        }
+    }
+    parts.push((start, &line[start..]));
+    parts
+}
+
+// Splits the text into statements, dropping comments and empty ones
+fn pieces(text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = vec![];
+    let mut line_start = 0;
+    for (i, line) in text.split('\n').enumerate() {
+        for (offset, part) in line_parts(line) {
+            let trimmed = part.trim_start();
+            let start = offset + (part.len() - trimmed.len());
+            let trimmed = trimmed.trim_end();
+            if !trimmed.is_empty() {
+                pieces.push(Piece {
+                    text: trimmed,
+                    span: Span {
+                        start: line_start + start,
+                        end: line_start + start + trimmed.len(),
+                        line: i as u32 + 1,
+                        column: start as u32 + 1,
+                    },
+                });
+            }
+        }
+        line_start += line.len() + 1;
+    }
+    pieces
+}
+
+// Splits a statement into words, with punctuation as words of its own
+fn words(text: &str) -> Vec<String> {
+    let mut spaced = String::new();
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '[' | ']' | ',' | ':' | '{' | '}' => {
+                spaced.push(' ');
+                spaced.push(c);
+                spaced.push(' ');
+            }
+            _ => spaced.push(c),
+        }
+    }
+    spaced.split_whitespace().map(str::to_owned).collect()
+}
+
+// A var is v followed by its number
+fn parse_var(word: &str) -> Option<VReg> {
+    let number = word.strip_prefix('v')?;
+    match number.bytes().all(|b| b.is_ascii_digit()) {
+        true => number.parse().ok().map(VReg),
+        false => None,
+    }
+}
+
+// Functions, globals, and strings are named like C identifiers
+fn is_name(word: &str) -> bool {
+    word.chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+struct Parser<'a> {
+    words: Vec<String>,
+    pos: usize,
+    span: Span,
+    source: &'a str, // the statement being parsed, for error messages
+}
+
+impl Parser<'_> {
+    fn new<'a>(piece: &Piece<'a>) -> Parser<'a> {
+        Parser {
+            words: words(piece.text),
+            pos: 0,
+            span: piece.span,
+            source: piece.text,
+        }
+    }
+
+    fn error(&self, expected: &str) -> CompileError {
+        let found = match self.words.get(self.pos) {
+            Some(word) => format!("`{}`", word),
+            None => "the end of the statement".to_owned(),
+        };
+        error(
+            self.span,
+            format!(
+                "Expected {} but found {} in `{}`",
+                expected, found, self.source
+            ),
+        )
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.words.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        self.pos += 1;
+        self.words.get(self.pos - 1).map(String::as_str)
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), CompileError> {
+        match self.peek() {
+            Some(w) if w == word => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.error(&format!("`{}`", word))),
+        }
+    }
+
+    fn var(&mut self) -> Result<VReg, CompileError> {
+        match self.peek().and_then(parse_var) {
+            Some(var) => {
+                self.pos += 1;
+                Ok(var)
+            }
+            None => Err(self.error("a var")),
+        }
+    }
+
+    // The name of a function, a global, or a string
+    fn name(&mut self) -> Result<Symbol, CompileError> {
+        match self.peek() {
+            Some(word) if is_name(word) => {
+                Ok(Symbol::intern(self.next().expect("a name was peeked")))
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    fn block(&mut self) -> Result<ControlBlockId, CompileError> {
+        let id = self
+            .peek()
+            .and_then(|w| w.strip_prefix("bb"))
+            .and_then(|n| n.parse().ok());
+        match id {
+            Some(id) => {
+                self.pos += 1;
+                Ok(id)
+            }
+            None => Err(self.error("a block label like bb0")),
+        }
+    }
+
+    fn end(&self) -> Result<(), CompileError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.error("the end of the statement")),
+        }
+    }
+
+    // A comma-separated list between `open` and `close`
+    fn list<T>(
+        &mut self,
+        open: &str,
+        close: &str,
+        mut item: impl FnMut(&mut Self) -> Result<T, CompileError>,
+    ) -> Result<Vec<T>, CompileError> {
+        self.expect(open)?;
+        let mut items = vec![];
+        while self.peek() != Some(close) {
+            if !items.is_empty() {
+                self.expect(",")?;
+            }
+            items.push(item(self)?);
+        }
+        self.expect(close)?;
+        Ok(items)
+    }
+
+    // `global name`, or `global name = value` where the value is a number or `addr str0`
+    fn global(&mut self) -> Result<(Symbol, Initializer), CompileError> {
+        self.expect("global")?;
+        let name = self.name()?;
+        if self.peek().is_none() {
+            return Ok((name, Initializer::Zero));
+        }
+        self.expect("=")?;
+        let value = match self.peek().map(str::parse) {
+            Some(Ok(value)) => {
+                self.pos += 1;
+                Initializer::Int(value)
+            }
+            _ => {
+                self.expect("addr")?;
+                Initializer::String(self.name()?)
+            }
+        };
+        self.end()?;
+        Ok((name, value))
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let statement = match self.peek() {
+            Some("goto") => {
+                self.pos += 1;
+                Statement::Goto(self.block()?)
+            }
+            Some("ret") => {
+                self.pos += 1;
+                match self.peek() {
+                    None => Statement::Return(None),
+                    Some(_) => Statement::Return(Some(self.var()?)),
+                }
+            }
+            Some("if") => {
+                self.pos += 1;
+                let var = self.var()?;
+                self.expect("goto")?;
+                let goto_true = self.block()?;
+                self.expect("else")?;
+                let goto_false = self.block()?;
+                Statement::If {
+                    var,
+                    goto_true,
+                    goto_false,
+                }
+            }
+            Some("switch") => {
+                self.pos += 1;
+                let var = self.var()?;
+                let targets = self.list("[", "]", Self::block)?;
+                self.expect("else")?;
+                let default = self.block()?;
+                Statement::Switch {
+                    var,
+                    targets,
+                    default,
+                }
+            }
+            Some(word @ ("store" | "store8")) => {
+                let width = if word == "store8" {
+                    Width::I8
+                } else {
+                    Width::I64
+                };
+                self.pos += 1;
+                let src = self.var()?;
+                self.expect(",")?;
+                let addr = self.var()?;
+                Statement::Store { addr, src, width }
+            }
+            Some("line") => {
+                self.pos += 1;
+                let line = self.peek().and_then(|w| w.parse().ok());
+                let Some(line) = line else {
+                    return Err(self.error("a line number"));
+                };
+                self.pos += 1;
+                Statement::Line(line)
+            }
+            _ => {
+                let dest = self.var()?;
+                self.expect("=")?;
+                self.value(dest)?
+            }
+        };
+        self.end()?;
+        Ok(statement)
+    }
+
+    // Everything to the right of `dest =`
+    fn value(&mut self, dest: VReg) -> Result<Statement, CompileError> {
+        let word = self.peek().ok_or_else(|| self.error("a value"))?.to_owned();
+        if let Ok(value) = word.parse() {
+            self.pos += 1;
+            return Ok(Statement::Assign { var: dest, value });
+        }
+        if word == "phi" {
+            self.pos += 1;
+            let sources = self.list("[", "]", |p| {
+                let block = p.block()?;
+                p.expect(":")?;
+                Ok((block, p.var()?))
+            })?;
+            return Ok(Statement::Phi { dest, sources });
+        }
+        if word == "alloca" {
+            self.pos += 1;
+            let size = self.peek().and_then(|w| w.parse().ok());
+            let Some(size) = size else {
+                return Err(self.error("a size in bytes"));
+            };
+            self.pos += 1;
+            return Ok(Statement::Alloca { dest, size });
+        }
+        if word == "load" || word == "load8" {
+            let width = if word == "load8" {
+                Width::I8
+            } else {
+                Width::I64
+            };
+            self.pos += 1;
+            let addr = self.var()?;
+            return Ok(Statement::Load { dest, addr, width });
+        }
+        let extension = match word.as_str() {
+            "sext8" => Some(UnaryOp::Sext8),
+            "sext32" => Some(UnaryOp::Sext32),
+            "zext32" => Some(UnaryOp::Zext32),
+            _ => None,
+        };
+        if let Some(op) = extension {
+            self.pos += 1;
+            let operand = self.var()?;
+            return Ok(Statement::UnaryOperation { dest, op, operand });
+        }
+        if word == "addr" {
+            self.pos += 1;
+            let label = self.name()?;
+            return Ok(Statement::LoadAddress { dest, label });
+        }
+        if word == "call" || word == "tail" {
+            let tail = word == "tail";
+            self.pos += 1;
+            if tail {
+                self.expect("call")?;
+            }
+            let func = self.name()?;
+            let args = self.list("(", ")", Parser::var)?;
+            return Ok(Statement::Call {
+                dest,
+                func,
+                args,
+                tail,
+            });
+        }
+
+        let unary = match word.chars().next() {
+            Some('-') => Some(UnaryOp::Neg),
+            Some('!') => Some(UnaryOp::Not),
+            Some('~') => Some(UnaryOp::BitNot),
+            _ => None,
+        };
+        if let Some(op) = unary {
+            let Some(operand) = parse_var(&word[1..]) else {
+                return Err(self.error("a var after the unary operator"));
+            };
+            self.pos += 1;
+            return Ok(Statement::UnaryOperation { dest, op, operand });
+        }
+
+        let lhs = self.var()?;
+        let op = match self.peek() {
+            None => return Ok(Statement::Copy { dest, src: lhs }),
+            Some("+") => BinOp::Add,
+            Some("-") => BinOp::Sub,
+            Some("*") => BinOp::Mul,
+            Some("/") => BinOp::Div,
+            Some("<<") => BinOp::Shl,
+            Some(">>") => BinOp::Shr,
+            Some("&") => BinOp::And,
+            Some("|") => BinOp::Or,
+            Some("^") => BinOp::Xor,
+            Some("==") => BinOp::Eq,
+            Some("!=") => BinOp::Ne,
+            Some("<") => BinOp::Lt,
+            Some("<=") => BinOp::Le,
+            Some(">") => BinOp::Gt,
+            Some(">=") => BinOp::Ge,
+            Some("/u") => BinOp::UDiv,
+            Some(">>u") => BinOp::UShr,
+            Some("<u") => BinOp::ULt,
+            Some("<=u") => BinOp::ULe,
+            Some(">u") => BinOp::UGt,
+            Some(">=u") => BinOp::UGe,
+            Some(_) => return Err(self.error("an operator")),
+        };
+        self.pos += 1;
+        let rhs = self.var()?;
+        Ok(Statement::Operation { dest, op, lhs, rhs })
+    }
+}
+
+// Parses the blocks of one graph, stopping at a closing brace
+fn parse_blocks(pieces: &[Piece], pos: &mut usize) -> Result<ControlFlowGraph, CompileError> {
+    let mut blocks: BTreeMap<ControlBlockId, ControlBlock> = BTreeMap::new();
+    let mut current: Option<ControlBlockId> = None;
+    while let Some(piece) = pieces.get(*pos) {
+        if piece.text == "}" {
+            break;
+        }
+        *pos += 1;
+
+        // A label may share its line with the block's first statement
+        let mut text = piece.text;
+        let mut span = piece.span;
+        if let Some((label, rest)) = text.split_once(':')
+            && let Some(id) = label.strip_prefix("bb").and_then(|n| n.parse().ok())
+        {
+            if blocks.insert(id, vec![]).is_some() {
+                return Err(error(span, format!("Duplicate block {}", block_name(id))));
+            }
+            current = Some(id);
+            let skipped = text.len() - rest.trim_start().len();
+            span.start += skipped;
+            span.column += skipped as u32;
+            text = rest.trim();
+            if text.is_empty() {
+                continue;
+            }
+        }
+
+        let Some(block) = current else {
+            return Err(error(
+                span,
+                format!("Statement `{}` is not inside a block", text),
+            ));
+        };
+        let statement = Parser::new(&Piece { text, span }).statement()?;
+        let block = blocks
+            .get_mut(&block)
+            .expect("the current block has been added");
+        block.push(statement);
+    }
+
+    if !blocks.contains_key(&0) {
+        return Err(CompileError::ParseError {
+            message: "Missing entry block bb0".to_owned(),
+            span: None,
+        });
+    }
+    let cfg = ControlFlowGraph(blocks);
+    for (id, block) in cfg.iter() {
+        for target in block.iter().flat_map(Statement::successors) {
+            if !cfg.contains_key(&target) {
+                return Err(CompileError::ParseError {
+                    message: format!(
+                        "{} branches to undefined block {}",
+                        block_name(*id),
+                        block_name(target)
+                    ),
+                    span: None,
+                });
+            }
+        }
+    }
+    Ok(cfg)
+}
+
+pub fn parse_cfg(text: &str) -> Result<ControlFlowGraph, CompileError> {
+    let pieces = pieces(text);
+    let mut pos = 0;
+    let cfg = parse_blocks(&pieces, &mut pos)?;
+    match pieces.get(pos) {
+        Some(piece) => Err(error(piece.span, "Unexpected `}`".to_owned())),
+        None => Ok(cfg),
+    }
+}
+
+pub fn parse_program(text: &str) -> Result<Program, CompileError> {
+    let pieces = pieces(text);
+    let mut functions = BTreeMap::new();
+    let mut strings = StringPool::new();
+    let mut globals = Globals::new();
+    let mut pos = 0;
+    while let Some(piece) = pieces.get(pos) {
+        pos += 1;
+        if let Some((label, value)) = string_definition(piece.text) {
+            if strings
+                .insert(Symbol::intern(label), value.to_owned())
+                .is_some()
+            {
+                return Err(error(piece.span, format!("Duplicate string {}", label)));
+            }
+            continue;
+        }
+        let mut parser = Parser::new(piece);
+        if parser.peek() == Some("global") {
+            let (name, value) = parser.global()?;
+            if globals.insert(name, value).is_some() {
+                return Err(error(piece.span, format!("Duplicate global {}", name)));
+            }
+            continue;
+        }
+        let inline = parser.peek() == Some("inline");
+        if inline {
+            parser.expect("inline")?;
+        }
+        parser.expect("fn")?;
+        let name = parser.name()?;
+        let params = parser.list("(", ")", Parser::var)?;
+        parser.expect("{")?;
+        parser.end()?;
+
+        let cfg = parse_blocks(&pieces, &mut pos)?;
+        if pieces.get(pos).is_none() {
+            return Err(error(
+                piece.span,
+                format!("Missing `}}` at the end of {}", name),
+            ));
+        }
+        pos += 1;
+        let function = Function {
+            params,
+            cfg,
+            inline,
+        };
+        if functions.insert(name, function).is_some() {
+            return Err(error(piece.span, format!("Duplicate function {}", name)));
+        }
+    }
+    Ok(Program {
+        functions,
+        strings,
+        globals,
+    })
+}
+
+// The label and contents of a `str0 = "text"` line
+fn string_definition(text: &str) -> Option<(&str, &str)> {
+    let (label, value) = text.split_once('=')?;
+    let label = label.trim();
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    is_name(label).then_some((label, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+
+    #[test]
+    fn test_parse_cfg() -> Result<(), String> {
+        let cfg = parse_cfg(
+            "bb0: v1 = 5; v2 = -v1 // a comment\n\
+             \x20 if v2 goto bb1 else bb2\n\
+             bb1:\n\
+             \x20 v3 = v1 <= v2; ret v3\n\
+             bb2: v4 = phi [bb0: v1]; v5 = tail call f(v4, v1); ret v5",
+        )?;
+        let expected = ControlFlowGraph(BTreeMap::from([
+            (
+                0,
+                vec![
+                    Statement::Assign {
+                        var: VReg(1),
+                        value: 5,
+                    },
+                    Statement::UnaryOperation {
+                        dest: VReg(2),
+                        op: UnaryOp::Neg,
+                        operand: VReg(1),
+                    },
+                    Statement::If {
+                        var: VReg(2),
+                        goto_true: 1,
+                        goto_false: 2,
+                    },
+                ],
+            ),
+            (
+                1,
+                vec![
+                    Statement::Operation {
+                        dest: VReg(3),
+                        op: BinOp::Le,
+                        lhs: VReg(1),
+                        rhs: VReg(2),
+                    },
+                    Statement::Return(Some(VReg(3))),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    Statement::Phi {
+                        dest: VReg(4),
+                        sources: vec![(0, VReg(1))],
+                    },
+                    Statement::Call {
+                        dest: VReg(5),
+                        func: "f".into(),
+                        args: vec![VReg(4), VReg(1)],
+                        tail: true,
+                    },
+                    Statement::Return(Some(VReg(5))),
+                ],
+            ),
+        ]));
+        assert_eq!(cfg, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), String> {
+        let source = "int add(int a, int b) { return a + b; } void nop() { return; } \
+                      int main() { int x = 1; while (x != 4 && !x) { x = x + 1; } \
+                      switch (x) { case 0: case 1: case 2: case 4: x = 2; } \
+                      return add(x, ~x); }";
+        let program = Program::from(&parse(&tokenize(source)?)?)?;
+        let text = program.to_string();
+        assert_eq!(parse_program(&text)?, program);
+        assert_eq!(parse_program(&text)?.to_string(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_constants() -> Result<(), String> {
+        // A negative constant isn't the unary minus of a var
+        let text = "bb0:\n  v1 = -9223372036854775808\n  v2 = -v1\n  v3 = v2 /u v1\n  ret v3\n";
+        let cfg = parse_cfg(text)?;
+        assert_eq!(
+            cfg[&0][0],
+            Statement::Assign {
+                var: VReg(1),
+                value: i64::MIN,
+            }
+        );
+        assert_eq!(cfg.to_string(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_memory() -> Result<(), String> {
+        let text = "bb0:\n  v1 = alloca 8\n  v2 = 3\n  store v2, v1\n  v3 = load v1\n  ret v3\n";
+        let cfg = parse_cfg(text)?;
+        assert_eq!(
+            cfg[&0][2],
+            Statement::Store {
+                addr: VReg(1),
+                src: VReg(2),
+                width: Width::I64,
+            }
+        );
+        assert_eq!(cfg.to_string(), text);
+
+        let text =
+            "bb0:\n  v1 = alloca 8\n  v2 = sext8 v1\n  store8 v2, v1\n  v3 = load8 v1\n  ret v3\n";
+        let cfg = parse_cfg(text)?;
+        assert_eq!(
+            cfg[&0][3],
+            Statement::Load {
+                dest: VReg(3),
+                addr: VReg(1),
+                width: Width::I8,
+            }
+        );
+        assert_eq!(cfg.to_string(), text);
+
+        let text = "bb0:\n  v1 = 4294967295\n  v2 = sext32 v1\n  v3 = zext32 v2\n  ret v3\n";
+        let cfg = parse_cfg(text)?;
+        assert_eq!(
+            cfg[&0][2],
+            Statement::UnaryOperation {
+                dest: VReg(3),
+                op: UnaryOp::Zext32,
+                operand: VReg(2),
+            }
+        );
+        assert_eq!(cfg.to_string(), text);
+        assert_eq!(
+            parse_cfg("bb0: v1 = alloca v2").map_err(|e| e.to_string()),
+            Err("1:6: Expected a size in bytes but found `v2` in `v1 = alloca v2`".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_lines() -> Result<(), String> {
+        let text = "bb0:\n  line 3\n  v1 = 1\n  line 4\n  ret v1\n";
+        let cfg = parse_cfg(text)?;
+        assert_eq!(cfg[&0][2], Statement::Line(4));
+        assert_eq!(cfg.to_string(), text);
+        assert!(parse_cfg("bb0: line x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_strings() -> Result<(), String> {
+        let text = "str0 = \"a; b // c\"\nfn main() {\nbb0:\n  v1 = addr str0\n  ret v1\n}\n";
+        let program = parse_program(text)?;
+        assert_eq!(program.strings[&Symbol::intern("str0")], "a; b // c");
+        assert_eq!(
+            program.functions[&Symbol::intern("main")].cfg[&0][0],
+            Statement::LoadAddress {
+                dest: VReg(1),
+                label: "str0".into(),
+            }
+        );
+        assert_eq!(program.to_string(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_globals() -> Result<(), String> {
+        let text = "str0 = \"a\"\nglobal n\nglobal p = addr str0\nglobal x = -4\n";
+        let program = parse_program(text)?;
+        assert_eq!(
+            program.globals,
+            Globals::from([
+                ("n".into(), Initializer::Zero),
+                ("p".into(), Initializer::String("str0".into())),
+                ("x".into(), Initializer::Int(-4)),
+            ])
+        );
+        assert_eq!(program.to_string(), text);
+        assert!(parse_program("global x = y").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let message = |text: &str| parse_cfg(text).map_err(|e| e.to_string()).map(|_| ());
+        assert_eq!(
+            message("bb0:\n  v1 = 5\n  v2 = v1 +\n"),
+            Err("3:3: Expected a var but found the end of the statement in `v2 = v1 +`".to_owned())
+        );
+        assert_eq!(
+            message("v1 = 5"),
+            Err("1:1: Statement `v1 = 5` is not inside a block".to_owned())
+        );
+        assert_eq!(
+            message("bb0: goto bb1"),
+            Err("bb0 branches to undefined block bb1".to_owned())
+        );
+        assert_eq!(
+            message("bb0: ret v1\nbb0: ret v1"),
+            Err("2:1: Duplicate block bb0".to_owned())
+        );
+    }
+}
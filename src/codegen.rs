@@ -1,23 +1,78 @@
+use crate::asm::{self, Instruction, Operand, imm, mem, op, reg, sym};
 use crate::cfg::*;
-use std::collections::HashMap;
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::liveness::VarSet;
+use crate::regalloc::{Allocation, Assignment};
+use crate::target::{self, CodegenOptions, FrameLayout, RegisterClass, TargetBackend};
 use std::fmt;
 
 /*
-    For now, we'll just assign variables to a few registerss:
-    v1: rbx
-    v2: rcx
-    v3: rdx
-    v4-v11: r8-r15
+    The register allocator hands out rax, rbx, rcx, rdx, r8, r9, and r12-r15, taking the
+    caller-saved ones first in a function that makes no calls. r10 and r11 are left free as
+    scratch registers.
+
+    A spilled var gets an 8-byte slot in the stack frame, and is reloaded into a scratch
+    register before each use and stored back after each definition. The frame sits below
+    %rbp, which the prologue pushes and then points at the stack pointer. The callee-saved
+    registers the function uses are saved at the top of the frame, then come the stack
+    slots from Alloca, then the spill slots. Each return puts its value in %rax, restores
+    the saved registers and %rbp, and returns with `ret`, as the System V ABI expects.
+
+    Calls follow the same ABI. The first six arguments go in rdi, rsi, rdx, rcx, r8, and r9,
+    and the rest are pushed, last one first, so they sit above the callee's return address.
+    %rsp is 16-byte aligned at the call, and the result comes back in %rax. The callee may
+    clobber rax, rcx, rdx, r8, and r9, so any of them holding a var that's still live after
+    the call is pushed before it and popped after.
+
+    The Microsoft x64 ABI, for --target=x86_64-windows, differs only in how arguments are
+    passed. The first four go in rcx, rdx, r8, and r9, and the caller reserves 32 bytes of
+    "shadow space" for the callee just below the stacked ones. It also lets a callee clobber
+    the same allocatable registers and makes it preserve the same ones, since rdi and rsi,
+    which it adds to the callee-saved set, are only ever written to pass arguments.
 */
 
-const ASM_HEADER: [&'static str; 2] = [".global _start", "_start:"];
-const SYSCALL_EXIT: u8 = 60;
+// How a calling convention passes arguments
+struct Abi {
+    arguments: &'static [RegisterGP],
+    shadow_space: u64, // bytes the caller reserves below the stacked arguments
+    plt: bool,         // whether calls to other modules name the callee's PLT entry
+    // Whether a variadic callee expects %al to say how many vector registers hold arguments
+    vector_count: bool,
+}
+
+const SYSTEM_V: Abi = Abi {
+    arguments: &[
+        RegisterGP::Rdi,
+        RegisterGP::Rsi,
+        RegisterGP::Rdx,
+        RegisterGP::Rcx,
+        RegisterGP::R8,
+        RegisterGP::R9,
+    ],
+    shadow_space: 0,
+    plt: true,
+    vector_count: true,
+};
 
-enum RegisterGP {
-    RAX,
-    RBX,
-    RCX,
-    RDX,
+const MICROSOFT_X64: Abi = Abi {
+    arguments: &[
+        RegisterGP::Rcx,
+        RegisterGP::Rdx,
+        RegisterGP::R8,
+        RegisterGP::R9,
+    ],
+    shadow_space: 32,
+    plt: false,
+    vector_count: false,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterGP {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
     R8,
     R9,
     R10,
@@ -26,15 +81,20 @@ enum RegisterGP {
     R13,
     R14,
     R15,
+    Rdi,
+    Rsi,
+    Rbp,
+    Rsp,
 }
 
 impl fmt::Display for RegisterGP {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s = match self {
-            RegisterGP::RAX => "rax",
-            RegisterGP::RBX => "rbx",
-            RegisterGP::RCX => "rcx",
-            RegisterGP::RDX => "rdx",
+            RegisterGP::Rax => "rax",
+            RegisterGP::Rbx => "rbx",
+            RegisterGP::Rcx => "rcx",
+            RegisterGP::Rdx => "rdx",
+            RegisterGP::R8 => "r8",
             RegisterGP::R9 => "r9",
             RegisterGP::R10 => "r10",
             RegisterGP::R11 => "r11",
@@ -42,82 +102,1538 @@ impl fmt::Display for RegisterGP {
             RegisterGP::R13 => "r13",
             RegisterGP::R14 => "r14",
             RegisterGP::R15 => "r15",
-            _ => "",
+            RegisterGP::Rdi => "rdi",
+            RegisterGP::Rsi => "rsi",
+            RegisterGP::Rbp => "rbp",
+            RegisterGP::Rsp => "rsp",
         };
         write!(f, "{}", s)
     }
 }
 
-fn var_to_reg(var: &CfgVarName) -> Result<RegisterGP, String> {
-    match var.as_str() {
-        "v1" => Ok(RegisterGP::RAX),
-        "v2" => Ok(RegisterGP::RBX),
-        "v3" => Ok(RegisterGP::RCX),
-        "v4" => Ok(RegisterGP::RDX),
-        "v5" => Ok(RegisterGP::R8),
-        "v6" => Ok(RegisterGP::R9),
-        _ => Err(format!("Could not map var {}", var)),
+impl RegisterGP {
+    // The name of the register's low byte, for moving a char
+    fn byte(&self) -> &'static str {
+        match self {
+            RegisterGP::Rax => "al",
+            RegisterGP::Rbx => "bl",
+            RegisterGP::Rcx => "cl",
+            RegisterGP::Rdx => "dl",
+            RegisterGP::R8 => "r8b",
+            RegisterGP::R9 => "r9b",
+            RegisterGP::R10 => "r10b",
+            RegisterGP::R11 => "r11b",
+            RegisterGP::R12 => "r12b",
+            RegisterGP::R13 => "r13b",
+            RegisterGP::R14 => "r14b",
+            RegisterGP::R15 => "r15b",
+            RegisterGP::Rdi => "dil",
+            RegisterGP::Rsi => "sil",
+            RegisterGP::Rbp => "bpl",
+            RegisterGP::Rsp => "spl",
+        }
+    }
+}
+
+// rdi and rsi are caller-saved in the System V ABI, but they're only ever written to pass
+// arguments, so they're reserved along with the scratch registers and the stack
+const REGISTERS: [(RegisterGP, RegisterClass); 16] = [
+    (RegisterGP::Rax, RegisterClass::CallerSaved),
+    (RegisterGP::Rbx, RegisterClass::CalleeSaved),
+    (RegisterGP::Rcx, RegisterClass::CallerSaved),
+    (RegisterGP::Rdx, RegisterClass::CallerSaved),
+    (RegisterGP::R8, RegisterClass::CallerSaved),
+    (RegisterGP::R9, RegisterClass::CallerSaved),
+    (RegisterGP::R12, RegisterClass::CalleeSaved),
+    (RegisterGP::R13, RegisterClass::CalleeSaved),
+    (RegisterGP::R14, RegisterClass::CalleeSaved),
+    (RegisterGP::R15, RegisterClass::CalleeSaved),
+    (RegisterGP::R10, RegisterClass::Reserved),
+    (RegisterGP::R11, RegisterClass::Reserved),
+    (RegisterGP::Rdi, RegisterClass::Reserved),
+    (RegisterGP::Rsi, RegisterClass::Reserved),
+    (RegisterGP::Rbp, RegisterClass::Reserved),
+    (RegisterGP::Rsp, RegisterClass::Reserved),
+];
+
+// Where a var's value is kept
+#[derive(Clone, Copy)]
+enum Location {
+    Register(RegisterGP),
+    Stack(u64), // offset below %rbp
+}
+
+impl Location {
+    fn operand(self) -> Operand<RegisterGP> {
+        match self {
+            Location::Register(reg) => Operand::Register(reg),
+            Location::Stack(offset) => rbp(offset),
+        }
+    }
+}
+
+type Asm = Vec<Instruction<RegisterGP>>;
+
+// The frame slot `offset` bytes below %rbp
+fn rbp(offset: u64) -> Operand<RegisterGP> {
+    mem(RegisterGP::Rbp, -(offset as i64))
+}
+
+// AT&T syntax, as the GNU assembler takes it
+fn att_operand(operand: &Operand<RegisterGP>) -> String {
+    match operand {
+        Operand::Register(reg) => format!("%{}", reg),
+        Operand::ByteRegister(reg) => format!("%{}", reg.byte()),
+        Operand::Immediate(value) => format!("${}", value),
+        Operand::Memory { base, offset: 0 } => format!("(%{})", base),
+        Operand::Memory { base, offset } => format!("{}(%{})", offset, base),
+        Operand::Indexed { base, index, scale } => format!("(%{},%{},{})", base, index, scale),
+        Operand::PcRelative(label) => format!("{}(%rip)", label),
+        Operand::Symbol(name) => name.clone(),
+        Operand::Indirect(reg) => format!("*%{}", reg),
+    }
+}
+
+// The location of every var in a function, the offset of each Alloca's slot, and where
+// each callee-saved register the function uses is saved
+pub struct Frame {
+    locations: VRegMap<Location>,
+    slots: VRegMap<u64>,
+    saved: Vec<(RegisterGP, u64)>,
+    size: u64, // what the prologue subtracts from %rsp
+    abi: &'static Abi,
+    pic: bool,
+}
+
+impl Frame {
+    fn new(cfg: &ControlFlowGraph, allocation: &Allocation, abi: &'static Abi, pic: bool) -> Self {
+        let used = |reg: &RegisterGP| {
+            allocation
+                .values()
+                .any(|a| *a == Assignment::Register(*reg))
+        };
+        let mut layout = FrameLayout::default();
+        let callee_saved = target::registers_in(&REGISTERS, RegisterClass::CalleeSaved);
+        let saved = layout.save(callee_saved.filter(used));
+
+        let mut slots = VRegMap::new();
+        for statement in cfg.values().flatten() {
+            if let Statement::Alloca { dest, size } = statement {
+                slots.insert(*dest, layout.slot(*size));
+            }
+        }
+
+        let mut locations = VRegMap::new();
+        let vars = cfg
+            .values()
+            .flatten()
+            .flat_map(|s| s.used_vars().into_iter().chain(s.defined_var()));
+        for var in vars {
+            if locations.contains_key(var) {
+                continue;
+            }
+            let location = match allocation.get(var) {
+                Some(Assignment::Register(reg)) => Location::Register(*reg),
+                _ => Location::Stack(layout.slot(8)),
+            };
+            locations.insert(*var, location);
+        }
+
+        Frame {
+            locations,
+            slots,
+            saved,
+            size: layout.size(),
+            abi,
+            pic,
+        }
+    }
+
+    // Sets up %rbp, makes room for the frame, and saves the callee-saved registers
+    fn prologue(&self) -> Asm {
+        let mut asm = vec![
+            op("push", [reg(RegisterGP::Rbp)]),
+            op("mov", [reg(RegisterGP::Rsp), reg(RegisterGP::Rbp)]),
+        ];
+        if self.size > 0 {
+            asm.push(op("sub", [imm(self.size as i64), reg(RegisterGP::Rsp)]));
+        }
+        for (saved, offset) in &self.saved {
+            asm.push(op("mov", [reg(*saved), rbp(*offset)]));
+        }
+        asm
+    }
+
+    // Undoes the prologue, leaving the return address on top of the stack
+    fn restore(&self) -> Asm {
+        let mut asm: Asm = self
+            .saved
+            .iter()
+            .map(|(saved, offset)| op("mov", [rbp(*offset), reg(*saved)]))
+            .collect();
+        asm.extend([
+            op("mov", [reg(RegisterGP::Rbp), reg(RegisterGP::Rsp)]),
+            op("pop", [reg(RegisterGP::Rbp)]),
+        ]);
+        asm
+    }
+
+    // Undoes the prologue and returns to the caller
+    fn epilogue(&self) -> Asm {
+        let mut asm = self.restore();
+        asm.push(op("ret", []));
+        asm
+    }
+
+    fn location(&self, var: &VReg) -> Result<Location, CompileError> {
+        self.locations
+            .get(var)
+            .copied()
+            .ok_or_else(|| CompileError::CodegenError(format!("Could not map var {}", var)))
+    }
+
+    // The register holding var's value, reloading it into scratch first if it's spilled
+    fn read(
+        &self,
+        var: &VReg,
+        scratch: RegisterGP,
+        asm: &mut Asm,
+    ) -> Result<RegisterGP, CompileError> {
+        match self.location(var)? {
+            Location::Register(reg) => Ok(reg),
+            location => {
+                asm.push(op("mov", [location.operand(), reg(scratch)]));
+                Ok(scratch)
+            }
+        }
+    }
+
+    // The register to compute var's new value in. If var is spilled that's scratch, and
+    // `store` has to be called once the value is there.
+    fn write(&self, var: &VReg, scratch: RegisterGP) -> Result<RegisterGP, CompileError> {
+        match self.location(var)? {
+            Location::Register(reg) => Ok(reg),
+            Location::Stack(_) => Ok(scratch),
+        }
+    }
+
+    // Stores the value computed in `src` back to var's slot if var is spilled
+    fn store(&self, var: &VReg, src: RegisterGP, asm: &mut Asm) -> Result<(), CompileError> {
+        if let location @ Location::Stack(_) = self.location(var)? {
+            asm.push(op("mov", [reg(src), location.operand()]));
+        }
+        Ok(())
+    }
+}
+
+// A register-to-register move, left out when both are the same
+fn mov(src: RegisterGP, dest: RegisterGP) -> Asm {
+    if src == dest {
+        return vec![];
     }
+    vec![op("mov", [reg(src), reg(dest)])]
 }
 
-fn assign_to_asm(var: &CfgVarName, value: u64) -> Result<Vec<String>, String> {
-    Ok(vec![format!("mov ${}, %{}", value, var_to_reg(var)?)])
+// The table holds each label's offset from the table itself, so that it needs no relocation
+// once linked, even in a position-independent executable. Indices past the end, negative
+// ones included, are caught by an unsigned comparison.
+fn jump_table_to_asm(
+    frame: &Frame,
+    var: &VReg,
+    table: &str,
+    labels: &[String],
+    default: &str,
+    section: &str,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let index = frame.read(var, RegisterGP::R10, &mut asm)?;
+    let entry = Operand::Indexed {
+        base: RegisterGP::R11,
+        index,
+        scale: 4,
+    };
+    asm.extend([
+        op("cmp", [imm(labels.len() as i64), reg(index)]),
+        op("jae", [sym(default)]),
+        op(
+            "lea",
+            [Operand::PcRelative(table.to_owned()), reg(RegisterGP::R11)],
+        ),
+        op("movslq", [entry, reg(RegisterGP::R10)]),
+        op("add", [reg(RegisterGP::R11), reg(RegisterGP::R10)]),
+        op("jmp", [Operand::Indirect(RegisterGP::R10)]),
+        asm::directive(format!(".section {}", section)),
+        asm::directive(".align 4"),
+        asm::label(table),
+    ]);
+    asm.extend(
+        labels
+            .iter()
+            .map(|label| asm::directive(format!(".long {}-{}", label, table))),
+    );
+    asm.push(asm::directive(".text"));
+    Ok(asm)
 }
 
-fn return_to_asm(var: &CfgVarName) -> Result<Vec<String>, String> {
-    Ok(vec![
-        // Here we're ok with blowing away %rdi and %rax because we're returning from main anyway.
-        // TODO: this will have to be smarter once we have more than one function
-        format!("mov %{}, %rdi", var_to_reg(var)?),
-        format!("mov ${}, %rax", SYSCALL_EXIT),
-        "syscall".to_owned(),
-    ])
+// Without a value, %rax is left holding whatever it happens to
+fn return_to_asm(frame: &Frame, var: Option<&VReg>) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    if let Some(var) = var {
+        let src = frame.read(var, RegisterGP::R10, &mut asm)?;
+        asm.extend(mov(src, RegisterGP::Rax));
+    }
+    asm.extend(frame.epilogue());
+    Ok(asm)
 }
 
-pub fn cfg_to_asm(cfg: &crate::cfg::ControlFlowGraph) -> Result<Vec<String>, String> {
-    assert_eq!(cfg.len(), 1); // Right now we're only considering programs with no control flow. These programs should have one control block
-    assert!(cfg.contains_key(&0)); // The one control block should have ID 0
+fn operation_to_asm(
+    frame: &Frame,
+    dest_var: &VReg,
+    op: &BinOp,
+    lhs: &VReg,
+    rhs: &VReg,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let lhs = frame.read(lhs, RegisterGP::R10, &mut asm)?;
+    let rhs = frame.read(rhs, RegisterGP::R11, &mut asm)?;
+    let dest = frame.write(dest_var, RegisterGP::R10)?;
+    let instruction = match op {
+        BinOp::Add => "add",
+        BinOp::Sub => "sub",
+        BinOp::Mul => "imul",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+        BinOp::Xor => "xor",
+        // A variable shift count has to be in cl, so rcx is saved around the shift the way
+        // idiv's registers are, with the operands in the scratch registers
+        BinOp::Shl | BinOp::Shr | BinOp::UShr => {
+            let instruction = match op {
+                BinOp::Shl => "sal",
+                BinOp::Shr => "sar",
+                _ => "shr",
+            };
+            asm.push(asm::op("push", [reg(RegisterGP::Rcx)]));
+            asm.extend(mov(lhs, RegisterGP::R10));
+            asm.extend(mov(rhs, RegisterGP::R11));
+            asm.extend([
+                asm::op("mov", [reg(RegisterGP::R11), reg(RegisterGP::Rcx)]),
+                asm::op(
+                    instruction,
+                    [Operand::ByteRegister(RegisterGP::Rcx), reg(RegisterGP::R10)],
+                ),
+                asm::op("pop", [reg(RegisterGP::Rcx)]),
+            ]);
+            asm.extend(mov(RegisterGP::R10, dest));
+            frame.store(dest_var, dest, &mut asm)?;
+            return Ok(asm);
+        }
+        // The comparison's flags pick the byte of the scratch register r11, which is then
+        // widened into dest. Unsigned comparisons go by below and above rather than less and
+        // greater.
+        BinOp::Eq
+        | BinOp::Ne
+        | BinOp::Lt
+        | BinOp::Le
+        | BinOp::Gt
+        | BinOp::Ge
+        | BinOp::ULt
+        | BinOp::ULe
+        | BinOp::UGt
+        | BinOp::UGe => {
+            let set = match op {
+                BinOp::Eq => "sete",
+                BinOp::Ne => "setne",
+                BinOp::Lt => "setl",
+                BinOp::Le => "setle",
+                BinOp::Gt => "setg",
+                BinOp::Ge => "setge",
+                BinOp::ULt => "setb",
+                BinOp::ULe => "setbe",
+                BinOp::UGt => "seta",
+                _ => "setae",
+            };
+            let flag = Operand::ByteRegister(RegisterGP::R11);
+            asm.extend([
+                asm::op("cmp", [reg(rhs), reg(lhs)]),
+                asm::op(set, [flag.clone()]),
+                asm::op("movzbq", [flag, reg(dest)]),
+            ]);
+            frame.store(dest_var, dest, &mut asm)?;
+            return Ok(asm);
+        }
+        // idiv divides rdx:rax, so both are saved around it and the operands go through the
+        // scratch registers r10 and r11 in case either lives in one of them. It takes the
+        // dividend sign-extended into rdx, where div takes it zero-extended.
+        BinOp::Div | BinOp::UDiv => {
+            let (extend, divide) = match op {
+                BinOp::Div => (asm::op("cqo", []), "idiv"),
+                _ => (
+                    asm::op("xor", [reg(RegisterGP::Rdx), reg(RegisterGP::Rdx)]),
+                    "div",
+                ),
+            };
+            asm.extend([
+                asm::op("push", [reg(RegisterGP::Rax)]),
+                asm::op("push", [reg(RegisterGP::Rdx)]),
+            ]);
+            asm.extend(mov(lhs, RegisterGP::R10));
+            asm.extend(mov(rhs, RegisterGP::R11));
+            asm.extend([
+                asm::op("mov", [reg(RegisterGP::R10), reg(RegisterGP::Rax)]),
+                extend,
+                asm::op(divide, [reg(RegisterGP::R11)]),
+                asm::op("mov", [reg(RegisterGP::Rax), reg(RegisterGP::R10)]),
+                asm::op("pop", [reg(RegisterGP::Rdx)]),
+                asm::op("pop", [reg(RegisterGP::Rax)]),
+            ]);
+            asm.extend(mov(RegisterGP::R10, dest));
+            frame.store(dest_var, dest, &mut asm)?;
+            return Ok(asm);
+        }
+    };
+    // The allocators never give dest the same register as rhs
+    asm.extend(mov(lhs, dest));
+    asm.push(asm::op(instruction, [reg(rhs), reg(dest)]));
+    frame.store(dest_var, dest, &mut asm)?;
+    Ok(asm)
+}
 
-    let block = cfg.get(&0).unwrap();
-    let mut asm: Vec<String> = ASM_HEADER.iter().map(|&s| s.to_owned()).collect();
-    for s in block {
-        let statement_asm = match s {
-            Statement::Assign { var, value } => assign_to_asm(var, *value)?,
-            Statement::Return(var) => return_to_asm(var)?,
-            _ => return Err("".to_owned()),
+fn unary_operation_to_asm(
+    frame: &Frame,
+    dest_var: &VReg,
+    op: &UnaryOp,
+    operand: &VReg,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let operand = frame.read(operand, RegisterGP::R10, &mut asm)?;
+    let dest = frame.write(dest_var, RegisterGP::R10)?;
+    match op {
+        UnaryOp::Neg | UnaryOp::BitNot => {
+            let instruction = if *op == UnaryOp::Neg { "neg" } else { "not" };
+            asm.extend(mov(operand, dest));
+            asm.push(asm::op(instruction, [reg(dest)]));
+        }
+        UnaryOp::Not => asm.extend([
+            asm::op("cmp", [imm(0), reg(operand)]),
+            asm::op("sete", [Operand::ByteRegister(RegisterGP::R11)]),
+            asm::op(
+                "movzbq",
+                [Operand::ByteRegister(RegisterGP::R11), reg(dest)],
+            ),
+        ]),
+        UnaryOp::Sext8 => asm.push(asm::op(
+            "movsbq",
+            [Operand::ByteRegister(operand), reg(dest)],
+        )),
+        // The low 32 bits are shifted up to the top and back down, bringing the sign or
+        // zeros with them
+        UnaryOp::Sext32 | UnaryOp::Zext32 => {
+            let instruction = if *op == UnaryOp::Sext32 { "sar" } else { "shr" };
+            asm.extend(mov(operand, dest));
+            asm.extend([
+                asm::op("sal", [imm(32), reg(dest)]),
+                asm::op(instruction, [imm(32), reg(dest)]),
+            ]);
+        }
+    }
+    frame.store(dest_var, dest, &mut asm)?;
+    Ok(asm)
+}
+
+// The statements that just put a value in dest, with at most one var to read
+fn definition_to_asm(
+    frame: &Frame,
+    program: &Program,
+    statement: &Statement,
+    dest_var: &VReg,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let dest = frame.write(dest_var, RegisterGP::R11)?;
+    match statement {
+        // mov only takes an immediate that's sign-extended from 32 bits, and movabs any
+        Statement::Assign { value, .. } => {
+            let instruction = match i32::try_from(*value) {
+                Ok(_) => "mov",
+                Err(_) => "movabs",
+            };
+            asm.push(op(instruction, [imm(*value), reg(dest)]))
+        }
+        Statement::Copy { src, .. } => {
+            let src = frame.read(src, RegisterGP::R10, &mut asm)?;
+            asm.extend(mov(src, dest));
+        }
+        Statement::Alloca { dest: slot, .. } => {
+            asm.push(op("lea", [rbp(frame.slots[slot]), reg(dest)]))
+        }
+        Statement::Load { addr, width, .. } => {
+            let addr = frame.read(addr, RegisterGP::R10, &mut asm)?;
+            match width {
+                Width::I8 => asm.push(op("movsbq", [mem(addr, 0), reg(dest)])),
+                Width::I64 => asm.push(op("mov", [mem(addr, 0), reg(dest)])),
+            }
+        }
+        // A global's address comes from its GOT entry in position-independent code
+        Statement::LoadAddress { label, .. }
+            if frame.pic && program.globals.contains_key(label) =>
+        {
+            let entry = Operand::PcRelative(format!("{}@GOTPCREL", label));
+            asm.push(op("mov", [entry, reg(dest)]))
+        }
+        Statement::LoadAddress { label, .. } => {
+            let address = Operand::PcRelative(target::symbol(program, *label));
+            asm.push(op("lea", [address, reg(dest)]))
+        }
+        _ => unreachable!(),
+    }
+    frame.store(dest_var, dest, &mut asm)?;
+    Ok(asm)
+}
+
+// Moves each parameter from where the caller left it into the location it was allocated
+fn parameters_to_asm(frame: &Frame, params: &[VReg]) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    // The argument registers can also be where parameters are allocated, so they're all
+    // pushed before any of them is overwritten
+    let arguments = frame.abi.arguments;
+    let in_registers = params.len().min(arguments.len());
+    for argument in &arguments[..in_registers] {
+        asm.push(op("push", [reg(*argument)]));
+    }
+    for (i, param) in params.iter().enumerate().rev() {
+        // A parameter the body never reads has no location
+        let dest = if frame.locations.contains_key(param) {
+            frame.write(param, RegisterGP::R10)?
+        } else if i < in_registers {
+            RegisterGP::R10
+        } else {
+            continue;
         };
-        asm.extend(statement_asm);
+        if i < in_registers {
+            asm.push(op("pop", [reg(dest)]));
+        } else {
+            // Above the saved %rbp, the return address, and the shadow space
+            let offset = 16 + frame.abi.shadow_space + 8 * (i - in_registers) as u64;
+            asm.push(op("mov", [mem(RegisterGP::Rbp, offset as i64), reg(dest)]));
+        }
+        if frame.locations.contains_key(param) {
+            frame.store(param, dest, &mut asm)?;
+        }
+    }
+    Ok(asm)
+}
+
+// Only a function the program doesn't define can be variadic, like printf, and no argument
+// is ever in a vector register
+fn vector_count(frame: &Frame, program: &Program, func: Symbol) -> Asm {
+    match frame.abi.vector_count && !program.functions.contains_key(&func) {
+        true => vec![op("mov", [imm(0), reg(RegisterGP::Rax)])],
+        false => vec![],
+    }
+}
+
+// Functions the program doesn't define are linked in from a shared library
+fn callee(frame: &Frame, program: &Program, func: Symbol) -> Operand<RegisterGP> {
+    let external = !program.functions.contains_key(&func);
+    match frame.abi.plt && (frame.pic || external) {
+        true => sym(format!("{}@PLT", func)),
+        false => sym(func),
+    }
+}
+
+// `live` is what's live just after the call
+fn call_to_asm(
+    frame: &Frame,
+    program: &Program,
+    dest_var: &VReg,
+    func: Symbol,
+    args: &[VReg],
+    live: &VarSet,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let mut saved = vec![];
+    for var in live.iter().filter(|var| *var != dest_var) {
+        if let Location::Register(live_reg) = frame.location(var)?
+            && target::class_of(&REGISTERS, &live_reg) == RegisterClass::CallerSaved
+            && !saved.contains(&live_reg)
+        {
+            asm.push(op("push", [reg(live_reg)]));
+            saved.push(live_reg);
+        }
+    }
+
+    let arguments = frame.abi.arguments;
+    let in_registers = args.len().min(arguments.len());
+    let on_stack = args.len() - in_registers;
+    // The frame keeps %rsp aligned, so an odd number of pushes needs a word of padding
+    let padding = (saved.len() + on_stack) % 2;
+    if padding == 1 {
+        asm.push(op("sub", [imm(8), reg(RegisterGP::Rsp)]));
+    }
+    // Every argument is pushed before any argument register is written, since those can
+    // hold other arguments. The ones that go in registers are then popped back off.
+    for arg in args.iter().rev() {
+        let src = frame.read(arg, RegisterGP::R10, &mut asm)?;
+        asm.push(op("push", [reg(src)]));
+    }
+    for argument in &arguments[..in_registers] {
+        asm.push(op("pop", [reg(*argument)]));
+    }
+    let shadow_space = frame.abi.shadow_space;
+    if shadow_space > 0 {
+        asm.push(op("sub", [imm(shadow_space as i64), reg(RegisterGP::Rsp)]));
+    }
+
+    asm.extend(vector_count(frame, program, func));
+    asm.push(op("call", [callee(frame, program, func)]));
+    let pushed = 8 * (on_stack + padding) as u64 + shadow_space;
+    if pushed > 0 {
+        asm.push(op("add", [imm(pushed as i64), reg(RegisterGP::Rsp)]));
+    }
+
+    // dest is never in one of the saved registers, since they hold vars live alongside it
+    let dest = frame.write(dest_var, RegisterGP::R11)?;
+    asm.extend(mov(RegisterGP::Rax, dest));
+    frame.store(dest_var, dest, &mut asm)?;
+    for saved_reg in saved.iter().rev() {
+        asm.push(op("pop", [reg(*saved_reg)]));
     }
     Ok(asm)
 }
 
+// Only when every argument goes in a register, since the stack above the return address
+// belongs to the caller's caller
+fn tail_call_to_asm(
+    frame: &Frame,
+    program: &Program,
+    func: Symbol,
+    args: &[VReg],
+) -> Result<Option<Asm>, CompileError> {
+    let arguments = frame.abi.arguments;
+    if args.len() > arguments.len() {
+        return Ok(None);
+    }
+    // Restoring the frame only touches callee-saved registers, which never pass arguments
+    let mut asm = vec![];
+    for arg in args.iter().rev() {
+        let src = frame.read(arg, RegisterGP::R10, &mut asm)?;
+        asm.push(op("push", [reg(src)]));
+    }
+    for argument in &arguments[..args.len()] {
+        asm.push(op("pop", [reg(*argument)]));
+    }
+    asm.extend(vector_count(frame, program, func));
+    asm.extend(frame.restore());
+    asm.push(op("jmp", [callee(frame, program, func)]));
+    Ok(Some(asm))
+}
+
+pub struct X86_64;
+
+impl TargetBackend for X86_64 {
+    type Register = RegisterGP;
+    type Frame<'a> = Frame;
+
+    const REGISTERS: &'static [(RegisterGP, RegisterClass)] = &REGISTERS;
+
+    fn frame(cfg: &ControlFlowGraph, allocation: &Allocation, options: &CodegenOptions) -> Frame {
+        Frame::new(cfg, allocation, &SYSTEM_V, options.pic)
+    }
+
+    fn enter(name: &str, frame: &Frame, params: &[VReg]) -> Result<Asm, CompileError> {
+        let mut asm = vec![
+            asm::directive(format!(".global {}", name)),
+            asm::label(name),
+        ];
+        asm.extend(frame.prologue());
+        asm.extend(parameters_to_asm(frame, params)?);
+        Ok(asm)
+    }
+
+    fn jump(label: &str) -> Instruction<RegisterGP> {
+        op("jmp", [sym(label)])
+    }
+
+    fn branch(frame: &Frame, var: &VReg, label: &str, if_zero: bool) -> Result<Asm, CompileError> {
+        let mut asm = vec![];
+        let var = frame.read(var, RegisterGP::R10, &mut asm)?;
+        let jump = if if_zero { "je" } else { "jne" };
+        asm.extend([op("cmp", [imm(0), reg(var)]), op(jump, [sym(label)])]);
+        Ok(asm)
+    }
+
+    fn jump_table(
+        frame: &Frame,
+        var: &VReg,
+        table: &str,
+        labels: &[String],
+        default: &str,
+    ) -> Result<Asm, CompileError> {
+        jump_table_to_asm(frame, var, table, labels, default, ".rodata")
+    }
+
+    fn ret(frame: &Frame, var: Option<&VReg>) -> Result<Asm, CompileError> {
+        return_to_asm(frame, var)
+    }
+
+    fn call(
+        frame: &Frame,
+        program: &Program,
+        dest: &VReg,
+        func: Symbol,
+        args: &[VReg],
+        live: &VarSet,
+    ) -> Result<Asm, CompileError> {
+        call_to_asm(frame, program, dest, func, args, live)
+    }
+
+    fn tail_call(
+        frame: &Frame,
+        program: &Program,
+        func: Symbol,
+        args: &[VReg],
+    ) -> Result<Option<Asm>, CompileError> {
+        tail_call_to_asm(frame, program, func, args)
+    }
+
+    fn instruction(
+        frame: &Frame,
+        program: &Program,
+        statement: &Statement,
+    ) -> Result<Asm, CompileError> {
+        match statement {
+            Statement::Assign { var: dest, .. }
+            | Statement::Copy { dest, .. }
+            | Statement::Alloca { dest, .. }
+            | Statement::Load { dest, .. }
+            | Statement::LoadAddress { dest, .. } => {
+                definition_to_asm(frame, program, statement, dest)
+            }
+            Statement::Operation { dest, op, lhs, rhs } => {
+                operation_to_asm(frame, dest, op, lhs, rhs)
+            }
+            Statement::UnaryOperation { dest, op, operand } => {
+                unary_operation_to_asm(frame, dest, op, operand)
+            }
+            Statement::Store { addr, src, width } => {
+                let mut asm = vec![];
+                let addr = frame.read(addr, RegisterGP::R10, &mut asm)?;
+                let src = frame.read(src, RegisterGP::R11, &mut asm)?;
+                match width {
+                    Width::I8 => asm.push(op("movb", [Operand::ByteRegister(src), mem(addr, 0)])),
+                    Width::I64 => asm.push(op("mov", [reg(src), mem(addr, 0)])),
+                }
+                Ok(asm)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // The stack is 16-byte aligned at _start, so it's aligned as main expects once the
+    // call pushes its return address. 60 is exit on Linux.
+    fn start() -> Asm {
+        vec![
+            asm::directive(".global _start"),
+            asm::label("_start"),
+            // The kernel leaves argc on top of the stack, with argv's pointers right above it
+            op("mov", [mem(RegisterGP::Rsp, 0), reg(RegisterGP::Rdi)]),
+            op("lea", [mem(RegisterGP::Rsp, 8), reg(RegisterGP::Rsi)]),
+            op("call", [sym("main")]),
+            op("mov", [reg(RegisterGP::Rax), reg(RegisterGP::Rdi)]),
+            op("mov", [imm(60), reg(RegisterGP::Rax)]),
+            op("syscall", []),
+        ]
+    }
+
+    fn render(instruction: &Instruction<RegisterGP>) -> String {
+        asm::render(instruction, att_operand)
+    }
+}
+
+// The same code with the Microsoft x64 calling convention, for PE/COFF toolchains like
+// MinGW. Everything but the frame and the data section names is shared with X86_64.
+pub struct X86_64Windows;
+
+impl TargetBackend for X86_64Windows {
+    type Register = RegisterGP;
+    type Frame<'a> = Frame;
+
+    const REGISTERS: &'static [(RegisterGP, RegisterClass)] = &REGISTERS;
+
+    fn frame(cfg: &ControlFlowGraph, allocation: &Allocation, options: &CodegenOptions) -> Frame {
+        Frame::new(cfg, allocation, &MICROSOFT_X64, options.pic)
+    }
+
+    fn enter(name: &str, frame: &Frame, params: &[VReg]) -> Result<Asm, CompileError> {
+        X86_64::enter(name, frame, params)
+    }
+
+    fn jump(label: &str) -> Instruction<RegisterGP> {
+        X86_64::jump(label)
+    }
+
+    fn branch(frame: &Frame, var: &VReg, label: &str, if_zero: bool) -> Result<Asm, CompileError> {
+        X86_64::branch(frame, var, label, if_zero)
+    }
+
+    fn jump_table(
+        frame: &Frame,
+        var: &VReg,
+        table: &str,
+        labels: &[String],
+        default: &str,
+    ) -> Result<Asm, CompileError> {
+        jump_table_to_asm(frame, var, table, labels, default, ".rdata,\"dr\"")
+    }
+
+    fn ret(frame: &Frame, var: Option<&VReg>) -> Result<Asm, CompileError> {
+        X86_64::ret(frame, var)
+    }
+
+    fn call(
+        frame: &Frame,
+        program: &Program,
+        dest: &VReg,
+        func: Symbol,
+        args: &[VReg],
+        live: &VarSet,
+    ) -> Result<Asm, CompileError> {
+        X86_64::call(frame, program, dest, func, args, live)
+    }
+
+    fn tail_call(
+        frame: &Frame,
+        program: &Program,
+        func: Symbol,
+        args: &[VReg],
+    ) -> Result<Option<Asm>, CompileError> {
+        X86_64::tail_call(frame, program, func, args)
+    }
+
+    fn instruction(
+        frame: &Frame,
+        program: &Program,
+        statement: &Statement,
+    ) -> Result<Asm, CompileError> {
+        X86_64::instruction(frame, program, statement)
+    }
+
+    // Windows has no stable system call interface, so the process exits through
+    // kernel32's ExitProcess. The entry point is called with %rsp 8 bytes off alignment,
+    // and 40 more bytes realign it and make the shadow space.
+    fn start() -> Asm {
+        vec![
+            asm::directive(".global mainCRTStartup"),
+            asm::label("mainCRTStartup"),
+            op("sub", [imm(40), reg(RegisterGP::Rsp)]),
+            // Without the C runtime there's no parsed command line, so main gets an empty one:
+            // argc 0, and argv pointing at the null pointer that ends it
+            op("xor", [reg(RegisterGP::Rcx), reg(RegisterGP::Rcx)]),
+            op("movq", [imm(0), mem(RegisterGP::Rsp, 32)]),
+            op("lea", [mem(RegisterGP::Rsp, 32), reg(RegisterGP::Rdx)]),
+            op("call", [sym("main")]),
+            op("mov", [reg(RegisterGP::Rax), reg(RegisterGP::Rcx)]),
+            op("call", [sym("ExitProcess")]),
+        ]
+    }
+
+    fn render(instruction: &Instruction<RegisterGP>) -> String {
+        X86_64::render(instruction)
+    }
+
+    // COFF has no .size, and gives a symbol's type in a .def block: storage class 2 is
+    // external, and type 32 is a function
+    fn function_start(name: &str) -> Asm {
+        vec![asm::directive(format!(
+            ".def {}; .scl 2; .type 32; .endef",
+            name
+        ))]
+    }
+
+    fn function_end(_: &str) -> Asm {
+        vec![]
+    }
+
+    // PE/COFF calls its read-only data section .rdata
+    fn data(program: &Program) -> Asm {
+        let mut asm = target::strings_to_asm(&program.strings);
+        if let Some(section) = asm.first_mut() {
+            *section = asm::directive(".section .rdata,\"dr\"");
+        }
+        asm.extend(target::globals_to_asm(&program.globals));
+        asm
+    }
+}
+
+pub fn program_to_asm(
+    program: &Program,
+    options: &CodegenOptions,
+) -> Result<Vec<String>, CompileError> {
+    target::program_to_asm::<X86_64>(program, options)
+}
+
+pub fn program_to_windows_asm(
+    program: &Program,
+    options: &CodegenOptions,
+) -> Result<Vec<String>, CompileError> {
+    target::program_to_asm::<X86_64Windows>(program, options)
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cfg_parser::{parse_cfg, parse_program};
+    use crate::diagnostic::DiagnosticSink;
     use crate::parser::parse;
+    use crate::regalloc::Allocator;
     use crate::symantic_check::check_syntax;
     use crate::tokenizer::tokenize;
     use std::fs::read_to_string;
 
+    // Generates a program whose only function is main, with the given body
+    fn main_to_asm(cfg: ControlFlowGraph) -> Result<Vec<String>, CompileError> {
+        let main = Function {
+            params: vec![],
+            cfg,
+            inline: false,
+        };
+        let program = Program {
+            functions: [("main".into(), main)].into(),
+            strings: StringPool::new(),
+            globals: Globals::new(),
+        };
+        program_to_asm(&program, &CodegenOptions::default())
+    }
+
     #[test]
     fn codegen_integration_return() -> Result<(), String> {
         let s = read_to_string("test/return.c").unwrap();
         let tokens = tokenize(&s)?;
         let ast = parse(&tokens)?;
-        check_syntax(&ast)?;
-        let cfg = ControlFlowGraph::from(&ast);
-        let asm = cfg_to_asm(&cfg)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let program = Program::from(&ast)?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
 
-        println!("CFG: {:?}", cfg);
+        println!("CFG: {:?}", program);
         let expected = vec![
-            ".global _start",
-            "_start:",
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
             "mov $123, %rax",
-            "mov %rax, %rdi",
-            "mov $60, %rax",
-            "syscall",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_void() -> Result<(), String> {
+        // Returning nothing leaves %rax alone
+        let tokens = tokenize("void f() { return; } int main() { f(); return 0; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+        let start = asm.iter().position(|line| line == "f:").unwrap();
+        let expected = vec![
+            "f:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+        ];
+        assert_eq!(asm[start..start + 6], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_arithmetic() -> Result<(), String> {
+        let tokens = tokenize("int main() { return 1 + 2 * 3 - 8 / 2; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "mov $1, %rax",
+            "mov $2, %rcx",
+            "mov $3, %rdx",
+            "mov %rcx, %r8",
+            "imul %rdx, %r8",
+            "mov %rax, %rcx",
+            "add %r8, %rcx",
+            "mov $8, %rax",
+            "mov $2, %rdx",
+            // idiv takes its dividend sign-extended across rdx:rax and leaves the quotient in
+            // rax, so both are saved around it
+            "push %rax",
+            "push %rdx",
+            "mov %rax, %r10",
+            "mov %rdx, %r11",
+            "mov %r10, %rax",
+            "cqo",
+            "idiv %r11",
+            "mov %rax, %r10",
+            "pop %rdx",
+            "pop %rax",
+            "mov %r10, %r8",
+            "mov %rcx, %rax",
+            "sub %r8, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_if() -> Result<(), String> {
+        let tokens = tokenize("int main() { int x = 1; if (x) { return 2; } return 3; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "mov $1, %rax",
+            "cmp $0, %rax",
+            "je .Lmain_2",
+            ".Lmain_1:",
+            "mov $2, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".Lmain_2:",
+            "mov $3, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_while_falls_through() -> Result<(), String> {
+        let tokens = tokenize("int main() { int i = 0; while (i < 3) { i = i + 1; } return i; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        // The entry block falls into the header and the header into the body, so the only
+        // jumps left are the loop exit and the back edge
+        let jumps: Vec<&str> = asm
+            .iter()
+            .map(|s| s.as_str())
+            .filter(|s| s.starts_with('j') || s.starts_with(".L"))
+            .collect();
+        assert_eq!(
+            jumps,
+            vec![
+                ".Lmain_1:",
+                "je .Lmain_3",
+                ".Lmain_2:",
+                "jmp .Lmain_1",
+                ".Lmain_3:"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_block_layout() -> Result<(), String> {
+        // bb3 is laid out after the entry block so the true branch falls through, and bb2 is
+        // unreachable so it goes last
+        let cfg = parse_cfg(
+            "bb0: v1 = 1; if v1 goto bb3 else bb1
+             bb1: ret v1
+             bb2: ret v1
+             bb3: goto bb1",
+        )?;
+
+        let asm = main_to_asm(cfg)?;
+        assert!(asm.contains(&"je .Lmain_1".to_owned()));
+        assert!(
+            !asm.iter()
+                .any(|s| s.starts_with("jne") || s.starts_with("jmp"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_comparison() -> Result<(), String> {
+        let tokens = tokenize("int main() { int x = 1; return x < 2; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "mov $1, %rax",
+            "mov $2, %rcx",
+            "cmp %rcx, %rax",
+            "setl %r11b",
+            "movzbq %r11b, %rdx",
+            "mov %rdx, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
         ];
         assert_eq!(asm, expected);
+        Ok(())
+    }
 
+    #[test]
+    fn codegen_shifts() -> Result<(), String> {
+        let cfg = parse_cfg(
+            "bb0: v1 = 40; v2 = 3; v3 = 1; v4 = v1 >> v2; v5 = v4 << v3; v6 = v5 ^ v1; ret v6",
+        )?;
+        let asm = main_to_asm(cfg)?;
+        // v2 lives in rcx, so rcx is put back once each shift has used cl
+        let expected = vec![
+            "mov $40, %rax",
+            "mov $3, %rcx",
+            "mov $1, %rdx",
+            "push %rcx",
+            "mov %rax, %r10",
+            "mov %rcx, %r11",
+            "mov %r11, %rcx",
+            "sar %cl, %r10",
+            "pop %rcx",
+            "mov %r10, %r8",
+            "push %rcx",
+            "mov %r8, %r10",
+            "mov %rdx, %r11",
+            "mov %r11, %rcx",
+            "sal %cl, %r10",
+            "pop %rcx",
+            "mov %r10, %rcx",
+            "mov %rcx, %rdx",
+            "xor %rax, %rdx",
+        ];
+        assert_eq!(asm[5..24], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_constants() -> Result<(), String> {
+        let cfg = parse_cfg(
+            "bb0: v1 = -15; v2 = 4294967296; v3 = -2147483648; v4 = v1 + v2; v5 = v4 + v3; ret v5",
+        )?;
+        let asm = main_to_asm(cfg)?;
+        // Only a constant that doesn't fit in a sign-extended 32 bits needs movabs
+        let expected = vec![
+            "mov $-15, %rax",
+            "movabs $4294967296, %rcx",
+            "mov $-2147483648, %rdx",
+        ];
+        assert_eq!(asm[5..8], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_unsigned_operations() -> Result<(), String> {
+        let cfg = parse_cfg(
+            "bb0: v1 = 40; v2 = 3; v3 = v1 /u v2; v4 = v3 >>u v2; v5 = v4 <u v1; ret v5",
+        )?;
+        let asm = main_to_asm(cfg)?;
+        // div takes rdx zeroed rather than holding the dividend's sign, the shift is logical,
+        // and the comparison tests below rather than less
+        for instruction in ["xor %rdx, %rdx", "div %r11", "shr %cl, %r10", "setb %r11b"] {
+            assert!(asm.contains(&instruction.to_owned()), "{}", instruction);
+        }
+        for signed in ["cqo", "idiv", "sar", "setl"] {
+            assert!(!asm.iter().any(|s| s.starts_with(signed)), "{}", signed);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_word_extensions() -> Result<(), String> {
+        let cfg = parse_cfg("bb0: v1 = -1; v2 = zext32 v1; v3 = sext32 v2; ret v3")?;
+        let asm = main_to_asm(cfg)?;
+        let expected = vec![
+            "mov $-1, %rax",
+            "mov %rax, %rcx",
+            "sal $32, %rcx",
+            "shr $32, %rcx",
+            "mov %rcx, %rax",
+            "sal $32, %rax",
+            "sar $32, %rax",
+        ];
+        assert_eq!(asm[5..12], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_chars() -> Result<(), String> {
+        let cfg = parse_cfg(
+            "bb0: v1 = alloca 8; v2 = 300; v3 = sext8 v2; store8 v3, v1; v4 = load8 v1; ret v4",
+        )?;
+        let asm = main_to_asm(cfg)?;
+        let expected = vec![
+            "lea -8(%rbp), %rax",
+            "mov $300, %rcx",
+            "movsbq %cl, %rdx",
+            "movb %dl, (%rax)",
+            "movsbq (%rax), %rcx",
+        ];
+        assert_eq!(asm[6..11], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_unary_operations() -> Result<(), String> {
+        let tokens = tokenize("int main() { int x = 1; return !-x; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "mov $1, %rax",
+            "mov %rax, %rcx",
+            "neg %rcx",
+            "cmp $0, %rcx",
+            "sete %r11b",
+            "movzbq %r11b, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_stack_slots() -> Result<(), String> {
+        let tokens = tokenize("int main() { int x = 3; int *p = &x; *p = 4; return x; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "sub $16, %rsp",
+            "mov $3, %rax",
+            "lea -8(%rbp), %rcx",
+            "mov %rax, (%rcx)",
+            "mov $4, %rax",
+            "mov %rax, (%rcx)",
+            "mov (%rcx), %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_string_literals() -> Result<(), String> {
+        let tokens = tokenize("int main() { char *s = \"hi\"; return 0; }")?;
+        let ast = parse(&tokens)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let asm = program_to_asm(&Program::from(&ast)?, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "lea .Lstr0(%rip), %rax",
+            "mov $0, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+            ".section .rodata",
+            ".Lstr0:",
+            ".string \"hi\"",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_spills() -> Result<(), String> {
+        // All eleven constants are live at once, one more than there are registers. v11 is
+        // live the longest, so it's the one spilled, and goes through the scratch registers.
+        // Spill slots come after the five callee-saved registers, which all get used.
+        let mut text = String::from("bb0:");
+        for i in 1..=11 {
+            text.push_str(&format!(" v{} = {};", i, i));
+        }
+        text.push_str(" v12 = v1 + v2;");
+        for i in 3..=11 {
+            text.push_str(&format!(" v{} = v{} + v{};", i + 10, i + 9, i));
+        }
+        text.push_str(" ret v21");
+        let asm = main_to_asm(parse_cfg(&text)?)?;
+
+        assert_eq!(asm[3..6], ["push %rbp", "mov %rsp, %rbp", "sub $64, %rsp"]);
+        assert!(asm.contains(&"mov %r15, -40(%rbp)".to_owned()));
+        assert!(asm.contains(&"mov -40(%rbp), %r15".to_owned()));
+        assert!(asm.contains(&"mov $11, %r11".to_owned()));
+        assert!(asm.contains(&"mov %r11, -56(%rbp)".to_owned()));
+        assert!(asm.contains(&"mov -56(%rbp), %r11".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_graph_coloring_drops_copies() -> Result<(), String> {
+        // The loop's copy back into x coalesces, so it needs no mov
+        let tokens = tokenize("int main() { int x = 0; while (x < 3) { x = x + 1; } return x; }")?;
+        let ast = parse(&tokens)?;
+        let program = Program::from(&ast)?;
+        let moves = |allocator| -> Result<usize, String> {
+            let options = CodegenOptions {
+                allocator,
+                ..Default::default()
+            };
+            let asm = program_to_asm(&program, &options)?;
+            Ok(asm.iter().filter(|line| line.starts_with("mov %")).count())
+        };
+        assert!(moves(Allocator::GraphColoring)? < moves(Allocator::LinearScan)?);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_globals() -> Result<(), String> {
+        // Globals are addressed relative to %rip, by name, and strings by local label
+        let program = parse_program(
+            "str0 = \"a\"
+             global x = 1
+             fn main() {
+             bb0: v1 = addr x; v2 = addr str0; store v2, v1; ret v1
+             }",
+        )?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
+        assert!(asm.iter().any(|l| l.starts_with("lea x(%rip), ")));
+        assert!(asm.iter().any(|l| l.starts_with("lea .Lstr0(%rip), ")));
+        assert!(asm.ends_with(&[".globl x".to_owned(), "x:".to_owned(), ".quad 1".to_owned()]));
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_windows() -> Result<(), String> {
+        // The fifth argument is stacked above the 32 bytes of shadow space, on both sides
+        // of the call, and library calls don't go through a PLT
+        let program = parse_program(
+            "str0 = \"a\"
+             fn f(v1, v2, v3, v4, v5) {
+             bb0: ret v5
+             }
+             fn main() {
+             bb0: v1 = 1; v2 = call f(v1, v1, v1, v1, v1); v3 = call putchar(v2); ret v3
+             }",
+        )?;
+        let asm = program_to_windows_asm(&program, &CodegenOptions::default())?;
+        let call = asm.iter().position(|s| s == "call f").unwrap();
+        let expected_call = vec![
+            "pop %rcx",
+            "pop %rdx",
+            "pop %r8",
+            "pop %r9",
+            "sub $32, %rsp",
+            "call f",
+            "add $48, %rsp",
+        ];
+        assert_eq!(asm[call - 5..call + 2], expected_call);
+        assert!(asm.contains(&"call putchar".to_owned()));
+        assert!(asm.iter().any(|s| s.starts_with("mov 48(%rbp), ")));
+        assert!(asm.contains(&".section .rdata,\"dr\"".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_pic() -> Result<(), String> {
+        // Globals come from the GOT and every call goes through the PLT, but strings are
+        // local to the file and stay %rip-relative
+        let program = parse_program(
+            "str0 = \"a\"
+             global x
+             fn f() {
+             bb0: v1 = addr x; ret v1
+             }
+             fn main() {
+             bb0: v1 = addr str0; v2 = call f(); ret v2
+             }",
+        )?;
+        let options = CodegenOptions {
+            pic: true,
+            ..Default::default()
+        };
+        let asm = program_to_asm(&program, &options)?;
+        assert!(asm.iter().any(|l| l.starts_with("mov x@GOTPCREL(%rip), ")));
+        assert!(asm.iter().any(|l| l.starts_with("lea .Lstr0(%rip), ")));
+        assert!(asm.contains(&"call f@PLT".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_calls() -> Result<(), String> {
+        // v1 is still needed after the call and lives in a caller-saved register, so it's
+        // pushed around it. The arguments are swapped on the way into rdi and rsi. sub makes
+        // no calls, so it sticks to caller-saved registers and has nothing to save.
+        let program = parse_program(
+            "fn sub(v1, v2) {
+             bb0: v3 = v1 - v2; ret v3
+             }
+             fn main() {
+             bb0: v1 = 5; v2 = 3; v3 = call sub(v2, v1); v4 = call putchar(v1); ret v3
+             }",
+        )?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
+        let sub = asm.iter().position(|s| s == ".global sub").unwrap();
+
+        let expected_sub = vec![
+            ".global sub",
+            ".type sub, @function",
+            "sub:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "push %rdi",
+            "push %rsi",
+            "pop %rcx",
+            "pop %rax",
+            "mov %rax, %rdx",
+            "sub %rcx, %rdx",
+            "mov %rdx, %rax",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size sub, .-sub",
+        ];
+        assert_eq!(asm[sub..], expected_sub);
+
+        let expected_main = vec![
+            ".global main",
+            ".type main, @function",
+            "main:",
+            "push %rbp",
+            "mov %rsp, %rbp",
+            "sub $16, %rsp",
+            "mov %rbx, -8(%rbp)",
+            "mov $5, %rax",
+            "mov $3, %rbx",
+            "push %rax",
+            "sub $8, %rsp",
+            "push %rax",
+            "push %rbx",
+            "pop %rdi",
+            "pop %rsi",
+            "call sub",
+            "add $8, %rsp",
+            "mov %rax, %rcx",
+            "pop %rax",
+            "push %rcx",
+            "sub $8, %rsp",
+            "push %rax",
+            "pop %rdi",
+            "mov $0, %rax",
+            "call putchar@PLT",
+            "add $8, %rsp",
+            "mov %rax, %rbx",
+            "pop %rcx",
+            "mov %rcx, %rax",
+            "mov -8(%rbp), %rbx",
+            "mov %rbp, %rsp",
+            "pop %rbp",
+            "ret",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm[..sub], expected_main);
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_tail_calls() -> Result<(), String> {
+        // The call in main jumps to f once the frame is gone. g takes more arguments than
+        // there are registers for, so the call to it can't be a jump.
+        let program = parse_program(
+            "fn f(v1) {
+             bb0: ret v1
+             }
+             fn g(v1, v2, v3, v4, v5, v6, v7) {
+             bb0: ret v7
+             }
+             fn main() {
+             bb0: v1 = 5; v2 = tail call f(v1); ret v2
+             }
+             fn h(v1) {
+             bb0: v2 = tail call g(v1, v1, v1, v1, v1, v1, v1); ret v2
+             }",
+        )?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
+        let function = |name: &str| {
+            let start = asm.iter().position(|s| *s == format!("{}:", name)).unwrap();
+            let end = asm[start..]
+                .iter()
+                .position(|s| s == "ret" || s.starts_with("jmp "));
+            asm[start..=start + end.unwrap()].to_vec()
+        };
+
+        let main = function("main");
+        assert!(!main.iter().any(|s| s.starts_with("call")));
+        assert_eq!(
+            main[main.len() - 3..],
+            ["mov %rbp, %rsp", "pop %rbp", "jmp f"]
+        );
+        let h = function("h");
+        assert!(h.contains(&"call g".to_owned()));
+        assert_eq!(h.last().map(String::as_str), Some("ret"));
+        Ok(())
+    }
+
+    #[test]
+    fn codegen_unsupported_program() -> Result<(), String> {
+        let program = |source: &str| -> Result<Program, String> {
+            let ast = parse(&tokenize(source)?)?;
+            Ok(Program::from(&ast)?)
+        };
+        assert_eq!(
+            program_to_asm(
+                &program("int f() { return 1; }")?,
+                &CodegenOptions::default()
+            )
+            .map(|_| ()),
+            Err(CompileError::CodegenError(
+                "Program has no main function".to_owned()
+            ))
+        );
         Ok(())
     }
 }
@@ -0,0 +1,82 @@
+use crate::diagnostic::json_string;
+
+/*
+ * A compilation database, the compile_commands.json that clangd and other C tooling read to
+ * learn how each file is built. Each entry is the command that compiles one input: the
+ * compiler, the flags it was given, and the file, run from `directory`.
+ *
+ * The flags are passed along as they were, less --emit=compile-commands and the -o that names
+ * the database itself. clangd ignores the ones it doesn't know, such as --passes.
+ */
+
+// Whether the argument at `index` goes in the entries: everything but the inputs, the
+// --emit that asked for the database, and the -o that says where it goes
+fn is_passed_on(args: &[String], index: usize, inputs: &[&str]) -> bool {
+    let arg = args[index].as_str();
+    let after_o = index > 0 && args[index - 1] == "-o";
+    arg != "--emit=compile-commands" && arg != "-o" && !after_o && !inputs.contains(&arg)
+}
+
+// The database for compiling each of `inputs` with `compiler` and the flags in `args`, from
+// the working directory `directory`
+pub fn database(directory: &str, compiler: &str, args: &[String], inputs: &[&str]) -> String {
+    let flags: Vec<&str> = (0..args.len())
+        .filter(|i| is_passed_on(args, *i, inputs))
+        .map(|i| args[i].as_str())
+        .collect();
+    let entries: Vec<String> = inputs
+        .iter()
+        .map(|input| {
+            let arguments: Vec<String> = [compiler]
+                .iter()
+                .chain(&flags)
+                .chain([input])
+                .map(|arg| json_string(arg))
+                .collect();
+            format!(
+                "  {{\n    \"directory\": {},\n    \"arguments\": [{}],\n    \"file\": {}\n  }}",
+                json_string(directory),
+                arguments.join(", "),
+                json_string(input)
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database() {
+        let args: Vec<String> = [
+            "-O2",
+            "a.c",
+            "--emit=compile-commands",
+            "-o",
+            "db.json",
+            "b c.c",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        let expected = r#"[
+  {
+    "directory": "/src/\"x\"",
+    "arguments": ["compiler", "-O2", "a.c"],
+    "file": "a.c"
+  },
+  {
+    "directory": "/src/\"x\"",
+    "arguments": ["compiler", "-O2", "b c.c"],
+    "file": "b c.c"
+  }
+]
+"#;
+        assert_eq!(
+            database("/src/\"x\"", "compiler", &args, &["a.c", "b c.c"]),
+            expected
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::ast::{BinOp, Expr, ExprKind, Type, UnaryOp};
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use std::collections::HashMap;
+
+/*
+ * Constant expressions, which C wants wherever a value has to be known before the program
+ * runs: global initializers and enumerator values. They're evaluated here, at compile time,
+ * the way the generated code would compute them, in 64-bit two's complement that wraps on
+ * overflow. && and || short-circuit, so `0 && 1 / 0` is a fine constant.
+ *
+ * Enumerators are constants, and `enumerators` gives the values of the ones in scope.
+ * Anything that needs the program to run (a variable, a call, an assignment, an address) is
+ * an error saying what it is.
+ */
+
+pub type Enumerators = HashMap<Symbol, i64>;
+
+pub fn evaluate(expr: &Expr, enumerators: &Enumerators) -> Result<i64, CompileError> {
+    match &expr.kind {
+        ExprKind::IntLiteral(value) => Ok(*value),
+        ExprKind::StringLiteral(_) => Err(CompileError::semantic(
+            "a string is not an integer".to_owned(),
+        )),
+        ExprKind::Variable(name) => enumerators
+            .get(name)
+            .copied()
+            .ok_or_else(|| CompileError::semantic(format!("{} is a variable", name))),
+        ExprKind::Call { name, .. } => Err(CompileError::semantic(format!("{}() is a call", name))),
+        ExprKind::UnaryOperation { op, expr } => {
+            let operand = || evaluate(expr, enumerators);
+            match op {
+                UnaryOp::Neg => Ok(operand()?.wrapping_neg()),
+                UnaryOp::Not => Ok((operand()? == 0) as i64),
+                UnaryOp::BitNot => Ok(!operand()?),
+                UnaryOp::AddrOf => Err(CompileError::semantic("& takes an address".to_owned())),
+                UnaryOp::Deref => Err(CompileError::semantic("* reads memory".to_owned())),
+            }
+        }
+        ExprKind::BinaryOperation { op, left, right } => {
+            let lhs = evaluate(left, enumerators)?;
+            // The right side of a short circuit that's already decided isn't evaluated
+            match (op, lhs) {
+                (BinOp::LogicalAnd, 0) => return Ok(0),
+                (BinOp::LogicalOr, lhs) if lhs != 0 => return Ok(1),
+                _ => {}
+            }
+            let rhs = || evaluate(right, enumerators);
+            match op {
+                BinOp::Add => Ok(lhs.wrapping_add(rhs()?)),
+                BinOp::Sub => Ok(lhs.wrapping_sub(rhs()?)),
+                BinOp::Mul => Ok(lhs.wrapping_mul(rhs()?)),
+                BinOp::Div => match rhs()? {
+                    0 => Err(CompileError::semantic("division by zero".to_owned())),
+                    rhs => Ok(lhs.wrapping_div(rhs)),
+                },
+                BinOp::Equals => Ok((lhs == rhs()?) as i64),
+                BinOp::NotEquals => Ok((lhs != rhs()?) as i64),
+                BinOp::Less => Ok((lhs < rhs()?) as i64),
+                BinOp::LessEqual => Ok((lhs <= rhs()?) as i64),
+                BinOp::Greater => Ok((lhs > rhs()?) as i64),
+                BinOp::GreaterEqual => Ok((lhs >= rhs()?) as i64),
+                BinOp::LogicalAnd | BinOp::LogicalOr => Ok((rhs()? != 0) as i64),
+                // Shift counts wrap around at 64, like the shift instructions'
+                BinOp::ShiftLeft => Ok(lhs.wrapping_shl(rhs()? as u32)),
+                BinOp::ShiftRight => Ok(lhs.wrapping_shr(rhs()? as u32)),
+                BinOp::BitAnd => Ok(lhs & rhs()?),
+                BinOp::BitOr => Ok(lhs | rhs()?),
+                BinOp::BitXor => Ok(lhs ^ rhs()?),
+                // All that's left are the assignments
+                _ => Err(CompileError::semantic("an assignment".to_owned())),
+            }
+        }
+        ExprKind::Cast { target, expr } => {
+            let value = evaluate(expr, enumerators)?;
+            match target {
+                Type::Char => Ok(value as i8 as i64),
+                Type::Int | Type::Enum(_) => Ok(value as i32 as i64),
+                Type::Unsigned => Ok(value as u32 as i64),
+                target => Err(CompileError::semantic(format!("a cast to {}", target))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+
+    // Evaluates the initializer of the global in `int g = <source>;`
+    fn evaluate_source(source: &str, enumerators: &Enumerators) -> Result<i64, String> {
+        let source = format!("int g = {};", source);
+        let declarations = parse(&tokenize(&source)?)?;
+        let crate::ast::Declaration::Global {
+            value: Some(value), ..
+        } = &declarations[0]
+        else {
+            return Err(format!("Expected a global, got {:?}", declarations[0]));
+        };
+        Ok(evaluate(value, enumerators)?)
+    }
+
+    #[test]
+    fn test_evaluate() -> Result<(), String> {
+        let none = Enumerators::new();
+        assert_eq!(evaluate_source("2 * 8", &none)?, 16);
+        assert_eq!(evaluate_source("-(1 + 2) * 3 / 2", &none)?, -4);
+        assert_eq!(evaluate_source("!0 + ~0 + (3 < 4) + (2 == 3)", &none)?, 1);
+        assert_eq!(evaluate_source("0 && 1 / 0", &none)?, 0);
+        assert_eq!(evaluate_source("2 || 1 / 0", &none)?, 1);
+        assert_eq!(evaluate_source("1 << 4 | 3 & 6 ^ 1", &none)?, 19);
+        assert_eq!(evaluate_source("-16 >> 2", &none)?, -4);
+
+        let colors = Enumerators::from([(Symbol::intern("RED"), 4)]);
+        assert_eq!(evaluate_source("RED * 2 + 1", &colors)?, 9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_constant() {
+        let none = Enumerators::new();
+        for (source, error) in [
+            ("x + 1", "x is a variable"),
+            ("f(2)", "f() is a call"),
+            ("4 / (2 - 2)", "division by zero"),
+            ("*0", "* reads memory"),
+            ("1 && \"s\"", "a string is not an integer"),
+        ] {
+            assert_eq!(evaluate_source(source, &none), Err(error.to_owned()));
+        }
+    }
+}
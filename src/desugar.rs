@@ -0,0 +1,432 @@
+use crate::ast::*;
+use crate::span::Span;
+
+/*
+ * Rewrites syntactic sugar into the smaller core AST that symantic_check and cfg handle:
+ *   for (init; cond; step) body  =>  { init; while (cond) { { body } step; } }
+ *   x op= e                      =>  x = x op e
+ * A missing for-condition becomes the constant 1. The original body is kept as a nested
+ * block so names it declares can't capture the ones used by the step expression. Since the
+ * step is now part of the loop body, a `continue` aimed at the for loop becomes
+ * `{ step; continue; }` so the step still runs.
+ *
+ * A rewritten node keeps the NodeId of the sugar it replaces, and nodes the pass invents
+ * get fresh ids, so ids stay unique (though no longer strictly bottom-up).
+ *
+ * `else if` chains and braceless bodies never reach this pass: the parser already wraps
+ * them in a Scope, since that's the only body shape the AST can represent.
+ */
+
+struct Desugarer {
+    scope_id_counter: ScopeIdCounter,
+    node_id_counter: NodeIdCounter,
+    loop_steps: Vec<Option<Expr>>, // the step of each enclosing loop, innermost last
+}
+
+pub fn desugar(declarations: Vec<Declaration>) -> Vec<Declaration> {
+    // Ids are assigned bottom-up by the parser, so each function's own id and root scope id
+    // are the largest in that function. New ids are numbered after all of them.
+    let max_scope_id = declarations
+        .iter()
+        .filter_map(|dec| match dec {
+            Declaration::Function { scope, .. } => Some(scope.id),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let max_node_id = declarations.iter().map(|dec| dec.id().0).max().unwrap_or(0);
+    let mut desugarer = Desugarer {
+        scope_id_counter: ScopeIdCounter {
+            counter: max_scope_id,
+        },
+        node_id_counter: NodeIdCounter {
+            counter: max_node_id,
+        },
+        loop_steps: vec![],
+    };
+
+    declarations
+        .into_iter()
+        .map(|dec| desugarer.desugar_declaration(dec))
+        .collect()
+}
+
+impl Desugarer {
+    fn desugar_declaration(&mut self, dec: Declaration) -> Declaration {
+        let Declaration::Function {
+            id,
+            span,
+            name,
+            args,
+            return_type,
+            scope,
+            inline,
+        } = dec
+        else {
+            return dec;
+        };
+        Declaration::Function {
+            id,
+            span,
+            name,
+            args,
+            return_type,
+            scope: self.desugar_scope(scope),
+            inline,
+        }
+    }
+
+    fn desugar_scope(&mut self, scope: Scope) -> Scope {
+        Scope {
+            id: scope.id,
+            statements: scope
+                .statements
+                .into_iter()
+                .map(|s| self.desugar_statement(s))
+                .collect(),
+            span: scope.span,
+        }
+    }
+
+    fn desugar_statement(&mut self, stmt: Statement) -> Statement {
+        let kind = match stmt.kind {
+            StatementKind::Return(expr) => {
+                StatementKind::Return(expr.map(|e| self.desugar_expr(e)))
+            }
+            StatementKind::Expression(expr) => StatementKind::Expression(self.desugar_expr(expr)),
+            StatementKind::VarDeclare {
+                name,
+                var_type,
+                value,
+                span,
+            } => StatementKind::VarDeclare {
+                name,
+                var_type,
+                value: value.map(|v| self.desugar_expr(v)),
+                span,
+            },
+            StatementKind::If {
+                condition,
+                true_block,
+                false_block,
+            } => StatementKind::If {
+                condition: self.desugar_expr(condition),
+                true_block: self.desugar_scope(true_block),
+                false_block: false_block.map(|s| self.desugar_scope(s)),
+            },
+            StatementKind::While { condition, body } => StatementKind::While {
+                condition: self.desugar_expr(condition),
+                body: self.desugar_loop_body(body, None),
+            },
+            StatementKind::Switch { value, body } => StatementKind::Switch {
+                value: self.desugar_expr(value),
+                body: self.desugar_scope(body),
+            },
+            StatementKind::Case(value) => StatementKind::Case(self.desugar_expr(value)),
+            StatementKind::Default => StatementKind::Default,
+            StatementKind::Block(scope) => StatementKind::Block(self.desugar_scope(scope)),
+            StatementKind::Break => StatementKind::Break,
+            StatementKind::Continue => self.desugar_continue(),
+            StatementKind::For {
+                init,
+                condition,
+                step,
+                body,
+            } => self.desugar_for(init, condition, step, body, stmt.span),
+        };
+        Statement { kind, ..stmt }
+    }
+
+    fn desugar_loop_body(&mut self, body: Scope, step: Option<Expr>) -> Scope {
+        self.loop_steps.push(step);
+        let body = self.desugar_scope(body);
+        self.loop_steps.pop();
+        body
+    }
+
+    fn desugar_continue(&mut self) -> StatementKind {
+        let Some(Some(step)) = self.loop_steps.last() else {
+            return StatementKind::Continue;
+        };
+        let step = StatementKind::Expression(self.copy_expr(&step.clone()));
+        let statements = vec![
+            Statement::new(self.node_id_counter.next(), step),
+            Statement::new(self.node_id_counter.next(), StatementKind::Continue),
+        ];
+        StatementKind::Block(Scope::from_statements(
+            statements,
+            &mut self.scope_id_counter,
+        ))
+    }
+
+    fn desugar_for(
+        &mut self,
+        init: Option<Box<Statement>>,
+        condition: Option<Expr>,
+        step: Option<Expr>,
+        body: Scope,
+        span: Option<Span>,
+    ) -> StatementKind {
+        // The block stands for the whole loop, from `for` to the end of the body
+        let span = span.zip(body.span).map(|(start, end)| Span {
+            end: end.end,
+            ..start
+        });
+        let step = step.map(|s| self.desugar_expr(s));
+        let body = StatementKind::Block(self.desugar_loop_body(body, step.clone()));
+        let mut loop_body = vec![Statement::new(self.node_id_counter.next(), body)];
+        if let Some(step) = step {
+            let step = StatementKind::Expression(step);
+            loop_body.push(Statement::new(self.node_id_counter.next(), step));
+        }
+
+        let mut statements = vec![];
+        if let Some(init) = init {
+            statements.push(self.desugar_statement(*init));
+        }
+        let condition = match condition {
+            Some(condition) => self.desugar_expr(condition),
+            None => Expr::new(self.node_id_counter.next(), ExprKind::IntLiteral(1)),
+        };
+        let while_loop = StatementKind::While {
+            condition,
+            body: Scope::from_statements(loop_body, &mut self.scope_id_counter),
+        };
+        statements.push(Statement::new(self.node_id_counter.next(), while_loop));
+
+        let mut block = Scope::from_statements(statements, &mut self.scope_id_counter);
+        block.span = span;
+        StatementKind::Block(block)
+    }
+
+    fn desugar_expr(&mut self, expr: Expr) -> Expr {
+        let (op, left, right) = match expr.kind {
+            ExprKind::BinaryOperation { op, left, right } => (op, left, right),
+            ExprKind::UnaryOperation { op, expr: operand } => {
+                let operand = Box::new(self.desugar_expr(*operand));
+                let kind = ExprKind::UnaryOperation { op, expr: operand };
+                return Expr { kind, ..expr };
+            }
+            ExprKind::Call { name, args } => {
+                let args = args.into_iter().map(|a| self.desugar_expr(a)).collect();
+                return Expr {
+                    kind: ExprKind::Call { name, args },
+                    ..expr
+                };
+            }
+            _ => return expr,
+        };
+
+        let left = self.desugar_expr(*left);
+        let right = self.desugar_expr(*right);
+        let kind = match op.compound_operator() {
+            // The target is only ever a plain variable, so evaluating it twice is safe
+            Some(arithmetic_op) => {
+                let target = self.copy_expr(&left);
+                let value = ExprKind::BinaryOperation {
+                    op: arithmetic_op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                };
+                ExprKind::BinaryOperation {
+                    op: BinOp::Assign,
+                    left: Box::new(target),
+                    right: Box::new(Expr::new(self.node_id_counter.next(), value)),
+                }
+            }
+            None => ExprKind::BinaryOperation {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+        };
+        Expr { kind, ..expr }
+    }
+
+    // Clones an expression, giving every node in the copy a fresh id
+    fn copy_expr(&mut self, expr: &Expr) -> Expr {
+        let kind = match &expr.kind {
+            ExprKind::BinaryOperation { op, left, right } => ExprKind::BinaryOperation {
+                op: op.clone(),
+                left: Box::new(self.copy_expr(left)),
+                right: Box::new(self.copy_expr(right)),
+            },
+            ExprKind::UnaryOperation { op, expr } => ExprKind::UnaryOperation {
+                op: op.clone(),
+                expr: Box::new(self.copy_expr(expr)),
+            },
+            ExprKind::Call { name, args } => ExprKind::Call {
+                name: *name,
+                args: args.iter().map(|a| self.copy_expr(a)).collect(),
+            },
+            kind => kind.clone(),
+        };
+        Expr {
+            id: self.node_id_counter.next(),
+            kind,
+            span: expr.span,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_dump::dump;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+
+    fn desugar_source(source: &str) -> Result<String, String> {
+        let tokens = tokenize(source)?;
+        Ok(dump(&desugar(parse(&tokens)?)))
+    }
+
+    #[test]
+    fn test_desugar_compound_assign() -> Result<(), String> {
+        let expected = "\
+(fn main int ()
+  (decl int x (int 1))
+  (expr (= (var x) (+ (var x) (* (int 2) (int 3)))))
+  (expr (= (var x) (/ (var x) (int 4))))
+  (ret (var x)))
+";
+        assert_eq!(
+            desugar_source("int main() { int x = 1; x += 2 * 3; x /= 4; return x; }")?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_desugar_for() -> Result<(), String> {
+        let expected = "\
+(fn main int ()
+  (decl int total (int 0))
+  (block
+    (decl int i (int 0))
+    (while (== (var i) (int 0))
+      (block
+        (expr (= (var total) (+ (var total) (var i)))))
+      (expr (= (var i) (+ (var i) (int 1))))))
+  (ret (var total)))
+";
+        assert_eq!(
+            desugar_source(
+                "int main() { int total = 0; \
+                 for (int i = 0; i == 0; i += 1) total += i; return total; }"
+            )?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_desugar_empty_for() -> Result<(), String> {
+        let expected = "\
+(fn main int ()
+  (block
+    (while (int 1)
+      (block
+        (ret (int 0))))))
+";
+        assert_eq!(
+            desugar_source("int main() { for (;;) { return 0; } }")?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_desugar_continue() -> Result<(), String> {
+        // The step runs before a for loop's continue, but a while loop has no step to run
+        let expected = "\
+(fn main int ()
+  (decl int i (int 0))
+  (block
+    (while (var i)
+      (block
+        (while (int 1)
+          (continue))
+        (if (var i)
+          (then
+            (block
+              (expr (= (var i) (+ (var i) (int 1))))
+              (continue))))
+        (break))
+      (expr (= (var i) (+ (var i) (int 1))))))
+  (ret (var i)))
+";
+        assert_eq!(
+            desugar_source(
+                "int main() { int i = 0; for (; i; i += 1) { \
+                 while (1) continue; if (i) continue; break; } return i; }"
+            )?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_desugar_fresh_scope_ids() -> Result<(), String> {
+        let tokens = tokenize("int main() { for (;;) { return 0; } }")?;
+        let declarations = desugar(parse(&tokens)?);
+        let Declaration::Function { scope, .. } = &declarations[0] else {
+            return Err(format!("Expected a function, got {:?}", declarations[0]));
+        };
+        let StatementKind::Block(block) = &scope.statements[0].kind else {
+            return Err(format!("Expected a block, got {:?}", scope.statements[0]));
+        };
+        let StatementKind::While { body, .. } = &block.statements[0].kind else {
+            return Err(format!("Expected a while, got {:?}", block.statements[0]));
+        };
+
+        // The for body (1) and main (2) come from the parser; the desugared while body
+        // and the block around it are numbered after them.
+        assert_eq!(scope.id, 2);
+        assert_eq!(body.id, 3);
+        assert_eq!(block.id, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_desugar_unique_node_ids() -> Result<(), String> {
+        fn collect_expr(expr: &Expr, ids: &mut Vec<NodeId>) {
+            ids.push(expr.id);
+            if let ExprKind::BinaryOperation { left, right, .. } = &expr.kind {
+                collect_expr(left, ids);
+                collect_expr(right, ids);
+            }
+        }
+        fn collect_scope(scope: &Scope, ids: &mut Vec<NodeId>) {
+            for s in &scope.statements {
+                ids.push(s.id);
+                match &s.kind {
+                    StatementKind::Expression(e) | StatementKind::Return(Some(e)) => {
+                        collect_expr(e, ids)
+                    }
+                    StatementKind::VarDeclare { value: Some(e), .. } => collect_expr(e, ids),
+                    StatementKind::While { condition, body } => {
+                        collect_expr(condition, ids);
+                        collect_scope(body, ids);
+                    }
+                    StatementKind::Block(block) => collect_scope(block, ids),
+                    _ => {}
+                }
+            }
+        }
+
+        let tokens = tokenize("int main() { int x = 0; for (;;) { x += 1; } }")?;
+        let declarations = desugar(parse(&tokens)?);
+        let Declaration::Function { scope, .. } = &declarations[0] else {
+            return Err(format!("Expected a function, got {:?}", declarations[0]));
+        };
+        let mut ids = vec![];
+        collect_scope(scope, &mut ids);
+
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use crate::error::CompileError;
+use crate::span::Span;
+use std::collections::HashSet;
+use std::fmt;
+
+/*
+ * Warnings are collected in a DiagnosticSink as the tokenizer, parser, and semantic checks
+ * run, rather than stopping compilation. Each warning has a gcc-style name so the driver's
+ * -W<name> / -Wno-<name> flags can turn it on or off, -Wall turns on the usual set, and
+ * -Werror reports every warning that's still enabled as an error.
+ */
+
+// Every warning the compiler knows about, and whether it's enabled by default
+const WARNINGS: [(&str, bool); 11] = [
+    ("overflow", true),             // constants that change value when converted
+    ("conversion", false),          // implicit conversions that may change a value
+    ("dead-store", false),          // values assigned to a variable that are never read
+    ("div-by-zero", true),          // constant divisions by zero
+    ("maybe-uninitialized", true),  // variables read where only some paths assign them
+    ("parentheses", false),         // assignments used as conditions
+    ("shadow", false),              // variables that hide one declared in an enclosing scope
+    ("shift-count-negative", true), // constant shifts by a negative count
+    ("shift-count-overflow", true), // constant shifts by at least the width of the type
+    ("unused-value", true),         // expression statements that have no effect
+    ("unused-variable", false),     // local variables that are never referred to
+];
+
+// The warnings -Wall turns on, as in gcc, whose -Wunused-but-set-variable is the nearest thing
+// to dead-store. unused-value and maybe-uninitialized are on by default anyway: reading a
+// variable that some path leaves unassigned is usually a bug, so it needs no flag.
+const ALL: [&str; 5] = [
+    "dead-store",
+    "maybe-uninitialized",
+    "parentheses",
+    "unused-value",
+    "unused-variable",
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub warning: Option<&'static str>, // name of the warning that produced this, if any
+    pub notes: Vec<Note>,
+}
+
+// Something else worth knowing about a diagnostic, usually another place in the source that
+// it's about, such as where a name was first declared
+#[derive(Clone, Debug, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Note {
+    pub fn new(message: String, span: Option<Span>) -> Self {
+        Note { message, span }
+    }
+}
+
+impl Diagnostic {
+    // A fatal error, which stops compilation rather than going through a sink
+    pub fn error(message: String, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            span,
+            warning: None,
+            notes: vec![],
+        }
+    }
+
+    // One line of JSON for --error-format=json, with the same fields as the text form
+    pub fn to_json(&self, file: &str) -> String {
+        let warning = match self.warning {
+            Some(name) => json_string(name),
+            None => "null".to_owned(),
+        };
+        let notes: Vec<String> = self
+            .notes
+            .iter()
+            .map(|note| {
+                format!(
+                    "{{\"message\":{},\"span\":{}}}",
+                    json_string(&note.message),
+                    span_json(note.span)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"severity\":\"{}\",\"message\":{},\"file\":{},\"span\":{},\"warning\":{},\"notes\":[{}]}}",
+            self.severity,
+            json_string(&self.message),
+            json_string(file),
+            span_json(self.span),
+            warning,
+            notes.join(",")
+        )
+    }
+}
+
+fn span_json(span: Option<Span>) -> String {
+    match span {
+        Some(span) => format!(
+            "{{\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}",
+            span.start, span.end, span.line, span.column
+        ),
+        None => "null".to_owned(),
+    }
+}
+
+impl Diagnostic {
+    /*
+     * The rustc-style rendering: the message, where it is, and the source line with the span
+     * underlined, then any notes. A note about another place in the source shows that place
+     * the same way. A span running past the end of its line is underlined to the end of the
+     * line.
+     *
+     *   error: Undefined variable x in scope 1
+     *    --> a.c:2:10
+     *     |
+     *   2 |   return x;
+     *     |          ^
+     */
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let mut out = format!("{}: {}", self.severity, self.message);
+        match (self.warning, self.severity) {
+            (Some(name), Severity::Warning) => out.push_str(&format!(" [-W{}]", name)),
+            (Some(name), Severity::Error) => out.push_str(&format!(" [-Werror={}]", name)),
+            (None, _) => (),
+        }
+        out.push('\n');
+
+        // Wide enough for the longest line number shown
+        let width = (self.notes.iter())
+            .map(|note| note.span)
+            .chain([self.span])
+            .flatten()
+            .map(|span| span.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(width);
+        snippet(&mut out, file, source, self.span, &gutter);
+        for note in &self.notes {
+            match note.span {
+                Some(span) => {
+                    out.push_str(&format!("note: {}\n", note.message));
+                    snippet(&mut out, file, source, Some(span), &gutter);
+                }
+                None => out.push_str(&format!("{} = note: {}\n", gutter, note.message)),
+            }
+        }
+        out
+    }
+}
+
+// Where a span is, and its line of the source with the span underlined
+fn snippet(out: &mut String, file: &str, source: &str, span: Option<Span>, gutter: &str) {
+    match span {
+        Some(span) => out.push_str(&format!("{}--> {}:{}\n", gutter, file, span)),
+        None => out.push_str(&format!("{}--> {}\n", gutter, file)),
+    }
+    if let Some(span) = span
+        && let Some(text) = source.lines().nth((span.line as usize).saturating_sub(1))
+    {
+        // Keep the tabs before the span so the carets line up with it
+        let before: String = text
+            .chars()
+            .take(span.column as usize - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = (span.end - span.start)
+            .min(text.len().saturating_sub(before.len()))
+            .max(1);
+        let number = format!("{:>1$}", span.line, gutter.len());
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", number, text));
+        out.push_str(&format!("{} | {}{}\n", gutter, before, "^".repeat(width)));
+    }
+}
+
+impl From<CompileError> for Diagnostic {
+    fn from(error: CompileError) -> Self {
+        let mut diagnostic = Diagnostic::error(error.message().to_owned(), error.span());
+        diagnostic.notes = error.notes().to_vec();
+        diagnostic
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(span) = self.span {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "note: {}", self.message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(span) = self.span {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)?;
+        match (self.warning, self.severity) {
+            (Some(name), Severity::Warning) => write!(f, " [-W{}]", name),
+            (Some(name), Severity::Error) => write!(f, " [-Werror={}]", name),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DiagnosticSink {
+    diagnostics: Vec<Diagnostic>,
+    enabled: HashSet<&'static str>,
+    werror: bool,
+}
+
+impl Default for DiagnosticSink {
+    fn default() -> Self {
+        DiagnosticSink {
+            diagnostics: vec![],
+            enabled: WARNINGS
+                .iter()
+                .filter(|(_, on)| *on)
+                .map(|(name, _)| *name)
+                .collect(),
+            werror: false,
+        }
+    }
+}
+
+impl DiagnosticSink {
+    // Applies one of -Werror, -Wall, -W<name>, or -Wno-<name>
+    pub fn apply_flag(&mut self, flag: &str) -> Result<(), String> {
+        let Some(option) = flag.strip_prefix("-W") else {
+            return Err(format!("Not a warning flag: {}", flag));
+        };
+        if option == "error" {
+            self.werror = true;
+            return Ok(());
+        }
+        if option == "all" {
+            self.enabled.extend(ALL);
+            return Ok(());
+        }
+
+        let (name, on) = match option.strip_prefix("no-") {
+            Some(name) => (name, false),
+            None => (option, true),
+        };
+        let Some((name, _)) = WARNINGS.iter().find(|(w, _)| *w == name) else {
+            return Err(format!("Unknown warning: {}", flag));
+        };
+        if on {
+            self.enabled.insert(name);
+        } else {
+            self.enabled.remove(name);
+        }
+        Ok(())
+    }
+
+    // Whether the named warning would be recorded, for checks that are only worth running
+    // when it would
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(name)
+    }
+
+    // Records the named warning, unless it has been turned off
+    pub fn warn(&mut self, name: &'static str, message: String, span: Option<Span>) {
+        self.warn_with_notes(name, message, span, vec![]);
+    }
+
+    pub fn warn_with_notes(
+        &mut self,
+        name: &'static str,
+        message: String,
+        span: Option<Span>,
+        notes: Vec<Note>,
+    ) {
+        debug_assert!(WARNINGS.iter().any(|(w, _)| *w == name));
+        if !self.enabled.contains(name) {
+            return;
+        }
+        self.diagnostics.push(Diagnostic {
+            severity: if self.werror {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            message,
+            span,
+            warning: Some(name),
+            notes,
+        });
+    }
+
+    // Records an error that doesn't stop compilation straight away, so that the ones after
+    // it are found too
+    pub fn error(&mut self, error: CompileError) {
+        self.diagnostics.push(Diagnostic::from(error));
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(sink: &DiagnosticSink) -> Vec<String> {
+        sink.diagnostics().iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn test_warning_flags() -> Result<(), String> {
+        let mut sink = DiagnosticSink::default();
+        sink.apply_flag("-Wno-overflow")?;
+        sink.apply_flag("-Wconversion")?;
+        sink.warn("overflow", "dropped".to_owned(), None);
+        sink.warn("conversion", "kept".to_owned(), None);
+        sink.warn("parentheses", "off by default".to_owned(), None);
+        assert_eq!(messages(&sink), vec!["warning: kept [-Wconversion]"]);
+        assert!(!sink.has_errors());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wall() -> Result<(), String> {
+        let mut sink = DiagnosticSink::default();
+        sink.apply_flag("-Wall")?;
+        sink.apply_flag("-Wno-parentheses")?;
+        sink.warn("unused-variable", "unused".to_owned(), None);
+        sink.warn("parentheses", "turned back off".to_owned(), None);
+        sink.warn("shadow", "not in -Wall".to_owned(), None);
+        assert_eq!(messages(&sink), vec!["warning: unused [-Wunused-variable]"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_werror() -> Result<(), String> {
+        let mut sink = DiagnosticSink::default();
+        sink.apply_flag("-Werror")?;
+        let span = Span {
+            start: 0,
+            end: 1,
+            line: 2,
+            column: 7,
+        };
+        sink.warn("overflow", "too big".to_owned(), Some(span));
+        assert_eq!(
+            messages(&sink),
+            vec!["2:7: error: too big [-Werror=overflow]"]
+        );
+        assert!(sink.has_errors());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json() {
+        let span = Span {
+            start: 12,
+            end: 13,
+            line: 2,
+            column: 3,
+        };
+        let mut sink = DiagnosticSink::default();
+        sink.warn("overflow", "too \"big\"".to_owned(), Some(span));
+        assert_eq!(
+            sink.diagnostics()[0].to_json("a.c"),
+            r#"{"severity":"warning","message":"too \"big\"","file":"a.c","span":{"start":12,"end":13,"line":2,"column":3},"warning":"overflow","notes":[]}"#
+        );
+        let mut error = Diagnostic::error("bad\n".to_owned(), None);
+        error.notes.push(Note::new("see\\here".to_owned(), None));
+        error
+            .notes
+            .push(Note::new("and here".to_owned(), Some(span)));
+        assert_eq!(
+            error.to_json("a.c"),
+            r#"{"severity":"error","message":"bad\n","file":"a.c","span":null,"warning":null,"notes":[{"message":"see\\here","span":null},{"message":"and here","span":{"start":12,"end":13,"line":2,"column":3}}]}"#
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "int main() {\n\treturn 1 + xyz;\n}\n";
+        let span = Span {
+            start: 25,
+            end: 28,
+            line: 2,
+            column: 13,
+        };
+        let mut error = Diagnostic::error("Undefined variable xyz".to_owned(), Some(span));
+        error
+            .notes
+            .push(Note::new("declare it first".to_owned(), None));
+        let expected = "\
+error: Undefined variable xyz
+ --> a.c:2:13
+  |
+2 | \treturn 1 + xyz;
+  | \t           ^^^
+  = note: declare it first
+";
+        assert_eq!(error.render("a.c", source), expected);
+
+        let error = Diagnostic::error("No main".to_owned(), None);
+        assert_eq!(error.render("a.c", source), "error: No main\n--> a.c\n");
+
+        // A note about another place shows its line too
+        let source = "int x;\n\n\n\n\n\n\n\n\nint x;\n";
+        let first = Span {
+            start: 4,
+            end: 5,
+            line: 1,
+            column: 5,
+        };
+        let second = Span {
+            start: 20,
+            end: 21,
+            line: 10,
+            column: 5,
+        };
+        let mut error = Diagnostic::error("Duplicate definition of x".to_owned(), Some(second));
+        error.notes.push(Note::new(
+            "Previous definition of x".to_owned(),
+            Some(first),
+        ));
+        let expected = "\
+error: Duplicate definition of x
+  --> a.c:10:5
+   |
+10 | int x;
+   |     ^
+note: Previous definition of x
+  --> a.c:1:5
+   |
+ 1 | int x;
+   |     ^
+";
+        assert_eq!(error.render("a.c", source), expected);
+    }
+
+    #[test]
+    fn test_unknown_flag() {
+        let mut sink = DiagnosticSink::default();
+        assert_eq!(
+            sink.apply_flag("-Wbogus"),
+            Err("Unknown warning: -Wbogus".to_owned())
+        );
+        assert_eq!(
+            sink.apply_flag("-O2"),
+            Err("Not a warning flag: -O2".to_owned())
+        );
+    }
+}
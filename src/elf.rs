@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+
+/*
+    Relocatable ELF64 object files, for --emit=obj. An Object is what an assembler produces:
+    sections of bytes, the symbols defined in them, and relocations for the places that
+    refer to a symbol whose address isn't known until link time. `to_elf` lays that out as
+    a little-endian ELF64 file of type ET_REL, which ld or cc can link like any other .o.
+
+    The file is the ELF header, then each section's contents, then the section header
+    table. Besides the object's own sections there's a .rela section for each one with
+    relocations, the symbol table and its string table, and the section name table.
+
+    Only global and undefined symbols go in the symbol table. A relocation against a local
+    symbol, like a string literal's .L label, is made against its section's symbol instead,
+    with the symbol's offset added to the addend, which is what GNU as does too.
+*/
+
+pub const EM_X86_64: u16 = 62;
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+pub const R_X86_64_GOTPCREL: u32 = 9;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const HEADER_SIZE: u64 = 64;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const RELOCATION_SIZE: u64 = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SectionKind {
+    Text,
+    ReadOnly,
+    Data,
+    Bss,  // takes no space in the file, only its size is recorded
+    Note, // not loaded, like .note.GNU-stack
+}
+
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub kind: SectionKind,
+    pub data: Vec<u8>, // zeros for a Bss section, which are never written out
+    pub align: u64,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Section {
+    pub fn new(name: &str, kind: SectionKind) -> Self {
+        Section {
+            name: name.to_owned(),
+            kind,
+            data: vec![],
+            align: 1,
+            relocations: vec![],
+        }
+    }
+
+    fn flags(&self) -> u64 {
+        match self.kind {
+            SectionKind::Text => SHF_ALLOC | SHF_EXECINSTR,
+            SectionKind::ReadOnly => SHF_ALLOC,
+            SectionKind::Data | SectionKind::Bss => SHF_ALLOC | SHF_WRITE,
+            SectionKind::Note => 0,
+        }
+    }
+}
+
+// A place in a section to fill in with a symbol's address at link time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocation {
+    pub offset: u64,
+    pub symbol: String,
+    pub kind: u32,
+    pub addend: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: usize, // index into Object::sections
+    pub value: u64,     // offset in the section
+    pub size: u64,      // how many bytes it spans, if the assembly said with .size
+    pub global: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+// Section names, symbol names, and the like, each terminated by a zero byte
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        // Offset 0 is the empty string
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend(self.name.to_le_bytes());
+        out.extend(self.kind.to_le_bytes());
+        out.extend(self.flags.to_le_bytes());
+        out.extend(0u64.to_le_bytes()); // address, which the linker assigns
+        out.extend(self.offset.to_le_bytes());
+        out.extend(self.size.to_le_bytes());
+        out.extend(self.link.to_le_bytes());
+        out.extend(self.info.to_le_bytes());
+        out.extend(self.align.to_le_bytes());
+        out.extend(self.entry_size.to_le_bytes());
+    }
+}
+
+fn symbol_entry(out: &mut Vec<u8>, name: u32, info: u8, section: u16, value: u64, size: u64) {
+    out.extend(name.to_le_bytes());
+    out.push(info);
+    out.push(0); // default visibility
+    out.extend(section.to_le_bytes());
+    out.extend(value.to_le_bytes());
+    out.extend(size.to_le_bytes());
+}
+
+fn pad_to(out: &mut Vec<u8>, align: u64) {
+    while !(out.len() as u64).is_multiple_of(align) {
+        out.push(0);
+    }
+}
+
+impl Object {
+    pub fn to_elf(&self, machine: u16) -> Vec<u8> {
+        let defined: HashMap<&str, &Symbol> =
+            self.symbols.iter().map(|s| (s.name.as_str(), s)).collect();
+
+        // The null symbol, one per section, then the global and undefined symbols. Section
+        // header 0 is the null section, so section i's header is i + 1.
+        let mut strtab = StringTable::new();
+        let mut symtab = vec![0; SYMBOL_SIZE as usize];
+        for i in 0..self.sections.len() {
+            symbol_entry(
+                &mut symtab,
+                0,
+                STB_LOCAL << 4 | STT_SECTION,
+                i as u16 + 1,
+                0,
+                0,
+            );
+        }
+        let first_global = 1 + self.sections.len();
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut add_global = |symtab: &mut Vec<u8>, name: &'_ str, symbol: Option<&Symbol>| {
+            let (kind, section, value, size) = match symbol {
+                Some(s) if self.sections[s.section].kind == SectionKind::Text => {
+                    (STT_FUNC, s.section as u16 + 1, s.value, s.size)
+                }
+                Some(s) => (STT_OBJECT, s.section as u16 + 1, s.value, s.size),
+                None => (STT_NOTYPE, 0, 0, 0),
+            };
+            let name_offset = strtab.add(name);
+            let info = STB_GLOBAL << 4 | kind;
+            symbol_entry(symtab, name_offset, info, section, value, size);
+        };
+        for symbol in self.symbols.iter().filter(|s| s.global) {
+            indices.insert(&symbol.name, first_global + indices.len());
+            add_global(&mut symtab, &symbol.name, Some(symbol));
+        }
+        let relocations = self.sections.iter().flat_map(|s| &s.relocations);
+        for relocation in relocations {
+            let name = relocation.symbol.as_str();
+            if !defined.contains_key(name) && !indices.contains_key(name) {
+                indices.insert(name, first_global + indices.len());
+                add_global(&mut symtab, name, None);
+            }
+        }
+
+        // Each section's relocations, as .rela entries against the symbols above
+        let rela: Vec<Vec<u8>> = self
+            .sections
+            .iter()
+            .map(|section| {
+                let mut out = vec![];
+                for relocation in &section.relocations {
+                    let name = relocation.symbol.as_str();
+                    let (symbol, addend) = match defined.get(name) {
+                        Some(s) if !s.global => (s.section + 1, relocation.addend + s.value as i64),
+                        _ => (indices[name], relocation.addend),
+                    };
+                    out.extend(relocation.offset.to_le_bytes());
+                    out.extend(((symbol as u64) << 32 | relocation.kind as u64).to_le_bytes());
+                    out.extend(addend.to_le_bytes());
+                }
+                out
+            })
+            .collect();
+
+        let mut shstrtab = StringTable::new();
+        let mut headers = vec![];
+        let mut out = vec![0; HEADER_SIZE as usize];
+        for section in &self.sections {
+            pad_to(&mut out, section.align);
+            let offset = out.len() as u64;
+            if section.kind != SectionKind::Bss {
+                out.extend(&section.data);
+            }
+            headers.push(SectionHeader {
+                name: shstrtab.add(&section.name),
+                kind: match section.kind {
+                    SectionKind::Bss => SHT_NOBITS,
+                    _ => SHT_PROGBITS,
+                },
+                flags: section.flags(),
+                offset,
+                size: section.data.len() as u64,
+                link: 0,
+                info: 0,
+                align: section.align,
+                entry_size: 0,
+            });
+        }
+        for (i, (section, rela)) in self.sections.iter().zip(&rela).enumerate() {
+            if rela.is_empty() {
+                continue;
+            }
+            pad_to(&mut out, 8);
+            headers.push(SectionHeader {
+                name: shstrtab.add(&format!(".rela{}", section.name)),
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset: out.len() as u64,
+                size: rela.len() as u64,
+                link: 0, // the symbol table, once its index is known
+                info: i as u32 + 1,
+                align: 8,
+                entry_size: RELOCATION_SIZE,
+            });
+            out.extend(rela);
+        }
+        // The .rela sections come before the symbol table, so its index is only known now
+        let symtab_index = headers.len() as u32 + 1;
+        for header in headers.iter_mut().filter(|h| h.kind == SHT_RELA) {
+            header.link = symtab_index;
+        }
+        pad_to(&mut out, 8);
+        headers.push(SectionHeader {
+            name: shstrtab.add(".symtab"),
+            kind: SHT_SYMTAB,
+            flags: 0,
+            offset: out.len() as u64,
+            size: symtab.len() as u64,
+            link: symtab_index + 1,
+            info: first_global as u32,
+            align: 8,
+            entry_size: SYMBOL_SIZE,
+        });
+        out.extend(&symtab);
+        headers.push(SectionHeader {
+            name: shstrtab.add(".strtab"),
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset: out.len() as u64,
+            size: strtab.bytes.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        });
+        out.extend(&strtab.bytes);
+        let shstrtab_header = SectionHeader {
+            name: shstrtab.add(".shstrtab"),
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset: out.len() as u64,
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        };
+        out.extend(&shstrtab.bytes);
+        headers.push(SectionHeader {
+            size: shstrtab.bytes.len() as u64,
+            ..shstrtab_header
+        });
+
+        pad_to(&mut out, 8);
+        let section_headers = out.len() as u64;
+        out.extend([0; SECTION_HEADER_SIZE as usize]);
+        for header in &headers {
+            header.write(&mut out);
+        }
+
+        let section_count = headers.len() as u16 + 1;
+        let mut header = vec![0x7f, b'E', b'L', b'F'];
+        header.extend([2, 1, 1]); // 64-bit, little-endian, version 1
+        header.resize(16, 0);
+        header.extend(1u16.to_le_bytes()); // ET_REL
+        header.extend(machine.to_le_bytes());
+        header.extend(1u32.to_le_bytes());
+        header.extend(0u64.to_le_bytes()); // no entry point
+        header.extend(0u64.to_le_bytes()); // no program headers
+        header.extend(section_headers.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend((HEADER_SIZE as u16).to_le_bytes());
+        header.extend([0; 4]); // program header size and count
+        header.extend((SECTION_HEADER_SIZE as u16).to_le_bytes());
+        header.extend(section_count.to_le_bytes());
+        header.extend((section_count - 1).to_le_bytes()); // .shstrtab comes last
+        out[..HEADER_SIZE as usize].copy_from_slice(&header);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn read_u64(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    // Each section header's name, type, and link
+    fn section_headers(elf: &[u8]) -> Vec<(String, u32, u32)> {
+        let table = read_u64(elf, 0x28) as usize;
+        let count = read_u16(elf, 0x3c) as usize;
+        let names = table + read_u16(elf, 0x3e) as usize * 64;
+        let names = read_u64(elf, names + 0x18) as usize;
+        (0..count)
+            .map(|i| {
+                let header = table + i * 64;
+                let name = names + read_u32(elf, header) as usize;
+                let end = name + elf[name..].iter().position(|b| *b == 0).unwrap();
+                let name = String::from_utf8(elf[name..end].to_vec()).unwrap();
+                (
+                    name,
+                    read_u32(elf, header + 4),
+                    read_u32(elf, header + 0x28),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut text = Section::new(".text", SectionKind::Text);
+        text.data = vec![0xe8, 0, 0, 0, 0, 0xc3];
+        text.relocations.push(Relocation {
+            offset: 1,
+            symbol: "f".to_owned(),
+            kind: R_X86_64_PLT32,
+            addend: -4,
+        });
+        let mut bss = Section::new(".bss", SectionKind::Bss);
+        bss.data = vec![0; 8];
+        let object = Object {
+            sections: vec![text, bss],
+            symbols: vec![Symbol {
+                name: "main".to_owned(),
+                section: 0,
+                value: 0,
+                size: 4,
+                global: true,
+            }],
+        };
+        let elf = object.to_elf(EM_X86_64);
+
+        assert_eq!(elf[..4], [0x7f, b'E', b'L', b'F']);
+        assert_eq!(read_u16(&elf, 0x10), 1); // ET_REL
+        assert_eq!(read_u16(&elf, 0x12), EM_X86_64);
+        let headers = section_headers(&elf);
+        let expected = [
+            ("", 0, 0),
+            (".text", SHT_PROGBITS, 0),
+            (".bss", SHT_NOBITS, 0),
+            (".rela.text", SHT_RELA, 4),
+            (".symtab", SHT_SYMTAB, 5),
+            (".strtab", SHT_STRTAB, 0),
+            (".shstrtab", SHT_STRTAB, 0),
+        ]
+        .map(|(name, kind, link)| (name.to_owned(), kind, link));
+        assert_eq!(headers, expected);
+
+        // The null symbol and the two section symbols are local, then come main and the
+        // undefined f, which the relocation refers to
+        let symtab = read_u64(&elf, read_u64(&elf, 0x28) as usize + 4 * 64 + 0x18) as usize;
+        assert_eq!(read_u64(&elf, symtab + 4 * 24 + 8), 0);
+        assert_eq!(read_u64(&elf, symtab + 3 * 24 + 16), 4);
+        assert_eq!(elf[symtab + 3 * 24 + 4], STB_GLOBAL << 4 | STT_FUNC);
+        assert_eq!(elf[symtab + 4 * 24 + 4], STB_GLOBAL << 4 | STT_NOTYPE);
+        let rela = read_u64(&elf, read_u64(&elf, 0x28) as usize + 3 * 64 + 0x18) as usize;
+        assert_eq!(read_u64(&elf, rela + 8), 4 << 32 | R_X86_64_PLT32 as u64);
+        assert_eq!(read_u64(&elf, rela + 16) as i64, -4);
+    }
+}
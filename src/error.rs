@@ -0,0 +1,142 @@
+use crate::diagnostic::Note;
+use crate::span::Span;
+use std::error::Error;
+use std::fmt;
+
+/*
+ * The error returned by every stage of the compiler. The variant records which stage
+ * failed, and errors that can be pinned to a place in the source carry its Span. A semantic
+ * error can also point at other places, such as an earlier declaration, with notes.
+ */
+// The variant names spell out the stage, so they all end in Error
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    LexError {
+        message: String,
+        span: Option<Span>,
+    },
+    ParseError {
+        message: String,
+        span: Option<Span>,
+    },
+    SemanticError {
+        message: String,
+        span: Option<Span>,
+        notes: Vec<Note>,
+    },
+    LoweringError(String), // AST to CFG
+    CodegenError(String),
+}
+
+impl CompileError {
+    pub fn semantic(message: String) -> Self {
+        CompileError::SemanticError {
+            message,
+            span: None,
+            notes: vec![],
+        }
+    }
+
+    // A semantic error at `span`
+    pub fn semantic_at(message: String, span: Span) -> Self {
+        CompileError::SemanticError {
+            message,
+            span: Some(span),
+            notes: vec![],
+        }
+    }
+
+    // Adds a note to a semantic error; other errors have nowhere to keep one
+    pub fn with_note(mut self, note: Note) -> Self {
+        if let CompileError::SemanticError { notes, .. } = &mut self {
+            notes.push(note);
+        }
+        self
+    }
+
+    pub fn notes(&self) -> &[Note] {
+        match self {
+            CompileError::SemanticError { notes, .. } => notes,
+            _ => &[],
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            CompileError::LexError { message, .. }
+            | CompileError::ParseError { message, .. }
+            | CompileError::SemanticError { message, .. }
+            | CompileError::LoweringError(message)
+            | CompileError::CodegenError(message) => message,
+        }
+    }
+
+    // Points an error raised somewhere inside a statement at that statement, unless it
+    // already knows where it came from
+    pub fn or_span(self, span: Option<Span>) -> Self {
+        match self {
+            CompileError::SemanticError {
+                message,
+                span: None,
+                notes,
+            } => CompileError::SemanticError {
+                message,
+                span,
+                notes,
+            },
+            error => error,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::LexError { span, .. }
+            | CompileError::ParseError { span, .. }
+            | CompileError::SemanticError { span, .. } => *span,
+            CompileError::LoweringError(_) | CompileError::CodegenError(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span() {
+            Some(span) => write!(f, "{}: {}", span, self.message()),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+// Lets code that only cares about the message (mostly tests) keep using `?` on a String result
+impl From<CompileError> for String {
+    fn from(error: CompileError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let span = Span {
+            start: 4,
+            end: 5,
+            line: 1,
+            column: 5,
+        };
+        let error = CompileError::ParseError {
+            message: "Expected Semicolon".to_owned(),
+            span: Some(span),
+        };
+        assert_eq!(error.to_string(), "1:5: Expected Semicolon");
+        assert_eq!(
+            CompileError::semantic("Undefined variable x".to_owned()).to_string(),
+            "Undefined variable x"
+        );
+    }
+}
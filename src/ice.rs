@@ -0,0 +1,153 @@
+use crate::span::Span;
+use std::cell::RefCell;
+use std::panic::{self, Location, PanicHookInfo};
+
+/*
+ * Internal compiler errors. A panic anywhere in the pipeline is a bug in the compiler rather
+ * than a mistake in the program being compiled, so instead of Rust's bare panic message the
+ * hook from `install_hook` reports it as an internal compiler error: what went wrong, which
+ * stage was running, where in the input it had got to, and a request to file a bug.
+ *
+ * The stages keep that context up to date with `enter_stage` and `enter_function` as they go.
+ * It's per thread, so compilations on different threads don't see each other's. A worker
+ * thread that takes over part of a stage starts from its parent's, with `current` and
+ * `restore`.
+ */
+
+const BUG_REPORT_URL: &str = "https://github.com/dklahn99/c-compiler/issues";
+
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    file: String,
+    stage: Option<&'static str>,
+    function: Option<String>,
+    span: Option<Span>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+// Starts a compilation of `file`, forgetting where the last one got to
+pub fn enter_file(file: &str) {
+    CONTEXT.with_borrow_mut(|context| {
+        *context = Context {
+            file: file.to_owned(),
+            ..Context::default()
+        }
+    });
+}
+
+pub fn enter_stage(stage: &'static str) {
+    CONTEXT.with_borrow_mut(|context| {
+        context.stage = Some(stage);
+        context.function = None;
+        context.span = None;
+    });
+}
+
+// `span` is where the function is declared, when the stage still knows
+pub fn enter_function(name: &str, span: Option<Span>) {
+    CONTEXT.with_borrow_mut(|context| {
+        context.function = Some(name.to_owned());
+        context.span = span;
+    });
+}
+
+// Where this thread's compilation has got to
+pub fn current() -> Context {
+    CONTEXT.with_borrow(Context::clone)
+}
+
+pub fn restore(context: Context) {
+    CONTEXT.set(context);
+}
+
+// What was passed to panic!, or to expect and friends
+fn panic_message<'a>(info: &'a PanicHookInfo) -> &'a str {
+    let payload = info.payload();
+    let message = payload.downcast_ref::<&str>().copied();
+    let message = message.or_else(|| payload.downcast_ref::<String>().map(String::as_str));
+    message
+        .filter(|message| !message.is_empty())
+        .unwrap_or("unexpected panic")
+}
+
+// The report for a panic, given the context it happened in
+fn report(context: &Context, message: &str, location: Option<&Location>) -> String {
+    let mut lines = vec![format!("error: internal compiler error: {}", message)];
+    if let Some(span) = context.span.filter(|span| span.line > 0) {
+        lines.push(format!(" --> {}:{}", context.file, span));
+    }
+    lines.push(match (context.stage, &context.function) {
+        (Some(stage), Some(function)) => format!("note: during {} of function {}", stage, function),
+        (Some(stage), None) => format!("note: during {} of {}", stage, context.file),
+        (None, _) => format!("note: while compiling {}", context.file),
+    });
+    if let Some(location) = location {
+        lines.push(format!("note: the compiler panicked at {}", location));
+    }
+    lines.push(format!(
+        "note: this is a bug in the compiler, not in your program; please file an issue at \
+         {} with the input that triggered it",
+        BUG_REPORT_URL
+    ));
+    lines.join("\n")
+}
+
+// Reports panics as internal compiler errors from now on. RUST_BACKTRACE still adds a backtrace.
+pub fn install_hook() {
+    panic::set_hook(Box::new(|info| {
+        eprintln!(
+            "{}",
+            report(&current(), panic_message(info), info.location())
+        );
+        let backtrace = std::backtrace::Backtrace::capture();
+        if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+            eprintln!("{}", backtrace);
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let context = Context {
+            file: "t.c".to_owned(),
+            stage: Some("codegen"),
+            function: Some("main".to_owned()),
+            span: Some(Span {
+                line: 3,
+                column: 5,
+                ..Span::default()
+            }),
+        };
+        let reported = report(&context, "no register for t1", Some(Location::caller()));
+        let lines: Vec<&str> = reported.lines().collect();
+        assert_eq!(
+            lines[0],
+            "error: internal compiler error: no register for t1"
+        );
+        assert_eq!(lines[1], " --> t.c:3:5");
+        assert_eq!(lines[2], "note: during codegen of function main");
+        assert!(lines[3].starts_with("note: the compiler panicked at src/ice.rs:"));
+        assert!(lines[4].contains(BUG_REPORT_URL));
+
+        // Before any stage has started there's only the file to go on
+        let context = Context {
+            file: "t.c".to_owned(),
+            ..Context::default()
+        };
+        let reported = report(&context, "unexpected panic", None);
+        assert_eq!(
+            reported.lines().take(2).collect::<Vec<_>>(),
+            vec![
+                "error: internal compiler error: unexpected panic",
+                "note: while compiling t.c"
+            ]
+        );
+    }
+}
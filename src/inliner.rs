@@ -0,0 +1,261 @@
+use crate::cfg::*;
+use crate::intern::Symbol;
+use std::collections::BTreeMap;
+
+/*
+ * Inlining: a call to a small function that calls nothing itself is replaced with a copy of
+ * the function's body. The arguments are copied into the copy's parameters, and each of its
+ * returns copies the value into the call's dest and jumps to what came after the call.
+ *
+ * Only leaf functions are inlined, so a body being inlined never holds another call to
+ * inline, and recursion can't come into it. A function declared `inline` is inlined
+ * whatever its size; any other has to be within the threshold.
+ *
+ * The functions themselves stay, since something may still call them by name.
+ */
+
+// The most statements a function can have and be inlined without being declared `inline`
+pub const THRESHOLD: usize = 16;
+
+// Line markers generate no code, so they don't count
+fn size(function: &Function) -> usize {
+    let statements = function.cfg.values().flatten();
+    statements
+        .filter(|s| !matches!(s, Statement::Line(_)))
+        .count()
+}
+
+fn is_leaf(function: &Function) -> bool {
+    let mut statements = function.cfg.values().flatten();
+    !statements.any(|s| matches!(s, Statement::Call { .. }))
+}
+
+// The functions to inline calls to, as they were before any inlining
+fn inlinable(program: &Program, threshold: usize) -> BTreeMap<Symbol, Function> {
+    program
+        .functions
+        .iter()
+        .filter(|(_, f)| is_leaf(f) && (f.inline || size(f) <= threshold))
+        .map(|(name, f)| (*name, f.clone()))
+        .collect()
+}
+
+// Moves the blocks a statement refers to up by `offset`
+fn offset_blocks(statement: &mut Statement, offset: ControlBlockId) {
+    match statement {
+        Statement::If {
+            goto_true,
+            goto_false,
+            ..
+        } => {
+            *goto_true += offset;
+            *goto_false += offset;
+        }
+        Statement::Goto(block) => *block += offset,
+        Statement::Switch {
+            targets, default, ..
+        } => {
+            for block in targets.iter_mut().chain([default]) {
+                *block += offset;
+            }
+        }
+        Statement::Phi { sources, .. } => {
+            for (block, _) in sources {
+                *block += offset;
+            }
+        }
+        _ => {}
+    }
+}
+
+// Replaces the call at `index` in `block` with the body of `callee`
+fn inline_call(function: &mut Function, block: ControlBlockId, index: usize, callee: &Function) {
+    let params = function.params.iter().map(|p| p.0);
+    let var_offset = params.chain([function.cfg.max_var_number()]).max();
+    let var_offset = var_offset.expect("there's at least the cfg's number");
+    let block_offset = function.cfg.keys().max().map_or(0, |last| last + 1);
+    let last_callee_block = callee.cfg.keys().max().copied().unwrap_or(0);
+    let continuation = block_offset + last_callee_block + 1;
+    let rename = |var: VReg| VReg(var.0 + var_offset);
+
+    let statements = function
+        .cfg
+        .get_mut(&block)
+        .expect("the call's block is in the CFG");
+    let mut rest = statements.split_off(index);
+    let Statement::Call { dest, args, .. } = rest.remove(0) else {
+        panic!("Expected a call at {}:{}", block_name(block), index);
+    };
+    // The inlined statements belong to the line of the call
+    let line = statements.iter().rev().find_map(|s| match s {
+        Statement::Line(line) => Some(Statement::Line(*line)),
+        _ => None,
+    });
+    for (param, arg) in callee.params.iter().zip(args) {
+        statements.push(Statement::Copy {
+            dest: rename(*param),
+            src: arg,
+        });
+    }
+    statements.push(Statement::Goto(block_offset));
+
+    // Phis after the call's block now see control arrive from the continuation
+    for statement in function.cfg.values_mut().flatten() {
+        if let Statement::Phi { sources, .. } = statement {
+            for (from, _) in sources.iter_mut() {
+                if *from == block {
+                    *from = continuation;
+                }
+            }
+        }
+    }
+    function
+        .cfg
+        .insert(continuation, line.iter().cloned().chain(rest).collect());
+
+    for (id, body) in callee.cfg.iter() {
+        let mut inlined: ControlBlock = line.iter().cloned().collect();
+        for statement in body {
+            let mut statement = statement.clone();
+            if let Some(var) = statement.defined_var_mut() {
+                *var = rename(*var);
+            }
+            for var in statement.used_vars_mut() {
+                *var = rename(*var);
+            }
+            offset_blocks(&mut statement, block_offset);
+            match statement {
+                Statement::Line(_) => {}
+                // As when called, returning nothing gives 0
+                Statement::Return(value) => {
+                    inlined.push(match value {
+                        Some(src) => Statement::Copy { dest, src },
+                        None => Statement::Assign {
+                            var: dest,
+                            value: 0,
+                        },
+                    });
+                    inlined.push(Statement::Goto(continuation));
+                }
+                statement => inlined.push(statement),
+            }
+        }
+        function.cfg.insert(id + block_offset, inlined);
+    }
+}
+
+// Returns whether there were any calls to inline
+fn inline_into(function: &mut Function, callees: &BTreeMap<Symbol, Function>) -> bool {
+    let mut changed = false;
+    loop {
+        let call = function.cfg.iter().find_map(|(block, statements)| {
+            statements
+                .iter()
+                .enumerate()
+                .find_map(|(index, s)| match s {
+                    Statement::Call { func, .. } => Some((*block, index, callees.get(func)?)),
+                    _ => None,
+                })
+        });
+        let Some((block, index, callee)) = call else {
+            return changed;
+        };
+        log::debug!("inlining a call at {}:{}", block_name(block), index);
+        inline_call(function, block, index, callee);
+        changed = true;
+    }
+}
+
+// Inlines every call to a leaf function that's declared `inline` or has at most `threshold`
+// statements. Returns whether there were any.
+pub fn inline_calls(program: &mut Program, threshold: usize) -> bool {
+    let callees = inlinable(program, threshold);
+    let mut changed = false;
+    for function in program.functions.values_mut() {
+        changed |= inline_into(function, &callees);
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::{parse_cfg, parse_program};
+    use crate::interp;
+    use crate::{CompileOptions, compile_to_program};
+
+    fn calls(function: &Function) -> usize {
+        let statements = function.cfg.values().flatten();
+        statements
+            .filter(|s| matches!(s, Statement::Call { .. }))
+            .count()
+    }
+
+    #[test]
+    fn test_inline_call() -> Result<(), String> {
+        let mut program = parse_program(
+            "fn add(v1, v2) {\nbb0: v3 = v1 + v2; ret v3\n}\n\
+             fn main() {\nbb0: v1 = 2; v2 = call add(v1, v1); v3 = v2 * v2; ret v3\n}",
+        )?;
+        assert!(inline_calls(&mut program, THRESHOLD));
+        // add's vars come after main's, and its blocks before what followed the call
+        assert_eq!(
+            program.functions[&Symbol::intern("main")].cfg,
+            parse_cfg(
+                "bb0: v1 = 2; v4 = v1; v5 = v1; goto bb1\n\
+                 bb1: v6 = v4 + v5; v2 = v6; goto bb2\n\
+                 bb2: v3 = v2 * v2; ret v3"
+            )?
+        );
+        assert_eq!(interp::run(&program, "main")?.result, 16);
+        assert!(!inline_calls(&mut program, THRESHOLD));
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_threshold() -> Result<(), String> {
+        // big is over the threshold, and calls is not a leaf
+        let mut program = parse_program(
+            "fn big(v1) {\nbb0: v2 = v1 + v1; ret v2\n}\n\
+             inline fn hinted(v1) {\nbb0: if v1 goto bb1 else bb2\nbb1: ret v1\nbb2: ret\n}\n\
+             fn calls(v1) {\nbb0: v2 = call big(v1); ret v2\n}\n\
+             fn main() {\nbb0: v1 = 3; v2 = call big(v1); v3 = call hinted(v2); \
+             v4 = call calls(v3); ret v4\n}",
+        )?;
+        assert!(inline_calls(&mut program, 1));
+        let main = &program.functions[&Symbol::intern("main")];
+        assert_eq!(calls(main), 2);
+        assert_eq!(calls(&program.functions[&Symbol::intern("calls")]), 1);
+        assert_eq!(interp::run(&program, "main")?.result, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_source() -> Result<(), String> {
+        let source = "
+inline int clamp(int x) {
+    if (x < 0) return 0;
+    if (x > 9) return 9;
+    return x;
+}
+int square(int x) { return x * x; }
+int main() {
+    int a = 7;
+    return clamp(square(a)) + clamp(-a) + clamp(a);
+}";
+        let options = CompileOptions {
+            passes: CompileOptions::passes_for_level(2),
+            ..Default::default()
+        };
+        let compiled = compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+        let program = compiled.program;
+        assert!(program.functions[&Symbol::intern("clamp")].inline);
+        // Once inlined, everything folds away
+        assert_eq!(
+            program.functions[&Symbol::intern("main")].cfg,
+            parse_cfg("bb0: v8 = 16; ret v8")?
+        );
+        assert_eq!(interp::run(&program, "main")?.result, 16);
+        Ok(())
+    }
+}
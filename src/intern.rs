@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/*
+ * Interned identifiers. The tokenizer turns each name into a Symbol, which is copied rather
+ * than cloned through the AST, the symbol table, and the CFG.
+ *
+ * A Symbol is the name's text, stored once by the interner and never freed, so reading it
+ * back takes no lock and `as_str` can hand out a &'static str. Names are copied into chunks
+ * of CHUNK_SIZE bytes rather than each getting an allocation of its own.
+ *
+ * Each thread interns into a table of its own, so the LSP's session and a compilation (or
+ * the workers of one) never wait on each other. The same name interned by two threads is
+ * stored twice, which is why symbols compare and hash by their text, only skipping the
+ * comparison when both point at the same copy.
+ *
+ * Symbols sort by their text, so anything kept in a BTreeMap by name (functions, globals)
+ * comes out in the same order however the names were first seen.
+ */
+
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static str);
+
+const CHUNK_SIZE: usize = 4096;
+
+#[derive(Default)]
+struct Interner {
+    names: HashSet<&'static str>,
+    free: &'static mut [u8], // what's left of the current chunk
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::default();
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> &'static str {
+        if let Some(interned) = self.names.get(name) {
+            return interned;
+        }
+        let interned = self.store(name);
+        self.names.insert(interned);
+        interned
+    }
+
+    // Copies the name into the current chunk, starting another when it doesn't fit
+    fn store(&mut self, name: &str) -> &'static str {
+        if name.len() > CHUNK_SIZE {
+            return Box::leak(name.into());
+        }
+        if name.len() > self.free.len() {
+            self.free = Box::leak(vec![0; CHUNK_SIZE].into_boxed_slice());
+        }
+        let (bytes, free) = std::mem::take(&mut self.free).split_at_mut(name.len());
+        self.free = free;
+        bytes.copy_from_slice(name.as_bytes());
+        std::str::from_utf8(bytes).expect("the bytes are copied from a str")
+    }
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Self {
+        Symbol(INTERNER.with_borrow_mut(|interner| interner.intern(name)))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(other.0)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Like a string's, so debug output reads the same as it did with names as Strings
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_intern() {
+        let main = Symbol::intern("main");
+        assert_eq!(Symbol::intern("main"), main);
+        assert_ne!(Symbol::intern("mainly"), main);
+        assert_eq!(main.as_str(), "main");
+        assert_eq!(main, "main");
+        assert_eq!(format!("{} {:?}", main, main), "main \"main\"");
+
+        // Ordered by name, whichever was interned first
+        let names: BTreeSet<Symbol> = ["zeta", "alpha", "mu"].map(Symbol::intern).into();
+        let names: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "mu", "zeta"]);
+
+        // Another thread stores its own copy, which is still the same symbol
+        let other = std::thread::spawn(|| Symbol::intern("main"))
+            .join()
+            .unwrap();
+        assert!(!std::ptr::eq(other.as_str(), main.as_str()));
+        assert_eq!(other, main);
+
+        // Names too long for a chunk get an allocation of their own
+        let long = "x".repeat(CHUNK_SIZE + 1);
+        assert_eq!(Symbol::intern(&long).as_str(), long);
+        assert_eq!(Symbol::intern("after"), "after");
+    }
+}
@@ -0,0 +1,628 @@
+use crate::cfg::*;
+use crate::intern::Symbol;
+use crate::optimize::{evaluate_binary, evaluate_unary};
+use std::collections::BTreeMap;
+
+/*
+ * Runs a program's CFG IR directly, as the reference for what the backends' code should do.
+ * Each var holds a 64-bit value, control follows the Goto and If edges, and main's Return
+ * is the result. Arithmetic is the optimizer's, so folding and running agree.
+ *
+ * Memory is flat and byte addressed, laid out like the Wasm backend's: string literals from
+ * address 16, so nothing lives at the null pointer, then the globals in 8-byte words, then
+ * the stack. A call's Alloca slots are fixed for the whole call, the way codegen gives each
+ * one its own place in the frame, and they're freed when it returns. Loads and stores move
+ * 8-byte words, little-endian.
+ *
+ * Of the functions the program doesn't define, only putchar can be called, and what it
+ * writes is collected as the program's output.
+ */
+
+const DATA_START: u64 = 16;
+
+// Limits that stand in for the machine's, so a runaway program stops with an error
+const MEMORY_LIMIT: u64 = 8 << 20;
+const MAX_CALL_DEPTH: usize = 10_000;
+const MAX_STEPS: u64 = 100_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Execution {
+    pub result: u64, // what main returned
+    pub output: Vec<u8>,
+}
+
+// How a function finished
+enum Exit {
+    Return(u64),
+    TailCall(Symbol, Vec<u64>),
+}
+
+struct Interpreter<'a> {
+    program: &'a Program,
+    addresses: BTreeMap<Symbol, u64>, // of each string and global
+    memory: Vec<u8>,                  // starting from address 0
+    output: Vec<u8>,
+    depth: usize,
+    steps: u64,
+}
+
+pub fn run(program: &Program, program_name: &str) -> Result<Execution, String> {
+    let mut interpreter = Interpreter::new(program);
+    let main = Symbol::intern("main");
+    // A main that takes argc and argv gets the command line a program run with no arguments
+    // would: argc 1, and argv pointing at the program's name and then the null pointer
+    let args = match program.functions.get(&main) {
+        Some(function) if function.params.len() == 2 => {
+            let memory = &mut interpreter.memory;
+            let name = memory.len() as u64;
+            memory.extend(program_name.bytes().chain([0]));
+            memory.resize(memory.len().next_multiple_of(8), 0);
+            let argv = memory.len() as u64;
+            memory.extend(name.to_le_bytes().into_iter().chain(0u64.to_le_bytes()));
+            vec![1, argv]
+        }
+        _ => vec![],
+    };
+    let result = interpreter.call(main, args)?;
+    Ok(Execution {
+        result,
+        output: interpreter.output,
+    })
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(program: &'a Program) -> Self {
+        let mut interpreter = Interpreter {
+            program,
+            addresses: BTreeMap::new(),
+            memory: vec![0; DATA_START as usize],
+            output: vec![],
+            depth: 0,
+            steps: 0,
+        };
+        for (label, value) in &program.strings {
+            let address = interpreter.memory.len() as u64;
+            interpreter.addresses.insert(*label, address);
+            interpreter.memory.extend(literal_bytes(value));
+            interpreter.memory.push(0);
+        }
+        interpreter.align();
+        // Globals can only point at strings, which all have addresses by now
+        for (name, value) in &program.globals {
+            let address = interpreter.memory.len() as u64;
+            interpreter.addresses.insert(*name, address);
+            let word = match value {
+                Initializer::Zero => 0,
+                Initializer::Int(value) => *value as u64,
+                Initializer::String(label) => interpreter.addresses[label],
+            };
+            interpreter.memory.extend(word.to_le_bytes());
+        }
+        interpreter
+    }
+
+    fn align(&mut self) {
+        let aligned = self.memory.len().div_ceil(8) * 8;
+        self.memory.resize(aligned, 0);
+    }
+
+    // A tail call takes the place of the call it's made from rather than nesting inside it,
+    // so a chain of them runs at one depth
+    fn call(&mut self, mut name: Symbol, mut args: Vec<u64>) -> Result<u64, String> {
+        loop {
+            match self.call_once(name, args)? {
+                Exit::Return(value) => return Ok(value),
+                Exit::TailCall(func, func_args) => (name, args) = (func, func_args),
+            }
+        }
+    }
+
+    fn call_once(&mut self, name: Symbol, args: Vec<u64>) -> Result<Exit, String> {
+        let Some(function) = self.program.functions.get(&name) else {
+            return self.call_external(name, &args).map(Exit::Return);
+        };
+        if args.len() != function.params.len() {
+            return Err(format!(
+                "{} takes {} arguments, but was called with {}",
+                name,
+                function.params.len(),
+                args.len()
+            ));
+        }
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(format!("Calls nested more than {} deep", MAX_CALL_DEPTH));
+        }
+
+        // The frame goes on top of the stack, and comes off when the call returns
+        let frame = self.memory.len();
+        let mut slots = VRegMap::new();
+        for statement in function.cfg.values().flatten() {
+            if let Statement::Alloca { dest, size } = statement {
+                slots.insert(*dest, self.memory.len() as u64);
+                let size = size.div_ceil(8) * 8;
+                if self.memory.len() as u64 + size > MEMORY_LIMIT {
+                    return Err("Stack overflow".to_owned());
+                }
+                self.memory.resize(self.memory.len() + size as usize, 0);
+            }
+        }
+        let env = function.params.iter().copied().zip(args).collect();
+        self.depth += 1;
+        let result = self.run_function(function, env, &slots);
+        self.depth -= 1;
+        self.memory.truncate(frame);
+        result
+    }
+
+    fn call_external(&mut self, name: Symbol, args: &[u64]) -> Result<u64, String> {
+        match (name.as_str(), args) {
+            ("putchar", [c]) => {
+                self.output.push(*c as u8);
+                Ok(*c as u8 as u64)
+            }
+            _ => Err(format!(
+                "Call to {}, which isn't defined in the program",
+                name
+            )),
+        }
+    }
+
+    fn run_function(
+        &mut self,
+        function: &Function,
+        mut env: VRegMap<u64>,
+        slots: &VRegMap<u64>,
+    ) -> Result<Exit, String> {
+        let cfg = &function.cfg;
+        let mut previous = None;
+        let mut block = 0;
+        loop {
+            let Some(statements) = cfg.get(&block) else {
+                return Err(format!(
+                    "Jump to {}, which doesn't exist",
+                    block_name(block)
+                ));
+            };
+            // A block's phis all read their sources before any of them is assigned
+            let phi_count = statements
+                .iter()
+                .take_while(|s| matches!(s, Statement::Phi { .. }))
+                .count();
+            let mut phi_values = vec![];
+            for statement in &statements[..phi_count] {
+                let Statement::Phi { dest, sources } = statement else {
+                    unreachable!()
+                };
+                let source = sources.iter().find(|(from, _)| Some(*from) == previous);
+                let Some((_, src)) = source else {
+                    return Err(format!(
+                        "`{}` has no source for how control got here",
+                        statement
+                    ));
+                };
+                phi_values.push((*dest, read(&env, src)?));
+            }
+            env.extend(phi_values);
+
+            let mut next = None;
+            for (i, statement) in statements.iter().enumerate().skip(phi_count) {
+                self.steps += 1;
+                if self.steps > MAX_STEPS {
+                    return Err(format!("Gave up after {} steps", MAX_STEPS));
+                }
+                match statement {
+                    Statement::If {
+                        var,
+                        goto_true,
+                        goto_false,
+                    } => {
+                        next = Some(match read(&env, var)? {
+                            0 => *goto_false,
+                            _ => *goto_true,
+                        });
+                        break;
+                    }
+                    Statement::Goto(target) => {
+                        next = Some(*target);
+                        break;
+                    }
+                    Statement::Switch {
+                        var,
+                        targets,
+                        default,
+                    } => {
+                        let index = usize::try_from(read(&env, var)?).ok();
+                        let target = index.and_then(|i| targets.get(i));
+                        next = Some(*target.unwrap_or(default));
+                        break;
+                    }
+                    Statement::Return(Some(var)) => return read(&env, var).map(Exit::Return),
+                    // A call to a function with nothing to return gives the caller 0
+                    Statement::Return(None) => return Ok(Exit::Return(0)),
+                    // The caller's frame is done with once its return is all that's left
+                    Statement::Call {
+                        dest,
+                        func,
+                        args,
+                        tail: true,
+                    } if statements.get(i + 1) == Some(&Statement::Return(Some(*dest))) => {
+                        let args = args
+                            .iter()
+                            .map(|arg| read(&env, arg))
+                            .collect::<Result<_, _>>()?;
+                        return Ok(Exit::TailCall(*func, args));
+                    }
+                    _ => {
+                        if let Some((var, value)) = self.execute(statement, &env, slots)? {
+                            env.insert(var, value);
+                        }
+                    }
+                }
+            }
+            let Some(next) = next else {
+                return Err(format!("{} ends without a terminator", block_name(block)));
+            };
+            previous = Some(block);
+            block = next;
+        }
+    }
+
+    // Runs a statement that isn't a terminator, giving the var it defines and its value
+    fn execute(
+        &mut self,
+        statement: &Statement,
+        env: &VRegMap<u64>,
+        slots: &VRegMap<u64>,
+    ) -> Result<Option<(VReg, u64)>, String> {
+        let value = match statement {
+            Statement::Assign { value, .. } => *value as u64,
+            Statement::Copy { src, .. } => read(env, src)?,
+            Statement::Operation { op, lhs, rhs, .. } => {
+                let (lhs, rhs) = (read(env, lhs)?, read(env, rhs)?);
+                evaluate_binary(op, lhs, rhs)
+                    .ok_or_else(|| format!("`{}` divides {} by {}", statement, lhs, rhs))?
+            }
+            Statement::UnaryOperation { op, operand, .. } => {
+                evaluate_unary(op, read(env, operand)?)
+            }
+            Statement::Call { func, args, .. } => {
+                let args = args
+                    .iter()
+                    .map(|arg| read(env, arg))
+                    .collect::<Result<_, _>>()?;
+                self.call(*func, args)?
+            }
+            Statement::Alloca { dest, .. } => slots[dest],
+            Statement::Load { addr, width, .. } => {
+                let address = read(env, addr)?;
+                let bytes = self.memory_at(address, *width, statement)?;
+                match width {
+                    Width::I8 => bytes[0] as i8 as u64,
+                    Width::I64 => u64::from_le_bytes(bytes.try_into().expect("a word is 8 bytes")),
+                }
+            }
+            Statement::Store { addr, src, width } => {
+                let address = read(env, addr)?;
+                let value = read(env, src)?;
+                self.memory_at(address, *width, statement)?
+                    .copy_from_slice(&value.to_le_bytes()[..width.bytes()]);
+                return Ok(None);
+            }
+            Statement::LoadAddress { label, .. } => match self.addresses.get(label) {
+                Some(address) => *address,
+                None => return Err(format!("Unknown data label {}", label)),
+            },
+            Statement::Line(_) => return Ok(None),
+            Statement::If { .. }
+            | Statement::Goto(_)
+            | Statement::Switch { .. }
+            | Statement::Return(_)
+            | Statement::Phi { .. } => {
+                unreachable!("terminators and phis are handled by run_function")
+            }
+        };
+        let var = statement
+            .defined_var()
+            .expect("every other statement defines a var");
+        Ok(Some((*var, value)))
+    }
+
+    // The 8 bytes at `address`, for `statement` to load or store
+    fn memory_at(
+        &mut self,
+        address: u64,
+        width: Width,
+        statement: &Statement,
+    ) -> Result<&mut [u8], String> {
+        let size = width.bytes();
+        let in_bounds = address >= DATA_START
+            && address.saturating_add(size as u64) <= self.memory.len() as u64;
+        if !in_bounds {
+            return Err(format!(
+                "`{}` accesses address {}, which is out of bounds",
+                statement, address
+            ));
+        }
+        Ok(&mut self.memory[address as usize..address as usize + size])
+    }
+}
+
+fn read(env: &VRegMap<u64>, var: &VReg) -> Result<u64, String> {
+    env.get(var)
+        .copied()
+        .ok_or_else(|| format!("{} is used before it is defined", var))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_program;
+    use crate::{CompileOptions, compile_to_program};
+
+    fn run_source(source: &str, level: u32) -> Result<Execution, String> {
+        let options = CompileOptions {
+            passes: CompileOptions::passes_for_level(level),
+            ..Default::default()
+        };
+        let compiled = compile_to_program(source, &options).map_err(|d| d[0].to_string())?;
+        run(&compiled.program, "main")
+    }
+
+    #[test]
+    fn test_run_source() -> Result<(), String> {
+        let source = "
+int fib(int n) {
+    if (n < 2) { return n; }
+    return fib(n - 1) + fib(n - 2);
+}
+int main() {
+    int total = 0;
+    for (int i = 0; i < 10; i = i + 1) { total = total + fib(i); }
+    return total;
+}";
+        // Every -O level means the same thing
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 88);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recursion() -> Result<(), String> {
+        // is_odd is called before its definition, through its prototype
+        let source = "
+int is_odd(int n);
+int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+int fact(int n) { if (n < 2) return 1; return n * fact(n - 1); }
+int main() { return is_even(10) + is_odd(7) * 2 + fact(5); }";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 123);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_deep_recursion() -> Result<(), String> {
+        // Each interpreted call is a few Rust frames, so this needs the compiler thread's stack
+        let depth = |n: usize| {
+            let source = format!(
+                "int depth(int n) {{ if (n == 0) return 0; return 1 + depth(n - 1); }} \
+                 int main() {{ return depth({}) - {}; }}",
+                n, n
+            );
+            std::thread::Builder::new()
+                .stack_size(crate::parallel::STACK_SIZE)
+                .spawn(move || run_source(&source, 0).map(|execution| execution.result))
+                .expect("the system can start a thread")
+                .join()
+                .expect("the interpreter doesn't panic")
+        };
+        // depth(n) is n + 1 calls deep, under main's
+        assert_eq!(depth(MAX_CALL_DEPTH - 2)?, 0);
+        assert_eq!(
+            depth(MAX_CALL_DEPTH - 1),
+            Err("Calls nested more than 10000 deep".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsigned() -> Result<(), String> {
+        // -1 converted to unsigned is the largest value there is, 32 bits of ones, which
+        // halves to one that's still positive. Unsigned arithmetic wraps at 32 bits, and
+        // converting back to int gives the negative number again. i converted to unsigned is
+        // u, so i < u doesn't hold.
+        let source = "int main() { unsigned u = -1; int i = -1; unsigned z = 0; z = z - 1; \
+                      unsigned w = i; unsigned big = 65536; unsigned one = 1; int n = 32; \
+                      int back = u; \
+                      return (u > 1) + 2 * (i < 1) + 4 * (u / 2 > 1) + 8 * (i / 2 == 0) \
+                      + 16 * (u >> 31 == 1) + 32 * (i >> 31 == -1) + 64 * (i < u) \
+                      + 128 * (u == 4294967295) + 256 * (z == u) + 512 * (w >> 28 == 15) \
+                      + 1024 * (big * big == 0) + 2048 * (one << n == 0) \
+                      + 4096 * (back == -1) + 8192 * (~u == 0) + 16384 * (-one == u); }";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 32767 - 64);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch() -> Result<(), String> {
+        // dense is a jump table and sparse a chain of comparisons, and both fall through
+        let source = "
+int dense(int x) {
+    int r = 0;
+    switch (x) {
+    case 1: r = 1;
+    case 2: r = r + 2; break;
+    case 3: case 4: return 4;
+    case 6: r = 6; break;
+    default: r = 9;
+    }
+    return r;
+}
+int sparse(int x) {
+    switch (x) { case -100: return 1; case 1000: return 2; }
+    return 3;
+}
+int main() {
+    int total = 0;
+    for (int i = 0; i < 8; i = i + 1) {
+        switch (i) { case 5: continue; }
+        total = total * 2 + dense(i);
+    }
+    return total + sparse(-100) + sparse(1000) * 10 + sparse(0) * 100;
+}";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 1094);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_enumerators() -> Result<(), String> {
+        // Enumerators are constants in initializers and case labels too, and a local can hide one
+        let source = "
+enum E { A = 2, B, C = B * 10 };
+int g = C + A;
+int f(int x) {
+    switch (x) { case A: return 1; case C: return 2; }
+    int B = 100;
+    return B;
+}
+int main() { return g + B + f(2) * 1000 + f(30) * 10000 + f(B) * 100000; }";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 10_021_035);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tail_calls() -> Result<(), String> {
+        // Calls in tail position reuse the frame, so they can go past MAX_CALL_DEPTH
+        let source = "
+int is_odd(int n);
+int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+int count(int n, int total) { if (n == 0) return total; return count(n - 1, total + 1); }
+int main() { return count(100000, 0) - 99990 + is_even(20001); }";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_calls() -> Result<(), String> {
+        // a, b and the partial sums are live across the inner calls
+        let source = "
+int f(int x) { return x * 3 - 1; }
+int h(int x, int y, int z) { return x - y * 2 + z; }
+int main() {
+    int a = 2;
+    int b = 5;
+    return a + f(h(b, f(a), a * b) + f(1)) * b - h(f(f(a)), a, f(b) - b);
+}";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 83);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_chained_assignment() -> Result<(), String> {
+        let source = "int main() { int a; int b; a = b = 3; a += b *= 2; return a * 10 + b; }";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 96);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_main_args() -> Result<(), String> {
+        // One argument, the program's name, which is main here ('m' is 109)
+        let source = "int main(int argc, char **argv) { char *name = *argv; return argc * 10 + (*name == 109); }";
+        assert_eq!(run_source(source, 0)?.result, 11);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory() -> Result<(), String> {
+        let source = "
+int g = 5;
+int set(int *p, int v) { *p = v; return 0; }
+int main() {
+    int x = 1;
+    set(&x, 40);
+    set(&g, *&g + 2);
+    return x + g;
+}";
+        assert_eq!(run_source(source, 1)?.result, 47);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chars() -> Result<(), String> {
+        // Each char keeps just its low byte, as a signed value, globals included, and
+        // writing one leaves the global next to it alone
+        let source = "
+char g = -1;
+char h;
+int n = 3;
+int first(char *s) { return *s; }
+int main() {
+    char c = 127;
+    c = c + 1;
+    char d;
+    char *p = &d;
+    *p = 200;
+    h = 128;
+    return (c == -128) + (d == -56) * 2 + (first(\"h\") == 104) * 4 + (g == -1) * 8
+        + (h == -128) * 16 + (n == 3) * 32;
+}";
+        for level in 0..=2 {
+            assert_eq!(run_source(source, level)?.result, 63);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_output() -> Result<(), String> {
+        let program = parse_program(
+            "str0 = \"hi\\n\"\nfn main() {\nbb0:\n  v1 = 104\n  v2 = call putchar(v1)\n  v3 = addr str0\n  ret v3\n}\n",
+        )?;
+        let execution = run(&program, "main")?;
+        assert_eq!(execution.output, b"h");
+        // Strings start at the bottom of memory
+        assert_eq!(execution.result, DATA_START);
+        Ok(())
+    }
+
+    #[test]
+    fn test_phis() -> Result<(), String> {
+        let program = parse_program(
+            "fn main() {\nbb0:\n  v1 = 0\n  goto bb1\nbb1:\n  v2 = phi [bb0: v1, bb1: v3]\n  v4 = 1\n  v3 = v2 + v4\n  v5 = 5\n  v6 = v3 < v5\n  if v6 goto bb1 else bb2\nbb2:\n  ret v3\n}\n",
+        )?;
+        assert_eq!(run(&program, "main")?.result, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() -> Result<(), String> {
+        let run_text = |text: &str| run(&parse_program(text)?, "main");
+        assert_eq!(
+            run_text("fn main() {\nbb0:\n  v1 = 7\n  v2 = 0\n  v3 = v1 / v2\n  ret v3\n}\n"),
+            Err("`v3 = v1 / v2` divides 7 by 0".to_owned())
+        );
+        assert_eq!(
+            run_text("fn main() {\nbb0:\n  v1 = 0\n  v2 = load v1\n  ret v2\n}\n"),
+            Err("`v2 = load v1` accesses address 0, which is out of bounds".to_owned())
+        );
+        assert_eq!(
+            run_text("fn main() {\nbb0:\n  v1 = call exit()\n  ret v1\n}\n"),
+            Err("Call to exit, which isn't defined in the program".to_owned())
+        );
+        Ok(())
+    }
+}
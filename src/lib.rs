@@ -0,0 +1,635 @@
+use cfg::Program;
+use diagnostic::{Diagnostic, DiagnosticSink};
+use error::CompileError;
+use pass_manager::PassManager;
+use regalloc::Allocator;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/*
+ * The compiler as a library, for programs that want to compile C without running the
+ * `compiler` binary: test harnesses, playgrounds, fuzzers. `compile_to_asm` runs the whole
+ * pipeline on a string, and the stages are public for anyone who wants to stop partway.
+ *
+ * Errors come back as the list of diagnostics that were reported, ending with the one that
+ * stopped compilation, so a caller can render them however it likes.
+ */
+
+pub mod asm;
+pub mod assembler;
+pub mod ast;
+pub mod ast_dump;
+pub mod ast_print;
+pub mod cfg;
+pub mod cfg_parser;
+pub mod codegen;
+pub mod compile_commands;
+pub mod constant;
+pub mod desugar;
+pub mod diagnostic;
+pub mod elf;
+pub mod error;
+pub mod ice;
+pub mod inliner;
+pub mod intern;
+pub mod interp;
+pub mod liveness;
+pub mod llvm;
+pub mod lsp;
+pub mod optimize;
+pub mod parallel;
+pub mod parser;
+pub mod pass_manager;
+pub mod regalloc;
+pub mod riscv;
+pub mod span;
+pub mod ssa;
+pub mod symantic_check;
+pub mod symbol_table;
+pub mod target;
+pub mod tokenizer;
+pub mod triple;
+pub mod type_table;
+pub mod wasm;
+
+pub use cfg::{ControlFlowGraph, Function};
+pub use parser::{parse, parse_with_spans};
+pub use tokenizer::{Token, tokenize, tokenize_with_spans};
+use triple::Arch;
+pub use triple::Target;
+
+pub type Diagnostics = Vec<Diagnostic>;
+
+// Everything about how a source file is compiled, from the warnings to the target. The
+// defaults are what the driver does with no flags, and each setting can be changed in a
+// chain, as in `CompileOptions::default().opt_level(2).warning("-Wall")`.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    pub target: Target,
+    pub passes: Vec<String>, // optimization passes by name, -O1's by default
+    pub allocator: Allocator,
+    pub warnings: Vec<String>, // -W flags, e.g. -Wall or -Werror
+    pub pic: bool,
+    pub freestanding: bool,
+    pub debug_info: bool,
+    pub asm_comments: bool,
+    pub file_name: String, // what -g's line information calls the source
+    pub jobs: usize,       // threads to optimize and generate code for functions on
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            target: Target::default(),
+            passes: CompileOptions::passes_for_level(1),
+            allocator: Allocator::default(),
+            warnings: vec![],
+            pic: false,
+            freestanding: false,
+            debug_info: false,
+            asm_comments: false,
+            file_name: "<stdin>".to_owned(),
+            jobs: parallel::default_jobs(),
+        }
+    }
+}
+
+impl CompileOptions {
+    // The passes -O<level> runs
+    pub fn passes_for_level(level: u32) -> Vec<String> {
+        let passes = PassManager::for_level(level).pass_names();
+        passes.into_iter().map(str::to_owned).collect()
+    }
+
+    // A sink with the -W flags applied, for the checks to report warnings to
+    pub fn diagnostic_sink(&self) -> Result<DiagnosticSink, String> {
+        let mut diagnostics = DiagnosticSink::default();
+        for flag in &self.warnings {
+            diagnostics.apply_flag(flag)?;
+        }
+        Ok(diagnostics)
+    }
+
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn opt_level(mut self, level: u32) -> Self {
+        self.passes = Self::passes_for_level(level);
+        self
+    }
+
+    // Replaces the -O pipeline with these passes, in this order
+    pub fn passes(mut self, passes: &[&str]) -> Self {
+        self.passes = passes.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn allocator(mut self, allocator: Allocator) -> Self {
+        self.allocator = allocator;
+        self
+    }
+
+    // Adds a -W flag, after the ones already given
+    pub fn warning(mut self, flag: &str) -> Self {
+        self.warnings.push(flag.to_owned());
+        self
+    }
+
+    pub fn pic(mut self, pic: bool) -> Self {
+        self.pic = pic;
+        self
+    }
+
+    pub fn freestanding(mut self, freestanding: bool) -> Self {
+        self.freestanding = freestanding;
+        self
+    }
+
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    pub fn asm_comments(mut self, asm_comments: bool) -> Self {
+        self.asm_comments = asm_comments;
+        self
+    }
+
+    pub fn file_name(mut self, file_name: &str) -> Self {
+        self.file_name = file_name.to_owned();
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    // Whether statements keep track of their source line, for -g's line table or the
+    // assembly's comments
+    pub fn line_info(&self) -> bool {
+        self.debug_info || self.asm_comments
+    }
+
+    // What the backends need to know, with `source` for the assembly's comments
+    pub fn codegen_options<'a>(&self, source: &'a str) -> target::CodegenOptions<'a> {
+        target::CodegenOptions {
+            allocator: self.allocator,
+            pic: self.pic,
+            freestanding: self.freestanding,
+            debug_info: self.debug_info,
+            comments: self.asm_comments.then_some(source),
+            jobs: self.jobs,
+        }
+    }
+}
+
+// How long a stage of the pipeline took, and some counts of what it made, for --time-passes.
+// Optimization is one stage, broken down by pass.
+#[derive(Clone, Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    pub time: Duration,
+    pub stats: Vec<(&'static str, usize)>,
+    pub passes: Vec<(&'static str, Duration)>,
+}
+
+impl Stage {
+    fn new(name: &'static str, start: Instant, stats: Vec<(&'static str, usize)>) -> Self {
+        Stage {
+            name,
+            time: start.elapsed(),
+            stats,
+            passes: vec![],
+        }
+    }
+
+    // Reports the finished stage at -v
+    fn logged(self) -> Self {
+        log::info!("{}", self);
+        self
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = |time: Duration| time.as_secs_f64() * 1000.0;
+        write!(f, "{:<24}{:>10.3}ms", self.name, millis(self.time))?;
+        for (name, count) in &self.stats {
+            write!(f, "  {}={}", name, count)?;
+        }
+        for (name, time) in &self.passes {
+            write!(f, "\n  {:<22}{:>10.3}ms", name, millis(*time))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CompiledProgram {
+    pub program: Program,
+    pub diagnostics: Diagnostics, // warnings
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileOutput {
+    pub asm: String,              // WebAssembly text for wasm32
+    pub diagnostics: Diagnostics, // warnings
+    pub stages: Vec<Stage>,
+}
+
+// Checks the source and lowers it to optimized CFGs, returning any warnings alongside
+pub fn compile_to_program(
+    source: &str,
+    options: &CompileOptions,
+) -> Result<CompiledProgram, Diagnostics> {
+    let mut diagnostics = options
+        .diagnostic_sink()
+        .map_err(|e| vec![Diagnostic::error(e, None)])?;
+    let names: Vec<&str> = options.passes.iter().map(String::as_str).collect();
+    let passes = PassManager::from_names(&names).map_err(|e| vec![Diagnostic::error(e, None)])?;
+
+    ice::enter_file(&options.file_name);
+    let mut stages = vec![];
+    let mut program =
+        front_end(source, options, &mut diagnostics, &mut stages).map_err(|error| {
+            let mut reported = diagnostics.diagnostics().to_vec();
+            reported.push(Diagnostic::from(error));
+            reported
+        })?;
+    // -Werror turns warnings into errors without stopping at the first one
+    if diagnostics.has_errors() {
+        return Err(diagnostics.diagnostics().to_vec());
+    }
+    ice::enter_stage("optimize");
+    let start = Instant::now();
+    let times = passes.run_program(&mut program, options.jobs);
+    let mut optimize = Stage::new("optimize", start, cfg_stats(&program));
+    optimize.passes = times;
+    stages.push(optimize.logged());
+    Ok(CompiledProgram {
+        program,
+        diagnostics: diagnostics.diagnostics().to_vec(),
+        stages,
+    })
+}
+
+fn front_end(
+    source: &str,
+    options: &CompileOptions,
+    diagnostics: &mut DiagnosticSink,
+    stages: &mut Vec<Stage>,
+) -> Result<Program, CompileError> {
+    ice::enter_stage("tokenize");
+    let start = Instant::now();
+    let (tokens, spans) = tokenizer::tokenize_with_spans(source)?;
+    stages.push(Stage::new("tokenize", start, vec![("tokens", tokens.len())]).logged());
+
+    ice::enter_stage("parse");
+    let start = Instant::now();
+    let (ast, lines) = parser::parse_with_statement_spans(&tokens, &spans, options, diagnostics)?;
+    let nodes = ast::NodeIdCounter::after(&ast).counter as usize;
+    stages.push(Stage::new("parse", start, vec![("nodes", nodes)]).logged());
+
+    ice::enter_stage("check");
+    let start = Instant::now();
+    let mut ast = desugar::desugar(ast);
+    let checked = symantic_check::check(&mut ast, options, diagnostics)?;
+    stages.push(Stage::new("check", start, vec![]).logged());
+
+    ice::enter_stage("lower");
+    let start = Instant::now();
+    let program = Program::lower(
+        &ast,
+        &checked.types,
+        checked.type_table.enumerators(),
+        &lines,
+        options,
+        diagnostics,
+    )?;
+    stages.push(Stage::new("lower", start, cfg_stats(&program)).logged());
+    Ok(program)
+}
+
+fn cfg_stats(program: &Program) -> Vec<(&'static str, usize)> {
+    let cfgs = program.functions.values().map(|f| &f.cfg);
+    let blocks = cfgs.clone().map(|cfg| cfg.len()).sum();
+    let statements = cfgs.flat_map(|cfg| cfg.values()).map(|b| b.len()).sum();
+    vec![("blocks", blocks), ("statements", statements)]
+}
+
+// Generates the target's assembly for a program from compile_to_program, one line each
+pub fn program_to_asm(
+    program: &Program,
+    source: &str,
+    options: &CompileOptions,
+) -> Result<Vec<String>, CompileError> {
+    let codegen_options = options.codegen_options(source);
+    ice::enter_stage("codegen");
+    let target = options.target;
+    let mut asm = match target.arch {
+        Arch::X86_64 if target.is_microsoft_abi() => {
+            codegen::program_to_windows_asm(program, &codegen_options)?
+        }
+        Arch::X86_64 => codegen::program_to_asm(program, &codegen_options)?,
+        Arch::Riscv64 => riscv::program_to_asm(program, &codegen_options)?,
+        Arch::Wasm32 => return wasm::program_to_wat(program, &codegen_options),
+    };
+    // The .loc directives in the code refer to the source as file 1
+    if options.debug_info {
+        asm.insert(0, format!(".file 1 {:?}", options.file_name));
+    }
+    Ok(asm)
+}
+
+// Times program_to_asm, counting the vars that don't get a register
+pub fn program_to_asm_timed(
+    program: &Program,
+    source: &str,
+    options: &CompileOptions,
+) -> Result<(Vec<String>, Stage), CompileError> {
+    let start = Instant::now();
+    let asm = program_to_asm(program, source, options)?;
+    let mut stage = Stage::new("codegen", start, vec![("lines", asm.len())]);
+    let allocator = options.allocator;
+    let spills = match options.target.arch {
+        Arch::X86_64 if options.target.is_microsoft_abi() => {
+            target::count_spills::<codegen::X86_64Windows>(program, allocator)
+        }
+        Arch::X86_64 => target::count_spills::<codegen::X86_64>(program, allocator),
+        Arch::Riscv64 => target::count_spills::<riscv::RiscV64>(program, allocator),
+        // Every var is a wasm local
+        Arch::Wasm32 => 0,
+    };
+    stage.stats.push(("spills", spills));
+    Ok((asm, stage.logged()))
+}
+
+pub fn compile_to_asm(
+    source: &str,
+    options: &CompileOptions,
+) -> Result<CompileOutput, Diagnostics> {
+    let compiled = compile_to_program(source, options)?;
+    match program_to_asm_timed(&compiled.program, source, options) {
+        Ok((asm, stage)) => Ok(CompileOutput {
+            asm: asm.join("\n") + "\n",
+            diagnostics: compiled.diagnostics,
+            stages: compiled.stages.into_iter().chain([stage]).collect(),
+        }),
+        Err(error) => {
+            let mut reported = compiled.diagnostics;
+            reported.push(Diagnostic::from(error));
+            Err(reported)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_to_asm() -> Result<(), String> {
+        let source = "int main() {\n  int unused;\n  return 3 + 4;\n}\n";
+        let options = CompileOptions::default().warning("-Wall");
+        let output = compile_to_asm(source, &options).map_err(|d| d[0].to_string())?;
+        assert!(
+            output
+                .asm
+                .starts_with(".global main\n.type main, @function\nmain:\n")
+        );
+        assert!(output.asm.contains("$7"));
+        assert_eq!(
+            output.diagnostics[0].to_string(),
+            "2:7: warning: Unused variable unused [-Wunused-variable]"
+        );
+
+        let options = CompileOptions::default().target(Target::WASM32);
+        let output = compile_to_asm(source, &options).map_err(|d| d[0].to_string())?;
+        assert!(output.asm.starts_with("(module"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = CompileOptions::default()
+            .target(Target::RISCV64_LINUX)
+            .opt_level(1)
+            .warning("-Wall")
+            .warning("-Wno-unused-variable")
+            .pic(true)
+            .file_name("main.c")
+            .jobs(4);
+        assert_eq!(options.target, Target::RISCV64_LINUX);
+        assert_eq!(options.passes, CompileOptions::passes_for_level(1));
+        assert!(options.pic && !options.freestanding);
+        assert_eq!(options.file_name, "main.c");
+        assert_eq!(options.jobs, 4);
+        let codegen = options.codegen_options("");
+        assert!(codegen.pic && codegen.comments.is_none() && codegen.jobs == 4);
+        assert!(!options.line_info());
+        let commented = options.clone().asm_comments(true);
+        assert!(commented.line_info() && commented.codegen_options("").comments.is_some());
+        // Later flags win, as they do on the command line
+        let source = "int main() {\n  int unused;\n  return 0;\n}\n";
+        let output = compile_to_asm(source, &options).unwrap();
+        assert!(output.diagnostics.is_empty());
+        let options = options.passes(&["unroll"]);
+        assert_eq!(options.passes, vec!["unroll".to_owned()]);
+
+        let bad = CompileOptions::default().warning("-Wbogus");
+        assert!(bad.diagnostic_sink().is_err());
+    }
+
+    #[test]
+    fn test_jump_tables() -> Result<(), String> {
+        let source = "int f(int x) { switch (x) { case 0: return 5; case 1: return 6; \
+                      case 2: return 7; case 3: return 8; } return 0; } \
+                      int main() { return f(2); }";
+        let asm = |target| {
+            let options = CompileOptions {
+                target,
+                ..Default::default()
+            };
+            compile_to_asm(source, &options)
+                .map(|output| output.asm)
+                .map_err(|d| d[0].to_string())
+        };
+        let x86 = asm(Target::X86_64_LINUX)?;
+        assert!(x86.contains("\njae .Lf_5\nlea .Lf_0_table(%rip), %r11\n"));
+        assert!(x86.contains(".section .rodata\n.align 4\n.Lf_0_table:\n"));
+        assert!(x86.contains(".long .Lf_4-.Lf_0_table\n.text\n"));
+        assert!(asm(Target::X86_64_WINDOWS)?.contains(".section .rdata,\"dr\"\n"));
+        assert!(asm(Target::RISCV64_LINUX)?.contains(".word .Lf_4-.Lf_0_table\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stages() -> Result<(), String> {
+        let source = "int main() { int x = 1; if (x) { return 2; } return 3; }";
+        let options = CompileOptions::default().opt_level(2);
+        let output = compile_to_asm(source, &options).map_err(|d| d[0].to_string())?;
+        let names: Vec<&str> = output.stages.iter().map(|s| s.name).collect();
+        assert_eq!(
+            names,
+            vec!["tokenize", "parse", "check", "lower", "optimize", "codegen"]
+        );
+        assert_eq!(output.stages[0].stats, vec![("tokens", 23)]);
+        // Propagating x folds the branch, leaving a single block
+        assert_eq!(
+            output.stages[4].stats,
+            vec![("blocks", 1), ("statements", 2)]
+        );
+        let passes: Vec<&str> = output.stages[4].passes.iter().map(|p| p.0).collect();
+        assert_eq!(
+            passes,
+            vec![
+                "inline",
+                "mem2reg",
+                "fold-constants",
+                "propagate-constants",
+                "cse",
+                "simplify-cfg",
+                "dce"
+            ]
+        );
+        assert_eq!(output.stages[5].stats[1], ("spills", 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jobs() -> Result<(), String> {
+        // However many threads the functions are spread over, they come out in the same order
+        let source = (0..20)
+            .map(|n| format!("int f{}(int x) {{ return x * {} + 1; }}\n", n, n))
+            .chain(["int main() { return f3(2) + f17(1); }\n".to_owned()])
+            .collect::<String>();
+        let compile = |target, jobs| -> Result<String, String> {
+            let options = CompileOptions {
+                target,
+                jobs,
+                ..Default::default()
+            };
+            let output = compile_to_asm(&source, &options).map_err(|d| d[0].to_string())?;
+            Ok(output.asm)
+        };
+        for target in [Target::X86_64_LINUX, Target::WASM32] {
+            let sequential = compile(target, 1)?;
+            for _ in 0..4 {
+                assert_eq!(compile(target, 8)?, sequential);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let errors = |source: &str, options: &CompileOptions| -> Vec<String> {
+            match compile_to_asm(source, options) {
+                Ok(_) => vec![],
+                Err(diagnostics) => diagnostics.iter().map(|d| d.to_string()).collect(),
+            }
+        };
+        assert_eq!(
+            errors("int main() {\n  return x;\n}", &CompileOptions::default()),
+            vec!["2:10: error: Undefined variable x in scope 1"]
+        );
+        // Every semantic error in the file is reported, in order
+        let source = "int main() {\n  int x;\n  int x;\n  y = 1;\n  return f(z);\n}";
+        assert_eq!(
+            errors(source, &CompileOptions::default()),
+            vec![
+                "3:7: error: Duplicate declaration of variable x",
+                "4:3: error: Undefined variable y in scope 1",
+                "5:10: error: Undefined function f",
+                "5:12: error: Undefined variable z in scope 1",
+            ]
+        );
+        // An enumerator is a value, not a variable
+        let source = "enum E { A, B };\nint main() {\n  B = 1;\n  int *p = &A;\n  return B;\n}";
+        assert_eq!(
+            errors(source, &CompileOptions::default()),
+            vec![
+                "3:3: error: Left side of = must be an lvalue, but got (var B)",
+                "4:3: error: Type error: invalid operand to unary & (int)",
+            ]
+        );
+        // Whichever check finds them
+        let source = "int f() {\n  int u;\n  return u;\n}\nint main() {\n  int a = \"s\";\n  \
+                      char *p = 3;\n  return y;\n}";
+        assert_eq!(
+            errors(source, &CompileOptions::default()),
+            vec![
+                "3:10: error: Variable u is used before being initialized",
+                "6:3: error: Type error: cannot initialize int a with a value of type char*",
+                "7:3: error: Type error: cannot initialize char* p with a value of type int",
+                "8:10: error: Undefined variable y in scope 2",
+            ]
+        );
+        let werror = CompileOptions {
+            warnings: vec!["-Werror".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            errors("int main() { return 3000000000; }", &werror),
+            vec![
+                "1:21: error: Integer constant 3000000000 is too large for int [-Werror=overflow]"
+            ]
+        );
+        // Only an int is too small for it
+        assert_eq!(
+            errors(
+                "int main() { unsigned int u = 4294967295; return u == 4294967295; }",
+                &werror
+            ),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            errors(
+                "int g = 65536 * 32768;\nint main() {\n  return g * 2;\n}",
+                &werror
+            ),
+            vec![
+                "1:9: error: Integer overflow: 65536 * 32768 is 2147483648, which doesn't fit \
+                 in int [-Werror=overflow]"
+            ]
+        );
+        assert_eq!(
+            errors(
+                "int main() {\n  int x = 3;\n  x = x / 0 + (1 << 70);\n  return x;\n}",
+                &werror
+            ),
+            vec![
+                "3:7: error: Division by zero [-Werror=div-by-zero]",
+                "3:16: error: Shift count 70 is not less than the width of the type (32 bits) \
+                 [-Werror=shift-count-overflow]",
+            ]
+        );
+        let conversion = CompileOptions {
+            warnings: vec!["-Wconversion".to_owned(), "-Werror".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            errors(
+                "int main() {\n  int x = 3;\n  char c = 300;\n  c = x + 1;\n  return c;\n}",
+                &conversion
+            ),
+            vec![
+                "3:12: error: Implicit conversion from int to char changes value from 300 to 44 \
+                 [-Werror=overflow]",
+                "4:7: error: Conversion from int to char may change value [-Werror=conversion]",
+            ]
+        );
+        let unknown = CompileOptions {
+            passes: vec!["unroll".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            errors("int main() { return 0; }", &unknown),
+            vec!["error: Unknown pass unroll"]
+        );
+    }
+}
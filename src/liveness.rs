@@ -0,0 +1,245 @@
+use crate::cfg::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+/*
+ * Liveness analysis: which vars hold a value that may still be read.
+ *
+ * This is the usual backward dataflow problem. A var is live into a block if the block
+ * reads it before writing it, or if it's live out of the block and the block never writes
+ * it. It's live out of a block if it's live into any successor.
+ *
+ * Phis read each source at the end of the predecessor it comes from, so a source is live
+ * out of that predecessor only, and never live into the phi's own block. The phi's dest
+ * is written at the top of its block.
+ */
+
+pub type VarSet = BTreeSet<VReg>;
+
+#[derive(Debug, PartialEq)]
+pub struct Liveness {
+    pub live_in: BTreeMap<ControlBlockId, VarSet>,
+    pub live_out: BTreeMap<ControlBlockId, VarSet>,
+}
+
+// The first and last statements a var is live across, numbered in program order
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LiveRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+// The vars a statement reads within its own block, which leaves out phi sources
+fn local_uses(statement: &Statement) -> Vec<&VReg> {
+    match statement {
+        Statement::Phi { .. } => vec![],
+        _ => statement.used_vars(),
+    }
+}
+
+// The vars `block`'s phis read when control comes from `predecessor`
+fn phi_uses(
+    cfg: &ControlFlowGraph,
+    block: ControlBlockId,
+    predecessor: ControlBlockId,
+) -> Vec<&VReg> {
+    cfg[&block]
+        .iter()
+        .filter_map(|s| match s {
+            Statement::Phi { sources, .. } => Some(sources),
+            _ => None,
+        })
+        .flatten()
+        .filter(|(from, _)| *from == predecessor)
+        .map(|(_, var)| var)
+        .collect()
+}
+
+// The vars live just before `statements`, given the ones live just after them
+fn live_before(statements: &[Statement], mut live: VarSet) -> VarSet {
+    for statement in statements.iter().rev() {
+        if let Some(var) = statement.defined_var() {
+            live.remove(var);
+        }
+        live.extend(local_uses(statement).into_iter().cloned());
+    }
+    live
+}
+
+impl Liveness {
+    pub fn analyze(cfg: &ControlFlowGraph) -> Self {
+        let blocks: Vec<ControlBlockId> = cfg.keys().copied().collect();
+        let mut liveness = Liveness {
+            live_in: blocks.iter().map(|b| (*b, VarSet::new())).collect(),
+            live_out: blocks.iter().map(|b| (*b, VarSet::new())).collect(),
+        };
+
+        // Visiting blocks in reverse tends to see successors first, so this settles quickly
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in blocks.iter().rev() {
+                let mut live_out = VarSet::new();
+                for successor in cfg.successors(*block) {
+                    live_out.extend(liveness.live_in[&successor].iter().cloned());
+                    live_out.extend(phi_uses(cfg, successor, *block).into_iter().cloned());
+                }
+                let live_in = live_before(&cfg[block], live_out.clone());
+                if live_in != liveness.live_in[block] || live_out != liveness.live_out[block] {
+                    liveness.live_in.insert(*block, live_in);
+                    liveness.live_out.insert(*block, live_out);
+                    changed = true;
+                }
+            }
+        }
+        liveness
+    }
+
+    // The vars live just after each statement of `block`, in the block's order
+    pub fn live_after(&self, cfg: &ControlFlowGraph, block: ControlBlockId) -> Vec<VarSet> {
+        let statements = &cfg[&block];
+        let mut live = self.live_out[&block].clone();
+        let mut after = vec![VarSet::new(); statements.len()];
+        for (i, statement) in statements.iter().enumerate().rev() {
+            after[i] = live.clone();
+            live = live_before(std::slice::from_ref(statement), live);
+        }
+        after
+    }
+
+    // Where the statements that define a var that's never read are, as (block, index)
+    pub fn dead_definitions(&self, cfg: &ControlFlowGraph) -> BTreeSet<(ControlBlockId, usize)> {
+        let mut dead = BTreeSet::new();
+        for (block, statements) in cfg.iter() {
+            let live_after = self.live_after(cfg, *block);
+            for (i, (statement, live)) in statements.iter().zip(&live_after).enumerate() {
+                if statement
+                    .defined_var()
+                    .is_some_and(|var| !live.contains(var))
+                {
+                    dead.insert((*block, i));
+                }
+            }
+        }
+        dead
+    }
+
+    /*
+     * Numbers every statement, going through the blocks in ascending order like codegen
+     * does, and gives each var the span from its first definition, use, or live point to
+     * its last. A var that's live around a loop covers the whole loop, since it's live
+     * after every statement in it.
+     */
+    pub fn live_ranges(&self, cfg: &ControlFlowGraph) -> BTreeMap<VReg, LiveRange> {
+        let mut ranges: BTreeMap<VReg, LiveRange> = BTreeMap::new();
+        let mut extend = |var: &VReg, point: usize| {
+            let range = ranges.entry(*var).or_insert(LiveRange {
+                start: point,
+                end: point,
+            });
+            range.start = range.start.min(point);
+            range.end = range.end.max(point);
+        };
+        let mut point = 0;
+        for (block, statements) in cfg.iter() {
+            for (statement, live) in statements.iter().zip(self.live_after(cfg, *block)) {
+                let vars = statement.used_vars().into_iter();
+                for var in vars.chain(statement.defined_var()).chain(&live) {
+                    extend(var, point);
+                }
+                point += 1;
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_cfg;
+    use crate::intern::Symbol;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+
+    fn lower_source(source: &str) -> Result<ControlFlowGraph, String> {
+        let mut program = Program::from(&parse(&tokenize(source)?)?)?;
+        let main = program
+            .functions
+            .remove(&Symbol::intern("main"))
+            .ok_or("Missing main")?;
+        Ok(main.cfg)
+    }
+
+    fn vars(numbers: &[u32]) -> VarSet {
+        numbers.iter().map(|n| VReg(*n)).collect()
+    }
+
+    #[test]
+    fn test_liveness_if_else() -> Result<(), String> {
+        // 0: v1 = 1; v2 = 2; if v1 -> 1, 2
+        // 1: v1 = v2; goto 3    2: goto 3    3: return v1
+        let cfg = lower_source(
+            "int main() { int x = 1; int y = 2; if (x) { x = y; } else { } return x; }",
+        )?;
+        let liveness = Liveness::analyze(&cfg);
+        assert_eq!(liveness.live_in[&0], vars(&[]));
+        assert_eq!(liveness.live_out[&0], vars(&[1, 2]));
+        assert_eq!(liveness.live_in[&1], vars(&[2]));
+        assert_eq!(liveness.live_in[&2], vars(&[1]));
+        assert_eq!(liveness.live_in[&3], vars(&[1]));
+        assert_eq!(liveness.live_out[&3], vars(&[]));
+
+        assert_eq!(
+            liveness.live_after(&cfg, 0),
+            vec![vars(&[1]), vars(&[1, 2]), vars(&[1, 2])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_ranges_loop() -> Result<(), String> {
+        // 0: v1 = 0; goto 1      1: v2 = 3; v3 = v1 == v2; if v3 -> 2, 3
+        // 2: v4 = 1; v5 = v1 + v4; v1 = v5; goto 1      3: return v1
+        let cfg =
+            lower_source("int main() { int x = 0; while (x == 3) { x = x + 1; } return x; }")?;
+        let liveness = Liveness::analyze(&cfg);
+        assert_eq!(liveness.live_in[&1], vars(&[1]));
+        assert_eq!(liveness.live_out[&2], vars(&[1]));
+
+        let ranges = liveness.live_ranges(&cfg);
+        // x is live from its initialization, around the whole loop, to the return
+        assert_eq!(ranges[&VReg(1)], LiveRange { start: 0, end: 9 });
+        assert_eq!(ranges[&VReg(2)], LiveRange { start: 2, end: 3 });
+        assert_eq!(ranges[&VReg(5)], LiveRange { start: 6, end: 7 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_definitions() -> Result<(), String> {
+        // 0: v1 = 0; v2 = 2; goto 1      1: v3 = 3; v4 = v1 == v3; if v4 -> 2, 3
+        // 2: v5 = 1; v6 = v1 + v5; v1 = v6; goto 1      3: return v1
+        let cfg = lower_source(
+            "int main() { int x = 0; int y = 2; while (x == 3) { x = x + 1; } return x; }",
+        )?;
+        // Only y's value is never read, since x's is read by the loop's condition
+        let dead = Liveness::analyze(&cfg).dead_definitions(&cfg);
+        assert_eq!(dead, BTreeSet::from([(0, 1)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_liveness_phis() -> Result<(), String> {
+        let cfg = parse_cfg(
+            "bb0: v1 = 1; if v1 goto bb1 else bb2\n\
+             bb1: v2 = 2; goto bb2\n\
+             bb2: v3 = phi [bb0: v1, bb1: v2]; ret v3",
+        )?;
+        let liveness = Liveness::analyze(&cfg);
+        // Each source is only live out of the block it comes from
+        assert_eq!(liveness.live_out[&0], vars(&[1]));
+        assert_eq!(liveness.live_out[&1], vars(&[2]));
+        assert_eq!(liveness.live_in[&1], vars(&[]));
+        assert_eq!(liveness.live_in[&2], vars(&[]));
+        Ok(())
+    }
+}
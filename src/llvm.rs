@@ -0,0 +1,520 @@
+use crate::cfg::*;
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::target::check_entry_point;
+use std::collections::{BTreeMap, BTreeSet};
+
+/*
+    Textual LLVM IR, for --emit=llvm-ir. The output can be fed to llc, lli, or opt. It uses
+    opaque pointers, which LLVM 15 and later assume and older versions need
+    -opaque-pointers for.
+
+    CFG vars can be assigned more than once, so rather than building SSA each var gets an
+    alloca (%v1.addr for v1) in an extra entry block, and every statement loads its operands
+    and stores its result. LLVM's mem2reg turns that back into registers. Pointers are i64
+    in the CFG, so addresses go through inttoptr and ptrtoint. Stack slots from Alloca are
+    also allocated up front, so a slot in a loop doesn't grow the stack on every iteration.
+    Globals are 8 bytes each, as i64 or, for one holding a string's address, ptr.
+*/
+
+fn slot(var: &VReg) -> String {
+    format!("%{}.addr", var)
+}
+
+// Names the temporaries for one function's loads and results
+#[derive(Default)]
+struct Temps {
+    count: usize,
+}
+
+impl Temps {
+    fn next(&mut self) -> String {
+        self.count += 1;
+        format!("%t{}", self.count)
+    }
+}
+
+// A literal's bytes in the form an LLVM string constant wants them
+fn string_constant(value: &str) -> (String, usize) {
+    let mut bytes = literal_bytes(value);
+    bytes.push(0);
+    let text = bytes
+        .iter()
+        .map(|b| match b {
+            b'"' | b'\\' => format!("\\{:02X}", b),
+            b' '..=b'~' => (*b as char).to_string(),
+            _ => format!("\\{:02X}", b),
+        })
+        .collect();
+    (text, bytes.len())
+}
+
+// Loads var into a new temporary and returns its name
+fn load(ir: &mut Vec<String>, temps: &mut Temps, var: &VReg) -> String {
+    let temp = temps.next();
+    ir.push(format!("{} = load i64, ptr {}", temp, slot(var)));
+    temp
+}
+
+fn store(ir: &mut Vec<String>, value: &str, var: &VReg) {
+    ir.push(format!("store i64 {}, ptr {}", value, slot(var)));
+}
+
+// Turns an i1 into the 0 or 1 the CFG expects
+fn widen(ir: &mut Vec<String>, temps: &mut Temps, flag: String) -> String {
+    let temp = temps.next();
+    ir.push(format!("{} = zext i1 {} to i64", temp, flag));
+    temp
+}
+
+// Sign-extends an i8 temp to a new i64 one
+fn sext_byte(ir: &mut Vec<String>, temps: &mut Temps, byte: String) -> String {
+    let value = temps.next();
+    ir.push(format!("{} = sext i8 {} to i64", value, byte));
+    value
+}
+
+fn statement_to_ir(statement: &Statement, temps: &mut Temps) -> Result<Vec<String>, CompileError> {
+    let mut ir = vec![];
+    match statement {
+        Statement::Assign { var, value } => store(&mut ir, &value.to_string(), var),
+        Statement::Copy { dest, src } => {
+            let value = load(&mut ir, temps, src);
+            store(&mut ir, &value, dest);
+        }
+        Statement::Operation { dest, op, lhs, rhs } => {
+            let lhs = load(&mut ir, temps, lhs);
+            let mut rhs = load(&mut ir, temps, rhs);
+            // A count of 64 or more is poison in LLVM, so it's masked the way the hardware
+            // masks it
+            if matches!(op, BinOp::Shl | BinOp::Shr | BinOp::UShr) {
+                let count = temps.next();
+                ir.push(format!("{} = and i64 {}, 63", count, rhs));
+                rhs = count;
+            }
+            let instruction = match op {
+                BinOp::Add => "add",
+                BinOp::Sub => "sub",
+                BinOp::Mul => "mul",
+                BinOp::Div => "sdiv",
+                BinOp::Shl => "shl",
+                BinOp::Shr => "ashr",
+                BinOp::And => "and",
+                BinOp::Or => "or",
+                BinOp::Xor => "xor",
+                BinOp::Eq => "icmp eq",
+                BinOp::Ne => "icmp ne",
+                BinOp::Lt => "icmp slt",
+                BinOp::Le => "icmp sle",
+                BinOp::Gt => "icmp sgt",
+                BinOp::Ge => "icmp sge",
+                BinOp::UDiv => "udiv",
+                BinOp::UShr => "lshr",
+                BinOp::ULt => "icmp ult",
+                BinOp::ULe => "icmp ule",
+                BinOp::UGt => "icmp ugt",
+                BinOp::UGe => "icmp uge",
+            };
+            let mut result = temps.next();
+            ir.push(format!("{} = {} i64 {}, {}", result, instruction, lhs, rhs));
+            if instruction.starts_with("icmp") {
+                result = widen(&mut ir, temps, result);
+            }
+            store(&mut ir, &result, dest);
+        }
+        Statement::UnaryOperation { dest, op, operand } => {
+            let operand = load(&mut ir, temps, operand);
+            let mut result = temps.next();
+            match op {
+                UnaryOp::Neg => ir.push(format!("{} = sub i64 0, {}", result, operand)),
+                UnaryOp::BitNot => ir.push(format!("{} = xor i64 {}, -1", result, operand)),
+                UnaryOp::Sext8 => {
+                    ir.push(format!("{} = trunc i64 {} to i8", result, operand));
+                    result = sext_byte(&mut ir, temps, result);
+                }
+                UnaryOp::Sext32 | UnaryOp::Zext32 => {
+                    let extend = if *op == UnaryOp::Sext32 {
+                        "sext"
+                    } else {
+                        "zext"
+                    };
+                    ir.push(format!("{} = trunc i64 {} to i32", result, operand));
+                    let value = temps.next();
+                    ir.push(format!("{} = {} i32 {} to i64", value, extend, result));
+                    result = value;
+                }
+                UnaryOp::Not => {
+                    ir.push(format!("{} = icmp eq i64 {}, 0", result, operand));
+                    result = widen(&mut ir, temps, result);
+                }
+            }
+            store(&mut ir, &result, dest);
+        }
+        // LLVM decides for itself whether a call marked tail can really be one
+        Statement::Call {
+            dest,
+            func,
+            args,
+            tail,
+        } => {
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| format!("i64 {}", load(&mut ir, temps, arg)))
+                .collect();
+            let result = temps.next();
+            let call = if *tail { "tail call" } else { "call" };
+            ir.push(format!(
+                "{} = {} i64 @{}({})",
+                result,
+                call,
+                func,
+                args.join(", ")
+            ));
+            store(&mut ir, &result, dest);
+        }
+        Statement::Alloca { dest, .. } => {
+            let address = temps.next();
+            ir.push(format!("{} = ptrtoint ptr %{}.slot to i64", address, dest));
+            store(&mut ir, &address, dest);
+        }
+        Statement::Load { dest, addr, width } => {
+            let addr = load(&mut ir, temps, addr);
+            let pointer = temps.next();
+            ir.push(format!("{} = inttoptr i64 {} to ptr", pointer, addr));
+            let value = temps.next();
+            let value = match width {
+                Width::I8 => {
+                    ir.push(format!("{} = load i8, ptr {}", value, pointer));
+                    sext_byte(&mut ir, temps, value)
+                }
+                Width::I64 => {
+                    ir.push(format!("{} = load i64, ptr {}", value, pointer));
+                    value
+                }
+            };
+            store(&mut ir, &value, dest);
+        }
+        Statement::Store { addr, src, width } => {
+            let addr = load(&mut ir, temps, addr);
+            let src = load(&mut ir, temps, src);
+            let pointer = temps.next();
+            ir.push(format!("{} = inttoptr i64 {} to ptr", pointer, addr));
+            match width {
+                Width::I8 => {
+                    let byte = temps.next();
+                    ir.push(format!("{} = trunc i64 {} to i8", byte, src));
+                    ir.push(format!("store i8 {}, ptr {}", byte, pointer));
+                }
+                Width::I64 => ir.push(format!("store i64 {}, ptr {}", src, pointer)),
+            }
+        }
+        Statement::LoadAddress { dest, label } => {
+            let address = temps.next();
+            ir.push(format!("{} = ptrtoint ptr @{} to i64", address, label));
+            store(&mut ir, &address, dest);
+        }
+        Statement::If {
+            var,
+            goto_true,
+            goto_false,
+        } => {
+            let value = load(&mut ir, temps, var);
+            let flag = temps.next();
+            ir.push(format!("{} = icmp ne i64 {}, 0", flag, value));
+            ir.push(format!(
+                "br i1 {}, label %{}, label %{}",
+                flag,
+                block_name(*goto_true),
+                block_name(*goto_false)
+            ));
+        }
+        Statement::Goto(block) => ir.push(format!("br label %{}", block_name(*block))),
+        // A negative value matches no case, so it goes to the default like any other
+        // that's past the end
+        Statement::Switch {
+            var,
+            targets,
+            default,
+        } => {
+            let value = load(&mut ir, temps, var);
+            let cases: Vec<String> = targets
+                .iter()
+                .enumerate()
+                .map(|(i, target)| format!("i64 {}, label %{}", i, block_name(*target)))
+                .collect();
+            ir.push(format!(
+                "switch i64 {}, label %{} [ {} ]",
+                value,
+                block_name(*default),
+                cases.join(" ")
+            ));
+        }
+        // Every function is defined to return an i64, so one with nothing to return gives 0
+        Statement::Return(Some(var)) => {
+            let value = load(&mut ir, temps, var);
+            ir.push(format!("ret i64 {}", value));
+        }
+        Statement::Return(None) => ir.push("ret i64 0".to_owned()),
+        Statement::Phi { .. } => {
+            return Err(CompileError::CodegenError(
+                "Phi nodes must be eliminated before codegen".to_owned(),
+            ));
+        }
+        // Debug metadata isn't emitted
+        Statement::Line(_) => {}
+    }
+    Ok(ir)
+}
+
+fn function_to_ir(name: &str, function: &Function) -> Result<Vec<String>, CompileError> {
+    let cfg = &function.cfg;
+    // main is called the C way, with an int argc and a pointer argv, which are widened to
+    // i64s on entry
+    let param_types = match name {
+        "main" => ["i32", "ptr"].as_slice(),
+        _ => &[],
+    };
+    let param_type = |i: usize| param_types.get(i).copied().unwrap_or("i64");
+    let params: Vec<String> = function
+        .params
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("{} %{}", param_type(i), p))
+        .collect();
+    let mut ir = vec![format!("define i64 @{}({}) {{", name, params.join(", "))];
+
+    // LLVM's entry block can't be branched to, so the allocas get a block of their own
+    ir.push("entry:".to_owned());
+    let vars: BTreeSet<&VReg> = cfg
+        .values()
+        .flatten()
+        .flat_map(|s| s.used_vars().into_iter().chain(s.defined_var()))
+        .chain(&function.params)
+        .collect();
+    for var in &vars {
+        ir.push(format!("  {} = alloca i64", slot(var)));
+    }
+    for statement in cfg.values().flatten() {
+        if let Statement::Alloca { dest, size } = statement {
+            ir.push(format!(
+                "  %{}.slot = alloca i8, i64 {}, align 8",
+                dest, size
+            ));
+        }
+    }
+    for (i, param) in function.params.iter().enumerate() {
+        let value = match param_type(i) {
+            "i64" => format!("%{}", param),
+            param_type => {
+                let conversion = if param_type == "i32" {
+                    "sext"
+                } else {
+                    "ptrtoint"
+                };
+                let wide = format!("%{}.i64", param);
+                ir.push(format!(
+                    "  {} = {} {} %{} to i64",
+                    wide, conversion, param_type, param
+                ));
+                wide
+            }
+        };
+        ir.push(format!("  store i64 {}, ptr {}", value, slot(param)));
+    }
+    ir.push(format!("  br label %{}", block_name(0)));
+
+    let mut temps = Temps::default();
+    for (id, statements) in cfg.iter() {
+        ir.push(format!("{}:", block_name(*id)));
+        for statement in statements {
+            for line in statement_to_ir(statement, &mut temps)? {
+                ir.push(format!("  {}", line));
+            }
+        }
+    }
+    ir.push("}".to_owned());
+    Ok(ir)
+}
+
+// Functions the program calls without defining, with the number of arguments each takes
+fn declarations(program: &Program) -> BTreeMap<Symbol, usize> {
+    let mut declarations = BTreeMap::new();
+    for function in program.functions.values() {
+        for statement in function.cfg.values().flatten() {
+            if let Statement::Call { func, args, .. } = statement
+                && !program.functions.contains_key(func)
+            {
+                declarations.insert(*func, args.len());
+            }
+        }
+    }
+    declarations
+}
+
+pub fn program_to_ir(program: &Program) -> Result<Vec<String>, CompileError> {
+    check_entry_point(program)?;
+    let mut ir = vec![];
+    for (label, value) in &program.strings {
+        let (text, len) = string_constant(value);
+        ir.push(format!(
+            "@{} = private unnamed_addr constant [{} x i8] c\"{}\"",
+            label, len, text
+        ));
+    }
+    for (name, value) in &program.globals {
+        let value = match value {
+            Initializer::Zero => "i64 0".to_owned(),
+            Initializer::Int(value) => format!("i64 {}", value),
+            Initializer::String(label) => format!("ptr @{}", label),
+        };
+        ir.push(format!("@{} = global {}, align 8", name, value));
+    }
+    for (func, arg_count) in declarations(program) {
+        ir.push(format!(
+            "declare i64 @{}({})",
+            func,
+            vec!["i64"; arg_count].join(", ")
+        ));
+    }
+    for (name, function) in &program.functions {
+        if !ir.is_empty() {
+            ir.push(String::new());
+        }
+        ir.extend(function_to_ir(name, function)?);
+    }
+    Ok(ir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_program;
+
+    #[test]
+    fn llvm_function() -> Result<(), String> {
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 2; v2 = call twice(v1); v3 = v2 < v1; if v3 goto bb1 else bb2
+             bb1: ret v1
+             bb2: ret v2
+             }
+             fn twice(v1) {
+             bb0: v2 = v1 + v1; ret v2
+             }",
+        )?;
+        let ir = program_to_ir(&program)?;
+
+        let expected = vec![
+            "define i64 @main() {",
+            "entry:",
+            "  %v1.addr = alloca i64",
+            "  %v2.addr = alloca i64",
+            "  %v3.addr = alloca i64",
+            "  br label %bb0",
+            "bb0:",
+            "  store i64 2, ptr %v1.addr",
+            "  %t1 = load i64, ptr %v1.addr",
+            "  %t2 = call i64 @twice(i64 %t1)",
+            "  store i64 %t2, ptr %v2.addr",
+            "  %t3 = load i64, ptr %v2.addr",
+            "  %t4 = load i64, ptr %v1.addr",
+            "  %t5 = icmp slt i64 %t3, %t4",
+            "  %t6 = zext i1 %t5 to i64",
+            "  store i64 %t6, ptr %v3.addr",
+            "  %t7 = load i64, ptr %v3.addr",
+            "  %t8 = icmp ne i64 %t7, 0",
+            "  br i1 %t8, label %bb1, label %bb2",
+            "bb1:",
+            "  %t9 = load i64, ptr %v1.addr",
+            "  ret i64 %t9",
+            "bb2:",
+            "  %t10 = load i64, ptr %v2.addr",
+            "  ret i64 %t10",
+            "}",
+            "",
+            "define i64 @twice(i64 %v1) {",
+            "entry:",
+            "  %v1.addr = alloca i64",
+            "  %v2.addr = alloca i64",
+            "  store i64 %v1, ptr %v1.addr",
+            "  br label %bb0",
+            "bb0:",
+            "  %t1 = load i64, ptr %v1.addr",
+            "  %t2 = load i64, ptr %v1.addr",
+            "  %t3 = add i64 %t1, %t2",
+            "  store i64 %t3, ptr %v2.addr",
+            "  %t4 = load i64, ptr %v2.addr",
+            "  ret i64 %t4",
+            "}",
+        ];
+        assert_eq!(ir, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn llvm_main_args() -> Result<(), String> {
+        // main takes argc and argv with C's types, and widens them to i64s
+        let program = parse_program("fn main(v1, v2) {\nbb0: ret v1\n}")?;
+        let ir = program_to_ir(&program)?;
+        let expected = vec![
+            "define i64 @main(i32 %v1, ptr %v2) {",
+            "entry:",
+            "  %v1.addr = alloca i64",
+            "  %v2.addr = alloca i64",
+            "  %v1.i64 = sext i32 %v1 to i64",
+            "  store i64 %v1.i64, ptr %v1.addr",
+            "  %v2.i64 = ptrtoint ptr %v2 to i64",
+            "  store i64 %v2.i64, ptr %v2.addr",
+        ];
+        assert_eq!(ir[..8], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn llvm_memory_and_declarations() -> Result<(), String> {
+        let program = parse_program(
+            "str0 = \"ok\\n\"
+             fn main() {
+             bb0: v1 = addr str0; v2 = call puts(v1); v3 = alloca 8; store v2, v3; ret v2
+             }",
+        )?;
+        let ir = program_to_ir(&program)?;
+
+        assert_eq!(
+            ir[..2],
+            [
+                "@str0 = private unnamed_addr constant [4 x i8] c\"ok\\0A\\00\"",
+                "declare i64 @puts(i64)",
+            ]
+        );
+        assert!(ir.contains(&"  %v3.slot = alloca i8, i64 8, align 8".to_owned()));
+        assert!(ir.contains(&"  %t4 = ptrtoint ptr %v3.slot to i64".to_owned()));
+        assert!(ir.contains(&"  %t1 = ptrtoint ptr @str0 to i64".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn llvm_globals() -> Result<(), String> {
+        let program = parse_program(
+            "str0 = \"a\"
+             global n
+             global p = addr str0
+             global x = 7
+             fn main() {
+             bb0: v1 = addr x; v2 = load v1; ret v2
+             }",
+        )?;
+        let ir = program_to_ir(&program)?;
+
+        assert_eq!(
+            ir[1..4],
+            [
+                "@n = global i64 0, align 8",
+                "@p = global ptr @str0, align 8",
+                "@x = global i64 7, align 8",
+            ]
+        );
+        assert!(ir.contains(&"  %t1 = ptrtoint ptr @x to i64".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,879 @@
+use crate::ast::{self, Declaration, Scope, VarInfo};
+use crate::diagnostic::{Diagnostic, DiagnosticSink, Severity, json_string};
+use crate::intern::Symbol;
+use crate::span::Span;
+use crate::symbol_table::{GLOBAL_SCOPE, SymbolTable, find_definition};
+use crate::tokenizer::{self, Token};
+use crate::{CompileOptions, desugar, parser};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/*
+ * A language server, run by `compiler --lsp`, that speaks the Language Server Protocol over
+ * standard input and output: JSON-RPC messages, each after a Content-Length header.
+ *
+ * Editors send the whole document on every change. Each time, it's compiled as far as
+ * optimization, and whatever diagnostics that reports are published for it. The document
+ * symbols are the top-level declarations, with each function's variables under it,
+ * hovering over a name shows the variable's type or the function's signature, and going to
+ * its definition finds where it's declared.
+ *
+ * LSP positions count lines from 0 and characters in UTF-16 code units, while a Span is a
+ * byte offset, so positions are converted by walking the document's text.
+ */
+
+// The LSP's numbers for the kinds of symbol a document has
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_VARIABLE: u32 = 13;
+const SYMBOL_CLASS: u32 = 5; // typedefs, as clangd shows them
+const SYMBOL_ENUM: u32 = 10;
+const SYMBOL_STRUCT: u32 = 23;
+
+// JSON-RPC's error codes
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+
+// A JSON value, as read from a message. Objects keep their keys in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+const NULL: Json = Json::Null;
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Json, String> {
+        let mut reader = JsonReader { s, pos: 0 };
+        let value = reader.value()?;
+        reader.skip_whitespace();
+        match reader.pos == s.len() {
+            true => Ok(value),
+            false => Err(format!("Unexpected text after JSON at {}", reader.pos)),
+        }
+    }
+
+    // The member named `key`, or null if there isn't one
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "{}", json_string(s)),
+            Json::Array(items) => {
+                let items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+                write!(f, "[{}]", items.join(","))
+            }
+            Json::Object(members) => {
+                let members: Vec<String> = members
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}", json_string(name), value))
+                    .collect();
+                write!(f, "{{{}}}", members.join(","))
+            }
+        }
+    }
+}
+
+struct JsonReader<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl JsonReader<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self, expected: &str) -> String {
+        format!("Expected {} at {} in JSON", expected, self.pos)
+    }
+
+    // Moves past `literal` if the text continues with it
+    fn eat(&mut self, literal: &str) -> bool {
+        self.skip_whitespace();
+        let found = self.s[self.pos..].starts_with(literal);
+        if found {
+            self.pos += literal.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let rest = &self.s[self.pos..];
+        for (literal, value) in [
+            ("null", Json::Null),
+            ("true", Json::Bool(true)),
+            ("false", Json::Bool(false)),
+        ] {
+            if self.eat(literal) {
+                return Ok(value);
+            }
+        }
+        match rest.chars().next() {
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = vec![];
+                if !self.eat("]") {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat("]") {
+                            break;
+                        }
+                        if !self.eat(",") {
+                            return Err(self.error(", or ]"));
+                        }
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut members = vec![];
+                if !self.eat("}") {
+                    loop {
+                        self.skip_whitespace();
+                        let name = self.string()?;
+                        if !self.eat(":") {
+                            return Err(self.error(":"));
+                        }
+                        members.push((name, self.value()?));
+                        if self.eat("}") {
+                            break;
+                        }
+                        if !self.eat(",") {
+                            return Err(self.error(", or }"));
+                        }
+                    }
+                }
+                Ok(Json::Object(members))
+            }
+            _ => {
+                let length = rest
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(rest.len());
+                let number = rest[..length].parse().map_err(|_| self.error("a value"))?;
+                self.pos += length;
+                Ok(Json::Number(number))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if !self.s[self.pos..].starts_with('"') {
+            return Err(self.error("a string"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let mut units = vec![];
+                        loop {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            let unit = u16::from_str_radix(&hex, 16)
+                                .map_err(|_| self.error("four hex digits"))?;
+                            units.push(unit);
+                            // The first half of a surrogate pair is followed by the second
+                            if !(0xd800..0xdc00).contains(&unit) || units.len() == 2 {
+                                break;
+                            }
+                            if chars.next().map(|(_, c)| c) != Some('\\')
+                                || chars.next().map(|(_, c)| c) != Some('u')
+                            {
+                                return Err(self.error("the rest of a surrogate pair"));
+                            }
+                        }
+                        out.push_str(&String::from_utf16_lossy(&units));
+                    }
+                    Some(c @ ('"' | '\\' | '/')) => out.push(c),
+                    _ => return Err(self.error("an escape sequence")),
+                },
+                c => out.push(c),
+            }
+        }
+        Err(self.error("a closing quote"))
+    }
+}
+
+// The body of the next message, or None at the end of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, body: &str) -> io::Result<()> {
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// The LSP position of the byte `offset` in `text`, as JSON
+fn position(text: &str, offset: usize) -> String {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[..line_start].matches('\n').count();
+    let character: usize = text[line_start..offset].chars().map(char::len_utf16).sum();
+    format!("{{\"line\":{},\"character\":{}}}", line, character)
+}
+
+fn range(text: &str, span: Span) -> String {
+    format!(
+        "{{\"start\":{},\"end\":{}}}",
+        position(text, span.start),
+        position(text, span.end)
+    )
+}
+
+// The byte offset of an LSP position, clamped to the end of its line
+fn offset(text: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line").as_usize()?;
+    let character = position.get("character").as_usize()?;
+    let line_start = match line {
+        0 => 0,
+        _ => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+// A document parsed as far as its symbol table, for answering questions about it
+struct Analysis<'a> {
+    tokens: Vec<Token<'a>>,
+    spans: Vec<Span>,
+    declarations: Vec<Declaration>,
+    symbol_table: SymbolTable,
+}
+
+impl<'a> Analysis<'a> {
+    // None if the document doesn't get that far, in which case only diagnostics are given
+    fn new(text: &'a str) -> Option<Self> {
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenizer::tokenize_with_spans(text).ok()?;
+        let declarations = parser::parse_with_spans(&tokens, &spans, &mut diagnostics).ok()?;
+        let declarations = desugar::desugar(declarations);
+        let symbol_table = SymbolTable::from_declarations(&declarations).ok()?;
+        Some(Analysis {
+            tokens,
+            spans,
+            declarations,
+            symbol_table,
+        })
+    }
+
+    // The declaration of the identifier under `offset`, in C, and where the identifier is
+    fn describe_at(&self, offset: usize) -> Option<(String, Span)> {
+        let index = self
+            .spans
+            .iter()
+            .position(|span| span.start <= offset && offset < span.end)?;
+        let Token::Identifier(name) = self.tokens[index] else {
+            return None;
+        };
+        let scope = ast::scope_at(&self.declarations, offset).unwrap_or(GLOBAL_SCOPE);
+        let description = match self.symbol_table.resolve_at(scope, name, offset) {
+            Some((_, var)) => format!("{} {}", var.var_type, name),
+            None => {
+                let function = self.symbol_table.get_function(name)?;
+                let mut params: Vec<String> =
+                    function.params.iter().map(|p| p.to_string()).collect();
+                if function.variadic {
+                    params.push("...".to_owned());
+                }
+                format!("{} {}({})", function.return_type, name, params.join(", "))
+            }
+        };
+        Some((description, self.spans[index]))
+    }
+
+    // A DocumentSymbol for each top-level declaration
+    fn document_symbols(&self, text: &str) -> String {
+        let symbols: Vec<String> = self
+            .declarations
+            .iter()
+            .map(|dec| {
+                let (kind, detail, children) = match dec {
+                    Declaration::Function { scope, .. } => {
+                        let mut variables = vec![];
+                        self.variables(scope, &mut variables);
+                        variables.sort_by_key(|v| v.span.start);
+                        let children = variables
+                            .iter()
+                            .map(|v| {
+                                let detail = v.var_type.to_string();
+                                symbol(text, v.name, SYMBOL_VARIABLE, &detail, v.span, None, &[])
+                            })
+                            .collect();
+                        (SYMBOL_FUNCTION, self.signature(dec), children)
+                    }
+                    Declaration::Prototype { .. } => (SYMBOL_FUNCTION, self.signature(dec), vec![]),
+                    Declaration::Struct { .. } => (SYMBOL_STRUCT, String::new(), vec![]),
+                    Declaration::Enum { .. } => (SYMBOL_ENUM, String::new(), vec![]),
+                    Declaration::Typedef { target, .. } => {
+                        (SYMBOL_CLASS, target.to_string(), vec![])
+                    }
+                    Declaration::Global { var_type, .. } => {
+                        (SYMBOL_VARIABLE, var_type.to_string(), vec![])
+                    }
+                };
+                let body = match dec {
+                    Declaration::Function { scope, .. } => scope.span,
+                    _ => None,
+                };
+                let name = declaration_name(dec);
+                symbol(text, name, kind, &detail, dec.span(), body, &children)
+            })
+            .collect();
+        format!("[{}]", symbols.join(","))
+    }
+
+    // The variables declared in `scope` and every scope inside it
+    fn variables<'t>(&'t self, scope: &Scope, out: &mut Vec<&'t VarInfo>) {
+        out.extend(self.symbol_table.symbols_in_scope(scope.id));
+        for statement in &scope.statements {
+            for child in statement.child_scopes() {
+                self.variables(child, out);
+            }
+        }
+    }
+
+    fn signature(&self, dec: &Declaration) -> String {
+        let Some(function) = self.symbol_table.get_function(declaration_name(dec)) else {
+            return String::new();
+        };
+        let mut params: Vec<String> = function.params.iter().map(|p| p.to_string()).collect();
+        if function.variadic {
+            params.push("...".to_owned());
+        }
+        format!("{} ({})", function.return_type, params.join(", "))
+    }
+}
+
+fn declaration_name(dec: &Declaration) -> Symbol {
+    match dec {
+        Declaration::Function { name, .. }
+        | Declaration::Prototype { name, .. }
+        | Declaration::Struct { name, .. }
+        | Declaration::Enum { name, .. }
+        | Declaration::Typedef { name, .. }
+        | Declaration::Global { name, .. } => *name,
+    }
+}
+
+// A DocumentSymbol named at `span`, covering up to the end of `body` if it has one
+fn symbol(
+    text: &str,
+    name: Symbol,
+    kind: u32,
+    detail: &str,
+    span: Span,
+    body: Option<Span>,
+    children: &[String],
+) -> String {
+    let whole = Span {
+        end: body.map_or(span.end, |body| body.end),
+        ..span
+    };
+    format!(
+        "{{\"name\":{},\"detail\":{},\"kind\":{},\"range\":{},\"selectionRange\":{},\"children\":[{}]}}",
+        json_string(name.as_str()),
+        json_string(detail),
+        kind,
+        range(text, whole),
+        range(text, span),
+        children.join(",")
+    )
+}
+
+// An LSP Diagnostic; one that isn't anywhere in particular goes at the top of the document.
+// Notes about another place in the document are its related information.
+fn lsp_diagnostic(uri: &str, text: &str, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    let mut message = diagnostic.message.clone();
+    let mut related = vec![];
+    for note in &diagnostic.notes {
+        match note.span {
+            Some(span) => related.push(format!(
+                "{{\"location\":{{\"uri\":{},\"range\":{}}},\"message\":{}}}",
+                json_string(uri),
+                range(text, span),
+                json_string(&note.message)
+            )),
+            None => message.push_str(&format!("\nnote: {}", note.message)),
+        }
+    }
+    let related = match related.is_empty() {
+        true => String::new(),
+        false => format!(",\"relatedInformation\":[{}]", related.join(",")),
+    };
+    let code = match diagnostic.warning {
+        Some(name) => format!(",\"code\":{}", json_string(&format!("-W{}", name))),
+        None => String::new(),
+    };
+    format!(
+        "{{\"range\":{},\"severity\":{},\"source\":\"compiler\",\"message\":{}{}{}}}",
+        range(text, diagnostic.span.unwrap_or_default()),
+        severity,
+        json_string(&message),
+        code,
+        related
+    )
+}
+
+struct Server {
+    options: CompileOptions,
+    documents: HashMap<String, String>, // the text of each open document, by URI
+    shut_down: bool,
+}
+
+impl Server {
+    // The messages to send in reply to `message`: a response if it's a request, and any
+    // notifications it leads to
+    fn handle(&mut self, message: &Json) -> Vec<String> {
+        let id = message.get("id");
+        let params = message.get("params");
+        let uri = params.get("textDocument").get("uri").as_str();
+        let respond = |result: String| {
+            format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}",
+                id, result
+            )
+        };
+        let Some(method) = message.get("method").as_str() else {
+            // A response to a request of ours, which the server never makes
+            return vec![];
+        };
+        if self.shut_down && method != "exit" && *id != Json::Null {
+            return vec![error(id, INVALID_REQUEST, "The server has shut down")];
+        }
+        match (method, uri) {
+            ("initialize", _) => vec![respond(
+                "{\"capabilities\":{\"textDocumentSync\":1,\"documentSymbolProvider\":true,\
+                 \"hoverProvider\":true,\"definitionProvider\":true},\"serverInfo\":{\"name\":\"compiler\"}}"
+                    .to_owned(),
+            )],
+            ("shutdown", _) => {
+                self.shut_down = true;
+                vec![respond("null".to_owned())]
+            }
+            ("textDocument/didOpen", Some(uri)) => {
+                let text = params.get("textDocument").get("text").as_str();
+                self.documents
+                    .insert(uri.to_owned(), text.unwrap_or_default().to_owned());
+                vec![self.publish_diagnostics(uri)]
+            }
+            // Only whole-document changes are asked for, so the last one is the new text
+            ("textDocument/didChange", Some(uri)) => {
+                if let Json::Array(changes) = params.get("contentChanges")
+                    && let Some(text) = changes.last().and_then(|c| c.get("text").as_str())
+                {
+                    self.documents.insert(uri.to_owned(), text.to_owned());
+                }
+                vec![self.publish_diagnostics(uri)]
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.documents.remove(uri);
+                vec![self.publish_diagnostics(uri)]
+            }
+            ("textDocument/documentSymbol", Some(uri)) => {
+                let text = self.document(uri);
+                let symbols = Analysis::new(text).map(|a| a.document_symbols(text));
+                vec![respond(symbols.unwrap_or_else(|| "null".to_owned()))]
+            }
+            ("textDocument/hover", Some(uri)) => {
+                let text = self.document(uri);
+                let described = offset(text, params.get("position"))
+                    .zip(Analysis::new(text))
+                    .and_then(|(offset, analysis)| analysis.describe_at(offset));
+                let hover = described.map(|(description, span)| {
+                    format!(
+                        "{{\"contents\":{{\"kind\":\"markdown\",\"value\":{}}},\"range\":{}}}",
+                        json_string(&format!("```c\n{}\n```", description)),
+                        range(text, span)
+                    )
+                });
+                vec![respond(hover.unwrap_or_else(|| "null".to_owned()))]
+            }
+            ("textDocument/definition", Some(uri)) => {
+                let text = self.document(uri);
+                let definition = offset(text, params.get("position"))
+                    .zip(Analysis::new(text))
+                    .and_then(|(offset, analysis)| {
+                        let Analysis {
+                            declarations,
+                            symbol_table,
+                            ..
+                        } = &analysis;
+                        find_definition(declarations, symbol_table, offset)
+                    });
+                let location = definition.map(|span| {
+                    format!(
+                        "{{\"uri\":{},\"range\":{}}}",
+                        json_string(uri),
+                        range(text, span)
+                    )
+                });
+                vec![respond(location.unwrap_or_else(|| "null".to_owned()))]
+            }
+            _ if *id != Json::Null => vec![error(
+                id,
+                METHOD_NOT_FOUND,
+                &format!("Unsupported method {}", method),
+            )],
+            // Notifications the server doesn't act on, like initialized
+            _ => vec![],
+        }
+    }
+
+    fn document(&self, uri: &str) -> &str {
+        self.documents.get(uri).map_or("", String::as_str)
+    }
+
+    // Every diagnostic compiling the document reports, or none once it's closed
+    fn publish_diagnostics(&self, uri: &str) -> String {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => {
+                let options = self.options.clone().file_name(uri).jobs(1);
+                let reported = match crate::compile_to_program(text, &options) {
+                    Ok(compiled) => compiled.diagnostics,
+                    Err(diagnostics) => diagnostics,
+                };
+                reported
+                    .iter()
+                    .map(|d| lsp_diagnostic(uri, text, d))
+                    .collect()
+            }
+            None => vec![],
+        };
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":\
+             {{\"uri\":{},\"diagnostics\":[{}]}}}}",
+            json_string(uri),
+            diagnostics.join(",")
+        )
+    }
+}
+
+fn error(id: &Json, code: i32, message: &str) -> String {
+    format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":{},\"message\":{}}}}}",
+        id,
+        code,
+        json_string(message)
+    )
+}
+
+// Answers messages from `input` on `output` until the client says to exit, returning the
+// exit code: 0 if it shut the server down first, as the protocol asks, and 1 if it didn't
+pub fn serve(
+    mut input: impl BufRead,
+    mut output: impl Write,
+    options: CompileOptions,
+) -> io::Result<i32> {
+    let mut server = Server {
+        options,
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+    while let Some(body) = read_message(&mut input)? {
+        let message = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                write_message(&mut output, &error(&Json::Null, PARSE_ERROR, &e))?;
+                continue;
+            }
+        };
+        if message.get("method").as_str() == Some("exit") {
+            return Ok(if server.shut_down { 0 } else { 1 });
+        }
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(if server.shut_down { 0 } else { 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(messages: &[&str]) -> Vec<u8> {
+        let mut out = vec![];
+        for message in messages {
+            write_message(&mut out, message).unwrap();
+        }
+        out
+    }
+
+    // Runs a session, returning the exit code and every message the server sent
+    fn session(messages: &[&str]) -> Result<(i32, Vec<Json>), String> {
+        let mut output = vec![];
+        let input = frame(messages);
+        let code =
+            serve(&input[..], &mut output, CompileOptions::default()).map_err(|e| e.to_string())?;
+        let mut output = &output[..];
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut output).map_err(|e| e.to_string())? {
+            replies.push(Json::parse(&body)?);
+        }
+        Ok((code, replies))
+    }
+
+    #[test]
+    fn test_json() -> Result<(), String> {
+        let json = Json::parse(r#" {"a": [1, -2.5e1, true, null], "b": "x\"é😀"} "#)?;
+        assert_eq!(
+            json.get("a"),
+            &Json::Array(vec![
+                Json::Number(1.0),
+                Json::Number(-25.0),
+                Json::Bool(true),
+                Json::Null
+            ])
+        );
+        assert_eq!(json.get("b").as_str(), Some("x\"é😀"));
+        assert_eq!(json.get("c"), &Json::Null);
+        assert_eq!(json.to_string(), r#"{"a":[1,-25,true,null],"b":"x\"é😀"}"#);
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("\"open").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "a\n\u{e9}\u{1f600}x\n";
+        assert_eq!(position(text, 0), r#"{"line":0,"character":0}"#);
+        // é is two bytes but one UTF-16 unit, and the emoji four bytes but two units
+        assert_eq!(position(text, 8), r#"{"line":1,"character":3}"#);
+        let at = |line, character| {
+            let position = format!("{{\"line\":{},\"character\":{}}}", line, character);
+            offset(text, &Json::parse(&position).unwrap())
+        };
+        assert_eq!(at(1, 3), Some(8));
+        assert_eq!(at(1, 100), Some(9));
+        assert_eq!(at(5, 0), None);
+    }
+
+    #[test]
+    fn test_session() -> Result<(), String> {
+        let source = "int g;\nint add(int a, char b) {\n  int c = a + b;\n  return c + y;\n}\n";
+        let open = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///a.c","languageId":"c","version":1,"text":{}}}}}}}"#,
+            json_string(source)
+        );
+        let fixed = source.replace("+ y", "+ g");
+        let change = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"file:///a.c","version":2}},"contentChanges":[{{"text":{}}}]}}}}"#,
+            json_string(&fixed)
+        );
+        let hover = |id, line, character| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":{},"method":"textDocument/hover","params":{{"textDocument":{{"uri":"file:///a.c"}},"position":{{"line":{},"character":{}}}}}}}"#,
+                id, line, character
+            )
+        };
+        let (code, replies) = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            &open,
+            &change,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///a.c"}}}"#,
+            &hover(3, 3, 9),
+            &hover(4, 1, 5),
+            &hover(5, 3, 3),
+            r#"{"jsonrpc":"2.0","id":6,"method":"textDocument/completion","params":{}}"#,
+            &hover(8, 3, 9).replace("hover", "definition"),
+            r#"{"jsonrpc":"2.0","id":7,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ])?;
+        assert_eq!(code, 0);
+        assert_eq!(replies.len(), 10);
+        assert_eq!(
+            replies[0]
+                .get("result")
+                .get("capabilities")
+                .get("hoverProvider"),
+            &Json::Bool(true)
+        );
+
+        // The undefined variable is reported where it's used, and goes away once it's fixed
+        let diagnostics = replies[1].get("params").get("diagnostics");
+        assert_eq!(
+            diagnostics.to_string(),
+            r#"[{"range":{"start":{"line":3,"character":13},"end":{"line":3,"character":14}},"severity":1,"source":"compiler","message":"Undefined variable y in scope 1"}]"#
+        );
+        assert_eq!(
+            replies[2].get("params").get("diagnostics").to_string(),
+            "[]"
+        );
+
+        let Json::Array(symbols) = replies[3].get("result") else {
+            return Err(format!("Expected symbols, got {}", replies[3]));
+        };
+        let names: Vec<String> = symbols
+            .iter()
+            .map(|s| format!("{} {}", s.get("name"), s.get("detail")))
+            .collect();
+        assert_eq!(names, vec![r#""g" "int""#, r#""add" "int (int, char)""#]);
+        let variables: Vec<&str> = match symbols[1].get("children") {
+            Json::Array(children) => children
+                .iter()
+                .filter_map(|c| c.get("name").as_str())
+                .collect(),
+            _ => vec![],
+        };
+        assert_eq!(variables, vec!["a", "b", "c"]);
+        assert_eq!(
+            symbols[1].get("range").get("end").to_string(),
+            r#"{"line":4,"character":1}"#
+        );
+
+        let hovered = |reply: &Json| reply.get("result").get("contents").get("value").clone();
+        assert_eq!(
+            hovered(&replies[4]),
+            Json::String("```c\nint c\n```".to_owned())
+        );
+        assert_eq!(
+            hovered(&replies[5]),
+            Json::String("```c\nint add(int, char)\n```".to_owned())
+        );
+        assert_eq!(replies[6].get("result"), &Json::Null);
+        assert_eq!(
+            replies[7].get("error").get("code"),
+            &Json::Number(METHOD_NOT_FOUND as f64)
+        );
+        assert_eq!(
+            replies[8].get("result").to_string(),
+            r#"{"uri":"file:///a.c","range":{"start":{"line":2,"character":6},"end":{"line":2,"character":7}}}"#
+        );
+        assert_eq!(replies[9].get("result"), &Json::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_related_information() -> Result<(), String> {
+        let open = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///a.c","languageId":"c","version":1,"text":{}}}}}}}"#,
+            json_string("int g;\nint g;\nint main() { return 0; }\n")
+        );
+        let (_, replies) = session(&[&open, r#"{"jsonrpc":"2.0","method":"exit"}"#])?;
+        // The earlier definition is pointed at as well
+        assert_eq!(
+            replies[0].get("params").get("diagnostics").to_string(),
+            r#"[{"range":{"start":{"line":1,"character":4},"end":{"line":1,"character":5}},"severity":1,"source":"compiler","message":"Duplicate definition of g","relatedInformation":[{"location":{"uri":"file:///a.c","range":{"start":{"line":0,"character":4},"end":{"line":0,"character":5}}},"message":"Previous definition of g"}]}]"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hover_scopes() -> Result<(), String> {
+        // Each x is the innermost one declared before the cursor, and the loop counter is
+        // in scope from its condition to the end of its body
+        let text = "int x;\nint main() {\n  char y = x;\n  { char *x; x; }\n  \
+                    for (unsigned i = 0; i < 3; i += 1) { y = i; }\n  int x;\n  return x;\n}\n";
+        let analysis = Analysis::new(text).ok_or("Expected the text to parse")?;
+        let hover = |needle: &str, nth: usize| -> Option<String> {
+            let at = text.match_indices(needle).nth(nth)?.0;
+            analysis.describe_at(at).map(|(description, _)| description)
+        };
+        assert_eq!(hover("x;", 1).as_deref(), Some("int x"));
+        assert_eq!(hover("x;", 3).as_deref(), Some("char* x"));
+        assert_eq!(hover("i <", 0).as_deref(), Some("unsigned i"));
+        assert_eq!(hover("i;", 0).as_deref(), Some("unsigned i"));
+        assert_eq!(hover("x;", 5).as_deref(), Some("int x"));
+        assert_eq!(hover("main", 0).as_deref(), Some("int main()"));
+        assert_eq!(hover("for", 0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_without_shutdown() -> Result<(), String> {
+        let (code, replies) = session(&["{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}"])?;
+        assert_eq!((code, replies.len()), (1, 0));
+        let (_, replies) = session(&["{not json"])?;
+        assert_eq!(
+            replies[0].get("error").get("code"),
+            &Json::Number(PARSE_ERROR as f64)
+        );
+        Ok(())
+    }
+}
@@ -1,35 +1,607 @@
-use std::fs::{read_to_string, write};
-use std::process::Command;
-
-mod ast;
-mod cfg;
-mod codegen;
-mod parser;
-mod symantic_check;
-mod symbol_table;
-mod tokenizer;
+use compiler::diagnostic::{Diagnostic, DiagnosticSink};
+use compiler::error::CompileError;
+use compiler::pass_manager::PassManager;
+use compiler::regalloc::Allocator;
+use compiler::triple::{Arch, Os};
+use compiler::{CompileOptions, Target};
+use compiler::{
+    assembler, ast_dump, compile_commands, desugar, elf, ice, interp, llvm, lsp, parallel, parser,
+    symantic_check, tokenizer,
+};
+use std::ffi::OsStr;
+use std::fs::{read, read_to_string, write};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, exit};
+use std::time::Duration;
 
 const FILE_ASM: &str = "out.s";
 const FILE_OBJ: &str = "out.o";
 const FILE_EXE: &str = "out";
+const FILE_WAT: &str = "out.wat";
+const FILE_COMPILE_COMMANDS: &str = "compile_commands.json";
 
 fn main() {
-    let s = read_to_string("test/return.c").unwrap();
-    let tokens = tokenizer::tokenize(&s).unwrap();
-    let ast = parser::parse(&tokens).unwrap();
-    symantic_check::check_syntax(&ast).unwrap();
-    let cfg = cfg::ControlFlowGraph::from(&ast);
-    let asm = codegen::cfg_to_asm(&cfg).unwrap().join("\n");
-
-    write(FILE_ASM, asm).expect(format!("Failed to write {}", FILE_ASM).as_str());
-
-    Command::new("as")
-        .args([FILE_ASM, "-o", FILE_OBJ])
-        .output()
-        .expect("Failed to execute `as`");
-
-    Command::new("ld")
-        .args([FILE_OBJ, "-o", FILE_EXE])
-        .output()
-        .expect("Failed to execute `ld`");
+    // A panic from here on is the compiler's bug, and is reported as one
+    ice::install_hook();
+    let compiler = std::thread::Builder::new()
+        .stack_size(parallel::STACK_SIZE)
+        .spawn(compile)
+        .unwrap_or_else(|e| {
+            eprintln!("error: failed to start the compiler thread: {}", e);
+            exit(1);
+        });
+    // The hook has already reported the panic
+    if compiler.join().is_err() {
+        exit(101);
+    }
+}
+
+fn compile() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // -v logs each stage as it finishes, along with how each switch was lowered, -vv also
+    // what the optimizer and register allocator decided, and -vvv everything. --verbose
+    // implies -v.
+    let verbosity: usize = args
+        .iter()
+        .filter_map(|a| a.strip_prefix('-'))
+        .filter(|v| !v.is_empty() && v.chars().all(|c| c == 'v'))
+        .map(str::len)
+        .sum();
+    let verbosity = match args.iter().any(|a| a == "--verbose") {
+        true => verbosity.max(1),
+        false => verbosity,
+    };
+    log::set_logger(&Logger).expect("the logger is only set once");
+    log::set_max_level(match verbosity {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
+    // --lsp serves an editor over standard input and output instead of compiling a file
+    if args.iter().any(|a| a == "--lsp") {
+        let options = with_warnings(CompileOptions::default(), &args);
+        if let Err(e) = options.diagnostic_sink() {
+            eprintln!("error: {}", e);
+            exit(1);
+        }
+        let served = lsp::serve(std::io::stdin().lock(), std::io::stdout(), options);
+        exit(served.unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            1
+        }));
+    }
+    let emit = args.iter().find_map(|a| a.strip_prefix("--emit="));
+    const EMIT_KINDS: [&str; 7] = [
+        "tokens",
+        "ast",
+        "cfg",
+        "asm",
+        "llvm-ir",
+        "obj",
+        "compile-commands",
+    ];
+    if let Some(kind) = emit.filter(|k| !EMIT_KINDS.contains(k)) {
+        eprintln!(
+            "error: unknown output kind {} (expected tokens, ast, cfg, asm, llvm-ir, obj, or \
+             compile-commands)",
+            kind
+        );
+        exit(1);
+    }
+    // --emit=ast and --emit=cfg are the same as --dump-ast and --dump-cfg=text
+    let dump_ast = args.iter().any(|a| a == "--dump-ast") || emit == Some("ast");
+    let dump_symbols = args.iter().any(|a| a == "--dump-symbols");
+    // --dump-scopes prints text, and --dump-scopes=json the same as JSON
+    let dump_scopes = args.iter().find_map(|a| match a.as_str() {
+        "--dump-scopes" => Some("text"),
+        _ => a.strip_prefix("--dump-scopes="),
+    });
+    let dump_cfg = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--dump-cfg="))
+        .or((emit == Some("cfg")).then_some("text"));
+    let debug_info = args.iter().any(|a| a == "-g");
+    let asm_comments = args.iter().any(|a| a == "--asm-comments");
+    if let Some(format) = dump_cfg.filter(|f| !["dot", "text"].contains(f)) {
+        eprintln!(
+            "error: unknown CFG dump format {} (expected dot or text)",
+            format
+        );
+        exit(1);
+    }
+    if let Some(format) = dump_scopes.filter(|f| !["json", "text"].contains(f)) {
+        eprintln!(
+            "error: unknown scope dump format {} (expected json or text)",
+            format
+        );
+        exit(1);
+    }
+    // -o names the final output, which is the executable unless -S or -c stops earlier
+    let output = args.iter().position(|a| a == "-o").map(|i| {
+        args.get(i + 1).map(String::as_str).unwrap_or_else(|| {
+            eprintln!("error: missing file name after -o");
+            exit(1);
+        })
+    });
+    // The one argument that isn't a flag or -o's file name, where - means standard input
+    let inputs: Vec<&str> = args
+        .iter()
+        .enumerate()
+        .filter(|(i, a)| {
+            (*a == "-" || !a.starts_with('-'))
+                && (*i == 0 || !["-o", "--target"].contains(&args[i - 1].as_str()))
+        })
+        .map(|(_, a)| a.as_str())
+        .collect();
+    // The compilation database describes how each input is compiled instead of compiling
+    // any, so it can take several
+    if emit == Some("compile-commands") {
+        if inputs.is_empty() || inputs.contains(&"-") {
+            eprintln!("error: --emit=compile-commands needs input files, not standard input");
+            exit(1);
+        }
+        let directory = std::env::current_dir().unwrap_or_else(|e| {
+            eprintln!("error: failed to get the current directory: {}", e);
+            exit(1);
+        });
+        let compiler = std::env::args()
+            .next()
+            .unwrap_or_else(|| "compiler".to_owned());
+        let database =
+            compile_commands::database(&directory.to_string_lossy(), &compiler, &args, &inputs);
+        write_output(output.unwrap_or(FILE_COMPILE_COMMANDS), database);
+        return;
+    }
+    let input = match inputs[..] {
+        [input] => input,
+        [] => {
+            eprintln!("usage: compiler [options] <file.c | ->");
+            exit(1);
+        }
+        _ => {
+            eprintln!("error: expected one input file, got {}", inputs.join(" "));
+            exit(1);
+        }
+    };
+    let stop_at_asm = args.iter().any(|a| a == "-S") || emit == Some("asm");
+    let stop_at_obj = args.iter().any(|a| a == "-c") || emit == Some("obj");
+    // A freestanding program brings its own _start, and is linked without the C runtime
+    let freestanding = args.iter().any(|a| a == "--freestanding");
+    // The assembler and the linker, e.g. --cc=clang, or --ld=ld.lld when freestanding
+    let assembler = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--as="))
+        .unwrap_or("as");
+    let linker = match freestanding {
+        true => args
+            .iter()
+            .find_map(|a| a.strip_prefix("--ld="))
+            .unwrap_or("ld"),
+        false => args
+            .iter()
+            .find_map(|a| a.strip_prefix("--cc="))
+            .unwrap_or("cc"),
+    };
+
+    // -O1 by default
+    let mut opt_level = 1;
+    for flag in args.iter().filter(|a| a.starts_with("-O")) {
+        opt_level = match flag.as_str() {
+            "-O0" => 0,
+            "-O1" => 1,
+            "-O2" => 2,
+            _ => {
+                eprintln!("error: unknown optimization level {}", flag);
+                exit(1);
+            }
+        };
+    }
+
+    // An explicit pass list, e.g. --passes=fold-constants,dce, replaces the -O pipeline
+    let passes = match args.iter().find_map(|a| a.strip_prefix("--passes=")) {
+        Some(names) => names.split(',').map(str::to_owned).collect(),
+        None => CompileOptions::passes_for_level(opt_level),
+    };
+    let names: Vec<&str> = passes.iter().map(String::as_str).collect();
+    if let Err(e) = PassManager::from_names(&names) {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+    if args.iter().any(|a| a == "--verbose") {
+        match names.is_empty() {
+            true => eprintln!("passes: none"),
+            false => eprintln!("passes: {}", names.join(", ")),
+        }
+    }
+
+    let allocator = match args.iter().find_map(|a| a.strip_prefix("--regalloc=")) {
+        Some(name) => Allocator::from_name(name).unwrap_or_else(|| {
+            eprintln!(
+                "error: unknown register allocator {} (expected linear-scan or graph-coloring)",
+                name
+            );
+            exit(1);
+        }),
+        None => Allocator::default(),
+    };
+
+    // -j<n> optimizes and generates code for up to n functions at once, one per core by default
+    let jobs = match args.iter().find_map(|a| a.strip_prefix("-j")) {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("error: expected a number of jobs, got -j{}", n);
+                exit(1);
+            }
+        },
+        None => parallel::default_jobs(),
+    };
+
+    // A target triple, --target=<triple> or --target <triple>, or one of the short names
+    let target_name = args.iter().enumerate().find_map(|(i, a)| match a.as_str() {
+        "--target" => Some(args.get(i + 1).map(String::as_str).unwrap_or_else(|| {
+            eprintln!("error: missing triple after --target");
+            exit(1);
+        })),
+        _ => a.strip_prefix("--target="),
+    });
+    let target = match target_name {
+        Some(name) => Target::parse(name).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            exit(1);
+        }),
+        None => Target::default(),
+    };
+    // Only x86-64 ELF output is assembled and linked; RISC-V and Windows assembly is left in
+    // out.s for a cross toolchain or simulator to pick up, and WebAssembly text goes in
+    // out.wat
+    let native = target.arch == Arch::X86_64 && target.os != Os::Windows;
+    if emit == Some("obj") && !native {
+        eprintln!("error: --emit=obj is not supported for {}", target);
+        exit(1);
+    }
+    // The GOT is an ELF thing; PE code reaches its data %rip-relative either way
+    if args.iter().any(|a| a == "-fPIC") && target.os == Os::Windows {
+        eprintln!("error: -fPIC is not supported for {}", target);
+        exit(1);
+    }
+    if freestanding && target.arch == Arch::Wasm32 {
+        eprintln!("error: --freestanding is not supported for {}", target);
+        exit(1);
+    }
+    if emit == Some("obj") && debug_info {
+        eprintln!("error: -g is not supported with --emit=obj");
+        exit(1);
+    }
+    if args.iter().any(|a| a == "-c") && !native {
+        eprintln!("error: -c is not supported for {}", target);
+        exit(1);
+    }
+
+    let format = match args.iter().find_map(|a| a.strip_prefix("--error-format=")) {
+        Some("human") | None => ErrorFormat::Human,
+        Some("short") => ErrorFormat::Short,
+        Some("json") => ErrorFormat::Json,
+        Some(format) => {
+            eprintln!(
+                "error: unknown error format {} (expected human, short, or json)",
+                format
+            );
+            exit(1);
+        }
+    };
+    // Diagnostics name the file they are about
+    let file_name = match input {
+        "-" => "<stdin>",
+        _ => input,
+    };
+    let options = CompileOptions::default()
+        .target(target)
+        .passes(&names)
+        .allocator(allocator)
+        .pic(args.iter().any(|a| a == "-fPIC"))
+        .freestanding(freestanding)
+        .debug_info(debug_info)
+        .asm_comments(asm_comments)
+        .file_name(file_name)
+        .jobs(jobs);
+    let options = with_warnings(options, &args);
+    // A bad -W flag is reported before anything is read
+    let mut diagnostics = options.diagnostic_sink().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        exit(1);
+    });
+    let s = match input {
+        "-" => std::io::read_to_string(std::io::stdin()),
+        _ => read_to_string(input),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("error: failed to read {}: {}", input, e);
+        exit(1);
+    });
+    let input = file_name;
+    ice::enter_file(input);
+    let fail = |diagnostics: &[Diagnostic]| -> ! {
+        report(input, &s, diagnostics, format);
+        exit(1);
+    };
+
+    // The stages before lowering are only run here to show what they produce
+    if emit == Some("tokens") || dump_ast || dump_symbols || dump_scopes.is_some() {
+        let fail = |diagnostics: &DiagnosticSink, error: CompileError| -> ! {
+            let mut reported = diagnostics.diagnostics().to_vec();
+            reported.push(Diagnostic::from(error));
+            fail(&reported)
+        };
+        ice::enter_stage("tokenize");
+        let (tokens, spans) =
+            tokenizer::tokenize_with_spans(&s).unwrap_or_else(|e| fail(&diagnostics, e));
+        if emit == Some("tokens") {
+            print!("{}", tokenizer::dump(&tokens, &spans));
+            return;
+        }
+        ice::enter_stage("parse");
+        let ast = parser::parse_with_spans(&tokens, &spans, &mut diagnostics)
+            .unwrap_or_else(|e| fail(&diagnostics, e));
+        if dump_ast {
+            print!("{}", ast_dump::dump(&ast));
+            return;
+        }
+        ice::enter_stage("check");
+        let mut ast = desugar::desugar(ast);
+        let checked = symantic_check::check(&mut ast, &options, &mut diagnostics)
+            .unwrap_or_else(|e| fail(&diagnostics, e));
+        let symbol_table = &checked.symbol_table;
+        match dump_scopes {
+            Some("json") => print!("{}", symbol_table.scopes_to_json(&s)),
+            Some(_) => print!("{}", symbol_table.dump_scopes(&s)),
+            None => print!("{}", symbol_table.dump()),
+        }
+        return;
+    }
+
+    let compiled = compiler::compile_to_program(&s, &options).unwrap_or_else(|d| fail(&d));
+    report(input, &s, &compiled.diagnostics, format);
+    let program = &compiled.program;
+    // The graphs as codegen will see them, after optimization
+    if dump_cfg == Some("text") {
+        print!("{}", program);
+        return;
+    }
+    if dump_cfg == Some("dot") {
+        for (name, function) in &program.functions {
+            print!("{}", function.cfg.to_dot(name));
+        }
+        return;
+    }
+    // --run executes the IR instead of compiling it, exiting with whatever main returns
+    if args.iter().any(|a| a == "--run") {
+        ice::enter_stage("run");
+        let execution = interp::run(program, input).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            exit(1);
+        });
+        write_output("-", execution.output);
+        exit(execution.result as i32);
+    }
+    if emit == Some("llvm-ir") {
+        ice::enter_stage("llvm-ir");
+        let ir = llvm::program_to_ir(program).unwrap_or_else(|e| fail(&[e.into()]));
+        println!("{}", ir.join("\n"));
+        return;
+    }
+    let (asm, codegen) =
+        compiler::program_to_asm_timed(program, &s, &options).unwrap_or_else(|e| fail(&[e.into()]));
+    // Where the time went, up to the assembler
+    if args.iter().any(|a| a == "--time-passes") {
+        let stages: Vec<_> = compiled.stages.iter().chain([&codegen]).collect();
+        for stage in &stages {
+            eprintln!("{}", stage);
+        }
+        let total = stages.iter().map(|stage| stage.time).sum::<Duration>();
+        eprintln!("{:<24}{:>10.3}ms", "total", total.as_secs_f64() * 1000.0);
+    }
+    if target.arch == Arch::Wasm32 {
+        write_output(output.unwrap_or(FILE_WAT), asm.join("\n"));
+        return;
+    }
+
+    // The object file is written directly, without needing `as`, and left for the user to
+    // link
+    if emit == Some("obj") {
+        ice::enter_stage("assemble");
+        let object = assembler::assemble(&asm).unwrap_or_else(|e| fail(&[e.into()]));
+        write_output(output.unwrap_or(FILE_OBJ), object.to_elf(elf::EM_X86_64));
+        return;
+    }
+
+    if stop_at_asm || !native {
+        write_output(output.unwrap_or(FILE_ASM), asm.join("\n") + "\n");
+        return;
+    }
+
+    // The files in between are the build's own, and go once it's done whether or not it
+    // worked
+    let temp = TempDir::new().unwrap_or_else(|e| {
+        eprintln!("error: failed to create a temporary directory: {}", e);
+        exit(1);
+    });
+    let built = assemble_and_link(&asm, &temp, assembler, linker, output, stop_at_obj);
+    drop(temp);
+    if let Err(e) = built {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
+}
+
+// Adds each -W flag on the command line, in the order given
+fn with_warnings(options: CompileOptions, args: &[String]) -> CompileOptions {
+    args.iter()
+        .filter(|a| a.starts_with("-W"))
+        .fold(options, |options, flag| options.warning(flag))
+}
+
+// Runs `as`, then the linker unless -c stops at the object file, writing whatever's asked for
+// to `output`, or standard output for -
+fn assemble_and_link(
+    asm: &[String],
+    temp: &TempDir,
+    assembler: &str,
+    linker: &str,
+    output: Option<&str>,
+    stop_at_obj: bool,
+) -> Result<(), String> {
+    let asm_file = temp.file(FILE_ASM);
+    write(&asm_file, asm.join("\n") + "\n")
+        .map_err(|e| format!("failed to write {}: {}", asm_file.display(), e))?;
+
+    let object = match output {
+        Some(output) if stop_at_obj && output != "-" => PathBuf::from(output),
+        _ if stop_at_obj && output.is_none() => PathBuf::from(FILE_OBJ),
+        _ => temp.file(FILE_OBJ),
+    };
+    run_tool(
+        assembler,
+        &[asm_file.as_os_str(), "-o".as_ref(), object.as_os_str()],
+    )?;
+    if stop_at_obj {
+        if output == Some("-") {
+            copy_to_stdout(&object)?;
+        }
+        return Ok(());
+    }
+
+    // Linked through cc so the C runtime's startup code calls main and exits with its
+    // result, unless the program has its own _start
+    let executable = match output {
+        Some("-") => temp.file(FILE_EXE),
+        output => PathBuf::from(output.unwrap_or(FILE_EXE)),
+    };
+    run_tool(
+        linker,
+        &[object.as_os_str(), "-o".as_ref(), executable.as_os_str()],
+    )?;
+    if output == Some("-") {
+        copy_to_stdout(&executable)?;
+    }
+    Ok(())
+}
+
+// A directory of its own for each run of the compiler, so builds running side by side never
+// share their intermediate files, removed with everything in it when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> std::io::Result<Self> {
+        let base = std::env::temp_dir();
+        let pid = std::process::id();
+        let mut attempt = 0;
+        loop {
+            let path = base.join(format!("compiler-{}-{}", pid, attempt));
+            // create_dir fails rather than reuse a directory that's already there
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(TempDir(path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Writes to `path`, or to standard output if it is -
+fn write_output(path: &str, contents: impl AsRef<[u8]>) {
+    let result = match path {
+        "-" => std::io::stdout().write_all(contents.as_ref()),
+        _ => write(path, contents),
+    };
+    if let Err(e) = result {
+        eprintln!("error: failed to write {}: {}", path, e);
+        exit(1);
+    }
+}
+
+// For -o - when the output was made by the assembler or linker, which need a real file
+fn copy_to_stdout(path: &Path) -> Result<(), String> {
+    let contents = read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    std::io::stdout()
+        .write_all(&contents)
+        .map_err(|e| format!("failed to write -: {}", e))
+}
+
+// Runs the assembler or linker, passing on its own error messages if it fails
+fn run_tool(program: &str, args: &[&OsStr]) -> Result<(), String> {
+    let result = Command::new(program).args(args).output();
+    let output = result.map_err(|e| format!("failed to run `{}`: {}", program, e))?;
+    if !output.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(match output.status.code() {
+            Some(code) => format!("`{}` failed with exit code {}", program, code),
+            None => format!("`{}` was terminated by a signal", program),
+        });
+    }
+    Ok(())
+}
+
+// Writes log records to stderr, tagged with their level and the module they came from
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        let module = record.target().strip_prefix("compiler::");
+        eprintln!(
+            "[{} {}] {}",
+            record.level().as_str().to_lowercase(),
+            module.unwrap_or(record.target()),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ErrorFormat {
+    Human, // with the source line underlined
+    Short, // one line each, like gcc
+    Json,  // one object per line
+}
+
+// Prints diagnostics to stderr in the chosen format
+fn report(input: &str, source: &str, diagnostics: &[Diagnostic], format: ErrorFormat) {
+    // In the order they appear in the source, whichever stage found them, with the ones that
+    // aren't anywhere in particular last
+    let mut diagnostics: Vec<&Diagnostic> = diagnostics.iter().collect();
+    diagnostics.sort_by_key(|d| d.span.map_or(usize::MAX, |span| span.start));
+    for diagnostic in diagnostics {
+        match (format, diagnostic.span) {
+            (ErrorFormat::Human, _) => eprintln!("{}", diagnostic.render(input, source)),
+            (ErrorFormat::Json, _) => eprintln!("{}", diagnostic.to_json(input)),
+            (ErrorFormat::Short, Some(_)) => eprintln!("{}:{}", input, diagnostic),
+            (ErrorFormat::Short, None) => eprintln!("{}: {}", input, diagnostic),
+        }
+        if format == ErrorFormat::Short {
+            for note in &diagnostic.notes {
+                match note.span {
+                    Some(_) => eprintln!("{}:{}", input, note),
+                    None => eprintln!("{}: {}", input, note),
+                }
+            }
+        }
+    }
 }
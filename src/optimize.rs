@@ -0,0 +1,1283 @@
+use crate::cfg::*;
+use crate::intern::Symbol;
+use crate::liveness::Liveness;
+use crate::ssa;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/*
+ * Optimization passes over a lowered function.
+ *
+ * Lowering reuses a variable's var for every assignment to it, so a var only has a known
+ * value everywhere if it has a single definition, and that definition is an Assign.
+ * Parameters are defined on entry, so they never count as constants.
+ *
+ * Values are folded the way codegen computes them: as 64-bit two's complement, wrapping on
+ * overflow.
+ */
+
+// Vars with a single definition that assigns them a constant, mapped to that constant
+fn constants(function: &Function) -> HashMap<VReg, u64> {
+    let mut definitions: HashMap<&VReg, Vec<&Statement>> = HashMap::new();
+    for statement in function.cfg.values().flatten() {
+        if let Some(var) = statement.defined_var() {
+            definitions.entry(var).or_default().push(statement);
+        }
+    }
+    definitions
+        .into_iter()
+        .filter(|(var, _)| !function.params.contains(var))
+        .filter_map(|(var, defs)| match defs[..] {
+            [Statement::Assign { value, .. }] => Some((*var, *value as u64)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn evaluate_binary(op: &BinOp, lhs: u64, rhs: u64) -> Option<u64> {
+    let (lhs, rhs) = (lhs as i64, rhs as i64);
+    let value = match op {
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        // Division by zero is left for the program to hit at run time
+        BinOp::Div => lhs.checked_div(rhs)?,
+        // Only the low six bits of the count matter, as with the shift instructions
+        BinOp::Shl => lhs.wrapping_shl(rhs as u32),
+        BinOp::Shr => lhs.wrapping_shr(rhs as u32),
+        BinOp::And => lhs & rhs,
+        BinOp::Or => lhs | rhs,
+        BinOp::Xor => lhs ^ rhs,
+        BinOp::Eq => (lhs == rhs) as i64,
+        BinOp::Ne => (lhs != rhs) as i64,
+        BinOp::Lt => (lhs < rhs) as i64,
+        BinOp::Le => (lhs <= rhs) as i64,
+        BinOp::Gt => (lhs > rhs) as i64,
+        BinOp::Ge => (lhs >= rhs) as i64,
+        BinOp::UDiv => (lhs as u64).checked_div(rhs as u64)? as i64,
+        BinOp::UShr => (lhs as u64).wrapping_shr(rhs as u32) as i64,
+        BinOp::ULt => ((lhs as u64) < rhs as u64) as i64,
+        BinOp::ULe => (lhs as u64 <= rhs as u64) as i64,
+        BinOp::UGt => (lhs as u64 > rhs as u64) as i64,
+        BinOp::UGe => (lhs as u64 >= rhs as u64) as i64,
+    };
+    Some(value as u64)
+}
+
+pub fn evaluate_unary(op: &UnaryOp, operand: u64) -> u64 {
+    match op {
+        UnaryOp::Neg => operand.wrapping_neg(),
+        UnaryOp::Not => (operand == 0) as u64,
+        UnaryOp::BitNot => !operand,
+        UnaryOp::Sext8 => operand as i8 as u64,
+        UnaryOp::Sext32 => operand as i32 as u64,
+        UnaryOp::Zext32 => operand as u32 as u64,
+    }
+}
+
+// The constant a statement computes, if all of its operands are constants
+fn fold(statement: &Statement, constants: &HashMap<VReg, u64>) -> Option<u64> {
+    match statement {
+        Statement::Copy { src, .. } => constants.get(src).copied(),
+        Statement::Operation { op, lhs, rhs, .. } => {
+            evaluate_binary(op, *constants.get(lhs)?, *constants.get(rhs)?)
+        }
+        Statement::UnaryOperation { op, operand, .. } => {
+            Some(evaluate_unary(op, *constants.get(operand)?))
+        }
+        _ => None,
+    }
+}
+
+/*
+ * Replaces each copy and operation whose operands are all constants with an Assign of the
+ * result. A folded var can make others constant in turn, so this repeats until nothing
+ * changes. Like the other passes, returns whether it changed anything.
+ */
+pub fn fold_constants(function: &mut Function) -> bool {
+    let mut folded_any = false;
+    loop {
+        let constants = constants(function);
+        let mut changed = false;
+        for statement in function.cfg.values_mut().flatten() {
+            if let Some(value) = fold(statement, &constants) {
+                log::debug!("folded `{}` to {}", statement, value);
+                let var = *statement.defined_var().expect("only definitions fold");
+                let value = value as i64;
+                *statement = Statement::Assign { var, value };
+                changed = true;
+            }
+        }
+        if !changed {
+            return folded_any;
+        }
+        folded_any = true;
+    }
+}
+
+// What conditional constant propagation knows about an SSA var
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Unknown, // its definition hasn't been reached yet
+    Constant(u64),
+    Varying,
+}
+
+impl Value {
+    fn meet(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Unknown, v) | (v, Value::Unknown) => v,
+            (Value::Constant(a), Value::Constant(b)) if a == b => Value::Constant(a),
+            _ => Value::Varying,
+        }
+    }
+}
+
+type Values = HashMap<VReg, Value>;
+
+fn value_of(values: &Values, var: &VReg) -> Value {
+    values.get(var).copied().unwrap_or(Value::Unknown)
+}
+
+// Combines operand values, evaluating only once they're all constant
+fn combine(operands: &[Value], evaluate: impl FnOnce(&[u64]) -> Option<u64>) -> Value {
+    if operands.contains(&Value::Varying) {
+        return Value::Varying;
+    }
+    let constants: Option<Vec<u64>> = operands
+        .iter()
+        .map(|v| match v {
+            Value::Constant(c) => Some(*c),
+            _ => None,
+        })
+        .collect();
+    match constants {
+        Some(constants) => evaluate(&constants).map_or(Value::Varying, Value::Constant),
+        None => Value::Unknown,
+    }
+}
+
+// The value a statement other than a phi gives the var it defines
+fn evaluate(statement: &Statement, values: &Values) -> Value {
+    match statement {
+        Statement::Assign { value, .. } => Value::Constant(*value as u64),
+        Statement::Copy { src, .. } => value_of(values, src),
+        Statement::Operation { op, lhs, rhs, .. } => {
+            combine(&[value_of(values, lhs), value_of(values, rhs)], |c| {
+                evaluate_binary(op, c[0], c[1])
+            })
+        }
+        Statement::UnaryOperation { op, operand, .. } => {
+            combine(&[value_of(values, operand)], |c| {
+                Some(evaluate_unary(op, c[0]))
+            })
+        }
+        // Memory isn't tracked, so nothing is known about a load
+        _ => Value::Varying,
+    }
+}
+
+// The successors control can actually take out of a block ending in `terminator`
+fn executable_successors(terminator: Option<&Statement>, values: &Values) -> Vec<ControlBlockId> {
+    match terminator {
+        Some(Statement::If {
+            var,
+            goto_true,
+            goto_false,
+        }) => match value_of(values, var) {
+            Value::Unknown => vec![],
+            Value::Constant(0) => vec![*goto_false],
+            Value::Constant(_) => vec![*goto_true],
+            Value::Varying => vec![*goto_true, *goto_false],
+        },
+        Some(Statement::Switch {
+            var,
+            targets,
+            default,
+        }) => match value_of(values, var) {
+            Value::Unknown => vec![],
+            Value::Constant(index) => {
+                let target = usize::try_from(index).ok().and_then(|i| targets.get(i));
+                vec![*target.unwrap_or(default)]
+            }
+            Value::Varying => targets.iter().chain([default]).copied().collect(),
+        },
+        Some(terminator) => terminator.successors(),
+        None => vec![],
+    }
+}
+
+// Sparse conditional constant propagation over a function in SSA form
+struct Propagation<'a> {
+    cfg: &'a ControlFlowGraph,
+    uses: HashMap<VReg, Vec<(ControlBlockId, usize)>>,
+    values: Values,
+    edges: HashSet<(ControlBlockId, ControlBlockId)>, // executable edges
+    executable: HashSet<ControlBlockId>,
+    flow_worklist: Vec<(ControlBlockId, ControlBlockId)>,
+    ssa_worklist: Vec<(ControlBlockId, usize)>,
+}
+
+impl<'a> Propagation<'a> {
+    fn run(cfg: &'a ControlFlowGraph) -> Self {
+        let mut uses: HashMap<VReg, Vec<(ControlBlockId, usize)>> = HashMap::new();
+        for (block, statements) in cfg.iter() {
+            for (i, statement) in statements.iter().enumerate() {
+                for var in statement.used_vars() {
+                    uses.entry(*var).or_default().push((*block, i));
+                }
+            }
+        }
+        let mut propagation = Propagation {
+            cfg,
+            uses,
+            values: HashMap::new(),
+            edges: HashSet::new(),
+            executable: HashSet::from([0]),
+            flow_worklist: vec![],
+            ssa_worklist: vec![],
+        };
+        for i in 0..cfg[&0].len() {
+            propagation.visit(0, i);
+        }
+        loop {
+            if let Some((from, to)) = propagation.flow_worklist.pop() {
+                propagation.visit_edge(from, to);
+            } else if let Some((block, i)) = propagation.ssa_worklist.pop() {
+                if propagation.executable.contains(&block) {
+                    propagation.visit(block, i);
+                }
+            } else {
+                return propagation;
+            }
+        }
+    }
+
+    // The first time an edge is taken its target's phis gain a source, and the first time a
+    // block is reached everything in it is visited
+    fn visit_edge(&mut self, from: ControlBlockId, to: ControlBlockId) {
+        if !self.edges.insert((from, to)) {
+            return;
+        }
+        let statements = &self.cfg[&to];
+        if self.executable.insert(to) {
+            for i in 0..statements.len() {
+                self.visit(to, i);
+            }
+        } else {
+            let phis = statements
+                .iter()
+                .take_while(|s| matches!(s, Statement::Phi { .. }))
+                .count();
+            for i in 0..phis {
+                self.visit(to, i);
+            }
+        }
+    }
+
+    fn visit(&mut self, block: ControlBlockId, i: usize) {
+        let statement = &self.cfg[&block][i];
+        if let Statement::If { .. } | Statement::Switch { .. } | Statement::Goto(_) = statement {
+            for successor in executable_successors(Some(statement), &self.values) {
+                if !self.edges.contains(&(block, successor)) {
+                    self.flow_worklist.push((block, successor));
+                }
+            }
+            return;
+        }
+        let Some(var) = statement.defined_var() else {
+            return;
+        };
+        let value = match statement {
+            Statement::Phi { sources, .. } => sources
+                .iter()
+                .filter(|(source, _)| self.edges.contains(&(*source, block)))
+                .map(|(_, var)| value_of(&self.values, var))
+                .fold(Value::Unknown, Value::meet),
+            _ => evaluate(statement, &self.values),
+        };
+        if value_of(&self.values, var) != value {
+            self.values.insert(*var, value);
+            if let Some(uses) = self.uses.get(var) {
+                self.ssa_worklist.extend(uses);
+            }
+        }
+    }
+}
+
+/*
+ * Conditional constant propagation. Unlike `fold_constants`, this follows each var's value
+ * through the blocks, so a var assigned different constants in different places is still
+ * known wherever only one of them reaches. Only blocks reachable given what's known so far
+ * are visited, so a branch on a constant doesn't let the untaken side spoil the values at
+ * the join.
+ *
+ * This is Wegman and Zadeck's sparse version, run on an SSA copy of the function: a var's
+ * uses are only revisited when its value changes, and a phi only meets the values coming
+ * in over edges known to be taken. Vars read before any assignment, parameters among them,
+ * get a definition at the top of the copy so every use has one, which evaluates as
+ * varying. The copy keeps each block's statements in order after its phis, so the results
+ * map straight back: constant computations become Assigns, branches on constants become
+ * Gotos, and blocks that can no longer be reached are removed.
+ */
+pub fn propagate_constants(function: &mut Function) -> bool {
+    let cfg = &mut function.cfg;
+    let mut ssa = cfg.clone();
+    let undefined: Vec<VReg> = Liveness::analyze(&ssa).live_in[&0]
+        .iter()
+        .copied()
+        .collect();
+    let entry = ssa.get_mut(&0).expect("every CFG has an entry block");
+    entry.splice(
+        0..0,
+        undefined.iter().map(|var| Statement::Alloca {
+            dest: *var,
+            size: 0,
+        }),
+    );
+    ssa::construct(&mut ssa).expect("every var has a definition on entry");
+    let propagation = Propagation::run(&ssa);
+
+    let mut changed = false;
+    for (block, statements) in cfg.iter_mut() {
+        if !propagation.executable.contains(block) {
+            continue;
+        }
+        let renamed = &ssa[block][ssa[block].len() - statements.len()..];
+        for (statement, renamed) in statements.iter_mut().zip(renamed) {
+            if let Statement::If { .. } | Statement::Switch { .. } = statement
+                && let [target] = executable_successors(Some(renamed), &propagation.values)[..]
+            {
+                log::debug!("`{}` always goes to block {}", statement, target);
+                *statement = Statement::Goto(target);
+                changed = true;
+            }
+            let folds = matches!(
+                statement,
+                Statement::Copy { .. }
+                    | Statement::Operation { .. }
+                    | Statement::UnaryOperation { .. }
+            );
+            if let (true, Some(var), Some(renamed)) =
+                (folds, statement.defined_var(), renamed.defined_var())
+                && let Value::Constant(value) = value_of(&propagation.values, renamed)
+            {
+                let value = value as i64;
+                log::debug!("`{}` is always {}", statement, value);
+                *statement = Statement::Assign { var: *var, value };
+                changed = true;
+            }
+        }
+    }
+    remove_unreachable_blocks(cfg) || changed
+}
+
+// Blocks that control can reach from the entry block
+fn reachable_blocks(cfg: &ControlFlowGraph) -> HashSet<ControlBlockId> {
+    let mut reachable = HashSet::from([0]);
+    let mut worklist = vec![0];
+    while let Some(block) = worklist.pop() {
+        for successor in cfg.successors(block) {
+            if reachable.insert(successor) {
+                worklist.push(successor);
+            }
+        }
+    }
+    reachable
+}
+
+// Returns whether there were any to remove
+fn remove_unreachable_blocks(cfg: &mut ControlFlowGraph) -> bool {
+    let reachable = reachable_blocks(cfg);
+    if reachable.len() == cfg.len() {
+        return false;
+    }
+    cfg.retain(|block, _| {
+        let keep = reachable.contains(block);
+        if !keep {
+            log::debug!("removed unreachable block {}", block);
+        }
+        keep
+    });
+    for statement in cfg.values_mut().flatten() {
+        if let Statement::Phi { sources, .. } = statement {
+            sources.retain(|(block, _)| reachable.contains(block));
+        }
+    }
+    true
+}
+
+/*
+ * Removes blocks that can't be reached from the entry block, then statements that only
+ * define a var nothing reads. Removing a statement can leave the vars it read unused, so
+ * that repeats until nothing changes. Calls stay regardless, since they may have side
+ * effects.
+ */
+pub fn eliminate_dead_code(function: &mut Function) -> bool {
+    let cfg = &mut function.cfg;
+    let mut removed_any = remove_unreachable_blocks(cfg);
+
+    loop {
+        let used: HashSet<VReg> = cfg
+            .values()
+            .flatten()
+            .flat_map(Statement::used_vars)
+            .cloned()
+            .collect();
+        let mut changed = false;
+        for block in cfg.values_mut() {
+            let before = block.len();
+            block.retain(|s| {
+                let live = matches!(s, Statement::Call { .. })
+                    || s.defined_var().is_none_or(|var| used.contains(var));
+                if !live {
+                    log::debug!("removed dead `{}`", s);
+                }
+                live
+            });
+            changed |= block.len() != before;
+        }
+        if !changed {
+            return removed_any;
+        }
+        removed_any = true;
+    }
+}
+
+fn has_phis(block: &ControlBlock) -> bool {
+    matches!(block.first(), Some(Statement::Phi { .. }))
+}
+
+// Points a branch that goes to `from` at `to` instead
+fn retarget(statement: &mut Statement, from: ControlBlockId, to: ControlBlockId) {
+    match statement {
+        Statement::Goto(target) if *target == from => *target = to,
+        Statement::If {
+            goto_true,
+            goto_false,
+            ..
+        } => {
+            for target in [goto_true, goto_false] {
+                if *target == from {
+                    *target = to;
+                }
+            }
+        }
+        Statement::Switch {
+            targets, default, ..
+        } => {
+            for target in targets.iter_mut().chain([default]) {
+                if *target == from {
+                    *target = to;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// Where control ends up after passing through any blocks that do nothing but jump. Line
+// markers generate no code, so a block with them still counts.
+fn thread_target(cfg: &ControlFlowGraph, mut block: ControlBlockId) -> ControlBlockId {
+    let mut seen = HashSet::new();
+    while block != 0
+        && seen.insert(block)
+        && let [lines @ .., Statement::Goto(next)] = &cfg[&block][..]
+        && lines.iter().all(|s| matches!(s, Statement::Line(_)))
+        && !has_phis(&cfg[next])
+    {
+        block = *next;
+    }
+    block
+}
+
+/*
+ * Tidies up the shape of the graph without changing what it computes:
+ *  - branches to a block that only jumps elsewhere go straight to where it jumps, and an
+ *    If or Switch whose targets end up all the same becomes a Goto
+ *  - a block that jumps to a block nothing else jumps to absorbs it
+ * Blocks that start with phis are left alone, since both of these change their
+ * predecessors. The entry block is never threaded through or absorbed.
+ */
+pub fn simplify_cfg(function: &mut Function) -> bool {
+    let cfg = &mut function.cfg;
+    let mut changed = false;
+
+    let ids: Vec<ControlBlockId> = cfg.keys().copied().collect();
+    for id in &ids {
+        let Some(terminator) = cfg[id].last().cloned() else {
+            continue;
+        };
+        let mut updated = terminator.clone();
+        for successor in terminator.successors() {
+            retarget(&mut updated, successor, thread_target(cfg, successor));
+        }
+        if let Statement::If {
+            goto_true,
+            goto_false,
+            ..
+        } = updated
+            && goto_true == goto_false
+        {
+            updated = Statement::Goto(goto_true);
+        }
+        if let Statement::Switch {
+            targets, default, ..
+        } = &updated
+            && targets.iter().all(|target| target == default)
+        {
+            updated = Statement::Goto(*default);
+        }
+        if updated != terminator {
+            log::debug!("rewrote `{}` as `{}`", terminator, updated);
+            let block = cfg
+                .get_mut(id)
+                .expect("terminators come from blocks in the CFG");
+            *block
+                .last_mut()
+                .expect("a block with a terminator isn't empty") = updated;
+            changed = true;
+        }
+    }
+    changed |= remove_unreachable_blocks(cfg);
+
+    let ids: Vec<ControlBlockId> = cfg.keys().copied().collect();
+    for id in ids {
+        // A block absorbed earlier in this loop is gone
+        if !cfg.contains_key(&id) {
+            continue;
+        }
+        loop {
+            let predecessors = cfg.predecessors();
+            let Some(Statement::Goto(next)) = cfg[&id].last() else {
+                break;
+            };
+            let next = *next;
+            if next == 0 || next == id || predecessors[&next] != [id] || has_phis(&cfg[&next]) {
+                break;
+            }
+            log::debug!("merged block {} into block {}", next, id);
+            let absorbed = cfg.remove(&next).expect("a successor is in the CFG");
+            let block = cfg.get_mut(&id).expect("the block absorbing is in the CFG");
+            block.pop();
+            block.extend(absorbed);
+            // Phis after the absorbed block now see control arrive from this one
+            for statement in cfg.values_mut().flatten() {
+                if let Statement::Phi { sources, .. } = statement {
+                    for (from, _) in sources.iter_mut() {
+                        if *from == next {
+                            *from = id;
+                        }
+                    }
+                }
+            }
+            changed = true;
+        }
+    }
+    changed
+}
+
+// A pure computation, with its operands as they'd be after earlier eliminations
+#[derive(Clone, Eq, Hash, PartialEq)]
+enum Expression {
+    Binary(BinOp, VReg, VReg),
+    Unary(UnaryOp, VReg),
+    Address(Symbol),
+}
+
+fn is_commutative(op: &BinOp) -> bool {
+    matches!(
+        op,
+        BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor | BinOp::Eq | BinOp::Ne
+    )
+}
+
+// The expression a statement computes, if it computes one that can be reused. Loads aren't,
+// since a store in between could change what they read.
+fn expression(statement: &Statement, replacements: &HashMap<VReg, VReg>) -> Option<Expression> {
+    let current = |var: &VReg| *replacements.get(var).unwrap_or(var);
+    match statement {
+        Statement::Operation { op, lhs, rhs, .. } => {
+            let (lhs, rhs) = (current(lhs), current(rhs));
+            if is_commutative(op) && rhs < lhs {
+                Some(Expression::Binary(op.clone(), rhs, lhs))
+            } else {
+                Some(Expression::Binary(op.clone(), lhs, rhs))
+            }
+        }
+        Statement::UnaryOperation { op, operand, .. } => {
+            Some(Expression::Unary(op.clone(), current(operand)))
+        }
+        Statement::LoadAddress { label, .. } => Some(Expression::Address(*label)),
+        _ => None,
+    }
+}
+
+fn operands(expression: &Expression) -> Vec<VReg> {
+    match expression {
+        Expression::Binary(_, lhs, rhs) => vec![*lhs, *rhs],
+        Expression::Unary(_, operand) => vec![*operand],
+        Expression::Address(_) => vec![],
+    }
+}
+
+// Vars defined exactly once, counting parameters as defined on entry. Like vars in SSA
+// form, each holds the same value everywhere its definition dominates.
+fn single_definitions(function: &Function) -> HashSet<VReg> {
+    let mut count: HashMap<VReg, usize> = function.params.iter().map(|p| (*p, 1)).collect();
+    for statement in function.cfg.values().flatten() {
+        if let Some(var) = statement.defined_var() {
+            *count.entry(*var).or_default() += 1;
+        }
+    }
+    count
+        .into_iter()
+        .filter(|(_, n)| *n == 1)
+        .map(|(var, _)| var)
+        .collect()
+}
+
+struct Availability<'a> {
+    cfg: &'a ControlFlowGraph,
+    children: BTreeMap<ControlBlockId, Vec<ControlBlockId>>,
+    values: HashSet<VReg>,
+    // Each expression computed in a block dominating the current one, with the var holding it
+    available: HashMap<Expression, VReg>,
+    // Vars whose definitions repeat an available expression, mapped to the var to use instead
+    replacements: HashMap<VReg, VReg>,
+}
+
+impl Availability<'_> {
+    // Visits the dominator tree depth first, so whatever is available in a block is
+    // available in the blocks it dominates, and only there
+    fn visit(&mut self, block: ControlBlockId) {
+        let mut added = vec![];
+        for statement in &self.cfg[&block] {
+            let Some(dest) = statement.defined_var() else {
+                continue;
+            };
+            let Some(expression) = expression(statement, &self.replacements) else {
+                continue;
+            };
+            if !self.values.contains(dest)
+                || !operands(&expression)
+                    .iter()
+                    .all(|v| self.values.contains(v))
+            {
+                continue;
+            }
+            match self.available.get(&expression) {
+                Some(earlier) => {
+                    log::debug!("`{}` repeats {}", statement, earlier);
+                    self.replacements.insert(*dest, *earlier);
+                }
+                None => {
+                    self.available.insert(expression.clone(), *dest);
+                    added.push(expression);
+                }
+            }
+        }
+        for child in self.children.get(&block).cloned().unwrap_or_default() {
+            self.visit(child);
+        }
+        for expression in added {
+            self.available.remove(&expression);
+        }
+    }
+}
+
+/*
+ * Common subexpression elimination. An operation that repeats one computed earlier, in the
+ * same block or one dominating it, is removed and its uses read the earlier result instead.
+ *
+ * Only vars with a single definition take part, which in SSA form is all of them. Such a
+ * var's definition dominates its uses, so an earlier computation from the same operands
+ * still holds the same value wherever a later one does.
+ */
+pub fn eliminate_common_subexpressions(function: &mut Function) -> bool {
+    let mut children: BTreeMap<ControlBlockId, Vec<ControlBlockId>> = BTreeMap::new();
+    for (block, idom) in ssa::dominators(&function.cfg) {
+        if block != idom {
+            children.entry(idom).or_default().push(block);
+        }
+    }
+    let mut availability = Availability {
+        cfg: &function.cfg,
+        children,
+        values: single_definitions(function),
+        available: HashMap::new(),
+        replacements: HashMap::new(),
+    };
+    availability.visit(0);
+    let replacements = availability.replacements;
+    if replacements.is_empty() {
+        return false;
+    }
+
+    for block in function.cfg.values_mut() {
+        block.retain(|s| {
+            s.defined_var()
+                .is_none_or(|var| !replacements.contains_key(var))
+        });
+        for var in block.iter_mut().flat_map(Statement::used_vars_mut) {
+            if let Some(earlier) = replacements.get(var) {
+                *var = *earlier;
+            }
+        }
+    }
+    true
+}
+
+// The uses of slot addresses in a statement other than as the address of a whole-word load
+// or store, or as the source of a copy into another address. Any of those lets the address
+// escape, or reads or writes the slot in a way a var can't stand in for.
+fn escaping_addresses(statement: &Statement, addresses: &HashMap<VReg, VReg>) -> Vec<VReg> {
+    let uses = match statement {
+        Statement::Load {
+            width: Width::I64, ..
+        } => vec![],
+        Statement::Store {
+            src,
+            width: Width::I64,
+            ..
+        } => vec![src],
+        Statement::Copy { dest, .. } if addresses.contains_key(dest) => vec![],
+        statement => statement.used_vars(),
+    };
+    uses.into_iter()
+        .filter(|var| addresses.contains_key(var))
+        .copied()
+        .collect()
+}
+
+/*
+ * Promotes stack slots back to vars. A slot whose address is only ever loaded from and
+ * stored to, a whole word at a time, can't be reached any other way, so the var its Alloca
+ * defined can hold the value instead: stores become copies into that var and loads copies
+ * out of it. Single-definition vars copied from the address count as the address too,
+ * which is what inlining leaves behind when a function is passed a pointer to a local.
+ *
+ * The var ends up with a definition for each store, the same as a variable that never had
+ * its address taken, and `ssa::construct` merges them with phis like any other. The Alloca
+ * itself becomes an assignment of 0, so every path to a load still defines the var.
+ */
+pub fn promote_slots(function: &mut Function) -> bool {
+    let values = single_definitions(function);
+    // Each var holding the address of an 8-byte slot, mapped to the slot's Alloca var
+    let mut addresses: HashMap<VReg, VReg> = HashMap::new();
+    for statement in function.cfg.values().flatten() {
+        if let Statement::Alloca { dest, size: 8 } = statement
+            && values.contains(dest)
+        {
+            addresses.insert(*dest, *dest);
+        }
+    }
+    loop {
+        let copies: Vec<(VReg, VReg)> = function
+            .cfg
+            .values()
+            .flatten()
+            .filter_map(|s| match s {
+                Statement::Copy { dest, src } if values.contains(dest) => {
+                    Some((*dest, *addresses.get(src)?))
+                }
+                _ => None,
+            })
+            .filter(|(dest, _)| !addresses.contains_key(dest))
+            .collect();
+        if copies.is_empty() {
+            break;
+        }
+        addresses.extend(copies);
+    }
+
+    let escaped: HashSet<VReg> = function
+        .cfg
+        .values()
+        .flatten()
+        .flat_map(|s| escaping_addresses(s, &addresses))
+        .map(|var| addresses[&var])
+        .collect();
+    addresses.retain(|_, slot| !escaped.contains(slot));
+    if addresses.is_empty() {
+        return false;
+    }
+
+    for block in function.cfg.values_mut() {
+        let statements = std::mem::take(block);
+        *block = statements
+            .into_iter()
+            .filter_map(|statement| match statement {
+                Statement::Alloca { dest, .. } if addresses.contains_key(&dest) => {
+                    log::debug!("promoted the slot in {}", dest);
+                    Some(Statement::Assign {
+                        var: dest,
+                        value: 0,
+                    })
+                }
+                Statement::Copy { dest, .. } if addresses.contains_key(&dest) => None,
+                Statement::Load { dest, addr, .. } if addresses.contains_key(&addr) => {
+                    Some(Statement::Copy {
+                        dest,
+                        src: addresses[&addr],
+                    })
+                }
+                Statement::Store { addr, src, .. } if addresses.contains_key(&addr) => {
+                    Some(Statement::Copy {
+                        dest: addresses[&addr],
+                        src,
+                    })
+                }
+                statement => Some(statement),
+            })
+            .collect();
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_cfg;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+
+    fn lower_function(source: &str, function: &str) -> Result<Function, String> {
+        let ast = parse(&tokenize(source)?)?;
+        let mut program = Program::from(&ast)?;
+        program
+            .functions
+            .remove(&Symbol::intern(function))
+            .ok_or(format!("Missing {}", function))
+    }
+
+    #[test]
+    fn test_fold_constants() -> Result<(), String> {
+        let mut function = lower_function(
+            "int main() { int x = 2; int y = -x * (x + 3); return (y < 0) + y / 0; }",
+            "main",
+        )?;
+        fold_constants(&mut function);
+        let assign = |var: u32, value: i64| Statement::Assign {
+            var: VReg(var),
+            value,
+        };
+        let expected = vec![
+            assign(1, 2),
+            assign(2, -2),
+            assign(3, 3),
+            assign(4, 5),
+            assign(5, -10),
+            assign(6, 0),
+            assign(7, 1),
+            assign(8, 0),
+            // Dividing by zero is left alone, along with everything that depends on it
+            Statement::Operation {
+                dest: VReg(9),
+                op: BinOp::Div,
+                lhs: VReg(5),
+                rhs: VReg(8),
+            },
+            Statement::Operation {
+                dest: VReg(10),
+                op: BinOp::Add,
+                lhs: VReg(7),
+                rhs: VReg(9),
+            },
+            Statement::Return(Some(VReg(10))),
+        ];
+        assert_eq!(function.cfg[&0], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_constants_reassigned() -> Result<(), String> {
+        // x is assigned twice and a is a parameter, so neither has a single known value
+        let mut function = lower_function(
+            "int f(int a) { int x = 1; x = 2; return x + a; } int main() { return 0; }",
+            "f",
+        )?;
+        fold_constants(&mut function);
+        let expected = vec![
+            Statement::Assign {
+                var: VReg(2),
+                value: 1,
+            },
+            Statement::Assign {
+                var: VReg(3),
+                value: 2,
+            },
+            Statement::Assign {
+                var: VReg(2),
+                value: 2,
+            },
+            Statement::Operation {
+                dest: VReg(4),
+                op: BinOp::Add,
+                lhs: VReg(2),
+                rhs: VReg(1),
+            },
+            Statement::Return(Some(VReg(4))),
+        ];
+        assert_eq!(function.cfg[&0], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eliminate_dead_code() -> Result<(), String> {
+        let mut function = lower_function(
+            "int f() { return 1; } \
+             int main() { int x = 2; int y = x * 3; f(); \
+             if (x) { return x + 1; } else { return 0; } }",
+            "main",
+        )?;
+        fold_constants(&mut function);
+        eliminate_dead_code(&mut function);
+
+        // Both branches return, so the join block is unreachable. y is never read, and
+        // once its multiplication is gone neither is the 3, but the call stays.
+        let expected = ControlFlowGraph(BTreeMap::from([
+            (
+                0,
+                vec![
+                    Statement::Assign {
+                        var: VReg(1),
+                        value: 2,
+                    },
+                    Statement::Call {
+                        dest: VReg(4),
+                        func: "f".into(),
+                        args: vec![],
+                        tail: false,
+                    },
+                    Statement::If {
+                        var: VReg(1),
+                        goto_true: 1,
+                        goto_false: 2,
+                    },
+                ],
+            ),
+            (
+                1,
+                vec![
+                    Statement::Assign {
+                        var: VReg(6),
+                        value: 3,
+                    },
+                    Statement::Return(Some(VReg(6))),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    Statement::Assign {
+                        var: VReg(7),
+                        value: 0,
+                    },
+                    Statement::Return(Some(VReg(7))),
+                ],
+            ),
+        ]));
+        assert_eq!(function.cfg, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_constants() -> Result<(), String> {
+        // The else branch can never run, so x is still known to be 4 after the if, and the
+        // second branch folds away too
+        let mut function = lower_function(
+            "int main() { int x = 1; if (x == 1) { x = 4; } else { x = 5; } \
+             if (x) { return x * 2; } return 0; }",
+            "main",
+        )?;
+        propagate_constants(&mut function);
+        eliminate_dead_code(&mut function);
+        let control_flow = |block: ControlBlockId| {
+            function.cfg[&block]
+                .iter()
+                .filter(|s| s.defined_var().is_none())
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let blocks: Vec<&ControlBlockId> = function.cfg.keys().collect();
+        assert_eq!(blocks, vec![&0, &1, &3, &4]);
+        assert_eq!(control_flow(0), vec![Statement::Goto(1)]);
+        assert_eq!(control_flow(1), vec![Statement::Goto(3)]);
+        assert_eq!(control_flow(3), vec![Statement::Goto(4)]);
+        assert_eq!(
+            function.cfg[&4],
+            vec![
+                Statement::Assign {
+                    var: VReg(7),
+                    value: 8,
+                },
+                Statement::Return(Some(VReg(7))),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_constants_loop() -> Result<(), String> {
+        // x changes on each trip around the loop, so the condition can't be folded
+        let mut function = lower_function(
+            "int main() { int x = 0; while (x == 0) { x = x + 1; } return x; }",
+            "main",
+        )?;
+        propagate_constants(&mut function);
+        assert!(matches!(
+            function.cfg[&1].last(),
+            Some(Statement::If { .. })
+        ));
+        assert_eq!(function.cfg[&3], vec![Statement::Return(Some(VReg(1)))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_constants_undefined() -> Result<(), String> {
+        // n is a parameter and x has no assignment before the loop, so neither has a
+        // definition on entry, but y is 2 wherever it's read
+        let mut function = lower_function(
+            "int f(int n) { int x; int y = 2; while (n > 0) { x = n; y = 2; n = n - 1; } \
+             return x + y * 3; }",
+            "f",
+        )?;
+        assert!(propagate_constants(&mut function));
+        let assigned: Vec<i64> = function
+            .cfg
+            .values()
+            .flatten()
+            .filter_map(|s| match s {
+                Statement::Assign { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert!(assigned.contains(&6));
+        assert!(
+            function
+                .cfg
+                .values()
+                .flatten()
+                .any(|s| matches!(s, Statement::If { .. }))
+        );
+        Ok(())
+    }
+
+    fn function(text: &str) -> Result<Function, String> {
+        Ok(Function {
+            params: vec![],
+            cfg: parse_cfg(text)?,
+            inline: false,
+        })
+    }
+
+    #[test]
+    fn test_simplify_cfg() -> Result<(), String> {
+        // bb2 and bb3 only pass control along, and bb4 then has a single predecessor
+        let mut f = function(
+            "bb0: v1 = 1; if v1 goto bb1 else bb2\n\
+             bb1: v2 = 2; goto bb2\n\
+             bb2: goto bb3\n\
+             bb3: goto bb4\n\
+             bb4: ret v1",
+        )?;
+        assert!(simplify_cfg(&mut f));
+        assert_eq!(
+            f.cfg,
+            parse_cfg("bb0: v1 = 1; if v1 goto bb1 else bb4\nbb1: v2 = 2; goto bb4\nbb4: ret v1")?
+        );
+        assert!(!simplify_cfg(&mut f));
+
+        // Once both branches lead to the same place, everything merges into the entry
+        let mut f = function(
+            "bb0: v1 = 1; if v1 goto bb1 else bb2\n\
+             bb1: goto bb3\n\
+             bb2: goto bb3\n\
+             bb3: v2 = 2; goto bb4\n\
+             bb4: ret v2",
+        )?;
+        assert!(simplify_cfg(&mut f));
+        assert_eq!(f.cfg, parse_cfg("bb0: v1 = 1; v2 = 2; ret v2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_constant() -> Result<(), String> {
+        // A known index picks its target, and one past the end the default
+        for (index, target) in [(1, 2), (2, 3), (-1, 3)] {
+            let mut f = function(&format!(
+                "bb0: v1 = {}; switch v1 [bb1, bb2] else bb3\n\
+                 bb1: ret v1\n\
+                 bb2: goto bb3\n\
+                 bb3: ret",
+                index
+            ))?;
+            propagate_constants(&mut f);
+            assert_eq!(f.cfg[&0].last(), Some(&Statement::Goto(target)));
+        }
+
+        // Once every target is the same block, so is where the switch goes
+        let mut f = function(
+            "bb0: v1 = 1; switch v1 [bb1, bb2] else bb2\n\
+             bb1: goto bb2\n\
+             bb2: ret v1",
+        )?;
+        assert!(simplify_cfg(&mut f));
+        assert_eq!(f.cfg, parse_cfg("bb0: v1 = 1; ret v1")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_simplify_cfg_keeps_phis_and_loops() -> Result<(), String> {
+        let text = "bb0: v1 = 1; if v1 goto bb1 else bb2\n\
+                    bb1: goto bb2\n\
+                    bb2: v2 = phi [bb0: v1, bb1: v1]; goto bb3\n\
+                    bb3: goto bb3";
+        let mut f = function(text)?;
+        assert!(!simplify_cfg(&mut f));
+        assert_eq!(f.cfg, parse_cfg(text)?);
+        Ok(())
+    }
+
+    fn with_params(params: &[u32], text: &str) -> Result<Function, String> {
+        Ok(Function {
+            params: params.iter().map(|n| VReg(*n)).collect(),
+            cfg: parse_cfg(text)?,
+            inline: false,
+        })
+    }
+
+    fn statement_count(function: &Function) -> usize {
+        function.cfg.values().map(Vec::len).sum()
+    }
+
+    #[test]
+    fn test_cse_local() -> Result<(), String> {
+        // Operands in either order of a commutative op are the same computation
+        let mut f = with_params(
+            &[1, 2],
+            "bb0: v3 = v1 * v2; v4 = v2 * v1; v5 = -v1; v6 = -v1; v7 = addr g; v8 = addr g;\n\
+             v9 = v3 + v4; v10 = v5 - v6; v11 = v4 + v3; v12 = call f(v7, v8, v9, v10, v11); ret v12",
+        )?;
+        assert!(eliminate_common_subexpressions(&mut f));
+        assert_eq!(
+            f.cfg,
+            parse_cfg(
+                "bb0: v3 = v1 * v2; v5 = -v1; v7 = addr g; v9 = v3 + v3; v10 = v5 - v5;\n\
+                 v12 = call f(v7, v7, v9, v10, v9); ret v12"
+            )?
+        );
+        assert_eq!(statement_count(&f), 7);
+        assert!(!eliminate_common_subexpressions(&mut f));
+
+        // Not so for subtraction, for reassigned operands, or for loads
+        let text = "bb0: v3 = v1 - v2; v4 = v2 - v1; v5 = v1 + v2; v1 = 1; v6 = v1 + v2;\n\
+                    v7 = load v2; store v2, v1; v8 = load v2; ret v8";
+        let mut f = with_params(&[1, 2], text)?;
+        assert!(!eliminate_common_subexpressions(&mut f));
+        assert_eq!(f.cfg, parse_cfg(text)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cse_global() -> Result<(), String> {
+        // bb0 dominates everything, but neither branch dominates bb3
+        let mut f = with_params(
+            &[1, 2],
+            "bb0: v3 = v1 + v2; if v1 goto bb1 else bb2\n\
+             bb1: v4 = v2 + v1; v5 = v1 < v2; goto bb3\n\
+             bb2: v6 = v1 < v2; goto bb3\n\
+             bb3: v7 = v1 < v2; v8 = v1 + v2; v9 = v7 * v8; ret v9",
+        )?;
+        assert!(eliminate_common_subexpressions(&mut f));
+        assert_eq!(
+            f.cfg,
+            parse_cfg(
+                "bb0: v3 = v1 + v2; if v1 goto bb1 else bb2\n\
+                 bb1: v5 = v1 < v2; goto bb3\n\
+                 bb2: v6 = v1 < v2; goto bb3\n\
+                 bb3: v7 = v1 < v2; v9 = v7 * v3; ret v9"
+            )?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cse_lowered() -> Result<(), String> {
+        let mut f = lower_function(
+            "int f(int a, int b) { int c = (a + b) * (a + b); if (c) return (a + b) - c; return c; }",
+            "f",
+        )?;
+        let operations = |f: &Function| {
+            f.cfg
+                .values()
+                .flatten()
+                .filter(|s| matches!(s, Statement::Operation { .. }))
+                .count()
+        };
+        // The sum is computed three times, with the third in a dominated block
+        assert_eq!(operations(&f), 5);
+        assert!(eliminate_common_subexpressions(&mut f));
+        assert_eq!(operations(&f), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_slots() -> Result<(), String> {
+        // v2 is only loaded and stored, through itself and through the copy in v4
+        let mut f = with_params(
+            &[1],
+            "bb0: v2 = alloca 8; store v1, v2; v4 = v2; if v1 goto bb1 else bb2\n\
+             bb1: v3 = 5; store v3, v4; goto bb2\n\
+             bb2: v5 = load v2; ret v5",
+        )?;
+        assert!(promote_slots(&mut f));
+        assert_eq!(
+            f.cfg,
+            parse_cfg(
+                "bb0: v2 = 0; v2 = v1; if v1 goto bb1 else bb2\n\
+                 bb1: v3 = 5; v2 = v3; goto bb2\n\
+                 bb2: v5 = v2; ret v5"
+            )?
+        );
+        assert!(!promote_slots(&mut f));
+
+        // Not a slot whose address is passed on, stored, or copied into a reassigned var,
+        // nor one read a byte at a time or bigger than a word
+        for text in [
+            "bb0: v2 = alloca 8; v3 = call f(v2); v4 = load v2; ret v4",
+            "bb0: v2 = alloca 8; v3 = alloca 8; store v2, v3; v4 = load v2; ret v4",
+            "bb0: v2 = alloca 8; v3 = v2; v3 = v1; store v1, v3; v4 = load v2; ret v4",
+            "bb0: v2 = alloca 8; store v1, v2; v3 = load8 v2; ret v3",
+            "bb0: v2 = alloca 16; store v1, v2; v3 = load v2; ret v3",
+        ] {
+            let mut f = with_params(&[1], text)?;
+            let promoted = promote_slots(&mut f);
+            // v3 in the second doesn't escape, so only it is promoted
+            assert_eq!(promoted, text.contains("v3 = alloca"), "{}", text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_slots_lowered() -> Result<(), String> {
+        let source = "int main() { int x = 1; int *p = &x; if (x) *p = *p + 2; return x; }";
+        let mut program = Program::from(&parse(&tokenize(source)?)?)?;
+        let main = program
+            .functions
+            .get_mut(&Symbol::intern("main"))
+            .ok_or("Missing main")?;
+        let memory = |f: &Function| {
+            f.cfg
+                .values()
+                .flatten()
+                .filter(|s| matches!(s, Statement::Alloca { .. } | Statement::Load { .. }))
+                .count()
+        };
+        assert_eq!(memory(main), 4);
+        assert!(promote_slots(main));
+        assert_eq!(memory(main), 0);
+        assert_eq!(crate::interp::run(&program, "main")?.result, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use crate::ice;
+use std::panic;
+use std::sync::Mutex;
+use std::thread;
+
+/*
+ * Work spread across threads, for the stages that handle each function on its own: the
+ * optimizer and codegen. `map` hands the items out to up to `jobs` worker threads as they
+ * become free, so one big function doesn't hold up the rest, and gives back the results in
+ * the order of the items, so the output is the same whichever thread finished first.
+ *
+ * The workers are scoped threads started for each stage rather than a pool that outlives
+ * it. Each gets the deep stack the compiler thread has and starts from that thread's ICE
+ * context, so a panic in a worker is reported against the stage and function it was in, and
+ * is carried back to the caller once the other workers are done.
+ */
+
+// Each stage recurses through expressions, so a long chain like 1 + 1 + ... + 1 needs more
+// than the main thread's stack
+pub const STACK_SIZE: usize = 256 << 20;
+
+// How many threads to use when nobody says, one for each core
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// `f` of each item, in order. With one job, or one item, it all runs on the calling thread.
+pub fn map<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let workers = jobs.min(items.len());
+    if workers <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    let count = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let context = ice::current();
+    let worker = || {
+        ice::restore(context.clone());
+        let mut done = vec![];
+        loop {
+            // The lock is only held to take the next item, never while working on one
+            let next = queue
+                .lock()
+                .expect("workers don't panic holding the queue")
+                .next();
+            let Some((i, item)) = next else { break };
+            done.push((i, f(item)));
+        }
+        done
+    };
+    let finished: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                thread::Builder::new()
+                    .stack_size(STACK_SIZE)
+                    .spawn_scoped(scope, worker)
+                    .expect("the system can start a worker thread")
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join()).collect()
+    });
+
+    let mut results: Vec<Option<R>> = (0..count).map(|_| None).collect();
+    for done in finished {
+        // The hook has reported the panic already, so it's passed on without another report
+        let done = done.unwrap_or_else(|payload| panic::resume_unwind(payload));
+        for (i, result) in done {
+            results[i] = Some(result);
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("every item is taken off the queue once"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let items: Vec<u64> = (0..100).collect();
+        let squares: Vec<u64> = items.iter().map(|n| n * n).collect();
+        for jobs in [0, 1, 4, 200] {
+            assert_eq!(map(items.clone(), jobs, |n| n * n), squares);
+        }
+    }
+
+    #[test]
+    fn test_map_panics() {
+        // A worker's panic comes out of map, with what it panicked with
+        let result = panic::catch_unwind(|| {
+            map(vec![1, 2, 3, 4], 2, |n| match n {
+                3 => panic!("three"),
+                n => n,
+            })
+        });
+        let payload = result.expect_err("map panics when a worker does");
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"three"));
+    }
+}
@@ -1,40 +1,125 @@
+use crate::CompileOptions;
 use crate::ast::*;
-use crate::tokenizer::{Token, tokenize};
+use crate::diagnostic::DiagnosticSink;
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
+use crate::tokenizer::Token;
 
 struct Parser<'a> {
     tokens: &'a [Token<'a>],
+    spans: &'a [Span],
     pos: usize,
     scope_id_counter: ScopeIdCounter,
+    node_id_counter: NodeIdCounter,
+    statement_spans: Option<NodeTable<Span>>, // where each statement starts, if it's wanted
+    diagnostics: &'a mut DiagnosticSink,
+    depth: usize, // how many statements and primary expressions are being parsed
 }
 
+// Deeper nesting than this is rejected rather than risking the stack, as with clang's
+// default -fbracket-depth
+const MAX_DEPTH: usize = 256;
+
 impl<'a> Parser<'a> {
-    fn new(tokens: &'a [Token]) -> Self {
+    fn new(
+        tokens: &'a [Token],
+        spans: &'a [Span],
+        options: &CompileOptions,
+        diagnostics: &'a mut DiagnosticSink,
+    ) -> Self {
         Parser {
             tokens,
+            spans,
             pos: 0,
             scope_id_counter: ScopeIdCounter { counter: 0 },
+            node_id_counter: NodeIdCounter { counter: 0 },
+            statement_spans: options.line_info().then(NodeTable::new),
+            diagnostics,
+            depth: 0,
+        }
+    }
+
+    // Runs `parse` one level deeper
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, CompileError>,
+    ) -> Result<T, CompileError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(
+                self.pos,
+                format!("Nesting is deeper than the limit of {}", MAX_DEPTH),
+            ));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Nodes must be created after their children so ids are assigned bottom-up
+    fn expr(&mut self, kind: ExprKind) -> Expr {
+        Expr::new(self.node_id_counter.next(), kind)
+    }
+
+    fn statement(&mut self, kind: StatementKind) -> Statement {
+        Statement::new(self.node_id_counter.next(), kind)
+    }
+
+    // Tokens parsed without location information get an empty span
+    fn span_at(&self, pos: usize) -> Span {
+        self.spans.get(pos).copied().unwrap_or_default()
+    }
+
+    // From the token at `start` to the end of the one before `end`, if there are spans
+    fn span_between(&self, start: usize, end: usize) -> Option<Span> {
+        let (first, last) = self.spans.get(start).zip(self.spans.get(end - 1))?;
+        Some(Span {
+            end: last.end,
+            ..*first
+        })
+    }
+
+    // A scope for `statements`, which were parsed from the tokens `start..end`
+    fn scope(&mut self, statements: Vec<Statement>, start: usize, end: usize) -> Scope {
+        let mut scope = Scope::from_statements(statements, &mut self.scope_id_counter);
+        scope.span = self.span_between(start, end);
+        scope
+    }
+
+    // A ParseError pointing at the token at `pos`
+    fn error(&self, pos: usize, message: String) -> CompileError {
+        CompileError::ParseError {
+            message,
+            span: self.spans.get(pos).copied(),
         }
     }
 
-    fn peek(&self) -> Option<&Token<'a>> {
+    fn peek(&self) -> Option<&'a Token<'a>> {
         self.tokens.get(self.pos)
     }
 
-    fn advance(&mut self) -> Option<&Token<'a>> {
+    fn advance(&mut self) -> Option<&'a Token<'a>> {
         let token = self.tokens.get(self.pos)?;
         self.pos += 1;
         Some(token)
     }
 
-    fn expect(&mut self, expected: &Token) -> Result<&Token<'a>, String> {
+    fn expect(&mut self, expected: &Token) -> Result<&'a Token<'a>, CompileError> {
         match self.advance() {
             Some(t) if t == expected => Ok(t),
-            Some(t) => Err(format!("Expected {:?}, but got {:?}", expected, t)),
-            None => Err(format!("Expected {:?}, but got nothing.", expected)),
+            Some(t) => Err(self.error(
+                self.pos - 1,
+                format!("Expected {:?}, but got {:?}", expected, t),
+            )),
+            None => Err(self.error(
+                self.pos - 1,
+                format!("Expected {:?}, but got nothing.", expected),
+            )),
         }
     }
 
-    fn parse_brace_block(&mut self) -> Result<Vec<Statement>, String> {
+    fn parse_brace_block(&mut self) -> Result<Vec<Statement>, CompileError> {
         self.expect(&Token::OpenBrace)?;
 
         let mut brace_block: Vec<Statement> = vec![];
@@ -46,40 +131,119 @@ impl<'a> Parser<'a> {
         Ok(brace_block)
     }
 
-    fn parse_parenthesis(&mut self) -> Result<Expr, String> {
+    // The body of an if/else/loop: either a brace block or a single statement, which
+    // gets its own scope just as if it had been wrapped in braces.
+    fn parse_body(&mut self) -> Result<Vec<Statement>, CompileError> {
+        match self.peek() {
+            Some(Token::OpenBrace) => self.parse_brace_block(),
+            _ => Ok(vec![self.parse_statement()?]),
+        }
+    }
+
+    fn parse_parenthesis(&mut self) -> Result<Expr, CompileError> {
         self.expect(&Token::OpenParen)?;
         let inner = self.parse_expression()?;
         self.expect(&Token::CloseParen)?;
         Ok(inner)
     }
 
-    fn parse_primary_expression(&mut self) -> Result<Expr, String> {
+    // The parenthesized condition of an if or while
+    fn parse_condition(&mut self) -> Result<Expr, CompileError> {
+        self.expect(&Token::OpenParen)?;
+        let start = self.pos;
+        let condition = self.parse_expression()?;
+        self.expect(&Token::CloseParen)?;
+
+        // `if (x = 1)` is usually a typo for `==`, unless wrapped in a second set of parens
+        let is_assignment = matches!(
+            &condition.kind,
+            ExprKind::BinaryOperation { op, .. } if op.is_assignment()
+        );
+        if is_assignment && self.tokens.get(start) != Some(&Token::OpenParen) {
+            self.diagnostics.warn(
+                "parentheses",
+                "Suggest parentheses around assignment used as truth value".to_owned(),
+                Some(self.span_at(start)),
+            );
+        }
+        Ok(condition)
+    }
+
+    fn parse_primary_expression(&mut self) -> Result<Expr, CompileError> {
+        let start = self.pos;
+        let mut expr = self.nested(Self::parse_primary)?;
+        // A parenthesized expression keeps the span inside its parentheses
+        if expr.span.is_none() {
+            expr.span = self.span_between(start, self.pos);
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, CompileError> {
         match self.peek() {
+            // Literals are kept as they're written, even ones too large for an int, and the
+            // type checker warns about any that don't fit the type they end up converted to.
+            // Past i64::MAX one wraps around to a negative value here.
             Some(Token::IntegerLiteral(i)) => {
-                let int_literal = *i;
+                let int_literal = *i as i64;
                 self.advance();
-                Ok(Expr::IntLiteral(int_literal))
+                Ok(self.expr(ExprKind::IntLiteral(int_literal)))
             }
             Some(Token::StringLiteral(s)) => {
                 let str_literal = s.to_string();
                 self.advance();
-                Ok(Expr::StringLiteral(str_literal))
+                Ok(self.expr(ExprKind::StringLiteral(str_literal)))
+            }
+            Some(Token::Identifier(name))
+                if self.tokens.get(self.pos + 1) == Some(&Token::OpenParen) =>
+            {
+                let name = *name;
+                self.advance();
+                self.parse_call(name)
             }
             Some(Token::Identifier(name)) => {
-                let var_name = name.to_string();
+                let var_name = *name;
                 self.advance();
-                Ok(Expr::Variable(var_name))
+                Ok(self.expr(ExprKind::Variable(var_name)))
             }
             Some(Token::OpenParen) => self.parse_parenthesis(),
-            _ => Err(format!(
-                "Error parsing token {:?} at position {:?}",
-                self.tokens.get(self.pos),
-                self.pos
+            // Unary operators bind tighter than any binary one, so they only take the
+            // primary expression that follows
+            Some(token) if let Some(op) = UnaryOp::from_token(token) => {
+                self.advance();
+                let operand = self.parse_primary_expression()?;
+                Ok(self.expr(ExprKind::UnaryOperation {
+                    op,
+                    expr: Box::new(operand),
+                }))
+            }
+            _ => Err(self.error(
+                self.pos,
+                format!(
+                    "Error parsing token {:?} at position {:?}",
+                    self.tokens.get(self.pos),
+                    self.pos
+                ),
             )),
         }
     }
 
-    fn parse_expression(&mut self) -> Result<Expr, String> {
+    // The argument list of a call to `name`: `(expr, ...)`
+    fn parse_call(&mut self, name: Symbol) -> Result<Expr, CompileError> {
+        self.expect(&Token::OpenParen)?;
+        let mut args = vec![];
+        while self.peek() != Some(&Token::CloseParen) {
+            if !args.is_empty() {
+                self.expect(&Token::Comma)?;
+            }
+            args.push(self.parse_expression()?);
+        }
+        self.expect(&Token::CloseParen)?;
+
+        Ok(self.expr(ExprKind::Call { name, args }))
+    }
+
+    fn parse_expression(&mut self) -> Result<Expr, CompileError> {
         let lhs = self.parse_primary_expression()?;
         self.parse_expression_precedence(lhs, 0)
     }
@@ -88,7 +252,7 @@ impl<'a> Parser<'a> {
         &mut self,
         mut lhs: Expr,
         min_precedence: u32,
-    ) -> Result<Expr, String> {
+    ) -> Result<Expr, CompileError> {
         while let Some(token) = self.peek() {
             // Try to get the operator and its precedence
             let op = match BinOp::from_token(token) {
@@ -100,10 +264,16 @@ impl<'a> Parser<'a> {
 
             let mut rhs = self.parse_primary_expression()?;
 
-            // Look ahead to see if we should bind rhs to the next operator first
+            // Look ahead to see if we should bind rhs to the next operator first. The
+            // assignments group right to left, so `a = b = 3` is a = (b = 3)
             while let Some(next_token) = self.peek() {
                 let next_op = match BinOp::from_token(next_token) {
                     Ok(next_op) if next_op.precedence() > op.precedence() => next_op,
+                    Ok(next_op)
+                        if next_op.is_assignment() && next_op.precedence() == op.precedence() =>
+                    {
+                        next_op
+                    }
                     _ => break,
                 };
 
@@ -112,37 +282,97 @@ impl<'a> Parser<'a> {
             }
 
             // Build the binary expression
-            lhs = Expr::BinaryOperation {
+            let span = lhs.span.zip(rhs.span).map(|(left, right)| Span {
+                end: right.end,
+                ..left
+            });
+            lhs = self.expr(ExprKind::BinaryOperation {
                 op,
                 left: Box::new(lhs),
                 right: Box::new(rhs),
-            };
+            });
+            lhs.span = span;
         }
 
         Ok(lhs)
     }
 
-    fn parse_variable_declaration(&mut self) -> Result<Statement, String> {
-        let var_type = match self.advance() {
+    fn parse_type(&mut self) -> Result<Type, CompileError> {
+        let mut parsed_type = match self.advance() {
             Some(Token::Keyword("void")) => Type::Void,
             Some(Token::Keyword("int")) => Type::Int,
             Some(Token::Keyword("char")) => Type::Char,
-            Some(Token::Identifier(type_name)) => Type::UserDefined(type_name.to_string()),
-            _ => {
-                return Err(format!(
-                    "Error parsing type from token {:?} at position {:?}",
-                    self.tokens[self.pos - 1],
-                    self.pos - 1
+            Some(Token::Keyword("unsigned")) => self.parse_unsigned()?,
+            Some(Token::Keyword("struct")) => Type::Struct(self.parse_name("a struct tag")?.0),
+            Some(Token::Keyword("enum")) => Type::Enum(self.parse_name("an enum tag")?.0),
+            Some(Token::Identifier(type_name)) => Type::UserDefined(*type_name),
+            t => {
+                return Err(self.error(
+                    self.pos - 1,
+                    format!(
+                        "Error parsing type from token {:?} at position {:?}",
+                        t,
+                        self.pos - 1
+                    ),
                 ));
             }
         };
-        let name: String = match self.advance() {
-            Some(Token::Identifier(var_name)) => var_name.to_string(),
-            _ => {
-                return Err(format!(
-                    "Error parsing variable name from token {:?} at position {:?}",
-                    self.tokens[self.pos - 1],
-                    self.pos - 1
+        while self.peek() == Some(&Token::Operator("*")) {
+            self.advance();
+            parsed_type = Type::Pointer(Box::new(parsed_type));
+        }
+        Ok(parsed_type)
+    }
+
+    // `unsigned` on its own or before `int` is an unsigned int. There's no unsigned char.
+    fn parse_unsigned(&mut self) -> Result<Type, CompileError> {
+        match self.peek() {
+            Some(Token::Keyword("int")) => {
+                self.advance();
+                Ok(Type::Unsigned)
+            }
+            Some(Token::Keyword("char")) => {
+                Err(self.error(self.pos, "unsigned char is not supported".to_owned()))
+            }
+            _ => Ok(Type::Unsigned),
+        }
+    }
+
+    // Whether the next tokens start a variable declaration rather than an expression
+    fn at_declaration(&self) -> bool {
+        matches!(
+            (self.peek(), self.tokens.get(self.pos + 1)),
+            (
+                Some(Token::Keyword(
+                    "int" | "char" | "unsigned" | "struct" | "enum"
+                )),
+                _
+            ) | (Some(Token::Identifier(_)), Some(Token::Identifier(_)))
+        )
+    }
+
+    // An identifier naming something, along with its location
+    fn parse_name(&mut self, what: &str) -> Result<(Symbol, Span), CompileError> {
+        let span = self.span_at(self.pos);
+        match self.advance() {
+            Some(Token::Identifier(name)) => Ok((*name, span)),
+            t => Err(self.error(self.pos - 1, format!("Expected {}, but got {:?}", what, t))),
+        }
+    }
+
+    fn parse_variable_declaration(&mut self) -> Result<Statement, CompileError> {
+        let var_type = self.parse_type()?;
+        let span = self.span_at(self.pos);
+        let name = match self.advance() {
+            Some(Token::Identifier(var_name)) => *var_name,
+            t => {
+                return Err(self.error(
+                    self.pos - 1,
+                    format!(
+                        "Error parsing variable name from token {:?} at position {:?}",
+                        t,
+                        self.pos - 1
+                    ),
                 ));
             }
         };
@@ -160,293 +390,922 @@ impl<'a> Parser<'a> {
             }
         };
 
-        Ok(Statement::VarDeclare {
+        Ok(self.statement(StatementKind::VarDeclare {
             name,
             var_type,
             value,
-        })
+            span,
+        }))
     }
 
-    fn parse_if_else(&mut self) -> Result<Statement, String> {
+    fn parse_if_else(&mut self) -> Result<Statement, CompileError> {
         self.expect(&Token::Keyword("if"))?;
-        self.expect(&Token::OpenParen)?;
-        let condition = self.parse_expression()?;
-        self.expect(&Token::CloseParen)?;
+        let condition = self.parse_condition()?;
 
-        let true_statements = self.parse_brace_block()?;
+        let true_start = self.pos;
+        let true_statements = self.parse_body()?;
+        let true_end = self.pos;
 
+        // `else if (...)` is just an else body consisting of a single if statement
         let false_statements = match self.peek() {
             Some(&Token::Keyword("else")) => {
                 self.expect(&Token::Keyword("else"))?;
-                Some(Scope::from_statements(
-                    self.parse_brace_block()?,
-                    &mut self.scope_id_counter,
-                ))
+                let start = self.pos;
+                let statements = self.parse_body()?;
+                Some(self.scope(statements, start, self.pos))
             }
             _ => None,
         };
 
-        Ok(Statement::If {
+        let true_block = self.scope(true_statements, true_start, true_end);
+        Ok(self.statement(StatementKind::If {
             condition,
-            true_block: Scope::from_statements(true_statements, &mut self.scope_id_counter),
+            true_block,
             false_block: false_statements,
-        })
+        }))
     }
 
-    fn parse_statement(&mut self) -> Result<Statement, String> {
-        let token = self.peek();
-        let next_token = self.tokens.get(self.pos + 1);
-        match (token, next_token) {
-            (Some(Token::Keyword("return")), _) => {
+    // A bare `{ ... }` block
+    fn parse_block(&mut self) -> Result<Statement, CompileError> {
+        let start = self.pos;
+        let statements = self.parse_brace_block()?;
+        let block = self.scope(statements, start, self.pos);
+        Ok(self.statement(StatementKind::Block(block)))
+    }
+
+    fn parse_while(&mut self) -> Result<Statement, CompileError> {
+        self.expect(&Token::Keyword("while"))?;
+        let condition = self.parse_condition()?;
+        let start = self.pos;
+        let body = self.parse_body()?;
+
+        let body = self.scope(body, start, self.pos);
+        Ok(self.statement(StatementKind::While { condition, body }))
+    }
+
+    fn parse_switch(&mut self) -> Result<Statement, CompileError> {
+        self.expect(&Token::Keyword("switch"))?;
+        let value = self.parse_parenthesis()?;
+        let start = self.pos;
+        let body = self.parse_body()?;
+
+        let body = self.scope(body, start, self.pos);
+        Ok(self.statement(StatementKind::Switch { value, body }))
+    }
+
+    fn parse_for(&mut self) -> Result<Statement, CompileError> {
+        self.expect(&Token::Keyword("for"))?;
+        self.expect(&Token::OpenParen)?;
+
+        let init = match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some(Token::Semicolon), _) => {
                 self.advance();
+                None
+            }
+            _ if self.at_declaration() => Some(Box::new(self.parse_variable_declaration()?)),
+            _ => {
                 let expression = self.parse_expression()?;
                 self.expect(&Token::Semicolon)?;
-                Ok(Statement::Return(expression))
+                Some(Box::new(
+                    self.statement(StatementKind::Expression(expression)),
+                ))
             }
-            (Some(Token::Keyword("if")), _) => self.parse_if_else(),
-            (Some(Token::Keyword("int")), _)
-            | (Some(Token::Keyword("char")), _)
-            | (Some(Token::Identifier(_)), Some(Token::Identifier(_))) => {
-                self.parse_variable_declaration()
+        };
+
+        let condition = match self.peek() {
+            Some(Token::Semicolon) => None,
+            _ => Some(self.parse_expression()?),
+        };
+        self.expect(&Token::Semicolon)?;
+
+        let step = match self.peek() {
+            Some(Token::CloseParen) => None,
+            _ => Some(self.parse_expression()?),
+        };
+        self.expect(&Token::CloseParen)?;
+
+        let start = self.pos;
+        let body = self.parse_body()?;
+
+        let body = self.scope(body, start, self.pos);
+        Ok(self.statement(StatementKind::For {
+            init,
+            condition,
+            step,
+            body,
+        }))
+    }
+
+    // A parameter list: `()`, `(void)`, or `(type name, ...)`, along with whether it ends in
+    // a literal `...` for a variadic function
+    fn parse_args(&mut self) -> Result<(Vec<VarInfo>, bool), CompileError> {
+        self.expect(&Token::OpenParen)?;
+
+        let mut args = vec![];
+        let mut variadic = false;
+        if let (Some(Token::Keyword("void")), Some(Token::CloseParen)) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        {
+            self.advance();
+        }
+        while self.peek() != Some(&Token::CloseParen) {
+            if !args.is_empty() {
+                self.expect(&Token::Comma)?;
+                // The variable arguments come after at least one named one, and last
+                if self.peek() == Some(&Token::Operator("...")) {
+                    self.advance();
+                    variadic = true;
+                    break;
+                }
             }
-            (None, _) => Err("End of input.".to_string()),
+            let var_type = self.parse_type()?;
+            let (name, span) = self.parse_name("a parameter name")?;
+            args.push(VarInfo {
+                name,
+                var_type,
+                span,
+            });
+        }
+        self.expect(&Token::CloseParen)?;
+
+        Ok((args, variadic))
+    }
+
+    // A top-level declaration: a struct, enum, or typedef definition, a function, or a
+    // global variable
+    fn parse_declaration(&mut self) -> Result<Declaration, CompileError> {
+        match (self.peek(), self.tokens.get(self.pos + 2)) {
+            (Some(Token::Keyword("typedef")), _) => self.parse_typedef(),
+            (Some(Token::Keyword("struct")), Some(Token::OpenBrace)) => self.parse_struct(),
+            (Some(Token::Keyword("enum")), Some(Token::OpenBrace)) => self.parse_enum(),
+            (Some(Token::Keyword("inline")), _) => self.parse_inline(),
+            _ => {
+                // Functions and globals both start with a type and a name, but only a
+                // function's name is followed by its parameters
+                let start = self.pos;
+                self.parse_type()?;
+                self.advance();
+                let is_function = self.peek() == Some(&Token::OpenParen);
+                self.pos = start;
+                match is_function {
+                    true => self.parse_function(),
+                    false => self.parse_global(),
+                }
+            }
+        }
+    }
+
+    // `inline` before a function. It only matters for a definition, since that's what gets
+    // inlined, so a prototype just drops it.
+    fn parse_inline(&mut self) -> Result<Declaration, CompileError> {
+        self.expect(&Token::Keyword("inline"))?;
+        let start = self.pos;
+        let mut declaration = self.parse_declaration()?;
+        match &mut declaration {
+            Declaration::Function { inline, .. } => *inline = true,
+            Declaration::Prototype { .. } => {}
+            _ => {
+                return Err(self.error(start, "Only functions can be declared inline".to_owned()));
+            }
+        }
+        Ok(declaration)
+    }
+
+    // `type name;` or `type name = value;` at file scope
+    fn parse_global(&mut self) -> Result<Declaration, CompileError> {
+        let Statement {
+            id,
+            kind:
+                StatementKind::VarDeclare {
+                    name,
+                    var_type,
+                    value,
+                    span,
+                },
+            ..
+        } = self.parse_variable_declaration()?
+        else {
+            unreachable!()
+        };
+        Ok(Declaration::Global {
+            id,
+            span,
+            name,
+            var_type,
+            value,
+        })
+    }
+
+    // `struct name { type member; ... };`
+    fn parse_struct(&mut self) -> Result<Declaration, CompileError> {
+        self.expect(&Token::Keyword("struct"))?;
+        let (name, span) = self.parse_name("a struct tag")?;
+        self.expect(&Token::OpenBrace)?;
+        let mut members = vec![];
+        while self.peek() != Some(&Token::CloseBrace) {
+            let var_type = self.parse_type()?;
+            let (name, span) = self.parse_name("a member name")?;
+            self.expect(&Token::Semicolon)?;
+            members.push(VarInfo {
+                name,
+                var_type,
+                span,
+            });
+        }
+        self.expect(&Token::CloseBrace)?;
+        self.expect(&Token::Semicolon)?;
+
+        Ok(Declaration::Struct {
+            id: self.node_id_counter.next(),
+            span,
+            name,
+            members,
+        })
+    }
+
+    // `enum name { A, B = 4, ... };`, optionally with a trailing comma
+    fn parse_enum(&mut self) -> Result<Declaration, CompileError> {
+        self.expect(&Token::Keyword("enum"))?;
+        let (name, span) = self.parse_name("an enum tag")?;
+        self.expect(&Token::OpenBrace)?;
+        let mut variants = vec![];
+        while self.peek() != Some(&Token::CloseBrace) {
+            let variant = self.parse_name("an enumerator")?.0;
+            let value = match self.peek() {
+                Some(Token::Operator("=")) => {
+                    self.advance();
+                    Some(self.parse_expression()?)
+                }
+                _ => None,
+            };
+            variants.push((variant, value));
+            if self.peek() != Some(&Token::CloseBrace) {
+                self.expect(&Token::Comma)?;
+            }
+        }
+        self.expect(&Token::CloseBrace)?;
+        self.expect(&Token::Semicolon)?;
+
+        Ok(Declaration::Enum {
+            id: self.node_id_counter.next(),
+            span,
+            name,
+            variants,
+        })
+    }
+
+    // `typedef type name;`
+    fn parse_typedef(&mut self) -> Result<Declaration, CompileError> {
+        self.expect(&Token::Keyword("typedef"))?;
+        let target = self.parse_type()?;
+        let (name, span) = self.parse_name("a type name")?;
+        self.expect(&Token::Semicolon)?;
+
+        Ok(Declaration::Typedef {
+            id: self.node_id_counter.next(),
+            span,
+            name,
+            target,
+        })
+    }
+
+    fn parse_function(&mut self) -> Result<Declaration, CompileError> {
+        let return_type = self.parse_type()?;
+        let span = self.span_at(self.pos);
+        let name = match self.advance() {
+            Some(Token::Identifier(name)) => *name,
+            t => {
+                return Err(self.error(
+                    self.pos - 1,
+                    format!("Expected a function name, but got {:?}", t),
+                ));
+            }
+        };
+        let args_start = self.pos;
+        let (args, variadic) = self.parse_args()?;
+        if self.peek() == Some(&Token::Semicolon) {
+            self.advance();
+            return Ok(Declaration::Prototype {
+                id: self.node_id_counter.next(),
+                span,
+                name,
+                args,
+                return_type,
+                variadic,
+            });
+        }
+        if variadic {
+            return Err(self.error(
+                self.pos,
+                format!(
+                    "Variadic function {} can only be declared, not defined",
+                    name
+                ),
+            ));
+        }
+        let body = self.parse_brace_block()?;
+
+        // The parameters are declared in the body's scope
+        let scope = self.scope(body, args_start, self.pos);
+        Ok(Declaration::Function {
+            id: self.node_id_counter.next(),
+            span,
+            name,
+            args,
+            return_type,
+            scope,
+            inline: false,
+        })
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, CompileError> {
+        let start = self.pos;
+        let mut statement = self.nested(Self::parse_statement_kind)?;
+        let span = self.span_at(start);
+        if let Some(statement_spans) = &mut self.statement_spans {
+            statement_spans.insert(statement.id, span);
+        }
+        statement.span = self.spans.get(start).copied();
+        Ok(statement)
+    }
+
+    fn parse_statement_kind(&mut self) -> Result<Statement, CompileError> {
+        let token = self.peek();
+        let next_token = self.tokens.get(self.pos + 1);
+        match (token, next_token) {
+            (Some(Token::Keyword("return")), _) => self.parse_return(),
+            (Some(Token::Keyword("if")), _) => self.parse_if_else(),
+            (Some(Token::Keyword("while")), _) => self.parse_while(),
+            (Some(Token::Keyword("for")), _) => self.parse_for(),
+            (Some(Token::Keyword("switch")), _) => self.parse_switch(),
+            (Some(Token::Keyword("case" | "default")), _) => self.parse_label(),
+            (Some(Token::Keyword("break" | "continue")), _) => self.parse_jump(),
+            (Some(Token::OpenBrace), _) => self.parse_block(),
+            _ if self.at_declaration() => self.parse_variable_declaration(),
+            (None, _) => Err(self.error(self.pos, "End of input.".to_owned())),
             _ => {
                 let expression = self.parse_expression()?;
                 self.expect(&Token::Semicolon)?;
-                Ok(Statement::Expression(expression))
+                Ok(self.statement(StatementKind::Expression(expression)))
             }
         }
     }
-}
 
-pub fn parse(tokens: &Vec<Token>) -> Result<Vec<Declaration>, String> {
-    // For now assume we're only parsing main functions
-    let expected_prefix = tokenize("int main()")?;
-    assert_eq!(tokens[..expected_prefix.len()], expected_prefix);
-    assert_eq!(*tokens.last().unwrap(), Token::CloseBrace);
+    fn parse_return(&mut self) -> Result<Statement, CompileError> {
+        self.expect(&Token::Keyword("return"))?;
+        let expression = match self.peek() {
+            Some(Token::Semicolon) => None,
+            _ => Some(self.parse_expression()?),
+        };
+        self.expect(&Token::Semicolon)?;
+        Ok(self.statement(StatementKind::Return(expression)))
+    }
 
-    let function_body_tokens = tokens[expected_prefix.len()..].to_vec();
-    let mut parser = Parser::new(&function_body_tokens);
+    // `case <value>:` or `default:`
+    fn parse_label(&mut self) -> Result<Statement, CompileError> {
+        let kind = match self.advance() {
+            Some(Token::Keyword("case")) => StatementKind::Case(self.parse_expression()?),
+            _ => StatementKind::Default,
+        };
+        self.expect(&Token::Operator(":"))?;
+        Ok(self.statement(kind))
+    }
 
-    let function_body = parser.parse_brace_block()?;
+    // `break;` or `continue;`
+    fn parse_jump(&mut self) -> Result<Statement, CompileError> {
+        let kind = match self.advance() {
+            Some(Token::Keyword("break")) => StatementKind::Break,
+            _ => StatementKind::Continue,
+        };
+        self.expect(&Token::Semicolon)?;
+        Ok(self.statement(kind))
+    }
+}
+
+pub fn parse(tokens: &[Token]) -> Result<Vec<Declaration>, CompileError> {
+    parse_with_spans(tokens, &[], &mut DiagnosticSink::default())
+}
+
+// `spans` holds the location of each token, as returned by tokenize_with_spans
+pub fn parse_with_spans(
+    tokens: &[Token],
+    spans: &[Span],
+    diagnostics: &mut DiagnosticSink,
+) -> Result<Vec<Declaration>, CompileError> {
+    let options = CompileOptions::default();
+    Ok(parse_with_statement_spans(tokens, spans, &options, diagnostics)?.0)
+}
 
-    Ok(vec![Declaration::Function {
-        name: "main".to_string(),
-        args: vec![],
-        return_type: Type::Int,
-        scope: Scope::from_statements(function_body, &mut parser.scope_id_counter),
-    }])
+// Also gives where each statement starts, by NodeId, when the options ask for line
+// information
+pub fn parse_with_statement_spans(
+    tokens: &[Token],
+    spans: &[Span],
+    options: &CompileOptions,
+    diagnostics: &mut DiagnosticSink,
+) -> Result<(Vec<Declaration>, NodeTable<Span>), CompileError> {
+    let mut parser = Parser::new(tokens, spans, options, diagnostics);
+    let mut declarations = vec![];
+    while parser.peek().is_some() {
+        declarations.push(parser.parse_declaration()?);
+    }
+    Ok((declarations, parser.statement_spans.unwrap_or_default()))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tokenizer::tokenize;
+    use crate::ast_dump::dump;
+    use crate::tokenizer::{tokenize, tokenize_with_spans};
+
+    fn parse_to_dump(source: &str) -> Result<String, String> {
+        let input: Vec<_> = tokenize(source)?;
+        Ok(dump(&parse(&input)?))
+    }
+
+    fn main_scope(declarations: &[Declaration]) -> &Scope {
+        let Declaration::Function { scope, .. } = &declarations[0] else {
+            panic!("Expected a function, got {:?}", declarations[0]);
+        };
+        scope
+    }
 
     #[test]
     fn test_main() -> Result<(), String> {
         let input: Vec<_> = tokenize("int main() { return 0; }")?;
         let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
+            id: NodeId(3),
+            span: Span::default(),
+            name: "main".into(),
             args: vec![],
             return_type: Type::Int,
             scope: Scope {
                 id: 1,
-                statements: vec![Statement::Return(Expr::IntLiteral(0))],
+                statements: vec![Statement::new(
+                    NodeId(2),
+                    StatementKind::Return(Some(Expr::new(NodeId(1), ExprKind::IntLiteral(0)))),
+                )],
+                span: None,
             },
+            inline: false,
         }];
         let result = parse(&input)?;
         assert_eq!(result, expected);
         Ok(())
     }
 
+    #[test]
+    fn test_node_ids_bottom_up() -> Result<(), String> {
+        let input: Vec<_> = tokenize("int main() { x = 1 + 2; }")?;
+        let result = parse(&input)?;
+        let scope = main_scope(&result);
+        let StatementKind::Expression(assign) = &scope.statements[0].kind else {
+            return Err(format!(
+                "Expected an expression, got {:?}",
+                scope.statements[0]
+            ));
+        };
+        let ExprKind::BinaryOperation { left, right, .. } = &assign.kind else {
+            return Err(format!("Expected a binary operation, got {:?}", assign));
+        };
+
+        assert_eq!(left.id, NodeId(1));
+        assert_eq!(right.id, NodeId(4));
+        assert_eq!(assign.id, NodeId(5));
+        assert_eq!(scope.statements[0].id, NodeId(6));
+        Ok(())
+    }
+
     #[test]
     fn test_variable_declaration() -> Result<(), String> {
         let z_value = "value of z".to_string();
-        let tokenize_input = format!(
+        let source = format!(
             "int main() {{ int x; int y = x; MyType z = \"{:}\"; }}",
             z_value
         );
-        let input: Vec<_> = tokenize(&tokenize_input)?;
-        let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
-            args: vec![],
-            return_type: Type::Int,
-            scope: Scope {
-                id: 1,
-                statements: vec![
-                    Statement::VarDeclare {
-                        name: "x".to_string(),
-                        var_type: Type::Int,
-                        value: None,
-                    },
-                    Statement::VarDeclare {
-                        name: "y".to_string(),
-                        var_type: Type::Int,
-                        value: Some(Expr::Variable("x".to_string())),
-                    },
-                    Statement::VarDeclare {
-                        name: "z".to_string(),
-                        var_type: Type::UserDefined("MyType".to_string()),
-                        value: Some(Expr::StringLiteral(z_value)),
-                    },
-                ],
-            },
-        }];
-        let result = parse(&input)?;
-        assert_eq!(result, expected);
+        let expected = "\
+(fn main int ()
+  (decl int x)
+  (decl int y (var x))
+  (decl MyType z (str \"value of z\")))
+";
+        assert_eq!(parse_to_dump(&source)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pointer_declaration() -> Result<(), String> {
+        let expected = "\
+(fn main int ()
+  (decl char* s (str \"hi\"))
+  (decl int** p))
+";
+        assert_eq!(
+            parse_to_dump("int main() { char *s = \"hi\"; int **p; }")?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_functions() -> Result<(), String> {
+        let expected = "\
+(fn add int ((int a) (char* b))
+  (ret (+ (var a) (int 1))))
+(fn f void ()
+  (ret))
+(fn main int ()
+  (ret (int 0)))
+";
+        assert_eq!(
+            parse_to_dump(
+                "int add(int a, char *b) { return a + 1; } \
+                 void f(void) { return; } int main() { return 0; }"
+            )?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prototypes() -> Result<(), String> {
+        let expected = "\
+(declare putchar int ((int c)))
+(declare printf int ((char* format) ...))
+(fn main int ()
+  (ret (call printf (str \"%d\") (int 1))))
+";
+        assert_eq!(
+            parse_to_dump(
+                "int putchar(int c); int printf(char *format, ...); \
+                 int main() { return printf(\"%d\", 1); }"
+            )?,
+            expected
+        );
+        assert_eq!(
+            parse_to_dump("int f(int a, ...) { return a; }"),
+            Err("Variadic function f can only be declared, not defined".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline() -> Result<(), String> {
+        let expected = "\
+(declare g int ((int x)))
+(inline (fn f int ((int x))
+  (ret (var x))))
+";
+        assert_eq!(
+            parse_to_dump("inline int g(int x); inline int f(int x) { return x; }")?,
+            expected
+        );
+        assert_eq!(
+            parse_to_dump("inline int x;"),
+            Err("Only functions can be declared inline".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsigned() -> Result<(), String> {
+        let expected = "\
+(fn f unsigned ((unsigned a) (unsigned* b))
+  (decl unsigned c (var a))
+  (ret (var c)))
+";
+        assert_eq!(
+            parse_to_dump(
+                "unsigned f(unsigned int a, unsigned *b) { unsigned int c = a; return c; }"
+            )?,
+            expected
+        );
+        assert_eq!(
+            parse_to_dump("unsigned char c;"),
+            Err("unsigned char is not supported".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_globals() -> Result<(), String> {
+        let expected = "\
+(global int count)
+(global char* name (str \"x\"))
+(fn main int ()
+  (ret (var count)))
+";
+        assert_eq!(
+            parse_to_dump("int count; char *name = \"x\"; int main() { return count; }")?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_spans() -> Result<(), String> {
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans("int f() { return 0; }\nvoid g() { }")?;
+        let result = parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+        let spans: Vec<String> = result.iter().map(|d| d.span().to_string()).collect();
+        assert_eq!(spans, vec!["1:5", "2:6"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_spans() -> Result<(), String> {
+        let source = "int f(int n) {\n  if (n) { n; } else n;\n  return n;\n}";
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let result = parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+        let scope = main_scope(&result);
+        let text = |scope: &Scope| scope.span.map(|s| &source[s.start..s.end]);
+        assert_eq!(text(scope), Some(&source[5..]));
+        let StatementKind::If {
+            true_block,
+            false_block: Some(false_block),
+            ..
+        } = &scope.statements[0].kind
+        else {
+            return Err(format!("Expected an if, got {:?}", scope.statements[0]));
+        };
+        assert_eq!(text(true_block), Some("{ n; }"));
+        assert_eq!(text(false_block), Some("n;"));
+
+        // The parameter is in the root scope, and the braces are in the block
+        assert_eq!(scope_at(&result, 7), Some(scope.id));
+        assert_eq!(scope_at(&result, 24), Some(true_block.id));
+        assert_eq!(scope_at(&result, 31), Some(scope.id));
+        assert_eq!(scope_at(&result, 36), Some(false_block.id));
+        assert_eq!(scope_at(&result, 0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_span() -> Result<(), String> {
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans("int main() {\n  return 0\n}")?;
+        let error = parse_with_spans(&tokens, &spans, &mut diagnostics).unwrap_err();
+        assert!(matches!(error, CompileError::ParseError { .. }));
+        assert_eq!(error.span().map(|s| s.to_string()), Some("3:1".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parentheses_warning() -> Result<(), String> {
+        let source = "int main() { int x;\n  if (x = 1) { }\n  while ((x = 0)) { }\n  return x; }";
+        let mut diagnostics = DiagnosticSink::default();
+        diagnostics.apply_flag("-Wparentheses")?;
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+        let warnings: Vec<String> = diagnostics
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "2:7: warning: Suggest parentheses around assignment used as truth value \
+                 [-Wparentheses]"
+            ]
+        );
         Ok(())
     }
 
     #[test]
     fn test_if() -> Result<(), String> {
-        let tokenize_input = "int main() { if(x) { return 0; } return 1;}";
-        let input: Vec<_> = tokenize(tokenize_input)?;
-        let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
-            args: vec![],
-            return_type: Type::Int,
-            scope: Scope {
-                id: 2,
-                statements: vec![
-                    Statement::If {
-                        condition: Expr::Variable("x".to_string()),
-                        true_block: Scope {
-                            id: 1,
-                            statements: vec![Statement::Return(Expr::IntLiteral(0))],
-                        },
-                        false_block: None,
-                    },
-                    Statement::Return(Expr::IntLiteral(1)),
-                ],
-            },
-        }];
-        let result = parse(&input)?;
-        assert_eq!(result, expected);
+        let source = "int main() { if(x) { return 0; } return 1;}";
+        let expected = "\
+(fn main int ()
+  (if (var x)
+    (then
+      (ret (int 0))))
+  (ret (int 1)))
+";
+        assert_eq!(parse_to_dump(source)?, expected);
+
+        let result = parse(&tokenize(source)?)?;
+        let scope = main_scope(&result);
+        assert_eq!(scope.id, 2);
+        let StatementKind::If { true_block, .. } = &scope.statements[0].kind else {
+            return Err(format!("Expected an if, got {:?}", scope.statements[0]));
+        };
+        assert_eq!(true_block.id, 1);
         Ok(())
     }
 
     #[test]
     fn test_if_else() -> Result<(), String> {
-        let tokenize_input = "int main() { if(x){ return 1; }else{ return 0; }}";
-        let input: Vec<_> = tokenize(tokenize_input)?;
-        let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
-            args: vec![],
-            return_type: Type::Int,
-            scope: Scope {
-                id: 3,
-                statements: vec![Statement::If {
-                    condition: Expr::Variable("x".to_string()),
-                    true_block: Scope {
-                        id: 2,
-                        statements: vec![Statement::Return(Expr::IntLiteral(1))],
-                    },
-                    false_block: Some(Scope {
-                        id: 1,
-                        statements: vec![Statement::Return(Expr::IntLiteral(0))],
-                    }),
-                }],
-            },
-        }];
-        let result = parse(&input)?;
-        assert_eq!(result, expected);
+        let source = "int main() { if(x){ return 1; }else{ return 0; }}";
+        let expected = "\
+(fn main int ()
+  (if (var x)
+    (then
+      (ret (int 1)))
+    (else
+      (ret (int 0)))))
+";
+        assert_eq!(parse_to_dump(source)?, expected);
+
+        let result = parse(&tokenize(source)?)?;
+        let scope = main_scope(&result);
+        assert_eq!(scope.id, 3);
+        let StatementKind::If {
+            true_block,
+            false_block: Some(false_block),
+            ..
+        } = &scope.statements[0].kind
+        else {
+            return Err(format!(
+                "Expected an if/else, got {:?}",
+                scope.statements[0]
+            ));
+        };
+        assert_eq!(true_block.id, 2);
+        assert_eq!(false_block.id, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loops_and_blocks() -> Result<(), String> {
+        let source =
+            "int main() { while (x) x = 1; for (int i = 0; i == 1; i += 1) { { x *= 2; } } }";
+        let expected = "\
+(fn main int ()
+  (while (var x)
+    (expr (= (var x) (int 1))))
+  (for (decl int i (int 0)) (== (var i) (int 1)) (+= (var i) (int 1))
+    (block
+      (expr (*= (var x) (int 2))))))
+";
+        assert_eq!(parse_to_dump(source)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch() -> Result<(), String> {
+        let source = "int main() { switch (x + 1) { case 1: case 2: x = 1; break; default: { } } }";
+        let expected = "\
+(fn main int ()
+  (switch (+ (var x) (int 1))
+    (case (int 1))
+    (case (int 2))
+    (expr (= (var x) (int 1)))
+    (break)
+    (default)
+    (block)))
+";
+        assert_eq!(parse_to_dump(source)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_else_if_braceless() -> Result<(), String> {
+        let source = "int main() { if (x) return 1; else if (y) return 2; else { return 3; } }";
+        let expected = "\
+(fn main int ()
+  (if (var x)
+    (then
+      (ret (int 1)))
+    (else
+      (if (var y)
+        (then
+          (ret (int 2)))
+        (else
+          (ret (int 3)))))))
+";
+        assert_eq!(parse_to_dump(source)?, expected);
         Ok(())
     }
 
     #[test]
     fn test_assign() -> Result<(), String> {
-        let tokenize_input = "int main() { x = 1; }";
-        let input: Vec<_> = tokenize(tokenize_input)?;
-        let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
-            args: vec![],
-            return_type: Type::Int,
-            scope: Scope {
-                id: 1,
-                statements: vec![Statement::Expression(Expr::BinaryOperation {
-                    op: BinOp::Assign,
-                    left: Box::new(Expr::Variable("x".to_string())),
-                    right: Box::new(Expr::IntLiteral(1)),
-                })],
-            },
-        }];
-        let result = parse(&input)?;
-        assert_eq!(result, expected);
+        let expected = "\
+(fn main int ()
+  (expr (= (var x) (int 1))))
+";
+        assert_eq!(parse_to_dump("int main() { x = 1; }")?, expected);
+
+        // Assignments chain right to left
+        let expected = "\
+(fn main int ()
+  (expr (= (var a) (= (var b) (int 3))))
+  (expr (+= (var a) (= (var b) (-= (var c) (int 1))))))
+";
+        assert_eq!(
+            parse_to_dump("int main() { a = b = 3; a += b = c -= 1; }")?,
+            expected
+        );
         Ok(())
     }
 
     #[test]
     fn test_precedence() -> Result<(), String> {
-        let tokenize_input = "int main() { x = 1 + 2 * 3; x = 1 * 2 + 3; }";
-        let input: Vec<_> = tokenize(tokenize_input)?;
-        let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
-            args: vec![],
-            return_type: Type::Int,
-            scope: Scope {
-                id: 1,
-                statements: vec![
-                    Statement::Expression(Expr::BinaryOperation {
-                        op: BinOp::Assign,
-                        left: Box::new(Expr::Variable("x".to_string())),
-                        right: Box::new(Expr::BinaryOperation {
-                            op: BinOp::Add,
-                            left: Box::new(Expr::IntLiteral(1)),
-                            right: Box::new(Expr::BinaryOperation {
-                                op: BinOp::Mul,
-                                left: Box::new(Expr::IntLiteral(2)),
-                                right: Box::new(Expr::IntLiteral(3)),
-                            }),
-                        }),
-                    }),
-                    Statement::Expression(Expr::BinaryOperation {
-                        op: BinOp::Assign,
-                        left: Box::new(Expr::Variable("x".to_string())),
-                        right: Box::new(Expr::BinaryOperation {
-                            op: BinOp::Add,
-                            left: Box::new(Expr::BinaryOperation {
-                                op: BinOp::Mul,
-                                left: Box::new(Expr::IntLiteral(1)),
-                                right: Box::new(Expr::IntLiteral(2)),
-                            }),
-                            right: Box::new(Expr::IntLiteral(3)),
-                        }),
-                    }),
-                ],
-            },
-        }];
-        let result = parse(&input)?;
-        assert_eq!(result, expected);
+        let expected = "\
+(fn main int ()
+  (expr (= (var x) (+ (int 1) (* (int 2) (int 3)))))
+  (expr (= (var x) (+ (* (int 1) (int 2)) (int 3)))))
+";
+        assert_eq!(
+            parse_to_dump("int main() { x = 1 + 2 * 3; x = 1 * 2 + 3; }")?,
+            expected
+        );
+
+        let expected = "\
+(fn main int ()
+  (expr (= (var x) (|| (&& (< (var a) (+ (int 1) (int 2))) (== (var b) (int 3))) (!= (var c) (int 4))))))
+";
+        assert_eq!(
+            parse_to_dump("int main() { x = a < 1 + 2 && b == 3 || c != 4; }")?,
+            expected
+        );
+
+        // Shifts bind tighter than comparisons and the bitwise operators looser, as in C
+        let expected = "\
+(fn main int ()
+  (expr (= (var x) (| (var a) (^ (var b) (& (var c) (== (var d) (<< (int 1) (+ (int 2) (var e))))))))))
+";
+        assert_eq!(
+            parse_to_dump("int main() { x = a | b ^ c & d == 1 << 2 + e; }")?,
+            expected
+        );
+
+        // Unary operators apply before any binary operator
+        let expected = "\
+(fn main int ()
+  (expr (= (var x) (* (- (var a)) (! (~ (var b)))))))
+";
+        assert_eq!(parse_to_dump("int main() { x = -a * !~b; }")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_calls() -> Result<(), String> {
+        let expected = "\
+(fn main int ()
+  (expr (call f))
+  (expr (= (var x) (+ (call g (var a) (* (int 2) (int 3))) (int 1)))))
+";
+        assert_eq!(
+            parse_to_dump("int main() { f(); x = g(a, 2 * 3) + 1; }")?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_definitions() -> Result<(), String> {
+        let expected = "\
+(struct Node ((int value) (struct Node* next)))
+(enum Dir (UP (= DOWN (* (int 2) (int 2)))))
+(typedef struct Node* list)
+(fn main int ()
+  (decl list l)
+  (decl enum Dir d)
+  (ret (int 0)))
+";
+        assert_eq!(
+            parse_to_dump(
+                "struct Node { int value; struct Node *next; }; enum Dir { UP, DOWN = 2 * 2, }; \
+                 typedef struct Node *list; int main() { list l; enum Dir d; return 0; }"
+            )?,
+            expected
+        );
+        assert!(parse_to_dump("struct S { int a; }").is_err());
         Ok(())
     }
 
     #[test]
     fn test_parens() -> Result<(), String> {
-        let tokenize_input = "int main() { x = ((1 + 2) * 3); }";
-        let input: Vec<_> = tokenize(tokenize_input)?;
-        let expected: Vec<Declaration> = vec![Declaration::Function {
-            name: "main".to_string(),
-            args: vec![],
-            return_type: Type::Int,
-            scope: Scope {
-                id: 1,
-                statements: vec![Statement::Expression(Expr::BinaryOperation {
-                    op: BinOp::Assign,
-                    left: Box::new(Expr::Variable("x".to_string())),
-                    right: Box::new(Expr::BinaryOperation {
-                        op: BinOp::Mul,
-                        left: Box::new(Expr::BinaryOperation {
-                            op: BinOp::Add,
-                            left: Box::new(Expr::IntLiteral(1)),
-                            right: Box::new(Expr::IntLiteral(2)),
-                        }),
-                        right: Box::new(Expr::IntLiteral(3)),
-                    }),
-                })],
-            },
-        }];
-        let result = parse(&input)?;
-        assert_eq!(result, expected);
+        let expected = "\
+(fn main int ()
+  (expr (= (var x) (* (+ (int 1) (int 2)) (int 3)))))
+";
+        assert_eq!(
+            parse_to_dump("int main() { x = ((1 + 2) * 3); }")?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_nesting_limit() -> Result<(), String> {
+        let parens = |n| {
+            format!(
+                "int main() {{ return {}1{}; }}",
+                "(".repeat(n),
+                ")".repeat(n)
+            )
+        };
+        parse_to_dump(&parens(200))?;
+        assert!(parse_to_dump(&parens(100_000)).is_err());
+        let blocks = format!(
+            "int main() {{ {}{} }}",
+            "{".repeat(100_000),
+            "}".repeat(100_000)
+        );
+        assert!(parse_to_dump(&blocks).is_err());
         Ok(())
     }
 }
@@ -0,0 +1,310 @@
+use crate::cfg::{Function, Program};
+use crate::ice;
+use crate::inliner;
+use crate::optimize;
+use crate::parallel;
+use std::time::{Duration, Instant};
+
+/*
+ * Runs optimization passes over each function. A pass reports whether it changed the
+ * function, and the manager keeps running its whole sequence until a round goes by with
+ * no changes, since one pass often opens up more work for another.
+ *
+ * Passes work on a Function rather than just its ControlFlowGraph because some of them
+ * need to know which vars are parameters.
+ *
+ * Each function is optimized on its own, so `run_program` spreads them across threads.
+ * Inlining is the exception, since it needs to see the functions being called: it works on
+ * the whole program, once, before the other passes.
+ */
+
+pub trait Pass: Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self, function: &mut Function) -> bool;
+}
+
+pub struct SlotPromotion;
+
+impl Pass for SlotPromotion {
+    fn name(&self) -> &'static str {
+        "mem2reg"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        optimize::promote_slots(function)
+    }
+}
+
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "fold-constants"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        optimize::fold_constants(function)
+    }
+}
+
+pub struct ConstantPropagation;
+
+impl Pass for ConstantPropagation {
+    fn name(&self) -> &'static str {
+        "propagate-constants"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        optimize::propagate_constants(function)
+    }
+}
+
+pub struct CommonSubexpressionElimination;
+
+impl Pass for CommonSubexpressionElimination {
+    fn name(&self) -> &'static str {
+        "cse"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        optimize::eliminate_common_subexpressions(function)
+    }
+}
+
+pub struct SimplifyCfg;
+
+impl Pass for SimplifyCfg {
+    fn name(&self) -> &'static str {
+        "simplify-cfg"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        optimize::simplify_cfg(function)
+    }
+}
+
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        optimize::eliminate_dead_code(function)
+    }
+}
+
+fn pass_by_name(name: &str) -> Option<Box<dyn Pass>> {
+    match name {
+        "mem2reg" => Some(Box::new(SlotPromotion)),
+        "fold-constants" => Some(Box::new(ConstantFolding)),
+        "propagate-constants" => Some(Box::new(ConstantPropagation)),
+        "cse" => Some(Box::new(CommonSubexpressionElimination)),
+        "simplify-cfg" => Some(Box::new(SimplifyCfg)),
+        "dce" => Some(Box::new(DeadCodeElimination)),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    // The size threshold to inline calls with, if the pipeline inlines
+    inline_threshold: Option<usize>,
+}
+
+impl PassManager {
+    // Every pass is monotone, so this is only a backstop against a pass that isn't
+    const MAX_ROUNDS: usize = 100;
+
+    pub fn new() -> Self {
+        PassManager::default()
+    }
+
+    // Builder-style, so a pipeline reads as a chain of passes
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    // Inlines calls to leaf functions with at most `threshold` statements, or that are
+    // declared `inline`, before running the other passes
+    pub fn inline(mut self, threshold: usize) -> Self {
+        self.inline_threshold = Some(threshold);
+        self
+    }
+
+    /*
+     * The pipeline for an -O level. -O0 runs nothing. -O1 moves variables out of stack slots
+     * that don't need to be in memory, folds constants within each block and removes
+     * whatever ends up dead. -O2 also propagates constants across blocks, which folds
+     * branches on them, reuses values already computed rather than computing them again, and
+     * cleans up the jumps that leaves behind. It starts by inlining small functions, which
+     * gives the rest more to work with.
+     */
+    pub fn for_level(level: u32) -> Self {
+        match level {
+            0 => PassManager::new(),
+            1 => PassManager::new()
+                .add(SlotPromotion)
+                .add(ConstantFolding)
+                .add(DeadCodeElimination),
+            _ => PassManager::new()
+                .inline(inliner::THRESHOLD)
+                .add(SlotPromotion)
+                .add(ConstantFolding)
+                .add(ConstantPropagation)
+                .add(CommonSubexpressionElimination)
+                .add(SimplifyCfg)
+                .add(DeadCodeElimination),
+        }
+    }
+
+    // A pipeline running the named passes in the given order, except that "inline" always
+    // comes first
+    pub fn from_names(names: &[&str]) -> Result<Self, String> {
+        let mut manager = PassManager::new();
+        for name in names {
+            manager = match *name {
+                "inline" => manager.inline(inliner::THRESHOLD),
+                name => {
+                    let pass = pass_by_name(name).ok_or(format!("Unknown pass {}", name))?;
+                    manager.passes.push(pass);
+                    manager
+                }
+            };
+        }
+        Ok(manager)
+    }
+
+    pub fn pass_names(&self) -> Vec<&'static str> {
+        let inline = self.inline_threshold.map(|_| "inline");
+        inline
+            .into_iter()
+            .chain(self.passes.iter().map(|p| p.name()))
+            .collect()
+    }
+
+    // Returns whether any pass changed the function. Only `run_program` inlines.
+    pub fn run(&self, function: &mut Function) -> bool {
+        self.run_timed(function, &mut vec![Duration::ZERO; self.passes.len()])
+    }
+
+    // Also adds the time each pass took to `times`, which is parallel to the passes
+    fn run_timed(&self, function: &mut Function, times: &mut [Duration]) -> bool {
+        let mut changed_any = false;
+        for _ in 0..PassManager::MAX_ROUNDS {
+            let mut changed = false;
+            for (pass, time) in self.passes.iter().zip(times.iter_mut()) {
+                let start = Instant::now();
+                let pass_changed = pass.run(function);
+                *time += start.elapsed();
+                if pass_changed {
+                    log::debug!("{} changed the function", pass.name());
+                }
+                changed |= pass_changed;
+            }
+            if !changed {
+                break;
+            }
+            changed_any = true;
+        }
+        changed_any
+    }
+
+    // Returns how long each pass took in total, over every round and function. With more
+    // than one job that's the time summed across threads, not the time it took.
+    pub fn run_program(&self, program: &mut Program, jobs: usize) -> Vec<(&'static str, Duration)> {
+        let mut inline_time = None;
+        if let Some(threshold) = self.inline_threshold {
+            let start = Instant::now();
+            inliner::inline_calls(program, threshold);
+            inline_time = Some(start.elapsed());
+        }
+        let functions: Vec<_> = program.functions.iter_mut().collect();
+        let function_times = parallel::map(functions, jobs, |(name, function)| {
+            ice::enter_function(name, None);
+            log::debug!("optimizing {}", name);
+            let mut times = vec![Duration::ZERO; self.passes.len()];
+            self.run_timed(function, &mut times);
+            times
+        });
+        let mut times = vec![Duration::ZERO; self.passes.len()];
+        for function_times in function_times {
+            for (time, function_time) in times.iter_mut().zip(function_times) {
+                *time += function_time;
+            }
+        }
+        let times = inline_time.into_iter().chain(times);
+        self.pass_names().into_iter().zip(times).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::{Statement, VReg};
+    use crate::cfg_parser::parse_cfg;
+
+    fn function(text: &str) -> Result<Function, String> {
+        Ok(Function {
+            params: vec![],
+            cfg: parse_cfg(text)?,
+            inline: false,
+        })
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(PassManager::for_level(0).pass_names().is_empty());
+        assert_eq!(
+            PassManager::for_level(1).pass_names(),
+            vec!["mem2reg", "fold-constants", "dce"]
+        );
+        assert_eq!(
+            PassManager::for_level(2).pass_names(),
+            vec![
+                "inline",
+                "mem2reg",
+                "fold-constants",
+                "propagate-constants",
+                "cse",
+                "simplify-cfg",
+                "dce"
+            ]
+        );
+        assert_eq!(
+            PassManager::from_names(&["dce", "unroll"]).map(|m| m.pass_names()),
+            Err("Unknown pass unroll".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_run_to_fixpoint() -> Result<(), String> {
+        // Folding v3 leaves v1 and v2 dead, which dce only sees after folding has run
+        let manager = PassManager::from_names(&["dce", "fold-constants"])?;
+        let mut f = function("bb0: v1 = 2; v2 = 3; v3 = v1 * v2; ret v3")?;
+        assert!(manager.run(&mut f));
+        assert_eq!(f.cfg, parse_cfg("bb0: v3 = 6; ret v3")?);
+
+        // Nothing is left to change
+        assert!(!manager.run(&mut f));
+        Ok(())
+    }
+
+    #[test]
+    fn test_o0_leaves_function_alone() -> Result<(), String> {
+        let mut f = function("bb0: v1 = 2; v2 = v1; ret v1")?;
+        assert!(!PassManager::for_level(0).run(&mut f));
+        assert_eq!(
+            f.cfg[&0][1],
+            Statement::Copy {
+                dest: VReg(2),
+                src: VReg(1),
+            }
+        );
+        Ok(())
+    }
+}
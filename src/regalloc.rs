@@ -0,0 +1,371 @@
+use crate::cfg::*;
+use crate::codegen::RegisterGP;
+use crate::liveness::{LiveRange, Liveness};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/*
+ * Register allocation: decides which vars get a register and which are spilled to the
+ * stack frame. There are two allocators to choose from, linear scan and graph coloring.
+ *
+ * Linear scan walks the live ranges in order of where they start. A range's register goes
+ * back to the pool once the range has ended, so vars that are never live at the same time
+ * can share one. When every register is taken, whichever range reaches furthest is spilled,
+ * since that frees a register for the longest.
+ *
+ * Ranges include both ends, so a statement's operands never share a register with its
+ * dest.
+ *
+ * Both allocators work on whatever register type a backend hands them, x86 registers
+ * being the default.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Assignment<R = RegisterGP> {
+    Register(R),
+    Spilled,
+}
+
+impl<R: fmt::Display> fmt::Display for Assignment<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Assignment::Register(register) => write!(f, "{}", register),
+            Assignment::Spilled => write!(f, "stack"),
+        }
+    }
+}
+
+pub type Allocation<R = RegisterGP> = VRegMap<Assignment<R>>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Allocator {
+    #[default]
+    LinearScan,
+    GraphColoring,
+}
+
+impl Allocator {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear-scan" => Some(Allocator::LinearScan),
+            "graph-coloring" => Some(Allocator::GraphColoring),
+            _ => None,
+        }
+    }
+
+    pub fn allocate<R: Copy>(&self, cfg: &ControlFlowGraph, registers: &[R]) -> Allocation<R> {
+        match self {
+            Allocator::LinearScan => linear_scan(cfg, registers),
+            Allocator::GraphColoring => graph_coloring(cfg, registers),
+        }
+    }
+}
+
+pub fn linear_scan<R: Copy>(cfg: &ControlFlowGraph, registers: &[R]) -> Allocation<R> {
+    let mut ranges: Vec<(VReg, LiveRange)> = Liveness::analyze(cfg)
+        .live_ranges(cfg)
+        .into_iter()
+        .collect();
+    ranges.sort_by_key(|(var, range)| (range.start, *var));
+
+    let mut allocation = Allocation::new();
+    // Indices into registers, so the earliest free register in the pool is always used first
+    let mut free: BTreeSet<usize> = (0..registers.len()).collect();
+    // The ranges holding a register, with its index
+    let mut active: Vec<(VReg, LiveRange, usize)> = vec![];
+    for (var, range) in ranges {
+        active.retain(|(_, other, reg)| {
+            if other.end < range.start {
+                free.insert(*reg);
+            }
+            other.end >= range.start
+        });
+
+        if let Some(reg) = free.pop_first() {
+            allocation.insert(var, Assignment::Register(registers[reg]));
+            active.push((var, range, reg));
+            continue;
+        }
+        // Ties go to the var that took its register first, so the result is deterministic
+        let furthest = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, (_, other, _))| (other.end, std::cmp::Reverse(*i)))
+            .map(|(i, _)| i);
+        match furthest {
+            Some(i) if active[i].1.end > range.end => {
+                let (spilled, _, reg) = active.remove(i);
+                allocation.insert(spilled, Assignment::Spilled);
+                allocation.insert(var, Assignment::Register(registers[reg]));
+                active.push((var, range, reg));
+            }
+            _ => {
+                allocation.insert(var, Assignment::Spilled);
+            }
+        }
+    }
+    allocation
+}
+
+/*
+ * Chaitin-style graph coloring. Two vars interfere if one is defined while the other is
+ * live, and interfering vars can't share a register. An operation's dest also interferes
+ * with its right operand, since codegen writes the dest before reading that operand.
+ *
+ * Before coloring, the two sides of a copy are coalesced into one node if they don't
+ * interfere, which lets codegen drop the copy. To keep that from making the graph harder
+ * to color, it's only done when the merged node has fewer than k neighbours with k or more
+ * neighbours of their own (Briggs' test), k being the number of registers.
+ *
+ * Coloring then repeatedly removes a node with fewer than k neighbours, since it can
+ * always be colored once its neighbours are. When there isn't one, the node with the most
+ * neighbours is removed anyway, in the hope that some of them end up sharing a color.
+ * Nodes get colors in the reverse order, and one with no color left is spilled.
+ */
+pub fn graph_coloring<R: Copy>(cfg: &ControlFlowGraph, registers: &[R]) -> Allocation<R> {
+    let mut graph = InterferenceGraph::build(cfg);
+    let k = registers.len();
+
+    // Each coalesced var maps to the var whose node it was merged into
+    let mut merged_into: BTreeMap<VReg, VReg> = BTreeMap::new();
+    let find = |merged_into: &BTreeMap<VReg, VReg>, var: &VReg| {
+        let mut var = *var;
+        while let Some(next) = merged_into.get(&var) {
+            var = *next;
+        }
+        var
+    };
+    for statement in cfg.values().flatten() {
+        let Statement::Copy { dest, src } = statement else {
+            continue;
+        };
+        let (dest, src) = (find(&merged_into, dest), find(&merged_into, src));
+        if dest != src && !graph.interferes(&dest, &src) && graph.briggs(&dest, &src, k) {
+            graph.merge(&dest, &src);
+            merged_into.insert(dest, src);
+        }
+    }
+
+    let mut remaining = graph.clone();
+    let mut stack = vec![];
+    loop {
+        let next = remaining
+            .nodes()
+            .find(|var| remaining.degree(var) < k)
+            .or_else(|| remaining.nodes().max_by_key(|var| remaining.degree(var)))
+            .cloned();
+        let Some(var) = next else {
+            break;
+        };
+        remaining.remove(&var);
+        stack.push(var);
+    }
+
+    let mut colors: BTreeMap<VReg, usize> = BTreeMap::new();
+    while let Some(var) = stack.pop() {
+        let taken: BTreeSet<usize> = graph.edges[&var]
+            .iter()
+            .filter_map(|neighbour| colors.get(neighbour).copied())
+            .collect();
+        if let Some(color) = (0..k).find(|c| !taken.contains(c)) {
+            colors.insert(var, color);
+        }
+    }
+
+    let mut allocation = Allocation::new();
+    let vars = graph.nodes().chain(merged_into.keys()).copied();
+    for var in vars.collect::<Vec<_>>() {
+        let assignment = match colors.get(&find(&merged_into, &var)) {
+            Some(color) => Assignment::Register(registers[*color]),
+            None => Assignment::Spilled,
+        };
+        allocation.insert(var, assignment);
+    }
+    allocation
+}
+
+#[derive(Clone)]
+struct InterferenceGraph {
+    edges: BTreeMap<VReg, BTreeSet<VReg>>,
+}
+
+impl InterferenceGraph {
+    fn build(cfg: &ControlFlowGraph) -> Self {
+        let mut graph = InterferenceGraph {
+            edges: BTreeMap::new(),
+        };
+        let liveness = Liveness::analyze(cfg);
+        // Vars live on entry are never defined, so they'd otherwise get no edges at all
+        let entry: Vec<&VReg> = liveness.live_in[&0].iter().collect();
+        for (i, a) in entry.iter().enumerate() {
+            for b in &entry[i + 1..] {
+                graph.add_edge(a, b);
+            }
+        }
+        for (block, statements) in cfg.iter() {
+            for (statement, live) in statements.iter().zip(liveness.live_after(cfg, *block)) {
+                for var in statement.used_vars() {
+                    graph.add_node(var);
+                }
+                let Some(dest) = statement.defined_var() else {
+                    continue;
+                };
+                graph.add_node(dest);
+                for other in &live {
+                    graph.add_edge(dest, other);
+                }
+                if let Statement::Operation { rhs, .. } = statement {
+                    graph.add_edge(dest, rhs);
+                }
+            }
+        }
+        graph
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &VReg> {
+        self.edges.keys()
+    }
+
+    fn add_node(&mut self, var: &VReg) {
+        self.edges.entry(*var).or_default();
+    }
+
+    fn add_edge(&mut self, a: &VReg, b: &VReg) {
+        if a == b {
+            return;
+        }
+        self.edges.entry(*a).or_default().insert(*b);
+        self.edges.entry(*b).or_default().insert(*a);
+    }
+
+    fn interferes(&self, a: &VReg, b: &VReg) -> bool {
+        self.edges[a].contains(b)
+    }
+
+    fn degree(&self, var: &VReg) -> usize {
+        self.edges[var].len()
+    }
+
+    // Whether merging a and b leaves a node with fewer than k significant neighbours
+    fn briggs(&self, a: &VReg, b: &VReg, k: usize) -> bool {
+        let neighbours: BTreeSet<&VReg> = self.edges[a].union(&self.edges[b]).collect();
+        neighbours
+            .into_iter()
+            .filter(|n| self.degree(n) >= k)
+            .count()
+            < k
+    }
+
+    // Moves var's edges onto into, and removes var
+    fn merge(&mut self, var: &VReg, into: &VReg) {
+        for neighbour in self.edges.remove(var).unwrap_or_default() {
+            let edges = self.edges.get_mut(&neighbour);
+            edges.expect("edges go both ways").remove(var);
+            self.add_edge(into, &neighbour);
+        }
+    }
+
+    fn remove(&mut self, var: &VReg) {
+        for neighbour in self.edges.remove(var).unwrap_or_default() {
+            let edges = self.edges.get_mut(&neighbour);
+            edges.expect("edges go both ways").remove(var);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_cfg;
+
+    const POOL: [RegisterGP; 2] = [RegisterGP::Rax, RegisterGP::Rbx];
+
+    #[test]
+    fn test_linear_scan_reuses_registers() -> Result<(), String> {
+        // v1 dies at the operation defining v3, so v4 can take its register
+        let cfg = parse_cfg("bb0: v1 = 1; v2 = 2; v3 = v1 + v2; v4 = -v3; ret v4")?;
+        let allocation = linear_scan(&cfg, &[RegisterGP::Rax, RegisterGP::Rbx, RegisterGP::Rcx]);
+        assert_eq!(allocation[&VReg(1)], Assignment::Register(RegisterGP::Rax));
+        assert_eq!(allocation[&VReg(2)], Assignment::Register(RegisterGP::Rbx));
+        assert_eq!(allocation[&VReg(3)], Assignment::Register(RegisterGP::Rcx));
+        assert_eq!(allocation[&VReg(4)], Assignment::Register(RegisterGP::Rax));
+        Ok(())
+    }
+
+    #[test]
+    fn test_linear_scan_spills_furthest() -> Result<(), String> {
+        // With two registers, the third var forces a spill, and v1 is live the longest
+        let cfg = parse_cfg("bb0: v1 = 1; v2 = 2; v3 = 3; v4 = v2 + v3; v5 = v4 + v1; ret v5")?;
+        let allocation = linear_scan(&cfg, &POOL);
+        assert_eq!(allocation[&VReg(1)], Assignment::Spilled);
+        assert_eq!(allocation[&VReg(2)], Assignment::Register(RegisterGP::Rbx));
+        assert_eq!(allocation[&VReg(3)], Assignment::Register(RegisterGP::Rax));
+        assert!(matches!(allocation[&VReg(5)], Assignment::Register(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_linear_scan_loop() -> Result<(), String> {
+        // v1 is live around the loop, so nothing defined inside it may share v1's register
+        let cfg = parse_cfg(
+            "bb0: v1 = 0; goto bb1\n\
+             bb1: v2 = 3; v3 = v1 < v2; if v3 goto bb2 else bb3\n\
+             bb2: v4 = 1; v5 = v1 + v4; v1 = v5; goto bb1\n\
+             bb3: ret v1",
+        )?;
+        let allocation = linear_scan(&cfg, &[RegisterGP::Rax, RegisterGP::Rbx, RegisterGP::Rcx]);
+        let Assignment::Register(v1) = allocation[&VReg(1)] else {
+            return Err("v1 should have a register".to_owned());
+        };
+        for var in [2, 3, 4, 5].map(VReg) {
+            assert_ne!(allocation[&var], Assignment::Register(v1), "{}", var);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_coloring_coalesces_copies() -> Result<(), String> {
+        // v5 dies at the copy into v1, so they share a register and the copy goes away.
+        // v1 is live around the loop, so nothing else may share it.
+        let cfg = parse_cfg(
+            "bb0: v1 = 0; goto bb1\n\
+             bb1: v2 = 3; v3 = v1 < v2; if v3 goto bb2 else bb3\n\
+             bb2: v4 = 1; v5 = v1 + v4; v1 = v5; goto bb1\n\
+             bb3: ret v1",
+        )?;
+        let allocation = graph_coloring(&cfg, &[RegisterGP::Rax, RegisterGP::Rbx, RegisterGP::Rcx]);
+        assert_eq!(allocation[&VReg(5)], allocation[&VReg(1)]);
+        let Assignment::Register(v1) = allocation[&VReg(1)] else {
+            return Err("v1 should have a register".to_owned());
+        };
+        for var in [2, 3, 4].map(VReg) {
+            assert_ne!(allocation[&var], Assignment::Register(v1), "{}", var);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_coloring_spills() -> Result<(), String> {
+        // v1, v2, and v3 are all live at the first addition, so one of three can't fit in two
+        // registers
+        let cfg = parse_cfg("bb0: v1 = 1; v2 = 2; v3 = 3; v4 = v2 + v3; v5 = v4 + v1; ret v5")?;
+        let allocation = graph_coloring(&cfg, &POOL);
+        let spilled = allocation
+            .values()
+            .filter(|a| **a == Assignment::Spilled)
+            .count();
+        assert_eq!(spilled, 1);
+        for (a, b) in [(1, 2), (1, 3), (2, 3), (4, 1)].map(|(a, b)| (VReg(a), VReg(b))) {
+            if allocation[&a] != Assignment::Spilled {
+                assert_ne!(allocation[&a], allocation[&b], "{} and {}", a, b);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_assignment_display() {
+        assert_eq!(Assignment::Register(RegisterGP::Rbx).to_string(), "rbx");
+        assert_eq!(Assignment::<RegisterGP>::Spilled.to_string(), "stack");
+    }
+}
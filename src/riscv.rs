@@ -0,0 +1,922 @@
+use crate::asm::{self, Instruction, Operand, imm, mem, op, reg, sym};
+use crate::cfg::*;
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::liveness::VarSet;
+use crate::regalloc::{Allocation, Assignment};
+use crate::target::{self, CodegenOptions, FrameLayout, RegisterClass, TargetBackend};
+use std::fmt;
+
+/*
+    RV64 assembly, for --target=riscv64. It follows the standard RISC-V calling convention
+    and sticks to the base integer instructions plus M for mul and div.
+
+    The register allocator hands out t0-t4 and s1-s11. t5 and t6 are left free as scratch
+    registers, and the argument registers a0-a7 are only used to pass arguments and results,
+    so moving values in and out of them never clobbers a var.
+
+    sp is set once in the prologue and stays put, and s0 points at its value on entry.
+    Below s0 come the return address, the caller's s0, the s registers the function uses,
+    a slot for each t register it uses if it makes calls, which save them there, the stack
+    slots from Alloca,
+    and the spill slots. Arguments past the eighth are stored at the bottom of the frame,
+    starting at 0(sp), which is where the callee finds them from its own s0.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterRV {
+    A0,
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    A6,
+    A7,
+    T0,
+    T1,
+    T2,
+    T3,
+    T4,
+    T5,
+    T6,
+    S1,
+    S2,
+    S3,
+    S4,
+    S5,
+    S6,
+    S7,
+    S8,
+    S9,
+    S10,
+    S11,
+    Sp,
+    S0,
+    Ra,
+    Gp,
+}
+
+impl fmt::Display for RegisterRV {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            RegisterRV::A0 => "a0",
+            RegisterRV::A1 => "a1",
+            RegisterRV::A2 => "a2",
+            RegisterRV::A3 => "a3",
+            RegisterRV::A4 => "a4",
+            RegisterRV::A5 => "a5",
+            RegisterRV::A6 => "a6",
+            RegisterRV::A7 => "a7",
+            RegisterRV::T0 => "t0",
+            RegisterRV::T1 => "t1",
+            RegisterRV::T2 => "t2",
+            RegisterRV::T3 => "t3",
+            RegisterRV::T4 => "t4",
+            RegisterRV::T5 => "t5",
+            RegisterRV::T6 => "t6",
+            RegisterRV::S1 => "s1",
+            RegisterRV::S2 => "s2",
+            RegisterRV::S3 => "s3",
+            RegisterRV::S4 => "s4",
+            RegisterRV::S5 => "s5",
+            RegisterRV::S6 => "s6",
+            RegisterRV::S7 => "s7",
+            RegisterRV::S8 => "s8",
+            RegisterRV::S9 => "s9",
+            RegisterRV::S10 => "s10",
+            RegisterRV::S11 => "s11",
+            RegisterRV::Sp => "sp",
+            RegisterRV::S0 => "s0",
+            RegisterRV::Ra => "ra",
+            RegisterRV::Gp => "gp",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// The a registers are caller-saved too, but they're only used to pass arguments and results
+const REGISTERS: [(RegisterRV, RegisterClass); 30] = [
+    (RegisterRV::T0, RegisterClass::CallerSaved),
+    (RegisterRV::T1, RegisterClass::CallerSaved),
+    (RegisterRV::T2, RegisterClass::CallerSaved),
+    (RegisterRV::T3, RegisterClass::CallerSaved),
+    (RegisterRV::T4, RegisterClass::CallerSaved),
+    (RegisterRV::S1, RegisterClass::CalleeSaved),
+    (RegisterRV::S2, RegisterClass::CalleeSaved),
+    (RegisterRV::S3, RegisterClass::CalleeSaved),
+    (RegisterRV::S4, RegisterClass::CalleeSaved),
+    (RegisterRV::S5, RegisterClass::CalleeSaved),
+    (RegisterRV::S6, RegisterClass::CalleeSaved),
+    (RegisterRV::S7, RegisterClass::CalleeSaved),
+    (RegisterRV::S8, RegisterClass::CalleeSaved),
+    (RegisterRV::S9, RegisterClass::CalleeSaved),
+    (RegisterRV::S10, RegisterClass::CalleeSaved),
+    (RegisterRV::S11, RegisterClass::CalleeSaved),
+    (RegisterRV::A0, RegisterClass::Reserved),
+    (RegisterRV::A1, RegisterClass::Reserved),
+    (RegisterRV::A2, RegisterClass::Reserved),
+    (RegisterRV::A3, RegisterClass::Reserved),
+    (RegisterRV::A4, RegisterClass::Reserved),
+    (RegisterRV::A5, RegisterClass::Reserved),
+    (RegisterRV::A6, RegisterClass::Reserved),
+    (RegisterRV::A7, RegisterClass::Reserved),
+    (RegisterRV::T5, RegisterClass::Reserved),
+    (RegisterRV::T6, RegisterClass::Reserved),
+    (RegisterRV::Sp, RegisterClass::Reserved),
+    (RegisterRV::S0, RegisterClass::Reserved),
+    (RegisterRV::Ra, RegisterClass::Reserved),
+    (RegisterRV::Gp, RegisterClass::Reserved),
+];
+
+const ARGUMENT_REGISTERS: [RegisterRV; 8] = [
+    RegisterRV::A0,
+    RegisterRV::A1,
+    RegisterRV::A2,
+    RegisterRV::A3,
+    RegisterRV::A4,
+    RegisterRV::A5,
+    RegisterRV::A6,
+    RegisterRV::A7,
+];
+
+// The return address and the caller's s0
+const LINKAGE_SIZE: u64 = 16;
+
+// Where a var's value is kept
+#[derive(Clone, Copy)]
+enum Location {
+    Register(RegisterRV),
+    Stack(u64), // offset below s0
+}
+
+impl Location {
+    fn operand(self) -> Operand<RegisterRV> {
+        match self {
+            Location::Register(reg) => Operand::Register(reg),
+            Location::Stack(offset) => s0(offset),
+        }
+    }
+}
+
+type Asm = Vec<Instruction<RegisterRV>>;
+
+// The frame slot `offset` bytes below s0
+fn s0(offset: u64) -> Operand<RegisterRV> {
+    mem(RegisterRV::S0, -(offset as i64))
+}
+
+fn sp(offset: u64) -> Operand<RegisterRV> {
+    mem(RegisterRV::Sp, offset as i64)
+}
+
+// Memory operands always spell out their offset, even when it's 0
+fn gnu_operand(operand: &Operand<RegisterRV>) -> String {
+    match operand {
+        Operand::Register(reg) => reg.to_string(),
+        Operand::Immediate(value) => value.to_string(),
+        Operand::Memory { base, offset } => format!("{}({})", offset, base),
+        Operand::Symbol(name) => name.clone(),
+        Operand::ByteRegister(_)
+        | Operand::Indexed { .. }
+        | Operand::PcRelative(_)
+        | Operand::Indirect(_) => unreachable!(),
+    }
+}
+
+// The same layout as the x86 backend's frame, plus the slots calls save t registers in
+// and the area for outgoing stack arguments
+pub struct Frame {
+    locations: VRegMap<Location>,
+    slots: VRegMap<u64>,
+    saved: Vec<(RegisterRV, u64)>,
+    call_saves: Vec<(RegisterRV, u64)>,
+    size: u64, // what the prologue subtracts from sp
+    pic: bool,
+}
+
+impl Frame {
+    fn new(cfg: &ControlFlowGraph, allocation: &Allocation<RegisterRV>, pic: bool) -> Self {
+        let used = |reg: &RegisterRV| {
+            allocation
+                .values()
+                .any(|a| *a == Assignment::Register(*reg))
+        };
+        let mut layout = FrameLayout::below(LINKAGE_SIZE);
+        let callee_saved = target::registers_in(&REGISTERS, RegisterClass::CalleeSaved);
+        let saved = layout.save(callee_saved.filter(used));
+        let calls: Vec<usize> = cfg
+            .values()
+            .flatten()
+            .filter_map(|s| match s {
+                Statement::Call { args, .. } => Some(args.len()),
+                _ => None,
+            })
+            .collect();
+        let mut call_saves = vec![];
+        if !calls.is_empty() {
+            let caller_saved = target::registers_in(&REGISTERS, RegisterClass::CallerSaved);
+            call_saves = layout.save(caller_saved.filter(used));
+        }
+
+        let mut slots = VRegMap::new();
+        for statement in cfg.values().flatten() {
+            if let Statement::Alloca { dest, size } = statement {
+                slots.insert(*dest, layout.slot(*size));
+            }
+        }
+
+        let mut locations = VRegMap::new();
+        let vars = cfg
+            .values()
+            .flatten()
+            .flat_map(|s| s.used_vars().into_iter().chain(s.defined_var()));
+        for var in vars {
+            if locations.contains_key(var) {
+                continue;
+            }
+            let location = match allocation.get(var) {
+                Some(Assignment::Register(reg)) => Location::Register(*reg),
+                _ => Location::Stack(layout.slot(8)),
+            };
+            locations.insert(*var, location);
+        }
+
+        // The outgoing stack arguments go at the bottom, where the callee finds them
+        let outgoing = calls.into_iter().max().unwrap_or(0);
+        layout.slot(8 * outgoing.saturating_sub(ARGUMENT_REGISTERS.len()) as u64);
+
+        Frame {
+            locations,
+            slots,
+            saved,
+            call_saves,
+            size: layout.size(),
+            pic,
+        }
+    }
+
+    fn location(&self, var: &VReg) -> Result<Location, CompileError> {
+        self.locations
+            .get(var)
+            .copied()
+            .ok_or_else(|| CompileError::CodegenError(format!("Could not map var {}", var)))
+    }
+
+    // The register holding var's value, loading it into scratch first if it's spilled
+    fn read(
+        &self,
+        var: &VReg,
+        scratch: RegisterRV,
+        asm: &mut Asm,
+    ) -> Result<RegisterRV, CompileError> {
+        match self.location(var)? {
+            Location::Register(reg) => Ok(reg),
+            location => {
+                asm.push(op("ld", [reg(scratch), location.operand()]));
+                Ok(scratch)
+            }
+        }
+    }
+
+    // The register to compute var's new value in. If var is spilled that's scratch, and
+    // `store` has to be called once the value is there.
+    fn write(&self, var: &VReg, scratch: RegisterRV) -> Result<RegisterRV, CompileError> {
+        match self.location(var)? {
+            Location::Register(reg) => Ok(reg),
+            Location::Stack(_) => Ok(scratch),
+        }
+    }
+
+    // Stores the value computed in `src` back to var's slot if var is spilled
+    fn store(&self, var: &VReg, src: RegisterRV, asm: &mut Asm) -> Result<(), CompileError> {
+        if let location @ Location::Stack(_) = self.location(var)? {
+            asm.push(op("sd", [reg(src), location.operand()]));
+        }
+        Ok(())
+    }
+
+    fn prologue(&self) -> Asm {
+        let size = self.size as i64;
+        let mut asm = vec![
+            op(
+                "addi",
+                [reg(RegisterRV::Sp), reg(RegisterRV::Sp), imm(-size)],
+            ),
+            op("sd", [reg(RegisterRV::Ra), sp(self.size - 8)]),
+            op("sd", [reg(RegisterRV::S0), sp(self.size - 16)]),
+            op(
+                "addi",
+                [reg(RegisterRV::S0), reg(RegisterRV::Sp), imm(size)],
+            ),
+        ];
+        for (saved, offset) in &self.saved {
+            asm.push(op("sd", [reg(*saved), s0(*offset)]));
+        }
+        asm
+    }
+
+    // Undoes the prologue, with ra holding the return address again
+    fn restore(&self) -> Asm {
+        let mut asm: Asm = self
+            .saved
+            .iter()
+            .map(|(saved, offset)| op("ld", [reg(*saved), s0(*offset)]))
+            .collect();
+        asm.extend([
+            op("ld", [reg(RegisterRV::Ra), sp(self.size - 8)]),
+            op("ld", [reg(RegisterRV::S0), sp(self.size - 16)]),
+            op(
+                "addi",
+                [
+                    reg(RegisterRV::Sp),
+                    reg(RegisterRV::Sp),
+                    imm(self.size as i64),
+                ],
+            ),
+        ]);
+        asm
+    }
+
+    fn epilogue(&self) -> Asm {
+        let mut asm = self.restore();
+        asm.push(op("ret", []));
+        asm
+    }
+}
+
+// A register-to-register move, left out when both are the same
+fn mv(src: RegisterRV, dest: RegisterRV) -> Asm {
+    if src == dest {
+        return vec![];
+    }
+    vec![op("mv", [reg(dest), reg(src)])]
+}
+
+// The statements that just put a value in dest, with at most one var to read
+fn definition_to_asm(
+    frame: &Frame,
+    program: &Program,
+    statement: &Statement,
+    dest_var: &VReg,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let dest = frame.write(dest_var, RegisterRV::T6)?;
+    match statement {
+        Statement::Assign { value, .. } => asm.push(op("li", [reg(dest), imm(*value)])),
+        Statement::Copy { src, .. } => {
+            let src = frame.read(src, RegisterRV::T5, &mut asm)?;
+            asm.extend(mv(src, dest));
+        }
+        Statement::Alloca { dest: slot, .. } => {
+            let offset = -(frame.slots[slot] as i64);
+            asm.push(op("addi", [reg(dest), reg(RegisterRV::S0), imm(offset)]));
+        }
+        Statement::Load { addr, width, .. } => {
+            let addr = frame.read(addr, RegisterRV::T5, &mut asm)?;
+            let instruction = if *width == Width::I8 { "lb" } else { "ld" };
+            asm.push(op(instruction, [reg(dest), mem(addr, 0)]));
+        }
+        // With `.option pic` in effect, la loads a global's address from its GOT entry
+        Statement::LoadAddress { label, .. }
+            if frame.pic && program.globals.contains_key(label) =>
+        {
+            asm.push(op("la", [reg(dest), sym(label)]))
+        }
+        Statement::LoadAddress { label, .. } => {
+            let symbol = target::symbol(program, *label);
+            asm.push(op("lla", [reg(dest), sym(symbol)]))
+        }
+        _ => unreachable!(),
+    }
+    frame.store(dest_var, dest, &mut asm)?;
+    Ok(asm)
+}
+
+fn operation_to_asm(
+    frame: &Frame,
+    dest_var: &VReg,
+    op: &BinOp,
+    lhs: &VReg,
+    rhs: &VReg,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let lhs = frame.read(lhs, RegisterRV::T5, &mut asm)?;
+    let rhs = frame.read(rhs, RegisterRV::T6, &mut asm)?;
+    let dest = frame.write(dest_var, RegisterRV::T5)?;
+    // There's only a set-if-less-than, so the other comparisons swap its operands, flip
+    // its result, or test a difference against zero. Each first instruction reads both
+    // operands, so it's fine for dest to share a register with either.
+    let instructions = match op {
+        BinOp::Add => vec![asm::op("add", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Sub => vec![asm::op("sub", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Mul => vec![asm::op("mul", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Div => vec![asm::op("div", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Shl => vec![asm::op("sll", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Shr => vec![asm::op("sra", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::And => vec![asm::op("and", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Or => vec![asm::op("or", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Xor => vec![asm::op("xor", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Eq => vec![
+            asm::op("sub", [reg(dest), reg(lhs), reg(rhs)]),
+            asm::op("seqz", [reg(dest), reg(dest)]),
+        ],
+        BinOp::Ne => vec![
+            asm::op("sub", [reg(dest), reg(lhs), reg(rhs)]),
+            asm::op("snez", [reg(dest), reg(dest)]),
+        ],
+        BinOp::Lt => vec![asm::op("slt", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::Gt => vec![asm::op("slt", [reg(dest), reg(rhs), reg(lhs)])],
+        BinOp::Le => vec![
+            asm::op("slt", [reg(dest), reg(rhs), reg(lhs)]),
+            asm::op("xori", [reg(dest), reg(dest), imm(1)]),
+        ],
+        BinOp::Ge => vec![
+            asm::op("slt", [reg(dest), reg(lhs), reg(rhs)]),
+            asm::op("xori", [reg(dest), reg(dest), imm(1)]),
+        ],
+        BinOp::UDiv => vec![asm::op("divu", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::UShr => vec![asm::op("srl", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::ULt => vec![asm::op("sltu", [reg(dest), reg(lhs), reg(rhs)])],
+        BinOp::UGt => vec![asm::op("sltu", [reg(dest), reg(rhs), reg(lhs)])],
+        BinOp::ULe => vec![
+            asm::op("sltu", [reg(dest), reg(rhs), reg(lhs)]),
+            asm::op("xori", [reg(dest), reg(dest), imm(1)]),
+        ],
+        BinOp::UGe => vec![
+            asm::op("sltu", [reg(dest), reg(lhs), reg(rhs)]),
+            asm::op("xori", [reg(dest), reg(dest), imm(1)]),
+        ],
+    };
+    asm.extend(instructions);
+    frame.store(dest_var, dest, &mut asm)?;
+    Ok(asm)
+}
+
+fn unary_operation_to_asm(
+    frame: &Frame,
+    dest_var: &VReg,
+    op: &UnaryOp,
+    operand: &VReg,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let operand = frame.read(operand, RegisterRV::T5, &mut asm)?;
+    let dest = frame.write(dest_var, RegisterRV::T5)?;
+    let instruction = match op {
+        UnaryOp::Neg => "neg",
+        UnaryOp::Not => "seqz",
+        UnaryOp::BitNot => "not",
+        // There's no sign-extend-byte without the B extension, so the byte is shifted up to
+        // the top and back down
+        UnaryOp::Sext8 => {
+            asm.extend([
+                asm::op("slli", [reg(dest), reg(operand), imm(56)]),
+                asm::op("srai", [reg(dest), reg(dest), imm(56)]),
+            ]);
+            frame.store(dest_var, dest, &mut asm)?;
+            return Ok(asm);
+        }
+        // sext.w is addiw of 0, which sign-extends the low word; zero-extending it takes the
+        // same pair of shifts as a byte, without the B extension's zext.w
+        UnaryOp::Sext32 => "sext.w",
+        UnaryOp::Zext32 => {
+            asm.extend([
+                asm::op("slli", [reg(dest), reg(operand), imm(32)]),
+                asm::op("srli", [reg(dest), reg(dest), imm(32)]),
+            ]);
+            frame.store(dest_var, dest, &mut asm)?;
+            return Ok(asm);
+        }
+    };
+    asm.push(asm::op(instruction, [reg(dest), reg(operand)]));
+    frame.store(dest_var, dest, &mut asm)?;
+    Ok(asm)
+}
+
+// Without a value, a0 is left holding whatever it happens to
+fn return_to_asm(frame: &Frame, var: Option<&VReg>) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    if let Some(var) = var {
+        let src = frame.read(var, RegisterRV::T5, &mut asm)?;
+        asm.extend(mv(src, RegisterRV::A0));
+    }
+    asm.extend(frame.epilogue());
+    Ok(asm)
+}
+
+// Moves each parameter from where the caller left it into the location it was allocated
+fn parameters_to_asm(frame: &Frame, params: &[VReg]) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    for (i, param) in params.iter().enumerate() {
+        // A parameter the body never reads has no location
+        if !frame.locations.contains_key(param) {
+            continue;
+        }
+        let dest = frame.write(param, RegisterRV::T5)?;
+        match ARGUMENT_REGISTERS.get(i) {
+            Some(reg) => asm.extend(mv(*reg, dest)),
+            None => {
+                let offset = 8 * (i - ARGUMENT_REGISTERS.len());
+                asm.push(op("ld", [reg(dest), mem(RegisterRV::S0, offset as i64)]));
+            }
+        }
+        frame.store(param, dest, &mut asm)?;
+    }
+    Ok(asm)
+}
+
+// `live` is what's live just after the call
+fn call_to_asm(
+    frame: &Frame,
+    dest_var: &VReg,
+    func: Symbol,
+    args: &[VReg],
+    live: &VarSet,
+) -> Result<Asm, CompileError> {
+    let mut asm = vec![];
+    let mut saved = vec![];
+    for var in live.iter().filter(|var| *var != dest_var) {
+        if let Location::Register(live_reg) = frame.location(var)?
+            && let Some((_, offset)) = frame.call_saves.iter().find(|(r, _)| *r == live_reg)
+            && !saved.contains(&(live_reg, *offset))
+        {
+            asm.push(op("sd", [reg(live_reg), s0(*offset)]));
+            saved.push((live_reg, *offset));
+        }
+    }
+
+    for (i, arg) in args.iter().enumerate() {
+        match ARGUMENT_REGISTERS.get(i) {
+            Some(argument) => match frame.location(arg)? {
+                Location::Register(src) => asm.extend(mv(src, *argument)),
+                location => asm.push(op("ld", [reg(*argument), location.operand()])),
+            },
+            None => {
+                let src = frame.read(arg, RegisterRV::T5, &mut asm)?;
+                let offset = 8 * (i - ARGUMENT_REGISTERS.len());
+                asm.push(op("sd", [reg(src), sp(offset as u64)]));
+            }
+        }
+    }
+    asm.push(op("call", [sym(func)]));
+
+    // dest is never in one of the saved registers, since they hold vars live alongside it
+    let dest = frame.write(dest_var, RegisterRV::T5)?;
+    asm.extend(mv(RegisterRV::A0, dest));
+    frame.store(dest_var, dest, &mut asm)?;
+    for (saved_reg, offset) in saved {
+        asm.push(op("ld", [reg(saved_reg), s0(offset)]));
+    }
+    Ok(asm)
+}
+
+// Only when every argument goes in a register, since the outgoing argument area is part of
+// the frame being popped. Restoring the frame leaves the argument registers alone, and the
+// tail pseudo-instruction jumps without touching ra.
+fn tail_call_to_asm(
+    frame: &Frame,
+    func: Symbol,
+    args: &[VReg],
+) -> Result<Option<Asm>, CompileError> {
+    if args.len() > ARGUMENT_REGISTERS.len() {
+        return Ok(None);
+    }
+    let mut asm = vec![];
+    for (arg, argument) in args.iter().zip(ARGUMENT_REGISTERS) {
+        match frame.location(arg)? {
+            Location::Register(src) => asm.extend(mv(src, argument)),
+            location => asm.push(op("ld", [reg(argument), location.operand()])),
+        }
+    }
+    asm.extend(frame.restore());
+    asm.push(op("tail", [sym(func)]));
+    Ok(Some(asm))
+}
+
+pub struct RiscV64;
+
+impl TargetBackend for RiscV64 {
+    type Register = RegisterRV;
+    type Frame<'a> = Frame;
+
+    const REGISTERS: &'static [(RegisterRV, RegisterClass)] = &REGISTERS;
+
+    fn frame(
+        cfg: &ControlFlowGraph,
+        allocation: &Allocation<RegisterRV>,
+        options: &CodegenOptions,
+    ) -> Frame {
+        Frame::new(cfg, allocation, options.pic)
+    }
+
+    fn enter(name: &str, frame: &Frame, params: &[VReg]) -> Result<Asm, CompileError> {
+        let mut asm = vec![asm::directive(format!(".globl {}", name)), asm::label(name)];
+        asm.extend(frame.prologue());
+        asm.extend(parameters_to_asm(frame, params)?);
+        Ok(asm)
+    }
+
+    fn jump(label: &str) -> Instruction<RegisterRV> {
+        op("j", [sym(label)])
+    }
+
+    fn branch(frame: &Frame, var: &VReg, label: &str, if_zero: bool) -> Result<Asm, CompileError> {
+        let mut asm = vec![];
+        let var = frame.read(var, RegisterRV::T5, &mut asm)?;
+        let branch = if if_zero { "beqz" } else { "bnez" };
+        asm.push(op(branch, [reg(var), sym(label)]));
+        Ok(asm)
+    }
+
+    // As on x86-64, the table holds each label's offset from the table. `.align` counts in
+    // powers of two here, so the 4-byte alignment is written `.p2align 2` to be clear.
+    fn jump_table(
+        frame: &Frame,
+        var: &VReg,
+        table: &str,
+        labels: &[String],
+        default: &str,
+    ) -> Result<Asm, CompileError> {
+        let mut asm = vec![];
+        let index = frame.read(var, RegisterRV::T5, &mut asm)?;
+        let (t5, t6) = (RegisterRV::T5, RegisterRV::T6);
+        asm.extend([
+            op("li", [reg(t6), imm(labels.len() as i64)]),
+            op("bgeu", [reg(index), reg(t6), sym(default)]),
+            op("lla", [reg(t6), sym(table)]),
+            op("slli", [reg(t5), reg(index), imm(2)]),
+            op("add", [reg(t5), reg(t6), reg(t5)]),
+            op("lw", [reg(t5), mem(t5, 0)]),
+            op("add", [reg(t5), reg(t6), reg(t5)]),
+            op("jr", [reg(t5)]),
+            asm::directive(".section .rodata"),
+            asm::directive(".p2align 2"),
+            asm::label(table),
+        ]);
+        asm.extend(
+            labels
+                .iter()
+                .map(|label| asm::directive(format!(".word {}-{}", label, table))),
+        );
+        asm.push(asm::directive(".text"));
+        Ok(asm)
+    }
+
+    fn ret(frame: &Frame, var: Option<&VReg>) -> Result<Asm, CompileError> {
+        return_to_asm(frame, var)
+    }
+
+    // The assembler's call pseudo-instruction goes through the PLT when it needs to, so
+    // the program isn't needed to tell local calls from library ones
+    fn call(
+        frame: &Frame,
+        _: &Program,
+        dest: &VReg,
+        func: Symbol,
+        args: &[VReg],
+        live: &VarSet,
+    ) -> Result<Asm, CompileError> {
+        call_to_asm(frame, dest, func, args, live)
+    }
+
+    fn tail_call(
+        frame: &Frame,
+        _: &Program,
+        func: Symbol,
+        args: &[VReg],
+    ) -> Result<Option<Asm>, CompileError> {
+        tail_call_to_asm(frame, func, args)
+    }
+
+    fn instruction(
+        frame: &Frame,
+        program: &Program,
+        statement: &Statement,
+    ) -> Result<Asm, CompileError> {
+        match statement {
+            Statement::Assign { var: dest, .. }
+            | Statement::Copy { dest, .. }
+            | Statement::Alloca { dest, .. }
+            | Statement::Load { dest, .. }
+            | Statement::LoadAddress { dest, .. } => {
+                definition_to_asm(frame, program, statement, dest)
+            }
+            Statement::Operation { dest, op, lhs, rhs } => {
+                operation_to_asm(frame, dest, op, lhs, rhs)
+            }
+            Statement::UnaryOperation { dest, op, operand } => {
+                unary_operation_to_asm(frame, dest, op, operand)
+            }
+            Statement::Store { addr, src, width } => {
+                let mut asm = vec![];
+                let addr = frame.read(addr, RegisterRV::T5, &mut asm)?;
+                let src = frame.read(src, RegisterRV::T6, &mut asm)?;
+                let instruction = if *width == Width::I8 { "sb" } else { "sd" };
+                asm.push(op(instruction, [reg(src), mem(addr, 0)]));
+                Ok(asm)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // gp has to be set up before anything the linker may relax into a gp-relative access,
+    // which its own setup mustn't be. main's result is already in a0, and 93 is exit on
+    // Linux.
+    fn start() -> Asm {
+        vec![
+            asm::directive(".globl _start"),
+            asm::label("_start"),
+            asm::directive(".option push"),
+            asm::directive(".option norelax"),
+            op("lla", [reg(RegisterRV::Gp), sym("__global_pointer$")]),
+            asm::directive(".option pop"),
+            // The kernel leaves argc on top of the stack, with argv's pointers right above it
+            op("ld", [reg(RegisterRV::A0), sp(0)]),
+            op("addi", [reg(RegisterRV::A1), reg(RegisterRV::Sp), imm(8)]),
+            op("call", [sym("main")]),
+            op("li", [reg(RegisterRV::A7), imm(93)]),
+            op("ecall", []),
+        ]
+    }
+
+    fn render(instruction: &Instruction<RegisterRV>) -> String {
+        asm::render(instruction, gnu_operand)
+    }
+}
+
+pub fn program_to_asm(
+    program: &Program,
+    options: &CodegenOptions,
+) -> Result<Vec<String>, CompileError> {
+    let mut asm = target::program_to_asm::<RiscV64>(program, options)?;
+    if options.pic {
+        asm.insert(0, ".option pic".to_owned());
+    }
+    Ok(asm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_program;
+
+    #[test]
+    fn riscv_calls() -> Result<(), String> {
+        // v1 is in t0 and still needed after the call, so it's saved in t0's frame slot. The
+        // frame has one for each of t0-t2, the t registers main uses.
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 5; v2 = call neg(v1); v3 = v1 + v2; ret v3
+             }
+             fn neg(v1) {
+             bb0: v2 = -v1; ret v2
+             }",
+        )?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
+
+        let expected = vec![
+            ".globl main",
+            ".type main, @function",
+            "main:",
+            "addi sp, sp, -48",
+            "sd ra, 40(sp)",
+            "sd s0, 32(sp)",
+            "addi s0, sp, 48",
+            "li t0, 5",
+            "sd t0, -24(s0)",
+            "mv a0, t0",
+            "call neg",
+            "mv t1, a0",
+            "ld t0, -24(s0)",
+            "add t2, t0, t1",
+            "mv a0, t2",
+            "ld ra, 40(sp)",
+            "ld s0, 32(sp)",
+            "addi sp, sp, 48",
+            "ret",
+            ".size main, .-main",
+            ".globl neg",
+            ".type neg, @function",
+            "neg:",
+            "addi sp, sp, -16",
+            "sd ra, 8(sp)",
+            "sd s0, 0(sp)",
+            "addi s0, sp, 16",
+            "mv t0, a0",
+            "neg t1, t0",
+            "mv a0, t1",
+            "ld ra, 8(sp)",
+            "ld s0, 0(sp)",
+            "addi sp, sp, 16",
+            "ret",
+            ".size neg, .-neg",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn riscv_tail_calls() -> Result<(), String> {
+        // main's frame is torn down before the jump, so neg returns straight to main's caller
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 5; v2 = tail call neg(v1); ret v2
+             }
+             fn neg(v1) {
+             bb0: v2 = -v1; ret v2
+             }",
+        )?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
+        let neg = asm.iter().position(|s| s == ".globl neg").unwrap();
+
+        let expected_main = vec![
+            ".globl main",
+            ".type main, @function",
+            "main:",
+            "addi sp, sp, -32",
+            "sd ra, 24(sp)",
+            "sd s0, 16(sp)",
+            "addi s0, sp, 32",
+            "li t0, 5",
+            "mv a0, t0",
+            "ld ra, 24(sp)",
+            "ld s0, 16(sp)",
+            "addi sp, sp, 32",
+            "tail neg",
+            ".size main, .-main",
+        ];
+        assert_eq!(asm[..neg], expected_main);
+        Ok(())
+    }
+
+    #[test]
+    fn riscv_pic() -> Result<(), String> {
+        let text =
+            "str0 = \"a\"\nglobal x\nfn main() {\nbb0: v1 = addr x; v2 = addr str0; ret v1\n}";
+        let options = CodegenOptions {
+            pic: true,
+            ..Default::default()
+        };
+        let asm = program_to_asm(&parse_program(text)?, &options)?;
+        assert_eq!(asm[0], ".option pic");
+        assert!(
+            asm.iter()
+                .any(|l| l.starts_with("la ") && l.ends_with(", x"))
+        );
+        assert!(
+            asm.iter()
+                .any(|l| l.starts_with("lla ") && l.ends_with(", .Lstr0"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn riscv_branches_and_comparisons() -> Result<(), String> {
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 1; v2 = 2; v3 = v1 <= v2; if v3 goto bb1 else bb2
+             bb1: ret v1
+             bb2: v4 = v1 != v2; ret v4
+             }",
+        )?;
+        let asm = program_to_asm(&program, &CodegenOptions::default())?;
+
+        // bb1 follows bb0, so only the false branch needs a jump
+        let body: Vec<&str> = asm[7..].iter().map(|s| s.as_str()).collect();
+        assert_eq!(
+            body[..6],
+            [
+                "li t0, 1",
+                "li t1, 2",
+                "slt t2, t1, t0",
+                "xori t2, t2, 1",
+                "beqz t2, .Lmain_2",
+                ".Lmain_1:",
+            ]
+        );
+        assert!(body.contains(&"snez t2, t2"));
+        Ok(())
+    }
+
+    #[test]
+    fn riscv_stack_arguments() -> Result<(), String> {
+        // The ninth argument goes at the bottom of the caller's frame, and the callee reads
+        // it from its own s0
+        let args: Vec<String> = (1..=9).map(|i| format!("v{}", i)).collect();
+        let mut text = String::from("fn main() {\nbb0:");
+        for i in 1..=9 {
+            text.push_str(&format!(" v{} = {};", i, i));
+        }
+        text.push_str(&format!(
+            " v10 = call f({}); ret v10\n}}\n",
+            args.join(", ")
+        ));
+        text.push_str(&format!("fn f({}) {{\nbb0: ret v9\n}}", args.join(", ")));
+        let asm = program_to_asm(&parse_program(&text)?, &CodegenOptions::default())?;
+
+        assert!(asm.contains(&"mv a7, s3".to_owned()));
+        assert!(asm.contains(&"sd s4, 0(sp)".to_owned()));
+        assert!(asm.contains(&"ld t0, 0(s0)".to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::fmt;
+
+/*
+ * A region of the source file. `start` and `end` are byte offsets (end exclusive), and
+ * `line`/`column` are the 1-based position of `start`, so diagnostics can point at the
+ * source without needing the text itself.
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    // The line and column of the last byte the span covers, in `source`, the file it's from
+    pub fn end_position(&self, source: &str) -> (u32, u32) {
+        let last = self.end.max(self.start + 1) - 1;
+        let before = &source.as_bytes()[..last.min(source.len())];
+        let line_start = before
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let lines = before.iter().filter(|&&b| b == b'\n').count();
+        (lines as u32 + 1, (before.len() - line_start) as u32 + 1)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
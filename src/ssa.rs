@@ -0,0 +1,442 @@
+use crate::cfg::*;
+use crate::error::CompileError;
+use crate::liveness::Liveness;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/*
+ * Conversion of a ControlFlowGraph into and out of SSA form.
+ *
+ * Lowering reuses a variable's var for every assignment to it, so `construct` renames them
+ * until every var is defined exactly once. Phi nodes go at the iterated dominance frontier
+ * of each var's definitions, but only where the var is live, so a variable that's dead
+ * after an if/else doesn't get a phi merging it. Each var's first definition keeps its
+ * name and later ones get fresh names numbered after every var already in the graph.
+ *
+ * `destruct` replaces the phis with copies so the graph can go to codegen. The copies for
+ * each edge go in a new block on that edge, through temporaries, so they behave like the
+ * simultaneous assignment a set of phis describes.
+ */
+
+// Blocks reachable from the entry, in reverse postorder
+fn reverse_postorder(cfg: &ControlFlowGraph) -> Vec<ControlBlockId> {
+    fn visit(
+        cfg: &ControlFlowGraph,
+        block: ControlBlockId,
+        seen: &mut HashSet<ControlBlockId>,
+        order: &mut Vec<ControlBlockId>,
+    ) {
+        if !seen.insert(block) {
+            return;
+        }
+        for successor in cfg.successors(block) {
+            visit(cfg, successor, seen, order);
+        }
+        order.push(block);
+    }
+
+    let mut order = vec![];
+    visit(cfg, 0, &mut HashSet::new(), &mut order);
+    order.reverse();
+    order
+}
+
+/*
+ * Immediate dominators, using the iterative algorithm from Cooper, Harvey, and Kennedy's
+ * "A Simple, Fast Dominance Algorithm". The entry block is its own immediate dominator.
+ */
+pub fn dominators(cfg: &ControlFlowGraph) -> BTreeMap<ControlBlockId, ControlBlockId> {
+    let order = reverse_postorder(cfg);
+    let position: HashMap<ControlBlockId, usize> =
+        order.iter().enumerate().map(|(i, b)| (*b, i)).collect();
+    let predecessors = cfg.predecessors();
+
+    let mut idom: BTreeMap<ControlBlockId, ControlBlockId> = BTreeMap::from([(0, 0)]);
+    let intersect = |idom: &BTreeMap<ControlBlockId, ControlBlockId>, mut a, mut b| {
+        while a != b {
+            while position[&a] > position[&b] {
+                a = idom[&a];
+            }
+            while position[&b] > position[&a] {
+                b = idom[&b];
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in order.iter().skip(1) {
+            let mut processed = predecessors[block].iter().filter(|p| idom.contains_key(p));
+            let Some(first) = processed.next() else {
+                continue;
+            };
+            let new_idom = processed.fold(*first, |a, b| intersect(&idom, a, *b));
+            if idom.get(block) != Some(&new_idom) {
+                idom.insert(*block, new_idom);
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+// The blocks where each block's dominance stops: successors of blocks it dominates that it
+// doesn't strictly dominate itself
+pub fn dominance_frontiers(
+    cfg: &ControlFlowGraph,
+    idom: &BTreeMap<ControlBlockId, ControlBlockId>,
+) -> BTreeMap<ControlBlockId, BTreeSet<ControlBlockId>> {
+    let mut frontiers: BTreeMap<ControlBlockId, BTreeSet<ControlBlockId>> =
+        idom.keys().map(|b| (*b, BTreeSet::new())).collect();
+    for (block, predecessors) in cfg.predecessors() {
+        if !idom.contains_key(&block) {
+            continue;
+        }
+        let reachable: Vec<ControlBlockId> = predecessors
+            .into_iter()
+            .filter(|p| idom.contains_key(p))
+            .collect();
+        if reachable.len() < 2 {
+            continue;
+        }
+        for mut runner in reachable {
+            while runner != idom[&block] {
+                frontiers.entry(runner).or_default().insert(block);
+                runner = idom[&runner];
+            }
+        }
+    }
+    frontiers
+}
+
+struct Renamer<'a> {
+    cfg: &'a mut ControlFlowGraph,
+    children: BTreeMap<ControlBlockId, Vec<ControlBlockId>>, // dominator tree
+    phi_vars: HashMap<(ControlBlockId, usize), VReg>,        // original var of each phi
+    renamed: HashSet<VReg>,                                  // vars defined more than once
+    kept: HashSet<VReg>, // renamed vars whose first definition kept the original name
+    stacks: HashMap<VReg, Vec<VReg>>,
+    next_var: u32,
+}
+
+impl Renamer<'_> {
+    fn new_name(&mut self, var: &VReg) -> VReg {
+        let name = if self.kept.insert(*var) {
+            *var
+        } else {
+            self.next_var += 1;
+            VReg(self.next_var)
+        };
+        self.stacks.entry(*var).or_default().push(name);
+        name
+    }
+
+    fn rename_block(&mut self, block: ControlBlockId) -> Result<(), CompileError> {
+        let mut pushed: Vec<VReg> = vec![];
+        let block_statements = self.cfg.get_mut(&block);
+        let mut statements =
+            std::mem::take(block_statements.expect("the dominator tree only has CFG blocks"));
+        for (i, s) in statements.iter_mut().enumerate() {
+            for var in s.used_vars_mut() {
+                if let Some(current) = self.stacks.get(var).and_then(|s| s.last()) {
+                    *var = *current;
+                }
+            }
+            let original = match s {
+                Statement::Phi { .. } => self.phi_vars.get(&(block, i)).copied(),
+                _ => s.defined_var().copied(),
+            };
+            if let Some(original) = original
+                && self.renamed.contains(&original)
+            {
+                let name = self.new_name(&original);
+                *s.defined_var_mut().expect("only definitions are renamed") = name;
+                pushed.push(original);
+            }
+        }
+        let successors = statements.last().map_or(vec![], |s| s.successors());
+        self.cfg.insert(block, statements);
+
+        // Fill in this block's operand of each phi in its successors
+        for successor in successors {
+            for (i, s) in self
+                .cfg
+                .get_mut(&successor)
+                .expect("successors are in the CFG")
+                .iter_mut()
+                .enumerate()
+            {
+                let Statement::Phi { sources, .. } = s else {
+                    break;
+                };
+                let original = &self.phi_vars[&(successor, i)];
+                let Some(current) = self.stacks.get(original).and_then(|s| s.last()) else {
+                    return Err(CompileError::LoweringError(format!(
+                        "No definition of {} reaches block {} from block {}",
+                        original, successor, block
+                    )));
+                };
+                sources.push((block, *current));
+            }
+        }
+
+        for child in self.children.get(&block).cloned().unwrap_or_default() {
+            self.rename_block(child)?;
+        }
+        for var in pushed {
+            let stack = self.stacks.get_mut(&var);
+            stack.expect("a name was pushed for the var").pop();
+        }
+        Ok(())
+    }
+}
+
+pub fn construct(cfg: &mut ControlFlowGraph) -> Result<(), CompileError> {
+    let blocks = reverse_postorder(cfg);
+    let idom = dominators(cfg);
+    let frontiers = dominance_frontiers(cfg, &idom);
+    let live = Liveness::analyze(cfg).live_in;
+
+    // Vars assigned in more than one place, and the blocks that assign them
+    let mut def_blocks: BTreeMap<VReg, BTreeSet<ControlBlockId>> = BTreeMap::new();
+    let mut def_counts: HashMap<VReg, usize> = HashMap::new();
+    for block in &blocks {
+        for var in cfg[block].iter().filter_map(|s| s.defined_var()) {
+            def_blocks.entry(*var).or_default().insert(*block);
+            *def_counts.entry(*var).or_default() += 1;
+        }
+    }
+    let renamed: HashSet<VReg> = def_counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(var, _)| var)
+        .collect();
+
+    let mut phis: BTreeMap<ControlBlockId, Vec<VReg>> = BTreeMap::new();
+    for (var, defs) in def_blocks.iter().filter(|(var, _)| renamed.contains(*var)) {
+        let mut worklist: Vec<ControlBlockId> = defs.iter().copied().collect();
+        let mut placed: BTreeSet<ControlBlockId> = BTreeSet::new();
+        while let Some(block) = worklist.pop() {
+            for frontier in &frontiers[&block] {
+                if live[frontier].contains(var) && placed.insert(*frontier) {
+                    phis.entry(*frontier).or_default().push(*var);
+                    if !defs.contains(frontier) {
+                        worklist.push(*frontier);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut phi_vars = HashMap::new();
+    for (block, vars) in phis {
+        let statements = cfg.get_mut(&block).expect("phis are placed in CFG blocks");
+        for (i, var) in vars.into_iter().enumerate() {
+            statements.insert(
+                i,
+                Statement::Phi {
+                    dest: var,
+                    sources: vec![],
+                },
+            );
+            phi_vars.insert((block, i), var);
+        }
+    }
+
+    let mut children: BTreeMap<ControlBlockId, Vec<ControlBlockId>> = BTreeMap::new();
+    for (block, parent) in &idom {
+        if block != parent {
+            children.entry(*parent).or_default().push(*block);
+        }
+    }
+    let next_var = cfg.max_var_number();
+    Renamer {
+        cfg,
+        children,
+        phi_vars,
+        renamed,
+        kept: HashSet::new(),
+        stacks: HashMap::new(),
+        next_var,
+    }
+    .rename_block(0)
+}
+
+pub fn destruct(cfg: &mut ControlFlowGraph) {
+    let mut next_var = cfg.max_var_number();
+    let mut next_block = cfg.keys().max().map_or(0, |b| b + 1);
+
+    let ids: Vec<ControlBlockId> = cfg.keys().copied().collect();
+    for block in ids {
+        let phi_count = cfg[&block]
+            .iter()
+            .take_while(|s| matches!(s, Statement::Phi { .. }))
+            .count();
+        let statements = cfg.get_mut(&block).expect("the block was just looked up");
+        let phis: Vec<Statement> = statements.drain(..phi_count).collect();
+        if phis.is_empty() {
+            continue;
+        }
+
+        let mut predecessors: BTreeSet<ControlBlockId> = BTreeSet::new();
+        for phi in &phis {
+            if let Statement::Phi { sources, .. } = phi {
+                predecessors.extend(sources.iter().map(|(p, _)| *p));
+            }
+        }
+        for predecessor in predecessors {
+            let mut reads = vec![];
+            let mut writes = vec![];
+            for phi in &phis {
+                let Statement::Phi { dest, sources } = phi else {
+                    continue;
+                };
+                let Some((_, src)) = sources.iter().find(|(p, _)| *p == predecessor) else {
+                    continue;
+                };
+                next_var += 1;
+                let temp = VReg(next_var);
+                reads.push(Statement::Copy {
+                    dest: temp,
+                    src: *src,
+                });
+                writes.push(Statement::Copy {
+                    dest: *dest,
+                    src: temp,
+                });
+            }
+
+            // Route the edge through a new block holding the copies
+            let edge = next_block;
+            next_block += 1;
+            reads.extend(writes);
+            reads.push(Statement::Goto(block));
+            cfg.insert(edge, reads);
+            match cfg.get_mut(&predecessor).and_then(|s| s.last_mut()) {
+                Some(Statement::Goto(target)) => *target = edge,
+                Some(Statement::If {
+                    goto_true,
+                    goto_false,
+                    ..
+                }) => {
+                    if *goto_true == block {
+                        *goto_true = edge;
+                    }
+                    if *goto_false == block {
+                        *goto_false = edge;
+                    }
+                }
+                Some(Statement::Switch {
+                    targets, default, ..
+                }) => {
+                    for target in targets.iter_mut().chain([default]) {
+                        if *target == block {
+                            *target = edge;
+                        }
+                    }
+                }
+                _ => unreachable!("Block {} has no branch to {}", predecessor, block),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::DiagnosticSink;
+    use crate::intern::Symbol;
+    use crate::parser::parse;
+    use crate::symantic_check::check_syntax;
+    use crate::tokenizer::tokenize;
+
+    fn lower_source(source: &str) -> Result<ControlFlowGraph, String> {
+        let ast = parse(&tokenize(source)?)?;
+        check_syntax(&ast, &mut DiagnosticSink::default())?;
+        let mut program = Program::from(&ast)?;
+        let main = program
+            .functions
+            .remove(&Symbol::intern("main"))
+            .ok_or("Missing main")?;
+        Ok(main.cfg)
+    }
+
+    fn assert_single_assignment(cfg: &ControlFlowGraph) {
+        let mut defined = HashSet::new();
+        for var in cfg.values().flatten().filter_map(|s| s.defined_var()) {
+            assert!(defined.insert(*var), "{} is defined twice", var);
+        }
+    }
+
+    #[test]
+    fn test_dominators() -> Result<(), String> {
+        // 0 -> 1 (then) -> 3, 0 -> 2 (else) -> 3
+        let cfg =
+            lower_source("int main() { int x = 1; if (x) { x = 2; } else { x = 3; } return x; }")?;
+        let idom = dominators(&cfg);
+        assert_eq!(idom, BTreeMap::from([(0, 0), (1, 0), (2, 0), (3, 0)]));
+        let frontiers = dominance_frontiers(&cfg, &idom);
+        assert_eq!(frontiers[&1], BTreeSet::from([3]));
+        assert_eq!(frontiers[&2], BTreeSet::from([3]));
+        assert!(frontiers[&0].is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ssa_if_else() -> Result<(), String> {
+        let mut cfg =
+            lower_source("int main() { int x = 1; if (x) { x = 2; } else { x = 3; } return x; }")?;
+        construct(&mut cfg)?;
+        assert_single_assignment(&cfg);
+        assert_eq!(
+            cfg[&3],
+            vec![
+                Statement::Phi {
+                    dest: VReg(6),
+                    sources: vec![(1, VReg(4)), (2, VReg(5))],
+                },
+                Statement::Return(Some(VReg(6))),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ssa_loop() -> Result<(), String> {
+        let mut cfg = lower_source(
+            "int main() { int x = 3; int total = 0; \
+             while (x) { int step = 2; total = total + step; x = x - 1; } return total; }",
+        )?;
+        construct(&mut cfg)?;
+        assert_single_assignment(&cfg);
+
+        // The header merges the values from before the loop and from the back edge, but
+        // `step` is dead there and gets no phi
+        let phis: Vec<&Statement> = cfg[&1]
+            .iter()
+            .filter(|s| matches!(s, Statement::Phi { .. }))
+            .collect();
+        assert_eq!(phis.len(), 2);
+        for phi in phis {
+            let Statement::Phi { sources, .. } = phi else {
+                unreachable!();
+            };
+            let preds: Vec<ControlBlockId> = sources.iter().map(|(p, _)| *p).collect();
+            assert_eq!(preds, vec![0, 2]);
+        }
+
+        destruct(&mut cfg);
+        assert!(
+            cfg.values()
+                .flatten()
+                .all(|s| !matches!(s, Statement::Phi { .. }))
+        );
+        // The edges into the header now go through blocks holding the copies
+        let predecessors = cfg.predecessors();
+        for p in &predecessors[&1] {
+            assert!(matches!(cfg[p].first(), Some(Statement::Copy { .. })));
+        }
+        Ok(())
+    }
+}
@@ -1,90 +1,2596 @@
+use crate::CompileOptions;
 use crate::ast::*;
-use crate::symbol_table::SymbolTable;
+use crate::ast_dump::dump_expr;
+use crate::constant;
+use crate::diagnostic::{DiagnosticSink, Note};
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
+use crate::symbol_table::{GLOBAL_SCOPE, SymbolTable};
+use crate::type_table::TypeTable;
+use std::collections::{HashMap, HashSet};
 
-fn check_scope_expr(expr: &Expr, scope_id: u32, symbol_table: &SymbolTable) -> Result<(), String> {
-    match expr {
-        Expr::BinaryOperation { left, right, .. } => {
-            check_scope_expr(left, scope_id, symbol_table)?;
-            check_scope_expr(right, scope_id, symbol_table)?;
-            Ok(())
-        }
-        Expr::Variable(var_name) => {
-            if let None = symbol_table.get(scope_id, var_name) {
-                return Err(format!(
-                    "Undefined variable {:} in scope {:}",
-                    var_name, scope_id
+// Each problem is recorded in `errors` and checking carries on, so one run reports them all.
+// An error points at the expression it's about when that has a span.
+fn check_scope_expr(
+    expr: &Expr,
+    scope_id: u32,
+    symbol_table: &SymbolTable,
+    errors: &mut Vec<CompileError>,
+) {
+    let mut error = |message| {
+        errors.push(CompileError::SemanticError {
+            message,
+            span: expr.span,
+            notes: vec![],
+        })
+    };
+    let enumerator = |e: &Expr| matches!(e.kind, ExprKind::Variable(name) if symbol_table.is_enumerator(scope_id, name));
+    match &expr.kind {
+        ExprKind::BinaryOperation { op, left, right } => {
+            if op.is_assignment() && (!left.is_lvalue() || enumerator(left)) {
+                error(format!(
+                    "Left side of {} must be an lvalue, but got {}",
+                    op.as_str(),
+                    dump_expr(left)
                 ));
             }
-            Ok(())
+            check_scope_expr(left, scope_id, symbol_table, errors);
+            check_scope_expr(right, scope_id, symbol_table, errors);
         }
-        _ => Ok(()),
+        ExprKind::UnaryOperation { expr, .. } => {
+            check_scope_expr(expr, scope_id, symbol_table, errors)
+        }
+        ExprKind::Variable(var_name)
+            if symbol_table.get(scope_id, *var_name).is_none() && !enumerator(expr) =>
+        {
+            error(format!(
+                "Undefined variable {:} in scope {:}",
+                var_name, scope_id
+            ));
+        }
+        ExprKind::Call { name, args } => {
+            if symbol_table.get_function(*name).is_none() {
+                error(format!("Undefined function {}", name));
+            }
+            for arg in args {
+                check_scope_expr(arg, scope_id, symbol_table, errors);
+            }
+        }
+        _ => {}
     }
 }
 
-fn check_scope(scope: &Scope, symbol_table: &SymbolTable) -> Result<(), String> {
+fn check_scope(
+    scope: &Scope,
+    symbol_table: &SymbolTable,
+    diagnostics: &mut DiagnosticSink,
+    errors: &mut Vec<CompileError>,
+) {
     for s in scope.statements.iter() {
-        match s {
-            Statement::Return(expr)
-            | Statement::Expression(expr)
-            | Statement::VarDeclare {
-                value: Some(expr), ..
-            } => check_scope_expr(&expr, scope.id, symbol_table)?,
-            Statement::If {
+        if let StatementKind::VarDeclare { name, span, .. } = &s.kind
+            && let Some(parent) = symbol_table.parent_scope(scope.id)
+            && let Some((_, previous)) = symbol_table.resolve(parent, *name)
+        {
+            diagnostics.warn_with_notes(
+                "shadow",
+                format!("Declaration of {} shadows a previous declaration", name),
+                Some(*span),
+                vec![Note::new(
+                    format!("Previous declaration of {}", name),
+                    Some(previous.span),
+                )],
+            );
+        }
+        // Loops are desugared into statements with no span of their own, which would
+        // warn about a for loop's step once for every copy of it
+        if let StatementKind::Expression(expr) = &s.kind
+            && s.span.is_some()
+            && !expr.has_side_effects()
+        {
+            diagnostics.warn(
+                "unused-value",
+                "Statement has no effect".to_owned(),
+                expr.span.or(s.span),
+            );
+        }
+
+        let mut statement_errors = vec![];
+        check_statement(
+            s,
+            scope.id,
+            symbol_table,
+            diagnostics,
+            &mut statement_errors,
+        );
+        errors.extend(statement_errors.into_iter().map(|e| e.or_span(s.span)));
+    }
+}
+
+fn check_statement(
+    s: &Statement,
+    scope_id: u32,
+    symbol_table: &SymbolTable,
+    diagnostics: &mut DiagnosticSink,
+    errors: &mut Vec<CompileError>,
+) {
+    match &s.kind {
+        StatementKind::Return(Some(expr))
+        | StatementKind::Expression(expr)
+        | StatementKind::VarDeclare {
+            value: Some(expr), ..
+        }
+        | StatementKind::Case(expr) => check_scope_expr(expr, scope_id, symbol_table, errors),
+        StatementKind::If {
+            condition,
+            true_block,
+            false_block,
+        } => {
+            check_scope_expr(condition, scope_id, symbol_table, errors);
+            check_scope(true_block, symbol_table, diagnostics, errors);
+            if let Some(false_scope) = false_block {
+                check_scope(false_scope, symbol_table, diagnostics, errors);
+            }
+        }
+        StatementKind::While {
+            condition: expr,
+            body,
+        }
+        | StatementKind::Switch { value: expr, body } => {
+            check_scope_expr(expr, scope_id, symbol_table, errors);
+            check_scope(body, symbol_table, diagnostics, errors);
+        }
+        StatementKind::Block(block) => check_scope(block, symbol_table, diagnostics, errors),
+        _ => {}
+    }
+}
+
+// Records the (scope, name) of every variable referred to in the scope, however it's used
+fn collect_uses(scope: &Scope, symbol_table: &SymbolTable, used: &mut HashSet<(u32, Symbol)>) {
+    fn collect_expr(
+        expr: &Expr,
+        scope_id: u32,
+        symbol_table: &SymbolTable,
+        used: &mut HashSet<(u32, Symbol)>,
+    ) {
+        match &expr.kind {
+            ExprKind::Variable(name) => {
+                if let Some((declared_in, _)) = symbol_table.resolve(scope_id, *name) {
+                    used.insert((declared_in, *name));
+                }
+            }
+            ExprKind::BinaryOperation { left, right, .. } => {
+                collect_expr(left, scope_id, symbol_table, used);
+                collect_expr(right, scope_id, symbol_table, used);
+            }
+            ExprKind::UnaryOperation { expr, .. } | ExprKind::Cast { expr, .. } => {
+                collect_expr(expr, scope_id, symbol_table, used)
+            }
+            ExprKind::Call { args, .. } => {
+                for arg in args {
+                    collect_expr(arg, scope_id, symbol_table, used);
+                }
+            }
+            ExprKind::IntLiteral(_) | ExprKind::StringLiteral(_) => {}
+        }
+    }
+
+    for s in &scope.statements {
+        for expr in s.exprs() {
+            collect_expr(expr, scope.id, symbol_table, used);
+        }
+        for child in s.child_scopes() {
+            collect_uses(child, symbol_table, used);
+        }
+    }
+}
+
+// Warns about local variables that are declared but never referred to. Parameters don't
+// count, since the signature may be fixed by whoever calls the function.
+fn warn_unused(scope: &Scope, used: &HashSet<(u32, Symbol)>, diagnostics: &mut DiagnosticSink) {
+    for s in &scope.statements {
+        if let StatementKind::VarDeclare { name, span, .. } = &s.kind
+            && !used.contains(&(scope.id, *name))
+        {
+            diagnostics.warn(
+                "unused-variable",
+                format!("Unused variable {}", name),
+                Some(*span),
+            );
+        }
+        for child in s.child_scopes() {
+            warn_unused(child, used, diagnostics);
+        }
+    }
+}
+
+/*
+ * Bottom-up type checking. Every expression's resolved type is recorded in a NodeTable so
+ * later stages don't have to recompute it, and so is every variable's, under the id of the
+ * statement or global that declares it, with any typedef replaced by what it stands for.
+ *
+ * Implicit conversions are made explicit along the way: integer operands are converted to a
+ * common type before arithmetic or comparison, which is unsigned if either of them is and
+ * int otherwise, and values are converted to the type of whatever they're stored into or
+ * returned as. Each conversion wraps the expression in a Cast node with a fresh id, so the
+ * CFG never has to work out an operation's type itself.
+ */
+
+// Whether a value of type `value` may be stored into something of type `target`
+fn is_assignable(target: &Type, value: &Type) -> bool {
+    target == value || (target.is_integer() && value.is_integer())
+}
+
+// What an integer operand is promoted to on its own, as for a unary operator or a shift
+fn promote(operand: &Type) -> Type {
+    match operand {
+        Type::Unsigned => Type::Unsigned,
+        _ => Type::Int,
+    }
+}
+
+// The type two integer operands are both converted to
+fn common_type(left: &Type, right: &Type) -> Type {
+    match (promote(left), promote(right)) {
+        (Type::Int, Type::Int) => Type::Int,
+        _ => Type::Unsigned,
+    }
+}
+
+fn check_binary_operation(op: &BinOp, left: Type, right: Type) -> Result<Type, CompileError> {
+    if let Some(arithmetic_op) = op.compound_operator() {
+        let value = check_binary_operation(&arithmetic_op, left.clone(), right)?;
+        return check_binary_operation(&BinOp::Assign, left, value);
+    }
+
+    match op {
+        BinOp::Assign if is_assignable(&left, &right) => Ok(left),
+        BinOp::Assign => Err(CompileError::semantic(format!(
+            "Type error: cannot assign a value of type {} to {}",
+            right, left
+        ))),
+        // A shift has the type of its left operand, where the rest have their common type
+        BinOp::ShiftLeft | BinOp::ShiftRight if left.is_integer() && right.is_integer() => {
+            Ok(promote(&left))
+        }
+        op if op.is_arithmetic() && left.is_integer() && right.is_integer() => {
+            Ok(common_type(&left, &right))
+        }
+        op if op.is_comparison()
+            && (left == right || (left.is_integer() && right.is_integer())) =>
+        {
+            Ok(Type::Int)
+        }
+        BinOp::LogicalAnd | BinOp::LogicalOr if left.is_scalar() && right.is_scalar() => {
+            Ok(Type::Int)
+        }
+        _ => Err(CompileError::semantic(format!(
+            "Type error: invalid operands to {} ({} and {})",
+            op.as_str(),
+            left,
+            right
+        ))),
+    }
+}
+
+// The value an integer constant ends up with after being stored into `target`
+fn convert_constant(value: i64, target: &Type) -> i64 {
+    match target {
+        Type::Char => value as u8 as i8 as i64,
+        Type::Unsigned => value as u32 as i64,
+        _ => value as u32 as i32 as i64,
+    }
+}
+
+// The warning for a constant right operand the operation isn't defined for. Shifts are only
+// defined for counts less than the width of the promoted left operand, which is always 32 bits.
+fn undefined_operation(op: &BinOp, rhs: i64) -> Option<(&'static str, String)> {
+    match op {
+        BinOp::Div if rhs == 0 => Some(("div-by-zero", "Division by zero".to_owned())),
+        BinOp::ShiftLeft | BinOp::ShiftRight if rhs < 0 => Some((
+            "shift-count-negative",
+            format!("Shift count {} is negative", rhs),
+        )),
+        BinOp::ShiftLeft | BinOp::ShiftRight if rhs >= 32 => Some((
+            "shift-count-overflow",
+            format!(
+                "Shift count {} is not less than the width of the type (32 bits)",
+                rhs
+            ),
+        )),
+        _ => None,
+    }
+}
+
+struct TypeChecker<'a> {
+    symbol_table: &'a SymbolTable,
+    type_table: &'a TypeTable,
+    types: NodeTable<Type>,
+    node_id_counter: NodeIdCounter,
+    diagnostics: &'a mut DiagnosticSink,
+    // The values of the expressions made only of constants, as the generated code computes them
+    constants: NodeTable<i64>,
+    // Where the statement or declaration being checked is, for the diagnostics about it
+    span: Option<Span>,
+    // A statement with a type error is skipped over, and checking goes on with the next one
+    errors: Vec<CompileError>,
+}
+
+impl TypeChecker<'_> {
+    fn check_expr_type(&mut self, expr: &mut Expr, scope_id: u32) -> Result<Type, CompileError> {
+        let expr_type = match &mut expr.kind {
+            ExprKind::IntLiteral(_) => Type::Int,
+            ExprKind::StringLiteral(_) => Type::Pointer(Box::new(Type::Char)),
+            ExprKind::Variable(var_name) => match self.symbol_table.get(scope_id, *var_name) {
+                Some(var_info) => self.type_table.resolve(&var_info.var_type),
+                // An enumerator is a constant of type int
+                None if self.symbol_table.is_enumerator(scope_id, *var_name) => {
+                    let value = self.type_table.enumerators()[var_name];
+                    self.constants.insert(expr.id, value);
+                    Type::Int
+                }
+                // Where the syntax check reports it too, so the two are the same error
+                None => {
+                    return Err(CompileError::SemanticError {
+                        message: format!("Undefined variable {:} in scope {:}", var_name, scope_id),
+                        span: expr.span,
+                        notes: vec![],
+                    });
+                }
+            },
+            ExprKind::BinaryOperation { op, left, right } => {
+                let mut left_type = self.check_expr_type(left, scope_id)?;
+                let mut right_type = self.check_expr_type(right, scope_id)?;
+                if let BinOp::Assign | BinOp::Equals | BinOp::NotEquals = op {
+                    right_type = self.null_pointer(right, right_type, &left_type);
+                    left_type = self.null_pointer(left, left_type, &right_type);
+                }
+                let result_type =
+                    check_binary_operation(op, left_type.clone(), right_type.clone())?;
+                match op {
+                    BinOp::Assign => self.convert(right, &right_type, &left_type),
+                    BinOp::ShiftLeft | BinOp::ShiftRight => {
+                        self.convert(left, &left_type, &promote(&left_type));
+                        self.convert(right, &right_type, &promote(&right_type));
+                    }
+                    op if (op.is_arithmetic() || op.is_comparison()) && left_type.is_integer() => {
+                        let common = common_type(&left_type, &right_type);
+                        self.convert(left, &left_type, &common);
+                        self.convert(right, &right_type, &common);
+                    }
+                    _ => {}
+                }
+                result_type
+            }
+            // `!` works on anything that can be tested for truthiness and yields an int, and
+            // `-` and `~` only on integers, yielding the promoted operand. `&` needs something
+            // with an address, and `*` a pointer to something other than void.
+            ExprKind::UnaryOperation { op, expr } => {
+                let operand_type = self.check_expr_type(expr, scope_id)?;
+                match (op, operand_type) {
+                    (UnaryOp::Not, operand_type) if operand_type.is_scalar() => Type::Int,
+                    (UnaryOp::Neg | UnaryOp::BitNot, operand_type) if operand_type.is_integer() => {
+                        let promoted = promote(&operand_type);
+                        self.convert(expr, &operand_type, &promoted);
+                        promoted
+                    }
+                    (UnaryOp::AddrOf, operand_type)
+                        if expr.is_lvalue() && !self.is_enumerator(expr, scope_id) =>
+                    {
+                        Type::Pointer(Box::new(operand_type))
+                    }
+                    (UnaryOp::Deref, Type::Pointer(target)) if *target != Type::Void => *target,
+                    (op, operand_type) => {
+                        return Err(CompileError::semantic(format!(
+                            "Type error: invalid operand to unary {} ({})",
+                            op.as_str(),
+                            operand_type
+                        )));
+                    }
+                }
+            }
+            ExprKind::Call { name, args } => {
+                let Some(signature) = self.symbol_table.get_function(*name) else {
+                    return Err(CompileError::SemanticError {
+                        message: format!("Undefined function {}", name),
+                        span: expr.span,
+                        notes: vec![],
+                    });
+                };
+                let params = signature.params.len();
+                if args.len() < params || (args.len() > params && !signature.variadic) {
+                    return Err(CompileError::semantic(format!(
+                        "Function {} expects {}{} arguments, but got {}",
+                        name,
+                        if signature.variadic { "at least " } else { "" },
+                        params,
+                        args.len()
+                    )));
+                }
+                for (i, (arg, param)) in args.iter_mut().zip(&signature.params).enumerate() {
+                    let param = &self.type_table.resolve(param);
+                    let arg_type = self.check_expr_type(arg, scope_id)?;
+                    let arg_type = self.null_pointer(arg, arg_type, param);
+                    if !is_assignable(param, &arg_type) {
+                        return Err(CompileError::semantic(format!(
+                            "Type error: argument {} of {} has type {} but {} was expected",
+                            i + 1,
+                            name,
+                            arg_type,
+                            param
+                        )));
+                    }
+                    self.convert(arg, &arg_type, param);
+                }
+                // The variable arguments have no parameter types to convert to, so they get
+                // the default promotions instead, which only widen a char to an int
+                for (i, arg) in args.iter_mut().enumerate().skip(params) {
+                    let arg_type = self.check_expr_type(arg, scope_id)?;
+                    if !arg_type.is_scalar() {
+                        return Err(CompileError::semantic(format!(
+                            "Type error: argument {} of {} has type {}, which can't be passed \
+                             as a variable argument",
+                            i + 1,
+                            name,
+                            arg_type
+                        )));
+                    }
+                    if arg_type == Type::Char {
+                        self.convert(arg, &arg_type, &Type::Int);
+                    }
+                }
+                self.type_table.resolve(&signature.return_type)
+            }
+            ExprKind::Cast { target, expr } => {
+                self.check_expr_type(expr, scope_id)?;
+                target.clone()
+            }
+        };
+
+        self.types.insert(expr.id, expr_type.clone());
+        self.fold_constant(expr, &expr_type);
+        Ok(expr_type)
+    }
+
+    // An integer constant expression that's 0 is the null pointer constant, which converts to any
+    // pointer type. Gives the type `expr` has where a `target` is wanted.
+    fn null_pointer(&mut self, expr: &Expr, value_type: Type, target: &Type) -> Type {
+        let null = value_type.is_integer() && self.constants.get(&expr.id) == Some(&0);
+        if !null || !matches!(target, Type::Pointer(_)) {
+            return value_type;
+        }
+        self.types.insert(expr.id, target.clone());
+        target.clone()
+    }
+
+    fn is_enumerator(&self, expr: &Expr, scope_id: u32) -> bool {
+        matches!(expr.kind, ExprKind::Variable(name) if self.symbol_table.is_enumerator(scope_id, name))
+    }
+
+    // Records the value of an expression whose operands are constants, warning when it's an int
+    // computation whose exact result doesn't fit in an int, or a division or shift its right
+    // operand leaves undefined. Only the first such problem is reported, since what's computed
+    // from it has a value that's already wrong.
+    fn fold_constant(&mut self, expr: &Expr, expr_type: &Type) {
+        let value = |e: &Expr| self.constants.get(&e.id).copied();
+        let literal = |value| Box::new(Expr::new(expr.id, ExprKind::IntLiteral(value)));
+        // The expression with its operands replaced by their values, and what it would be
+        // without wrapping, where it can overflow
+        let (shallow, exact, shown) = match &expr.kind {
+            ExprKind::IntLiteral(value) => {
+                self.constants.insert(expr.id, *value);
+                return;
+            }
+            ExprKind::UnaryOperation { op, expr: operand } => {
+                let Some(operand) = value(operand) else {
+                    return;
+                };
+                let exact = (*op == UnaryOp::Neg).then(|| -(operand as i128));
+                let kind = ExprKind::UnaryOperation {
+                    op: op.clone(),
+                    expr: literal(operand),
+                };
+                (kind, exact, format!("{}{}", op.as_str(), operand))
+            }
+            ExprKind::BinaryOperation { op, left, right } => {
+                // Dividing or shifting by a bad constant is wrong whatever the left side is
+                if let Some((name, message)) = value(right).and_then(|r| undefined_operation(op, r))
+                {
+                    self.diagnostics
+                        .warn(name, message, expr.span.or(self.span));
+                    return;
+                }
+                let (Some(lhs), Some(rhs)) = (value(left), value(right)) else {
+                    return;
+                };
+                let (l, r) = (lhs as i128, rhs as i128);
+                let exact = match op {
+                    BinOp::Add => Some(l + r),
+                    BinOp::Sub => Some(l - r),
+                    BinOp::Mul => Some(l * r),
+                    BinOp::Div => Some(l / r),
+                    BinOp::ShiftLeft => Some(l << r),
+                    _ => None,
+                };
+                let kind = ExprKind::BinaryOperation {
+                    op: op.clone(),
+                    left: literal(lhs),
+                    right: literal(rhs),
+                };
+                (kind, exact, format!("{} {} {}", lhs, op.as_str(), rhs))
+            }
+            ExprKind::Cast {
+                target,
+                expr: operand,
+            } => {
+                let Some(operand) = value(operand) else {
+                    return;
+                };
+                let kind = ExprKind::Cast {
+                    target: target.clone(),
+                    expr: literal(operand),
+                };
+                (kind, None, String::new())
+            }
+            _ => return,
+        };
+
+        if let Some(exact) = exact
+            && *expr_type == Type::Int
+            && i32::try_from(exact).is_err()
+        {
+            self.diagnostics.warn(
+                "overflow",
+                format!(
+                    "Integer overflow: {} is {}, which doesn't fit in int",
+                    shown, exact
+                ),
+                expr.span.or(self.span),
+            );
+            return;
+        }
+        let shallow = Expr::new(expr.id, shallow);
+        if let Ok(folded) = constant::evaluate(&shallow, self.type_table.enumerators()) {
+            self.constants.insert(expr.id, folded);
+        }
+    }
+
+    // Wraps `expr` in a Cast to `to`, if converting from `from` actually changes the type
+    fn convert(&mut self, expr: &mut Expr, from: &Type, to: &Type) {
+        if !from.is_integer() || !to.is_integer() {
+            return;
+        }
+
+        match expr.kind {
+            // A literal is an int, but one that's too large for an int is fine as long as it's
+            // converted to a type it fits in
+            ExprKind::IntLiteral(value) if from == to => {
+                if convert_constant(value, to) != value {
+                    self.diagnostics.warn(
+                        "overflow",
+                        format!("Integer constant {} is too large for {}", value, to),
+                        expr.span.or(self.span),
+                    );
+                }
+                return;
+            }
+            _ if from == to => return,
+            ExprKind::IntLiteral(value) => {
+                let converted = convert_constant(value, to);
+                if converted != value {
+                    self.diagnostics.warn(
+                        "overflow",
+                        format!(
+                            "Implicit conversion from {} to {} changes value from {} to {}",
+                            from, to, value, converted
+                        ),
+                        expr.span.or(self.span),
+                    );
+                }
+            }
+            _ if matches!(from, Type::Int | Type::Unsigned) && *to == Type::Char => {
+                self.diagnostics.warn(
+                    "conversion",
+                    format!("Conversion from {} to {} may change value", from, to),
+                    expr.span.or(self.span),
+                )
+            }
+            _ => {}
+        }
+
+        let id = self.node_id_counter.next();
+        let inner = std::mem::replace(expr, Expr::new(id, ExprKind::IntLiteral(0)));
+        *expr = Expr::new(
+            id,
+            ExprKind::Cast {
+                target: to.clone(),
+                expr: Box::new(inner),
+            },
+        );
+        self.types.insert(id, to.clone());
+        self.fold_constant(expr, to);
+    }
+
+    fn check_condition_type(
+        &mut self,
+        condition: &mut Expr,
+        scope_id: u32,
+    ) -> Result<(), CompileError> {
+        let condition_type = self.check_expr_type(condition, scope_id)?;
+        if !condition_type.is_scalar() {
+            return Err(CompileError::semantic(format!(
+                "Type error: condition has non-scalar type {}",
+                condition_type
+            )));
+        }
+        Ok(())
+    }
+
+    // Each case value must be an integer constant that no other case in the switch has, and
+    // there can be at most one default label
+    fn check_case_labels(
+        &mut self,
+        body: &mut Scope,
+        value_type: &Type,
+    ) -> Result<(), CompileError> {
+        let mut values = HashSet::new();
+        let mut has_default = false;
+        for s in body.statements.iter_mut() {
+            let span = s.span;
+            let value = match &mut s.kind {
+                StatementKind::Case(value) => value,
+                StatementKind::Default if has_default => {
+                    return Err(CompileError::semantic(
+                        "Multiple default labels in one switch".to_owned(),
+                    )
+                    .or_span(span));
+                }
+                StatementKind::Default => {
+                    has_default = true;
+                    continue;
+                }
+                _ => continue,
+            };
+            self.span = span;
+            let case_type = self.check_expr_type(value, body.id)?;
+            if !case_type.is_integer() {
+                return Err(CompileError::semantic(format!(
+                    "Type error: case value has non-integer type {}",
+                    case_type
+                ))
+                .or_span(span));
+            }
+            self.convert(value, &case_type, value_type);
+            let constant =
+                constant::evaluate(value, self.type_table.enumerators()).map_err(|e| {
+                    CompileError::semantic(format!("Case value is not a constant: {}", e.message()))
+                        .or_span(span)
+                })?;
+            if !values.insert(constant) {
+                return Err(
+                    CompileError::semantic(format!("Duplicate case value {}", constant))
+                        .or_span(span),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_scope_types(&mut self, scope: &mut Scope, return_type: &Type) {
+        for s in scope.statements.iter_mut() {
+            let span = s.span;
+            self.span = span;
+            if let Err(e) = self.check_statement_types(s, scope.id, return_type) {
+                self.errors.push(e.or_span(span));
+            }
+        }
+    }
+
+    fn check_statement_types(
+        &mut self,
+        s: &mut Statement,
+        scope_id: u32,
+        return_type: &Type,
+    ) -> Result<(), CompileError> {
+        let id = s.id;
+        match &mut s.kind {
+            StatementKind::Return(Some(expr)) => {
+                let value_type = self.check_expr_type(expr, scope_id)?;
+                let value_type = self.null_pointer(expr, value_type, return_type);
+                // Mismatches are reported by check_returns
+                if is_assignable(return_type, &value_type) {
+                    self.convert(expr, &value_type, return_type);
+                }
+            }
+            StatementKind::Expression(expr) => {
+                self.check_expr_type(expr, scope_id)?;
+            }
+            StatementKind::Return(None) => {}
+            StatementKind::VarDeclare {
+                name,
+                var_type,
+                value,
+                ..
+            } => {
+                let var_type = self.type_table.resolve(var_type);
+                if matches!(var_type, Type::Struct(_) | Type::Enum(_))
+                    && !self.type_table.is_complete(&var_type)
+                {
+                    return Err(CompileError::semantic(format!(
+                        "Variable {} has incomplete type {}",
+                        name, var_type
+                    )));
+                }
+                self.types.insert(id, var_type.clone());
+                let Some(value) = value else {
+                    return Ok(());
+                };
+                let value_type = self.check_expr_type(value, scope_id)?;
+                let value_type = self.null_pointer(value, value_type, &var_type);
+                if !is_assignable(&var_type, &value_type) {
+                    return Err(CompileError::semantic(format!(
+                        "Type error: cannot initialize {} {} with a value of type {}",
+                        var_type, name, value_type
+                    )));
+                }
+                self.convert(value, &value_type, &var_type);
+            }
+            StatementKind::If {
                 condition,
                 true_block,
                 false_block,
             } => {
-                check_scope_expr(condition, scope.id, symbol_table)?;
-                check_scope(true_block, symbol_table)?;
+                self.check_condition_type(condition, scope_id)?;
+                self.check_scope_types(true_block, return_type);
                 if let Some(false_scope) = false_block {
-                    check_scope(false_scope, symbol_table)?;
+                    self.check_scope_types(false_scope, return_type);
+                }
+            }
+            StatementKind::While { condition, body } => {
+                self.check_condition_type(condition, scope_id)?;
+                self.check_scope_types(body, return_type);
+            }
+            // The value gets the integer promotions, and the case values are converted to the
+            // promoted type
+            StatementKind::Switch { value, body } => {
+                let value_type = self.check_expr_type(value, scope_id)?;
+                if !value_type.is_integer() {
+                    return Err(CompileError::semantic(format!(
+                        "Type error: switch value has non-integer type {}",
+                        value_type
+                    )));
+                }
+                let promoted = promote(&value_type);
+                self.convert(value, &value_type, &promoted);
+                self.check_case_labels(body, &promoted)?;
+                self.check_scope_types(body, return_type);
+            }
+            // Checked along with the switch they're in
+            StatementKind::Case(_) | StatementKind::Default => {}
+            StatementKind::Block(block) => self.check_scope_types(block, return_type),
+            StatementKind::Break | StatementKind::Continue => {}
+            StatementKind::For { .. } => {
+                return Err(CompileError::semantic(
+                    "For loops must be desugared before type checking".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn check_types(
+    declarations: &mut [Declaration],
+    symbol_table: &SymbolTable,
+    type_table: &TypeTable,
+    diagnostics: &mut DiagnosticSink,
+) -> Result<NodeTable<Type>, CompileError> {
+    let mut errors = vec![];
+    let types = type_errors(
+        declarations,
+        symbol_table,
+        type_table,
+        diagnostics,
+        &mut errors,
+    );
+    report(errors, diagnostics)?;
+    Ok(types)
+}
+
+fn type_errors(
+    declarations: &mut [Declaration],
+    symbol_table: &SymbolTable,
+    type_table: &TypeTable,
+    diagnostics: &mut DiagnosticSink,
+    errors: &mut Vec<CompileError>,
+) -> NodeTable<Type> {
+    let mut checker = TypeChecker {
+        symbol_table,
+        type_table,
+        types: NodeTable::new(),
+        node_id_counter: NodeIdCounter::after(declarations),
+        diagnostics,
+        constants: NodeTable::new(),
+        span: None,
+        errors: vec![],
+    };
+    for dec in declarations.iter_mut() {
+        match dec {
+            Declaration::Function {
+                return_type, scope, ..
+            } => checker.check_scope_types(scope, &type_table.resolve(return_type)),
+            Declaration::Global {
+                id,
+                span,
+                name,
+                var_type,
+                value,
+            } => {
+                let var_type = type_table.resolve(var_type);
+                checker.types.insert(*id, var_type.clone());
+                checker.span = Some(*span);
+                let Some(value) = value else {
+                    continue;
+                };
+                let value_type = match checker.check_expr_type(value, GLOBAL_SCOPE) {
+                    Ok(value_type) => checker.null_pointer(value, value_type, &var_type),
+                    Err(e) => {
+                        checker.errors.push(e.or_span(Some(*span)));
+                        continue;
+                    }
+                };
+                if !is_assignable(&var_type, &value_type) {
+                    checker.errors.push(CompileError::SemanticError {
+                        message: format!(
+                            "Type error: cannot initialize {} {} with a value of type {}",
+                            var_type, name, value_type
+                        ),
+                        span: Some(*span),
+                        notes: vec![],
+                    });
+                    continue;
+                }
+                checker.convert(value, &value_type, &var_type);
+                // A global's value is in the executable before any code runs, so it has to be
+                // a constant expression, or a string, whose address is known by then too
+                if matches!(value.kind, ExprKind::StringLiteral(_)) {
+                    continue;
+                }
+                if let Err(e) = constant::evaluate(value, type_table.enumerators()) {
+                    checker.errors.push(CompileError::SemanticError {
+                        message: format!(
+                            "Initializer of global {} is not a constant: {}",
+                            name,
+                            e.message()
+                        ),
+                        span: Some(*span),
+                        notes: vec![],
+                    });
                 }
             }
             _ => {}
         }
     }
+    errors.extend(checker.errors);
+    checker.types
+}
 
-    Ok(())
+/*
+ * Return checking: every `return` must agree with its function's declared return type, and
+ * control must not fall off the end of a non-void function. main is exempt from the latter
+ * since reaching its closing brace returns 0.
+ */
+
+fn check_scope_returns(
+    scope: &Scope,
+    name: &str,
+    return_type: &Type,
+    types: &NodeTable<Type>,
+    errors: &mut Vec<CompileError>,
+) {
+    for s in scope.statements.iter() {
+        match &s.kind {
+            StatementKind::Return(Some(_)) if *return_type == Type::Void => {
+                errors.push(
+                    CompileError::semantic(format!("Void function {} cannot return a value", name))
+                        .or_span(s.span),
+                );
+            }
+            StatementKind::Return(Some(expr)) => {
+                // A value without a type was wrong in a way the type checker has reported
+                let Some(value_type) = types.get(&expr.id) else {
+                    continue;
+                };
+                if !is_assignable(return_type, value_type) {
+                    errors.push(CompileError::semantic(format!(
+                        "Type error: function {} returns a value of type {} but is declared to return {}",
+                        name, value_type, return_type
+                    )).or_span(s.span));
+                }
+            }
+            StatementKind::Return(None) if *return_type != Type::Void => {
+                errors.push(
+                    CompileError::semantic(format!(
+                        "Non-void function {} must return a value of type {}",
+                        name, return_type
+                    ))
+                    .or_span(s.span),
+                );
+            }
+            StatementKind::If {
+                true_block,
+                false_block,
+                ..
+            } => {
+                check_scope_returns(true_block, name, return_type, types, errors);
+                if let Some(false_scope) = false_block {
+                    check_scope_returns(false_scope, name, return_type, types, errors);
+                }
+            }
+            StatementKind::While { body, .. }
+            | StatementKind::Switch { body, .. }
+            | StatementKind::Block(body) => {
+                check_scope_returns(body, name, return_type, types, errors)
+            }
+            _ => {}
+        }
+    }
 }
 
-pub fn check_syntax(declarations: &Vec<Declaration>) -> Result<SymbolTable, String> {
-    // For now, we're only considering programs with a single declaration: a main function
-    assert_eq!(declarations.len(), 1);
+// Whether control can never reach the end of `statements`
+fn always_returns(statements: &[Statement]) -> bool {
+    statements.iter().any(|s| match &s.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::If {
+            true_block,
+            false_block: Some(false_scope),
+            ..
+        } => always_returns(&true_block.statements) && always_returns(&false_scope.statements),
+        StatementKind::Block(block) => always_returns(&block.statements),
+        // `while (1)` without a break can only be left through a return
+        StatementKind::While { condition, body } => {
+            matches!(condition.kind, ExprKind::IntLiteral(v) if v != 0)
+                && !breaks_out(&body.statements)
+        }
+        // With a default label, control always goes to one of the labels. Without a break,
+        // it can then only leave through the end of the last label's statements.
+        StatementKind::Switch { body, .. } => {
+            let statements = &body.statements;
+            let is_label =
+                |s: &Statement| matches!(s.kind, StatementKind::Case(_) | StatementKind::Default);
+            let last_label = statements.iter().rposition(is_label);
+            statements
+                .iter()
+                .any(|s| matches!(s.kind, StatementKind::Default))
+                && !breaks_out(statements)
+                && always_returns(&statements[last_label.map_or(0, |i| i + 1)..])
+        }
+        _ => false,
+    })
+}
 
-    let symbol_table = SymbolTable::from_function(&declarations[0])?;
-    let Declaration::Function { scope, .. } = &declarations[0];
+// Whether any break in `statements` leaves the loop or switch they're the body of
+fn breaks_out(statements: &[Statement]) -> bool {
+    statements.iter().any(|s| match &s.kind {
+        StatementKind::Break => true,
+        // A break inside a nested loop or switch only leaves that
+        StatementKind::While { .. } | StatementKind::For { .. } | StatementKind::Switch { .. } => {
+            false
+        }
+        _ => s
+            .child_scopes()
+            .iter()
+            .any(|scope| breaks_out(&scope.statements)),
+    })
+}
 
-    check_scope(&scope, &symbol_table)?;
-    Ok(symbol_table)
+// Every continue must be inside a loop, and every break inside a loop or switch. Case and
+// default labels must be directly in the body of a switch, which `switch_body` says the
+// scope is.
+fn check_jumps(
+    scope: &Scope,
+    in_loop: bool,
+    in_switch: bool,
+    switch_body: bool,
+    errors: &mut Vec<CompileError>,
+) {
+    for s in &scope.statements {
+        let message = match &s.kind {
+            StatementKind::Break if !in_loop && !in_switch => {
+                "break statement not within a loop or switch"
+            }
+            StatementKind::Continue if !in_loop => "continue statement not within a loop",
+            StatementKind::Case(_) if !switch_body => "case label not directly within a switch",
+            StatementKind::Default if !switch_body => "default label not directly within a switch",
+            StatementKind::While { body, .. } | StatementKind::For { body, .. } => {
+                check_jumps(body, true, in_switch, false, errors);
+                continue;
+            }
+            StatementKind::Switch { body, .. } => {
+                check_jumps(body, in_loop, true, true, errors);
+                continue;
+            }
+            _ => {
+                for child in s.child_scopes() {
+                    check_jumps(child, in_loop, in_switch, false, errors);
+                }
+                continue;
+            }
+        };
+        errors.push(CompileError::semantic(message.to_owned()).or_span(s.span));
+    }
 }
 
-mod tests {
-    use super::*;
-    use crate::parser::parse;
-    use crate::tokenizer::tokenize;
-    use std::fs::read_to_string;
+pub fn check_returns(
+    declarations: &[Declaration],
+    types: &NodeTable<Type>,
+    type_table: &TypeTable,
+    diagnostics: &mut DiagnosticSink,
+) -> Result<(), CompileError> {
+    let mut errors = vec![];
+    return_errors(declarations, types, type_table, &mut errors);
+    report(errors, diagnostics)
+}
 
-    #[test]
-    fn test_symantic_main() -> Result<(), String> {
-        let s = read_to_string("test/main.c").unwrap();
-        let tokens = tokenize(&s)?;
-        let syntax_tree = parse(&tokens)?;
-        assert_eq!(1, syntax_tree.len());
+fn return_errors(
+    declarations: &[Declaration],
+    types: &NodeTable<Type>,
+    type_table: &TypeTable,
+    errors: &mut Vec<CompileError>,
+) {
+    for dec in declarations {
+        let Declaration::Function {
+            name,
+            return_type,
+            scope,
+            span,
+            ..
+        } = dec
+        else {
+            continue;
+        };
+        let return_type = type_table.resolve(return_type);
+        check_scope_returns(scope, name, &return_type, types, errors);
+        if return_type != Type::Void && name != "main" && !always_returns(&scope.statements) {
+            errors.push(CompileError::SemanticError {
+                message: format!(
+                    "Non-void function {} does not return a value on all control paths",
+                    name
+                ),
+                span: Some(*span),
+                notes: vec![],
+            });
+        }
+    }
+}
 
-        check_syntax(&syntax_tree)?;
-        Ok(())
+/*
+ * Use-before-initialization: a forward pass over each function tracking which variables are
+ * initialized. The environment is a stack with one map per open scope, from the names
+ * declared there to whether they have been assigned on every path so far, on none, or only
+ * on some. A use on no path is an error, since the program can't get there without reading
+ * garbage. A use on only some is a -Wmaybe-uninitialized warning, since the paths that skip
+ * the assignment may never run, like a loop that's known to run at least once.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Init {
+    No,
+    Maybe,
+    Yes,
+}
+
+type InitEnv = Vec<HashMap<Symbol, Init>>;
+
+// The warnings go straight to the sink, and the errors are collected to be reported along
+// with everything else that's wrong
+struct Findings<'a> {
+    diagnostics: &'a mut DiagnosticSink,
+    errors: Vec<CompileError>,
+}
+
+fn init_mut(env: &mut InitEnv, name: Symbol) -> Option<&mut Init> {
+    env.iter_mut().rev().find_map(|scope| scope.get_mut(&name))
+}
+
+// A variable is initialized after the join only if it was initialized on both paths, and
+// uninitialized only if it was on neither
+fn merge_init_envs(mut a: InitEnv, b: InitEnv) -> InitEnv {
+    for (a_scope, b_scope) in a.iter_mut().zip(b) {
+        for (name, init) in a_scope.iter_mut() {
+            if b_scope.get(name) != Some(init) {
+                *init = Init::Maybe;
+            }
+        }
     }
+    a
+}
 
-    #[test]
-    fn test_symantic_main_undef_var() -> Result<(), String> {
-        let s = read_to_string("test/main_undef_var.c").unwrap();
-        let tokens = tokenize(&s)?;
-        let syntax_tree = parse(&tokens)?;
-        assert_eq!(syntax_tree.len(), 1);
+// The variables `scope` assigns or takes the address of anywhere in it, on any path
+fn assigned_in(scope: &Scope, names: &mut HashSet<Symbol>) {
+    fn assigned_in_expr(expr: &Expr, names: &mut HashSet<Symbol>) {
+        match &expr.kind {
+            ExprKind::BinaryOperation { op, left, right } => {
+                if let (true, ExprKind::Variable(name)) = (op.is_assignment(), &left.kind) {
+                    names.insert(*name);
+                }
+                assigned_in_expr(left, names);
+                assigned_in_expr(right, names);
+            }
+            ExprKind::UnaryOperation { op, expr } => {
+                if let (UnaryOp::AddrOf, ExprKind::Variable(name)) = (op, &expr.kind) {
+                    names.insert(*name);
+                }
+                assigned_in_expr(expr, names)
+            }
+            ExprKind::Cast { expr, .. } => assigned_in_expr(expr, names),
+            ExprKind::Call { args, .. } => args.iter().for_each(|a| assigned_in_expr(a, names)),
+            _ => {}
+        }
+    }
+    for s in &scope.statements {
+        for expr in s.exprs() {
+            assigned_in_expr(expr, names);
+        }
+        for child in s.child_scopes() {
+            assigned_in(child, names);
+        }
+    }
+}
 
+// Paths the pass doesn't follow, through a loop's later iterations or out of a break, may
+// assign anything the body does, so none of it can be uninitialized on every path any more
+fn may_assign(env: &mut InitEnv, body: &Scope) {
+    let mut names = HashSet::new();
+    assigned_in(body, &mut names);
+    for name in names {
+        if let Some(init @ Init::No) = init_mut(env, name) {
+            *init = Init::Maybe;
+        }
+    }
+}
+
+fn check_init_expr(
+    expr: &Expr,
+    env: &mut InitEnv,
+    findings: &mut Findings,
+) -> Result<(), CompileError> {
+    match &expr.kind {
+        ExprKind::Variable(name) => match init_mut(env, *name) {
+            Some(Init::No) => Err(CompileError::SemanticError {
+                message: format!("Variable {} is used before being initialized", name),
+                span: expr.span,
+                notes: vec![],
+            }),
+            Some(init @ Init::Maybe) => {
+                findings.diagnostics.warn(
+                    "maybe-uninitialized",
+                    format!("Variable {} may be used before being initialized", name),
+                    expr.span,
+                );
+                // Once is enough
+                *init = Init::Yes;
+                Ok(())
+            }
+            _ => Ok(()),
+        },
+        ExprKind::BinaryOperation {
+            op: BinOp::Assign,
+            left,
+            right,
+        } => {
+            check_init_expr(right, env, findings)?;
+            match &left.kind {
+                ExprKind::Variable(name) => {
+                    if let Some(init) = init_mut(env, *name) {
+                        *init = Init::Yes;
+                    }
+                    Ok(())
+                }
+                _ => check_init_expr(left, env, findings),
+            }
+        }
+        ExprKind::BinaryOperation { left, right, .. } => {
+            check_init_expr(left, env, findings)?;
+            check_init_expr(right, env, findings)
+        }
+        // Taking a variable's address lets it be written through the pointer, so from then
+        // on it's assumed to be initialized
+        ExprKind::UnaryOperation {
+            op: UnaryOp::AddrOf,
+            expr,
+        } => match &expr.kind {
+            ExprKind::Variable(name) => {
+                if let Some(init) = init_mut(env, *name) {
+                    *init = Init::Yes;
+                }
+                Ok(())
+            }
+            _ => check_init_expr(expr, env, findings),
+        },
+        ExprKind::UnaryOperation { expr, .. } => check_init_expr(expr, env, findings),
+        ExprKind::Call { args, .. } => args
+            .iter()
+            .try_for_each(|a| check_init_expr(a, env, findings)),
+        _ => Ok(()),
+    }
+}
+
+// Returns whether control can reach the end of the scope
+fn check_init_scope(
+    scope: &Scope,
+    env: &mut InitEnv,
+    findings: &mut Findings,
+) -> Result<bool, CompileError> {
+    env.push(HashMap::new());
+    let falls_through = check_init_statements(&scope.statements, env, findings)?;
+    env.pop();
+    Ok(falls_through)
+}
+
+fn check_init_statements(
+    statements: &[Statement],
+    env: &mut InitEnv,
+    findings: &mut Findings,
+) -> Result<bool, CompileError> {
+    for s in statements {
+        // The rest of a statement that's wrong isn't checked, but the ones after it are
+        match check_init_statement(s, env, findings) {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
+            Err(e) => findings.errors.push(e.or_span(s.span)),
+        }
+    }
+
+    Ok(true)
+}
+
+// Returns whether control can continue past the statement
+fn check_init_statement(
+    s: &Statement,
+    env: &mut InitEnv,
+    findings: &mut Findings,
+) -> Result<bool, CompileError> {
+    match &s.kind {
+        StatementKind::VarDeclare { name, value, .. } => {
+            if let Some(value) = value {
+                check_init_expr(value, env, findings)?;
+            }
+            if let Some(scope) = env.last_mut() {
+                let init = match value {
+                    Some(_) => Init::Yes,
+                    None => Init::No,
+                };
+                scope.insert(*name, init);
+            }
+        }
+        StatementKind::Expression(expr) => check_init_expr(expr, env, findings)?,
+        StatementKind::Return(expr) => {
+            if let Some(expr) = expr {
+                check_init_expr(expr, env, findings)?;
+            }
+            return Ok(false);
+        }
+        StatementKind::If {
+            condition,
+            true_block,
+            false_block,
+        } => {
+            check_init_expr(condition, env, findings)?;
+            let mut true_env = env.clone();
+            let true_falls_through = check_init_scope(true_block, &mut true_env, findings)?;
+            let mut false_env = env.clone();
+            let false_falls_through = match false_block {
+                Some(false_scope) => check_init_scope(false_scope, &mut false_env, findings)?,
+                None => true,
+            };
+
+            *env = match (true_falls_through, false_falls_through) {
+                (true, true) => merge_init_envs(true_env, false_env),
+                (true, false) => true_env,
+                (false, true) => false_env,
+                (false, false) => return Ok(false),
+            };
+        }
+        StatementKind::While { condition, body } => {
+            // The body may run zero times, so nothing it assigns is initialized on every
+            // path afterwards, and anything it assigns may have been by an earlier iteration
+            check_init_expr(condition, env, findings)?;
+            may_assign(env, body);
+            check_init_scope(body, &mut env.clone(), findings)?;
+        }
+        StatementKind::Block(block) => {
+            if !check_init_scope(block, env, findings)? {
+                return Ok(false);
+            }
+        }
+        StatementKind::Switch { value, body } => {
+            check_init_expr(value, env, findings)?;
+            return check_init_switch(body, env, findings);
+        }
+        // Only ever directly in a switch's body, which check_init_switch goes through itself
+        StatementKind::Case(_) | StatementKind::Default => {}
+        // Whatever follows in this scope is unreachable. Leaving the loop early only
+        // loses assignments, which the While case already assumes.
+        StatementKind::Break | StatementKind::Continue => return Ok(false),
+        StatementKind::For { .. } => {
+            return Err(CompileError::semantic(
+                "For loops must be desugared before checking initialization".to_owned(),
+            ));
+        }
+    }
+    Ok(true)
+}
+
+// Control enters the body at one of its labels with what was initialized before the switch,
+// and leaves through a break or the end of the body, or goes past the body when no label
+// matches. A break nested inside another statement isn't followed, so if there is one,
+// anything the body assigns may or may not be initialized afterwards. Returns whether
+// control can get past the switch.
+fn check_init_switch(
+    body: &Scope,
+    env: &mut InitEnv,
+    findings: &mut Findings,
+) -> Result<bool, CompileError> {
+    let mut entry = env.clone();
+    entry.push(HashMap::new());
+    let mut exits = vec![];
+    let has_default = body
+        .statements
+        .iter()
+        .any(|s| matches!(s.kind, StatementKind::Default));
+    let nested_break = body.statements.iter().any(|s| {
+        let scopes = s.child_scopes();
+        !matches!(
+            s.kind,
+            StatementKind::While { .. } | StatementKind::For { .. } | StatementKind::Switch { .. }
+        ) && scopes.iter().any(|scope| breaks_out(&scope.statements))
+    });
+    if !has_default {
+        exits.push(entry.clone());
+    }
+    if nested_break {
+        let mut skipped = entry.clone();
+        may_assign(&mut skipped, body);
+        exits.push(skipped);
+    }
+
+    // What's initialized where control has got to, or None where it can't get
+    let mut current: Option<InitEnv> = None;
+    for s in &body.statements {
+        current = match (&s.kind, current) {
+            (StatementKind::Case(_) | StatementKind::Default, Some(current)) => {
+                Some(merge_init_envs(current, entry.clone()))
+            }
+            (StatementKind::Case(_) | StatementKind::Default, None) => Some(entry.clone()),
+            (StatementKind::Break, Some(current)) => {
+                exits.push(current);
+                None
+            }
+            (_, Some(mut current)) => match check_init_statement(s, &mut current, findings) {
+                Ok(falls_through) => falls_through.then_some(current),
+                Err(e) => {
+                    findings.errors.push(e.or_span(s.span));
+                    Some(current)
+                }
+            },
+            (_, None) => None,
+        };
+    }
+    exits.extend(current);
+
+    let Some(mut merged) = exits.into_iter().reduce(merge_init_envs) else {
+        return Ok(false);
+    };
+    merged.pop();
+    *env = merged;
+    Ok(true)
+}
+
+pub fn check_initialization(
+    declarations: &[Declaration],
+    diagnostics: &mut DiagnosticSink,
+) -> Result<(), CompileError> {
+    let mut errors = vec![];
+    initialization_errors(declarations, diagnostics, &mut errors);
+    report(errors, diagnostics)
+}
+
+fn initialization_errors(
+    declarations: &[Declaration],
+    diagnostics: &mut DiagnosticSink,
+    errors: &mut Vec<CompileError>,
+) {
+    let mut findings = Findings {
+        diagnostics,
+        errors: vec![],
+    };
+    for dec in declarations {
+        let Declaration::Function { args, scope, .. } = dec else {
+            continue;
+        };
+        let mut env: InitEnv = vec![args.iter().map(|a| (a.name, Init::Yes)).collect()];
+        if let Err(e) = check_init_scope(scope, &mut env, &mut findings) {
+            findings.errors.push(e);
+        }
+    }
+    errors.extend(findings.errors);
+}
+
+// Every function, global, and typedef name in the translation unit must be defined exactly
+// once. Struct and enum tags live in their own namespace, which the TypeTable checks.
+// A function can also be declared by prototypes, any number of times, as long as they all
+// agree with each other and with its definition.
+fn check_top_level(declarations: &[Declaration], errors: &mut Vec<CompileError>) {
+    let mut defined: HashMap<Symbol, Span> = HashMap::new();
+    // The parameter types, return type, and whether it's variadic
+    type Signature<'a> = (Vec<&'a Type>, &'a Type, bool);
+    let mut declared: HashMap<Symbol, (Signature, Span)> = HashMap::new();
+    for dec in declarations {
+        let (name, span) = match dec {
+            Declaration::Function {
+                name,
+                args,
+                return_type,
+                span,
+                ..
+            }
+            | Declaration::Prototype {
+                name,
+                args,
+                return_type,
+                span,
+                ..
+            } => {
+                let variadic = matches!(dec, Declaration::Prototype { variadic: true, .. });
+                let signature = (
+                    args.iter().map(|a| &a.var_type).collect(),
+                    return_type,
+                    variadic,
+                );
+                // A second definition, or a function named like a global or typedef, is a
+                // duplicate whatever its signature
+                let is_definition = matches!(dec, Declaration::Function { .. });
+                if let Some(previous) = defined.get(name)
+                    && (is_definition || !declared.contains_key(name))
+                {
+                    errors.push(duplicate_definition(*name, *span, *previous));
+                    continue;
+                }
+                match declared.get(name) {
+                    Some((previous, at)) if *previous != signature => {
+                        let message = format!("Conflicting declarations of function {}", name);
+                        let note = format!("Previous declaration of {}", name);
+                        errors.push(
+                            CompileError::semantic_at(message, *span)
+                                .with_note(Note::new(note, Some(*at))),
+                        );
+                        continue;
+                    }
+                    Some(_) => {}
+                    None => {
+                        declared.insert(*name, (signature, *span));
+                    }
+                }
+                if !is_definition {
+                    continue;
+                }
+                (name, span)
+            }
+            Declaration::Typedef { name, span, .. } | Declaration::Global { name, span, .. } => {
+                if let Some((_, at)) = declared.get(name) {
+                    errors.push(duplicate_definition(*name, *span, *at));
+                    continue;
+                }
+                (name, span)
+            }
+            Declaration::Struct { .. } | Declaration::Enum { .. } => continue,
+        };
+        match defined.get(name) {
+            Some(previous) => errors.push(duplicate_definition(*name, *span, *previous)),
+            None => {
+                defined.insert(*name, *span);
+            }
+        }
+    }
+}
+
+fn duplicate_definition(name: Symbol, span: Span, previous: Span) -> CompileError {
+    let note = Note::new(format!("Previous definition of {}", name), Some(previous));
+    CompileError::semantic_at(format!("Duplicate definition of {}", name), span).with_note(note)
+}
+
+// Reports every error but the last through the sink and fails with the last, so that the
+// driver lists them all, in the order they appear in the source
+fn report(
+    mut errors: Vec<CompileError>,
+    diagnostics: &mut DiagnosticSink,
+) -> Result<(), CompileError> {
+    errors.sort_by_key(|e| e.span().map_or(usize::MAX, |span| span.start));
+    let Some(last) = errors.pop() else {
+        return Ok(());
+    };
+    for error in errors {
+        diagnostics.error(error);
+    }
+    Err(last)
+}
+
+// Checks the whole translation unit before failing, so every undefined name, duplicate
+// declaration, and misplaced jump is reported at once
+pub fn check_syntax(
+    declarations: &[Declaration],
+    diagnostics: &mut DiagnosticSink,
+) -> Result<SymbolTable, CompileError> {
+    let mut errors = vec![];
+    let symbol_table = syntax_errors(declarations, diagnostics, &mut errors);
+    report(errors, diagnostics)?;
+    Ok(symbol_table)
+}
+
+fn syntax_errors(
+    declarations: &[Declaration],
+    diagnostics: &mut DiagnosticSink,
+    errors: &mut Vec<CompileError>,
+) -> SymbolTable {
+    check_top_level(declarations, errors);
+
+    // A global declared twice has already been reported by check_top_level
+    let (symbol_table, duplicates) = SymbolTable::build(declarations);
+    let reported: HashSet<_> = errors.iter().filter_map(|e| e.span()).collect();
+    errors.extend(
+        duplicates
+            .into_iter()
+            .filter(|e| e.span().is_none_or(|span| !reported.contains(&span))),
+    );
+    for dec in declarations {
+        if let Declaration::Function { scope, .. } = dec {
+            check_scope(scope, &symbol_table, diagnostics, errors);
+            let mut used = HashSet::new();
+            collect_uses(scope, &symbol_table, &mut used);
+            warn_unused(scope, &used, diagnostics);
+            check_jumps(scope, false, false, false, errors);
+        }
+    }
+    symbol_table
+}
+
+// What the checks know about a program that passes them
+pub struct CheckedProgram {
+    pub symbol_table: SymbolTable,
+    pub type_table: TypeTable,
+    pub types: NodeTable<Type>,
+}
+
+// Runs every check over the translation unit before failing, so all of its errors are
+// reported together whichever check finds them, in the order they appear in the source
+pub fn check(
+    declarations: &mut [Declaration],
+    options: &CompileOptions,
+    diagnostics: &mut DiagnosticSink,
+) -> Result<CheckedProgram, CompileError> {
+    let mut errors = vec![];
+    let symbol_table = syntax_errors(declarations, diagnostics, &mut errors);
+    initialization_errors(declarations, diagnostics, &mut errors);
+    let typed = match TypeTable::from_declarations(declarations, options.target) {
+        Ok(type_table) => {
+            let types = type_errors(
+                declarations,
+                &symbol_table,
+                &type_table,
+                diagnostics,
+                &mut errors,
+            );
+            return_errors(declarations, &types, &type_table, &mut errors);
+            Some((type_table, types))
+        }
+        Err(e) => {
+            errors.push(e);
+            None
+        }
+    };
+    // The type checker comes across the same undefined names as the syntax check
+    let mut seen = HashSet::new();
+    errors.retain(|e| seen.insert((e.message().to_owned(), e.span())));
+    report(errors, diagnostics)?;
+    let (type_table, types) = typed.expect("the type table was built without errors");
+    Ok(CheckedProgram {
+        symbol_table,
+        type_table,
+        types,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desugar::desugar;
+    use crate::parser::{parse, parse_with_spans};
+    use crate::tokenizer::{tokenize, tokenize_with_spans};
+    use crate::triple::Target;
+    use std::fs::read_to_string;
+
+    #[test]
+    fn test_symantic_main() -> Result<(), String> {
+        let s = read_to_string("test/main.c").unwrap();
+        let tokens = tokenize(&s)?;
+        let syntax_tree = parse(&tokens)?;
+        assert_eq!(1, syntax_tree.len());
+
+        check_syntax(&syntax_tree, &mut DiagnosticSink::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_symantic_main_undef_var() -> Result<(), String> {
+        let s = read_to_string("test/main_undef_var.c").unwrap();
+        let tokens = tokenize(&s)?;
+        let syntax_tree = parse(&tokens)?;
+        assert_eq!(syntax_tree.len(), 1);
+
+        assert_eq!(
+            check_syntax(&syntax_tree, &mut DiagnosticSink::default()),
+            Err(CompileError::semantic(
+                "Undefined variable z in scope 1".to_owned()
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lvalues() -> Result<(), String> {
+        let check = |source: &str| -> Result<(), String> {
+            check_syntax(&parse(&tokenize(source)?)?, &mut DiagnosticSink::default())?;
+            Ok(())
+        };
+        check("int main() { int x; x = 1; x += 2; return x; }")?;
+        assert_eq!(
+            check("int main() { int x = 1; 5 = x; return x; }"),
+            Err("Left side of = must be an lvalue, but got (int 5)".to_owned())
+        );
+        assert_eq!(
+            check("int main() { int a; int b; (a + b) = 1; return a; }"),
+            Err("Left side of = must be an lvalue, but got (+ (var a) (var b))".to_owned())
+        );
+        assert_eq!(
+            check("int main() { int a; a + 1 *= 2; return a; }"),
+            Err("Left side of *= must be an lvalue, but got (+ (var a) (int 1))".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_functions() -> Result<(), String> {
+        let check = |source: &str| {
+            let mut diagnostics = DiagnosticSink::default();
+            let (tokens, spans) = tokenize_with_spans(source)?;
+            let declarations = parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+            check_syntax(&declarations, &mut diagnostics).map_err(with_notes)?;
+            Ok::<(), String>(())
+        };
+        check("int f() { int x = 1; return x; }\nint main() { int x = 2; return x; }")?;
+        assert_eq!(
+            check("int f() { return 1; }\nint main() { return 0; }\nvoid f() { }"),
+            Err("3:6: Duplicate definition of f\n1:5: note: Previous definition of f".to_owned())
+        );
+
+        // A function can be declared as often as it likes, as long as it's the same each time
+        check(
+            "int f(int a);\nint f(int a) { return a; }\nint f(int b);\nint main() { return f(1); }",
+        )?;
+        assert_eq!(
+            check("int f(int a);\nint main() { return 0; }\nint f(char a) { return a; }"),
+            Err(
+                "3:5: Conflicting declarations of function f\n1:5: note: Previous declaration of f"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            check("int f;\nint f(int a);\nint main() { return 0; }"),
+            Err("2:5: Duplicate definition of f\n1:5: note: Previous definition of f".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_variables() -> Result<(), String> {
+        let check = |source: &str| {
+            let mut diagnostics = DiagnosticSink::default();
+            diagnostics.apply_flag("-Wunused-variable")?;
+            let (tokens, spans) = tokenize_with_spans(source)?;
+            let declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+            check_syntax(&declarations, &mut diagnostics)?;
+            Ok::<Vec<String>, String>(
+                diagnostics
+                    .diagnostics()
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            check("int f(int unused) {\n  int x;\n  int y;\n  y = 1;\n  return 0;\n}")?,
+            vec!["2:7: warning: Unused variable x [-Wunused-variable]"]
+        );
+        // The inner x is the one that's used
+        assert_eq!(
+            check("int main() {\n  int x = 0;\n  {\n    int x = 1;\n    return x;\n  }\n}")?,
+            vec!["2:7: warning: Unused variable x [-Wunused-variable]"]
+        );
+        assert_eq!(
+            check(
+                "int main() { int n = 0; for (int i = 0; i < 3; i += 1) { n += 1; } return n; }"
+            )?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_values() -> Result<(), String> {
+        // On by default, as in gcc
+        let mut diagnostics = DiagnosticSink::default();
+        let source = "int f(int x) { return x; }\nint main() {\n  int x = 1;\n  x + 2;\n  \
+                      f(x);\n  x = f(x) * 2;\n  -f(x);\n  (x);\n  \
+                      for (int i = 0; i < 3; i += 1) {}\n  return x;\n}";
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+        check_syntax(&declarations, &mut diagnostics)?;
+        let warnings: Vec<String> = diagnostics
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "4:3: warning: Statement has no effect [-Wunused-value]",
+                "8:4: warning: Statement has no effect [-Wunused-value]",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_locations() -> Result<(), String> {
+        // Errors inside a statement point at the expression they're about, or otherwise the
+        // innermost statement they came from
+        let check = |source: &str| {
+            let mut diagnostics = DiagnosticSink::default();
+            let (tokens, spans) = tokenize_with_spans(source)?;
+            let mut declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+            let symbol_table = check_syntax(&declarations, &mut diagnostics)?;
+            check_initialization(&declarations, &mut diagnostics)?;
+            let type_table = TypeTable::from_declarations(&declarations, Target::default())?;
+            check_types(
+                &mut declarations,
+                &symbol_table,
+                &type_table,
+                &mut diagnostics,
+            )?;
+            Ok::<(), String>(())
+        };
+        assert_eq!(
+            check("int main() {\n  if (1) {\n    return y;\n  }\n  return 0;\n}"),
+            Err("3:12: Undefined variable y in scope 1".to_owned())
+        );
+        assert_eq!(
+            check("int main() {\n  int x;\n  return x;\n}"),
+            Err("3:10: Variable x is used before being initialized".to_owned())
+        );
+        assert_eq!(
+            check("int main() {\n  int *p = 1;\n  return 0;\n}"),
+            Err("2:3: Type error: cannot initialize int* p with a value of type int".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_errors() -> Result<(), String> {
+        // Checking carries on past each error, and all but the last go through the sink
+        let source =
+            "int g;\nint g;\nint main() {\n  int *p = 1;\n  if (p) { break; }\n  return q;\n}";
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+        let error = check_syntax(&declarations, &mut diagnostics).map(|_| ());
+        assert_eq!(
+            error.map_err(|e| e.to_string()),
+            Err("6:10: Undefined variable q in scope 2".to_owned())
+        );
+        let messages: Vec<String> = diagnostics
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "2:5: error: Duplicate definition of g",
+                "5:12: error: break statement not within a loop or switch",
+            ]
+        );
+
+        // Type errors are collected the same way once the names all resolve
+        let source = "int main() {\n  int *p = 1;\n  char *s = 2;\n  return 0;\n}";
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let mut declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+        let symbol_table = check_syntax(&declarations, &mut diagnostics)?;
+        let type_table = TypeTable::from_declarations(&declarations, Target::default())?;
+        let error = check_types(
+            &mut declarations,
+            &symbol_table,
+            &type_table,
+            &mut diagnostics,
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+        assert_eq!(
+            error,
+            Err("3:3: Type error: cannot initialize char* s with a value of type int".to_owned())
+        );
+        assert_eq!(
+            diagnostics.diagnostics()[0].to_string(),
+            "2:3: error: Type error: cannot initialize int* p with a value of type int"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_globals() -> Result<(), String> {
+        let check = |source: &str| {
+            let mut diagnostics = DiagnosticSink::default();
+            let (tokens, spans) = tokenize_with_spans(source)?;
+            let mut declarations = parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+            let symbol_table = check_syntax(&declarations, &mut diagnostics)?;
+            let type_table = TypeTable::from_declarations(&declarations, Target::default())?;
+            check_types(
+                &mut declarations,
+                &symbol_table,
+                &type_table,
+                &mut diagnostics,
+            )?;
+            Ok::<(), String>(())
+        };
+        // Every function sees the globals, wherever they're declared
+        check("int f() { return g; }\nint g = -1;\nint main() { g = f(); return g; }")?;
+        check("int k = 2 * 8 - (1 < 2);\nint main() { return k; }")?;
+        assert_eq!(
+            check("int g = 1;\nint h = g;\nint main() { return h; }"),
+            Err("2:5: Initializer of global h is not a constant: g is a variable".to_owned())
+        );
+        assert_eq!(
+            check("int g;\nint g() { return 0; }"),
+            Err("2:5: Duplicate definition of g".to_owned())
+        );
+        assert_eq!(
+            check("int *p = \"x\";\nint main() { return 0; }"),
+            Err("1:6: Type error: cannot initialize int* p with a value of type char*".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_declaration_locations() -> Result<(), String> {
+        let check = |source: &str| {
+            let mut diagnostics = DiagnosticSink::default();
+            diagnostics.apply_flag("-Wshadow")?;
+            let (tokens, spans) = tokenize_with_spans(source)?;
+            let declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+            check_syntax(&declarations, &mut diagnostics).map_err(with_notes)?;
+            Ok::<Vec<String>, String>(
+                (diagnostics.diagnostics().iter())
+                    .flat_map(|d| {
+                        let notes = d.notes.iter().map(|note| note.to_string());
+                        [d.to_string()].into_iter().chain(notes)
+                    })
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            check("int main() {\n  int x = 1;\n  char x;\n  return 0;\n}"),
+            Err(
+                "3:8: Duplicate declaration of variable x\n2:7: note: Previous declaration of x"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            check("int f(int a) {\n  int a;\n  return a;\n}"),
+            Err(
+                "2:7: Duplicate declaration of variable a\n1:11: note: Previous declaration of a"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            check("int f(int a) {\n  while (a) {\n    int a = 0;\n  }\n  return a;\n}")?,
+            vec![
+                "3:9: warning: Declaration of a shadows a previous declaration [-Wshadow]",
+                "1:11: note: Previous declaration of a",
+            ]
+        );
+        assert_eq!(
+            check("int main() { int x; { int y; } { int y; } return 0; }")?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    // An error and its notes, one to a line
+    fn with_notes(error: CompileError) -> String {
+        let notes = error.notes().iter().map(|note| format!("\n{}", note));
+        error.to_string() + &notes.collect::<String>()
+    }
+
+    // The -Wmaybe-uninitialized warnings, if there's no error
+    fn check_source_initialization(source: &str) -> Result<Vec<String>, String> {
+        let mut diagnostics = DiagnosticSink::default();
+        diagnostics.apply_flag("-Wmaybe-uninitialized")?;
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let declarations = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+        check_initialization(&declarations, &mut diagnostics)?;
+        Ok(diagnostics
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn test_initialized() -> Result<(), String> {
+        for path in ["test/main.c", "test/if_else.c", "test/loops.c"] {
+            assert!(check_source_initialization(&read_to_string(path).unwrap())?.is_empty());
+        }
+        for source in [
+            "int main() { int x; if (1) { x = 1; } else { x = 2; } return x; }",
+            "int f(int a) { int x; x = a; return x; }",
+            "int main() { int x; if (1) { return 0; } else { x = 2; } return x; }",
+            "int main() { int x; { x = 1; } return x; }",
+            "int f(int a) { int x; switch (a) { case 1: x = 1; break; default: x = 2; } \
+             return x; }",
+        ] {
+            assert_eq!(check_source_initialization(source)?, Vec::<String>::new());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_uninitialized() -> Result<(), String> {
+        // Read on every path before anything assigns it
+        let error = |column| {
+            Err(format!(
+                "1:{}: Variable x is used before being initialized",
+                column
+            ))
+        };
+        assert_eq!(
+            check_source_initialization("int main() { int x; return x; }"),
+            error(28)
+        );
+        assert_eq!(
+            check_source_initialization("int main() { int x; x = x + 1; return 0; }"),
+            error(25)
+        );
+        assert_eq!(
+            check_source_initialization("int main() { int x; x += 1; return 0; }"),
+            error(21)
+        );
+        assert_eq!(
+            check_source_initialization(
+                "int main() { int x; int i = 0; while (i) { i = x; } return 0; }"
+            ),
+            error(48)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_maybe_uninitialized() -> Result<(), String> {
+        // Only some paths assign x, which may be the only ones that run, so these are valid
+        // C that gets a warning
+        let warning = |column| {
+            Ok(vec![format!(
+                "1:{}: warning: Variable x may be used before being initialized \
+                 [-Wmaybe-uninitialized]",
+                column
+            )])
+        };
+        assert_eq!(
+            check_source_initialization("int main() { int x; if (1) { x = 1; } return x; }"),
+            warning(46)
+        );
+        assert_eq!(
+            check_source_initialization(
+                "int main() { int x; for (int i = 0; i < 3; i += 1) { x = i; } return x; }"
+            ),
+            warning(70)
+        );
+        assert_eq!(
+            check_source_initialization(
+                "int main() { int x; int i = 0; while (i < 1) { x = 5; i = i + 1; } return x; }"
+            ),
+            warning(75)
+        );
+        assert_eq!(
+            check_source_initialization(
+                "int main() { int x; while (1) { x = 1; break; } return x; }"
+            ),
+            warning(56)
+        );
+        // Set on one iteration, read on the next
+        assert_eq!(
+            check_source_initialization(
+                "int main() { int x; int i = 0; while (i < 2) { if (i) { return x; } x = 1; \
+                 i = i + 1; } return 0; }"
+            ),
+            warning(64)
+        );
+        // Without a default, or with a way past the assignment, the switch can leave x alone
+        assert_eq!(
+            check_source_initialization(
+                "int f(int a) { int x; switch (a) { case 1: x = 1; } return x; }"
+            ),
+            warning(60)
+        );
+        assert_eq!(
+            check_source_initialization(
+                "int f(int a) { int x; switch (a) { case 1: if (a) { break; } x = 1; \
+                 default: x = 2; } return x; }"
+            ),
+            warning(94)
+        );
+        assert_eq!(
+            check_source_initialization(
+                "int f(int a) { int x; switch (a) { case 1: break; default: x = 2; } return x; }"
+            ),
+            warning(76)
+        );
+        // Only reported once
+        assert_eq!(
+            check_source_initialization(
+                "int main() { int x; if (1) { x = 1; } int y = x; return x + y; }"
+            ),
+            warning(47)
+        );
+        Ok(())
+    }
+
+    fn check_source_types(source: &str) -> Result<(Vec<Declaration>, NodeTable<Type>), String> {
+        let (syntax_tree, types, _) = check_source_conversions(source, &[])?;
+        Ok((syntax_tree, types))
+    }
+
+    type Checked = (Vec<Declaration>, NodeTable<Type>, Vec<String>);
+
+    // Also returns the warnings reported with the given -W flags
+    fn check_source_conversions(source: &str, flags: &[&str]) -> Result<Checked, String> {
+        let mut diagnostics = DiagnosticSink::default();
+        for flag in flags {
+            diagnostics.apply_flag(flag)?;
+        }
+        let tokens = tokenize(source)?;
+        let mut syntax_tree = desugar(parse(&tokens)?);
+        let symbol_table = check_syntax(&syntax_tree, &mut DiagnosticSink::default())?;
+        let type_table = TypeTable::from_declarations(&syntax_tree, Target::default())?;
+        let types = check_types(
+            &mut syntax_tree,
+            &symbol_table,
+            &type_table,
+            &mut diagnostics,
+        )?;
+        let warnings = diagnostics
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        Ok((syntax_tree, types, warnings))
+    }
+
+    fn check_source_returns(source: &str) -> Result<(), String> {
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let mut syntax_tree = desugar(parse_with_spans(&tokens, &spans, &mut diagnostics)?);
+        let symbol_table = check_syntax(&syntax_tree, &mut diagnostics)?;
+        let type_table = TypeTable::from_declarations(&syntax_tree, Target::default())?;
+        let types = check_types(
+            &mut syntax_tree,
+            &symbol_table,
+            &type_table,
+            &mut diagnostics,
+        )?;
+        Ok(check_returns(
+            &syntax_tree,
+            &types,
+            &type_table,
+            &mut DiagnosticSink::default(),
+        )?)
+    }
+
+    #[test]
+    fn test_returns_ok() -> Result<(), String> {
+        for path in ["test/if.c", "test/if_else.c", "test/loops.c"] {
+            check_source_returns(&read_to_string(path).unwrap())?;
+        }
+        check_source_returns("void f() { return; }")?;
+        check_source_returns("void f() { }")?;
+        check_source_returns("char f() { int x = 1; return x; }")?;
+        check_source_returns("int f() { while (1) { } }")?;
+        check_source_returns("int f() { { if (1) return 1; else { return 2; } } }")?;
+        check_source_returns("int main() { int x = 1; }")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_returns_type_mismatch() -> Result<(), String> {
+        assert_eq!(
+            check_source_returns("int f() { return \"s\"; }"),
+            Err(
+                "1:11: Type error: function f returns a value of type char* but is declared to \
+                 return int"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            check_source_returns("void f() { if (1) { return 1; } }"),
+            Err("1:21: Void function f cannot return a value".to_owned())
+        );
+        assert_eq!(
+            check_source_returns("int f() { return; }"),
+            Err("1:11: Non-void function f must return a value of type int".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_returns_missing() -> Result<(), String> {
+        let error =
+            Err("1:5: Non-void function f does not return a value on all control paths".to_owned());
+        assert_eq!(check_source_returns("int f() { }"), error);
+        assert_eq!(
+            check_source_returns("int f() { if (1) { return 1; } }"),
+            error
+        );
+        assert_eq!(
+            check_source_returns("int f() { int x = 0; while (x) { return 1; } }"),
+            error
+        );
+        assert_eq!(
+            check_source_returns("int f() { while (1) { if (1) { break; } } }"),
+            error
+        );
+        // Only the inner loop is left by its break
+        check_source_returns("int f() { while (1) { while (1) { break; } } }")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_loop_jumps() -> Result<(), String> {
+        check_source_returns(
+            "int main() { for (int i = 0; i == 0; i += 1) { if (i) { continue; } break; } \
+             return 0; }",
+        )?;
+        assert_eq!(
+            check_source_returns("int main() { break; return 0; }"),
+            Err("1:14: break statement not within a loop or switch".to_owned())
+        );
+        assert_eq!(
+            check_source_returns("int main() { if (1) { continue; } return 0; }"),
+            Err("1:23: continue statement not within a loop".to_owned())
+        );
+        // A switch can be broken out of, but not continued
+        check_source_returns("int main() { switch (1) { case 1: break; } return 0; }")?;
+        check_source_returns(
+            "int main() { while (1) { switch (1) { default: continue; } } return 0; }",
+        )?;
+        assert_eq!(
+            check_source_returns("int main() { switch (1) { default: continue; } return 0; }"),
+            Err("1:36: continue statement not within a loop".to_owned())
+        );
+        assert_eq!(
+            check_source_returns("int main() { case 1: return 0; }"),
+            Err("1:14: case label not directly within a switch".to_owned())
+        );
+        assert_eq!(
+            check_source_returns(
+                "int main() { switch (1) { case 1: if (1) { default: return 1; } } return 0; }"
+            ),
+            Err("1:44: default label not directly within a switch".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_returns() -> Result<(), String> {
+        check_source_returns(
+            "int f(int x) { switch (x) { case 1: return 1; default: return 2; } }",
+        )?;
+        // Falling through to a return is returning
+        check_source_returns("int f(int x) { switch (x) { case 1: x = 2; default: return x; } }")?;
+        let error =
+            Err("1:5: Non-void function f does not return a value on all control paths".to_owned());
+        assert_eq!(
+            check_source_returns("int f(int x) { switch (x) { case 1: return 1; } }"),
+            error
+        );
+        assert_eq!(
+            check_source_returns(
+                "int f(int x) { switch (x) { case 1: break; default: return 2; } }"
+            ),
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_cases() -> Result<(), String> {
+        check_source_types(
+            "int main() { char c = 97; switch (c) { case 97: case 98 + 1: return 1; } return 0; }",
+        )?;
+        let error = |source| check_source_types(source).map(|_| ());
+        assert_eq!(
+            error("int main() { switch (\"s\") { default: return 0; } }"),
+            Err("Type error: switch value has non-integer type char*".to_owned())
+        );
+        assert_eq!(
+            error("int main() { int x = 1; switch (1) { case x: return 1; } return 0; }"),
+            Err("Case value is not a constant: x is a variable".to_owned())
+        );
+        assert_eq!(
+            error("int main() { switch (1) { case 2: case 1 + 1: return 1; } return 0; }"),
+            Err("Duplicate case value 2".to_owned())
+        );
+        assert_eq!(
+            error("int main() { switch (1) { default: default: return 1; } }"),
+            Err("Multiple default labels in one switch".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_annotated() -> Result<(), String> {
+        let (syntax_tree, types) =
+            check_source_types("int main() { char c = 1; char *s = \"hi\"; return c + 2; }")?;
+        let Declaration::Function { scope, .. } = &syntax_tree[0] else {
+            return Err(format!("Expected a function, got {:?}", syntax_tree[0]));
+        };
+
+        let StatementKind::VarDeclare { value: Some(s), .. } = &scope.statements[1].kind else {
+            return Err(format!(
+                "Expected a declaration, got {:?}",
+                scope.statements[1]
+            ));
+        };
+        assert_eq!(types.get(&s.id), Some(&Type::Pointer(Box::new(Type::Char))));
+        // So does each declaration, as the type of the variable it declares
+        assert_eq!(types.get(&scope.statements[0].id), Some(&Type::Char));
+
+        let StatementKind::Return(Some(sum)) = &scope.statements[2].kind else {
+            return Err(format!("Expected a return, got {:?}", scope.statements[2]));
+        };
+        assert_eq!(types.get(&sum.id), Some(&Type::Int));
+        let ExprKind::BinaryOperation { left, .. } = &sum.kind else {
+            return Err(format!("Expected a binary operation, got {:?}", sum));
+        };
+        assert_eq!(types.get(&left.id), Some(&Type::Int));
+        let ExprKind::Cast { expr: c, .. } = &left.kind else {
+            return Err(format!("Expected a cast, got {:?}", left));
+        };
+        assert_eq!(types.get(&c.id), Some(&Type::Char));
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_implicit_conversions() -> Result<(), String> {
+        let expected = "\
+(fn f char ((char a))
+  (decl char c (cast char (int 1)))
+  (decl int x (cast int (var a)))
+  (expr (= (var c) (cast char (+ (cast int (var c)) (cast int (var a))))))
+  (if (== (var x) (cast int (var c)))
+    (then
+      (ret (cast char (var x)))))
+  (ret (var c)))
+";
+        let (syntax_tree, _, warnings) = check_source_conversions(
+            "char f(char a) { char c = 1; int x = a; c = c + a; \
+             if (x == c) return x; return c; }",
+            &[],
+        )?;
+        assert_eq!(crate::ast_dump::dump(&syntax_tree), expected);
+        assert!(warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_unsigned_conversions() -> Result<(), String> {
+        // Mixed with an unsigned operand, an int is converted to unsigned, but a shift keeps
+        // its left operand's type and a comparison always gives an int
+        let expected = "\
+(fn f unsigned ((unsigned u) (char c))
+  (decl int i (cast int (var u)))
+  (decl unsigned sum (+ (var u) (cast unsigned (var i))))
+  (decl int shifted (<< (cast int (var c)) (var u)))
+  (decl int less (< (cast unsigned (var c)) (var u)))
+  (decl unsigned negated (- (var u)))
+  (ret (/ (var sum) (cast unsigned (int 2)))))
+";
+        let (syntax_tree, types, warnings) = check_source_conversions(
+            "unsigned f(unsigned u, char c) { int i = u; unsigned sum = u + i; \
+             int shifted = c << u; int less = c < u; unsigned negated = -u; return sum / 2; }",
+            &[],
+        )?;
+        assert_eq!(crate::ast_dump::dump(&syntax_tree), expected);
+        assert!(warnings.is_empty());
+        let Declaration::Function { scope, .. } = &syntax_tree[0] else {
+            panic!("Expected a function");
+        };
+        let StatementKind::Return(Some(quotient)) = &scope.statements[5].kind else {
+            panic!("Expected a return");
+        };
+        assert_eq!(types.get(&quotient.id), Some(&Type::Unsigned));
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_narrowing_warning() -> Result<(), String> {
+        let source = "int main() { int x = 1; char c = 300; char d = 100; c = 255; c = x; \
+                      return c; }";
+        let (_, _, warnings) = check_source_conversions(source, &[])?;
+        assert_eq!(
+            warnings,
+            vec![
+                "warning: Implicit conversion from int to char changes value from 300 to 44 \
+                 [-Woverflow]",
+                "warning: Implicit conversion from int to char changes value from 255 to -1 \
+                 [-Woverflow]",
+            ]
+        );
+
+        let (_, _, warnings) =
+            check_source_conversions(source, &["-Wno-overflow", "-Wconversion", "-Werror"])?;
+        assert_eq!(
+            warnings,
+            vec!["error: Conversion from int to char may change value [-Werror=conversion]"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_constant_overflow() -> Result<(), String> {
+        // Unsigned arithmetic wraps by definition, and the sum of the overflowing product is
+        // already wrong, so it isn't reported again
+        let source = "int main() { int x = -2147483647 - 1; int y = (1 << 31) + 1; \
+                      unsigned u = 4294967295; u = u * 2 + 1; \
+                      return 1000000 * 1000 / 10 - 2147483647 / -1; }";
+        let (_, _, warnings) = check_source_conversions(source, &[])?;
+        assert_eq!(
+            warnings,
+            vec![
+                "warning: Integer overflow: 1 << 31 is 2147483648, which doesn't fit in int \
+                 [-Woverflow]",
+                "warning: Integer overflow: 100000000 - -2147483647 is 2247483647, which \
+                 doesn't fit in int [-Woverflow]",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_undefined_operations() -> Result<(), String> {
+        // A constant right operand is enough, whether or not the left one is known
+        let source = "int main() { int x = 5; unsigned u = 1; \
+                      return x / 0 + (x << -1) + (u >> 32) + (x << 31) + (1 >> 40) / 0; }";
+        let (_, _, warnings) = check_source_conversions(source, &[])?;
+        assert_eq!(
+            warnings,
+            vec![
+                "warning: Division by zero [-Wdiv-by-zero]",
+                "warning: Shift count -1 is negative [-Wshift-count-negative]",
+                "warning: Shift count 32 is not less than the width of the type (32 bits) \
+                 [-Wshift-count-overflow]",
+                "warning: Shift count 40 is not less than the width of the type (32 bits) \
+                 [-Wshift-count-overflow]",
+                "warning: Division by zero [-Wdiv-by-zero]",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_calls() -> Result<(), String> {
+        let expected = "\
+(fn add int ((int a) (char b))
+  (ret (+ (var a) (cast int (var b)))))
+(fn main int ()
+  (decl char c (cast char (call add (int 1) (cast char (int 2)))))
+  (ret (call add (cast int (var c)) (var c))))
+";
+        let (syntax_tree, types) = check_source_types(
+            "int add(int a, char b) { return a + b; } \
+             int main() { char c = add(1, 2); return add(c, c); }",
+        )?;
+        assert_eq!(crate::ast_dump::dump(&syntax_tree), expected);
+        let Declaration::Function { scope, .. } = &syntax_tree[1] else {
+            return Err(format!("Expected a function, got {:?}", syntax_tree[1]));
+        };
+        let StatementKind::Return(Some(call)) = &scope.statements[1].kind else {
+            return Err(format!("Expected a return, got {:?}", scope.statements[1]));
+        };
+        assert_eq!(types.get(&call.id), Some(&Type::Int));
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_call_errors() -> Result<(), String> {
+        let check = |source: &str| check_source_types(source).map(|_| ());
+        assert_eq!(
+            check("int main() { return f(); }"),
+            Err("Undefined function f".to_owned())
+        );
+        assert_eq!(
+            check("int f(int a) { return a; } int main() { return f(); }"),
+            Err("Function f expects 1 arguments, but got 0".to_owned())
+        );
+        assert_eq!(
+            check("int f(int a) { return a; } int main() { return f(\"s\"); }"),
+            Err("Type error: argument 1 of f has type char* but int was expected".to_owned())
+        );
+        assert_eq!(
+            check("void f() { } int main() { return f() + 1; }"),
+            Err("Type error: invalid operands to + (void and int)".to_owned())
+        );
+        assert_eq!(
+            check("int p(char *s, ...); int main() { return p(); }"),
+            Err("Function p expects at least 1 arguments, but got 0".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_variadic_calls() -> Result<(), String> {
+        // Past the parameters, a char is widened to an int and nothing else changes
+        let expected = "\
+(declare printf int ((char* format) ...))
+(fn main int ()
+  (decl char c (cast char (int 65)))
+  (ret (call printf (str \"%c%s\") (cast int (var c)) (str \"!\"))))
+";
+        let (syntax_tree, _) = check_source_types(
+            "int printf(char *format, ...); \
+             int main() { char c = 65; return printf(\"%c%s\", c, \"!\"); }",
+        )?;
+        assert_eq!(crate::ast_dump::dump(&syntax_tree), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_user_types() -> Result<(), String> {
+        let expected = "\
+(typedef int number)
+(enum Color (RED GREEN))
+(struct Point ((number x) (number y)))
+(typedef struct Point point)
+(fn f number ((enum Color c) (point p))
+  (decl number n (cast int (var c)))
+  (decl point q)
+  (expr (= (var q) (var p)))
+  (ret (var n)))
+";
+        let (syntax_tree, types) = check_source_types(
+            "typedef int number; enum Color { RED, GREEN }; \
+             struct Point { number x; number y; }; typedef struct Point point; \
+             number f(enum Color c, point p) { number n = c; point q; q = p; return n; }",
+        )?;
+        assert_eq!(crate::ast_dump::dump(&syntax_tree), expected);
+        // The declarations' types are what the typedefs stand for
+        let Declaration::Function { scope, .. } = &syntax_tree[4] else {
+            return Err(format!("Expected a function, got {:?}", syntax_tree[4]));
+        };
+        let declared: Vec<Option<&Type>> = scope.statements[..2]
+            .iter()
+            .map(|s| types.get(&s.id))
+            .collect();
+        let point = Type::Struct("Point".into());
+        assert_eq!(declared, vec![Some(&Type::Int), Some(&point)]);
+
+        assert_eq!(
+            check_source_returns(
+                "struct A { int a; }; struct B { int b; }; \
+                 int main() { struct A a; struct B b; a = b; return 0; }"
+            ),
+            Err("1:80: Type error: cannot assign a value of type struct B to struct A".to_owned())
+        );
+        assert_eq!(
+            check_source_returns("int main() { struct S s; return 0; }"),
+            Err("1:14: Variable s has incomplete type struct S".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_unary_operators() -> Result<(), String> {
+        let check = |source: &str| check_source_types(source).map(|_| ());
+        assert_eq!(
+            check("int main() { char *s = \"s\"; return !s + -1 + ~2; }"),
+            Ok(())
+        );
+        assert_eq!(
+            check("int main() { return -\"s\"; }"),
+            Err("Type error: invalid operand to unary - (char*)".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_pointers() -> Result<(), String> {
+        let check = |source: &str| check_source_types(source).map(|_| ());
+        assert_eq!(
+            check("int main() { int x; int *p = &x; *p = 2; int **q = &p; return **q; }"),
+            Ok(())
+        );
+        assert_eq!(
+            check("int main() { return *5; }"),
+            Err("Type error: invalid operand to unary * (int)".to_owned())
+        );
+        assert_eq!(
+            check("int main() { int x = 1; return *&(x + 1); }"),
+            Err("Type error: invalid operand to unary & (int)".to_owned())
+        );
+        assert_eq!(
+            check("int main() { int x = 1; char *p = &x; return 0; }"),
+            Err("Type error: cannot initialize char* p with a value of type int*".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_null_pointer() -> Result<(), String> {
+        // Any integer constant that's 0 converts to a pointer, but no other integer does
+        let check = |source: &str| check_source_types(source).map(|_| ());
+        let source = "enum E { NONE }; char *g = 0; char *f(int *q) { return 1 - 1; } \
+                      int main() { char *s = 0; int *p; p = NONE; \
+                      return p == 0 && 0 != s && f(0) == g; }";
+        assert_eq!(check(source), Ok(()));
+        assert_eq!(
+            check("int main() { char *s = 1; return 0; }"),
+            Err("Type error: cannot initialize char* s with a value of type int".to_owned())
+        );
+        assert_eq!(
+            check("int main() { int x = 0; char *s; s = x; return 0; }"),
+            Err("Type error: cannot assign a value of type int to char*".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_string_initializer() -> Result<(), String> {
+        assert_eq!(
+            check_source_types("int main() { int x = \"string\"; return 0; }").map(|_| ()),
+            Err("Type error: cannot initialize int x with a value of type char*".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_string_arithmetic() -> Result<(), String> {
+        assert_eq!(
+            check_source_types("int main() { int x = 1; return x + \"s\"; }").map(|_| ()),
+            Err("Type error: invalid operands to + (int and char*)".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_types_user_defined_assignment() -> Result<(), String> {
+        check_source_types("int main() { MyType a; MyType b; a = b; return 0; }")?;
+        assert_eq!(
+            check_source_types("int main() { MyType a; Other b; a = b; return 0; }").map(|_| ()),
+            Err("Type error: cannot assign a value of type Other to MyType".to_owned())
+        );
         assert_eq!(
-            check_syntax(&syntax_tree),
-            Err("Undefined variable z in scope 1".to_owned())
+            check_source_types("int main() { MyType a; if (a) { return 1; } return 0; }")
+                .map(|_| ()),
+            Err("Type error: condition has non-scalar type MyType".to_owned())
         );
         Ok(())
     }
@@ -1,12 +1,31 @@
 use crate::ast::*;
-use std::collections::HashMap;
+use crate::diagnostic::{Note, json_string};
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
+use std::collections::{HashMap, HashSet};
 
-pub type VarName = String;
+pub type VarName = Symbol;
+
+// Globals are declared in a scope of their own, which every function's root scope sees
+pub const GLOBAL_SCOPE: u32 = 0;
+
+#[derive(Debug, PartialEq)]
+pub struct FunctionSignature {
+    pub name: Symbol,
+    pub params: Vec<Type>,
+    pub return_type: Type,
+    pub variadic: bool, // takes any number of arguments after params
+}
 
 #[derive(Debug, PartialEq)]
 pub struct SymbolTable {
     vars: HashMap<(u32, VarName), VarInfo>, // key is (scope_id, var_name)
     scope_tree: HashMap<u32, u32>,          // maps scope id to parent scope id
+    scopes: HashSet<u32>,                   // every scope, including empty ones
+    spans: HashMap<u32, Span>,              // the source each scope covers, if it has one
+    functions: HashMap<Symbol, FunctionSignature>,
+    enumerators: HashSet<Symbol>, // enumerators are global, and a variable can hide one
 }
 
 impl SymbolTable {
@@ -14,178 +33,824 @@ impl SymbolTable {
         SymbolTable {
             vars: HashMap::new(),
             scope_tree: HashMap::new(),
+            scopes: HashSet::new(),
+            spans: HashMap::new(),
+            functions: HashMap::new(),
+            enumerators: HashSet::new(),
         }
     }
 
-    pub fn from_function(dec: &Declaration) -> Result<Self, String> {
-        // TODO: also add args to scope
-        let Declaration::Function { args, scope, .. } = dec;
-        let mut table = Self::from_scope(scope)?;
-        for v in args {
-            table.insert(
-                scope.id,
-                &v.name,
-                VarInfo {
-                    name: v.name.clone(),
-                    var_type: v.var_type.clone(),
-                },
-            )?;
+    // Builds one table covering every function. Scope ids are unique across the whole
+    // translation unit, so the functions' tables never overlap. Every function is visible
+    // from every other, regardless of the order they're defined in, and so is every global.
+    // A function that's only declared has just its signature.
+    pub fn from_declarations(declarations: &[Declaration]) -> Result<Self, CompileError> {
+        let (table, errors) = Self::build(declarations);
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(table),
         }
-        Ok(table)
     }
 
-    fn from_scope(scope: &Scope) -> Result<Self, String> {
-        let Scope { id, statements } = scope;
+    // The same table, along with every duplicate declaration rather than just the first. A
+    // duplicate is left out, so its name still refers to the first declaration.
+    pub fn build(declarations: &[Declaration]) -> (Self, Vec<CompileError>) {
+        let mut errors = vec![];
+        let mut table = Self::new();
+        for dec in declarations {
+            if let Declaration::Prototype {
+                name,
+                args,
+                return_type,
+                variadic,
+                ..
+            } = dec
+            {
+                table.functions.insert(
+                    *name,
+                    FunctionSignature {
+                        name: *name,
+                        params: args.iter().map(|a| a.var_type.clone()).collect(),
+                        return_type: return_type.clone(),
+                        variadic: *variadic,
+                    },
+                );
+            }
+            if let Declaration::Global {
+                name,
+                var_type,
+                span,
+                ..
+            } = dec
+            {
+                table.scopes.insert(GLOBAL_SCOPE);
+                table.insert(
+                    GLOBAL_SCOPE,
+                    VarInfo {
+                        name: *name,
+                        var_type: var_type.clone(),
+                        span: *span,
+                    },
+                    &mut errors,
+                );
+            }
+            if let Declaration::Enum { variants, .. } = dec {
+                table
+                    .enumerators
+                    .extend(variants.iter().map(|(variant, _)| *variant));
+            }
+            table.merge(Self::from_function(dec, &mut errors));
+        }
+        (table, errors)
+    }
+
+    // The function's signature, plus its parameters bound in the root scope of its body.
+    // Type definitions don't declare any variables, so they give an empty table.
+    pub fn from_function(dec: &Declaration, errors: &mut Vec<CompileError>) -> Self {
+        let Declaration::Function {
+            name,
+            args,
+            return_type,
+            scope,
+            ..
+        } = dec
+        else {
+            return Self::new();
+        };
+        let mut table = Self::from_scope(scope, args, errors);
+        table.functions.insert(
+            *name,
+            FunctionSignature {
+                name: *name,
+                params: args.iter().map(|a| a.var_type.clone()).collect(),
+                return_type: return_type.clone(),
+                variadic: false,
+            },
+        );
+        table
+    }
+
+    // `params` are declared in the scope ahead of its statements
+    fn from_scope(scope: &Scope, params: &[VarInfo], errors: &mut Vec<CompileError>) -> Self {
+        let Scope {
+            id,
+            statements,
+            span,
+        } = scope;
 
         let mut table = Self::new();
+        table.scopes.insert(*id);
+        if let Some(span) = span {
+            table.spans.insert(*id, *span);
+        }
+        for param in params {
+            table.insert(*id, param.clone(), errors);
+        }
         for s in statements {
-            match s {
-                Statement::VarDeclare { name, var_type, .. } => table.insert(
-                    *id,
+            match &s.kind {
+                StatementKind::VarDeclare {
                     name,
+                    var_type,
+                    span,
+                    ..
+                } => table.insert(
+                    *id,
                     VarInfo {
-                        name: name.clone(),
+                        name: *name,
                         var_type: var_type.clone(),
+                        span: *span,
                     },
-                )?,
-                Statement::If {
-                    true_block,
-                    false_block,
-                    ..
-                } => {
-                    table.add_child_scope(*id, true_block)?;
-                    if let Some(false_scope) = false_block {
-                        table.add_child_scope(*id, false_scope)?;
-                    }
+                    errors,
+                ),
+                // The init declaration has no scope of its own until desugaring wraps the
+                // loop in a block
+                StatementKind::For { .. } => {
+                    errors.push(CompileError::semantic(
+                        "For loops must be desugared before building the symbol table".to_owned(),
+                    ));
                 }
                 _ => {}
             }
+            for child in s.child_scopes() {
+                table.add_child_scope(*id, child, errors);
+            }
         }
 
-        Ok(table)
+        table
     }
 
-    fn insert(&mut self, scope_id: u32, var_name: &str, var_info: VarInfo) -> Result<(), String> {
-        if self.vars.contains_key(&(scope_id, var_name.to_owned())) {
-            return Err(format!(
-                "Duplicate insertion of variable {:} into scope {:}.",
-                var_name, scope_id
-            ));
+    fn insert(&mut self, scope_id: u32, var_info: VarInfo, errors: &mut Vec<CompileError>) {
+        if let Some(previous) = self.vars.get(&(scope_id, var_info.name)) {
+            let message = format!("Duplicate declaration of variable {}", var_info.name);
+            let note = format!("Previous declaration of {}", var_info.name);
+            errors.push(
+                CompileError::semantic_at(message, var_info.span)
+                    .with_note(Note::new(note, Some(previous.span))),
+            );
+            return;
         }
-        self.vars.insert((scope_id, var_name.to_owned()), var_info);
-        Ok(())
+        self.vars.insert((scope_id, var_info.name), var_info);
     }
 
     fn merge(&mut self, other: SymbolTable) {
         self.vars.extend(other.vars);
         self.scope_tree.extend(other.scope_tree);
+        self.scopes.extend(other.scopes);
+        self.spans.extend(other.spans);
+        self.functions.extend(other.functions);
     }
 
-    fn add_child_scope(&mut self, parent_id: u32, child: &Scope) -> Result<(), String> {
-        let child_table = Self::from_scope(child)?;
+    fn add_child_scope(&mut self, parent_id: u32, child: &Scope, errors: &mut Vec<CompileError>) {
+        let child_table = Self::from_scope(child, &[], errors);
         self.merge(child_table);
         self.scope_tree.insert(child.id, parent_id);
-        Ok(())
     }
 
-    pub fn get(&self, scope_id: u32, var_name: &str) -> Option<&VarInfo> {
+    pub fn get(&self, scope_id: u32, var_name: Symbol) -> Option<&VarInfo> {
+        self.resolve(scope_id, var_name)
+            .map(|(_, var_info)| var_info)
+    }
+
+    // Looks the name up from the given scope outwards, returning the id of the scope that
+    // declares it along with its info
+    pub fn resolve(&self, scope_id: u32, var_name: Symbol) -> Option<(u32, &VarInfo)> {
         // If current scope has the variable, return it.
         // Otherwise, search the parent scope.
-        if let Some(var_info) = self.vars.get(&(scope_id, var_name.to_owned())) {
-            return Some(var_info);
+        if let Some(var_info) = self.vars.get(&(scope_id, var_name)) {
+            return Some((scope_id, var_info));
         }
         if let Some(parent_scope) = self.scope_tree.get(&scope_id) {
-            return self.get(*parent_scope, var_name);
+            return self.resolve(*parent_scope, var_name);
+        }
+        if scope_id != GLOBAL_SCOPE {
+            return self.resolve(GLOBAL_SCOPE, var_name);
         }
         None
     }
+
+    // Whether the name refers to an enumerator from the given scope, rather than to a variable
+    pub fn is_enumerator(&self, scope_id: u32, name: Symbol) -> bool {
+        self.enumerators.contains(&name) && self.resolve(scope_id, name).is_none()
+    }
+
+    // Like resolve, but as seen from the byte `offset` in the source: a local declared
+    // further on in its scope isn't in scope there yet, so the search carries on past it
+    pub fn resolve_at(
+        &self,
+        scope_id: u32,
+        var_name: Symbol,
+        offset: usize,
+    ) -> Option<(u32, &VarInfo)> {
+        let (found, var_info) = self.resolve(scope_id, var_name)?;
+        if found == GLOBAL_SCOPE || var_info.span.start <= offset {
+            return Some((found, var_info));
+        }
+        let parent = self.parent_scope(found).unwrap_or(GLOBAL_SCOPE);
+        self.resolve_at(parent, var_name, offset)
+    }
+
+    // The variables declared directly in the scope, sorted by name
+    pub fn symbols_in_scope(&self, scope_id: u32) -> Vec<&VarInfo> {
+        let mut symbols: Vec<&VarInfo> = self
+            .vars
+            .iter()
+            .filter(|((id, _), _)| *id == scope_id)
+            .map(|(_, var_info)| var_info)
+            .collect();
+        symbols.sort_by_key(|v| v.name);
+        symbols
+    }
+
+    // Every scope id in the table, in ascending order
+    pub fn all_scopes(&self) -> Vec<u32> {
+        let mut scopes: Vec<u32> = self.scopes.iter().copied().collect();
+        scopes.sort();
+        scopes
+    }
+
+    // None for the root scope of a function body, and for the global scope
+    pub fn parent_scope(&self, scope_id: u32) -> Option<u32> {
+        self.scope_tree.get(&scope_id).copied()
+    }
+
+    // None for the global scope, and for scopes the desugar pass made up
+    pub fn scope_span(&self, scope_id: u32) -> Option<Span> {
+        self.spans.get(&scope_id).copied()
+    }
+
+    pub fn get_function(&self, name: Symbol) -> Option<&FunctionSignature> {
+        self.functions.get(&name)
+    }
+
+    /*
+     * S-expression dump for `--dump-symbols`, in the style of ast_dump. Function signatures
+     * come first, sorted by name, followed by each tree of scopes with the variables
+     * declared directly in it and where they were declared, e.g.
+     *   (fn main int ())
+     *   (scope 1
+     *     (decl int x 1:18)
+     *     (scope 2
+     *       (decl char x 1:37)))
+     */
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        let mut functions: Vec<&FunctionSignature> = self.functions.values().collect();
+        functions.sort_by_key(|f| f.name);
+        for f in functions {
+            let mut params: Vec<String> = f.params.iter().map(|p| p.to_string()).collect();
+            if f.variadic {
+                params.push("...".to_owned());
+            }
+            out.push_str(&format!(
+                "(fn {} {} ({}))\n",
+                f.name,
+                f.return_type,
+                params.join(" ")
+            ));
+        }
+        for scope_id in self.all_scopes() {
+            if self.parent_scope(scope_id).is_none() {
+                self.dump_scope(scope_id, 0, &mut out);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    fn dump_scope(&self, scope_id: u32, depth: usize, out: &mut String) {
+        let indent = "  ".repeat(depth + 1);
+        out.push_str(&format!("(scope {}", scope_id));
+        for v in self.symbols_in_scope(scope_id) {
+            out.push_str(&format!(
+                "\n{}(decl {} {} {})",
+                indent, v.var_type, v.name, v.span
+            ));
+        }
+        for child in self.all_scopes() {
+            if self.parent_scope(child) == Some(scope_id) {
+                out.push_str(&format!("\n{}", indent));
+                self.dump_scope(child, depth + 1, out);
+            }
+        }
+        out.push(')');
+    }
+
+    // Every scope in id order, starting with the global one even when there are no globals.
+    // A function's root scope has the global scope as its parent, since that's where a name
+    // it doesn't declare is looked up next.
+    fn scopes_with_parents(&self) -> Vec<(u32, Option<u32>)> {
+        let mut scopes = self.all_scopes();
+        if scopes.first() != Some(&GLOBAL_SCOPE) {
+            scopes.insert(0, GLOBAL_SCOPE);
+        }
+        let parent = |id| match id {
+            GLOBAL_SCOPE => None,
+            id => Some(self.parent_scope(id).unwrap_or(GLOBAL_SCOPE)),
+        };
+        scopes.into_iter().map(|id| (id, parent(id))).collect()
+    }
+
+    /*
+     * Text dump for `--dump-scopes`: each scope with its parent and the lines and columns it
+     * runs between in `source`, then the variables declared directly in it, e.g.
+     *   scope 0
+     *     int g at 1:5
+     *   scope 2, parent 0, 2:11 to 5:1
+     *     int x at 3:7
+     */
+    pub fn dump_scopes(&self, source: &str) -> String {
+        let mut out = String::new();
+        for (id, parent) in self.scopes_with_parents() {
+            out.push_str(&format!("scope {}", id));
+            if let Some(parent) = parent {
+                out.push_str(&format!(", parent {}", parent));
+            }
+            if let Some(span) = self.scope_span(id) {
+                let (line, column) = span.end_position(source);
+                out.push_str(&format!(", {} to {}:{}", span, line, column));
+            }
+            out.push('\n');
+            for v in self.symbols_in_scope(id) {
+                out.push_str(&format!("  {} {} at {}\n", v.var_type, v.name, v.span));
+            }
+        }
+        out
+    }
+
+    // The same as dump_scopes, as a JSON array with an object for each scope
+    pub fn scopes_to_json(&self, source: &str) -> String {
+        let span_json = |span: Span| {
+            let (end_line, end_column) = span.end_position(source);
+            format!(
+                "{{\"start\":{},\"end\":{},\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{}}}",
+                span.start, span.end, span.line, span.column, end_line, end_column
+            )
+        };
+        let scopes: Vec<String> = self
+            .scopes_with_parents()
+            .into_iter()
+            .map(|(id, parent)| {
+                let variables: Vec<String> = self
+                    .symbols_in_scope(id)
+                    .iter()
+                    .map(|v| {
+                        format!(
+                            "{{\"name\":{},\"type\":{},\"span\":{}}}",
+                            json_string(v.name.as_str()),
+                            json_string(&v.var_type.to_string()),
+                            span_json(v.span)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"id\":{},\"parent\":{},\"span\":{},\"variables\":[{}]}}",
+                    id,
+                    parent.map_or("null".to_owned(), |p| p.to_string()),
+                    self.scope_span(id).map_or("null".to_owned(), span_json),
+                    variables.join(",")
+                )
+            })
+            .collect();
+        format!("[{}]\n", scopes.join(","))
+    }
+}
+
+// A name in the source, and what it names
+enum NameAt {
+    Variable(Symbol),
+    Function(Symbol),
+    Declaration(Span), // a name being declared, which is its own definition
+}
+
+/*
+ * Where the name at the byte `offset` in the source is declared, for an editor's "go to
+ * definition": the variable a use refers to from the scope it's in, or the function a call
+ * calls, preferring its definition to a prototype. A name that's being declared is its own
+ * definition. `declarations` must have been parsed with spans and desugared, as for
+ * building `symbol_table`.
+ */
+pub fn find_definition(
+    declarations: &[Declaration],
+    symbol_table: &SymbolTable,
+    offset: usize,
+) -> Option<Span> {
+    let contains = |span: Span| span.start <= offset && offset < span.end;
+    let name = declarations.iter().find_map(|dec| {
+        if contains(dec.span()) {
+            return Some(NameAt::Declaration(dec.span()));
+        }
+        match dec {
+            Declaration::Function { args, scope, .. } => args
+                .iter()
+                .find(|arg| contains(arg.span))
+                .map(|arg| NameAt::Declaration(arg.span))
+                .or_else(|| name_in_scope(scope, offset)),
+            Declaration::Global {
+                value: Some(value), ..
+            } => name_in_expr(value, offset),
+            Declaration::Enum { variants, .. } => variants
+                .iter()
+                .filter_map(|(_, value)| value.as_ref())
+                .find_map(|value| name_in_expr(value, offset)),
+            _ => None,
+        }
+    })?;
+    match name {
+        NameAt::Declaration(span) => Some(span),
+        NameAt::Variable(name) => {
+            let scope = scope_at(declarations, offset).unwrap_or(GLOBAL_SCOPE);
+            let (_, var_info) = symbol_table.resolve_at(scope, name, offset)?;
+            Some(var_info.span)
+        }
+        NameAt::Function(name) => {
+            let defined = declarations
+                .iter()
+                .find(|dec| matches!(dec, Declaration::Function { name: n, .. } if *n == name));
+            let declared = declarations
+                .iter()
+                .find(|dec| matches!(dec, Declaration::Prototype { name: n, .. } if *n == name));
+            defined.or(declared).map(Declaration::span)
+        }
+    }
+}
+
+fn name_in_scope(scope: &Scope, offset: usize) -> Option<NameAt> {
+    scope.statements.iter().find_map(|statement| {
+        if let StatementKind::VarDeclare { span, .. } = &statement.kind
+            && span.start <= offset
+            && offset < span.end
+        {
+            return Some(NameAt::Declaration(*span));
+        }
+        statement
+            .exprs()
+            .into_iter()
+            .find_map(|expr| name_in_expr(expr, offset))
+            .or_else(|| {
+                statement
+                    .child_scopes()
+                    .into_iter()
+                    .find_map(|child| name_in_scope(child, offset))
+            })
+    })
+}
+
+fn name_in_expr(expr: &Expr, offset: usize) -> Option<NameAt> {
+    // Expressions the compiler made up have no span, and are looked through
+    if let Some(span) = expr.span
+        && !(span.start <= offset && offset < span.end)
+    {
+        return None;
+    }
+    match &expr.kind {
+        ExprKind::Variable(name) => expr.span.map(|_| NameAt::Variable(*name)),
+        // The call's span starts with the function's name
+        ExprKind::Call { name, args } => match expr.span {
+            Some(span) if offset < span.start + name.as_str().len() => {
+                Some(NameAt::Function(*name))
+            }
+            _ => args.iter().find_map(|arg| name_in_expr(arg, offset)),
+        },
+        ExprKind::BinaryOperation { left, right, .. } => {
+            name_in_expr(left, offset).or_else(|| name_in_expr(right, offset))
+        }
+        ExprKind::UnaryOperation { expr, .. } | ExprKind::Cast { expr, .. } => {
+            name_in_expr(expr, offset)
+        }
+        ExprKind::IntLiteral(_) | ExprKind::StringLiteral(_) => None,
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::desugar::desugar;
+    use crate::parser::parse;
+    use crate::span::Span;
+    use crate::tokenizer::tokenize;
 
     fn make_symbol_table() -> Result<SymbolTable, String> {
         let scope = Scope {
             id: 1,
             statements: vec![
-                Statement::VarDeclare {
-                    name: "x".to_owned(),
-                    var_type: Type::Int,
-                    value: None,
-                },
-                Statement::If {
-                    condition: Expr::IntLiteral(1),
-                    true_block: Scope {
-                        id: 2,
-                        statements: vec![Statement::VarDeclare {
-                            name: "x".to_owned(),
-                            var_type: Type::UserDefined("MyType".to_owned()),
-                            value: None,
-                        }],
+                Statement::new(
+                    NodeId(1),
+                    StatementKind::VarDeclare {
+                        name: "x".into(),
+                        var_type: Type::Int,
+                        value: None,
+                        span: Span::default(),
                     },
-                    false_block: Some(Scope {
-                        id: 3,
-                        statements: vec![Statement::VarDeclare {
-                            name: "y".to_owned(),
-                            var_type: Type::Int,
-                            value: None,
-                        }],
-                    }),
-                },
+                ),
+                Statement::new(
+                    NodeId(5),
+                    StatementKind::If {
+                        condition: Expr::new(NodeId(2), ExprKind::IntLiteral(1)),
+                        true_block: Scope {
+                            id: 2,
+                            statements: vec![Statement::new(
+                                NodeId(3),
+                                StatementKind::VarDeclare {
+                                    name: "x".into(),
+                                    var_type: Type::UserDefined("MyType".into()),
+                                    value: None,
+                                    span: Span::default(),
+                                },
+                            )],
+                            span: None,
+                        },
+                        false_block: Some(Scope {
+                            id: 3,
+                            statements: vec![Statement::new(
+                                NodeId(4),
+                                StatementKind::VarDeclare {
+                                    name: "y".into(),
+                                    var_type: Type::Int,
+                                    value: None,
+                                    span: Span::default(),
+                                },
+                            )],
+                            span: None,
+                        }),
+                    },
+                ),
             ],
+            span: None,
         };
-        SymbolTable::from_scope(&scope)
+        let mut errors = vec![];
+        let table = SymbolTable::from_scope(&scope, &[], &mut errors);
+        match errors.pop() {
+            Some(error) => Err(error.into()),
+            None => Ok(table),
+        }
     }
 
     #[test]
     fn test_symbol_table_get() -> Result<(), String> {
         let st = make_symbol_table()?;
         assert_eq!(
-            st.get(1, "x"),
+            st.get(1, "x".into()),
             Some(&VarInfo {
-                name: "x".to_owned(),
-                var_type: Type::Int
+                name: "x".into(),
+                var_type: Type::Int,
+                span: Span::default(),
             })
         );
         assert_eq!(
-            st.get(2, "x"),
+            st.get(2, "x".into()),
             Some(&VarInfo {
-                name: "x".to_owned(),
-                var_type: Type::UserDefined("MyType".to_owned())
+                name: "x".into(),
+                var_type: Type::UserDefined("MyType".into()),
+                span: Span::default(),
             })
         );
         assert_eq!(
-            st.get(3, "x"),
+            st.get(3, "x".into()),
             Some(&VarInfo {
-                name: "x".to_owned(),
-                var_type: Type::Int
+                name: "x".into(),
+                var_type: Type::Int,
+                span: Span::default(),
             })
         );
         assert_eq!(
-            st.get(3, "y"),
+            st.get(3, "y".into()),
             Some(&VarInfo {
-                name: "y".to_owned(),
-                var_type: Type::Int
+                name: "y".into(),
+                var_type: Type::Int,
+                span: Span::default(),
+            })
+        );
+        assert_eq!(st.get(2, "y".into()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbol_table_functions() -> Result<(), String> {
+        let tokens = tokenize("int add(int a, char b) { int c = a; return c; } void f() { }")?;
+        let declarations = parse(&tokens)?;
+        let st = SymbolTable::from_declarations(&declarations)?;
+
+        assert_eq!(
+            st.get_function("add".into()),
+            Some(&FunctionSignature {
+                name: "add".into(),
+                params: vec![Type::Int, Type::Char],
+                return_type: Type::Int,
+                variadic: false,
             })
         );
-        assert_eq!(st.get(2, "y"), None);
+        assert_eq!(st.get_function("f".into()).map(|f| f.params.len()), Some(0));
+        assert_eq!(st.get_function("g".into()), None);
+
+        // Parameters live in the body's root scope, alongside its locals
+        let Declaration::Function { scope, .. } = &declarations[0] else {
+            return Err(format!("Expected a function, got {:?}", declarations[0]));
+        };
+        assert_eq!(
+            st.get(scope.id, "b".into()).map(|v| &v.var_type),
+            Some(&Type::Char)
+        );
+        assert!(st.get(scope.id, "c".into()).is_some());
+
+        let duplicate = parse(&tokenize("int f(int a) { int a; return a; }")?)?;
+        assert!(SymbolTable::from_declarations(&duplicate).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbol_table_nested_scopes() -> Result<(), String> {
+        let tokens = tokenize(
+            "int main() { int a; while (a) { int b; { int c; } } \
+             for (int i = 0; a; i += 1) { int d; } return 0; }",
+        )?;
+        let declarations = desugar(parse(&tokens)?);
+        let st = SymbolTable::from_declarations(&declarations)?;
+
+        // Every scope in the function chains back to the function's root scope, so each
+        // innermost scope can see everything declared around it
+        let Declaration::Function { scope, .. } = &declarations[0] else {
+            return Err(format!("Expected a function, got {:?}", declarations[0]));
+        };
+        fn innermost(scope: &Scope) -> Vec<&Scope> {
+            let children: Vec<&Scope> = scope
+                .statements
+                .iter()
+                .flat_map(|s| s.child_scopes())
+                .collect();
+            if children.is_empty() {
+                return vec![scope];
+            }
+            children.into_iter().flat_map(innermost).collect()
+        }
+        let leaves = innermost(scope);
+        assert_eq!(leaves.len(), 2);
+        for name in ["a", "b", "c"] {
+            assert!(
+                st.get(leaves[0].id, name.into()).is_some(),
+                "{} not visible",
+                name
+            );
+        }
+        for name in ["a", "i", "d"] {
+            assert!(
+                st.get(leaves[1].id, name.into()).is_some(),
+                "{} not visible",
+                name
+            );
+        }
+        assert_eq!(st.get(leaves[0].id, "i".into()), None);
+        assert_eq!(st.get(scope.id, "b".into()), None);
+
+        let undesugared = parse(&tokenize("int main() { for (;;) { } }")?)?;
+        assert!(SymbolTable::from_declarations(&undesugared).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbol_table_queries() -> Result<(), String> {
+        let st = make_symbol_table()?;
+        assert_eq!(st.all_scopes(), vec![1, 2, 3]);
+        assert_eq!(st.parent_scope(3), Some(1));
+        assert_eq!(st.parent_scope(1), None);
+
+        let names = |scope_id| -> Vec<&str> {
+            st.symbols_in_scope(scope_id)
+                .iter()
+                .map(|v| v.name.as_str())
+                .collect()
+        };
+        assert_eq!(names(1), vec!["x"]);
+        assert_eq!(names(3), vec!["y"]);
+
+        // The inner x shadows the outer one, and an outer x is found from scope 3
+        assert_eq!(st.resolve(2, "x".into()).map(|(id, _)| id), Some(2));
+        assert_eq!(st.resolve(3, "x".into()).map(|(id, _)| id), Some(1));
+        assert_eq!(st.resolve(1, "y".into()), None);
+
+        // Empty scopes are still listed
+        let tokens = tokenize("void f() { { } }")?;
+        let st = SymbolTable::from_declarations(&parse(&tokens)?)?;
+        assert_eq!(st.all_scopes().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbol_table_dump() -> Result<(), String> {
+        let expected = "\
+(fn f void (int))
+(fn main int ())
+(scope 1
+  (decl int n 0:0))
+(scope 4
+  (decl int a 0:0)
+  (decl int b 0:0)
+  (scope 2)
+  (scope 3
+    (decl char a 0:0)))
+";
+        let tokens = tokenize(
+            "void f(int n) { } \
+             int main() { int b; int a; if (a) { char a; } else { } return 0; }",
+        )?;
+        let st = SymbolTable::from_declarations(&parse(&tokens)?)?;
+        assert_eq!(st.dump(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_dump() -> Result<(), String> {
+        let source =
+            "int main(int n) {\n  int x = n;\n  if (x) {\n    char x;\n  }\n  return x;\n}\n";
+        let mut diagnostics = crate::diagnostic::DiagnosticSink::default();
+        let (tokens, spans) = crate::tokenizer::tokenize_with_spans(source)?;
+        let declarations = crate::parser::parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+        let st = SymbolTable::from_declarations(&declarations)?;
+        let expected = "\
+scope 0
+scope 1, parent 2, 3:10 to 5:3
+  char x at 4:10
+scope 2, parent 0, 1:9 to 7:1
+  int n at 1:14
+  int x at 2:7
+";
+        assert_eq!(st.dump_scopes(source), expected);
+        let json = st.scopes_to_json(source);
+        assert!(json.starts_with("[{\"id\":0,\"parent\":null,\"span\":null,\"variables\":[]},"));
+        assert!(json.contains(
+            "{\"id\":1,\"parent\":2,\"span\":{\"start\":40,\"end\":57,\"line\":3,\
+             \"column\":10,\"end_line\":5,\"end_column\":3},\"variables\":[{\"name\":\"x\",\
+             \"type\":\"char\",\"span\":"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_definition() -> Result<(), String> {
+        let source = "int g;\nint f(int n);\nint main() {\n  int x = g;\n  \
+                      for (int i = 0; i < 3; i += 1) { int x = i; g = x; }\n  \
+                      return f(x);\n}\nint f(int n) { return n; }\n";
+        let mut diagnostics = crate::diagnostic::DiagnosticSink::default();
+        let (tokens, spans) = crate::tokenizer::tokenize_with_spans(source)?;
+        let declarations = desugar(crate::parser::parse_with_spans(
+            &tokens,
+            &spans,
+            &mut diagnostics,
+        )?);
+        let st = SymbolTable::from_declarations(&declarations)?;
+        // Where the definition of the nth occurrence of `needle` is, as line:column
+        let definition = |needle: &str, nth: usize| -> Option<String> {
+            let offset = source.match_indices(needle).nth(nth)?.0;
+            find_definition(&declarations, &st, offset).map(|span| span.to_string())
+        };
+        assert_eq!(definition("g", 1).as_deref(), Some("1:5"));
+        assert_eq!(definition("i <", 0).as_deref(), Some("5:12"));
+        assert_eq!(definition("i +=", 0).as_deref(), Some("5:12"));
+        assert_eq!(definition("x;", 0).as_deref(), Some("5:40"));
+        assert_eq!(definition("x)", 0).as_deref(), Some("4:7"));
+        // Calls go to the definition rather than the prototype, and a declaration is its own
+        assert_eq!(definition("f(x", 0).as_deref(), Some("8:5"));
+        assert_eq!(definition("n)", 1).as_deref(), Some("8:11"));
+        assert_eq!(definition("n;", 0).as_deref(), Some("8:11"));
+        assert_eq!(definition("return", 0), None);
+        assert_eq!(definition("3", 0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_at() -> Result<(), String> {
+        // Until the inner x is declared, x is the outer one
+        let mut st = make_symbol_table()?;
+        let inner = |span_start| VarInfo {
+            name: "z".into(),
+            var_type: Type::Char,
+            span: Span {
+                start: span_start,
+                ..Span::default()
+            },
+        };
+        let mut errors = vec![];
+        st.insert(1, inner(10), &mut errors);
+        st.insert(2, inner(20), &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(st.resolve_at(2, "z".into(), 25).map(|(id, _)| id), Some(2));
+        assert_eq!(st.resolve_at(2, "z".into(), 15).map(|(id, _)| id), Some(1));
+        assert_eq!(st.resolve_at(2, "z".into(), 5), None);
         Ok(())
     }
 
     #[test]
     fn test_symbol_table_duplicate() -> Result<(), String> {
         let mut st = make_symbol_table()?;
-        assert!(
-            st.insert(
-                1,
-                "x",
-                VarInfo {
-                    name: "x".to_owned(),
-                    var_type: Type::Int,
-                },
-            )
-            .is_err()
-        );
+        let mut errors = vec![];
+        let duplicate = VarInfo {
+            name: "x".into(),
+            var_type: Type::Char,
+            span: Span::default(),
+        };
+        st.insert(1, duplicate, &mut errors);
+        assert_eq!(errors.len(), 1);
+        // The first declaration is the one that's kept
+        assert_eq!(st.get(1, "x".into()).map(|v| &v.var_type), Some(&Type::Int));
         Ok(())
     }
 }
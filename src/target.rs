@@ -0,0 +1,928 @@
+use crate::asm::{self, Instruction};
+use crate::cfg::*;
+use crate::error::CompileError;
+use crate::ice;
+use crate::intern::Symbol;
+use crate::liveness::{Liveness, VarSet};
+use crate::parallel;
+use crate::regalloc::{Allocation, Allocator, Assignment};
+use std::collections::HashSet;
+use std::fmt;
+
+/*
+    What's shared by the backends for register machines, x86-64 and RV64.
+
+    A backend implements TargetBackend: its register set, a frame that says where each var
+    lives once registers are allocated, how to emit each kind of statement as instructions,
+    and how to write an instruction out in its assembly syntax. The driver here does
+    everything else the same way for every target. It allocates registers, lays out the
+    blocks so branches fall through where they can, labels them, works out what's live
+    across each call, and puts the string literals after the code. Only once the whole
+    program is generated is it written out as text.
+
+    The WebAssembly and LLVM IR backends keep vars in locals instead of registers, so they
+    go from the CFG to their output directly.
+*/
+
+// How to generate code, beyond what the program itself says. The driver's are taken from its
+// CompileOptions by CompileOptions::codegen_options.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CodegenOptions<'a> {
+    pub allocator: Allocator,
+    // Position-independent code for a shared library, like -fPIC. Another module may
+    // define the same symbols as the program, so its globals are reached through the GOT
+    // and calls to its own functions go through the PLT.
+    pub pic: bool,
+    // Whether the program runs without the C runtime, entering at a _start of its own
+    pub freestanding: bool,
+    // Whether each Line in the program becomes a .loc directive for the line table
+    pub debug_info: bool,
+    // The program's source, to annotate the assembly with comments showing the source
+    // line and CFG statement each group of instructions comes from
+    pub comments: Option<&'a str>,
+    // How many threads to generate the functions' code on; they all run on the calling
+    // thread by default
+    pub jobs: usize,
+}
+
+// Who keeps a register's value across a call, or whether the allocator may hand it out at
+// all
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterClass {
+    // A call may clobber it, so the caller saves it if the value is needed afterwards
+    CallerSaved,
+    // A function that uses it saves it in the prologue and restores it before returning
+    CalleeSaved,
+    // Never allocated, like the stack pointer or a scratch register
+    Reserved,
+}
+
+// The registers of one class, in the order the table lists them
+pub fn registers_in<R: Copy>(
+    registers: &[(R, RegisterClass)],
+    class: RegisterClass,
+) -> impl Iterator<Item = R> + '_ {
+    registers
+        .iter()
+        .filter(move |(_, c)| *c == class)
+        .map(|(register, _)| *register)
+}
+
+pub fn class_of<R: PartialEq>(registers: &[(R, RegisterClass)], register: &R) -> RegisterClass {
+    registers
+        .iter()
+        .find(|(r, _)| r == register)
+        .map_or(RegisterClass::Reserved, |(_, class)| *class)
+}
+
+// A function that makes no calls
+pub fn is_leaf(cfg: &ControlFlowGraph) -> bool {
+    !cfg.values()
+        .flatten()
+        .any(|s| matches!(s, Statement::Call { .. }))
+}
+
+/*
+ * The registers the allocator hands out for a function, in the order it prefers them. That's
+ * the order of the target's table, except that a leaf function takes the caller-saved ones
+ * first: with no calls to save them around they cost nothing, while every callee-saved
+ * register it uses has to be saved and restored.
+ */
+pub fn allocatable<R: Copy>(registers: &[(R, RegisterClass)], cfg: &ControlFlowGraph) -> Vec<R> {
+    let mut allocatable: Vec<_> = registers
+        .iter()
+        .filter(|(_, class)| *class != RegisterClass::Reserved)
+        .collect();
+    if is_leaf(cfg) {
+        allocatable.sort_by_key(|(_, class)| *class != RegisterClass::CallerSaved);
+    }
+    allocatable
+        .into_iter()
+        .map(|(register, _)| *register)
+        .collect()
+}
+
+pub trait TargetBackend {
+    type Register: Copy + PartialEq + fmt::Display + Send + 'static;
+    // Where each var of one function lives
+    type Frame<'a>;
+
+    // Every register the backend knows about and what it's for. The ones that aren't
+    // reserved are handed out by the allocator, in this order unless the function is a leaf.
+    const REGISTERS: &'static [(Self::Register, RegisterClass)];
+
+    fn frame<'a>(
+        cfg: &'a ControlFlowGraph,
+        allocation: &Allocation<Self::Register>,
+        options: &CodegenOptions,
+    ) -> Self::Frame<'a>;
+
+    // Everything from the function's label up to its entry block: the prologue, and moving
+    // the parameters to where they were allocated
+    fn enter(
+        name: &str,
+        frame: &Self::Frame<'_>,
+        params: &[VReg],
+    ) -> Result<Vec<Instruction<Self::Register>>, CompileError>;
+
+    fn jump(label: &str) -> Instruction<Self::Register>;
+
+    // Jumps to label if var is nonzero, or if it's zero when `if_zero` is set
+    fn branch(
+        frame: &Self::Frame<'_>,
+        var: &VReg,
+        label: &str,
+        if_zero: bool,
+    ) -> Result<Vec<Instruction<Self::Register>>, CompileError>;
+
+    // Jumps to the label at index var in `labels`, or to `default` when var is past the end
+    // as an unsigned number. The labels go in a table in read-only data, labeled `table`.
+    fn jump_table(
+        frame: &Self::Frame<'_>,
+        var: &VReg,
+        table: &str,
+        labels: &[String],
+        default: &str,
+    ) -> Result<Vec<Instruction<Self::Register>>, CompileError>;
+
+    // `var` is None when there's no value to return
+    fn ret(
+        frame: &Self::Frame<'_>,
+        var: Option<&VReg>,
+    ) -> Result<Vec<Instruction<Self::Register>>, CompileError>;
+
+    // `live` is what's still live after the call
+    fn call(
+        frame: &Self::Frame<'_>,
+        program: &Program,
+        dest: &VReg,
+        func: Symbol,
+        args: &[VReg],
+        live: &VarSet,
+    ) -> Result<Vec<Instruction<Self::Register>>, CompileError>;
+
+    // Undoes the prologue and jumps to func, so that func returns straight to the caller.
+    // None if the calling convention doesn't allow it, as when an argument has to go on
+    // the stack, where the caller's frame is about to be popped.
+    fn tail_call(
+        frame: &Self::Frame<'_>,
+        program: &Program,
+        func: Symbol,
+        args: &[VReg],
+    ) -> Result<Option<Vec<Instruction<Self::Register>>>, CompileError>;
+
+    // Every other statement except Phi, which has to be eliminated before codegen
+    fn instruction(
+        frame: &Self::Frame<'_>,
+        program: &Program,
+        statement: &Statement,
+    ) -> Result<Vec<Instruction<Self::Register>>, CompileError>;
+
+    // The entry point of a freestanding program, which calls main with argc and argv and
+    // exits with its result using a system call
+    fn start() -> Vec<Instruction<Self::Register>>;
+
+    // What goes around a function's code: before its label, and after its last instruction.
+    // ELF marks the symbol as a function and gives its size, so tools like objdump can tell
+    // where it ends.
+    fn function_start(name: &str) -> Vec<Instruction<Self::Register>> {
+        vec![asm::directive(format!(".type {}, @function", name))]
+    }
+
+    fn function_end(name: &str) -> Vec<Instruction<Self::Register>> {
+        vec![asm::directive(format!(".size {}, .-{}", name, name))]
+    }
+
+    // Writes an instruction out in the target's assembly syntax
+    fn render(instruction: &Instruction<Self::Register>) -> String;
+
+    // What goes after the code
+    fn data(program: &Program) -> Vec<Instruction<Self::Register>> {
+        let mut asm = strings_to_asm(&program.strings);
+        asm.extend(globals_to_asm(&program.globals));
+        asm
+    }
+}
+
+pub fn check_entry_point(program: &Program) -> Result<(), CompileError> {
+    let Some(main) = program.functions.get(&Symbol::intern("main")) else {
+        return Err(CompileError::CodegenError(
+            "Program has no main function".to_owned(),
+        ));
+    };
+    // Either nothing, or argc and argv
+    if ![0, 2].contains(&main.params.len()) {
+        return Err(CompileError::CodegenError(
+            "main takes either no parameters or argc and argv".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+// Block labels carry the function's name, since every function numbers its blocks from 0
+pub fn label(function: &str, block: ControlBlockId) -> String {
+    format!(".L{}_{}", function, block)
+}
+
+// Data labels are names rather than numbers, so they can't clash with block labels
+pub fn data_label(label: &str) -> String {
+    format!(".L{}", label)
+}
+
+// The assembly symbol for a LoadAddress label. Strings are local to the file, while a
+// global keeps its C name so it's visible to the linker.
+pub fn symbol(program: &Program, label: Symbol) -> String {
+    match program.strings.contains_key(&label) {
+        true => data_label(&label),
+        false => label.to_string(),
+    }
+}
+
+// Each string goes in .rodata with a terminating zero byte
+pub fn strings_to_asm<R>(strings: &StringPool) -> Vec<Instruction<R>> {
+    if strings.is_empty() {
+        return vec![];
+    }
+    let mut asm = vec![asm::directive(".section .rodata")];
+    for (label, value) in strings {
+        asm.push(asm::label(data_label(label)));
+        asm.push(asm::directive(format!(".string \"{}\"", value)));
+    }
+    asm
+}
+
+// Initialized globals go in .data and the rest in .bss, which takes no space in the file
+pub fn globals_to_asm<R>(globals: &Globals) -> Vec<Instruction<R>> {
+    let mut asm = vec![];
+    for (section, zero) in [(".data", false), (".bss", true)] {
+        let in_section: Vec<_> = globals
+            .iter()
+            .filter(|(_, value)| (**value == Initializer::Zero) == zero)
+            .collect();
+        if in_section.is_empty() {
+            continue;
+        }
+        asm.push(asm::directive(format!(".section {}", section)));
+        asm.push(asm::directive(".align 8"));
+        for (name, value) in in_section {
+            asm.push(asm::directive(format!(".globl {}", name)));
+            asm.push(asm::label(name));
+            asm.push(asm::directive(match value {
+                Initializer::Zero => ".zero 8".to_owned(),
+                Initializer::Int(value) => format!(".quad {}", value),
+                Initializer::String(label) => format!(".quad {}", data_label(label)),
+            }));
+        }
+    }
+    asm
+}
+
+/*
+ * Where everything a function keeps on the stack goes: the callee-saved registers it uses,
+ * the stack slots from Alloca, and the spill slots. The frame grows down from the frame
+ * pointer, and each slot is known by the offset of its lowest byte below it. Slots are
+ * rounded up to 8 bytes so they stay aligned, and the frame as a whole to 16, so that one
+ * adjustment of the stack pointer in the prologue makes room for all of it and leaves the
+ * stack aligned for calls.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameLayout {
+    used: u64,
+}
+
+impl FrameLayout {
+    // A frame whose top `bytes` are already taken by the prologue, like the return address
+    pub fn below(bytes: u64) -> Self {
+        FrameLayout { used: bytes }
+    }
+
+    // A slot of at least `bytes` bytes
+    pub fn slot(&mut self, bytes: u64) -> u64 {
+        self.used += bytes.div_ceil(8) * 8;
+        self.used
+    }
+
+    // A slot to save each of the registers in
+    pub fn save<R>(&mut self, registers: impl IntoIterator<Item = R>) -> Vec<(R, u64)> {
+        registers
+            .into_iter()
+            .map(|register| (register, self.slot(8)))
+            .collect()
+    }
+
+    // How far the prologue moves the stack pointer
+    pub fn size(&self) -> u64 {
+        self.used.div_ceil(16) * 16
+    }
+}
+
+/*
+ * The order blocks are emitted in. Starting from the entry block, each block is followed by
+ * its first successor that hasn't been placed yet (the true branch of an If, which is the
+ * then-branch or loop body), so that edge falls through instead of needing a jump.
+ * Successors that lose out start new chains later on, and unreachable blocks go last.
+ */
+pub fn block_layout(cfg: &ControlFlowGraph) -> Vec<ControlBlockId> {
+    let mut layout = vec![];
+    let mut placed = HashSet::new();
+    let mut chain_starts = vec![0];
+    let mut i = 0;
+    while i < chain_starts.len() {
+        let mut next = Some(chain_starts[i]);
+        i += 1;
+        while let Some(id) = next.filter(|id| !placed.contains(id)) {
+            placed.insert(id);
+            layout.push(id);
+            next = None;
+            for successor in cfg.successors(id) {
+                if placed.contains(&successor) {
+                    continue;
+                }
+                if next.is_none() {
+                    next = Some(successor);
+                } else {
+                    chain_starts.push(successor);
+                }
+            }
+        }
+    }
+    layout.extend(cfg.keys().filter(|id| !placed.contains(id)));
+    layout
+}
+
+// Every function is emitted, with main as the program's entry point. The instructions are
+// only written out as text once they're all generated.
+pub fn program_to_asm<T: TargetBackend>(
+    program: &Program,
+    options: &CodegenOptions,
+) -> Result<Vec<String>, CompileError> {
+    check_entry_point(program)?;
+    let mut asm = vec![];
+    if options.freestanding {
+        asm.extend(T::start());
+    }
+    // The functions' code comes out in order, and so does the first error
+    let functions: Vec<_> = program.functions.iter().collect();
+    let functions_asm = parallel::map(functions, options.jobs, |(name, function)| {
+        ice::enter_function(name, None);
+        function_to_asm::<T>(program, name, function, options)
+    });
+    for function_asm in functions_asm {
+        asm.extend(function_asm?);
+    }
+    asm.extend(T::data(program));
+    Ok(asm.iter().map(T::render).collect())
+}
+
+// How many vars the allocator leaves on the stack across the program, for --time-passes
+pub fn count_spills<T: TargetBackend>(program: &Program, allocator: Allocator) -> usize {
+    let allocations = program.functions.values().map(|function| {
+        allocator.allocate(&function.cfg, &allocatable(T::REGISTERS, &function.cfg))
+    });
+    allocations
+        .flat_map(|allocation| allocation.into_values())
+        .filter(|assignment| matches!(assignment, Assignment::Spilled))
+        .count()
+}
+
+fn function_to_asm<T: TargetBackend>(
+    program: &Program,
+    name: &str,
+    function: &Function,
+    options: &CodegenOptions,
+) -> Result<Vec<Instruction<T::Register>>, CompileError> {
+    let cfg = &function.cfg;
+    assert!(cfg.contains_key(&0)); // Block 0 is the entry block
+
+    // The entry block comes straight after the prologue, and every other block gets a label
+    // that branches can jump to
+    let allocation = options
+        .allocator
+        .allocate(cfg, &allocatable(T::REGISTERS, cfg));
+    if log::log_enabled!(log::Level::Debug) {
+        let assignments: Vec<String> = allocation
+            .iter()
+            .map(|(var, assignment)| format!("{}={}", var, assignment))
+            .collect();
+        log::debug!("registers for {}: {}", name, assignments.join(" "));
+    }
+    let frame = T::frame(cfg, &allocation, options);
+    let mut asm = T::enter(name, &frame, &function.params)?;
+    let start = asm
+        .iter()
+        .position(|i| *i == Instruction::Label(name.to_owned()))
+        .unwrap_or(0);
+    asm.splice(start..start, T::function_start(name));
+    // A Line at the very start covers the prologue too, so it goes straight after the
+    // function's label
+    let mut statements_to_skip = 0;
+    if let Some(Statement::Line(line)) = cfg[&0].first() {
+        let start = asm
+            .iter()
+            .position(|i| *i == Instruction::Label(name.to_owned()));
+        let start = start.map_or(0, |i| i + 1);
+        asm.splice(start..start, line_to_asm(*line, options));
+        statements_to_skip = 1;
+    }
+    let liveness = Liveness::analyze(cfg);
+    let layout = block_layout(cfg);
+    for (i, id) in layout.iter().enumerate() {
+        if *id != 0 {
+            asm.push(asm::label(label(name, *id)));
+        }
+        // A jump to the next block is left out, since control falls through to it
+        let next = layout.get(i + 1).copied();
+        let jump = |block| match next == Some(block) {
+            true => vec![],
+            false => vec![T::jump(&label(name, block))],
+        };
+        let statements = cfg[id].iter().zip(liveness.live_after(cfg, *id));
+        let skip = if *id == 0 { statements_to_skip } else { 0 };
+        // Set once a tail call jumps away, leaving out the return after it
+        let mut returned = false;
+        for (i, (s, live)) in statements.enumerate().skip(skip) {
+            if returned {
+                break;
+            }
+            if options.comments.is_some() && !matches!(s, Statement::Line(_)) {
+                asm.push(Instruction::Comment(s.to_string()));
+            }
+            let statement_asm = match s {
+                Statement::Return(var) => T::ret(&frame, var.as_ref())?,
+                Statement::If {
+                    var,
+                    goto_true,
+                    goto_false,
+                } => {
+                    if next == Some(*goto_true) {
+                        T::branch(&frame, var, &label(name, *goto_false), true)?
+                    } else {
+                        let mut asm = T::branch(&frame, var, &label(name, *goto_true), false)?;
+                        asm.extend(jump(*goto_false));
+                        asm
+                    }
+                }
+                Statement::Goto(block) => jump(*block),
+                Statement::Switch {
+                    var,
+                    targets,
+                    default,
+                } => {
+                    let labels: Vec<String> = targets.iter().map(|t| label(name, *t)).collect();
+                    let table = format!("{}_table", label(name, *id));
+                    T::jump_table(&frame, var, &table, &labels, &label(name, *default))?
+                }
+                Statement::Phi { .. } => {
+                    return Err(CompileError::CodegenError(
+                        "Phi nodes must be eliminated before codegen".to_owned(),
+                    ));
+                }
+                Statement::Call {
+                    dest,
+                    func,
+                    args,
+                    tail,
+                } => {
+                    let returns_dest = cfg[id].get(i + 1) == Some(&Statement::Return(Some(*dest)));
+                    let tail_call = match *tail && returns_dest {
+                        true => T::tail_call(&frame, program, *func, args)?,
+                        false => None,
+                    };
+                    returned = tail_call.is_some();
+                    match tail_call {
+                        Some(asm) => asm,
+                        None => T::call(&frame, program, dest, *func, args, &live)?,
+                    }
+                }
+                Statement::Line(line) => line_to_asm(*line, options),
+                _ => T::instruction(&frame, program, s)?,
+            };
+            asm.extend(statement_asm);
+        }
+    }
+    asm.extend(T::function_end(name));
+    Ok(asm)
+}
+
+// Both targets' assemblers build the DWARF line table from .loc, with the source as file
+// 1, and take # as a comment
+fn line_to_asm<R>(line: u32, options: &CodegenOptions) -> Vec<Instruction<R>> {
+    let mut asm = vec![];
+    if options.debug_info {
+        asm.push(asm::directive(format!(".loc 1 {}", line)));
+    }
+    if let Some(source) = options.comments {
+        let text = source.lines().nth(line as usize - 1).unwrap_or("");
+        asm.push(Instruction::Comment(format!("{}: {}", line, text.trim())));
+    }
+    asm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::{parse_cfg, parse_program};
+
+    // A target that writes each statement out as CFG text, to check what the driver does
+    // around the backend
+    struct Mock;
+
+    type MockAsm = Vec<Instruction<u8>>;
+
+    impl TargetBackend for Mock {
+        type Register = u8;
+        type Frame<'a> = Allocation<u8>;
+
+        const REGISTERS: &'static [(u8, RegisterClass)] = &[
+            (0, RegisterClass::CalleeSaved),
+            (1, RegisterClass::Reserved),
+            (2, RegisterClass::CallerSaved),
+        ];
+
+        fn frame(
+            _: &ControlFlowGraph,
+            allocation: &Allocation<u8>,
+            _: &CodegenOptions,
+        ) -> Allocation<u8> {
+            allocation.clone()
+        }
+
+        fn enter(name: &str, _: &Allocation<u8>, params: &[VReg]) -> Result<MockAsm, CompileError> {
+            Ok(vec![asm::label(format!("{}({})", name, join_vars(params)))])
+        }
+
+        fn jump(label: &str) -> Instruction<u8> {
+            asm::op("jump", [asm::sym(label)])
+        }
+
+        fn branch(
+            _: &Allocation<u8>,
+            var: &VReg,
+            label: &str,
+            if_zero: bool,
+        ) -> Result<MockAsm, CompileError> {
+            let condition = if if_zero { "zero" } else { "nonzero" };
+            let operands = [asm::sym(var), asm::sym(condition), asm::sym(label)];
+            Ok(vec![asm::op("branch", operands)])
+        }
+
+        fn jump_table(
+            _: &Allocation<u8>,
+            var: &VReg,
+            table: &str,
+            labels: &[String],
+            default: &str,
+        ) -> Result<MockAsm, CompileError> {
+            let labels = format!("[{}]", labels.join(" "));
+            let operands = [table, &var.to_string(), &labels, default].map(asm::sym);
+            Ok(vec![asm::op("jump", operands)])
+        }
+
+        fn ret(_: &Allocation<u8>, var: Option<&VReg>) -> Result<MockAsm, CompileError> {
+            let statement = Statement::Return(var.copied());
+            Ok(vec![asm::directive(statement.to_string())])
+        }
+
+        fn call(
+            _: &Allocation<u8>,
+            _: &Program,
+            dest: &VReg,
+            func: Symbol,
+            _: &[VReg],
+            live: &VarSet,
+        ) -> Result<MockAsm, CompileError> {
+            let live: Vec<String> = live.iter().map(VReg::to_string).collect();
+            let text = format!("{} = call {} live {}", dest, func, live.join(" "));
+            Ok(vec![asm::directive(text)])
+        }
+
+        // Only with no arguments, to check the driver falls back to a call
+        fn tail_call(
+            _: &Allocation<u8>,
+            _: &Program,
+            func: Symbol,
+            args: &[VReg],
+        ) -> Result<Option<MockAsm>, CompileError> {
+            let jump = asm::op("jump", [asm::sym("to"), asm::sym(func)]);
+            Ok(args.is_empty().then(|| vec![jump]))
+        }
+
+        fn instruction(
+            _: &Allocation<u8>,
+            _: &Program,
+            statement: &Statement,
+        ) -> Result<MockAsm, CompileError> {
+            Ok(vec![asm::directive(statement.to_string())])
+        }
+
+        fn start() -> MockAsm {
+            vec![asm::directive("start")]
+        }
+
+        fn function_start(name: &str) -> MockAsm {
+            vec![asm::directive(format!("start {}", name))]
+        }
+
+        fn function_end(name: &str) -> MockAsm {
+            vec![asm::directive(format!("end {}", name))]
+        }
+
+        fn data(_: &Program) -> MockAsm {
+            vec![asm::directive("data")]
+        }
+
+        // Operands are separated by spaces, so an op reads like the CFG's own statements
+        fn render(instruction: &Instruction<u8>) -> String {
+            match instruction {
+                Instruction::Op { mnemonic, operands } => {
+                    let operands = operands.iter().map(|operand| match operand {
+                        asm::Operand::Symbol(name) => name.clone(),
+                        operand => format!("{:?}", operand),
+                    });
+                    [mnemonic.to_string()]
+                        .into_iter()
+                        .chain(operands)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                instruction => asm::render(instruction, |_| unreachable!()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_layout() -> Result<(), String> {
+        // bb3 is laid out after the entry block so the true branch falls through, and bb2 is
+        // unreachable so it goes last
+        let cfg = parse_cfg(
+            "bb0: v1 = 1; if v1 goto bb3 else bb1
+             bb1: ret v1
+             bb2: ret v1
+             bb3: goto bb1",
+        )?;
+        assert_eq!(block_layout(&cfg), vec![0, 3, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_layout() {
+        let mut layout = FrameLayout::default();
+        assert_eq!(layout.size(), 0);
+        assert_eq!(layout.save([7, 9]), vec![(7, 8), (9, 16)]);
+        // An odd-sized slot still leaves the next one aligned
+        assert_eq!(layout.slot(12), 32);
+        assert_eq!(layout.slot(8), 40);
+        assert_eq!(layout.size(), 48);
+
+        let mut layout = FrameLayout::below(16);
+        assert_eq!(layout.slot(1), 24);
+        assert_eq!(layout.size(), 32);
+    }
+
+    #[test]
+    fn test_allocatable() -> Result<(), String> {
+        // The reserved register is never handed out, and a leaf takes the caller-saved one
+        // before the callee-saved one it would have to save
+        let leaf = parse_cfg("bb0: v1 = 1; ret v1")?;
+        assert!(is_leaf(&leaf));
+        assert_eq!(allocatable(Mock::REGISTERS, &leaf), vec![2, 0]);
+        let caller = parse_cfg("bb0: v1 = call f(); ret v1")?;
+        assert!(!is_leaf(&caller));
+        assert_eq!(allocatable(Mock::REGISTERS, &caller), vec![0, 2]);
+
+        let callee_saved: Vec<u8> =
+            registers_in(Mock::REGISTERS, RegisterClass::CalleeSaved).collect();
+        assert_eq!(callee_saved, vec![0]);
+        assert_eq!(class_of(Mock::REGISTERS, &2), RegisterClass::CallerSaved);
+        assert_eq!(class_of(Mock::REGISTERS, &7), RegisterClass::Reserved);
+        Ok(())
+    }
+
+    #[test]
+    fn test_driver() -> Result<(), String> {
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 1; v2 = call f(v1); if v1 goto bb2 else bb1
+             bb1: goto bb2
+             bb2: ret v2
+             }",
+        )?;
+        let asm = program_to_asm::<Mock>(&program, &CodegenOptions::default())?;
+
+        // bb2 is placed after bb0, so bb0 branches to bb1 when v1 is zero, and bb1 has to
+        // jump back to bb2. v2 is live across the call, and v1 too since the If reads it.
+        let expected = vec![
+            "start main",
+            "main():",
+            "v1 = 1",
+            "v2 = call f live v1 v2",
+            "branch v1 zero .Lmain_1",
+            ".Lmain_2:",
+            "ret v2",
+            ".Lmain_1:",
+            "jump .Lmain_2",
+            "end main",
+            "data",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_driver_switch() -> Result<(), String> {
+        // The table is named after the block it's in, and the targets keep their labels
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 1; switch v1 [bb2, bb1, bb2] else bb3
+             bb1: ret v1
+             bb2: goto bb3
+             bb3: ret
+             }",
+        )?;
+        let asm = program_to_asm::<Mock>(&program, &CodegenOptions::default())?;
+        assert_eq!(
+            asm[1..4],
+            [
+                "main():",
+                "v1 = 1",
+                "jump .Lmain_0_table v1 [.Lmain_2 .Lmain_1 .Lmain_2] .Lmain_3",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_driver_tail_calls() -> Result<(), String> {
+        // f's jump replaces its return. g takes an argument, which the mock can't pass, and
+        // h's result isn't what's returned, so both stay calls.
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 1; if v1 goto bb1 else bb2
+             bb1: v2 = tail call f(); ret v2
+             bb2: v3 = tail call g(v1); ret v3
+             bb3: v4 = tail call h(); ret v1
+             }",
+        )?;
+        let asm = program_to_asm::<Mock>(&program, &CodegenOptions::default())?;
+        let expected = vec![
+            "start main",
+            "main():",
+            "v1 = 1",
+            "branch v1 zero .Lmain_2",
+            ".Lmain_1:",
+            "jump to f",
+            ".Lmain_2:",
+            "v3 = call g live v3",
+            "ret v3",
+            ".Lmain_3:",
+            "v4 = call h live v1",
+            "ret v1",
+            "end main",
+            "data",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_driver_lines() -> Result<(), String> {
+        // The function's own line comes before the prologue
+        let program = parse_program(
+            "fn main() {
+             bb0: line 1; line 2; v1 = 1; goto bb1
+             bb1: line 3; ret v1
+             }",
+        )?;
+        let options = CodegenOptions {
+            debug_info: true,
+            ..Default::default()
+        };
+        let asm = program_to_asm::<Mock>(&program, &options)?;
+        let expected = vec![
+            ".loc 1 1",
+            "start main",
+            "main():",
+            ".loc 1 2",
+            "v1 = 1",
+            ".Lmain_1:",
+            ".loc 1 3",
+            "ret v1",
+            "end main",
+            "data",
+        ];
+        assert_eq!(asm, expected);
+
+        // Without -g the lines only show up in comments, if at all
+        assert!(
+            program_to_asm::<Mock>(&program, &CodegenOptions::default())?
+                .iter()
+                .all(|s| !s.starts_with(".loc"))
+        );
+        let options = CodegenOptions {
+            comments: Some("int main() {\n  int x = 1;\n  return x;\n}"),
+            ..Default::default()
+        };
+        let asm = program_to_asm::<Mock>(&program, &options)?;
+        let expected = vec![
+            "# 1: int main() {",
+            "start main",
+            "main():",
+            "# 2: int x = 1;",
+            "# v1 = 1",
+            "v1 = 1",
+            "# goto bb1",
+            ".Lmain_1:",
+            "# 3: return x;",
+            "# ret v1",
+            "ret v1",
+            "end main",
+            "data",
+        ];
+        assert_eq!(asm, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_driver_freestanding() -> Result<(), String> {
+        let program = parse_program("fn main() {\nbb0: v1 = 0; ret v1\n}")?;
+        let options = CodegenOptions {
+            freestanding: true,
+            ..Default::default()
+        };
+        let asm = program_to_asm::<Mock>(&program, &options)?;
+        assert_eq!(
+            asm,
+            vec![
+                "start",
+                "start main",
+                "main():",
+                "v1 = 0",
+                "ret v1",
+                "end main",
+                "data"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_point() -> Result<(), String> {
+        let program = parse_program("fn f(v1) {\nbb0: ret v1\n}")?;
+        assert_eq!(
+            check_entry_point(&program),
+            Err(CompileError::CodegenError(
+                "Program has no main function".to_owned()
+            ))
+        );
+        let program = parse_program("fn main(v1) {\nbb0: ret v1\n}")?;
+        assert_eq!(
+            check_entry_point(&program),
+            Err(CompileError::CodegenError(
+                "main takes either no parameters or argc and argv".to_owned()
+            ))
+        );
+        let program = parse_program("fn main(v1, v2) {\nbb0: ret v1\n}")?;
+        assert_eq!(check_entry_point(&program), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_sections() -> Result<(), String> {
+        let program = parse_program(
+            "str0 = \"hi\"
+             global n
+             global p = addr str0
+             global x = 3
+             fn main() {
+             bb0: v1 = addr x; v2 = addr str0; ret v1
+             }",
+        )?;
+        assert_eq!(symbol(&program, "x".into()), "x");
+        assert_eq!(symbol(&program, "str0".into()), ".Lstr0");
+
+        let expected = vec![
+            ".section .rodata",
+            ".Lstr0:",
+            ".string \"hi\"",
+            ".section .data",
+            ".align 8",
+            ".globl p",
+            "p:",
+            ".quad .Lstr0",
+            ".globl x",
+            "x:",
+            ".quad 3",
+            ".section .bss",
+            ".align 8",
+            ".globl n",
+            "n:",
+            ".zero 8",
+        ];
+        let data = strings_to_asm(&program.strings)
+            .into_iter()
+            .chain(globals_to_asm(&program.globals));
+        let data: Vec<String> = data.map(|i: Instruction<u8>| Mock::render(&i)).collect();
+        assert_eq!(data, expected);
+        Ok(())
+    }
+}
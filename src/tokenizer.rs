@@ -1,11 +1,23 @@
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
+
 /*
 * TODOs:
 *   - floating point literals
 *   - Comments
 */
 
-const KEYWORDS: [&'static str; 6] = ["void", "int", "char", "return", "if", "else"];
-const OPERATORS: [&'static str; 6] = ["+", "-", "*", "/", "=", "=="];
+const KEYWORDS: [&str; 18] = [
+    "void", "int", "char", "unsigned", "return", "if", "else", "while", "for", "break", "continue",
+    "switch", "case", "default", "struct", "enum", "typedef", "inline",
+];
+// Neither `...` nor the `:` after a case label is an operator, but they're punctuation that
+// tokenizes like one
+const OPERATORS: [&str; 31] = [
+    "+", "-", "*", "/", "=", "==", "!=", "<", "<=", ">", ">=", "&&", "||", "!", "~", "&", "+=",
+    "-=", "*=", "/=", "<<", ">>", "|", "^", "<<=", ">>=", "&=", "|=", "^=", "...", ":",
+];
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token<'a> {
@@ -14,26 +26,29 @@ pub enum Token<'a> {
     OpenBrace,
     CloseBrace,
     Semicolon,
+    Comma,
     Operator(&'a str),   // e.g. =, ==, +
     Keyword(&'a str),    // e.g. int, if, return
-    Identifier(&'a str), // e.g. myvar or main
+    Identifier(Symbol),  // e.g. myvar or main
     IntegerLiteral(u64), // e.g. 0, 1, 500
     StringLiteral(&'a str), // e.g. "text"
                          // TODO: CharLiteral, e.g. '\n'
 }
 
-fn tokenize_operator(s: &str) -> Result<(Token, usize), ()> {
-    assert!(s.len() != 0);
+fn tokenize_operator(s: &str) -> Result<(Token<'_>, usize), ()> {
+    assert!(!s.is_empty());
 
     let mut ptr = 0;
     while ptr < s.len() {
         // Increment the pointer until the next increment causes the buffer to no
-        // longer match any operators.
-        let buf = &s[..ptr + 1];
+        // longer match any operators. Operators are ASCII, so one can't end partway
+        // through a character.
+        let Some(buf) = s.get(..ptr + 1) else {
+            break;
+        };
         if !OPERATORS
             .iter()
-            .map(|op| buf.len() <= op.len() && buf == &op[..ptr + 1])
-            .any(|x| x)
+            .any(|op| buf.len() <= op.len() && buf == &op[..ptr + 1])
         {
             break;
         }
@@ -48,17 +63,16 @@ fn tokenize_operator(s: &str) -> Result<(Token, usize), ()> {
     Err(())
 }
 
-fn tokenize_string_literal(s: &str) -> Result<(Token, usize), ()> {
-    assert!(s.len() != 0);
+fn tokenize_string_literal(s: &str) -> Result<(Token<'_>, usize), ()> {
+    assert!(!s.is_empty());
 
     let quote = '"';
-    if s.chars().nth(0).unwrap() != quote {
+    if !s.starts_with(quote) {
         return Err(());
     }
 
-    let next_quote_index = s[1..]
-        .find(quote)
-        .expect("Tokenization Error: String Literal: missing matching quote.");
+    // An unterminated literal is reported by the caller
+    let next_quote_index = s[1..].find(quote).ok_or(())?;
 
     Ok((
         Token::StringLiteral(&s[1..next_quote_index + 1]),
@@ -66,18 +80,18 @@ fn tokenize_string_literal(s: &str) -> Result<(Token, usize), ()> {
     ))
 }
 
-fn tokenize_keywords_integers_ids(s: &str) -> Result<(Token, usize), ()> {
-    assert!(s.len() != 0);
+fn tokenize_keywords_integers_ids(s: &str) -> Result<(Token<'_>, usize), ()> {
+    assert!(!s.is_empty());
 
     let mut substr = s;
-    for (i, c) in s.chars().enumerate() {
+    for (i, c) in s.char_indices() {
         if !(c.is_alphanumeric() || c == '_') {
             substr = &s[..i];
             break;
         }
     }
 
-    if substr.len() == 0 {
+    if substr.is_empty() {
         return Err(());
     }
 
@@ -85,60 +99,223 @@ fn tokenize_keywords_integers_ids(s: &str) -> Result<(Token, usize), ()> {
         return Ok((Token::Keyword(substr), substr.len()));
     }
 
-    let as_int = substr.parse::<u64>();
-    if as_int.is_ok() {
-        return Ok((Token::IntegerLiteral(as_int.unwrap()), substr.len()));
+    if let Ok(as_int) = substr.parse::<u64>() {
+        return Ok((Token::IntegerLiteral(as_int), substr.len()));
     }
 
-    Ok((Token::Identifier(substr), substr.len()))
+    Ok((Token::Identifier(Symbol::intern(substr)), substr.len()))
 }
 
-pub fn tokenize(s: &str) -> Result<Vec<Token>, String> {
-    let mut ptr = 0;
-    let mut tokens: Vec<Token> = Vec::new();
-    while ptr < s.len() {
-        // TODO: nth() is O(n). If we assume the input file is ASCII
-        // we can use byte indexing which is faster
-        let c = s.chars().nth(ptr).ok_or("Out of Bounds Error")?;
-        if c.is_whitespace() {
-            ptr += 1;
-            continue;
+pub fn tokenize(s: &str) -> Result<Vec<Token<'_>>, CompileError> {
+    Ok(tokenize_with_spans(s)?.0)
+}
+
+// Where tokenizing has got to in the source, and the line that position is on
+struct Lexer<'a> {
+    s: &'a str,
+    ptr: usize,
+    line: u32,
+    line_start: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn span(&self, len: usize) -> Span {
+        Span {
+            start: self.ptr,
+            end: self.ptr + len,
+            line: self.line,
+            column: (self.ptr - self.line_start + 1) as u32,
         }
+    }
+
+    // Moves past any whitespace, returning false at the end of the source
+    fn skip_whitespace(&mut self) -> bool {
+        while let Some(c) = self.s[self.ptr..].chars().next() {
+            if c == '\n' {
+                self.line += 1;
+                self.line_start = self.ptr + 1;
+            }
+            if !c.is_whitespace() {
+                return true;
+            }
+            self.ptr += c.len_utf8();
+        }
+        false
+    }
 
+    // The token at the current position, which skip_whitespace has left on one
+    fn next_token(&mut self) -> Result<(Token<'a>, Span), CompileError> {
+        let s = &self.s[self.ptr..];
+        let c = s.chars().next().ok_or(CompileError::LexError {
+            message: "Out of Bounds Error".to_owned(),
+            span: None,
+        })?;
         let (next_token, num_chars) = match c {
             '(' => (Token::OpenParen, 1),
             ')' => (Token::CloseParen, 1),
             '{' => (Token::OpenBrace, 1),
             '}' => (Token::CloseBrace, 1),
             ';' => (Token::Semicolon, 1),
-            _ => tokenize_operator(&s[ptr..])
-                .or_else(|()| tokenize_string_literal(&s[ptr..]))
-                .or_else(|()| tokenize_keywords_integers_ids(&s[ptr..]))
-                .or(Err(format!(
-                    "Tokenization error at position {} character {}",
-                    ptr, c
-                )))?,
+            ',' => (Token::Comma, 1),
+            _ => tokenize_operator(s)
+                .or_else(|()| tokenize_string_literal(s))
+                .or_else(|()| tokenize_keywords_integers_ids(s))
+                .map_err(|()| CompileError::LexError {
+                    message: match c {
+                        '"' => "Missing closing quote for string literal".to_owned(),
+                        _ => format!(
+                            "Tokenization error at position {} character {}",
+                            self.ptr, c
+                        ),
+                    },
+                    span: Some(self.span(c.len_utf8())),
+                })?,
         };
 
-        tokens.push(next_token);
-        ptr += num_chars;
+        let span = self.span(num_chars);
+        self.ptr += num_chars;
+        Ok((next_token, span))
+    }
+}
+
+// Tokenizes `s`, also returning the Span of each token (parallel to the token list)
+pub fn tokenize_with_spans<'a>(s: &'a str) -> Result<(Vec<Token<'a>>, Vec<Span>), CompileError> {
+    let mut lexer = Lexer {
+        s,
+        ptr: 0,
+        line: 1,
+        line_start: 0,
+    };
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut spans: Vec<Span> = Vec::new();
+    while lexer.skip_whitespace() {
+        let (token, span) = lexer.next_token()?;
+        tokens.push(token);
+        spans.push(span);
+    }
+
+    Ok((tokens, spans))
+}
+
+// A change to the source: the bytes that were at `start..old_end` are now `start..new_end`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+/*
+ * Tokenizes `s` after an edit, given the tokens and spans from before it, for an editor
+ * that wants to keep up with each keystroke on a large file.
+ *
+ * A token only depends on the text up to the character after it, so the tokens that end
+ * before the edit are kept, and tokenizing picks up again where the last of them ends.
+ * Tokenizing from the start of a token depends only on the text from there on, so once
+ * it's past the edited text and reaches the start of one of the old tokens, the rest of
+ * them are what they were, just shifted, and they're spliced in without being lexed
+ * again.
+ */
+pub fn retokenize<'a>(
+    tokens: &[Token],
+    spans: &[Span],
+    s: &'a str,
+    edit: Edit,
+) -> Result<(Vec<Token<'a>>, Vec<Span>), CompileError> {
+    let kept = spans.partition_point(|span| span.end < edit.start);
+    let mut lexer = match kept.checked_sub(1).map(|last| spans[last]) {
+        Some(last) => Lexer {
+            s,
+            ptr: last.end,
+            line: last.line,
+            line_start: last.start + 1 - last.column as usize,
+        },
+        None => Lexer {
+            s,
+            ptr: 0,
+            line: 1,
+            line_start: 0,
+        },
+    };
+    let mut new_tokens: Vec<Token> = tokens[..kept]
+        .iter()
+        .zip(spans)
+        .map(|(token, span)| rebase(token, s, span))
+        .collect();
+    let mut new_spans = spans[..kept].to_vec();
+
+    // Where the old tokens that started after the edit start now
+    let shifted = |offset: usize| offset - edit.old_end + edit.new_end;
+    let mut after = spans.partition_point(|span| span.start < edit.old_end);
+    while lexer.skip_whitespace() {
+        while after < spans.len() && shifted(spans[after].start) < lexer.ptr {
+            after += 1;
+        }
+        if after < spans.len() && shifted(spans[after].start) == lexer.ptr {
+            let resumed = spans[after];
+            let column = (lexer.ptr - lexer.line_start + 1) as u32;
+            for (token, span) in tokens[after..].iter().zip(&spans[after..]) {
+                let span = Span {
+                    start: shifted(span.start),
+                    end: shifted(span.end),
+                    line: span.line + lexer.line - resumed.line,
+                    column: match span.line == resumed.line {
+                        true => span.column + column - resumed.column,
+                        false => span.column,
+                    },
+                };
+                new_tokens.push(rebase(token, s, &span));
+                new_spans.push(span);
+            }
+            return Ok((new_tokens, new_spans));
+        }
+        let (token, span) = lexer.next_token()?;
+        new_tokens.push(token);
+        new_spans.push(span);
+    }
+    Ok((new_tokens, new_spans))
+}
+
+// The same token, borrowing its text from `s` where it's now at `span`
+fn rebase<'a>(token: &Token, s: &'a str, span: &Span) -> Token<'a> {
+    match token {
+        Token::OpenParen => Token::OpenParen,
+        Token::CloseParen => Token::CloseParen,
+        Token::OpenBrace => Token::OpenBrace,
+        Token::CloseBrace => Token::CloseBrace,
+        Token::Semicolon => Token::Semicolon,
+        Token::Comma => Token::Comma,
+        Token::Operator(_) => Token::Operator(&s[span.start..span.end]),
+        Token::Keyword(_) => Token::Keyword(&s[span.start..span.end]),
+        Token::Identifier(name) => Token::Identifier(*name),
+        Token::IntegerLiteral(value) => Token::IntegerLiteral(*value),
+        Token::StringLiteral(_) => Token::StringLiteral(&s[span.start + 1..span.end - 1]),
     }
+}
 
-    Ok(tokens)
+// One token per line, after the line:column it starts at, for --emit=tokens
+pub fn dump(tokens: &[Token], spans: &[Span]) -> String {
+    let mut out = String::new();
+    for (token, span) in tokens.iter().zip(spans) {
+        out.push_str(&format!("{} {:?}\n", span, token));
+    }
+    out
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_symbols() -> Result<(), String> {
-        let input = "(){};";
+        let input = "(){};,";
         let expected: Vec<Token> = vec![
             Token::OpenParen,
             Token::CloseParen,
             Token::OpenBrace,
             Token::CloseBrace,
             Token::Semicolon,
+            Token::Comma,
         ];
         let result = tokenize(input)?;
         assert_eq!(result, expected);
@@ -147,7 +324,7 @@ mod tests {
 
     #[test]
     fn test_operators() -> Result<(), String> {
-        let input = "+-===";
+        let input = "+- ===";
         let expected: Vec<Token> = vec![
             Token::Operator("+"),
             Token::Operator("-"),
@@ -159,6 +336,57 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_compound_assign_operators() -> Result<(), String> {
+        let input = "+=-= *=/=+";
+        let expected: Vec<Token> = vec![
+            Token::Operator("+="),
+            Token::Operator("-="),
+            Token::Operator("*="),
+            Token::Operator("/="),
+            Token::Operator("+"),
+        ];
+        let result = tokenize(input)?;
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_comparison_operators() -> Result<(), String> {
+        let input = "< <= > >=!= && ||";
+        let expected: Vec<Token> = vec![
+            Token::Operator("<"),
+            Token::Operator("<="),
+            Token::Operator(">"),
+            Token::Operator(">="),
+            Token::Operator("!="),
+            Token::Operator("&&"),
+            Token::Operator("||"),
+        ];
+        let result = tokenize(input)?;
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitwise_operators() -> Result<(), String> {
+        // The longest operator wins, so <<= is one token and &&& is && then &
+        let input = "<<=<<>> >>=&&&|^|=";
+        let expected: Vec<Token> = ["<<=", "<<", ">>", ">>=", "&&", "&", "|", "^", "|="]
+            .map(Token::Operator)
+            .into();
+        assert_eq!(tokenize(input)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ellipsis() -> Result<(), String> {
+        let expected = vec![Token::Comma, Token::Operator("..."), Token::CloseParen];
+        assert_eq!(tokenize(", ...)")?, expected);
+        assert!(tokenize("..").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_keywords_and_identifiers() -> Result<(), String> {
         let identifier = "my_identifier123";
@@ -168,13 +396,82 @@ mod tests {
             .iter()
             .map(|k| Token::Keyword(k))
             .collect::<Vec<_>>();
-        expected.append(&mut vec![Token::Identifier(identifier)]);
+        expected.append(&mut vec![Token::Identifier(identifier.into())]);
 
         let result = tokenize(&input)?;
         assert_eq!(result, expected);
         Ok(())
     }
 
+    #[test]
+    fn test_spans() -> Result<(), String> {
+        let (tokens, spans) = tokenize_with_spans("int main() {\n  return 10;\n}")?;
+        assert_eq!(tokens.len(), spans.len());
+        assert_eq!(
+            spans[1],
+            Span {
+                start: 4,
+                end: 8,
+                line: 1,
+                column: 5
+            }
+        );
+        assert_eq!(
+            spans[6],
+            Span {
+                start: 22,
+                end: 24,
+                line: 2,
+                column: 10
+            }
+        );
+        assert_eq!(spans[8].line, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump() -> Result<(), String> {
+        let (tokens, spans) = tokenize_with_spans("int x;\n  x = 1;")?;
+        let expected = "\
+1:1 Keyword(\"int\")
+1:5 Identifier(\"x\")
+1:6 Semicolon
+2:3 Identifier(\"x\")
+2:5 Operator(\"=\")
+2:7 IntegerLiteral(1)
+2:8 Semicolon
+";
+        assert_eq!(dump(&tokens, &spans), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retokenize() -> Result<(), String> {
+        // Each edit's result should match tokenizing the edited source from scratch
+        let before = "int main() {\n  int x = 1;\n  return x <= 2;\n}";
+        let edits = [
+            (19, 20, "count"),  // rename x
+            (23, 24, "10"),     // change a literal
+            (38, 39, ""),       // shorten an operator
+            (36, 37, ""),       // x<= 2, joining what follows
+            (12, 12, "\n"),     // add a line
+            (0, 3, "unsigned"), // the very first token
+            (43, 44, ""),       // the very last token
+        ];
+        let (tokens, spans) = tokenize_with_spans(before)?;
+        for (start, old_end, text) in edits {
+            let after = format!("{}{}{}", &before[..start], text, &before[old_end..]);
+            let edit = Edit {
+                start,
+                old_end,
+                new_end: start + text.len(),
+            };
+            let retokenized = retokenize(&tokens, &spans, &after, edit)?;
+            assert_eq!(retokenized, tokenize_with_spans(&after)?);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_literals() -> Result<(), String> {
         let input = "100 \"My_String\"";
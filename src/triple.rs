@@ -0,0 +1,210 @@
+use std::fmt;
+
+/*
+ * What the compiler is generating code for, parsed from a target triple like
+ * x86_64-unknown-linux-gnu. The architecture picks the backend, the OS picks the calling
+ * convention and object format conventions, and the ABI is carried along for the backends
+ * that care. The vendor field is accepted and ignored.
+ *
+ * The short names the driver has always taken (x86_64, x86_64-windows, riscv64, wasm32) are
+ * aliases for the usual triple of each.
+ */
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arch {
+    X86_64,
+    Riscv64,
+    Wasm32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Os {
+    Linux,
+    Windows,
+    None, // bare metal, or wasm's unknown
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Abi {
+    Gnu,
+    Msvc,
+    None,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Target {
+    pub arch: Arch,
+    pub os: Os,
+    pub abi: Abi,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::X86_64_LINUX
+    }
+}
+
+impl Target {
+    pub const X86_64_LINUX: Target = Target {
+        arch: Arch::X86_64,
+        os: Os::Linux,
+        abi: Abi::Gnu,
+    };
+    pub const X86_64_WINDOWS: Target = Target {
+        arch: Arch::X86_64,
+        os: Os::Windows,
+        abi: Abi::Msvc,
+    };
+    pub const RISCV64_LINUX: Target = Target {
+        arch: Arch::Riscv64,
+        os: Os::Linux,
+        abi: Abi::Gnu,
+    };
+    pub const WASM32: Target = Target {
+        arch: Arch::Wasm32,
+        os: Os::None,
+        abi: Abi::None,
+    };
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        let target = match name {
+            "x86_64" => return Ok(Target::X86_64_LINUX),
+            "x86_64-windows" => return Ok(Target::X86_64_WINDOWS),
+            "riscv64" => return Ok(Target::RISCV64_LINUX),
+            "wasm32" => return Ok(Target::WASM32),
+            _ => Target::parse_triple(name)?,
+        };
+        match (target.arch, target.os) {
+            (Arch::Riscv64, Os::Windows) | (Arch::Wasm32, Os::Linux | Os::Windows) => {
+                Err(format!("Unsupported target {}", name))
+            }
+            _ => Ok(target),
+        }
+    }
+
+    // arch-vendor-os[-abi], or arch-os[-abi] without the vendor
+    fn parse_triple(name: &str) -> Result<Self, String> {
+        let unknown = || format!("Unknown target {}", name);
+        let mut parts = name.split('-');
+        let arch = match parts.next() {
+            Some("x86_64" | "amd64") => Arch::X86_64,
+            Some("riscv64" | "riscv64gc") => Arch::Riscv64,
+            Some("wasm32") => Arch::Wasm32,
+            _ => return Err(unknown()),
+        };
+        let rest: Vec<&str> = parts.collect();
+        let os_index = rest
+            .iter()
+            .position(|p| ["linux", "windows", "mingw32", "none"].contains(p))
+            .or_else(|| (arch == Arch::Wasm32 && rest.len() == 2).then_some(1))
+            .ok_or_else(unknown)?;
+        // Only a vendor may come before the OS, and only an ABI after it
+        if os_index > 1 || rest.len() > os_index + 2 {
+            return Err(unknown());
+        }
+        let (os, default_abi) = match rest[os_index] {
+            "linux" => (Os::Linux, Abi::Gnu),
+            "windows" => (Os::Windows, Abi::Msvc),
+            "mingw32" => (Os::Windows, Abi::Gnu),
+            _ => (Os::None, Abi::None),
+        };
+        let abi = match rest.get(os_index + 1).copied() {
+            None => default_abi,
+            Some("gnu") => Abi::Gnu,
+            Some("msvc") => Abi::Msvc,
+            Some("elf") => Abi::None,
+            Some(_) => return Err(unknown()),
+        };
+        Ok(Target { arch, os, abi })
+    }
+
+    // The size of a pointer, and of the registers vars are kept in, in bytes
+    pub fn pointer_size(&self) -> u64 {
+        match self.arch {
+            Arch::X86_64 | Arch::Riscv64 => 8,
+            Arch::Wasm32 => 4,
+        }
+    }
+
+    // Windows passes arguments in rcx, rdx, r8, and r9 with shadow space above them;
+    // everything else on x86-64 uses the System V registers
+    pub fn is_microsoft_abi(&self) -> bool {
+        self.arch == Arch::X86_64 && self.os == Os::Windows
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arch = match self.arch {
+            Arch::X86_64 => "x86_64",
+            Arch::Riscv64 => "riscv64",
+            Arch::Wasm32 => "wasm32",
+        };
+        let (vendor, os) = match self.os {
+            Os::Linux => ("unknown", "linux"),
+            Os::Windows => ("pc", "windows"),
+            Os::None if self.arch == Arch::Wasm32 => ("unknown", "unknown"),
+            Os::None => ("unknown", "none"),
+        };
+        write!(f, "{}-{}-{}", arch, vendor, os)?;
+        match self.abi {
+            Abi::Gnu => write!(f, "-gnu"),
+            Abi::Msvc => write!(f, "-msvc"),
+            Abi::None if self.arch == Arch::Wasm32 => Ok(()),
+            Abi::None => write!(f, "-elf"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triples() -> Result<(), String> {
+        assert_eq!(
+            Target::parse("x86_64-unknown-linux-gnu")?,
+            Target::X86_64_LINUX
+        );
+        assert_eq!(Target::parse("x86_64-linux")?, Target::X86_64_LINUX);
+        assert_eq!(
+            Target::parse("x86_64-pc-windows-msvc")?,
+            Target::X86_64_WINDOWS
+        );
+        let mingw = Target::parse("x86_64-w64-mingw32")?;
+        assert_eq!((mingw.os, mingw.abi), (Os::Windows, Abi::Gnu));
+        assert!(mingw.is_microsoft_abi());
+        assert_eq!(
+            Target::parse("riscv64gc-unknown-linux-gnu")?,
+            Target::RISCV64_LINUX
+        );
+        assert_eq!(Target::parse("wasm32-unknown-unknown")?, Target::WASM32);
+        assert_eq!(
+            Target::parse("riscv64-unknown-none-elf")?.to_string(),
+            "riscv64-unknown-none-elf"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliases() -> Result<(), String> {
+        // Each alias names the triple it prints as
+        for alias in ["x86_64", "x86_64-windows", "riscv64", "wasm32"] {
+            let target = Target::parse(alias)?;
+            assert_eq!(Target::parse(&target.to_string())?, target);
+        }
+        assert_eq!(Target::parse("wasm32")?.pointer_size(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_triples() {
+        for name in ["arm-linux", "x86_64-pc-linux-gnu-extra", "x86_64-pc-beos"] {
+            assert_eq!(Target::parse(name), Err(format!("Unknown target {}", name)));
+        }
+        assert_eq!(
+            Target::parse("riscv64-pc-windows-msvc"),
+            Err("Unsupported target riscv64-pc-windows-msvc".to_owned())
+        );
+    }
+}
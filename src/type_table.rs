@@ -0,0 +1,401 @@
+use crate::ast::*;
+use crate::constant::{Enumerators, evaluate};
+use crate::diagnostic::Note;
+use crate::error::CompileError;
+use crate::intern::Symbol;
+use crate::span::Span;
+use crate::triple::Target;
+use std::collections::HashMap;
+
+/*
+ * The struct, enum, and typedef definitions in a translation unit, and the size and
+ * alignment of the types built from them. Definitions are registered in source order, so a
+ * struct can only contain types defined above it, though it may point to any struct.
+ *
+ * Typedef names that were never defined are left alone by `resolve`, so they behave as
+ * opaque types that are only compatible with themselves. How big a pointer is depends on
+ * the target.
+ */
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Member {
+    pub name: Symbol,
+    pub member_type: Type,
+    pub offset: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StructDef {
+    pub name: Symbol,
+    pub span: Span,
+    pub members: Vec<Member>,
+    pub layout: Layout,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct EnumDef {
+    pub name: Symbol,
+    pub span: Span,
+    pub variants: Vec<(Symbol, i64)>, // enumerators and their values
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TypeTable {
+    structs: HashMap<Symbol, StructDef>,
+    enums: HashMap<Symbol, EnumDef>,
+    enumerators: Enumerators, // every enum's enumerators, with their values
+    typedefs: HashMap<Symbol, (Type, Span)>,
+    target: Target,
+}
+
+fn redefinition(what: String, span: Span, previous: Span) -> CompileError {
+    let note = Note::new(format!("Previous definition of {}", what), Some(previous));
+    CompileError::semantic_at(format!("Redefinition of {}", what), span).with_note(note)
+}
+
+impl TypeTable {
+    pub fn from_declarations(
+        declarations: &[Declaration],
+        target: Target,
+    ) -> Result<Self, CompileError> {
+        let mut table = TypeTable {
+            target,
+            ..Default::default()
+        };
+        for dec in declarations {
+            match dec {
+                Declaration::Struct {
+                    span,
+                    name,
+                    members,
+                    ..
+                } => table.define_struct(*name, *span, members)?,
+                Declaration::Enum {
+                    span,
+                    name,
+                    variants,
+                    ..
+                } => table.define_enum(*name, *span, variants)?,
+                Declaration::Typedef {
+                    span, name, target, ..
+                } => {
+                    if let Some((_, previous)) = table.typedefs.get(name) {
+                        return Err(redefinition(format!("typedef {}", name), *span, *previous));
+                    }
+                    table.typedefs.insert(*name, (target.clone(), *span));
+                }
+                Declaration::Function { .. }
+                | Declaration::Prototype { .. }
+                | Declaration::Global { .. } => {}
+            }
+        }
+        Ok(table)
+    }
+
+    // Structs and enums share one namespace of tags
+    fn check_tag(&self, name: Symbol, span: Span) -> Result<(), CompileError> {
+        if let Some(previous) = self.structs.get(&name) {
+            return Err(redefinition(
+                format!("struct {}", name),
+                span,
+                previous.span,
+            ));
+        }
+        if let Some(previous) = self.enums.get(&name) {
+            return Err(redefinition(format!("enum {}", name), span, previous.span));
+        }
+        Ok(())
+    }
+
+    fn define_struct(
+        &mut self,
+        name: Symbol,
+        span: Span,
+        members: &[VarInfo],
+    ) -> Result<(), CompileError> {
+        self.check_tag(name, span)?;
+
+        // Each member is placed at the next offset that satisfies its alignment, and the
+        // struct is padded to a multiple of its strictest member's alignment.
+        let mut laid_out: Vec<Member> = vec![];
+        let mut offset: u64 = 0;
+        let mut align: u64 = 1;
+        for member in members {
+            if laid_out.iter().any(|m| m.name == member.name) {
+                return Err(CompileError::SemanticError {
+                    message: format!("Duplicate member {} in struct {}", member.name, name),
+                    span: Some(span),
+                    notes: vec![],
+                });
+            }
+            let layout = self.layout(&member.var_type)?;
+            offset = offset.next_multiple_of(layout.align);
+            laid_out.push(Member {
+                name: member.name,
+                member_type: member.var_type.clone(),
+                offset,
+            });
+            offset += layout.size;
+            align = align.max(layout.align);
+        }
+
+        let layout = Layout {
+            size: offset.next_multiple_of(align),
+            align,
+        };
+        self.structs.insert(
+            name,
+            StructDef {
+                name,
+                span,
+                members: laid_out,
+                layout,
+            },
+        );
+        Ok(())
+    }
+
+    // An enumerator without a value is one more than the one before it, and the first is 0.
+    // A value can refer to the enumerators before it.
+    fn define_enum(
+        &mut self,
+        name: Symbol,
+        span: Span,
+        variants: &[(Symbol, Option<Expr>)],
+    ) -> Result<(), CompileError> {
+        self.check_tag(name, span)?;
+        let mut values = vec![];
+        let mut next = 0;
+        for (variant, value) in variants {
+            if let Some(previous) = self
+                .enums
+                .values()
+                .find(|e| e.variants.iter().any(|(v, _)| v == variant))
+            {
+                return Err(redefinition(
+                    format!("enumerator {}", variant),
+                    span,
+                    previous.span,
+                ));
+            }
+            let value = match value {
+                Some(value) => {
+                    evaluate(value, &self.enumerators).map_err(|e| CompileError::SemanticError {
+                        message: format!(
+                            "Value of enumerator {} is not a constant: {}",
+                            variant,
+                            e.message()
+                        ),
+                        span: Some(span),
+                        notes: vec![],
+                    })?
+                }
+                None => next,
+            };
+            self.enumerators.insert(*variant, value);
+            values.push((*variant, value));
+            next = value.wrapping_add(1);
+        }
+
+        self.enums.insert(
+            name,
+            EnumDef {
+                name,
+                span,
+                variants: values,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get_struct(&self, name: Symbol) -> Option<&StructDef> {
+        self.structs.get(&name)
+    }
+
+    pub fn get_enum(&self, name: Symbol) -> Option<&EnumDef> {
+        self.enums.get(&name)
+    }
+
+    pub fn enumerators(&self) -> &Enumerators {
+        &self.enumerators
+    }
+
+    // Replaces typedef names with the types they stand for
+    pub fn resolve(&self, t: &Type) -> Type {
+        match t {
+            Type::UserDefined(name) => match self.typedefs.get(name) {
+                Some((target, _)) => self.resolve(target),
+                None => t.clone(),
+            },
+            Type::Pointer(inner) => Type::Pointer(Box::new(self.resolve(inner))),
+            _ => t.clone(),
+        }
+    }
+
+    pub fn layout(&self, t: &Type) -> Result<Layout, CompileError> {
+        let incomplete = |t: &Type| CompileError::semantic(format!("Incomplete type {}", t));
+        match self.resolve(t) {
+            Type::Char => Ok(Layout { size: 1, align: 1 }),
+            Type::Int | Type::Unsigned => Ok(Layout { size: 4, align: 4 }),
+            Type::Enum(name) if self.enums.contains_key(&name) => Ok(Layout { size: 4, align: 4 }),
+            Type::Pointer(_) => {
+                let size = self.target.pointer_size();
+                Ok(Layout { size, align: size })
+            }
+            Type::Struct(name) => self
+                .structs
+                .get(&name)
+                .map(|s| s.layout)
+                .ok_or_else(|| incomplete(t)),
+            _ => Err(incomplete(t)),
+        }
+    }
+
+    // Whether objects of the type can be declared, i.e. its size is known
+    pub fn is_complete(&self, t: &Type) -> bool {
+        match self.resolve(t) {
+            Type::Void | Type::UserDefined(_) => false,
+            Type::Struct(name) => self.structs.contains_key(&name),
+            Type::Enum(name) => self.enums.contains_key(&name),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::DiagnosticSink;
+    use crate::parser::parse_with_spans;
+    use crate::tokenizer::tokenize_with_spans;
+
+    fn type_table(source: &str) -> Result<TypeTable, String> {
+        type_table_for(source, Target::default())
+    }
+
+    fn type_table_for(source: &str, target: Target) -> Result<TypeTable, String> {
+        let mut diagnostics = DiagnosticSink::default();
+        let (tokens, spans) = tokenize_with_spans(source)?;
+        let declarations = parse_with_spans(&tokens, &spans, &mut diagnostics)?;
+        // An error's notes follow it, one to a line
+        TypeTable::from_declarations(&declarations, target).map_err(|e| {
+            let notes = e.notes().iter().map(|note| format!("\n{}", note));
+            e.to_string() + &notes.collect::<String>()
+        })
+    }
+
+    #[test]
+    fn test_struct_layout() -> Result<(), String> {
+        let table = type_table(
+            "struct Inner { char c; int *p; }; \
+             struct Outer { char a; int b; char c; struct Inner inner; struct Outer *next; };",
+        )?;
+        assert_eq!(
+            table.layout(&Type::Struct("Inner".into()))?,
+            Layout { size: 16, align: 8 }
+        );
+
+        let outer = table
+            .get_struct("Outer".into())
+            .ok_or("Missing struct Outer")?;
+        let offsets: Vec<(&str, u64)> = outer
+            .members
+            .iter()
+            .map(|m| (m.name.as_str(), m.offset))
+            .collect();
+        assert_eq!(
+            offsets,
+            vec![("a", 0), ("b", 4), ("c", 8), ("inner", 16), ("next", 32)]
+        );
+        assert_eq!(outer.layout, Layout { size: 40, align: 8 });
+
+        // Pointers are 4 bytes on wasm32
+        let table = type_table_for("struct Inner { char c; int *p; };", Target::WASM32)?;
+        assert_eq!(
+            table.layout(&Type::Struct("Inner".into()))?,
+            Layout { size: 8, align: 4 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_typedefs_and_enums() -> Result<(), String> {
+        let table = type_table(
+            "enum Color { RED, GREEN, BLUE, }; typedef enum Color color; \
+             typedef color *palette; struct S { palette p; };",
+        )?;
+        let palette = Type::UserDefined("palette".into());
+        assert_eq!(
+            table.resolve(&palette),
+            Type::Pointer(Box::new(Type::Enum("Color".into())))
+        );
+        assert_eq!(table.layout(&Type::UserDefined("color".into()))?.size, 4);
+        assert_eq!(
+            table.get_enum("Color".into()).map(|e| e.variants.clone()),
+            Some(vec![
+                ("RED".into(), 0),
+                ("GREEN".into(), 1),
+                ("BLUE".into(), 2)
+            ])
+        );
+
+        // Values count on from the last one given, and can use the enumerators before them
+        let table = type_table(
+            "enum Color { RED, GREEN }; enum Size { S = 2 * 8, M, L = M + GREEN * 10 };",
+        )?;
+        assert_eq!(
+            table.get_enum("Size".into()).map(|e| e.variants.clone()),
+            Some(vec![("S".into(), 16), ("M".into(), 17), ("L".into(), 27)])
+        );
+        assert_eq!(
+            type_table("enum E { A = B, B };"),
+            Err("1:6: Value of enumerator A is not a constant: B is a variable".to_owned())
+        );
+
+        // Unknown typedef names stay opaque
+        let opaque = Type::UserDefined("MyType".into());
+        assert_eq!(table.resolve(&opaque), opaque);
+        assert!(!table.is_complete(&opaque));
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_errors() -> Result<(), String> {
+        assert_eq!(
+            type_table("struct S { int a; };\nenum S { A };").map(|_| ()),
+            Err(
+                "2:6: Redefinition of struct S\n1:8: note: Previous definition of struct S"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            type_table("typedef int t;\ntypedef char t;").map(|_| ()),
+            Err(
+                "2:14: Redefinition of typedef t\n1:13: note: Previous definition of typedef t"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            type_table("enum A { X };\nenum B { Y, X };").map(|_| ()),
+            Err(
+                "2:6: Redefinition of enumerator X\n1:6: note: Previous definition of enumerator X"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            type_table("struct S { int a; char a; };").map(|_| ()),
+            Err("1:8: Duplicate member a in struct S".to_owned())
+        );
+        assert_eq!(
+            type_table("struct S { struct S inner; };").map(|_| ()),
+            Err("Incomplete type struct S".to_owned())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,570 @@
+use crate::cfg::*;
+use crate::error::CompileError;
+use crate::ice;
+use crate::intern::Symbol;
+use crate::parallel;
+use crate::target::{CodegenOptions, check_entry_point};
+use std::collections::{BTreeMap, BTreeSet};
+
+/*
+    WebAssembly text output, for --target=wasm32. Every var becomes an i64 local, so there's
+    no register allocation, and the result runs as-is in wasmtime or a browser.
+
+    Wasm only has structured control flow, so instead of reconstructing loops and ifs, each
+    function is a dispatch loop. A `br_table` on the $block local jumps into the code for
+    the current block, and each terminator sets $block to its target and branches back to
+    the top of the loop:
+
+      (loop $dispatch
+        (block $bb1
+          (block $bb0
+            local.get $block
+            br_table $bb0 $bb1)
+          ;; bb0's code, ending in a return or a branch to $dispatch
+        )
+        ;; bb1's code
+      )
+
+    Stack slots from Alloca live in linear memory, below a stack pointer in the $sp global
+    that starts at the top of the first page. String literals and globals go in data
+    segments at the bottom of memory, and a pointer is an i64 holding a memory offset.
+
+    Functions the program doesn't define are imported from the "env" module.
+*/
+
+const PAGE_SIZE: u64 = 65536;
+
+// Data starts here, so no string or global ends up at the null pointer
+const DATA_START: u64 = 16;
+
+fn local(var: &VReg) -> String {
+    format!("${}", var)
+}
+
+fn block_label(block: ControlBlockId) -> String {
+    format!("${}", block_name(block))
+}
+
+// A literal's bytes in the form a Wasm string wants them. Printable ASCII is kept and
+// everything else is written as a hex escape. Also gives the number of bytes.
+fn data_string(value: &str) -> (String, u64) {
+    let bytes = literal_bytes(value);
+    let text = bytes
+        .iter()
+        .map(|b| match b {
+            b'"' | b'\\' => format!("\\{:02x}", b),
+            b' '..=b'~' => (*b as char).to_string(),
+            _ => format!("\\{:02x}", b),
+        })
+        .collect();
+    (text, bytes.len() as u64)
+}
+
+// Lays the strings out one after another with their terminating zero bytes, followed by
+// the globals in 8-byte words. Memory starts out zeroed, so only initialized globals need
+// a data segment.
+fn data_addresses(program: &Program) -> (BTreeMap<Symbol, u64>, Vec<String>) {
+    let mut addresses = BTreeMap::new();
+    let mut data = vec![];
+    let mut address = DATA_START;
+    for (label, value) in &program.strings {
+        let (text, len) = data_string(value);
+        addresses.insert(*label, address);
+        data.push(format!("(data (i32.const {}) \"{}\\00\")", address, text));
+        address += len + 1;
+    }
+    address = address.div_ceil(8) * 8;
+    for (name, value) in &program.globals {
+        addresses.insert(*name, address);
+        let word = match value {
+            Initializer::Zero => None,
+            Initializer::Int(value) => Some(*value),
+            Initializer::String(label) => addresses.get(label).map(|a| *a as i64),
+        };
+        if let Some(word) = word {
+            let bytes: String = word
+                .to_le_bytes()
+                .iter()
+                .map(|b| format!("\\{:02x}", b))
+                .collect();
+            data.push(format!("(data (i32.const {}) \"{}\")", address, bytes));
+        }
+        address += 8;
+    }
+    (addresses, data)
+}
+
+struct FunctionContext<'a> {
+    // Where each string and global is in memory
+    addresses: &'a BTreeMap<Symbol, u64>,
+    // Each block's index in the br_table, which is its position in ascending order
+    indices: BTreeMap<ControlBlockId, usize>,
+    // The offset of each Alloca's slot from the bottom of the frame
+    slots: BTreeMap<&'a VReg, u64>,
+    frame_size: u64,
+}
+
+impl FunctionContext<'_> {
+    fn jump(&self, block: ControlBlockId) -> Vec<String> {
+        vec![
+            format!("i32.const {}", self.indices[&block]),
+            "local.set $block".to_owned(),
+            "br $dispatch".to_owned(),
+        ]
+    }
+
+    fn statement_to_wat(&self, statement: &Statement) -> Result<Vec<String>, CompileError> {
+        let get = |var: &VReg| format!("local.get {}", local(var));
+        let wat = match statement {
+            Statement::Assign { var, value } => {
+                vec![
+                    format!("i64.const {}", value),
+                    format!("local.set {}", local(var)),
+                ]
+            }
+            Statement::Copy { dest, src } => vec![get(src), format!("local.set {}", local(dest))],
+            Statement::Operation { dest, op, lhs, rhs } => {
+                let instruction = match op {
+                    BinOp::Add => "i64.add",
+                    BinOp::Sub => "i64.sub",
+                    BinOp::Mul => "i64.mul",
+                    BinOp::Div => "i64.div_s",
+                    BinOp::Shl => "i64.shl",
+                    BinOp::Shr => "i64.shr_s",
+                    BinOp::And => "i64.and",
+                    BinOp::Or => "i64.or",
+                    BinOp::Xor => "i64.xor",
+                    BinOp::Eq => "i64.eq",
+                    BinOp::Ne => "i64.ne",
+                    BinOp::Lt => "i64.lt_s",
+                    BinOp::Le => "i64.le_s",
+                    BinOp::Gt => "i64.gt_s",
+                    BinOp::Ge => "i64.ge_s",
+                    BinOp::UDiv => "i64.div_u",
+                    BinOp::UShr => "i64.shr_u",
+                    BinOp::ULt => "i64.lt_u",
+                    BinOp::ULe => "i64.le_u",
+                    BinOp::UGt => "i64.gt_u",
+                    BinOp::UGe => "i64.ge_u",
+                };
+                let mut wat = vec![get(lhs), get(rhs), instruction.to_owned()];
+                // Comparisons give an i32
+                if op.is_comparison() {
+                    wat.push("i64.extend_i32_u".to_owned());
+                }
+                wat.push(format!("local.set {}", local(dest)));
+                wat
+            }
+            Statement::UnaryOperation { dest, op, operand } => {
+                let mut wat = match op {
+                    UnaryOp::Neg => {
+                        vec!["i64.const 0".to_owned(), get(operand), "i64.sub".to_owned()]
+                    }
+                    UnaryOp::Not => vec![
+                        get(operand),
+                        "i64.eqz".to_owned(),
+                        "i64.extend_i32_u".to_owned(),
+                    ],
+                    UnaryOp::BitNot => {
+                        vec![
+                            get(operand),
+                            "i64.const -1".to_owned(),
+                            "i64.xor".to_owned(),
+                        ]
+                    }
+                    UnaryOp::Sext8 => vec![get(operand), "i64.extend8_s".to_owned()],
+                    UnaryOp::Sext32 => vec![get(operand), "i64.extend32_s".to_owned()],
+                    UnaryOp::Zext32 => vec![
+                        get(operand),
+                        "i32.wrap_i64".to_owned(),
+                        "i64.extend_i32_u".to_owned(),
+                    ],
+                };
+                wat.push(format!("local.set {}", local(dest)));
+                wat
+            }
+            Statement::Call {
+                dest, func, args, ..
+            } => {
+                let mut wat: Vec<String> = args.iter().map(get).collect();
+                wat.push(format!("call ${}", func));
+                wat.push(format!("local.set {}", local(dest)));
+                wat
+            }
+            Statement::Alloca { dest, .. } => vec![
+                "local.get $frame".to_owned(),
+                format!("i32.const {}", self.slots[dest]),
+                "i32.add".to_owned(),
+                "i64.extend_i32_u".to_owned(),
+                format!("local.set {}", local(dest)),
+            ],
+            Statement::Load { dest, addr, width } => vec![
+                get(addr),
+                "i32.wrap_i64".to_owned(),
+                match width {
+                    Width::I8 => "i64.load8_s".to_owned(),
+                    Width::I64 => "i64.load".to_owned(),
+                },
+                format!("local.set {}", local(dest)),
+            ],
+            Statement::Store { addr, src, width } => vec![
+                get(addr),
+                "i32.wrap_i64".to_owned(),
+                get(src),
+                match width {
+                    Width::I8 => "i64.store8".to_owned(),
+                    Width::I64 => "i64.store".to_owned(),
+                },
+            ],
+            Statement::LoadAddress { dest, label } => {
+                let Some(address) = self.addresses.get(label) else {
+                    return Err(CompileError::CodegenError(format!(
+                        "Unknown data label {}",
+                        label
+                    )));
+                };
+                vec![
+                    format!("i64.const {}", address),
+                    format!("local.set {}", local(dest)),
+                ]
+            }
+            Statement::If {
+                var,
+                goto_true,
+                goto_false,
+            } => {
+                let mut wat = vec![get(var), "i64.eqz".to_owned(), "if".to_owned()];
+                wat.extend(
+                    self.jump(*goto_false)
+                        .into_iter()
+                        .map(|s| format!("  {}", s)),
+                );
+                wat.push("end".to_owned());
+                wat.extend(self.jump(*goto_true));
+                wat
+            }
+            Statement::Goto(block) => self.jump(*block),
+            // br_table takes an i32, so anything past the end goes to the default before the
+            // value is wrapped. The nth target's jump comes after the end of the nth block
+            // out from the br_table, which is where breaking out of that block goes.
+            Statement::Switch {
+                var,
+                targets,
+                default,
+            } => {
+                let mut wat = vec![
+                    get(var),
+                    format!("i64.const {}", targets.len()),
+                    "i64.ge_u".to_owned(),
+                    "if".to_owned(),
+                ];
+                wat.extend(self.jump(*default).into_iter().map(|s| format!("  {}", s)));
+                wat.push("end".to_owned());
+                let indent = |depth: usize| "  ".repeat(depth);
+                for depth in 0..targets.len() {
+                    wat.push(format!("{}block", indent(depth)));
+                }
+                let depths: Vec<String> = (0..targets.len()).map(|i| i.to_string()).collect();
+                wat.extend([
+                    format!("{}{}", indent(targets.len()), get(var)),
+                    format!("{}i32.wrap_i64", indent(targets.len())),
+                    format!("{}br_table {} 0", indent(targets.len()), depths.join(" ")),
+                ]);
+                for (i, target) in targets.iter().enumerate() {
+                    let depth = targets.len() - i - 1;
+                    wat.push(format!("{}end", indent(depth)));
+                    wat.extend(
+                        self.jump(*target)
+                            .into_iter()
+                            .map(|s| format!("{}{}", indent(depth), s)),
+                    );
+                }
+                wat
+            }
+            Statement::Return(var) => {
+                // Every function gives back an i64, so one with nothing to return gives 0
+                let mut wat = vec![match var {
+                    Some(var) => get(var),
+                    None => "i64.const 0".to_owned(),
+                }];
+                // Pops the frame
+                if self.frame_size > 0 {
+                    wat.extend([
+                        "local.get $frame".to_owned(),
+                        format!("i32.const {}", self.frame_size),
+                        "i32.add".to_owned(),
+                        "global.set $sp".to_owned(),
+                    ]);
+                }
+                wat.push("return".to_owned());
+                wat
+            }
+            Statement::Phi { .. } => {
+                return Err(CompileError::CodegenError(
+                    "Phi nodes must be eliminated before codegen".to_owned(),
+                ));
+            }
+            // WebAssembly text has no line information to give
+            Statement::Line(_) => vec![],
+        };
+        Ok(wat)
+    }
+}
+
+fn function_to_wat(
+    addresses: &BTreeMap<Symbol, u64>,
+    name: &str,
+    function: &Function,
+) -> Result<Vec<String>, CompileError> {
+    let cfg = &function.cfg;
+    let mut frame_size = 0;
+    let mut slots = BTreeMap::new();
+    for statement in cfg.values().flatten() {
+        if let Statement::Alloca { dest, size } = statement {
+            slots.insert(dest, frame_size);
+            frame_size += size.div_ceil(8) * 8;
+        }
+    }
+    let context = FunctionContext {
+        addresses,
+        indices: cfg.keys().enumerate().map(|(i, id)| (*id, i)).collect(),
+        slots,
+        frame_size,
+    };
+
+    let params: Vec<String> = function
+        .params
+        .iter()
+        .map(|p| format!(" (param {} i64)", local(p)))
+        .collect();
+    let mut wat = vec![format!("(func ${}{} (result i64)", name, params.concat())];
+    let locals: BTreeSet<&VReg> = cfg
+        .values()
+        .flatten()
+        .flat_map(|s| s.used_vars().into_iter().chain(s.defined_var()))
+        .filter(|var| !function.params.contains(var))
+        .collect();
+    for var in locals {
+        wat.push(format!("  (local {} i64)", local(var)));
+    }
+    wat.push("  (local $block i32)".to_owned());
+    if frame_size > 0 {
+        wat.extend([
+            "  (local $frame i32)".to_owned(),
+            "  global.get $sp".to_owned(),
+            format!("  i32.const {}", frame_size),
+            "  i32.sub".to_owned(),
+            "  local.tee $frame".to_owned(),
+            "  global.set $sp".to_owned(),
+        ]);
+    }
+
+    // The blocks nest with the entry block innermost, so its code comes first
+    let ids: Vec<&ControlBlockId> = cfg.keys().collect();
+    let depth = ids.len() + 1;
+    let indent = |level: usize| "  ".repeat(level);
+    wat.push(format!("{}(loop $dispatch", indent(1)));
+    for (i, id) in ids.iter().enumerate().rev() {
+        wat.push(format!("{}(block {}", indent(depth - i), block_label(**id)));
+    }
+    let table: Vec<String> = ids.iter().map(|id| block_label(**id)).collect();
+    wat.push(format!("{}local.get $block", indent(depth + 1)));
+    wat.push(format!("{}br_table {}", indent(depth + 1), table.join(" ")));
+    for (i, id) in ids.iter().enumerate() {
+        wat.push(format!("{})", indent(depth - i)));
+        for statement in &cfg[*id] {
+            for line in context.statement_to_wat(statement)? {
+                wat.push(format!("{}{}", indent(depth - i), line));
+            }
+        }
+    }
+    wat.push(format!("{})", indent(1)));
+    wat.push("  unreachable".to_owned());
+    wat.push(")".to_owned());
+    Ok(wat)
+}
+
+// Calls to functions the program doesn't define, with the number of arguments each takes
+fn imports(program: &Program) -> BTreeMap<Symbol, usize> {
+    let mut imports = BTreeMap::new();
+    for function in program.functions.values() {
+        for statement in function.cfg.values().flatten() {
+            if let Statement::Call { func, args, .. } = statement
+                && !program.functions.contains_key(func)
+            {
+                imports.insert(*func, args.len());
+            }
+        }
+    }
+    imports
+}
+
+pub fn program_to_wat(
+    program: &Program,
+    options: &CodegenOptions,
+) -> Result<Vec<String>, CompileError> {
+    check_entry_point(program)?;
+    if !program.functions[&Symbol::intern("main")].params.is_empty() {
+        return Err(CompileError::CodegenError(
+            "main can't take argc and argv on wasm32, which has no command line".to_owned(),
+        ));
+    }
+    let mut wat = vec!["(module".to_owned()];
+    for (func, arg_count) in imports(program) {
+        wat.push(format!(
+            "  (import \"env\" \"{}\" (func ${}{} (result i64)))",
+            func,
+            func,
+            " (param i64)".repeat(arg_count)
+        ));
+    }
+    wat.push("  (memory (export \"memory\") 1)".to_owned());
+    wat.push(format!(
+        "  (global $sp (mut i32) (i32.const {}))",
+        PAGE_SIZE
+    ));
+    let (addresses, data) = data_addresses(program);
+    wat.extend(data.into_iter().map(|line| format!("  {}", line)));
+    let functions: Vec<_> = program.functions.iter().collect();
+    let functions_wat = parallel::map(functions, options.jobs, |(name, function)| {
+        ice::enter_function(name, None);
+        function_to_wat(&addresses, name, function)
+    });
+    for function_wat in functions_wat {
+        wat.extend(function_wat?.into_iter().map(|line| format!("  {}", line)));
+    }
+    wat.push("  (export \"main\" (func $main))".to_owned());
+    wat.push(")".to_owned());
+    Ok(wat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg_parser::parse_program;
+
+    #[test]
+    fn wasm_dispatch_loop() -> Result<(), String> {
+        let program = parse_program(
+            "fn main() {
+             bb0: v1 = 1; if v1 goto bb1 else bb2
+             bb1: ret v1
+             bb2: v2 = -v1; ret v2
+             }",
+        )?;
+        let wat = program_to_wat(&program, &CodegenOptions::default())?;
+
+        let expected = vec![
+            "(module",
+            "  (memory (export \"memory\") 1)",
+            "  (global $sp (mut i32) (i32.const 65536))",
+            "  (func $main (result i64)",
+            "    (local $v1 i64)",
+            "    (local $v2 i64)",
+            "    (local $block i32)",
+            "    (loop $dispatch",
+            "      (block $bb2",
+            "        (block $bb1",
+            "          (block $bb0",
+            "            local.get $block",
+            "            br_table $bb0 $bb1 $bb2",
+            "          )",
+            "          i64.const 1",
+            "          local.set $v1",
+            "          local.get $v1",
+            "          i64.eqz",
+            "          if",
+            "            i32.const 2",
+            "            local.set $block",
+            "            br $dispatch",
+            "          end",
+            "          i32.const 1",
+            "          local.set $block",
+            "          br $dispatch",
+            "        )",
+            "        local.get $v1",
+            "        return",
+            "      )",
+            "      i64.const 0",
+            "      local.get $v1",
+            "      i64.sub",
+            "      local.set $v2",
+            "      local.get $v2",
+            "      return",
+            "    )",
+            "    unreachable",
+            "  )",
+            "  (export \"main\" (func $main))",
+            ")",
+        ];
+        assert_eq!(wat, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn wasm_memory_and_imports() -> Result<(), String> {
+        // The string's escapes become bytes, the slot is carved out of the $sp stack, and
+        // putchar is imported since the program doesn't define it
+        let program = parse_program(
+            "str0 = \"a\\n\\\"\"
+             fn main() {
+             bb0: v1 = addr str0; v2 = alloca 8; store v1, v2; v3 = load v2
+                  v4 = call putchar(v3); ret v4
+             }",
+        )?;
+        let wat = program_to_wat(&program, &CodegenOptions::default())?;
+
+        assert_eq!(
+            wat[1],
+            "  (import \"env\" \"putchar\" (func $putchar (param i64) (result i64)))"
+        );
+        assert!(wat.contains(&"  (data (i32.const 16) \"a\\0a\\22\\00\")".to_owned()));
+        let body: Vec<&str> = wat.iter().map(|line| line.trim()).collect();
+        let frame = [
+            "global.get $sp",
+            "i32.const 8",
+            "i32.sub",
+            "local.tee $frame",
+        ];
+        assert!(body.windows(4).any(|lines| lines == frame));
+        let pop = [
+            "local.get $frame",
+            "i32.const 8",
+            "i32.add",
+            "global.set $sp",
+        ];
+        assert!(body.windows(4).any(|lines| lines == pop));
+        assert!(body.contains(&"i64.const 16"));
+        Ok(())
+    }
+
+    #[test]
+    fn wasm_globals() -> Result<(), String> {
+        // The globals start at the first 8-byte boundary after the strings, and only the
+        // initialized ones need a data segment
+        let program = parse_program(
+            "str0 = \"abc\"
+             global n
+             global p = addr str0
+             global x = -1
+             fn main() {
+             bb0: v1 = addr x; v2 = load v1; ret v2
+             }",
+        )?;
+        let wat = program_to_wat(&program, &CodegenOptions::default())?;
+
+        let data: Vec<&str> = wat
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| line.starts_with("(data"))
+            .collect();
+        let expected = [
+            "(data (i32.const 16) \"abc\\00\")",
+            "(data (i32.const 32) \"\\10\\00\\00\\00\\00\\00\\00\\00\")",
+            "(data (i32.const 40) \"\\ff\\ff\\ff\\ff\\ff\\ff\\ff\\ff\")",
+        ];
+        assert_eq!(data, expected);
+        assert!(wat.iter().any(|line| line.trim() == "i64.const 40"));
+        Ok(())
+    }
+}
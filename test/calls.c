@@ -0,0 +1,8 @@
+int square(int x) {
+    return x * x;
+}
+
+int main() {
+    int a = square(3);
+    return square(a) + square(2);
+}
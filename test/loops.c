@@ -0,0 +1,13 @@
+int main() {
+    int total = 0;
+    for (int i = 0; i == 0; i += 1)
+        total += 2;
+    while (total == 2) {
+        total = total * 3;
+    }
+    if (total == 1)
+        return 1;
+    else if (total == 6)
+        return 0;
+    return 2;
+}
@@ -0,0 +1,40 @@
+use std::env;
+use std::fs::{remove_file, write};
+use std::process::Command;
+
+/*
+ * What the compiler binary reports for a source file given no flags but -S, so the
+ * defaults the driver starts from are checked as a user would meet them.
+ */
+
+// The driver's stderr for `source`, and whether it succeeded
+fn compile(name: &str, source: &str) -> (String, bool) {
+    let file = env::temp_dir().join(format!("compiler-driver-{}-{}.c", std::process::id(), name));
+    write(&file, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .args(["-S", "-o", "-"])
+        .arg(&file)
+        .output()
+        .unwrap();
+    remove_file(&file).unwrap();
+    (
+        String::from_utf8(output.stderr).unwrap(),
+        output.status.success(),
+    )
+}
+
+#[test]
+fn test_default_warnings() {
+    let source =
+        "int main(int argc, char **argv) {\n  int x;\n  if (argc) x = 1;\n  return x;\n}\n";
+    let (stderr, success) = compile("maybe-uninitialized", source);
+    assert!(success, "{}", stderr);
+    assert!(
+        stderr.starts_with(
+            "warning: Variable x may be used before being initialized [-Wmaybe-uninitialized]\n"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains(":4:10\n"), "{}", stderr);
+}
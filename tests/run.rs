@@ -0,0 +1,96 @@
+use std::env;
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/*
+ * Programs compiled by the driver and run natively, at each optimization level, checked
+ * against the exit code they should give and against what the interpreter makes of them
+ * with --run. Linking needs the system's cc, and the executables are x86-64 Linux ones, so
+ * there's nothing to do anywhere else.
+ */
+
+// The directory for this test binary's sources and executables
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("compiler-run-{}-{}", std::process::id(), name));
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn native() -> bool {
+    cfg!(all(target_arch = "x86_64", target_os = "linux"))
+        && Command::new("cc").arg("--version").output().is_ok()
+}
+
+// The exit code of the program built at -O<level>, and the one --run gives
+fn run(dir: &Path, source: &str, level: u32) -> (i32, i32) {
+    let file = dir.join("main.c");
+    let executable = dir.join(format!("main-O{}", level));
+    write(&file, source).unwrap();
+    let level = format!("-O{}", level);
+    let compiler = env!("CARGO_BIN_EXE_compiler");
+    let built = Command::new(compiler)
+        .args([&level, "-o"])
+        .arg(&executable)
+        .arg(&file)
+        .output()
+        .unwrap();
+    assert!(
+        built.status.success(),
+        "{}",
+        String::from_utf8_lossy(&built.stderr)
+    );
+    let compiled = Command::new(&executable).status().unwrap();
+    let interpreted = Command::new(compiler)
+        .args([&level, "--run"])
+        .arg(&file)
+        .status()
+        .unwrap();
+    (compiled.code().unwrap(), interpreted.code().unwrap())
+}
+
+#[test]
+fn test_unsigned_wraps() {
+    if !native() {
+        return;
+    }
+    let dir = scratch("unsigned");
+    for (source, expected) in [
+        (
+            "int main() { unsigned u = 0; u = u - 1; return u == 4294967295; }",
+            1,
+        ),
+        (
+            "int main() { int x = -1; unsigned u = x; return u >> 28; }",
+            15,
+        ),
+        ("int main() { unsigned a = 65536; return a * a == 0; }", 1),
+        (
+            "int main() { unsigned one = 1; int n = 32; return (one << n) == 0; }",
+            1,
+        ),
+        (
+            "int main() { unsigned u = -1; int i = u; return i < 0; }",
+            1,
+        ),
+    ] {
+        for level in 0..=2 {
+            assert_eq!(run(&dir, source, level), (expected, expected), "{}", source);
+        }
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_main_args() {
+    if !native() {
+        return;
+    }
+    let dir = scratch("args");
+    let source =
+        "int main(int argc, char **argv) { char *name = *argv; return argc * 10 + (*name != 0); }";
+    for level in 0..=2 {
+        assert_eq!(run(&dir, source, level), (11, 11));
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
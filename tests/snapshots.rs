@@ -0,0 +1,117 @@
+use std::env;
+use std::fs::{read_dir, read_to_string, write};
+use std::path::PathBuf;
+use std::process::Command;
+
+/*
+ * Golden assembly for the driver. Every test/<name>.c that compiles is run through the
+ * compiler binary with -S, and what it prints has to match test/<name>.s.expected exactly,
+ * so a change to the backend shows up as a diff of the whole output. Running the tests with
+ * UPDATE_SNAPSHOTS=1 writes the driver's output to the .s.expected files instead. The files
+ * in REJECTED are there to be rejected, and every other one has to compile.
+ */
+
+const REJECTED: [&str; 1] = ["main_undef_var.c"];
+
+// The lines only in `expected` marked -, and the ones only in `actual` marked +, in order,
+// from a longest common subsequence of the two
+fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    // common[i][j] is how long the common subsequence of old[i..] and new[j..] is
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    lines.join("\n")
+}
+
+fn sources() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = read_dir("test")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "c"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn test_asm_snapshots() {
+    let update = env::var("UPDATE_SNAPSHOTS").is_ok_and(|v| v == "1");
+    let mut failures = vec![];
+    let mut checked = 0;
+    for path in sources() {
+        let output = Command::new(env!("CARGO_BIN_EXE_compiler"))
+            .args(["-S", "-o", "-"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        match (output.status.success(), REJECTED.contains(&name)) {
+            (true, false) => {}
+            (false, true) => continue,
+            (true, true) => {
+                failures.push(format!(
+                    "{} compiled, but should be rejected",
+                    path.display()
+                ));
+                continue;
+            }
+            (false, false) => {
+                failures.push(format!(
+                    "{} failed to compile:\n{}",
+                    path.display(),
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+                continue;
+            }
+        }
+        let asm = String::from_utf8(output.stdout).unwrap();
+        let snapshot = path.with_extension("s.expected");
+        checked += 1;
+        if update {
+            write(&snapshot, &asm).unwrap();
+            continue;
+        }
+        match read_to_string(&snapshot) {
+            Ok(expected) if expected == asm => {}
+            Ok(expected) => failures.push(format!(
+                "{} differs:\n{}",
+                snapshot.display(),
+                diff(&expected, &asm)
+            )),
+            Err(_) => failures.push(format!(
+                "{} is missing; run with UPDATE_SNAPSHOTS=1 to create it",
+                snapshot.display()
+            )),
+        }
+    }
+    assert!(checked > 0);
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn test_diff() {
+    assert_eq!(diff("a\nb\nc\n", "a\nb\nc\n"), "");
+    assert_eq!(diff("a\nb\nc\n", "a\nx\nc\nd\n"), "-b\n+x\n+d");
+}